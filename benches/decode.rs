@@ -0,0 +1,232 @@
+//! CPU microbenchmarks for the hot decode paths.
+//!
+//! Every benchmark operates on in-memory blocks that were copied out of the 4k golden image and
+//! checked in under `benches/fixtures`, so they need neither fusefs nor root.  The exception is
+//! `BlockReader`'s, which reads a scratch file, so it mostly measures the page cache.  Run them with
+//! `cargo bench --bench decode`.  To compare a refactor against the current tree, first run
+//! `cargo bench --bench decode -- --save-baseline master` on the old code, and then
+//! `cargo bench --bench decode -- --baseline master` on the new.
+//!
+//! Baseline numbers, measured on a 2 vCPU Intel Xeon VM with Rust 1.95:
+//!
+//! | Benchmark                                                | Time     | Throughput    |
+//! |----------------------------------------------------------|----------|---------------|
+//! | DinodeCore::decode/inode block (8 inodes)                | 961 ns   | 3.97 GiB/s    |
+//! | Dir2DataEntry::decode/leaf dir data block (8 KiB)        | 2.96 µs  | 2.58 GiB/s    |
+//! | AttrLeafblock::decode/attr leaf block                    | 6.71 µs  | 582 MiB/s     |
+//! | BmbtRec::decode/bmbt leaf block (251 records)            | 2.92 µs  | 1.28 GiB/s    |
+//! | hashname/1                                               | 2.74 ns  | 365 Melem/s   |
+//! | hashname/4                                               | 5.70 ns  | 175 Melem/s   |
+//! | hashname/16                                              | 11.8 ns  | 85.0 Melem/s  |
+//! | hashname/64                                              | 47.1 ns  | 21.2 Melem/s  |
+//! | hashname/255                                             | 178 ns   | 5.62 Melem/s  |
+//! | Dir2LeafNDisk::get_address_range/every hash in a leaf... | 7.10 µs  | 54.4 Melem/s  |
+use std::{
+    ffi::OsStr,
+    io::{Cursor, Read, Seek, SeekFrom},
+    os::unix::{ffi::OsStrExt, fs::FileExt},
+    path::Path,
+    sync::OnceLock,
+};
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+// Bench targets are built with cfg(test) but without the test harness, so the unit tests' helpers
+// and imports go unused.
+#[allow(dead_code, deprecated, unused_imports)]
+#[path = "../src/libxfuse/mod.rs"]
+mod libxfuse;
+
+use libxfuse::{
+    attr::AttrLeafblock,
+    block_reader::BlockReader,
+    bmbt_rec::BmbtRec,
+    da_btree::hashname,
+    dinode_core::DinodeCore,
+    dir3::{Dir2DataEntry, Dir2DataUnused, Dir2LeafEntry, Dir3DataHdr},
+    dir3_lf::Dir2LeafNDisk,
+    sb::Sb,
+    utils::{decode, decode_array},
+};
+
+/// Inode size of the golden images
+const INODESIZE: usize = 512;
+
+/// Size of a V5 long-format btree block header, which precedes the records in a bmbt leaf.
+const BMBT_LBLOCK_CRC_LEN: usize = 72;
+
+/// Size of a V5 directory leaf block header, which precedes its entries
+const DIR3_LEAF_HDR_LEN: usize = 64;
+
+fn fixture(name: &str) -> Vec<u8> {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("benches/fixtures")
+        .join(name);
+    std::fs::read(&path).unwrap_or_else(|e| panic!("Cannot read {}: {}", path.display(), e))
+}
+
+/// The golden image's superblock, which some decoders consult
+fn superblock() -> &'static Sb {
+    static SUPERBLOCK: OnceLock<Sb> = OnceLock::new();
+    SUPERBLOCK.get_or_init(|| Sb::from(&mut Cursor::new(fixture("sb.bin"))).unwrap())
+}
+
+fn dinode_core(c: &mut Criterion) {
+    let raw = fixture("inodes.bin");
+    let mut group = c.benchmark_group("DinodeCore::decode");
+    group.throughput(Throughput::Bytes(raw.len() as u64));
+    group.bench_function("inode block", |b| {
+        b.iter(|| {
+            for inode in raw.chunks(INODESIZE) {
+                black_box(decode::<DinodeCore>(black_box(inode)).unwrap());
+            }
+        })
+    });
+    group.finish();
+}
+
+fn dir2_data_entry(c: &mut Criterion) {
+    let sb = superblock();
+    let raw = fixture("dir-data.bin");
+    let mut group = c.benchmark_group("Dir2DataEntry::decode");
+    group.throughput(Throughput::Bytes(raw.len() as u64));
+    group.bench_function("leaf dir data block", |b| {
+        b.iter(|| {
+            // Walk the block the same way as Dir2Lf::next does
+            let mut offset = Dir3DataHdr::SIZE as usize;
+            while offset < raw.len() {
+                let freetag: u16 = decode(&raw[offset..]).unwrap().0;
+                if freetag == 0xffff {
+                    let (_, length) = decode::<Dir2DataUnused>(&raw[offset..]).unwrap();
+                    offset += length;
+                } else {
+                    let (entry, length) = Dir2DataEntry::decode(&raw[offset..], sb).unwrap();
+                    black_box(entry);
+                    offset += length;
+                }
+            }
+        })
+    });
+    group.finish();
+}
+
+fn attr_leafblock(c: &mut Criterion) {
+    let raw = fixture("attr-leaf.bin");
+    let mut group = c.benchmark_group("AttrLeafblock::decode");
+    group.throughput(Throughput::Bytes(raw.len() as u64));
+    group.bench_function("attr leaf block", |b| {
+        b.iter(|| black_box(AttrLeafblock::from_block(black_box(&raw)).unwrap()))
+    });
+    group.finish();
+}
+
+fn bmbt_rec(c: &mut Criterion) {
+    let raw = fixture("bmbt-leaf.bin");
+    let numrecs = usize::from(u16::from_be_bytes([raw[6], raw[7]]));
+    let recs = &raw[BMBT_LBLOCK_CRC_LEN..BMBT_LBLOCK_CRC_LEN + numrecs * 16];
+    let mut group = c.benchmark_group("BmbtRec::decode");
+    group.throughput(Throughput::Bytes(recs.len() as u64));
+    group.bench_function("bmbt leaf block", |b| {
+        b.iter(|| {
+            for rec in recs.chunks(16) {
+                black_box(decode::<BmbtRec>(black_box(rec)).unwrap());
+            }
+        })
+    });
+    group.finish();
+}
+
+fn hashname_lengths(c: &mut Criterion) {
+    let mut group = c.benchmark_group("hashname");
+    group.throughput(Throughput::Elements(1));
+    for len in [1, 4, 16, 64, 255] {
+        let name = vec![b'x'; len];
+        group.bench_with_input(BenchmarkId::from_parameter(len), &name, |b, name| {
+            b.iter(|| black_box(hashname(OsStr::from_bytes(black_box(name)))))
+        });
+    }
+    group.finish();
+}
+
+fn get_address_range(c: &mut Criterion) {
+    let raw = fixture("dir-leaf.bin");
+    let leaf = Dir2LeafNDisk::from_block(&raw).unwrap();
+    let hashes = leaf.ents.iter().map(|e| e.hashval).collect::<Vec<_>>();
+    let mut group = c.benchmark_group("Dir2LeafNDisk::get_address_range");
+    group.throughput(Throughput::Elements(hashes.len() as u64));
+    group.bench_function("every hash in a leaf block", |b| {
+        b.iter(|| {
+            for hash in hashes.iter() {
+                black_box(leaf.get_address_range(black_box(*hash)));
+            }
+        })
+    });
+    group.finish();
+}
+
+/// Compare bincode against the hand-rolled parser for a directory leaf block's entries
+fn dir2_leaf_entry(c: &mut Criterion) {
+    let raw = fixture("dir-leaf.bin");
+    let count = Dir2LeafNDisk::from_block(&raw).unwrap().ents.len();
+    let ents = &raw[DIR3_LEAF_HDR_LEN..DIR3_LEAF_HDR_LEN + count * 8];
+    let mut group = c.benchmark_group("Dir2LeafEntry");
+    group.throughput(Throughput::Bytes(ents.len() as u64));
+    group.bench_function("Decode", |b| {
+        b.iter(|| {
+            for ent in ents.chunks(8) {
+                black_box(decode::<Dir2LeafEntry>(black_box(ent)).unwrap());
+            }
+        })
+    });
+    group.bench_function("FromBytes", |b| {
+        b.iter(|| black_box(decode_array::<Dir2LeafEntry>(black_box(ents), count).unwrap()))
+    });
+    group.finish();
+}
+
+/// Compare a memory-mapped image against sector-aligned reads, reading a file's data sequentially
+/// one block at a time, as Volume::do_read does
+fn block_reader(c: &mut Criterion) {
+    const BLOCKSIZE: usize = 4096;
+    const FSIZE: usize = 16 << 20;
+    let f = tempfile::NamedTempFile::new().unwrap();
+    f.as_file().write_all_at(&vec![0xaa; FSIZE], 0).unwrap();
+    let mut group = c.benchmark_group("BlockReader");
+    group.throughput(Throughput::Bytes(FSIZE as u64));
+    for mapped in [true, false] {
+        let mut br = BlockReader::open(f.path()).unwrap();
+        if !mapped {
+            br.unmap();
+        }
+        br.set_bufsize(BLOCKSIZE);
+        let mut buf = vec![0u8; BLOCKSIZE];
+        let name = if mapped {
+            "sequential/mmap"
+        } else {
+            "sequential/read"
+        };
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                for ofs in (0..FSIZE).step_by(BLOCKSIZE) {
+                    br.seek(SeekFrom::Start(ofs as u64)).unwrap();
+                    br.read_exact(&mut buf).unwrap();
+                    black_box(&buf);
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    dinode_core,
+    dir2_data_entry,
+    attr_leafblock,
+    bmbt_rec,
+    hashname_lengths,
+    get_address_range,
+    dir2_leaf_entry,
+    block_reader
+);
+criterion_main!(benches);
@@ -16,6 +16,7 @@ use function_name::named;
 use tempfile::tempdir;
 use xattr::FileExt;
 
+#[allow(dead_code)]
 mod util {
     include!("../tests/util.rs");
 }
@@ -110,6 +111,7 @@ impl Bench {
 const BENCHES: &[Bench] = &[
     Bench::new("metadata-sf", Image::Golden4K, stat_sf),
     Bench::new("metadata-block", Image::Golden4K, stat_block),
+    Bench::new("lookup-block", Image::Golden4K, lookup_block),
     Bench::new("metadata-leaf1k", Image::Golden1K, stat_leaf_1k),
     Bench::new("metadata-leaf4k", Image::Golden4K, stat_leaf_4k),
     Bench::new("metadata-node1", Image::Golden1K, stat_node1),
@@ -123,6 +125,7 @@ const BENCHES: &[Bench] = &[
     Bench::new("getxattr-local", Image::Golden4K, get_local_xattrs),
     Bench::new("getxattr-extents", Image::Golden4K, get_extents_xattrs),
     Bench::new("getxattr-btree", Image::Golden1K, get_btree_xattrs),
+    Bench::new("xattr-probe-leaf4k", Image::Golden4K, probe_xattrs_leaf_4k),
 ];
 
 fn stat_files(path: &Path, inode_size: u64) -> u64 {
@@ -146,6 +149,12 @@ fn stat_block(mountpoint: &Path) -> u64 {
     stat_files(&mountpoint.join("block"), 512)
 }
 
+/// Look up a single file in the block directory, without listing it
+fn lookup_block(mountpoint: &Path) -> u64 {
+    let _ = std::fs::metadata(mountpoint.join("block").join("frame000000")).unwrap();
+    512
+}
+
 /// Read all metadata from all files in the leaf directory
 fn stat_leaf_1k(mountpoint: &Path) -> u64 {
     stat_files(&mountpoint.join("leaf"), 512)
@@ -253,6 +262,21 @@ fn get_btree_xattrs(mountpoint: &Path) -> u64 {
     )
 }
 
+/// Probe for missing extended attributes on every file in the leaf directory, like `cp -a` does
+fn probe_xattrs_leaf_4k(mountpoint: &Path) -> u64 {
+    let mut nfiles = 0;
+    for entry in std::fs::read_dir(mountpoint.join("leaf")).unwrap() {
+        let path = entry.unwrap().path();
+        for name in ["user.DOSATTRIB", "user.mime_type"] {
+            for _ in 0..2 {
+                assert!(xattr::get(&path, name).unwrap().is_none());
+            }
+        }
+        nfiles += 1;
+    }
+    nfiles * 512
+}
+
 #[named]
 fn main() {
     require_fusefs!();
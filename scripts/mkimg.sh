@@ -472,9 +472,45 @@ mkfs_4kn() {
 	zstd -f resources/xfs_4kn.img
 }
 
+mkfs_64k() {
+	# Create an XFS image that uses 64KiB blocks, the largest allowed.
+	# Mounting it requires either a platform with 64KiB pages or Linux 6.12
+	# or later.
+	rm -f resources/xfs65536.img
+	truncate -s 512m resources/xfs65536.img
+	mkfs.xfs --unsupported -b size=65536 -f resources/xfs65536.img
+	MNTDIR=`mktemp -d`
+	mount -t xfs resources/xfs65536.img $MNTDIR
+
+	# With 64k blocks and 64k directories
+	# nfiles	namelen	format
+	# 2		11	sf
+	# 1024		11	block
+	# 4096		11	leaf
+	# 16384		11	node
+	mkfiles ${MNTDIR}/sf 2
+	mkfiles ${MNTDIR}/block 1024
+	mkfiles ${MNTDIR}/leaf 4096
+	mkfiles ${MNTDIR}/node 16384
+
+	mkdir ${MNTDIR}/xattrs
+	mkattrs ${MNTDIR}/xattrs/local 4 0
+	mkattrs ${MNTDIR}/xattrs/extents1024 1024 0
+
+	mkdir ${MNTDIR}/files
+	write_sequential_file ${MNTDIR}/files/large_extent.txt 1048576
+	write_fragmented_file ${MNTDIR}/files/four_extents.txt 65536 4
+	write_fragmented_file ${MNTDIR}/files/btree.txt 65536 64
+
+	umount ${MNTDIR}
+	rmdir $MNTDIR
+	zstd -f resources/xfs65536.img
+}
+
 mkfs_4096
 mkfs_512
 mkfs_v4
 mkfs_preallocated
 mkfs_noftype
 mkfs_4kn
+mkfs_64k
@@ -0,0 +1,75 @@
+/*
+ * BSD 2-Clause License
+ *
+ * Copyright (c) 2021, Khaled Emara
+ * All rights reserved.
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice, this
+ *    list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice,
+ *    this list of conditions and the following disclaimer in the documentation
+ *    and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+ * AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+ * IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+ * FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+ * SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+ * CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+ * OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+ * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ */
+//! Read XFS file systems without the kernel's help, from a device or an image file.
+//!
+//! A [`Volume`] decodes the file system lazily, caching what it reads.  Its inodes are numbered
+//! from [`ROOT_INO`], the root directory, and can be found by path with [`Volume::resolve`].
+//!
+//! ```no_run
+//! use std::path::Path;
+//!
+//! use xfuse::{FileType, Options, Volume};
+//!
+//! # fn main() -> std::io::Result<()> {
+//! let mut vol = Volume::open(Path::new("xfs.img"), Options::default())?;
+//! let dir = vol.resolve("/etc")?;
+//! for entry in vol.read_dir(dir)? {
+//!     if entry.kind == FileType::RegularFile {
+//!         let attr = vol.getattr(entry.ino)?;
+//!         let head = vol.read(entry.ino, 0, 64)?;
+//!         println!("{:?}: {} bytes, starting {:?}", entry.name, attr.size, head);
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! With the `fuse` feature, which is on by default, a [`Volume`] is also a FUSE file system,
+//! which is how the `xfs-fuse` daemon uses it.  Without that feature, nothing depends on FUSE.
+
+// The decoders are public so that the xfs-fuse executable, benchmarks, and tests can reach them,
+// but only the re-exports below are meant to be stable.
+#[doc(hidden)]
+pub mod libxfuse;
+
+pub use libxfuse::{
+    attr::Attr,
+    block_reader::BlockReader,
+    dinode::Dinode,
+    dir3::Dir3,
+    sb::Sb,
+    stat::{FileAttr, FileType, ROOT_INO},
+    stats::Stats,
+    volume::{DirEntry, Options, Volume},
+};
+
+// Lets unit tests count their heap allocations
+#[cfg(test)]
+#[global_allocator]
+static ALLOCATOR: libxfuse::alloc_count::CountingAllocator =
+    libxfuse::alloc_count::CountingAllocator;
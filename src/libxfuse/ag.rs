@@ -0,0 +1,318 @@
+/*
+ * BSD 2-Clause License
+ *
+ * Copyright (c) 2021, Khaled Emara
+ * All rights reserved.
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice, this
+ *    list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice,
+ *    this list of conditions and the following disclaimer in the documentation
+ *    and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+ * AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+ * IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+ * FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+ * SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+ * CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+ * OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+ * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ */
+use std::{
+    ffi::OsStr,
+    io::{self, prelude::*, SeekFrom},
+    path::Path,
+};
+
+use super::{
+    block_reader::BlockReader,
+    definitions::*,
+    list::write_json_str,
+    sb::Sb,
+    utils::Uuid,
+    validate::crc_ok,
+};
+
+const XFS_AGF_VERSION: u32 = 1;
+const XFS_AGI_VERSION: u32 = 1;
+
+/// Byte offsets of the fields that the AGF and AGI have in common
+const HDR_MAGIC: usize = 0;
+const HDR_VERSION: usize = 4;
+const HDR_SEQNO: usize = 8;
+const HDR_LENGTH: usize = 12;
+
+/// Byte offsets of the V5-only fields of each header
+const AGF_UUID: usize = 64;
+const AGF_CRC: usize = 216;
+const AGI_UUID: usize = 296;
+const AGI_CRC: usize = 312;
+const AGFL_SEQNO: usize = 4;
+const AGFL_UUID: usize = 8;
+const AGFL_CRC: usize = 32;
+
+fn be32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+/// The health of one allocation group's headers: the AGF, AGI, and AGFL.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct AgStatus {
+    pub agno:     XfsAgnumber,
+    /// Human-readable descriptions of everything wrong with the headers.  Empty if they're fine.
+    pub problems: Vec<String>,
+}
+
+impl AgStatus {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    /// Read and validate the headers of allocation group `agno`.
+    pub fn check<R: Read + Seek>(reader: &mut R, sb: &Sb, agno: XfsAgnumber) -> Self {
+        let mut status = AgStatus {
+            agno,
+            problems: Vec::new(),
+        };
+        // The superblock, AGF, AGI, and AGFL each occupy one sector at the start of the AG.
+        let sectsize = usize::from(sb.sb_sectsize);
+        let start = sb.fsb_to_offset(u64::from(agno) << sb.sb_agblklog) + sectsize as u64;
+        let mut buf = vec![0u8; 3 * sectsize];
+        if let Err(e) = reader
+            .seek(SeekFrom::Start(start))
+            .and_then(|_| reader.read_exact(&mut buf))
+        {
+            status.problems.push(format!("cannot read headers: {}", e));
+            return status;
+        }
+        let (agf, rest) = buf.split_at(sectsize);
+        let (agi, agfl) = rest.split_at(sectsize);
+        let v5 = sb.version() >= 5;
+
+        status.check_hdr(sb, "AGF", agf, XFS_AGF_MAGIC, XFS_AGF_VERSION);
+        status.check_hdr(sb, "AGI", agi, XFS_AGI_MAGIC, XFS_AGI_VERSION);
+        if v5 {
+            status.check_v5(sb, "AGF", agf, AGF_UUID, AGF_CRC);
+            status.check_v5(sb, "AGI", agi, AGI_UUID, AGI_CRC);
+            // Before V5, the AGFL had no header at all.
+            let magic = be32(agfl, HDR_MAGIC);
+            if magic != XFS_AGFL_MAGIC {
+                status
+                    .problems
+                    .push(format!("AGFL: bad magic {:#x}", magic));
+            }
+            let seqno = be32(agfl, AGFL_SEQNO);
+            if seqno != agno {
+                status.problems.push(format!("AGFL: bad seqno {}", seqno));
+            }
+            status.check_v5(sb, "AGFL", agfl, AGFL_UUID, AGFL_CRC);
+        }
+        status
+    }
+
+    /// Check the fields that the AGF and AGI have in common
+    fn check_hdr(&mut self, sb: &Sb, name: &str, hdr: &[u8], magic: u32, version: u32) {
+        let m = be32(hdr, HDR_MAGIC);
+        if m != magic {
+            self.problems.push(format!("{}: bad magic {:#x}", name, m));
+        }
+        let v = be32(hdr, HDR_VERSION);
+        if v != version {
+            self.problems.push(format!("{}: bad version {}", name, v));
+        }
+        let seqno = be32(hdr, HDR_SEQNO);
+        if seqno != self.agno {
+            self.problems.push(format!("{}: bad seqno {}", name, seqno));
+        }
+        let length = be32(hdr, HDR_LENGTH);
+        if u64::from(length) != sb.ag_length(self.agno) {
+            self.problems.push(format!(
+                "{}: bad length {}; expected {}",
+                name,
+                length,
+                sb.ag_length(self.agno)
+            ));
+        }
+    }
+
+    /// Check the UUID and CRC that V5 file systems add to every header
+    fn check_v5(&mut self, sb: &Sb, name: &str, hdr: &[u8], uuid_offset: usize, crc_offset: usize) {
+        let uuid = u128::from_be_bytes(hdr[uuid_offset..uuid_offset + 16].try_into().unwrap());
+        if Uuid::from_u128(uuid) != sb.sb_uuid {
+            self.problems.push(format!("{}: bad uuid", name));
+        }
+        if !crc_ok(hdr, crc_offset) {
+            self.problems.push(format!("{}: bad crc", name));
+        }
+    }
+}
+
+/// Check the headers of every allocation group.
+pub fn check_all<R: Read + Seek>(reader: &mut R, sb: &Sb) -> Vec<AgStatus> {
+    (0..sb.sb_agcount)
+        .map(|agno| AgStatus::check(reader, sb, agno))
+        .collect()
+}
+
+/// Check every allocation group's headers without mounting the file system, and print the result
+/// as JSON.  Return true if they're all healthy.
+pub fn check<W: Write>(device: &Path, no_mmap: bool, mut out: W) -> io::Result<bool> {
+    let mut reader = BlockReader::open(device)?;
+    if no_mmap {
+        reader.unmap();
+    }
+    let sb = Sb::from(reader.by_ref())
+        .map_err(|e| io::Error::other(format!("{}: {}", device.display(), e)))?;
+    let statuses = check_all(&mut reader, &sb);
+
+    let ro_compat = sb
+        .ro_compat_names()
+        .iter()
+        .map(|name| format!("\"{}\"", name))
+        .collect::<Vec<_>>()
+        .join(",");
+    write!(
+        out,
+        "{{\"agcount\":{},\"unit\":{},\"width\":{},\"ro_compat\":[{}],\"ags\":[",
+        sb.sb_agcount, sb.sb_unit, sb.sb_width, ro_compat
+    )?;
+    for (i, status) in statuses.iter().enumerate() {
+        if i > 0 {
+            out.write_all(b",")?;
+        }
+        write!(
+            out,
+            "{{\"agno\":{},\"ok\":{},\"problems\":[",
+            status.agno,
+            status.is_ok()
+        )?;
+        for (j, problem) in status.problems.iter().enumerate() {
+            if j > 0 {
+                out.write_all(b",")?;
+            }
+            write_json_str(&mut out, OsStr::new(problem))?;
+        }
+        out.write_all(b"]}")?;
+    }
+    out.write_all(b"]}\n")?;
+    out.flush()?;
+    Ok(statuses.iter().all(AgStatus::is_ok))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use rstest::rstest;
+
+    use super::*;
+    use crate::libxfuse::validate::set_crc;
+
+    const SECTSIZE: usize = 512;
+
+    /// Three AGs of 16 4 KiB blocks each, except the last which is only 8
+    fn sb(version: u16) -> Sb {
+        let mut sb = Sb::for_test(version, 3, 16, 40);
+        sb.sb_uuid = Uuid::from_u128(0x1234);
+        sb
+    }
+
+    /// Build an image with healthy AG headers
+    fn image(sb: &Sb) -> Vec<u8> {
+        let mut img = vec![0u8; (sb.sb_dblocks << sb.sb_blocklog) as usize];
+        let uuid = 0x1234u128.to_be_bytes();
+        for agno in 0..sb.sb_agcount {
+            let start = sb.fsb_to_offset(u64::from(agno) << sb.sb_agblklog) as usize;
+            let length = sb.ag_length(agno) as u32;
+            for (i, magic, uuid_offset, crc_offset) in [
+                (1, XFS_AGF_MAGIC, AGF_UUID, AGF_CRC),
+                (2, XFS_AGI_MAGIC, AGI_UUID, AGI_CRC),
+            ] {
+                let hdr = &mut img[start + i * SECTSIZE..start + (i + 1) * SECTSIZE];
+                hdr[HDR_MAGIC..HDR_MAGIC + 4].copy_from_slice(&magic.to_be_bytes());
+                hdr[HDR_VERSION..HDR_VERSION + 4].copy_from_slice(&1u32.to_be_bytes());
+                hdr[HDR_SEQNO..HDR_SEQNO + 4].copy_from_slice(&agno.to_be_bytes());
+                hdr[HDR_LENGTH..HDR_LENGTH + 4].copy_from_slice(&length.to_be_bytes());
+                if sb.version() >= 5 {
+                    hdr[uuid_offset..uuid_offset + 16].copy_from_slice(&uuid);
+                    set_crc(hdr, crc_offset);
+                }
+            }
+            if sb.version() >= 5 {
+                let agfl = &mut img[start + 3 * SECTSIZE..start + 4 * SECTSIZE];
+                agfl[HDR_MAGIC..HDR_MAGIC + 4].copy_from_slice(&XFS_AGFL_MAGIC.to_be_bytes());
+                agfl[AGFL_SEQNO..AGFL_SEQNO + 4].copy_from_slice(&agno.to_be_bytes());
+                agfl[AGFL_UUID..AGFL_UUID + 16].copy_from_slice(&uuid);
+                set_crc(agfl, AGFL_CRC);
+            }
+        }
+        img
+    }
+
+    #[rstest]
+    fn healthy(#[values(4, 5)] version: u16) {
+        let sb = sb(version);
+        let mut cursor = Cursor::new(image(&sb));
+        let statuses = check_all(&mut cursor, &sb);
+        assert_eq!(statuses.len(), 3);
+        for (agno, status) in statuses.iter().enumerate() {
+            assert_eq!(status.agno, agno as XfsAgnumber);
+            assert!(status.is_ok(), "{:?}", status);
+        }
+    }
+
+    /// Corrupt one byte of AG 1's headers.  Only AG 1 should be reported.
+    #[rstest]
+    #[case::agf_magic(4, SECTSIZE, "AGF: bad magic 0xa7414746")]
+    #[case::agf_version(4, SECTSIZE + HDR_VERSION + 3, "AGF: bad version 254")]
+    #[case::agi_seqno(4, 2 * SECTSIZE + HDR_SEQNO + 3, "AGI: bad seqno 254")]
+    #[case::agf_length(4, SECTSIZE + HDR_LENGTH + 3, "AGF: bad length 239; expected 16")]
+    #[case::agf_crc(5, SECTSIZE + AGF_CRC, "AGF: bad crc")]
+    #[case::agi_uuid(5, 2 * SECTSIZE + AGI_UUID, "AGI: bad uuid")]
+    #[case::agfl_magic(5, 3 * SECTSIZE, "AGFL: bad magic 0xa741464c")]
+    fn corrupt(#[case] version: u16, #[case] offset: usize, #[case] expected: &str) {
+        let sb = sb(version);
+        let mut img = image(&sb);
+        let ag1 = sb.fsb_to_offset(1 << sb.sb_agblklog) as usize;
+        img[ag1 + offset] ^= 0xff;
+        let statuses = check_all(&mut Cursor::new(img), &sb);
+        assert!(statuses[0].is_ok());
+        assert!(statuses[2].is_ok());
+        assert!(
+            statuses[1].problems.iter().any(|p| p == expected),
+            "{:?}",
+            statuses[1]
+        );
+    }
+
+    /// V4 file systems have no CRCs, so garbage there is harmless.
+    #[test]
+    fn v4_ignores_crc() {
+        let sb = sb(4);
+        let mut img = image(&sb);
+        let ag1 = sb.fsb_to_offset(1 << sb.sb_agblklog) as usize;
+        img[ag1 + SECTSIZE + AGF_CRC] ^= 0xff;
+        img[ag1 + 3 * SECTSIZE] ^= 0xff;
+        assert!(check_all(&mut Cursor::new(img), &sb)
+            .iter()
+            .all(AgStatus::is_ok));
+    }
+
+    #[test]
+    fn truncated() {
+        let sb = sb(4);
+        let mut img = image(&sb);
+        img.truncate((32 << sb.sb_blocklog) + 2 * SECTSIZE);
+        let statuses = check_all(&mut Cursor::new(img), &sb);
+        assert!(statuses[0].is_ok());
+        assert!(statuses[1].is_ok());
+        assert!(statuses[2].problems[0].starts_with("cannot read headers"));
+    }
+}
@@ -28,6 +28,8 @@
 use std::{
     ffi::OsStr,
     io::{BufRead, Seek, SeekFrom},
+    mem,
+    os::unix::ffi::OsStrExt,
 };
 
 use bincode::{
@@ -41,18 +43,20 @@ use super::{
     attr_leaf::AttrLeaf,
     attr_node::AttrNode,
     bmbt_rec::Bmx,
-    da_btree::{XfsDa3Blkinfo, XfsDa3Intnode, XfsDaBlkinfo},
+    da_btree::{self, hashname, XfsDa3Blkinfo, XfsDa3Intnode, XfsDaBlkinfo},
     definitions::{
         XfsDablk,
         XfsFsblock,
         XFS_ATTR3_LEAF_MAGIC,
+        XFS_ATTR3_RMT_MAGIC,
         XFS_ATTR_LEAF_MAGIC,
         XFS_DA3_NODE_MAGIC,
         XFS_DA_NODE_MAGIC,
+        XFS_XATTR_SIZE_MAX,
     },
     sb::Sb,
-    utils,
-    volume::SUPERBLOCK,
+    utils::{self, FromBytes},
+    validate,
 };
 
 #[allow(dead_code)]
@@ -68,20 +72,58 @@ mod constants {
     pub const XFS_ATTR_NSP_ONDISK_MASK: u8 = XFS_ATTR_ROOT | XFS_ATTR_SECURE;
 }
 
-pub const fn get_namespace_from_flags(flags: u8) -> &'static [u8] {
-    if flags & constants::XFS_ATTR_SECURE != 0 {
-        b"secure."
-    } else if flags & constants::XFS_ATTR_ROOT != 0 {
-        b"trusted."
-    } else {
-        b"user."
-    }
+/// Each namespace's on-disk flags, and the prefix that its attributes' names are given.  Linux
+/// calls XFS's secure namespace "security".  FreeBSD has no trusted namespace, but only root may
+/// use its system namespace, like trusted.  The user namespace matches any flags, so it's last.
+#[cfg(target_os = "linux")]
+const NAMESPACES: [(u8, &[u8]); 3] = [
+    (constants::XFS_ATTR_SECURE, b"security."),
+    (constants::XFS_ATTR_ROOT, b"trusted."),
+    (0, b"user."),
+];
+#[cfg(target_os = "freebsd")]
+const NAMESPACES: [(u8, &[u8]); 3] = [
+    (constants::XFS_ATTR_SECURE, b"secure."),
+    (constants::XFS_ATTR_ROOT, b"system."),
+    (0, b"user."),
+];
+#[cfg(not(any(target_os = "linux", target_os = "freebsd")))]
+const NAMESPACES: [(u8, &[u8]); 3] = [
+    (constants::XFS_ATTR_SECURE, b"secure."),
+    (constants::XFS_ATTR_ROOT, b"trusted."),
+    (0, b"user."),
+];
+
+pub fn get_namespace_from_flags(flags: u8) -> &'static [u8] {
+    NAMESPACES
+        .iter()
+        .find(|(ns, _)| flags & ns == *ns)
+        .map(|(_, prefix)| *prefix)
+        .unwrap()
 }
 
-pub const fn get_namespace_size_from_flags(flags: u8) -> u32 {
+pub fn get_namespace_size_from_flags(flags: u8) -> u32 {
     get_namespace_from_flags(flags).len() as u32
 }
 
+/// Split an extended attribute's name, as it's listed, into its namespace's on-disk flags and the
+/// name proper.
+pub fn parse_name(name: &OsStr) -> Result<(u8, &OsStr), libc::c_int> {
+    NAMESPACES
+        .iter()
+        .find_map(|(ns, prefix)| {
+            let attr_name = name.as_bytes().strip_prefix(*prefix)?;
+            Some((*ns, OsStr::from_bytes(attr_name)))
+        })
+        // An attribute outside of any namespace can't exist
+        .ok_or(libc::ENOATTR)
+}
+
+/// Is an entry with these flags and name the attribute `attr_name` in namespace `ns`?
+pub fn name_matches(flags: u8, name: &[u8], ns: u8, attr_name: &OsStr) -> bool {
+    flags & constants::XFS_ATTR_NSP_ONDISK_MASK == ns && name == attr_name.as_bytes()
+}
+
 #[derive(Debug, Decode)]
 pub struct AttrLeafMap {
     _base: u16,
@@ -123,7 +165,7 @@ impl Decode for AttrLeafHdr {
 }
 impl_borrow_decode!(AttrLeafHdr);
 
-#[derive(Debug, Decode)]
+#[derive(Debug, Decode, PartialEq)]
 pub struct AttrLeafEntry {
     pub hashval: u32,
     pub nameidx: u16,
@@ -131,6 +173,19 @@ pub struct AttrLeafEntry {
     _pad2:       u8,
 }
 
+impl FromBytes for AttrLeafEntry {
+    const SIZE: usize = 8;
+
+    fn from_bytes(raw: &[u8]) -> Self {
+        AttrLeafEntry {
+            hashval: u32::from_be_bytes(raw[0..4].try_into().unwrap()),
+            nameidx: u16::from_be_bytes(raw[4..6].try_into().unwrap()),
+            flags:   raw[6],
+            _pad2:   raw[7],
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct AttrLeafNameLocal {
     pub namelen: u8,
@@ -169,14 +224,31 @@ impl AttrLeafName {
         }
     }
 
-    fn value<F, R>(&mut self, buf_reader: &mut R, map_dblock: F) -> &[u8]
+    /// The length of the value, without reading it if it's remote
+    fn value_len(&self) -> Result<u32, i32> {
+        match self {
+            AttrLeafName::Local(local) => {
+                Ok((local.nameval.len() - usize::from(local.namelen)) as u32)
+            }
+            AttrLeafName::Remote(remote) => remote.checked_len(),
+        }
+    }
+
+    fn value<F, R>(
+        &mut self,
+        buf_reader: &mut R,
+        super_block: &Sb,
+        map_dblock: F,
+    ) -> Result<&[u8], i32>
     where
         R: BufRead + Reader + Seek,
         F: Fn(XfsDablk, &mut R) -> XfsFsblock,
     {
         match self {
-            AttrLeafName::Local(local) => &local.nameval[local.namelen as usize..],
-            AttrLeafName::Remote(remote) => remote.value(buf_reader.by_ref(), map_dblock),
+            AttrLeafName::Local(local) => Ok(&local.nameval[local.namelen as usize..]),
+            AttrLeafName::Remote(remote) => {
+                remote.value(buf_reader.by_ref(), super_block, map_dblock)
+            }
         }
     }
 }
@@ -191,6 +263,48 @@ pub struct AttrLeafblock {
 }
 
 impl AttrLeafblock {
+    /// Decode a leaf from a whole file system block.
+    pub fn from_block(raw: &[u8]) -> Result<Self, DecodeError> {
+        let (hdr, hdrlen) = utils::decode::<AttrLeafHdr>(raw)?;
+        let count = usize::from(hdr.count);
+        utils::check_count::<AttrLeafEntry>("Attribute leaf block", count, raw.len() - hdrlen)?;
+        let entries: Vec<AttrLeafEntry> = utils::decode_array(&raw[hdrlen..], count)?;
+
+        let mut names = Vec::with_capacity(entries.len());
+        for e in entries.iter() {
+            let ofs = usize::from(e.nameidx);
+            if e.flags & constants::XFS_ATTR_LOCAL != 0 {
+                let local = utils::decode(&raw[ofs..])?.0;
+                names.push(AttrLeafName::Local(local));
+            } else {
+                let remote = utils::decode(&raw[ofs..])?.0;
+                names.push(AttrLeafName::Remote(remote));
+            }
+        }
+
+        Ok(AttrLeafblock {
+            hdr,
+            entries,
+            names,
+        })
+    }
+
+    /// Roughly how many bytes of memory the decoded leaf occupies
+    pub fn cache_bytes(&self) -> usize {
+        let names: usize = self
+            .names
+            .iter()
+            .map(|name| match name {
+                AttrLeafName::Local(local) => local.nameval.len(),
+                AttrLeafName::Remote(remote) => remote.name.len() + remote.value.len(),
+            })
+            .sum();
+        mem::size_of::<Self>()
+            + self.entries.len() * mem::size_of::<AttrLeafEntry>()
+            + self.names.len() * mem::size_of::<AttrLeafName>()
+            + names
+    }
+
     pub fn get_total_size(&self) -> u32 {
         let mut total: u32 = 0;
 
@@ -209,55 +323,51 @@ impl AttrLeafblock {
         }
     }
 
+    /// Get the value of the attribute `name` in namespace `ns`
     pub fn get<R: BufRead + Reader + Seek, F: Fn(XfsDablk, &mut R) -> XfsFsblock>(
         &mut self,
         buf_reader: &mut R,
-        hash: u32,
+        super_block: &Sb,
+        ns: u8,
+        name: &OsStr,
         map_logical_block_to_fs_block: F,
     ) -> Result<&[u8], i32> {
-        match self
-            .entries
-            .binary_search_by_key(&hash, |entry| entry.hashval)
-        {
-            Ok(i) => Ok(self.names[i].value(buf_reader, map_logical_block_to_fs_block)),
-            Err(_) => Err(libc::ENOATTR),
-        }
+        let i = self.find(ns, name)?;
+        self.names[i].value(buf_reader, super_block, map_logical_block_to_fs_block)
     }
-}
 
-impl Decode for AttrLeafblock {
-    fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError> {
-        let blocksize = SUPERBLOCK.get().unwrap().sb_blocksize as usize;
-        let mut raw = vec![0u8; blocksize];
-        decoder.reader().read(&mut raw[..])?;
-
-        let config = decoder.config();
-        let sl = bincode::de::read::SliceReader::new(&raw);
-        let mut sldecoder = bincode::de::DecoderImpl::new(sl, *config);
-        let hdr: AttrLeafHdr = Decode::decode(&mut sldecoder)?;
-
-        let mut entries = Vec::<AttrLeafEntry>::with_capacity(hdr.count.into());
-        for _i in 0..entries.capacity() {
-            entries.push(Decode::decode(&mut sldecoder)?);
-        }
+    /// Like [`get`](Self::get), but only return the value's length
+    pub fn value_len(&self, ns: u8, name: &OsStr) -> Result<u32, i32> {
+        self.find(ns, name).and_then(|i| self.names[i].value_len())
+    }
 
-        let mut names = Vec::with_capacity(entries.len());
-        for e in entries.iter() {
-            let ofs = usize::from(e.nameidx);
-            if e.flags & constants::XFS_ATTR_LOCAL != 0 {
-                let local = bincode::decode_from_slice(&raw[ofs..], *config)?.0;
-                names.push(AttrLeafName::Local(local));
-            } else {
-                let remote = bincode::decode_from_slice(&raw[ofs..], *config)?.0;
-                names.push(AttrLeafName::Remote(remote));
-            }
-        }
+    /// Could the attribute with this hash be in a later leaf block?  True if this block is empty,
+    /// as it may be after heavy attribute churn, or if every entry's hash is below it.
+    pub fn continues_past(&self, hash: u32) -> bool {
+        self.hdr.forw != 0
+            && self
+                .entries
+                .last()
+                .map(|entry| entry.hashval < hash)
+                .unwrap_or(true)
+    }
 
-        Ok(AttrLeafblock {
-            hdr,
-            entries,
-            names,
-        })
+    /// Does this block contain an entry with the given hash?
+    pub fn contains(&self, hash: u32) -> bool {
+        self.entries
+            .binary_search_by_key(&hash, |entry| entry.hashval)
+            .is_ok()
+    }
+
+    /// Return the index of the attribute `name` in namespace `ns`.  Names in different namespaces,
+    /// or that merely collide, share a hash, so check every entry with it.
+    fn find(&self, ns: u8, name: &OsStr) -> Result<usize, i32> {
+        let hash = hashname(name);
+        let first = self.entries.partition_point(|entry| entry.hashval < hash);
+        (first..self.entries.len())
+            .take_while(|i| self.entries[*i].hashval == hash)
+            .find(|i| name_matches(self.entries[*i].flags, self.names[*i].name(), ns, name))
+            .ok_or(libc::ENOATTR)
     }
 }
 
@@ -271,31 +381,85 @@ pub struct AttrLeafNameRemote {
 }
 
 impl AttrLeafNameRemote {
-    fn value<R, F>(&mut self, buf_reader: &mut R, map_dblock: F) -> &[u8]
+    /// The most that will be read from disk in a single operation
+    const MAX_READ: usize = 1 << 20;
+
+    /// The length of the value.  It comes straight from disk, so a corrupt one could be as much
+    /// as 4 GiB.  Refuse anything longer than XFS allows, rather than trying to allocate it.
+    /// Decoding doesn't check it, so the rest of the leaf block stays readable.
+    fn checked_len(&self) -> Result<u32, i32> {
+        if self.valuelen > XFS_XATTR_SIZE_MAX {
+            validate::failure(format_args!(
+                "Remote attribute value length {} exceeds the maximum of {}",
+                self.valuelen, XFS_XATTR_SIZE_MAX
+            ))?;
+            return Err(libc::E2BIG);
+        }
+        Ok(self.valuelen)
+    }
+
+    fn value<R, F>(&mut self, buf_reader: &mut R, sb: &Sb, map_dblock: F) -> Result<&[u8], i32>
     where
         R: BufRead + Reader + Seek,
         F: Fn(XfsDablk, &mut R) -> XfsFsblock,
     {
-        if self.value.len() < self.valuelen as usize {
-            let sb = SUPERBLOCK.get().unwrap();
-            self.value.reserve(self.valuelen as usize);
-            let mut valueblk = self.valueblk;
-            let mut valuelen: i64 = self.valuelen.into();
-
-            while valuelen > 0 {
-                let blk_num = map_dblock(valueblk, buf_reader.by_ref());
+        if self.value.len() < self.checked_len()? as usize {
+            let blocksize = sb.sb_blocksize as usize;
+            // Only V5 file systems have a header in each remote value block
+            let hdrsize = if sb.version() == 5 {
+                AttrRmtHdr::SIZE
+            } else {
+                0
+            };
+            let valuelen = self.valuelen as usize;
+            let nblocks = valuelen.div_ceil(blocksize - hdrsize);
+
+            // Map every block up front, so physically contiguous ones can be read all at once.
+            let fsblocks = (0..nblocks as u32)
+                .map(|i| map_dblock(self.valueblk + i, buf_reader.by_ref()))
+                .collect::<Vec<_>>();
+
+            self.value.reserve(valuelen);
+            let mut scratch = Vec::new();
+            let mut i = 0;
+            while i < nblocks {
+                let mut run = 1;
+                while i + run < nblocks
+                    && (run + 1) * blocksize <= Self::MAX_READ
+                    && fsblocks[i + run] == fsblocks[i] + run as u64
+                {
+                    run += 1;
+                }
+                scratch.resize(run * blocksize, 0);
                 buf_reader
-                    .seek(SeekFrom::Start(sb.fsb_to_offset(blk_num)))
+                    .seek(SeekFrom::Start(sb.fsb_to_offset(fsblocks[i])))
                     .unwrap();
-                let hdr: AttrRmtHdr = utils::decode_from(buf_reader.by_ref()).unwrap();
-                let oldlen = self.value.len();
-                self.value.resize(oldlen + hdr.rm_bytes as usize, 0);
-                buf_reader.read_exact(&mut self.value[oldlen..]).unwrap();
-                valuelen -= i64::from(hdr.rm_bytes);
-                valueblk += 1;
+                buf_reader.read_exact(&mut scratch).unwrap();
+                for (j, block) in scratch.chunks_exact(blocksize).enumerate() {
+                    let bytes = if hdrsize > 0 {
+                        let hdr: AttrRmtHdr = utils::decode(block).unwrap().0;
+                        if validate::strict()
+                            && (hdr.rm_magic != XFS_ATTR3_RMT_MAGIC
+                                || !validate::crc_ok(block, AttrRmtHdr::CRC_OFFSET))
+                        {
+                            // Don't leave a partial value behind for the next call to extend.
+                            self.value.clear();
+                            validate::failure(format_args!(
+                                "Remote attribute block {} is corrupt",
+                                fsblocks[i] + j as u64
+                            ))?;
+                        }
+                        hdr.rm_bytes as usize
+                    } else {
+                        blocksize.min(valuelen - self.value.len())
+                    };
+                    self.value
+                        .extend_from_slice(&block[hdrsize..hdrsize + bytes]);
+                }
+                i += run;
             }
         }
-        &self.value[..]
+        Ok(&self.value[..])
     }
 }
 
@@ -320,7 +484,7 @@ impl Decode for AttrLeafNameRemote {
 
 #[derive(Debug, Decode)]
 struct AttrRmtHdr {
-    _rm_magic:  u32,
+    rm_magic:   u32,
     _rm_offset: u32,
     rm_bytes:   u32,
     _rm_crc:    u32,
@@ -330,6 +494,13 @@ struct AttrRmtHdr {
     _rm_lsn:    u64,
 }
 
+impl AttrRmtHdr {
+    /// Offset of the CRC, which covers the entire file system block
+    const CRC_OFFSET: usize = 12;
+    /// On-disk size in bytes
+    const SIZE: usize = 56;
+}
+
 #[enum_dispatch::enum_dispatch]
 pub trait Attr {
     fn get_total_size<R: BufRead + Reader + Seek>(
@@ -341,6 +512,8 @@ pub trait Attr {
     fn list<R: BufRead + Reader + Seek>(&mut self, buf_reader: &mut R, super_block: &Sb)
         -> Vec<u8>;
 
+    /// Get the value of the named attribute.  `name` is as [`list`](Self::list) gives it, with
+    /// its namespace's prefix.
     fn get<R>(
         &mut self,
         buf_reader: &mut R,
@@ -349,6 +522,24 @@ pub trait Attr {
     ) -> Result<Vec<u8>, libc::c_int>
     where
         R: BufRead + Reader + Seek;
+
+    /// Return the length of the named attribute's value.  Unlike [`get`](Self::get), this never
+    /// reads a remote value's blocks.
+    fn value_len<R>(
+        &mut self,
+        buf_reader: &mut R,
+        super_block: &Sb,
+        name: &OsStr,
+    ) -> Result<u32, libc::c_int>
+    where
+        R: BufRead + Reader + Seek;
+
+    /// Roughly how many bytes of memory the fork's cached entries and leaves occupy
+    fn cache_bytes(&self) -> usize;
+
+    /// Evict cached leaves, least recently used first, until the cache occupies at most `target`
+    /// bytes.  Forks whose cache can't be partly evicted keep all of it.
+    fn shrink_cache(&self, _target: usize) {}
 }
 
 /// Open an attribute block, whose type may be unknown until its contents are examined.
@@ -365,10 +556,11 @@ pub fn open<R: Reader + BufRead + Seek>(
         // What follows is either a xfs_da_blkinfo or a xfs_da3_blkinfo.  The first three fields
         // are the same.
         let magic: u16 = utils::decode(&raw[8..]).unwrap().0;
+        da_btree::check_uuid(superblock, &raw);
 
         match magic {
             XFS_ATTR_LEAF_MAGIC | XFS_ATTR3_LEAF_MAGIC => {
-                let leaf: AttrLeafblock = utils::decode(&raw).unwrap().0;
+                let leaf = AttrLeafblock::from_block(&raw).unwrap();
                 Attributes::Leaf(AttrLeaf {
                     bmx,
                     leaf,
@@ -376,7 +568,7 @@ pub fn open<R: Reader + BufRead + Seek>(
                 })
             }
             XFS_DA_NODE_MAGIC | XFS_DA3_NODE_MAGIC => {
-                let node: XfsDa3Intnode = utils::decode(&raw).unwrap().0;
+                let node = XfsDa3Intnode::from_block(&raw).unwrap();
                 Attributes::Node(AttrNode::new(bmx, node))
             }
             magic => {
@@ -399,3 +591,282 @@ pub enum Attributes {
     Node(AttrNode),
     Btree(crate::libxfuse::attr_bptree::AttrBtree),
 }
+
+#[cfg(test)]
+pub(super) mod tests {
+    use std::{
+        io::{BufReader, Cursor},
+        sync::Arc,
+    };
+
+    use proptest::prelude::*;
+    use rstest::rstest;
+
+    use super::*;
+    use crate::libxfuse::{
+        bmbt_rec::tests::superblock,
+        da_btree::hashname,
+        test_util::CountingReader,
+        validate::Validator,
+    };
+
+    const BLOCKSIZE: usize = 512;
+
+    /// A remote value should be read with one operation per physically contiguous run of blocks,
+    /// and reassembled correctly.
+    #[rstest]
+    #[case::v4_contiguous(4, &[10, 11, 12], 1)]
+    #[case::v4_fragmented(4, &[10, 11, 20], 2)]
+    #[case::v5_contiguous(5, &[10, 11, 12], 1)]
+    #[case::v5_fragmented(5, &[10, 20, 21], 2)]
+    fn remote_value(#[case] version: u16, #[case] fsblocks: &[u64], #[case] expected_reads: usize) {
+        let mut sb = Sb::default();
+        sb.sb_blocksize = BLOCKSIZE as u32;
+        sb.sb_blocklog = 9;
+        sb.sb_agblklog = 20;
+        sb.sb_agblocks = 1 << 20;
+        sb.set_version(version);
+        let hdrsize = if version == 5 { AttrRmtHdr::SIZE } else { 0 };
+
+        let value = (0..1200u32).map(|i| i as u8).collect::<Vec<_>>();
+        let mut image = vec![0u8; 32 * BLOCKSIZE];
+        for (chunk, fsblock) in value.chunks(BLOCKSIZE - hdrsize).zip(fsblocks) {
+            let ofs = *fsblock as usize * BLOCKSIZE;
+            if version == 5 {
+                image[ofs + 8..ofs + 12].copy_from_slice(&(chunk.len() as u32).to_be_bytes());
+            }
+            image[ofs + hdrsize..ofs + hdrsize + chunk.len()].copy_from_slice(chunk);
+        }
+        let (cr, counts) = CountingReader::new(image);
+        let mut br = BufReader::with_capacity(BLOCKSIZE, cr);
+
+        let mut remote = AttrLeafNameRemote {
+            valueblk: 0,
+            valuelen: value.len() as u32,
+            namelen:  0,
+            name:     Vec::new(),
+            value:    Vec::new(),
+        };
+        let v = remote
+            .value(&mut br, &sb, |dablk, _| fsblocks[dablk as usize])
+            .unwrap();
+        assert_eq!(v, &value[..]);
+        assert_eq!(counts.reads.get(), expected_reads);
+    }
+
+    /// In strict mode, each V5 remote value block's magic and CRC are checked, and a bad one is
+    /// EIO.  Lenient mode returns whatever it finds.
+    #[rstest]
+    #[case::lenient(false, true)]
+    #[case::strict_good(true, false)]
+    #[case::strict_bad(true, true)]
+    fn remote_value_crc(#[case] strict: bool, #[case] corrupt: bool) {
+        let mut sb = Sb::default();
+        sb.sb_blocksize = BLOCKSIZE as u32;
+        sb.sb_blocklog = 9;
+        sb.sb_agblklog = 20;
+        sb.sb_agblocks = 1 << 20;
+        sb.set_version(5);
+
+        let value = (0..1200u32).map(|i| i as u8).collect::<Vec<_>>();
+        let mut image = vec![0u8; 16 * BLOCKSIZE];
+        for (i, chunk) in value.chunks(BLOCKSIZE - AttrRmtHdr::SIZE).enumerate() {
+            let block = &mut image[(10 + i) * BLOCKSIZE..(11 + i) * BLOCKSIZE];
+            block[0..4].copy_from_slice(&XFS_ATTR3_RMT_MAGIC.to_be_bytes());
+            block[8..12].copy_from_slice(&(chunk.len() as u32).to_be_bytes());
+            block[AttrRmtHdr::SIZE..AttrRmtHdr::SIZE + chunk.len()].copy_from_slice(chunk);
+            validate::set_crc(block, AttrRmtHdr::CRC_OFFSET);
+        }
+        if corrupt {
+            image[11 * BLOCKSIZE + AttrRmtHdr::SIZE] ^= 0xff;
+        }
+        let mut br = BufReader::new(Cursor::new(image));
+
+        let mut remote = AttrLeafNameRemote {
+            valueblk: 0,
+            valuelen: value.len() as u32,
+            namelen:  0,
+            name:     Vec::new(),
+            value:    Vec::new(),
+        };
+        let validator = Arc::new(Validator::new(validate::Config { strict }));
+        validator.enter();
+        let r = remote.value(&mut br, &sb, |dablk, _| 10 + u64::from(dablk));
+        if strict && corrupt {
+            assert_eq!(r, Err(libc::EIO));
+            assert_eq!(validator.failures(), 1);
+            assert!(remote.value.is_empty());
+        } else {
+            assert_eq!(r.unwrap().len(), value.len());
+            assert_eq!(validator.failures(), 0);
+        }
+    }
+
+    /// Build a V4 attribute leaf block, the size of the global superblock's blocks, holding a
+    /// single remote attribute that claims a value of `valuelen` bytes
+    pub(in crate::libxfuse) fn remote_leaf(name: &str, valuelen: u32) -> Vec<u8> {
+        let blocksize = superblock().sb_blocksize as usize;
+        let mut blk = vec![0u8; blocksize];
+        blk[8..10].copy_from_slice(&XFS_ATTR_LEAF_MAGIC.to_be_bytes());
+        blk[12..14].copy_from_slice(&1u16.to_be_bytes());
+        let nameidx = blocksize - ((9 + name.len() + 3) & !3);
+        blk[32..36].copy_from_slice(&hashname(OsStr::new(name)).to_be_bytes());
+        blk[36..38].copy_from_slice(&(nameidx as u16).to_be_bytes());
+        blk[nameidx..nameidx + 4].copy_from_slice(&1u32.to_be_bytes());
+        blk[nameidx + 4..nameidx + 8].copy_from_slice(&valuelen.to_be_bytes());
+        blk[nameidx + 8] = name.len() as u8;
+        blk[nameidx + 9..nameidx + 9 + name.len()].copy_from_slice(name.as_bytes());
+        blk
+    }
+
+    /// A corrupt remote value length must be an error, without trying to allocate or read that
+    /// much.  Strict mode makes it EIO.
+    #[rstest]
+    #[case::lenient(false, libc::E2BIG)]
+    #[case::strict(true, libc::EIO)]
+    fn remote_value_too_long(#[case] strict: bool, #[case] errno: i32) {
+        let name = OsStr::new("huge");
+        let raw = remote_leaf("huge", u32::MAX);
+        let mut leaf = AttrLeafblock::from_block(&raw).unwrap();
+        let mut br = BufReader::new(Cursor::new(Vec::new()));
+
+        let validator = Arc::new(Validator::new(validate::Config { strict }));
+        validator.enter();
+        assert_eq!(leaf.value_len(0, name), Err(errno));
+        let r = leaf.get(&mut br, superblock(), 0, name, |_, _| {
+            panic!("The value's blocks must not be mapped")
+        });
+        assert_eq!(r, Err(errno));
+        assert_eq!(validator.failures(), 2);
+    }
+
+    /// A damaged entry count that overflows the leaf block is an error, before any entry is
+    /// decoded.
+    #[test]
+    fn leaf_count_overflow() {
+        let mut raw = remote_leaf("attr", 1);
+        raw[12..14].copy_from_slice(&u16::MAX.to_be_bytes());
+        let Err(DecodeError::OtherString(e)) = AttrLeafblock::from_block(&raw) else {
+            panic!("The count must be rejected");
+        };
+        assert_eq!(
+            e,
+            "Attribute leaf block has a count of 65535, but room for only 508"
+        );
+    }
+
+    /// Build a V4 attribute leaf block, the size of the global superblock's blocks, holding local
+    /// attributes with the given namespace flags, names, and values
+    fn local_leaf(attrs: &[(u8, &str, &[u8])]) -> Vec<u8> {
+        let blocksize = superblock().sb_blocksize as usize;
+        let mut attrs = attrs
+            .iter()
+            .map(|(ns, name, value)| (hashname(OsStr::new(name)), *ns, name.as_bytes(), *value))
+            .collect::<Vec<_>>();
+        attrs.sort_by_key(|a| a.0);
+        let mut blk = vec![0u8; blocksize];
+        blk[8..10].copy_from_slice(&XFS_ATTR_LEAF_MAGIC.to_be_bytes());
+        blk[12..14].copy_from_slice(&(attrs.len() as u16).to_be_bytes());
+        let mut nameidx = blocksize;
+        for (i, (hash, ns, name, value)) in attrs.iter().enumerate() {
+            nameidx -= (3 + name.len() + value.len() + 3) & !3;
+            let ent = 32 + 8 * i;
+            blk[ent..ent + 4].copy_from_slice(&hash.to_be_bytes());
+            blk[ent + 4..ent + 6].copy_from_slice(&(nameidx as u16).to_be_bytes());
+            blk[ent + 6] = constants::XFS_ATTR_LOCAL | ns;
+            blk[nameidx..nameidx + 2].copy_from_slice(&(value.len() as u16).to_be_bytes());
+            blk[nameidx + 2] = name.len() as u8;
+            blk[nameidx + 3..nameidx + 3 + name.len()].copy_from_slice(name);
+            blk[nameidx + 3 + name.len()..nameidx + 3 + name.len() + value.len()]
+                .copy_from_slice(value);
+        }
+        blk
+    }
+
+    /// Attributes with the same name in different namespaces, or whose names merely share a hash,
+    /// are each found by their own namespace and name.
+    #[rstest]
+    #[case::user(0, "abcde", Ok(&b"user"[..]))]
+    #[case::trusted(constants::XFS_ATTR_ROOT, "abcde", Ok(&b"trusted"[..]))]
+    #[case::secure(constants::XFS_ATTR_SECURE, "abcde", Err(libc::ENOATTR))]
+    #[case::collision(0, "qbcdd", Ok(&b"collision"[..]))]
+    #[case::collision_trusted(constants::XFS_ATTR_ROOT, "qbcdd", Err(libc::ENOATTR))]
+    #[case::missing(0, "bar", Err(libc::ENOATTR))]
+    fn leaf_namespaces(#[case] ns: u8, #[case] name: &str, #[case] expected: Result<&[u8], i32>) {
+        // "abcde" and "qbcdd" share a hash
+        assert_eq!(hashname(OsStr::new("abcde")), hashname(OsStr::new("qbcdd")));
+        let raw = local_leaf(&[
+            (constants::XFS_ATTR_ROOT, "abcde", b"trusted"),
+            (0, "qbcdd", b"collision"),
+            (0, "abcde", b"user"),
+        ]);
+        let mut leaf = AttrLeafblock::from_block(&raw).unwrap();
+        let mut br = BufReader::new(Cursor::new(Vec::new()));
+        let name = OsStr::new(name);
+        let expected_len = expected.map(|v| v.len() as u32);
+        assert_eq!(leaf.value_len(ns, name), expected_len);
+        let r = leaf.get(&mut br, superblock(), ns, name, |_, _| unreachable!());
+        assert_eq!(r, expected);
+    }
+
+    /// Names are listed with their own platform's prefixes, and parse back to the same namespace
+    #[rstest]
+    #[case::user(0, "user.")]
+    #[cfg_attr(
+        target_os = "linux",
+        case::trusted(constants::XFS_ATTR_ROOT, "trusted.")
+    )]
+    #[cfg_attr(
+        target_os = "linux",
+        case::security(constants::XFS_ATTR_SECURE, "security.")
+    )]
+    #[cfg_attr(
+        target_os = "freebsd",
+        case::system(constants::XFS_ATTR_ROOT, "system.")
+    )]
+    #[cfg_attr(
+        target_os = "freebsd",
+        case::secure(constants::XFS_ATTR_SECURE, "secure.")
+    )]
+    fn namespace_prefix(#[case] ns: u8, #[case] prefix: &str) {
+        assert_eq!(get_namespace_from_flags(ns), prefix.as_bytes());
+        assert_eq!(
+            get_namespace_from_flags(ns | constants::XFS_ATTR_LOCAL),
+            prefix.as_bytes()
+        );
+        let name = format!("{}foo.bar", prefix);
+        assert_eq!(
+            parse_name(OsStr::new(&name)),
+            Ok((ns, OsStr::new("foo.bar")))
+        );
+    }
+
+    #[rstest]
+    #[case::user("user.foo", Ok((0, "foo")))]
+    #[case::dotted("user.foo.bar", Ok((0, "foo.bar")))]
+    #[case::empty_name("user.", Ok((0, "")))]
+    #[case::no_namespace("foo", Err(libc::ENOATTR))]
+    #[case::unknown_namespace("bogus.foo", Err(libc::ENOATTR))]
+    #[case::no_dot("userfoo", Err(libc::ENOATTR))]
+    fn xattr_namespace(#[case] name: &str, #[case] expected: Result<(u8, &str), libc::c_int>) {
+        let expected = expected.map(|(ns, n)| (ns, OsStr::new(n)));
+        assert_eq!(parse_name(OsStr::new(name)), expected);
+    }
+
+    proptest! {
+        /// Any remote value length decodes, but only those that XFS allows are reported.
+        #[test]
+        fn remote_value_len(
+            valuelen in prop_oneof![0..=XFS_XATTR_SIZE_MAX + 1, any::<u32>()]
+        ) {
+            let name = OsStr::new("attr");
+            let raw = remote_leaf("attr", valuelen);
+            let leaf = AttrLeafblock::from_block(&raw).unwrap();
+            if valuelen <= XFS_XATTR_SIZE_MAX {
+                prop_assert_eq!(leaf.value_len(0, name), Ok(valuelen));
+            } else {
+                prop_assert_eq!(leaf.value_len(0, name), Err(libc::E2BIG));
+            }
+        }
+    }
+}
@@ -27,18 +27,20 @@
  */
 use std::{
     cell::RefCell,
-    collections::{btree_map::Entry, BTreeMap},
     convert::TryInto,
     ffi::OsStr,
     io::{BufRead, Seek, SeekFrom},
 };
 
 use bincode::de::read::Reader;
+use libc::c_int;
+use tracing::error;
 
 use super::{
-    attr::{Attr, AttrLeafblock},
+    attr::{parse_name, Attr, AttrLeafblock},
     btree::{Btree, BtreeRoot},
-    da_btree::{hashname, XfsDa3Intnode},
+    cache_budget::LruMap,
+    da_btree::{self, hashname, XfsDa3Intnode},
     definitions::{
         XfsDablk,
         XfsFsblock,
@@ -59,7 +61,8 @@ use super::{
 #[derive(Debug)]
 enum AttrBtreeBlock0 {
     Node(XfsDa3Intnode),
-    Leaf,
+    /// A lone leaf block, and its block number
+    Leaf(XfsDablk),
 }
 
 impl AttrBtreeBlock0 {
@@ -70,7 +73,7 @@ impl AttrBtreeBlock0 {
     {
         match self {
             AttrBtreeBlock0::Node(node) => node.first_block(buf_reader, super_block, map_dblock),
-            AttrBtreeBlock0::Leaf => 0,
+            AttrBtreeBlock0::Leaf(dablk) => *dablk,
         }
     }
 
@@ -83,21 +86,30 @@ impl AttrBtreeBlock0 {
     ) -> Result<XfsDablk, i32> {
         match self {
             AttrBtreeBlock0::Node(node) => node.lookup(buf_reader, super_block, hash, map_dblock),
-            AttrBtreeBlock0::Leaf => Ok(0),
+            AttrBtreeBlock0::Leaf(dablk) => Ok(*dablk),
         }
     }
 
-    fn new<R: BufRead + Reader + Seek>(buf_reader: &mut R) -> Self {
-        buf_reader.fill_buf().unwrap();
-        let magic: u16 = utils::decode(&buf_reader.peek_read(10).unwrap()[8..])
-            .unwrap()
+    fn new<R>(buf_reader: &mut R, sb: &Sb, dablk: XfsDablk) -> Result<Self, c_int>
+    where
+        R: BufRead + Reader + Seek,
+    {
+        da_btree::check_uuid(sb, buf_reader.fill_buf().map_err(|_| libc::EIO)?);
+        let magic: u16 = utils::decode(&buf_reader.peek_read(10).ok_or(libc::EIO)?[8..])
+            .map_err(|_| libc::EIO)?
             .0;
         match magic {
             XFS_DA_NODE_MAGIC | XFS_DA3_NODE_MAGIC => {
-                AttrBtreeBlock0::Node(XfsDa3Intnode::from(buf_reader))
+                XfsDa3Intnode::from(buf_reader, sb.sb_blocksize as usize).map(AttrBtreeBlock0::Node)
+            }
+            XFS_ATTR_LEAF_MAGIC | XFS_ATTR3_LEAF_MAGIC => Ok(AttrBtreeBlock0::Leaf(dablk)),
+            _ => {
+                error!(
+                    "Unexpected magic value {:#x} in attribute block {}",
+                    magic, dablk
+                );
+                Err(libc::EIO)
             }
-            XFS_ATTR_LEAF_MAGIC | XFS_ATTR3_LEAF_MAGIC => AttrBtreeBlock0::Leaf,
-            _ => panic!("Unexpected magic value {:#x}", magic),
         }
     }
 }
@@ -108,28 +120,43 @@ pub struct AttrBtree {
     total_size: i64,
     node:       AttrBtreeBlock0,
     /// A cache of leaf blocks, indexed by directory block number
-    leaves:     RefCell<BTreeMap<XfsDablk, AttrLeafblock>>,
+    leaves:     RefCell<LruMap<XfsDablk, AttrLeafblock>>,
 }
 
 impl AttrBtree {
-    pub fn new<R>(buf_reader: &mut R, sb: &Sb, btree: BtreeRoot) -> Self
+    pub fn new<R>(buf_reader: &mut R, sb: &Sb, btree: BtreeRoot) -> Result<Self, c_int>
     where
         R: bincode::de::read::Reader + BufRead + Seek,
     {
-        let fsblk = btree.map_block(buf_reader.by_ref(), 0).unwrap().0.unwrap();
+        // Block 0 normally holds the root of the da btree.  But a fork that shrank may have left
+        // it unmapped, so fall back to the lowest mapped block, which is the root's first key.
+        let mut dablk = 0;
+        let mut fsblk = btree.map_block(buf_reader.by_ref(), sb, 0)?.0.fsblock();
+        if fsblk.is_none() {
+            if let Some(key) = btree.keys.first() {
+                dablk = XfsDablk::try_from(key.br_startoff).map_err(|_| libc::EIO)?;
+                fsblk = btree
+                    .map_block(buf_reader.by_ref(), sb, key.br_startoff)?
+                    .0
+                    .fsblock();
+            }
+        }
+        let Some(fsblk) = fsblk else {
+            error!("Btree attribute fork has no mapped blocks");
+            return Err(libc::EIO);
+        };
         buf_reader
             .seek(SeekFrom::Start(sb.fsb_to_offset(fsblk)))
-            .unwrap();
+            .map_err(|_| libc::EIO)?;
 
-        buf_reader.fill_buf().unwrap();
-        let node = AttrBtreeBlock0::new(buf_reader.by_ref());
+        let node = AttrBtreeBlock0::new(buf_reader.by_ref(), sb, dablk)?;
 
-        Self {
+        Ok(Self {
             btree,
             total_size: -1,
             node,
             leaves: Default::default(),
-        }
+        })
     }
 
     // Attribute blocks always have the same size, so we don't need to return the extent length.
@@ -137,15 +164,53 @@ impl AttrBtree {
     fn map_dblock<R: bincode::de::read::Reader + BufRead + Seek>(
         &self,
         buf_reader: &mut R,
+        sb: &Sb,
         logical_block: XfsDablk,
     ) -> Result<XfsFsblock, i32> {
         self.btree
-            .map_block(buf_reader, logical_block.into())?
+            .map_block(buf_reader, sb, logical_block.into())?
             .0
+            .fsblock()
             .ok_or(libc::ENOATTR)
     }
 
-    /// Read the AttrLeafblock located at the given directory block number
+    /// Find the leaf block that may contain the attribute with this hash
+    fn lookup_leaf<R>(
+        &self,
+        buf_reader: &mut R,
+        super_block: &Sb,
+        hash: u32,
+    ) -> Result<XfsDablk, i32>
+    where
+        R: Reader + BufRead + Seek,
+    {
+        self.node
+            .lookup(buf_reader.by_ref(), super_block, hash, |block, reader| {
+                self.map_dblock(reader.by_ref(), super_block, block)
+                    .unwrap()
+            })
+            .map_err(|e| if e == libc::ENOENT { libc::ENOATTR } else { e })
+    }
+
+    /// Find the leaf block that contains the attribute with this hash.  The node may route the
+    /// hash to an empty leaf, or one whose entries all hash lower, with the attribute in a
+    /// sibling.  So follow the forw chain until the hash can no longer be found further along.
+    fn find_leaf<R>(&self, buf_reader: &mut R, super_block: &Sb, hash: u32) -> Result<XfsDablk, i32>
+    where
+        R: Reader + BufRead + Seek,
+    {
+        let mut dablk = self.lookup_leaf(buf_reader.by_ref(), super_block, hash)?;
+        loop {
+            let leaf = self.read_leaf(buf_reader.by_ref(), super_block, dablk)?;
+            if leaf.contains(hash) || !leaf.continues_past(hash) {
+                return Ok(dablk);
+            }
+            dablk = leaf.hdr.forw;
+        }
+    }
+
+    /// Read the AttrLeafblock located at the given directory block number.  The returned guard
+    /// borrows the whole cache, so it must be dropped before reading another leaf.
     fn read_leaf<'a, R>(
         &'a self,
         buf_reader: &mut R,
@@ -156,13 +221,17 @@ impl AttrBtree {
         R: Reader + BufRead + Seek,
     {
         let mut cache_guard = self.leaves.borrow_mut();
-        let entry = cache_guard.entry(dblock);
-        if matches!(entry, Entry::Vacant(_)) {
-            let fsblock = self.map_dblock(buf_reader.by_ref(), dblock)?;
+        if !cache_guard.contains_key(&dblock) {
+            let fsblock = self.map_dblock(buf_reader.by_ref(), sb, dblock)?;
             let leaf_offset = sb.fsb_to_offset(fsblock);
-            buf_reader.seek(SeekFrom::Start(leaf_offset)).unwrap();
-            let leaf: AttrLeafblock = utils::decode_from(buf_reader.by_ref()).unwrap();
-            entry.or_insert(leaf);
+            let mut raw = vec![0u8; sb.sb_blocksize as usize];
+            buf_reader
+                .seek(SeekFrom::Start(leaf_offset))
+                .and_then(|_| buf_reader.read_exact(&mut raw))
+                .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))?;
+            da_btree::check_uuid(sb, &raw);
+            let leaf = AttrLeafblock::from_block(&raw).unwrap();
+            cache_guard.insert(dblock, leaf);
         }
         Ok(std::cell::RefMut::map(cache_guard, |v| {
             v.get_mut(&dblock).unwrap()
@@ -171,6 +240,21 @@ impl AttrBtree {
 }
 
 impl Attr for AttrBtree {
+    fn cache_bytes(&self) -> usize {
+        self.leaves
+            .borrow()
+            .values()
+            .map(AttrLeafblock::cache_bytes)
+            .sum()
+    }
+
+    fn shrink_cache(&self, target: usize) {
+        let excess = self.cache_bytes().saturating_sub(target);
+        self.leaves
+            .borrow_mut()
+            .evict(excess, AttrLeafblock::cache_bytes);
+    }
+
     fn get_total_size<R: Reader + BufRead + Seek>(
         &mut self,
         buf_reader: &mut R,
@@ -183,13 +267,14 @@ impl Attr for AttrBtree {
             let mut dablk =
                 self.node
                     .first_block(buf_reader.by_ref(), super_block, |block, reader| {
-                        self.map_dblock(reader.by_ref(), block).unwrap()
+                        self.map_dblock(reader.by_ref(), super_block, block)
+                            .unwrap()
                     });
             loop {
                 let leaf = self
                     .read_leaf(buf_reader.by_ref(), super_block, dablk)
                     .unwrap();
-                total_size += leaf.get_total_size();
+                total_size = total_size.saturating_add(leaf.get_total_size());
                 dablk = leaf.hdr.forw;
                 if dablk == 0 {
                     break;
@@ -213,7 +298,8 @@ impl Attr for AttrBtree {
         let mut dablk = self
             .node
             .first_block(buf_reader.by_ref(), super_block, |block, reader| {
-                self.map_dblock(reader.by_ref(), block).unwrap()
+                self.map_dblock(reader.by_ref(), super_block, block)
+                    .unwrap()
             });
         loop {
             let leaf = self
@@ -233,19 +319,89 @@ impl Attr for AttrBtree {
     where
         R: Reader + BufRead + Seek,
     {
+        let (ns, name) = parse_name(name)?;
         let hash = hashname(name);
 
-        let dablk = self
-            .node
-            .lookup(buf_reader.by_ref(), super_block, hash, |block, reader| {
-                self.map_dblock(reader.by_ref(), block).unwrap()
-            })
-            .map_err(|e| if e == libc::ENOENT { libc::ENOATTR } else { e })?;
+        let dablk = self.find_leaf(buf_reader.by_ref(), super_block, hash)?;
         let mut leaf = self.read_leaf(buf_reader.by_ref(), super_block, dablk)?;
 
-        leaf.get(buf_reader.by_ref(), hash, |block, reader| {
-            self.map_dblock(reader.by_ref(), block).unwrap()
-        })
+        leaf.get(
+            buf_reader.by_ref(),
+            super_block,
+            ns,
+            name,
+            |block, reader| {
+                self.map_dblock(reader.by_ref(), super_block, block)
+                    .unwrap()
+            },
+        )
         .map(Vec::from)
     }
+
+    fn value_len<R>(
+        &mut self,
+        buf_reader: &mut R,
+        super_block: &Sb,
+        name: &OsStr,
+    ) -> Result<u32, i32>
+    where
+        R: Reader + BufRead + Seek,
+    {
+        let (ns, name) = parse_name(name)?;
+        let hash = hashname(name);
+
+        let dablk = self.find_leaf(buf_reader.by_ref(), super_block, hash)?;
+        let leaf = self.read_leaf(buf_reader.by_ref(), super_block, dablk)?;
+
+        leaf.value_len(ns, name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, Cursor};
+
+    use super::*;
+    use crate::libxfuse::{
+        attr::tests::remote_leaf,
+        bmbt_rec::{tests::superblock, BmbtRec},
+        btree::tests::single_leaf,
+    };
+
+    /// A btree attribute fork whose only extent, holding a single leaf block, is at `startoff`
+    fn fork(startoff: u64) -> (BtreeRoot, BufReader<Cursor<Vec<u8>>>) {
+        let blocksize = superblock().sb_blocksize as usize;
+        let (root, mut br) = single_leaf(&[BmbtRec {
+            br_startoff:   startoff,
+            br_startblock: 2,
+            br_blockcount: 1,
+            br_flag:       false,
+        }]);
+        let image = br.get_mut().get_mut();
+        image.resize(3 * blocksize, 0);
+        image[2 * blocksize..].copy_from_slice(&remote_leaf("big", 100));
+        (root, br)
+    }
+
+    /// If block 0 isn't mapped, the attributes may still be found at the first block that is.
+    #[test]
+    fn unmapped_block0() {
+        let sb = superblock();
+        let (root, mut br) = fork(5);
+        let mut attrs = AttrBtree::new(&mut br, sb, root).unwrap();
+        assert_eq!(attrs.list(&mut br, sb), b"user.big\0");
+        assert_eq!(
+            attrs.value_len(&mut br, sb, OsStr::new("user.big")),
+            Ok(100)
+        );
+    }
+
+    /// A fork whose keys point at no mapped blocks at all is an error, not a panic.
+    #[test]
+    fn no_mapped_blocks() {
+        let sb = superblock();
+        let (mut root, mut br) = fork(7);
+        root.keys[0].br_startoff = 5;
+        assert_eq!(AttrBtree::new(&mut br, sb, root).map(drop), Err(libc::EIO));
+    }
 }
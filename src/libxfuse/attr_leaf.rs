@@ -34,9 +34,8 @@ use std::{
 use bincode::de::read::Reader;
 
 use super::{
-    attr::{Attr, AttrLeafblock},
+    attr::{parse_name, Attr, AttrLeafblock},
     bmbt_rec::Bmx,
-    da_btree::hashname,
     sb::Sb,
 };
 
@@ -48,6 +47,10 @@ pub struct AttrLeaf {
 }
 
 impl Attr for AttrLeaf {
+    fn cache_bytes(&self) -> usize {
+        self.leaf.cache_bytes()
+    }
+
     fn get_total_size<R: BufRead + Reader + Seek>(
         &mut self,
         _buf_reader: &mut R,
@@ -74,23 +77,31 @@ impl Attr for AttrLeaf {
         list
     }
 
-    fn get<R>(
-        &mut self,
-        buf_reader: &mut R,
-        _super_block: &Sb,
-        name: &OsStr,
-    ) -> Result<Vec<u8>, i32>
+    fn get<R>(&mut self, buf_reader: &mut R, super_block: &Sb, name: &OsStr) -> Result<Vec<u8>, i32>
     where
         R: BufRead + Reader + Seek,
     {
-        let hash = hashname(name);
+        let (ns, name) = parse_name(name)?;
 
         let bmx = &self.bmx;
         self.leaf
-            .get(buf_reader.by_ref(), hash, |block, _| {
+            .get(buf_reader.by_ref(), super_block, ns, name, |block, _| {
                 bmx.map_dblock(block)
                     .expect("holes are not allowed in attr forks")
             })
             .map(Vec::from)
     }
+
+    fn value_len<R>(
+        &mut self,
+        _buf_reader: &mut R,
+        _super_block: &Sb,
+        name: &OsStr,
+    ) -> Result<u32, i32>
+    where
+        R: BufRead + Reader + Seek,
+    {
+        let (ns, name) = parse_name(name)?;
+        self.leaf.value_len(ns, name)
+    }
 }
@@ -27,7 +27,6 @@
  */
 use std::{
     cell::RefCell,
-    collections::{btree_map::Entry, BTreeMap},
     convert::TryInto,
     ffi::OsStr,
     io::{BufRead, Seek, SeekFrom},
@@ -36,12 +35,12 @@ use std::{
 use bincode::de::read::Reader;
 
 use super::{
-    attr::{Attr, AttrLeafblock},
+    attr::{parse_name, Attr, AttrLeafblock},
     bmbt_rec::Bmx,
-    da_btree::{hashname, XfsDa3Intnode},
+    cache_budget::LruMap,
+    da_btree::{self, hashname, XfsDa3Intnode},
     definitions::{XfsDablk, XfsFsblock},
     sb::Sb,
-    utils::decode_from,
 };
 
 #[derive(Debug)]
@@ -50,7 +49,7 @@ pub struct AttrNode {
     pub node:       XfsDa3Intnode,
     pub total_size: i64,
     /// A cache of leaf blocks, indexed by directory block number
-    leaves:         RefCell<BTreeMap<XfsDablk, AttrLeafblock>>,
+    leaves:         RefCell<LruMap<XfsDablk, AttrLeafblock>>,
 }
 
 impl AttrNode {
@@ -69,7 +68,42 @@ impl AttrNode {
             .expect("holes are not allowed in attr forks")
     }
 
-    /// Read the AttrLeafblock located at the given directory block number
+    /// Find the leaf block that may contain the attribute with this hash
+    fn lookup_leaf<R>(
+        &self,
+        buf_reader: &mut R,
+        super_block: &Sb,
+        hash: u32,
+    ) -> Result<XfsDablk, i32>
+    where
+        R: Reader + BufRead + Seek,
+    {
+        self.node
+            .lookup(buf_reader.by_ref(), super_block, hash, |block, _| {
+                self.map_dblock(block)
+            })
+            .map_err(|e| if e == libc::ENOENT { libc::ENOATTR } else { e })
+    }
+
+    /// Find the leaf block that contains the attribute with this hash.  The node may route the
+    /// hash to an empty leaf, or one whose entries all hash lower, with the attribute in a
+    /// sibling.  So follow the forw chain until the hash can no longer be found further along.
+    fn find_leaf<R>(&self, buf_reader: &mut R, super_block: &Sb, hash: u32) -> Result<XfsDablk, i32>
+    where
+        R: Reader + BufRead + Seek,
+    {
+        let mut dablk = self.lookup_leaf(buf_reader.by_ref(), super_block, hash)?;
+        loop {
+            let leaf = self.read_leaf(buf_reader.by_ref(), super_block, dablk)?;
+            if leaf.contains(hash) || !leaf.continues_past(hash) {
+                return Ok(dablk);
+            }
+            dablk = leaf.hdr.forw;
+        }
+    }
+
+    /// Read the AttrLeafblock located at the given directory block number.  The returned guard
+    /// borrows the whole cache, so it must be dropped before reading another leaf.
     fn read_leaf<'a, R>(
         &'a self,
         buf_reader: &mut R,
@@ -80,13 +114,17 @@ impl AttrNode {
         R: Reader + BufRead + Seek,
     {
         let mut cache_guard = self.leaves.borrow_mut();
-        let entry = cache_guard.entry(dblock);
-        if matches!(entry, Entry::Vacant(_)) {
+        if !cache_guard.contains_key(&dblock) {
             let fsblock = self.map_dblock(dblock);
             let leaf_offset = sb.fsb_to_offset(fsblock);
-            buf_reader.seek(SeekFrom::Start(leaf_offset)).unwrap();
-            let node: AttrLeafblock = decode_from(buf_reader.by_ref()).unwrap();
-            entry.or_insert(node);
+            let mut raw = vec![0u8; sb.sb_blocksize as usize];
+            buf_reader
+                .seek(SeekFrom::Start(leaf_offset))
+                .and_then(|_| buf_reader.read_exact(&mut raw))
+                .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))?;
+            da_btree::check_uuid(sb, &raw);
+            let node = AttrLeafblock::from_block(&raw).unwrap();
+            cache_guard.insert(dblock, node);
         }
         Ok(std::cell::RefMut::map(cache_guard, |v| {
             v.get_mut(&dblock).unwrap()
@@ -95,6 +133,21 @@ impl AttrNode {
 }
 
 impl Attr for AttrNode {
+    fn cache_bytes(&self) -> usize {
+        self.leaves
+            .borrow()
+            .values()
+            .map(AttrLeafblock::cache_bytes)
+            .sum()
+    }
+
+    fn shrink_cache(&self, target: usize) {
+        let excess = self.cache_bytes().saturating_sub(target);
+        self.leaves
+            .borrow_mut()
+            .evict(excess, AttrLeafblock::cache_bytes);
+    }
+
     fn get_total_size<R: Reader + BufRead + Seek>(
         &mut self,
         buf_reader: &mut R,
@@ -112,7 +165,7 @@ impl Attr for AttrNode {
                 let leaf = self
                     .read_leaf(buf_reader.by_ref(), super_block, dablk)
                     .unwrap();
-                total_size += leaf.get_total_size();
+                total_size = total_size.saturating_add(leaf.get_total_size());
                 dablk = leaf.hdr.forw;
             }
 
@@ -150,17 +203,147 @@ impl Attr for AttrNode {
     where
         R: Reader + BufRead + Seek,
     {
+        let (ns, name) = parse_name(name)?;
         let hash = hashname(name);
 
-        let dablk = self
-            .node
-            .lookup(buf_reader.by_ref(), super_block, hash, |block, _| {
-                self.map_dblock(block)
-            })
-            .map_err(|e| if e == libc::ENOENT { libc::ENOATTR } else { e })?;
+        let dablk = self.find_leaf(buf_reader.by_ref(), super_block, hash)?;
         let mut leaf = self.read_leaf(buf_reader.by_ref(), super_block, dablk)?;
 
-        leaf.get(buf_reader.by_ref(), hash, |block, _| self.map_dblock(block))
-            .map(Vec::from)
+        leaf.get(buf_reader.by_ref(), super_block, ns, name, |block, _| {
+            self.map_dblock(block)
+        })
+        .map(Vec::from)
+    }
+
+    fn value_len<R>(
+        &mut self,
+        buf_reader: &mut R,
+        super_block: &Sb,
+        name: &OsStr,
+    ) -> Result<u32, i32>
+    where
+        R: Reader + BufRead + Seek,
+    {
+        let (ns, name) = parse_name(name)?;
+        let hash = hashname(name);
+
+        let dablk = self.find_leaf(buf_reader.by_ref(), super_block, hash)?;
+        let leaf = self.read_leaf(buf_reader.by_ref(), super_block, dablk)?;
+
+        leaf.value_len(ns, name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, Cursor};
+
+    use rstest::rstest;
+
+    use super::*;
+    use crate::libxfuse::{
+        bmbt_rec::{tests::superblock, BmbtRec},
+        definitions::{XFS_ATTR_LEAF_MAGIC, XFS_DA_NODE_MAGIC},
+    };
+
+    const BLOCKSIZE: usize = 4096;
+    const XFS_ATTR_LOCAL: u8 = 1;
+    /// The attribute fork's blocks start at this file system block
+    const FIRST_FSBLOCK: u64 = 10;
+
+    /// Build a V4 attribute leaf block holding local user attributes with the given names and
+    /// values
+    fn leaf(forw: u32, attrs: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut attrs = attrs
+            .iter()
+            .map(|(name, value)| (hashname(OsStr::new(name)), name.as_bytes(), *value))
+            .collect::<Vec<_>>();
+        attrs.sort_by_key(|a| a.0);
+        let mut blk = vec![0u8; BLOCKSIZE];
+        blk[0..4].copy_from_slice(&forw.to_be_bytes());
+        blk[8..10].copy_from_slice(&XFS_ATTR_LEAF_MAGIC.to_be_bytes());
+        blk[12..14].copy_from_slice(&(attrs.len() as u16).to_be_bytes());
+        let mut nameidx = BLOCKSIZE;
+        for (i, (hash, name, value)) in attrs.iter().enumerate() {
+            nameidx -= (3 + name.len() + value.len() + 3) & !3;
+            let ent = 32 + 8 * i;
+            blk[ent..ent + 4].copy_from_slice(&hash.to_be_bytes());
+            blk[ent + 4..ent + 6].copy_from_slice(&(nameidx as u16).to_be_bytes());
+            blk[ent + 6] = XFS_ATTR_LOCAL;
+            blk[nameidx..nameidx + 2].copy_from_slice(&(value.len() as u16).to_be_bytes());
+            blk[nameidx + 2] = name.len() as u8;
+            blk[nameidx + 3..nameidx + 3 + name.len()].copy_from_slice(name);
+            blk[nameidx + 3 + name.len()..nameidx + 3 + name.len() + value.len()]
+                .copy_from_slice(value);
+        }
+        blk
+    }
+
+    /// Build an attribute fork whose node routes every hash to leaf block 1, followed by the given
+    /// chain of leaves.  The last entry in the node covers every hash.
+    fn attr_node(leaves: &[Vec<u8>]) -> (AttrNode, BufReader<Cursor<Vec<u8>>>) {
+        superblock();
+        let mut node = vec![0u8; BLOCKSIZE];
+        node[8..10].copy_from_slice(&XFS_DA_NODE_MAGIC.to_be_bytes());
+        node[12..14].copy_from_slice(&1u16.to_be_bytes());
+        node[14..16].copy_from_slice(&1u16.to_be_bytes());
+        node[16..20].copy_from_slice(&u32::MAX.to_be_bytes());
+        node[20..24].copy_from_slice(&1u32.to_be_bytes());
+
+        let mut image = vec![0u8; FIRST_FSBLOCK as usize * BLOCKSIZE];
+        image.extend_from_slice(&node);
+        for leaf in leaves {
+            image.extend_from_slice(leaf);
+        }
+        let bmx = Bmx::new(&[BmbtRec {
+            br_startoff:   0,
+            br_startblock: FIRST_FSBLOCK,
+            br_blockcount: 1 + leaves.len() as u64,
+            br_flag:       false,
+        }]);
+        let node = XfsDa3Intnode::from_block(&node).unwrap();
+        (AttrNode::new(bmx, node), BufReader::new(Cursor::new(image)))
+    }
+
+    /// The node routes the hash to an empty leaf, and the attribute lives in its sibling
+    #[rstest]
+    #[case::empty_first(vec![leaf(2, &[]), leaf(0, &[("b", b"bval")])])]
+    #[case::lower_first(vec![leaf(2, &[("a", b"aval")]), leaf(0, &[("b", b"bval")])])]
+    #[case::empty_middle(vec![
+        leaf(2, &[]),
+        leaf(3, &[]),
+        leaf(0, &[("b", b"bval")])
+    ])]
+    fn get_follows_forw(#[case] leaves: Vec<Vec<u8>>) {
+        let sb = superblock();
+        let (mut attr, mut br) = attr_node(&leaves);
+        let name = OsStr::new("user.b");
+        assert_eq!(attr.get(&mut br, sb, name).unwrap(), b"bval");
+        assert_eq!(attr.value_len(&mut br, sb, name), Ok(4));
+        assert_eq!(
+            attr.get(&mut br, sb, OsStr::new("user.missing")),
+            Err(libc::ENOATTR)
+        );
+    }
+
+    /// Empty leaves contribute nothing to listings
+    #[test]
+    fn list_empty_leaves() {
+        let sb = superblock();
+        let leaves = [
+            leaf(2, &[]),
+            leaf(3, &[("a", b"aval")]),
+            leaf(4, &[]),
+            leaf(0, &[("b", b"bval")]),
+        ];
+        let (mut attr, mut br) = attr_node(&leaves);
+        let list = attr.list(&mut br, sb);
+        let mut names = list
+            .split(|b| *b == 0)
+            .filter(|n| !n.is_empty())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, [&b"user.a"[..], &b"user.b"[..]]);
+        assert_eq!(attr.get_total_size(&mut br, sb) as usize, list.len());
     }
 }
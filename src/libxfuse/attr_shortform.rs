@@ -28,7 +28,7 @@
 use std::{
     ffi::OsStr,
     io::{BufRead, Seek},
-    os::unix::ffi::OsStrExt,
+    mem,
 };
 
 use bincode::{
@@ -38,7 +38,13 @@ use bincode::{
 };
 
 use super::{
-    attr::{get_namespace_from_flags, get_namespace_size_from_flags, Attr},
+    attr::{
+        get_namespace_from_flags,
+        get_namespace_size_from_flags,
+        name_matches,
+        parse_name,
+        Attr,
+    },
     sb::Sb,
 };
 
@@ -97,6 +103,13 @@ impl Decode for AttrShortform {
 }
 
 impl Attr for AttrShortform {
+    fn cache_bytes(&self) -> usize {
+        self.list
+            .iter()
+            .map(|e| mem::size_of::<AttrSfEntry>() + e.nameval.len())
+            .sum()
+    }
+
     fn get_total_size<R: BufRead + Reader + Seek>(
         &mut self,
         _buf_reader: &mut R,
@@ -132,16 +145,96 @@ impl Attr for AttrShortform {
     where
         R: BufRead + Reader + Seek,
     {
+        let (ns, name) = parse_name(name)?;
         for entry in &self.list {
-            let entry_name = entry.nameval[0..(entry.namelen as usize)].to_vec();
-
-            if name.as_bytes().to_vec() == entry_name {
-                let namelen = entry.namelen as usize;
+            let namelen = entry.namelen as usize;
 
+            if name_matches(entry.flags, &entry.nameval[0..namelen], ns, name) {
                 return Ok(entry.nameval[namelen..].to_vec());
             }
         }
 
         Err(libc::ENOATTR)
     }
+
+    fn value_len<R>(
+        &mut self,
+        buf_reader: &mut R,
+        super_block: &Sb,
+        name: &OsStr,
+    ) -> Result<u32, i32>
+    where
+        R: BufRead + Reader + Seek,
+    {
+        // Short form values are stored inline, so there's nothing to gain by not copying them
+        self.get(buf_reader, super_block, name)
+            .map(|value| value.len() as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufReader, Cursor},
+        os::unix::ffi::OsStrExt,
+    };
+
+    use rstest::rstest;
+
+    use super::*;
+    use crate::libxfuse::{bmbt_rec::tests::superblock, utils::decode};
+
+    const XFS_ATTR_ROOT: u8 = 2;
+    const XFS_ATTR_SECURE: u8 = 4;
+
+    /// Build a short form attribute fork holding the given namespace flags, names, and values
+    fn shortform(attrs: &[(u8, &str, &[u8])]) -> Vec<u8> {
+        let mut raw = vec![0u8; 4];
+        raw[2] = attrs.len() as u8;
+        for (ns, name, value) in attrs {
+            raw.extend_from_slice(&[name.len() as u8, value.len() as u8, *ns]);
+            raw.extend_from_slice(name.as_bytes());
+            raw.extend_from_slice(value);
+        }
+        let totsize = raw.len() as u16;
+        raw[0..2].copy_from_slice(&totsize.to_be_bytes());
+        raw
+    }
+
+    /// Attributes with the same name in different namespaces are each found by their own
+    /// namespace's prefix.
+    #[rstest]
+    #[case::user("user.foo", Ok(&b"user"[..]))]
+    #[cfg_attr(target_os = "linux", case::trusted("trusted.foo", Ok(&b"trusted"[..])))]
+    #[cfg_attr(target_os = "linux", case::security("security.foo", Ok(&b"security"[..])))]
+    #[cfg_attr(target_os = "freebsd", case::system("system.foo", Ok(&b"trusted"[..])))]
+    #[cfg_attr(target_os = "freebsd", case::secure("secure.foo", Ok(&b"security"[..])))]
+    #[case::other_name("user.bar", Err(libc::ENOATTR))]
+    #[case::unknown_namespace("bogus.foo", Err(libc::ENOATTR))]
+    fn namespaces(#[case] name: &str, #[case] expected: Result<&[u8], i32>) {
+        let raw = shortform(&[
+            (XFS_ATTR_ROOT, "foo", b"trusted"),
+            (0, "foo", b"user"),
+            (XFS_ATTR_SECURE, "foo", b"security"),
+        ]);
+        let mut attrs: AttrShortform = decode(&raw).unwrap().0;
+        let sb = superblock();
+        let mut br = BufReader::new(Cursor::new(Vec::new()));
+        let name = OsStr::new(name);
+        assert_eq!(attrs.get(&mut br, sb, name).as_deref(), expected.as_deref());
+        assert_eq!(
+            attrs.value_len(&mut br, sb, name),
+            expected.map(|v| v.len() as u32)
+        );
+
+        // Every one is listed under its own prefix
+        let list = attrs.list(&mut br, sb);
+        assert_eq!(list.len() as u32, attrs.get_total_size(&mut br, sb));
+        let names = list
+            .split(|c| *c == 0)
+            .filter(|n| !n.is_empty())
+            .map(|n| attrs.get(&mut br, sb, OsStr::from_bytes(n)).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(names, [&b"trusted"[..], b"user", b"security"]);
+    }
 }
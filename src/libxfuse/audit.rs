@@ -0,0 +1,270 @@
+/*
+ * BSD 2-Clause License
+ *
+ * Copyright (c) 2021, Khaled Emara
+ * All rights reserved.
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice, this
+ *    list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice,
+ *    this list of conditions and the following disclaimer in the documentation
+ *    and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+ * AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+ * IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+ * FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+ * SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+ * CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+ * OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+ * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ */
+//! The audit log written by `-o auditlog`.
+//!
+//! Every lookup, open, read, readdir, readlink, getxattr, and listxattr appends one
+//! tab-separated line to the log:
+//!
+//! ```text
+//! TIME OP INO PATH ATTR OFFSET LENGTH RESULT
+//! ```
+//!
+//! `TIME` is seconds since the epoch, with microseconds.  `PATH` is reconstructed from earlier
+//! lookups, and is `?` if some component of it was never looked up.  `ATTR` is the extended
+//! attribute's name for `GETXATTR`.  `LENGTH` is the number of bytes (or for `READDIR`,
+//! entries) returned.  `RESULT` is 0 on success, or else the errno.  Fields that don't apply to
+//! an operation are `-`.
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    fmt::Display,
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Write},
+    os::unix::ffi::OsStrExt,
+    path::Path,
+    sync::{Mutex, PoisonError},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use libc::c_int;
+use tracing::error;
+
+use super::stat::ROOT_INO;
+
+/// The details of one audited operation
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Record<'a> {
+    /// The name of the extended attribute, for GETXATTR
+    pub attr:   Option<&'a OsStr>,
+    pub offset: Option<i64>,
+    /// The number of bytes or entries returned
+    pub len:    Option<u64>,
+    /// 0 on success, or the errno
+    pub errno:  c_int,
+}
+
+#[derive(Debug)]
+pub struct AuditLog<W: Write = File> {
+    // Locked, so records from concurrent operations won't interleave
+    out:   Mutex<BufWriter<W>>,
+    /// The parent and name of every inode looked up so far, for reconstructing paths.  Entries
+    /// are never removed, because the log may still need a directory's name after the kernel
+    /// forgets it.
+    names: HashMap<u64, (u64, OsString)>,
+}
+
+impl AuditLog {
+    /// Open an audit log, appending to it if it already exists.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let f = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self::new(f))
+    }
+}
+
+impl<W: Write> AuditLog<W> {
+    pub fn new(out: W) -> Self {
+        AuditLog {
+            out:   Mutex::new(BufWriter::new(out)),
+            names: HashMap::new(),
+        }
+    }
+
+    /// The path of an inode, as far as is known
+    fn path(&self, mut ino: u64) -> Option<Vec<u8>> {
+        let mut components = Vec::new();
+        while ino != ROOT_INO {
+            let (parent, name) = self.names.get(&ino)?;
+            components.push(name.as_bytes());
+            // Guard against loops in a corrupt file system
+            if components.len() > self.names.len() {
+                return None;
+            }
+            ino = *parent;
+        }
+        if components.is_empty() {
+            return Some(b"/".to_vec());
+        }
+        let mut path = Vec::new();
+        for name in components.iter().rev() {
+            path.push(b'/');
+            path.extend_from_slice(name);
+        }
+        Some(path)
+    }
+
+    /// Log a lookup of `name` in `parent`, which found `ino` or failed with `errno`.
+    pub fn lookup(&mut self, parent: u64, name: &OsStr, r: Result<u64, c_int>) {
+        let path = match r {
+            Ok(ino) => {
+                if name != "." && name != ".." {
+                    self.names.insert(ino, (parent, name.to_owned()));
+                }
+                self.path(ino)
+            }
+            Err(_) => self.path(parent).map(|mut path| {
+                if path != b"/" {
+                    path.push(b'/');
+                }
+                path.extend_from_slice(name.as_bytes());
+                path
+            }),
+        };
+        let (ino, errno) = match r {
+            Ok(ino) => (ino, 0),
+            Err(e) => (0, e),
+        };
+        self.write(
+            "LOOKUP",
+            ino,
+            path,
+            &Record {
+                errno,
+                ..Default::default()
+            },
+        );
+    }
+
+    /// Log any other operation on `ino`.
+    pub fn record(&self, op: &str, ino: u64, rec: &Record) {
+        self.write(op, ino, self.path(ino), rec);
+    }
+
+    fn write(&self, op: &str, ino: u64, path: Option<Vec<u8>>, rec: &Record) {
+        fn field<T: Display>(v: Option<T>) -> String {
+            v.map(|v| v.to_string()).unwrap_or_else(|| "-".to_string())
+        }
+        // Escape tabs and newlines, so every record is one line
+        fn escape(name: &[u8]) -> String {
+            String::from_utf8_lossy(name).escape_debug().to_string()
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut out = self.out.lock().unwrap_or_else(PoisonError::into_inner);
+        let r = writeln!(
+            out,
+            "{}.{:06}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            now.as_secs(),
+            now.subsec_micros(),
+            op,
+            ino,
+            path.map(|p| escape(&p)).unwrap_or_else(|| "?".to_string()),
+            field(rec.attr.map(|a| escape(a.as_bytes()))),
+            field(rec.offset),
+            field(rec.len),
+            rec.errno
+        )
+        .and_then(|_| out.flush());
+        if let Err(e) = r {
+            error!("Cannot write to the audit log: {}", e);
+        }
+    }
+
+    #[cfg(test)]
+    fn into_inner(self) -> W {
+        self.out
+            .into_inner()
+            .unwrap()
+            .into_inner()
+            .map_err(|_| ())
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Strip the timestamps from the log's lines
+    fn lines(log: AuditLog<Vec<u8>>) -> Vec<String> {
+        String::from_utf8(log.into_inner())
+            .unwrap()
+            .lines()
+            .map(|l| l.split_once('\t').unwrap().1.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn paths() {
+        let mut log = AuditLog::new(Vec::new());
+        log.lookup(ROOT_INO, OsStr::new("dir"), Ok(100));
+        log.lookup(100, OsStr::new("file"), Ok(101));
+        log.lookup(100, OsStr::new("missing"), Err(libc::ENOENT));
+        log.lookup(100, OsStr::new(".."), Ok(ROOT_INO));
+        log.record(
+            "READ",
+            101,
+            &Record {
+                offset: Some(4096),
+                len: Some(512),
+                ..Default::default()
+            },
+        );
+        log.record(
+            "GETXATTR",
+            100,
+            &Record {
+                attr: Some(OsStr::new("user.foo")),
+                errno: libc::ENOATTR,
+                ..Default::default()
+            },
+        );
+        log.record(
+            "READDIR",
+            ROOT_INO,
+            &Record {
+                offset: Some(0),
+                len: Some(3),
+                ..Default::default()
+            },
+        );
+        // Never looked up
+        log.record("READLINK", 200, &Record::default());
+        assert_eq!(
+            lines(log),
+            vec![
+                "LOOKUP\t100\t/dir\t-\t-\t-\t0",
+                "LOOKUP\t101\t/dir/file\t-\t-\t-\t0",
+                format!("LOOKUP\t0\t/dir/missing\t-\t-\t-\t{}", libc::ENOENT).as_str(),
+                "LOOKUP\t1\t/\t-\t-\t-\t0",
+                "READ\t101\t/dir/file\t-\t4096\t512\t0",
+                format!("GETXATTR\t100\t/dir\tuser.foo\t-\t-\t{}", libc::ENOATTR).as_str(),
+                "READDIR\t1\t/\t-\t0\t3\t0",
+                "READLINK\t200\t?\t-\t-\t-\t0",
+            ]
+        );
+    }
+
+    #[test]
+    fn escape() {
+        let mut log = AuditLog::new(Vec::new());
+        log.lookup(ROOT_INO, OsStr::new("a\tb\nc"), Ok(100));
+        assert_eq!(lines(log), vec!["LOOKUP\t100\t/a\\tb\\nc\t-\t-\t-\t0"]);
+    }
+}
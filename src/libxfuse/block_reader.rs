@@ -26,15 +26,33 @@
  * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
  */
 use std::{
+    collections::HashMap,
+    fmt,
     fs::File,
     io::{self, BufRead, Read, Result as IoResult, Seek, SeekFrom},
     mem,
-    os::{fd::AsRawFd, unix::fs::MetadataExt},
-    path::Path,
+    os::{
+        fd::AsRawFd,
+        unix::fs::{FileExt, MetadataExt},
+    },
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc,
+        Condvar,
+        Mutex,
+    },
+    thread,
+    time::Duration,
 };
 
 use bincode::{de::read::Reader, error::DecodeError};
 use cfg_if::cfg_if;
+use memmap2::Mmap;
+use tracing::warn;
+
+use super::{stats::Stats, trace};
 
 #[cfg(target_os = "freebsd")]
 mod ffi {
@@ -46,17 +64,246 @@ mod ffi {
     }
 }
 
+/// Limits how long a single device read may take.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ReadTimeout {
+    pub timeout: Duration,
+    /// How many times to retry a read that timed out before failing with EIO
+    pub retries: u32,
+}
+
+/// At most this many helper threads may be left blocked in reads that timed out.  Beyond that,
+/// reads fail at once until one of them returns, rather than leaving ever more threads behind on
+/// a device that has hung for good.
+const MAX_ABANDONED: usize = 4;
+
+/// A helper thread that performs reads on behalf of a [`Watchdog`].
+struct Worker {
+    tx: Sender<(Vec<u8>, u64)>,
+    rx: Receiver<(Vec<u8>, IoResult<()>)>,
+}
+
+impl Worker {
+    /// `abandoned` counts the workers whose reads timed out and are still running.  The thread
+    /// leaves the count when it finds that it was one of them.
+    fn spawn(
+        device: Arc<dyn FileExt + Send + Sync>,
+        abandoned: Arc<AtomicUsize>,
+    ) -> IoResult<Self> {
+        let (tx, req_rx) = mpsc::channel::<(Vec<u8>, u64)>();
+        let (resp_tx, rx) = mpsc::channel();
+        thread::Builder::new()
+            .name("xfs-fuse-read".to_string())
+            .spawn(move || {
+                // Once the Watchdog abandons us, the next send will fail, or the loop will end.
+                // Either way, a late read doesn't leave the thread behind.
+                for (mut buf, offset) in req_rx {
+                    let r = device.read_exact_at(&mut buf, offset);
+                    if resp_tx.send((buf, r)).is_err() {
+                        abandoned.fetch_sub(1, Ordering::Relaxed);
+                        break;
+                    }
+                }
+            })?;
+        Ok(Worker { tx, rx })
+    }
+}
+
+/// Performs device reads on a helper thread, so that a stalled device, such as a network block
+/// device, returns EIO rather than blocking the caller forever.
+struct Watchdog {
+    device:    Arc<dyn FileExt + Send + Sync>,
+    timeout:   ReadTimeout,
+    worker:    Option<Worker>,
+    /// How many abandoned workers are still blocked in their reads
+    abandoned: Arc<AtomicUsize>,
+}
+
+impl Watchdog {
+    fn new(device: Arc<dyn FileExt + Send + Sync>, timeout: ReadTimeout) -> Self {
+        Watchdog {
+            device,
+            timeout,
+            worker: None,
+            abandoned: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    fn read_exact_at(&mut self, buf: &mut Vec<u8>, offset: u64) -> IoResult<()> {
+        let len = buf.len();
+        for attempt in 0..=self.timeout.retries {
+            let worker = match self.worker.take() {
+                Some(worker) => worker,
+                None if self.abandoned.load(Ordering::Relaxed) >= MAX_ABANDONED => break,
+                None => Worker::spawn(self.device.clone(), self.abandoned.clone())?,
+            };
+            if worker.tx.send((mem::take(buf), offset)).is_err() {
+                break;
+            }
+            match worker.rx.recv_timeout(self.timeout.timeout) {
+                Ok((b, r)) => {
+                    *buf = b;
+                    self.worker = Some(worker);
+                    return r;
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    warn!(
+                        "Read of {} bytes at offset {} timed out (attempt {})",
+                        len,
+                        offset,
+                        attempt + 1
+                    );
+                    // Abandon the worker.  Its thread will exit whenever the read completes.
+                    self.abandoned.fetch_add(1, Ordering::Relaxed);
+                    drop(worker);
+                    *buf = vec![0u8; len];
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        buf.resize(len, 0u8);
+        Err(io::Error::from_raw_os_error(libc::EIO))
+    }
+}
+
+impl fmt::Debug for Watchdog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Watchdog")
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+/// The outcome of one in-flight read, as seen by the readers waiting on it.  `io::Error` isn't
+/// `Clone`, so each waiter gets a copy rebuilt from its errno or kind.
+type Shared = Result<Arc<[u8]>, (io::ErrorKind, Option<i32>, String)>;
+
+/// A read that one reader is performing on behalf of any others that want the same block
+#[derive(Debug, Default)]
+struct Inflight {
+    result: Mutex<Option<Shared>>,
+    done:   Condvar,
+}
+
+/// Performs an in-flight read on behalf of the readers waiting on it.  If it's dropped without
+/// finishing, as when the read panics, the waiters get EIO rather than waiting forever.
+struct Leader<'a> {
+    reads: &'a InflightReads,
+    key:   (u64, usize),
+    slot:  Option<Arc<Inflight>>,
+}
+
+impl<'a> Leader<'a> {
+    /// Retire the read, and hand its result to anybody waiting on it.  `make` is only called if
+    /// somebody is.
+    fn publish<F: FnOnce() -> Shared>(&mut self, make: F) {
+        let Some(slot) = self.slot.take() else {
+            return;
+        };
+        // Once the slot leaves the map, nobody else can start waiting on it.
+        self.reads.reads.lock().unwrap().remove(&self.key);
+        if Arc::strong_count(&slot) > 1 {
+            *slot.result.lock().unwrap() = Some(make());
+            slot.done.notify_all();
+        }
+    }
+}
+
+impl<'a> Drop for Leader<'a> {
+    fn drop(&mut self) {
+        self.publish(|| Err((io::ErrorKind::Other, Some(libc::EIO), String::new())));
+    }
+}
+
+/// The device reads that are currently in progress, keyed by offset and length.  Every
+/// [`BlockReader`] of one device shares it, so when several of them miss the same block at the
+/// same time, only one reads it and the rest wait for its data, or its error.
+#[derive(Debug, Default)]
+pub struct InflightReads {
+    reads: Mutex<HashMap<(u64, usize), Arc<Inflight>>>,
+}
+
+impl InflightReads {
+    /// Fill `buf` with the data at `offset`, either by calling `read` or by waiting for another
+    /// thread's identical read.
+    fn read_exact_at<B, F>(&self, buf: &mut B, offset: u64, read: F) -> IoResult<()>
+    where
+        B: AsMut<[u8]> + AsRef<[u8]> + ?Sized,
+        F: FnOnce(&mut B) -> IoResult<()>,
+    {
+        let key = (offset, buf.as_ref().len());
+        let mut reads = self.reads.lock().unwrap();
+        if let Some(slot) = reads.get(&key).cloned() {
+            drop(reads);
+            let mut result = slot.result.lock().unwrap();
+            while result.is_none() {
+                result = slot.done.wait(result).unwrap();
+            }
+            return match result.as_ref().unwrap() {
+                Ok(data) => {
+                    buf.as_mut().copy_from_slice(data);
+                    Ok(())
+                }
+                Err((_, Some(errno), _)) => Err(io::Error::from_raw_os_error(*errno)),
+                Err((kind, None, msg)) => Err(io::Error::new(*kind, msg.clone())),
+            };
+        }
+        let slot = Arc::<Inflight>::default();
+        reads.insert(key, slot.clone());
+        drop(reads);
+
+        let mut leader = Leader {
+            reads: self,
+            key,
+            slot: Some(slot),
+        };
+        let r = read(buf);
+        leader.publish(|| match &r {
+            Ok(()) => Ok(Arc::from(buf.as_ref())),
+            Err(e) => Err((e.kind(), e.raw_os_error(), e.to_string())),
+        });
+        r
+    }
+}
+
+/// A whole image, memory-mapped, and the current offset within it
+#[derive(Debug)]
+struct Mapped {
+    map: Mmap,
+    pos: usize,
+}
+
+impl Mapped {
+    fn remaining(&self) -> &[u8] {
+        &self.map[self.pos..]
+    }
+}
+
 #[derive(Debug)]
 pub struct BlockReader {
     file:       File,
+    /// Where `file` was opened from, so [`try_clone`](Self::try_clone) can open it again
+    path:       PathBuf,
     block:      Vec<u8>,
     idx:        usize,
     /// The absolute minimum that we can read in any operation
     sectorsize: usize,
+    /// Is `sectorsize` a disk device's real sector size, rather than a file's preferred I/O size?
+    is_device:  bool,
+    /// If set, reads will time out
+    watchdog:   Option<Watchdog>,
+    /// Reads in progress, shared with any other readers of the same device
+    inflight:   Arc<InflightReads>,
+    /// Counts the bytes read
+    stats:      Arc<Stats>,
+    /// The image, if it's a regular file that we've memory-mapped.  Then all reads come from
+    /// here, and `block`, `idx`, and the file's offset are unused.
+    mapped:     Option<Mapped>,
 }
 
 impl BlockReader {
-    fn sectorsize(f: &File) -> usize {
+    /// Return the sector size, and whether it came from a disk device
+    fn sectorsize(f: &File) -> (usize, bool) {
         let md = f.metadata().unwrap();
         cfg_if! {
             if #[cfg(target_os = "freebsd")] {
@@ -68,30 +315,153 @@ impl BlockReader {
                     unsafe {
                         // This ioctl is always safe
                         ffi::diocgsectorsize(f.as_raw_fd(), sectorsize.as_mut_ptr()).unwrap();
-                        return sectorsize.assume_init() as usize;
+                        return (sectorsize.assume_init() as usize, true);
                     }
                 }
             }
         }
-        md.blksize() as usize
+        (md.blksize() as usize, false)
     }
 
     pub fn open(path: &Path) -> IoResult<Self> {
-        let file = File::options().read(true).write(false).open(path)?;
+        Self::open_with(path, false)
+    }
 
-        let sectorsize = Self::sectorsize(&file);
+    /// Open the device for writing as well as reading, so [`BlockReader::write_at`] can be used.
+    pub fn open_rw(path: &Path) -> IoResult<Self> {
+        Self::open_with(path, true)
+    }
+
+    fn open_with(path: &Path, write: bool) -> IoResult<Self> {
+        let file = File::options().read(true).write(write).open(path)?;
+
+        let (sectorsize, is_device) = Self::sectorsize(&file);
         let block = vec![0u8; sectorsize];
+        let mapped = if file.metadata()?.is_file() {
+            // Safety: if another process modifies the image while it's mapped, we'll decode
+            // garbage, just as we would if it did so between two reads.  If it truncates the
+            // image, we'll get SIGBUS; that's what --no-mmap is for.
+            let map = unsafe { Mmap::map(&file)? };
+            Some(Mapped { map, pos: 0 })
+        } else {
+            None
+        };
         Ok(Self {
             file,
+            path: path.to_owned(),
             block,
             idx: sectorsize,
             sectorsize,
+            is_device,
+            watchdog: None,
+            inflight: Arc::default(),
+            stats: Arc::default(),
+            mapped,
         })
     }
 
+    /// Open another, read-only, reader of the same device, for use by another thread.  It has its
+    /// own file descriptor, with its own offset, and its own buffer.  It copies this reader's
+    /// bufsize, read timeout, and whether it's memory-mapped, and shares any identical reads that
+    /// they're both making at once.
+    pub fn try_clone(&self) -> IoResult<Self> {
+        let mut clone = Self::open_with(&self.path, false)?;
+        if self.mapped.is_none() {
+            clone.unmap();
+        }
+        clone.set_read_timeout(self.watchdog.as_ref().map(|watchdog| watchdog.timeout))?;
+        clone.set_bufsize(self.bufsize());
+        clone.inflight = self.inflight.clone();
+        clone.stats = self.stats.clone();
+        Ok(clone)
+    }
+
+    /// The counters of the bytes read, which clones share
+    pub fn stats(&self) -> &Arc<Stats> {
+        &self.stats
+    }
+
+    /// Count the bytes read with `stats`, as with another device's.
+    pub fn set_stats(&mut self, stats: Arc<Stats>) {
+        self.stats = stats;
+    }
+
+    /// Is the image memory-mapped?
+    pub fn is_mapped(&self) -> bool {
+        self.mapped.is_some()
+    }
+
+    /// Stop using the memory map, if any, and read the image with sector-aligned reads instead.
+    /// Afterwards, as after set_bufsize, the position is undefined until the next absolute seek.
+    pub fn unmap(&mut self) {
+        self.mapped = None;
+        self.idx = self.block.len();
+    }
+
+    /// The underlying disk device's sector size, if it is a disk device.  Every read is rounded
+    /// to a multiple of it.
+    pub fn device_sectorsize(&self) -> Option<usize> {
+        self.is_device.then_some(self.sectorsize)
+    }
+
+    /// Make every subsequent read fail with EIO if the device doesn't respond in time.  A page
+    /// fault can't time out, so this unmaps the image.
+    pub fn set_read_timeout(&mut self, timeout: Option<ReadTimeout>) -> IoResult<()> {
+        self.watchdog = match timeout {
+            Some(timeout) => {
+                self.unmap();
+                let device = Arc::new(self.file.try_clone()?);
+                Some(Watchdog::new(device, timeout))
+            }
+            None => None,
+        };
+        Ok(())
+    }
+
     fn refill(&mut self) -> IoResult<()> {
-        self.file.read_exact(&mut self.block)?;
-        self.idx = 0;
+        let pos = self.file.stream_position()?;
+        if trace::enabled() {
+            trace::record(pos, self.block.len());
+        }
+        self.stats.read_bytes(self.block.len());
+        let (file, watchdog) = (&self.file, &mut self.watchdog);
+        let r = self
+            .inflight
+            .read_exact_at(&mut self.block, pos, |block| match watchdog {
+                Some(watchdog) => watchdog.read_exact_at(block, pos),
+                None => file.read_exact_at(block, pos),
+            })
+            .and_then(|()| self.file.seek(SeekFrom::Current(self.block.len() as i64)));
+        if r.is_err() {
+            // A short read leaves the buffer partially overwritten.  Never return any of it.  As
+            // after set_bufsize, the position is undefined until the next absolute seek.
+            self.idx = self.block.len();
+        } else {
+            self.idx = 0;
+        }
+        r.map(drop)
+    }
+
+    /// Read directly into `buf`, bypassing the internal buffer.  `buf` must be a multiple of the
+    /// bufsize.
+    fn read_direct(&mut self, buf: &mut [u8]) -> IoResult<()> {
+        let pos = self.file.stream_position()?;
+        if trace::enabled() {
+            trace::record(pos, buf.len());
+        }
+        self.stats.read_bytes(buf.len());
+        let (file, watchdog) = (&self.file, &mut self.watchdog);
+        self.inflight
+            .read_exact_at(buf, pos, |buf| match watchdog {
+                Some(watchdog) => {
+                    let mut v = vec![0u8; buf.len()];
+                    watchdog.read_exact_at(&mut v, pos)?;
+                    buf.copy_from_slice(&v);
+                    Ok(())
+                }
+                None => file.read_exact_at(buf, pos),
+            })?;
+        self.file.seek(SeekFrom::Current(buf.len() as i64))?;
         Ok(())
     }
 
@@ -106,6 +476,37 @@ impl BlockReader {
         Ok(())
     }
 
+    /// Write `buf` at byte offset `offset`.  Devices can only be written in whole sectors, so any
+    /// parts of the first and last sectors outside of `buf` are read back first.  Afterwards, as
+    /// after set_bufsize, the buffer is undefined until the next absolute Seek operation.
+    pub fn write_at(&mut self, buf: &[u8], offset: u64) -> IoResult<()> {
+        let ss = self.sectorsize as u64;
+        let start = offset / ss * ss;
+        let end = (offset + buf.len() as u64).div_ceil(ss) * ss;
+        let mut sectors = vec![0u8; (end - start) as usize];
+        let head = (offset - start) as usize;
+        if head > 0 {
+            self.file
+                .read_exact_at(&mut sectors[..self.sectorsize], start)?;
+        }
+        if (head + buf.len()) % self.sectorsize > 0 {
+            let last = sectors.len() - self.sectorsize;
+            self.file.read_exact_at(&mut sectors[last..], end - ss)?;
+        }
+        sectors[head..head + buf.len()].copy_from_slice(buf);
+        if trace::enabled() {
+            trace::record(start, sectors.len());
+        }
+        // Never let the buffer serve stale data
+        self.idx = self.block.len();
+        self.file.write_all_at(&sectors, start)
+    }
+
+    /// Flush everything written so far to stable storage
+    pub fn sync(&self) -> IoResult<()> {
+        self.file.sync_data()
+    }
+
     /// The current size of the buffer
     pub fn bufsize(&self) -> usize {
         self.block.len()
@@ -113,8 +514,11 @@ impl BlockReader {
 
     /// Change the reader's bufsize.  It will be rounded up to a multiple of the sectorsize.
     /// After this operation, the buffer should be considered undefined until the next absolute
-    /// Seek operation.
+    /// Seek operation.  A memory-mapped image has no buffer, so then this does nothing.
     pub fn set_bufsize(&mut self, bufsize: usize) {
+        if self.mapped.is_some() {
+            return;
+        }
         let remainder = bufsize & (self.sectorsize - 1);
         let bufsize = if remainder > 0 {
             bufsize + self.sectorsize - remainder
@@ -128,6 +532,19 @@ impl BlockReader {
 
 impl Read for BlockReader {
     fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        if let Some(mapped) = &mut self.mapped {
+            let num = buf.len().min(mapped.remaining().len());
+            buf[..num].copy_from_slice(&mapped.remaining()[..num]);
+            mapped.pos += num;
+            self.stats.read_bytes(num);
+            return Ok(num);
+        }
+        if self.buffered() == 0 && buf.len() >= self.bufsize() {
+            // Large reads bypass the buffer, so they can be satisfied with a single operation.
+            let num = buf.len() / self.bufsize() * self.bufsize();
+            self.read_direct(&mut buf[..num])?;
+            return Ok(num);
+        }
         self.refill_if_empty()?;
         let num = buf.len().min(self.buffered());
         let buf = &mut buf[0..num];
@@ -139,11 +556,22 @@ impl Read for BlockReader {
 
 impl BufRead for BlockReader {
     fn fill_buf(&mut self) -> IoResult<&[u8]> {
-        self.refill_if_empty()?;
-        Ok(&self.block[self.idx..])
+        if self.mapped.is_none() {
+            self.refill_if_empty()?;
+        }
+        Ok(match &self.mapped {
+            Some(mapped) => mapped.remaining(),
+            None => &self.block[self.idx..],
+        })
     }
 
     fn consume(&mut self, amt: usize) {
+        if let Some(mapped) = &mut self.mapped {
+            assert!(amt <= mapped.remaining().len());
+            mapped.pos += amt;
+            self.stats.read_bytes(amt);
+            return;
+        }
         assert!(amt <= self.buffered());
         self.idx += amt;
     }
@@ -151,6 +579,25 @@ impl BufRead for BlockReader {
 
 impl Seek for BlockReader {
     fn seek(&mut self, pos: SeekFrom) -> IoResult<u64> {
+        if let Some(mapped) = &mut self.mapped {
+            let new = match pos {
+                SeekFrom::Start(pos) => Some(pos),
+                SeekFrom::Current(offset) => (mapped.pos as u64).checked_add_signed(offset),
+                SeekFrom::End(offset) => (mapped.map.len() as u64).checked_add_signed(offset),
+            };
+            return match new {
+                None => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+                // Like a sector-aligned read, fail at once, and leave nothing to be read.
+                Some(new) if new >= mapped.map.len() as u64 => {
+                    mapped.pos = mapped.map.len();
+                    Err(io::Error::from(io::ErrorKind::UnexpectedEof))
+                }
+                Some(new) => {
+                    mapped.pos = new as usize;
+                    Ok(new)
+                }
+            };
+        }
         let bs = self.bufsize() as u64;
         match pos {
             SeekFrom::Start(pos) => {
@@ -191,6 +638,9 @@ impl Reader for BlockReader {
     }
 
     fn peek_read(&mut self, n: usize) -> Option<&[u8]> {
+        if let Some(mapped) = &self.mapped {
+            return mapped.remaining().get(..n);
+        }
         self.block[self.idx..].get(..n)
     }
 
@@ -201,8 +651,11 @@ impl Reader for BlockReader {
 
 #[cfg(test)]
 mod t {
+    use rstest::rstest;
+
     use super::*;
 
+    /// These test the sector-aligned read path, and its buffer
     mod seek {
         use super::*;
 
@@ -211,7 +664,8 @@ mod t {
         fn harness() -> BlockReader {
             let f = tempfile::NamedTempFile::new().unwrap();
             f.as_file().set_len(FSIZE).unwrap();
-            let br = BlockReader::open(f.path()).unwrap();
+            let mut br = BlockReader::open(f.path()).unwrap();
+            br.unmap();
             let bs = br.bufsize();
             assert!(FSIZE > 2 * bs as u64);
             br
@@ -297,4 +751,468 @@ mod t {
             );
         }
     }
+
+    /// Reads larger than the buffer should bypass it
+    #[test]
+    fn read_direct() {
+        let f = tempfile::NamedTempFile::new().unwrap();
+        f.as_file().set_len(1 << 20).unwrap();
+        let mut br = BlockReader::open(f.path()).unwrap();
+        br.unmap();
+        let bs = br.bufsize();
+        f.as_file().write_all_at(&[1, 2, 3, 4], bs as u64).unwrap();
+        f.as_file()
+            .write_all_at(&[5, 6, 7, 8], 3 * bs as u64 - 2)
+            .unwrap();
+
+        br.seek(SeekFrom::Start(bs as u64 - 2)).unwrap();
+        let mut buf = vec![0u8; 2 * bs + 4];
+        br.read_exact(&mut buf).unwrap();
+        assert_eq!(buf[2..6], [1, 2, 3, 4]);
+        assert_eq!(buf[2 * bs..], [5, 6, 7, 8]);
+        assert_eq!(
+            br.file.stream_position().unwrap(),
+            4 * bs as u64,
+            "the tail of the read should've refilled the buffer"
+        );
+    }
+
+    /// Writes that don't start or end on a sector boundary must preserve the rest of the sectors
+    /// that they touch, and mustn't leave stale data in the buffer or the map.
+    #[rstest]
+    fn write_at(#[values(true, false)] mapped: bool) {
+        let f = tempfile::NamedTempFile::new().unwrap();
+        f.as_file().write_all_at(&vec![0xaa; 1 << 20], 0).unwrap();
+        let mut br = BlockReader::open_rw(f.path()).unwrap();
+        if !mapped {
+            br.unmap();
+        }
+        let ss = br.sectorsize as u64;
+        br.seek(SeekFrom::Start(ss)).unwrap();
+
+        br.write_at(&[1, 2, 3, 4], 2 * ss - 2).unwrap();
+
+        let mut buf = [0u8; 8];
+        br.seek(SeekFrom::Start(2 * ss - 4)).unwrap();
+        br.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [0xaa, 0xaa, 1, 2, 3, 4, 0xaa, 0xaa]);
+    }
+
+    /// A read-only BlockReader can't write
+    #[test]
+    fn write_at_ro() {
+        let f = tempfile::NamedTempFile::new().unwrap();
+        f.as_file().set_len(1 << 20).unwrap();
+        let mut br = BlockReader::open(f.path()).unwrap();
+        assert!(br.write_at(&[1], 0).is_err());
+    }
+
+    /// A short read, as at the end of a truncated image, must fail without leaving any of the
+    /// partially overwritten buffer to be read.
+    #[test]
+    fn short_read() {
+        let f = tempfile::NamedTempFile::new().unwrap();
+        let mut br = BlockReader::open(f.path()).unwrap();
+        br.unmap();
+        let bs = br.bufsize();
+        f.as_file().set_len(bs as u64 + 100).unwrap();
+        f.as_file().write_all_at(&[1, 2, 3, 4], 0).unwrap();
+
+        br.seek(SeekFrom::Start(0)).unwrap();
+        assert_eq!(br.peek_read(4), Some(&[1, 2, 3, 4][..]));
+        let e = br.seek(SeekFrom::Start(bs as u64)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(br.peek_read(1), None);
+    }
+
+    /// A clone reads the same device, but seeks independently of the original
+    #[rstest]
+    fn try_clone(#[values(true, false)] mapped: bool) {
+        let f = tempfile::NamedTempFile::new().unwrap();
+        f.as_file().set_len(1 << 20).unwrap();
+        f.as_file().write_all_at(&[1, 2, 3, 4], 4096).unwrap();
+        f.as_file().write_all_at(&[5, 6, 7, 8], 8192).unwrap();
+        let mut br = BlockReader::open(f.path()).unwrap();
+        if !mapped {
+            br.unmap();
+        }
+        br.set_bufsize(4096);
+        let mut clone = br.try_clone().unwrap();
+        assert_eq!(clone.is_mapped(), mapped);
+        assert_eq!(clone.bufsize(), br.bufsize());
+        assert!(Arc::ptr_eq(&clone.inflight, &br.inflight));
+        assert!(Arc::ptr_eq(&clone.stats, &br.stats));
+
+        br.seek(SeekFrom::Start(4096)).unwrap();
+        clone.seek(SeekFrom::Start(8192)).unwrap();
+        let mut buf = [0u8; 4];
+        br.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 3, 4]);
+        clone.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [5, 6, 7, 8]);
+        assert!(clone.write_at(&[1], 0).is_err());
+    }
+
+    /// A sector-aligned reader counts whole sectors read from the device, and a mapped one counts
+    /// the bytes copied from the map.
+    #[rstest]
+    #[case::mapped(true, 4)]
+    #[case::unmapped(false, 4096)]
+    fn stats(#[case] mapped: bool, #[case] expected: u64) {
+        let f = tempfile::NamedTempFile::new().unwrap();
+        f.as_file().set_len(1 << 20).unwrap();
+        let mut br = BlockReader::open(f.path()).unwrap();
+        if !mapped {
+            br.unmap();
+        }
+        br.set_bufsize(4096);
+        br.seek(SeekFrom::Start(4096)).unwrap();
+        let mut buf = [0u8; 4];
+        br.read_exact(&mut buf).unwrap();
+        assert_eq!(br.stats().device_bytes(), expected);
+    }
+
+    mod mapped {
+        use super::*;
+
+        const FSIZE: usize = 1 << 20;
+
+        /// A mapped reader of a file whose every byte is a function of its offset
+        fn harness() -> BlockReader {
+            let f = tempfile::NamedTempFile::new().unwrap();
+            let data = (0..FSIZE).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+            f.as_file().write_all_at(&data, 0).unwrap();
+            let br = BlockReader::open(f.path()).unwrap();
+            assert!(br.is_mapped());
+            br
+        }
+
+        fn expected(range: std::ops::Range<usize>) -> Vec<u8> {
+            range.map(|i| (i % 251) as u8).collect()
+        }
+
+        /// Every way of reading a mapped image must return the same data as the read path.
+        #[test]
+        fn same_as_unmapped() {
+            let mut mapped = harness();
+            let mut unmapped = harness();
+            unmapped.unmap();
+            for br in [&mut mapped, &mut unmapped] {
+                let bs = br.bufsize();
+                br.seek(SeekFrom::Start(bs as u64 - 3)).unwrap();
+                let mut buf = vec![0u8; 3 * bs + 7];
+                br.read_exact(&mut buf).unwrap();
+                assert_eq!(buf, expected(bs - 3..4 * bs + 4));
+                br.seek(SeekFrom::Current(-10)).unwrap();
+                assert_eq!(
+                    br.fill_buf().unwrap()[..4],
+                    expected(4 * bs - 6..4 * bs - 2)
+                );
+                BufRead::consume(br, 2);
+                assert_eq!(br.peek_read(4).unwrap(), expected(4 * bs - 4..4 * bs));
+                assert_eq!(br.stream_position().unwrap(), 4 * bs as u64 - 4);
+            }
+        }
+
+        /// A mapped reader has no buffer to resize
+        #[test]
+        fn set_bufsize() {
+            let mut br = harness();
+            let bs = br.bufsize();
+            br.set_bufsize(4 * bs);
+            assert_eq!(br.bufsize(), bs);
+            br.seek(SeekFrom::Start(5)).unwrap();
+            assert_eq!(br.peek_read(8 * bs).unwrap(), expected(5..5 + 8 * bs));
+        }
+
+        /// Seeking before the start fails with EINVAL, and seeking past the end fails at once,
+        /// leaving nothing to read.
+        #[test]
+        fn seek_out_of_bounds() {
+            let mut br = harness();
+            br.seek(SeekFrom::Start(100)).unwrap();
+            let e = br.seek(SeekFrom::Current(-101)).unwrap_err();
+            assert_eq!(libc::EINVAL, e.raw_os_error().unwrap());
+            assert_eq!(br.stream_position().unwrap(), 100);
+
+            let e = br.seek(SeekFrom::Start(FSIZE as u64)).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+            assert_eq!(br.peek_read(1), None);
+            let mut buf = [0u8; 1];
+            assert_eq!(Read::read(&mut br, &mut buf).unwrap(), 0);
+        }
+
+        /// Seeking relative to the end works like any other seek
+        #[test]
+        fn seek_end() {
+            let mut br = harness();
+            assert_eq!(br.seek(SeekFrom::End(-10)).unwrap(), FSIZE as u64 - 10);
+            assert_eq!(br.peek_read(10).unwrap(), expected(FSIZE - 10..FSIZE));
+            let e = br.seek(SeekFrom::End(-(FSIZE as i64) - 1)).unwrap_err();
+            assert_eq!(libc::EINVAL, e.raw_os_error().unwrap());
+            let e = br.seek(SeekFrom::End(0)).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        }
+
+        /// Reads can't time out on a mapped image, so setting a read timeout unmaps it.
+        #[test]
+        fn read_timeout_unmaps() {
+            let mut br = harness();
+            let timeout = ReadTimeout {
+                timeout: Duration::from_secs(1),
+                retries: 0,
+            };
+            br.set_read_timeout(Some(timeout)).unwrap();
+            assert!(!br.is_mapped());
+            br.seek(SeekFrom::Start(1000)).unwrap();
+            assert_eq!(br.peek_read(4).unwrap(), expected(1000..1004));
+        }
+    }
+
+    mod inflight {
+        use std::sync::{
+            atomic::{AtomicU32, Ordering},
+            Barrier,
+        };
+
+        use super::*;
+
+        const THREADS: usize = 16;
+        const DELAY: Duration = Duration::from_millis(200);
+
+        /// Issue the same read from many threads at once, with a slow `read`, and return each
+        /// thread's result and how many times `read` was called.
+        fn concurrent<F>(read: F) -> (Vec<IoResult<Vec<u8>>>, u32)
+        where
+            F: Fn(&mut [u8]) -> IoResult<()> + Sync,
+        {
+            let inflight = InflightReads::default();
+            let reads = AtomicU32::new(0);
+            let barrier = Barrier::new(THREADS);
+            let results = thread::scope(|s| {
+                let handles = (0..THREADS)
+                    .map(|_| {
+                        s.spawn(|| {
+                            let mut buf = vec![0u8; 512];
+                            barrier.wait();
+                            inflight
+                                .read_exact_at(&mut buf[..], 4096, |buf| {
+                                    reads.fetch_add(1, Ordering::Relaxed);
+                                    thread::sleep(DELAY);
+                                    read(buf)
+                                })
+                                .map(|()| buf)
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                handles
+                    .into_iter()
+                    .map(|h| h.join().unwrap())
+                    .collect::<Vec<_>>()
+            });
+            assert!(inflight.reads.lock().unwrap().is_empty());
+            (results, reads.into_inner())
+        }
+
+        /// Concurrent reads of the same block should read the device only once.
+        #[test]
+        fn coalesce() {
+            let (results, reads) = concurrent(|buf| {
+                buf.fill(42);
+                Ok(())
+            });
+            assert_eq!(1, reads);
+            for r in results {
+                assert!(r.unwrap().iter().all(|b| *b == 42));
+            }
+        }
+
+        /// If the one read fails, every waiter should get its error.
+        #[test]
+        fn error() {
+            let (results, reads) = concurrent(|_| Err(io::Error::from_raw_os_error(libc::EIO)));
+            assert_eq!(1, reads);
+            for r in results {
+                assert_eq!(libc::EIO, r.unwrap_err().raw_os_error().unwrap());
+            }
+        }
+
+        /// Reads of different blocks should not wait for each other.
+        #[test]
+        fn distinct() {
+            let inflight = InflightReads::default();
+            let mut buf = [0u8; 512];
+            inflight
+                .read_exact_at(&mut buf[..], 0, |outer| {
+                    let mut inner = [0u8; 512];
+                    inflight.read_exact_at(&mut inner[..], 512, |inner| {
+                        inner.fill(2);
+                        Ok(())
+                    })?;
+                    outer.copy_from_slice(&inner);
+                    Ok(())
+                })
+                .unwrap();
+            assert!(buf.iter().all(|b| *b == 2));
+        }
+    }
+
+    mod timeout {
+        use std::{
+            sync::atomic::{AtomicU32, Ordering},
+            time::Instant,
+        };
+
+        use super::*;
+
+        const TIMEOUT: Duration = Duration::from_millis(50);
+        const DELAY: Duration = Duration::from_millis(500);
+
+        /// A fake device whose first few reads stall
+        #[derive(Default)]
+        struct SlowDevice {
+            /// How many more reads will stall
+            slow_reads: AtomicU32,
+            /// How many reads have been attempted
+            reads:      AtomicU32,
+        }
+
+        impl SlowDevice {
+            fn new(slow_reads: u32) -> Arc<Self> {
+                Arc::new(SlowDevice {
+                    slow_reads: AtomicU32::new(slow_reads),
+                    ..Default::default()
+                })
+            }
+        }
+
+        impl FileExt for SlowDevice {
+            fn read_at(&self, buf: &mut [u8], offset: u64) -> IoResult<usize> {
+                self.reads.fetch_add(1, Ordering::Relaxed);
+                let slow = self
+                    .slow_reads
+                    .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+                    .is_ok();
+                if slow {
+                    thread::sleep(DELAY);
+                }
+                buf.fill(offset as u8);
+                Ok(buf.len())
+            }
+
+            fn write_at(&self, _buf: &[u8], _offset: u64) -> IoResult<usize> {
+                Err(io::Error::from_raw_os_error(libc::EROFS))
+            }
+        }
+
+        fn watchdog(device: &Arc<SlowDevice>, retries: u32) -> Watchdog {
+            let timeout = ReadTimeout {
+                timeout: TIMEOUT,
+                retries,
+            };
+            Watchdog::new(device.clone(), timeout)
+        }
+
+        /// A stalled read should fail with EIO, and a subsequent fast read should succeed.
+        #[test]
+        fn eio() {
+            let device = SlowDevice::new(1);
+            let mut wd = watchdog(&device, 0);
+            let mut buf = vec![0u8; 512];
+
+            let start = Instant::now();
+            let e = wd.read_exact_at(&mut buf, 0).unwrap_err();
+            assert_eq!(libc::EIO, e.raw_os_error().unwrap());
+            assert!(start.elapsed() < DELAY);
+            assert_eq!(buf.len(), 512);
+
+            wd.read_exact_at(&mut buf, 7).unwrap();
+            assert!(buf.iter().all(|b| *b == 7));
+        }
+
+        /// Reads that time out should be retried
+        #[test]
+        fn retries() {
+            let device = SlowDevice::new(2);
+            let mut wd = watchdog(&device, 2);
+            let mut buf = vec![0u8; 512];
+
+            wd.read_exact_at(&mut buf, 3).unwrap();
+            assert!(buf.iter().all(|b| *b == 3));
+            assert_eq!(3, device.reads.load(Ordering::Relaxed));
+        }
+
+        /// Once a stalled read finally completes, its helper thread should exit.
+        #[test]
+        fn no_leak() {
+            let device = SlowDevice::new(1);
+            let mut wd = watchdog(&device, 0);
+            let mut buf = vec![0u8; 512];
+
+            wd.read_exact_at(&mut buf, 0).unwrap_err();
+            wd.read_exact_at(&mut buf, 0).unwrap();
+            // Held by this test, the Watchdog, the live worker, and the abandoned worker
+            assert_eq!(4, Arc::strong_count(&device));
+
+            let deadline = Instant::now() + 10 * DELAY;
+            while Arc::strong_count(&device) > 3 {
+                assert!(
+                    Instant::now() < deadline,
+                    "abandoned read thread never exited"
+                );
+                thread::sleep(TIMEOUT);
+            }
+            drop(wd);
+            while Arc::strong_count(&device) > 1 {
+                assert!(Instant::now() < deadline, "read thread never exited");
+                thread::sleep(TIMEOUT);
+            }
+        }
+
+        /// A device that stays hung shouldn't strand a thread for every read.  Once enough are
+        /// stuck, reads should fail without trying, until the stuck ones return.
+        #[test]
+        fn max_abandoned() {
+            let device = SlowDevice::new(MAX_ABANDONED as u32);
+            let mut wd = watchdog(&device, 0);
+            let mut buf = vec![0u8; 512];
+
+            for _ in 0..MAX_ABANDONED {
+                wd.read_exact_at(&mut buf, 0).unwrap_err();
+            }
+            let start = Instant::now();
+            let e = wd.read_exact_at(&mut buf, 0).unwrap_err();
+            assert_eq!(libc::EIO, e.raw_os_error().unwrap());
+            assert!(start.elapsed() < TIMEOUT);
+            assert_eq!(buf.len(), 512);
+            assert_eq!(MAX_ABANDONED as u32, device.reads.load(Ordering::Relaxed));
+
+            let deadline = Instant::now() + 10 * DELAY;
+            while wd.abandoned.load(Ordering::Relaxed) > 0 {
+                assert!(Instant::now() < deadline, "stuck reads never returned");
+                thread::sleep(TIMEOUT);
+            }
+            wd.read_exact_at(&mut buf, 5).unwrap();
+            assert!(buf.iter().all(|b| *b == 5));
+        }
+
+        /// BlockReader should use the watchdog to refill its buffer
+        #[test]
+        fn block_reader() {
+            let f = tempfile::NamedTempFile::new().unwrap();
+            f.as_file().set_len(1 << 20).unwrap();
+            f.as_file().write_all_at(&[1, 2, 3, 4], 4096).unwrap();
+            let mut br = BlockReader::open(f.path()).unwrap();
+            let timeout = ReadTimeout {
+                timeout: Duration::from_secs(10),
+                retries: 0,
+            };
+            br.set_read_timeout(Some(timeout)).unwrap();
+
+            br.seek(SeekFrom::Start(4096)).unwrap();
+            let mut buf = [0u8; 4];
+            br.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, [1, 2, 3, 4]);
+        }
+    }
 }
@@ -29,7 +29,7 @@
 use bincode::{de::Decoder, error::DecodeError, Decode};
 use num_derive::FromPrimitive;
 
-use super::{definitions::*, volume::SUPERBLOCK};
+use super::{definitions::*, sb::Sb};
 
 #[derive(Debug, FromPrimitive, Clone)]
 pub enum XfsExntst {
@@ -70,7 +70,33 @@ impl Decode for BmbtRec {
     }
 }
 
-/// An ordered list of [`BmbtRec`].
+/// How a file block is stored, as found by [`Bmx::get_extent`]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Mapping {
+    /// Written data, beginning at this file system block
+    Data(XfsFsblock),
+    /// A preallocated extent that hasn't been written yet.  It reads as zeros, and lseek treats
+    /// it as a hole.
+    Unwritten,
+    /// Not allocated at all
+    Hole,
+}
+
+impl Mapping {
+    /// The file system block where the data begins, if there is any data
+    pub fn fsblock(self) -> Option<XfsFsblock> {
+        match self {
+            Mapping::Data(fsblock) => Some(fsblock),
+            Mapping::Unwritten | Mapping::Hole => None,
+        }
+    }
+
+    pub fn is_data(self) -> bool {
+        matches!(self, Mapping::Data(_))
+    }
+}
+
+/// An ordered list of [`BmbtRec`], including unwritten extents.
 #[derive(Debug, Clone)]
 pub struct Bmx(Vec<BmbtRec>);
 
@@ -79,130 +105,215 @@ impl Bmx {
     where
         I: IntoIterator<Item = &'a BmbtRec>,
     {
-        // Filter out preallocated but unwritten extents.  This makes the lseek implementation much
-        // easier than if we try to consider the br_flag field in the lseek method itself.
-        let bmx = bmx
-            .into_iter()
-            .filter(|rec| !rec.br_flag)
-            .cloned()
-            .collect();
-        Self(bmx)
+        Self(bmx.into_iter().cloned().collect())
     }
 
     /// Return the extent, if any, that contains the given block within the file.
-    /// Return its starting position as an FSblock, and its length in file system block units.
+    /// Return how it is mapped, and its length in file system block units.
     /// If a hole's length extends to EoF, return None for length.
-    pub fn get_extent(&self, dblock: XfsFileoff) -> (Option<XfsFsblock>, Option<u64>) {
+    pub fn get_extent(&self, dblock: XfsFileoff) -> (Mapping, Option<u64>) {
         match self.0.partition_point(|entry| entry.br_startoff <= dblock) {
             0 => {
                 // A hole at the beginning of the file
                 let len = self.0.first().map(|b| b.br_startoff - dblock);
-                (None, len)
+                (Mapping::Hole, len)
             }
             i => {
                 let entry = &self.0[i - 1];
                 let skip = dblock - entry.br_startoff;
                 if entry.br_startoff + entry.br_blockcount > dblock {
-                    assert!(!entry.br_flag);
-                    (
-                        Some(entry.br_startblock + skip),
-                        Some(entry.br_blockcount - skip),
-                    )
+                    let mapping = if entry.br_flag {
+                        Mapping::Unwritten
+                    } else {
+                        Mapping::Data(entry.br_startblock + skip)
+                    };
+                    (mapping, Some(entry.br_blockcount - skip))
                 } else {
                     // It's a hole
-                    let len = self
-                        .0
-                        .get(i)
-                        .map(|e| e.br_startoff - entry.br_startoff - skip);
-                    (None, len)
+                    let len = self.0.get(i).map(|e| e.br_startoff - dblock);
+                    (Mapping::Hole, len)
                 }
             }
         }
     }
 
+    /// Return the first extent that contains written data
     pub fn first(&self) -> Option<&BmbtRec> {
-        self.0.first()
+        self.0.iter().find(|rec| !rec.br_flag)
     }
 
-    pub fn lseek(&self, offset: u64, whence: i32) -> Result<u64, i32> {
-        let sb = SUPERBLOCK.get().unwrap();
-
-        let dblock = offset >> sb.sb_blocklog;
-        match self.0.partition_point(|entry| entry.br_startoff <= dblock) {
-            0 => {
-                // A hole at the beginning of the file
-                if whence == libc::SEEK_HOLE {
-                    Ok(offset)
-                } else {
-                    self.first()
-                        .map(|b| b.br_startoff << sb.sb_blocklog)
-                        .ok_or(libc::ENXIO)
-                }
+    pub fn lseek(&self, sb: &Sb, offset: u64, whence: i32) -> Result<u64, i32> {
+        let want_data = whence == libc::SEEK_DATA;
+        let mut dblock = offset >> sb.sb_blocklog;
+        loop {
+            let (mapping, len) = self.get_extent(dblock);
+            if mapping.is_data() == want_data {
+                return Ok((dblock << sb.sb_blocklog).max(offset));
             }
-            i => {
-                let cur_entry = &self.0[i - 1];
-                let br_end = cur_entry.br_startoff + cur_entry.br_blockcount;
-                if dblock < br_end {
-                    // In a data region
-                    if whence == libc::SEEK_HOLE {
-                        // Scan for the next hole
-                        for j in (i - 1)..self.0.len() - 1 {
-                            let before = &self.0[j];
-                            let after = &self.0[j + 1];
-                            let br_end = before.br_startoff + before.br_blockcount;
-                            if after.br_startoff > br_end {
-                                return Ok(br_end << sb.sb_blocklog);
-                            }
-                        }
-                        // Reached EOF without finding another hole.  Return the virtual hole at
-                        // EOF
-                        let entry = self.0.last().unwrap();
-                        let br_end = entry.br_startoff + entry.br_blockcount;
-                        Ok(br_end << sb.sb_blocklog)
-                    } else {
-                        Ok(offset)
-                    }
-                } else {
-                    // In a hole
-                    if whence == libc::SEEK_HOLE {
-                        Ok(offset)
-                    } else {
-                        match self.0.get(i) {
-                            Some(next_entry) => Ok(next_entry.br_startoff << sb.sb_blocklog),
-                            None => Err(libc::ENXIO),
-                        }
-                    }
-                }
+            match len {
+                Some(len) => dblock += len,
+                // Only a hole can extend to EoF, and there's no data beyond it
+                None => return Err(libc::ENXIO),
             }
         }
     }
 
     pub fn map_dblock(&self, dblock: XfsDablk) -> Option<XfsFsblock> {
-        let dblock = XfsFileoff::from(dblock);
-        let i = self.0.partition_point(|rec| rec.br_startoff <= dblock);
-        let rec = &self.0[i - 1];
-        if i == 0 || rec.br_startoff > dblock || rec.br_startoff + rec.br_blockcount <= dblock {
-            None
-        } else {
-            Some(rec.br_startblock + dblock - rec.br_startoff)
-        }
+        self.get_extent(XfsFileoff::from(dblock)).0.fsblock()
     }
 }
 
 impl<I: IntoIterator<Item = BmbtRec>> From<I> for Bmx {
     // The same as Bmx::new, but with an owned iterator
     fn from(i: I) -> Self {
-        // Filter out preallocated but unwritten extents.  This makes the lseek implementation much
-        // easier than if we try to consider the br_flag field in the lseek method itself.
-        let bmx = i.into_iter().filter(|rec| !rec.br_flag).collect();
-        Self(bmx)
+        Self(i.into_iter().collect())
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(super) mod tests {
+    use std::sync::OnceLock;
+
+    use proptest::prelude::*;
+
     use super::*;
 
+    const BLOCKLOG: u8 = 12;
+
+    /// A superblock with 4 KiB blocks and a single AG, shared by every test that doesn't care
+    /// about the geometry
+    pub(in crate::libxfuse) fn superblock() -> &'static Sb {
+        static SUPERBLOCK: OnceLock<Sb> = OnceLock::new();
+        SUPERBLOCK.get_or_init(|| Sb::for_test(0, 1, 1 << 16, 1 << 16))
+    }
+
+    /// Generate a sorted, non-overlapping extent list, with holes and unwritten extents.  Each
+    /// extent is described by (preceding hole, length, physical gap, unwritten).
+    pub(in crate::libxfuse) fn extents() -> impl Strategy<Value = Vec<BmbtRec>> {
+        prop::collection::vec((0u64..4, 1u64..5, 0u64..3, prop::bool::weighted(0.2)), 0..8)
+            .prop_map(|v| {
+                let mut startoff = 0;
+                let mut startblock = 2;
+                v.into_iter()
+                    .map(|(hole, len, gap, unwritten)| {
+                        let rec = BmbtRec {
+                            br_startoff:   startoff + hole,
+                            br_startblock: startblock + gap,
+                            br_blockcount: len,
+                            br_flag:       unwritten,
+                        };
+                        startoff = rec.br_startoff + len;
+                        startblock = rec.br_startblock + len;
+                        rec
+                    })
+                    .collect()
+            })
+    }
+
+    /// Reference implementation of lseek, by brute force over the written blocks.
+    pub(in crate::libxfuse) fn expected_lseek(
+        recs: &[BmbtRec],
+        offset: u64,
+        whence: i32,
+    ) -> Result<u64, i32> {
+        let written = |dblock: u64| {
+            recs.iter().any(|r| {
+                !r.br_flag && r.br_startoff <= dblock && dblock < r.br_startoff + r.br_blockcount
+            })
+        };
+        let eof = recs
+            .iter()
+            .filter(|r| !r.br_flag)
+            .map(|r| r.br_startoff + r.br_blockcount)
+            .max()
+            .unwrap_or(0);
+        let dblock = offset >> BLOCKLOG;
+        if written(dblock) == (whence == libc::SEEK_DATA) {
+            return Ok(offset);
+        }
+        match (dblock + 1..=eof).find(|b| written(*b) == (whence == libc::SEEK_DATA)) {
+            Some(b) => Ok(b << BLOCKLOG),
+            None if whence == libc::SEEK_HOLE => Ok(offset),
+            None => Err(libc::ENXIO),
+        }
+    }
+
+    /// Offsets to probe: block boundaries and mid-block positions, a little past EoF.
+    pub(in crate::libxfuse) fn offsets(recs: &[BmbtRec]) -> impl Iterator<Item = u64> {
+        let end = recs
+            .last()
+            .map(|r| r.br_startoff + r.br_blockcount)
+            .unwrap_or(0);
+        (0..(end + 2) << BLOCKLOG).step_by(1 << (BLOCKLOG - 1))
+    }
+
+    /// Treat ENXIO as +∞ for the purpose of monotonicity checks
+    fn seek_key(r: Result<u64, i32>) -> u64 {
+        r.unwrap_or(u64::MAX)
+    }
+
+    proptest! {
+        /// map_dblock and get_extent must agree on every block, and get_extent's lengths must be
+        /// consistent with the blocks that follow.  Unwritten extents must be reported as such,
+        /// not as holes.
+        #[test]
+        fn get_extent_consistent(recs in extents()) {
+            let bmx = Bmx::new(&recs);
+            let end = recs.last().map(|r| r.br_startoff + r.br_blockcount).unwrap_or(0);
+            for dblock in 0..end + 2 {
+                let (mapping, len) = bmx.get_extent(dblock);
+                prop_assert_eq!(bmx.map_dblock(dblock as XfsDablk), mapping.fsblock());
+                match (mapping, len) {
+                    (Mapping::Data(fsblock), Some(len)) => {
+                        prop_assert!(len > 0);
+                        for i in 0..len {
+                            prop_assert_eq!(bmx.map_dblock((dblock + i) as XfsDablk),
+                                Some(fsblock + i));
+                        }
+                    }
+                    (Mapping::Unwritten, Some(len)) => {
+                        prop_assert!(len > 0);
+                        for i in 0..len {
+                            prop_assert_eq!(bmx.get_extent(dblock + i).0, Mapping::Unwritten);
+                        }
+                    }
+                    (Mapping::Data(_) | Mapping::Unwritten, None) => {
+                        prop_assert!(false, "extent without a length")
+                    }
+                    (Mapping::Hole, Some(len)) => {
+                        prop_assert!(len > 0);
+                        for i in 0..len {
+                            prop_assert_eq!(bmx.get_extent(dblock + i).0, Mapping::Hole);
+                        }
+                        prop_assert_ne!(bmx.get_extent(dblock + len).0, Mapping::Hole);
+                    }
+                    (Mapping::Hole, None) => {
+                        for i in dblock..end + 2 {
+                            prop_assert_eq!(bmx.get_extent(i).0, Mapping::Hole);
+                        }
+                    }
+                }
+            }
+        }
+
+        /// SEEK_DATA and SEEK_HOLE must return the first matching offset at or after the
+        /// requested one, and must be monotone.
+        #[test]
+        fn lseek(recs in extents()) {
+            let bmx = Bmx::new(&recs);
+            for whence in [libc::SEEK_DATA, libc::SEEK_HOLE] {
+                let mut prev = 0;
+                for offset in offsets(&recs) {
+                    let r = bmx.lseek(superblock(), offset, whence);
+                    prop_assert_eq!(r, expected_lseek(&recs, offset, whence),
+                        "offset={} whence={}", offset, whence);
+                    prop_assert!(seek_key(r) >= prev);
+                    prev = seek_key(r);
+                }
+            }
+        }
+    }
+
     #[test]
     fn map_dblock() {
         let bmx = Bmx::new(&[
@@ -228,4 +339,20 @@ mod tests {
 
         assert_eq!(bmx.map_dblock(6), Some(41));
     }
+
+    /// Directories may have a hole before their first data block
+    #[test]
+    fn map_dblock_leading_hole() {
+        let bmx = Bmx::new(&[BmbtRec {
+            br_startoff:   4,
+            br_startblock: 20,
+            br_blockcount: 2,
+            br_flag:       false,
+        }]);
+
+        assert_eq!(bmx.map_dblock(0), None);
+        assert_eq!(bmx.map_dblock(3), None);
+        assert_eq!(bmx.map_dblock(4), Some(20));
+        assert_eq!(bmx.map_dblock(6), None);
+    }
 }
@@ -29,7 +29,7 @@ use std::{
     cell::RefCell,
     collections::{btree_map::Entry, BTreeMap},
     io::{prelude::*, SeekFrom},
-    marker::PhantomData,
+    mem,
 };
 
 use bincode::{
@@ -40,24 +40,24 @@ use bincode::{
 use num_traits::{PrimInt, Unsigned};
 
 use super::{
-    bmbt_rec::Bmx,
-    definitions::{XfsFileoff, XfsFsblock, XFS_BMAP_CRC_MAGIC, XFS_BMAP_MAGIC},
-    utils::{decode, decode_from, Uuid},
-    volume::SUPERBLOCK,
+    bmbt_rec::{Bmx, Mapping},
+    definitions::{XfsFileoff, XfsFsblock, NULLFSBLOCK, XFS_BMAP_CRC_MAGIC, XFS_BMAP_MAGIC},
+    sb::Sb,
+    utils::{check_count, decode, decode_array, decode_from, FromBytes, Uuid},
+    validate,
 };
 
 #[derive(Clone, Copy, Debug)]
 pub struct BtreeBlockHdr<T: PrimInt + Unsigned> {
-    bb_magic:       u32,
-    pub bb_level:   u16,
-    pub bb_numrecs: u16,
-    //_bb_leftsib: T,
-    //_bb_rightsib: T,
-    _phantom:       PhantomData<T>,
+    _bb_magic:       u32,
+    pub bb_level:    u16,
+    pub bb_numrecs:  u16,
+    pub bb_leftsib:  T,
+    pub bb_rightsib: T,
     // Below fields are for V5 file systems only
     //_bb_blkno: u64,
     //_bb_lsn: u64,
-    //_bb_uuid: Uuid,
+    pub bb_uuid:     Option<Uuid>,
     //_bb_owner: u64,
     //_bb_crc: u32,
     //_bb_pad: u32,
@@ -68,31 +68,41 @@ impl<T: Decode + PrimInt + Unsigned> Decode for BtreeBlockHdr<T> {
         let bb_magic: u32 = Decode::decode(decoder)?;
         let bb_level = Decode::decode(decoder)?;
         let bb_numrecs = Decode::decode(decoder)?;
-        let _bb_leftsib: T = Decode::decode(decoder)?;
-        let _bb_rightsib: T = Decode::decode(decoder)?;
-        match bb_magic {
-            XFS_BMAP_MAGIC => {}
+        let bb_leftsib = Decode::decode(decoder)?;
+        let bb_rightsib = Decode::decode(decoder)?;
+        let bb_uuid = match bb_magic {
+            XFS_BMAP_MAGIC => None,
             XFS_BMAP_CRC_MAGIC => {
                 let _bb_blkno: u64 = Decode::decode(decoder)?;
                 let _bb_lsn: u64 = Decode::decode(decoder)?;
                 let bb_uuid: Uuid = Decode::decode(decoder)?;
-                let super_block = SUPERBLOCK.get().unwrap();
-                assert_eq!(bb_uuid, super_block.sb_uuid);
                 let _bb_owner: u64 = Decode::decode(decoder)?;
                 let _bb_crc: u32 = Decode::decode(decoder)?;
                 let _bb_pad: u32 = Decode::decode(decoder)?;
+                Some(bb_uuid)
             }
             _ => panic!("Unexpected magic value {:#x}", bb_magic),
         };
         Ok(BtreeBlockHdr {
-            bb_magic,
+            _bb_magic: bb_magic,
             bb_level,
             bb_numrecs,
-            _phantom: PhantomData,
+            bb_leftsib,
+            bb_rightsib,
+            bb_uuid,
         })
     }
 }
 
+impl<T: PrimInt + Unsigned> BtreeBlockHdr<T> {
+    /// A V5 block must belong to this file system.
+    fn check_uuid(&self, sb: &Sb) {
+        if let Some(uuid) = self.bb_uuid {
+            assert_eq!(uuid, sb.sb_uuid);
+        }
+    }
+}
+
 #[derive(Debug, Clone, Decode)]
 pub struct BmdrBlock {
     pub bb_level:   u16,
@@ -103,13 +113,19 @@ impl BmdrBlock {
     pub const SIZE: usize = 4;
 }
 
-#[derive(Debug, Clone, Decode)]
+#[derive(Debug, Clone, Decode, PartialEq)]
 pub struct BmbtKey {
     pub br_startoff: XfsFileoff,
 }
 
-impl BmbtKey {
-    pub const SIZE: usize = 8;
+impl FromBytes for BmbtKey {
+    const SIZE: usize = 8;
+
+    fn from_bytes(raw: &[u8]) -> Self {
+        BmbtKey {
+            br_startoff: u64::from_bytes(raw),
+        }
+    }
 }
 
 pub type XfsBmbtPtr = XfsFsblock;
@@ -121,20 +137,54 @@ trait BtreePriv {
     fn level(&self) -> u16;
     fn block_cache(&self) -> &RefCell<BlockCache>;
     fn ptrs(&self) -> &[XfsBmbtPtr];
+
+    /// Is this the root of the tree, stored in the inode?
+    fn is_root(&self) -> bool;
+
+    /// In strict mode, check that the idx'th child's sibling pointers agree with its neighbors in
+    /// this block.  The outermost children's outer neighbors are unknown, except at the root.
+    fn check_siblings(
+        &self,
+        idx: usize,
+        leftsib: XfsFsblock,
+        rightsib: XfsFsblock,
+    ) -> Result<(), i32> {
+        if !validate::strict() {
+            return Ok(());
+        }
+        let ptrs = self.ptrs();
+        let left = match idx {
+            0 if self.is_root() => Some(NULLFSBLOCK),
+            0 => None,
+            _ => Some(ptrs[idx - 1]),
+        };
+        let right = match ptrs.get(idx + 1) {
+            Some(ptr) => Some(*ptr),
+            None if self.is_root() => Some(NULLFSBLOCK),
+            None => None,
+        };
+        if left.is_some_and(|l| l != leftsib) || right.is_some_and(|r| r != rightsib) {
+            validate::failure(format_args!(
+                "bmap btree block {} has siblings ({:#x}, {:#x}), but expected ({:#x?}, {:#x?})",
+                ptrs[idx], leftsib, rightsib, left, right
+            ))?;
+        }
+        Ok(())
+    }
 }
 
 /// Methods that are common to both BtreeRoot and BtreeIntermediate
 #[allow(private_bounds)]
 pub trait Btree: BtreePriv {
     /// Return the extent, if any, that contains the given block within the file.
-    /// Return its starting position as an FSblock, and its length in file system block units.
+    /// Return how it is mapped, and its length in file system block units.
     /// If a hole's length extents to EoF, return None for length.
     fn map_block<R: bincode::de::read::Reader + BufRead + Seek>(
         &self,
         buf_reader: &mut R,
+        super_block: &Sb,
         logical_block: XfsFileoff,
-    ) -> Result<(Option<XfsFsblock>, Option<u64>), i32> {
-        let super_block = SUPERBLOCK.get().unwrap();
+    ) -> Result<(Mapping, Option<u64>), i32> {
         let pp = self
             .keys()
             .partition_point(|k| k.br_startoff <= logical_block);
@@ -143,7 +193,7 @@ pub trait Btree: BtreePriv {
         let idx = pp.saturating_sub(1);
 
         let mut guard = self.block_cache().borrow_mut();
-        match &mut *guard {
+        let r = match &mut *guard {
             BlockCache::Intermediate(bci) => {
                 assert!(self.level() > 1);
 
@@ -154,13 +204,20 @@ pub trait Btree: BtreePriv {
                         buf_reader
                             .seek(SeekFrom::Start(offset))
                             .map_err(|e| e.raw_os_error().unwrap())?;
-                        let bti: BtreeIntermediate =
-                            decode_from(buf_reader.by_ref()).map_err(|_| libc::EDESTADDRREQ)?;
-                        ve.insert(bti).map_block(buf_reader, logical_block)
+                        let mut raw = vec![0u8; super_block.sb_blocksize as usize];
+                        buf_reader
+                            .read_exact(&mut raw)
+                            .map_err(|e| e.raw_os_error().unwrap())?;
+                        let bti =
+                            BtreeIntermediate::from_block(&raw).map_err(|_| libc::EDESTADDRREQ)?;
+                        bti.hdr.check_uuid(super_block);
+                        self.check_siblings(idx, bti.hdr.bb_leftsib, bti.hdr.bb_rightsib)?;
+                        ve.insert(bti)
+                            .map_block(buf_reader, super_block, logical_block)
                     }
                     Entry::Occupied(oe) => {
                         let v: &BtreeIntermediate = oe.get();
-                        v.map_block(buf_reader, logical_block)
+                        v.map_block(buf_reader, super_block, logical_block)
                     }
                 }
             }
@@ -176,6 +233,8 @@ pub trait Btree: BtreePriv {
                             .map_err(|e| e.raw_os_error().unwrap())?;
                         let btl: BtreeLeaf =
                             decode_from(buf_reader.by_ref()).map_err(|_| libc::EDESTADDRREQ)?;
+                        btl.hdr.check_uuid(super_block);
+                        self.check_siblings(idx, btl.hdr.bb_leftsib, btl.hdr.bb_rightsib)?;
                         Ok(ve.insert(btl).get_extent(logical_block))
                     }
                     Entry::Occupied(oe) => {
@@ -184,6 +243,14 @@ pub trait Btree: BtreePriv {
                     }
                 }
             }
+        };
+        match (r, self.keys().get(idx + 1)) {
+            (Ok((Mapping::Hole, None)), Some(next)) => {
+                // The child thinks that this hole extends to EoF, but it really extends to the
+                // start of the next child.
+                Ok((Mapping::Hole, Some(next.br_startoff - logical_block)))
+            }
+            (r, _) => r,
         }
     }
 }
@@ -219,66 +286,29 @@ pub struct BtreeRoot {
 }
 
 impl BtreeRoot {
-    pub fn lseek<R>(&self, buf_reader: &mut R, offset: u64, whence: i32) -> Result<u64, i32>
+    pub fn lseek<R>(
+        &self,
+        buf_reader: &mut R,
+        sb: &Sb,
+        offset: u64,
+        whence: i32,
+    ) -> Result<u64, i32>
     where
         R: BufRead + Reader + Seek,
     {
-        let sb = SUPERBLOCK.get().unwrap();
-
+        // Walk from extent to extent, which may lie in different children, until the mapping
+        // changes between data and hole.  Unwritten extents count as holes.
+        let want_data = whence == libc::SEEK_DATA;
         let mut dblock = offset >> sb.sb_blocklog;
-        match self.map_block(buf_reader.by_ref(), dblock)? {
-            (None, Some(len)) => {
-                // A hole, followed by data
-                if whence == libc::SEEK_HOLE {
-                    Ok(offset)
-                } else {
-                    // It should be impossible to have two hole extents in a row.  But
-                    // double-check.
-                    debug_assert!(self
-                        .map_block(buf_reader.by_ref(), dblock + len)
-                        .unwrap()
-                        .0
-                        .is_some());
-                    Ok(offset + (len << sb.sb_blocklog))
-                }
+        loop {
+            let (mapping, len) = self.map_block(buf_reader.by_ref(), sb, dblock)?;
+            if mapping.is_data() == want_data {
+                return Ok((dblock << sb.sb_blocklog).max(offset));
             }
-            (Some(_), None) => {
-                unreachable!(
-                    "Btree::map_block should never return None for the length of a data region"
-                );
-            }
-            (Some(_), Some(len)) => {
-                // In a data region
-                if whence == libc::SEEK_HOLE {
-                    // Scan for the next hole
-                    dblock += len;
-                    loop {
-                        match self.map_block(buf_reader.by_ref(), dblock)? {
-                            (Some(_fsblock), Some(len)) => {
-                                dblock += len;
-                            }
-                            (Some(_fsblock), None) => {
-                                unreachable!(
-                                    "Btree::map_block should never return None for the length of \
-                                     a data region"
-                                );
-                            }
-                            (None, _) => {
-                                return Ok(dblock << sb.sb_blocklog);
-                            }
-                        }
-                    }
-                } else {
-                    Ok(offset)
-                }
-            }
-            (None, None) => {
-                // A hole that extends to EOF
-                if whence == libc::SEEK_HOLE {
-                    Ok(offset)
-                } else {
-                    Err(libc::ENXIO)
-                }
+            match len {
+                Some(len) => dblock += len,
+                // Only a hole can extend to EoF, and there's no data beyond it
+                None => return Err(libc::ENXIO),
             }
         }
     }
@@ -310,6 +340,10 @@ impl BtreePriv for BtreeRoot {
     fn ptrs(&self) -> &[XfsBmdrPtr] {
         &self.ptrs
     }
+
+    fn is_root(&self) -> bool {
+        true
+    }
 }
 
 impl Btree for BtreeRoot {}
@@ -340,39 +374,37 @@ impl BtreePriv for BtreeIntermediate {
     fn ptrs(&self) -> &[XfsBmbtPtr] {
         &self.ptrs
     }
+
+    fn is_root(&self) -> bool {
+        false
+    }
 }
 
 impl Btree for BtreeIntermediate {}
 
-impl Decode for BtreeIntermediate {
-    fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError> {
-        let blocksize = SUPERBLOCK.get().unwrap().sb_blocksize as usize;
-        let mut raw = vec![0u8; blocksize];
-        decoder.reader().read(&mut raw)?;
-        let (hdr, mut ofs) = decode::<XfsBmbtLblock>(&raw)?;
+/// Offset of the pointers within an intermediate bmap btree block, whose header is `hdrlen` bytes
+/// long.  Like XFS_BMBT_PTR_ADDR, they follow room for as many keys as the block could hold.
+/// With 4k blocks that's the 0x808 documented in the XFS Algorithms & Data Structures
+/// document, section 16.2, or 0x820 for V5.
+fn ptrs_offset(blocksize: usize, hdrlen: usize) -> usize {
+    let maxrecs = (blocksize - hdrlen) / (BmbtKey::SIZE + mem::size_of::<XfsBmbtPtr>());
+    hdrlen + maxrecs * BmbtKey::SIZE
+}
+
+impl BtreeIntermediate {
+    /// Decode an intermediate node from a whole file system block.
+    fn from_block(raw: &[u8]) -> Result<Self, DecodeError> {
+        let blocksize = raw.len();
+        let (hdr, ofs) = decode::<XfsBmbtLblock>(raw)?;
         assert!(hdr.bb_level > 0);
 
-        let mut keys = Vec::with_capacity(usize::from(hdr.bb_numrecs));
-        for _ in 0..hdr.bb_numrecs {
-            let (key, keylen) = decode(&raw[ofs..])?;
-            ofs += keylen;
-            keys.push(key);
-        }
+        let numrecs = usize::from(hdr.bb_numrecs);
+        let ptrs_ofs = ptrs_offset(blocksize, ofs);
+        check_count::<BmbtKey>("Bmap btree node", numrecs, ptrs_ofs - ofs)?;
 
-        // The XFS Algorithms & Data Structures document section
-        // 16.2 says that the pointers start at offset 0x808 within the block.  But for V5 file
-        // systems it looks to me like they really start at offset 0x820.
-        ofs = match hdr.bb_magic {
-            XFS_BMAP_MAGIC => blocksize / 2 + 0x08,
-            XFS_BMAP_CRC_MAGIC => blocksize / 2 + 0x20,
-            _ => unreachable!(),
-        };
-        let mut ptrs = Vec::with_capacity(usize::from(hdr.bb_numrecs));
-        for _ in 0..hdr.bb_numrecs {
-            let (ptr, ptrlen) = decode(&raw[ofs..])?;
-            ofs += ptrlen;
-            ptrs.push(ptr);
-        }
+        let keys = decode_array(&raw[ofs..], numrecs)?;
+
+        let ptrs = decode_array(&raw[ptrs_ofs..], numrecs)?;
 
         let blocks = RefCell::new(BlockCache::new(hdr.bb_level));
         Ok(Self {
@@ -387,15 +419,15 @@ impl Decode for BtreeIntermediate {
 /// A Leaf Btree.
 #[derive(Debug)]
 struct BtreeLeaf {
-    // hdr: XfsBmbtLblock,
+    hdr: XfsBmbtLblock,
     bmx: Bmx,
 }
 
 impl BtreeLeaf {
     /// Return the extent, if any, that contains the given block within the file.
-    /// Return its starting position as an FSblock, and its length in file system block units.
+    /// Return how it is mapped, and its length in file system block units.
     /// If a hole's length extends to EoF, return None for length.
-    pub fn get_extent(&self, dblock: XfsFileoff) -> (Option<XfsFsblock>, Option<u64>) {
+    pub fn get_extent(&self, dblock: XfsFileoff) -> (Mapping, Option<u64>) {
         self.bmx.get_extent(dblock)
     }
 }
@@ -407,6 +439,208 @@ impl Decode for BtreeLeaf {
 
         let bmx = Bmx::from((0..hdr.bb_numrecs).map(|_| Decode::decode(decoder).unwrap()));
 
-        Ok(Self { bmx })
+        Ok(Self { hdr, bmx })
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use std::{
+        io::{BufReader, Cursor},
+        sync::Arc,
+    };
+
+    use proptest::prelude::*;
+    use rstest::rstest;
+
+    use super::*;
+    use crate::libxfuse::{
+        bmbt_rec::{
+            tests::{expected_lseek, extents, offsets, superblock},
+            BmbtRec,
+        },
+        validate::Validator,
+    };
+
+    /// Build a file system image containing a single V4 bmap btree leaf at fsblock 1, and a root
+    /// that points to it.
+    pub(in crate::libxfuse) fn single_leaf(
+        recs: &[BmbtRec],
+    ) -> (BtreeRoot, BufReader<Cursor<Vec<u8>>>) {
+        leaves(&[recs])
+    }
+
+    /// Build a file system image containing V4 bmap btree leaves at fsblocks 1, 2, and so on, each
+    /// holding one of `leaves`, and a root that points to them all.
+    fn leaves(leaves: &[&[BmbtRec]]) -> (BtreeRoot, BufReader<Cursor<Vec<u8>>>) {
+        let blocksize = superblock().sb_blocksize as usize;
+        let mut image = vec![0u8; (1 + leaves.len()) * blocksize];
+        let sib = |i: usize| {
+            if (1..=leaves.len()).contains(&i) {
+                i as u64
+            } else {
+                u64::MAX
+            }
+        };
+        for (i, recs) in leaves.iter().enumerate() {
+            let mut leaf = Vec::with_capacity(blocksize);
+            leaf.extend_from_slice(&XFS_BMAP_MAGIC.to_be_bytes());
+            leaf.extend_from_slice(&0u16.to_be_bytes());
+            leaf.extend_from_slice(&(recs.len() as u16).to_be_bytes());
+            leaf.extend_from_slice(&sib(i).to_be_bytes());
+            leaf.extend_from_slice(&sib(i + 2).to_be_bytes());
+            for rec in recs.iter() {
+                let raw = (u128::from(rec.br_flag) << 127)
+                    | (u128::from(rec.br_startoff) << 73)
+                    | (u128::from(rec.br_startblock) << 21)
+                    | u128::from(rec.br_blockcount);
+                leaf.extend_from_slice(&raw.to_be_bytes());
+            }
+            let ofs = (1 + i) * blocksize;
+            image[ofs..ofs + leaf.len()].copy_from_slice(&leaf);
+        }
+
+        let root = BtreeRoot::new(
+            BmdrBlock {
+                bb_level:   1,
+                bb_numrecs: leaves.len() as u16,
+            },
+            leaves
+                .iter()
+                .map(|recs| BmbtKey {
+                    br_startoff: recs[0].br_startoff,
+                })
+                .collect(),
+            (1..=leaves.len() as u64).collect(),
+        );
+        (root, BufReader::new(Cursor::new(image)))
+    }
+
+    proptest! {
+        /// A btree must map blocks the same way as the equivalent extent list.
+        #[test]
+        fn map_block(recs in extents().prop_filter("empty", |v| !v.is_empty())) {
+            let (root, mut br) = single_leaf(&recs);
+            let bmx = Bmx::new(&recs);
+            let end = recs.last().map(|r| r.br_startoff + r.br_blockcount).unwrap();
+            for dblock in 0..end + 2 {
+                prop_assert_eq!(root.map_block(&mut br, superblock(), dblock).unwrap(),
+                    bmx.get_extent(dblock));
+            }
+        }
+
+        /// A btree's SEEK_DATA and SEEK_HOLE must find the same offsets as a brute-force scan.
+        #[test]
+        fn lseek(recs in extents().prop_filter("empty", |v| !v.is_empty())) {
+            let (root, mut br) = single_leaf(&recs);
+            for whence in [libc::SEEK_DATA, libc::SEEK_HOLE] {
+                for offset in offsets(&recs) {
+                    prop_assert_eq!(root.lseek(&mut br, superblock(), offset, whence),
+                        expected_lseek(&recs, offset, whence),
+                        "offset={} whence={}", offset, whence);
+                }
+            }
+        }
+    }
+
+    /// A hole after one leaf's last extent extends to the start of the next leaf, not to EoF
+    #[test]
+    fn map_block_hole_between_leaves() {
+        let rec = |br_startoff, br_startblock| BmbtRec {
+            br_startoff,
+            br_startblock,
+            br_blockcount: 2,
+            br_flag: false,
+        };
+        let (root, mut br) = leaves(&[&[rec(0, 100)], &[rec(10, 200)]]);
+        let sb = superblock();
+        assert_eq!(
+            root.map_block(&mut br, sb, 1).unwrap(),
+            (Mapping::Data(101), Some(1))
+        );
+        assert_eq!(
+            root.map_block(&mut br, sb, 2).unwrap(),
+            (Mapping::Hole, Some(8))
+        );
+        assert_eq!(
+            root.map_block(&mut br, sb, 9).unwrap(),
+            (Mapping::Hole, Some(1))
+        );
+        assert_eq!(
+            root.map_block(&mut br, sb, 10).unwrap(),
+            (Mapping::Data(200), Some(2))
+        );
+        assert_eq!(
+            root.map_block(&mut br, sb, 12).unwrap(),
+            (Mapping::Hole, None)
+        );
+        assert_eq!(
+            root.lseek(&mut br, sb, 2 << sb.sb_blocklog, libc::SEEK_DATA),
+            Ok(10 << sb.sb_blocklog)
+        );
+    }
+
+    /// The pointers follow the keys' maximum extent, which the header and block size determine.
+    #[rstest]
+    #[case::v4_512(512, 24, 0x108)]
+    #[case::v4_4k(4096, 24, 0x808)]
+    #[case::v5_1k(1024, 72, 0x220)]
+    #[case::v5_4k(4096, 72, 0x820)]
+    #[case::v4_64k(65536, 24, 0x8008)]
+    #[case::v5_64k(65536, 72, 0x8020)]
+    fn ptrs_offset(#[case] blocksize: usize, #[case] hdrlen: usize, #[case] expected: usize) {
+        assert_eq!(super::ptrs_offset(blocksize, hdrlen), expected);
+    }
+
+    /// A damaged record count that would read keys from the pointers' space is an error.  With
+    /// 4k blocks, a V4 node has room for 254 keys.
+    #[rstest]
+    #[case::full(254, true)]
+    #[case::overflow(255, false)]
+    #[case::max(u16::MAX, false)]
+    fn intermediate_numrecs(#[case] numrecs: u16, #[case] ok: bool) {
+        let mut raw = vec![0u8; 4096];
+        raw[0..4].copy_from_slice(&XFS_BMAP_MAGIC.to_be_bytes());
+        raw[4..6].copy_from_slice(&1u16.to_be_bytes());
+        raw[6..8].copy_from_slice(&numrecs.to_be_bytes());
+        match BtreeIntermediate::from_block(&raw) {
+            Ok(node) => {
+                assert!(ok);
+                assert_eq!(node.keys.len(), usize::from(numrecs));
+            }
+            Err(DecodeError::OtherString(e)) => {
+                assert!(!ok);
+                assert_eq!(
+                    e,
+                    format!("Bmap btree node has a count of {numrecs}, but room for only 254")
+                );
+            }
+            Err(e) => panic!("{e:?}"),
+        }
+    }
+
+    /// In strict mode, a child's sibling pointers must agree with the parent.  The root's only
+    /// child has no siblings.
+    #[rstest]
+    #[case::lenient(false, 7, Ok(()))]
+    #[case::strict_good(true, u64::MAX, Ok(()))]
+    #[case::strict_bad(true, 7, Err(libc::EIO))]
+    fn siblings(#[case] strict: bool, #[case] rightsib: u64, #[case] expected: Result<(), i32>) {
+        let recs = [BmbtRec {
+            br_startoff:   0,
+            br_startblock: 100,
+            br_blockcount: 4,
+            br_flag:       false,
+        }];
+        let (root, mut br) = single_leaf(&recs);
+        let blocksize = superblock().sb_blocksize as usize;
+        br.get_mut().get_mut()[blocksize + 16..blocksize + 24]
+            .copy_from_slice(&rightsib.to_be_bytes());
+
+        let validator = Arc::new(Validator::new(validate::Config { strict }));
+        validator.enter();
+        let r = root.map_block(&mut br, superblock(), 0).map(drop);
+        assert_eq!(r, expected);
+        assert_eq!(validator.failures(), u64::from(r.is_err()));
     }
 }
@@ -0,0 +1,307 @@
+/*
+ * BSD 2-Clause License
+ *
+ * Copyright (c) 2021, Khaled Emara
+ * All rights reserved.
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice, this
+ *    list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice,
+ *    this list of conditions and the following disclaimer in the documentation
+ *    and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+ * AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+ * IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+ * FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+ * SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+ * CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+ * OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+ * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ */
+//! Accounting for the memory used by every cache, with `-o mem_limit` or `--cache-size`.
+//!
+//! Each cache charges the budget for what it holds, and releases the charge when it drops it.
+//! Whenever the total exceeds the limit, the caches must evict entries until it doesn't.
+use std::{
+    collections::BTreeMap,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// The kinds of cached data that are charged to a [`CacheBudget`]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CacheKind {
+    /// Hot inodes' Dinodes, including their extent lists
+    Inodes,
+    /// Directories' decoded blocks and entries
+    Directories,
+    /// Attribute forks' decoded leaves and entries
+    Attributes,
+    /// File data read ahead of each handle's sequential reads
+    Readahead,
+}
+
+impl CacheKind {
+    pub const ALL: [CacheKind; 4] = [
+        CacheKind::Inodes,
+        CacheKind::Directories,
+        CacheKind::Attributes,
+        CacheKind::Readahead,
+    ];
+
+    /// The name used for this cache in the stats JSON
+    pub fn name(self) -> &'static str {
+        match self {
+            CacheKind::Inodes => "inodes",
+            CacheKind::Directories => "directories",
+            CacheKind::Attributes => "attributes",
+            CacheKind::Readahead => "readahead",
+        }
+    }
+}
+
+/// The bytes charged by every cache, and an optional limit on their total.  It's shared by all of
+/// a Volume's caches.
+#[derive(Debug, Default)]
+pub struct CacheBudget {
+    limit: Option<u64>,
+    used:  [AtomicU64; CacheKind::ALL.len()],
+}
+
+impl CacheBudget {
+    pub fn new(limit: Option<u64>) -> Self {
+        CacheBudget {
+            limit,
+            ..Default::default()
+        }
+    }
+
+    /// Charge `kind` for `bytes` newly cached.
+    pub fn charge(&self, kind: CacheKind, bytes: u64) {
+        self.used[kind as usize].fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Release `bytes` that `kind` charged earlier.
+    pub fn release(&self, kind: CacheKind, bytes: u64) {
+        let r = self.used[kind as usize].fetch_update(Ordering::Relaxed, Ordering::Relaxed, |u| {
+            Some(u.saturating_sub(bytes))
+        });
+        debug_assert!(
+            r.unwrap() >= bytes,
+            "{:?} released more than it charged",
+            kind
+        );
+    }
+
+    /// The bytes currently charged to `kind`
+    pub fn used(&self, kind: CacheKind) -> u64 {
+        self.used[kind as usize].load(Ordering::Relaxed)
+    }
+
+    /// The bytes currently charged to all caches
+    pub fn total(&self) -> u64 {
+        CacheKind::ALL.iter().map(|kind| self.used(*kind)).sum()
+    }
+
+    /// Do the caches hold more than the limit?
+    pub fn exceeded(&self) -> bool {
+        self.excess() > 0
+    }
+
+    /// How many bytes the caches hold beyond the limit, if any
+    pub fn excess(&self) -> u64 {
+        self.limit
+            .map_or(0, |limit| self.total().saturating_sub(limit))
+    }
+
+    /// The limit and each cache's usage, as JSON
+    pub fn to_json(&self) -> String {
+        let limit = self
+            .limit
+            .map(|limit| limit.to_string())
+            .unwrap_or_else(|| "null".to_string());
+        let mut json = format!("{{\"limit\":{},\"total\":{}", limit, self.total());
+        for kind in CacheKind::ALL {
+            json.push_str(&format!(",\"{}\":{}", kind.name(), self.used(kind)));
+        }
+        json.push('}');
+        json
+    }
+}
+
+/// A map of cached blocks that remembers which were used least recently, so that a single large
+/// directory or attribute fork can give up some of its blocks rather than all of them.
+#[derive(Debug)]
+pub struct LruMap<K, V> {
+    /// Each entry, and when it was last used
+    entries: BTreeMap<K, (u64, V)>,
+    /// Each entry's key, by when it was last used
+    order:   BTreeMap<u64, K>,
+    /// Incremented on every use, to stamp the entry used
+    clock:   u64,
+}
+
+impl<K, V> Default for LruMap<K, V> {
+    fn default() -> Self {
+        LruMap {
+            entries: BTreeMap::new(),
+            order:   BTreeMap::new(),
+            clock:   0,
+        }
+    }
+}
+
+impl<K: Copy + Ord, V> LruMap<K, V> {
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    /// Look up an entry, marking it as the most recently used.
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        self.get_mut(key).map(|v| &*v)
+    }
+
+    /// Look up an entry, marking it as the most recently used.
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        let (stamp, value) = self.entries.get_mut(key)?;
+        self.order.remove(stamp);
+        self.clock += 1;
+        *stamp = self.clock;
+        self.order.insert(self.clock, *key);
+        Some(value)
+    }
+
+    /// Insert an entry as the most recently used, returning any that it replaced.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        self.clock += 1;
+        let old = self.entries.insert(key, (self.clock, value));
+        self.order.insert(self.clock, key);
+        old.map(|(stamp, value)| {
+            self.order.remove(&stamp);
+            value
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Remove every entry that doesn't satisfy `keep`.
+    pub fn retain<F: FnMut(&K, &V) -> bool>(&mut self, mut keep: F) {
+        let order = &mut self.order;
+        self.entries.retain(|key, (stamp, value)| {
+            keep(key, value) || {
+                order.remove(stamp);
+                false
+            }
+        });
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.entries.values().map(|(_, value)| value)
+    }
+
+    /// Remove and return the least recently used entry, if any.
+    pub fn pop_lru(&mut self) -> Option<V> {
+        let (_, key) = self.order.pop_first()?;
+        self.entries.remove(&key).map(|(_, value)| value)
+    }
+
+    /// Evict the least recently used entries until at least `bytes` have been freed, as measured
+    /// by `size`, or none remain.  Return how many bytes were freed.
+    pub fn evict<F: Fn(&V) -> usize>(&mut self, bytes: usize, size: F) -> usize {
+        let mut freed = 0;
+        while freed < bytes {
+            let Some((_, key)) = self.order.pop_first() else {
+                break;
+            };
+            let (_, value) = self.entries.remove(&key).unwrap();
+            freed += size(&value);
+        }
+        freed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accounting() {
+        let budget = CacheBudget::new(Some(100));
+        budget.charge(CacheKind::Inodes, 60);
+        budget.charge(CacheKind::Directories, 40);
+        assert!(!budget.exceeded());
+        budget.charge(CacheKind::Attributes, 1);
+        assert!(budget.exceeded());
+        budget.release(CacheKind::Directories, 40);
+        assert_eq!(budget.total(), 61);
+        assert!(!budget.exceeded());
+        assert_eq!(
+            budget.to_json(),
+            "{\"limit\":100,\"total\":61,\"inodes\":60,\"directories\":0,\"attributes\":1,\"\
+             readahead\":0}"
+        );
+    }
+
+    #[test]
+    fn unlimited() {
+        let budget = CacheBudget::new(None);
+        budget.charge(CacheKind::Inodes, u64::MAX / 2);
+        assert!(!budget.exceeded());
+        assert!(budget.to_json().starts_with("{\"limit\":null,"));
+    }
+
+    #[test]
+    fn excess() {
+        let budget = CacheBudget::new(Some(100));
+        budget.charge(CacheKind::Directories, 99);
+        assert_eq!(budget.excess(), 0);
+        budget.charge(CacheKind::Attributes, 5);
+        assert_eq!(budget.excess(), 4);
+    }
+
+    /// Eviction takes the least recently used entries first, and only as many as needed
+    #[test]
+    fn lru_evict() {
+        let mut lru = LruMap::default();
+        for key in 0..4u32 {
+            lru.insert(key, vec![0u8; 10]);
+        }
+        // Use 0 and 2, and replace 1, so 3 and then 0 are the least recently used
+        assert!(lru.get(&0).is_some());
+        assert!(lru.get_mut(&2).is_some());
+        assert_eq!(lru.insert(1, vec![0u8; 5]).map(|v| v.len()), Some(10));
+        assert_eq!(lru.evict(15, Vec::len), 20);
+        assert!(!lru.contains_key(&0));
+        assert!(lru.contains_key(&1));
+        assert!(lru.contains_key(&2));
+        assert!(!lru.contains_key(&3));
+        assert_eq!(lru.evict(0, Vec::len), 0);
+        assert_eq!(lru.values().count(), 2);
+        assert_eq!(lru.evict(100, Vec::len), 15);
+        assert_eq!(lru.values().count(), 0);
+    }
+
+    /// Entries removed by retain are no longer evicted
+    #[test]
+    fn lru_retain() {
+        let mut lru = LruMap::default();
+        for key in 0..4u32 {
+            lru.insert(key, 1);
+        }
+        lru.retain(|key, _| key % 2 == 1);
+        assert_eq!(lru.len(), 2);
+        assert_eq!(lru.evict(1, |v| *v), 1);
+        assert!(!lru.contains_key(&1));
+        assert!(lru.contains_key(&3));
+        assert_eq!(lru.pop_lru(), Some(1));
+        assert_eq!(lru.pop_lru(), None);
+    }
+}
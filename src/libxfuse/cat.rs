@@ -0,0 +1,114 @@
+/*
+ * BSD 2-Clause License
+ *
+ * Copyright (c) 2021, Khaled Emara
+ * All rights reserved.
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice, this
+ *    list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice,
+ *    this list of conditions and the following disclaimer in the documentation
+ *    and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+ * AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+ * IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+ * FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+ * SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+ * CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+ * OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+ * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ */
+//! Printing a single file's contents, or one of its extended attributes, used by `--cat`.
+use std::{
+    ffi::OsStr,
+    io::{self, prelude::*},
+    path::Path,
+};
+
+use super::{
+    attr::Attr,
+    block_reader::BlockReader,
+    dinode::Dinode,
+    list::resolve,
+    sb::Sb,
+    stat::FileType,
+    volume::{Options, Volume},
+};
+
+/// Write a regular file's contents to `out`.  Holes and unwritten extents are written as zeros.
+pub(super) fn copy<W: Write>(
+    reader: &mut BlockReader,
+    sb: &Sb,
+    dinode: &Dinode,
+    mut out: W,
+) -> io::Result<()> {
+    const CHUNK: u32 = 1 << 20;
+
+    reader.set_bufsize(sb.sb_blocksize as usize);
+    let file = dinode
+        .get_file(reader.by_ref())
+        .map_err(io::Error::from_raw_os_error)?;
+    let mut offset = 0;
+    while offset < file.size() {
+        let (v, skip) = file
+            .read(reader.by_ref(), sb, offset, CHUNK)
+            .map_err(io::Error::from_raw_os_error)?;
+        out.write_all(&v[skip..])?;
+        offset += i64::from(CHUNK);
+    }
+    out.flush()
+}
+
+/// Without mounting the file system, write the contents of the regular file at `path` to `out`,
+/// or if `xattr` is given, the value of that extended attribute of any file.
+pub fn cat<W: Write>(
+    device: &Path,
+    path: &Path,
+    xattr: Option<&OsStr>,
+    no_mmap: bool,
+    mut out: W,
+) -> io::Result<()> {
+    let opts = Options {
+        no_mmap,
+        ..Default::default()
+    };
+    let mut vol = Volume::open(device, opts)?;
+    let sb = vol.sb;
+    let (_, ino) = resolve(&mut vol.device, &sb, path)?;
+    vol.device.set_bufsize(sb.inode_size());
+    let mut dinode =
+        Dinode::from(vol.device.by_ref(), &sb, ino).map_err(io::Error::from_raw_os_error)?;
+
+    if let Some(name) = xattr {
+        vol.device.set_bufsize(sb.sb_blocksize as usize);
+        let value = match dinode.get_attrs(vol.device.by_ref(), &sb) {
+            Some(attrs) => attrs
+                .get(vol.device.by_ref(), &sb, name)
+                .map_err(io::Error::from_raw_os_error)?,
+            None => return Err(io::Error::from_raw_os_error(libc::ENOATTR)),
+        };
+        out.write_all(&value)?;
+        return out.flush();
+    }
+
+    let kind = dinode
+        .di_core
+        .stat(ino)
+        .map_err(io::Error::from_raw_os_error)?
+        .kind;
+    match kind {
+        FileType::RegularFile => copy(&mut vol.device, &sb, &dinode, out),
+        FileType::Directory => Err(io::Error::from_raw_os_error(libc::EISDIR)),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "not a regular file",
+        )),
+    }
+}
@@ -26,11 +26,11 @@
  * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
  */
 use std::{
-    cell::{Ref, RefCell},
-    collections::{btree_map::Entry, BTreeMap},
+    collections::BTreeMap,
     ffi::OsStr,
     io::{BufRead, Seek, SeekFrom},
     os::unix::ffi::OsStrExt,
+    sync::{Arc, Mutex},
 };
 
 use bincode::{
@@ -39,9 +39,14 @@ use bincode::{
     impl_borrow_decode,
     Decode,
 };
-use byteorder::{BigEndian, ReadBytesExt};
+use tracing::error;
 
-use super::{definitions::*, sb::Sb, utils, utils::Uuid, volume::SUPERBLOCK};
+use super::{
+    definitions::*,
+    sb::Sb,
+    utils,
+    utils::{FromBytes, Uuid},
+};
 
 pub fn hashname(name: &OsStr) -> XfsDahash {
     let name = name.as_bytes();
@@ -90,7 +95,7 @@ pub struct XfsDa3Blkinfo {
     // _crc: u32
     // _blkno: u64
     // _lsn: u64
-    // uuid: Uuid
+    pub uuid:  Uuid,
     // _owner: u64
 }
 
@@ -105,13 +110,29 @@ impl Decode for XfsDa3Blkinfo {
         let _lsn: u64 = Decode::decode(decoder)?;
         let uuid: Uuid = Decode::decode(decoder)?;
         let _owner: u64 = Decode::decode(decoder)?;
-        assert_eq!(uuid, SUPERBLOCK.get().unwrap().sb_uuid, "UUID mismatch!");
 
-        Ok(XfsDa3Blkinfo { forw, magic })
+        Ok(XfsDa3Blkinfo { forw, magic, uuid })
     }
 }
 impl_borrow_decode!(XfsDa3Blkinfo);
 
+/// Check that a directory or attribute btree block belongs to this file system.  Only V5 blocks,
+/// whose header is an xfs_da3_blkinfo, record its UUID.
+pub fn check_uuid(sb: &Sb, raw: &[u8]) {
+    let Some(magic) = raw.get(8..10) else {
+        return;
+    };
+    let magic = u16::from_be_bytes(magic.try_into().unwrap());
+    if matches!(
+        magic,
+        XFS_DA3_NODE_MAGIC | XFS_ATTR3_LEAF_MAGIC | XFS_DIR3_LEAF1_MAGIC | XFS_DIR3_LEAFN_MAGIC
+    ) {
+        if let Ok((info, _)) = utils::decode::<XfsDa3Blkinfo>(raw) {
+            assert_eq!(info.uuid, sb.sb_uuid, "UUID mismatch!");
+        }
+    }
+}
+
 #[derive(Debug, Decode)]
 struct XfsDaNodeHdr {
     _info:     XfsDaBlkinfo,
@@ -141,18 +162,20 @@ impl Decode for XfsDa3NodeHdr {
 }
 impl_borrow_decode!(XfsDa3NodeHdr);
 
-#[derive(Debug, Decode)]
+#[derive(Debug, Decode, PartialEq)]
 pub struct XfsDa3NodeEntry {
     pub hashval: XfsDahash,
     pub before:  XfsDablk,
 }
 
-impl XfsDa3NodeEntry {
-    pub fn from<R: BufRead>(buf_reader: &mut R) -> XfsDa3NodeEntry {
-        let hashval = buf_reader.read_u32::<BigEndian>().unwrap();
-        let before = buf_reader.read_u32::<BigEndian>().unwrap();
+impl FromBytes for XfsDa3NodeEntry {
+    const SIZE: usize = 8;
 
-        XfsDa3NodeEntry { hashval, before }
+    fn from_bytes(raw: &[u8]) -> Self {
+        XfsDa3NodeEntry {
+            hashval: u32::from_be_bytes(raw[0..4].try_into().unwrap()),
+            before:  u32::from_be_bytes(raw[4..8].try_into().unwrap()),
+        }
     }
 }
 
@@ -160,42 +183,63 @@ impl XfsDa3NodeEntry {
 /// system verison.
 #[derive(Debug)]
 pub struct XfsDa3Intnode {
-    pub magic: u16,
     level:     u16,
     //hdr: XfsDa3NodeHdr,
     pub btree: Vec<XfsDa3NodeEntry>,
-    children:  RefCell<BTreeMap<XfsDablk, Self>>,
+    /// The size of the node's block, and its children's.  That's the directory block size for
+    /// directories, or the file system block size for attributes.
+    blksize:   usize,
+    /// A cache of child nodes.  It's a Mutex rather than a RefCell only so that directories can
+    /// be sent between threads; it's never contended.
+    children:  Mutex<BTreeMap<XfsDablk, Arc<Self>>>,
 }
 
 impl XfsDa3Intnode {
-    pub fn from<R: BufRead + Reader + Seek>(buf_reader: &mut R) -> XfsDa3Intnode {
-        let magic: u16 = utils::decode(&buf_reader.peek_read(10).unwrap()[8..])
-            .unwrap()
-            .0;
-        let (count, level) = match magic {
+    /// Read a node whose block is `blksize` bytes long, from the reader's position.
+    pub fn from<R: BufRead + Reader + Seek>(
+        buf_reader: &mut R,
+        blksize: usize,
+    ) -> Result<Self, i32> {
+        let mut raw = vec![0u8; blksize];
+        buf_reader
+            .read_exact(&mut raw)
+            .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))?;
+        Self::from_block(&raw).map_err(|e| {
+            error!("Cannot decode btree node: {}", e);
+            libc::EIO
+        })
+    }
+
+    /// Decode a node from a whole block.
+    pub fn from_block(raw: &[u8]) -> Result<Self, DecodeError> {
+        let magic: u16 = utils::decode(raw.get(8..).unwrap_or_default())?.0;
+        let (count, level, hdrlen) = match magic {
             XFS_DA_NODE_MAGIC => {
-                let hdr: XfsDaNodeHdr = utils::decode_from(buf_reader.by_ref()).unwrap();
-                (hdr.count, hdr.level)
+                let (hdr, hdrlen) = utils::decode::<XfsDaNodeHdr>(raw)?;
+                (hdr.count, hdr.level, hdrlen)
             }
             XFS_DA3_NODE_MAGIC => {
-                let hdr: XfsDa3NodeHdr = utils::decode_from(buf_reader.by_ref()).unwrap();
-                (hdr.count, hdr.level)
+                let (hdr, hdrlen) = utils::decode::<XfsDa3NodeHdr>(raw)?;
+                (hdr.count, hdr.level, hdrlen)
+            }
+            _ => {
+                return Err(DecodeError::OtherString(format!(
+                    "Unexpected magic {:#x} in btree node",
+                    magic
+                )))
             }
-            _ => panic!("Bad magic in XfsDa3Intnode! {:#x}", magic),
         };
-
-        let mut btree = Vec::<XfsDa3NodeEntry>::new();
-        for _i in 0..count {
-            btree.push(XfsDa3NodeEntry::from(buf_reader.by_ref()))
-        }
+        let count = usize::from(count);
+        utils::check_count::<XfsDa3NodeEntry>("Btree node", count, raw.len() - hdrlen)?;
+        let btree = utils::decode_array(&raw[hdrlen..], count)?;
         let children = Default::default();
 
-        XfsDa3Intnode {
-            magic,
+        Ok(XfsDa3Intnode {
             level,
             btree,
+            blksize: raw.len(),
             children,
-        }
+        })
     }
 
     pub fn lookup<R: BufRead + Reader + Seek, F: Fn(XfsDablk, &mut R) -> XfsFsblock>(
@@ -237,60 +281,28 @@ impl XfsDa3Intnode {
         }
     }
 
-    fn read_child<'a, R, F>(
-        &'a self,
+    /// Read a child node, through the cache.  The cache is never borrowed beyond this call.
+    fn read_child<R, F>(
+        &self,
         buf_reader: &mut R,
         super_block: &Sb,
         dblock: XfsDablk,
         map_dblock: &F,
-    ) -> Result<impl std::ops::Deref<Target = Self> + 'a, i32>
+    ) -> Result<Arc<Self>, i32>
     where
         R: BufRead + Reader + Seek,
         F: Fn(XfsDablk, &mut R) -> XfsFsblock,
     {
-        let mut cache_guard = self.children.borrow_mut();
-        let entry = cache_guard.entry(dblock);
-        if matches!(entry, Entry::Vacant(_)) {
-            let fsblock = map_dblock(dblock, buf_reader.by_ref());
-            let offset = super_block.fsb_to_offset(fsblock);
-            buf_reader.seek(SeekFrom::Start(offset)).unwrap();
-            buf_reader.fill_buf().unwrap();
-            let node = XfsDa3Intnode::from(buf_reader.by_ref());
-            entry.or_insert(node);
+        if let Some(node) = self.children.lock().unwrap().get(&dblock) {
+            return Ok(node.clone());
         }
-        // Annoyingly, there's no function to downgrade a RefMut into a Ref.
-        drop(cache_guard);
-        let cache_guard = self.children.borrow();
-        Ok(Ref::map(cache_guard, |v| &v[&dblock]))
-    }
-}
-
-impl Decode for XfsDa3Intnode {
-    fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError> {
-        let magic: u16 = utils::decode(&decoder.reader().peek_read(10).unwrap()[8..])?.0;
-        let (count, level) = match magic {
-            XFS_DA_NODE_MAGIC => {
-                let hdr: XfsDaNodeHdr = Decode::decode(decoder)?;
-                (hdr.count, hdr.level)
-            }
-            XFS_DA3_NODE_MAGIC => {
-                let hdr: XfsDa3NodeHdr = Decode::decode(decoder)?;
-                (hdr.count, hdr.level)
-            }
-            _ => panic!("Bad magic in XfsDa3Intnode! {:#x}", magic),
-        };
-        let mut btree = Vec::<XfsDa3NodeEntry>::new();
-        for _i in 0..count {
-            btree.push(Decode::decode(decoder)?);
-        }
-        let children = Default::default();
-
-        Ok(XfsDa3Intnode {
-            magic,
-            level,
-            btree,
-            children,
-        })
+        let fsblock = map_dblock(dblock, buf_reader.by_ref());
+        let offset = super_block.fsb_to_offset(fsblock);
+        buf_reader.seek(SeekFrom::Start(offset)).unwrap();
+        check_uuid(super_block, buf_reader.fill_buf().unwrap());
+        let node = Arc::new(XfsDa3Intnode::from(buf_reader.by_ref(), self.blksize)?);
+        self.children.lock().unwrap().insert(dblock, node.clone());
+        Ok(node)
     }
 }
 
@@ -324,3 +336,38 @@ fn hashname_collisions() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    /// A damaged entry count that overflows a node's block is an error.  A V4 node in a 4k block
+    /// has room for 510 entries.
+    #[rstest]
+    #[case::full(510, true)]
+    #[case::overflow(511, false)]
+    #[case::max(u16::MAX, false)]
+    fn intnode_count(#[case] count: u16, #[case] ok: bool) {
+        let mut raw = vec![0u8; 4096];
+        raw[8..10].copy_from_slice(&XFS_DA_NODE_MAGIC.to_be_bytes());
+        raw[12..14].copy_from_slice(&count.to_be_bytes());
+        raw[14..16].copy_from_slice(&1u16.to_be_bytes());
+        match XfsDa3Intnode::from_block(&raw) {
+            Ok(node) => {
+                assert!(ok);
+                assert_eq!(node.btree.len(), usize::from(count));
+                assert_eq!(node.blksize, raw.len());
+            }
+            Err(DecodeError::OtherString(e)) => {
+                assert!(!ok);
+                assert_eq!(
+                    e,
+                    format!("Btree node has a count of {count}, but room for only 510")
+                );
+            }
+            Err(e) => panic!("{e:?}"),
+        }
+    }
+}
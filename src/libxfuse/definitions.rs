@@ -35,7 +35,8 @@ pub const XFS_DQUOT_MAGIC: u16 = 0x4451; // Quota Inodes
 pub const XFS_SYMLINK_MAGIC: u32 = 0x58534c4d; // Symbolic Links
 pub const XFS_ABTB_CRC_MAGIC: u32 = 0x41423342; // Free Space by Block B+tree
 pub const XFS_ABTC_CRC_MAGIC: u32 = 0x41423343; // Free Space by Size B+tree
-pub const XFS_IBT_CRC_MAGIC: u32 = 0x49414233; // Inode B+tree
+pub const XFS_IBT_MAGIC: u32 = 0x49414254; // Inode B+tree
+pub const XFS_IBT_CRC_MAGIC: u32 = 0x49414233; // Inode B+tree, V5
 pub const XFS_FIBT_CRC_MAGIC: u32 = 0x46494233; // Free Inode B+tree
 pub const XFS_BMAP_MAGIC: u32 = 0x424d4150; // B+Tree Extent List, V5
 pub const XFS_BMAP_CRC_MAGIC: u32 = 0x424d4133; // B+Tree Extent List, V5
@@ -50,10 +51,12 @@ pub const XFS_DIR2_LEAF1_MAGIC: u16 = 0xd2f1; // Leaf Directory
 pub const XFS_DIR3_LEAF1_MAGIC: u16 = 0x3df1; // Leaf Directory, V5
 pub const XFS_DIR2_LEAFN_MAGIC: u16 = 0xd2ff; // Node Directory
 pub const XFS_DIR3_LEAFN_MAGIC: u16 = 0x3dff; // Node Directory, V5
-pub const XFS_DIR3_FREE_MAGIC: u32 = 0x58444633; // Node Directory Free Space
+pub const XFS_DIR2_FREE_MAGIC: u32 = 0x58443246; // Node Directory Free Space
+pub const XFS_DIR3_FREE_MAGIC: u32 = 0x58444633; // Node Directory Free Space, V5
 pub const XFS_ATTR_LEAF_MAGIC: u16 = 0xfbee; // Leaf Attribute
 pub const XFS_ATTR3_LEAF_MAGIC: u16 = 0x3bee; // Leaf Attribute, V5
 pub const XFS_ATTR3_RMT_MAGIC: u32 = 0x5841524d; // Remote Attribute Value
+pub const XFS_XATTR_SIZE_MAX: u32 = 1 << 16; // The longest possible attribute value
 pub const XFS_RMAP_CRC_MAGIC: u32 = 0x524d4233; // Reverse Mapping B+tree
 pub const XFS_RTRMAP_CRC_MAGIC: u32 = 0x4d415052; // Real-Time Reverse Mapping B+tree
 pub const XFS_REFC_CRC_MAGIC: u32 = 0x52334643; // Reference Count B+tree
@@ -64,9 +67,10 @@ pub type XfsOff = i64; // file offset
 pub type XfsDaddr = i64; // disk address (sectors)
 pub type XfsAgnumber = u32; // AG number
 pub type XfsAgblock = u32; // AG relative block number
+pub type XfsAgino = u32; // AG relative inode number
 pub type XfsExtlen = u32; // extent length in blocks
-pub type XfsExtnum = i32; // number of extends in a data fork
-pub type XfsAextnum = i16; // number of extents in an attribute fork
+pub type XfsExtnum = u64; // number of extents in a data fork
+pub type XfsAextnum = u32; // number of extents in an attribute fork
 pub type XfsDablk = u32; // block number for directories and extended attributes
 pub type XfsDahash = u32; // hash of a directory file name or extended attribute name
 pub type XfsFsblock = u64; // filesystem block number combining AG number
@@ -75,3 +79,8 @@ pub type XfsRtblock = u64; // extent number in the real-time sub-volume
 pub type XfsFileoff = u64; // block offset into a file
 pub type XfsFilblks = u64; // block count for a file
 pub type XfsFsize = i64; // byte size of a file
+pub type XfsDev = u32; // device number, with a 14-bit major and an 18-bit minor
+
+pub const NULLFSBLOCK: XfsFsblock = XfsFsblock::MAX; // A null block pointer, like a btree sibling
+pub const NULLAGBLOCK: XfsAgblock = XfsAgblock::MAX; // A null AG block pointer
+pub const NULLAGINO: XfsAgino = XfsAgino::MAX; // The end of an unlinked inode list
@@ -27,24 +27,39 @@
  */
 use std::{
     ffi::CString,
-    io::{BufRead, Seek, SeekFrom},
+    io::{BufRead, ErrorKind, Seek, SeekFrom},
+    mem,
 };
 
 use bincode::{
     de::{read::Reader, Decoder},
+    error::DecodeError,
     Decode,
 };
-use libc::{mode_t, S_IFBLK, S_IFCHR, S_IFDIR, S_IFIFO, S_IFLNK, S_IFMT, S_IFREG, S_IFSOCK};
+use libc::{
+    c_int,
+    mode_t,
+    EIO,
+    S_IFBLK,
+    S_IFCHR,
+    S_IFDIR,
+    S_IFIFO,
+    S_IFLNK,
+    S_IFMT,
+    S_IFREG,
+    S_IFSOCK,
+};
+use tracing::error;
 
 use super::{
-    attr::Attributes,
+    attr::{Attr, Attributes},
     attr_bptree::AttrBtree,
     attr_shortform::AttrShortform,
     bmbt_rec::{BmbtRec, Bmx},
     btree::{BmbtKey, BmdrBlock, BtreeRoot, XfsBmbtPtr},
     definitions::*,
     dinode_core::{DinodeCore, XfsDinodeFmt},
-    dir3::Directory,
+    dir3::{Dir3, Directory},
     dir3_block::Dir2Block,
     dir3_lf::Dir2Lf,
     dir3_sf::Dir2Sf,
@@ -52,15 +67,16 @@ use super::{
     file_btree::FileBtree,
     file_extent_list::FileExtentList,
     sb::Sb,
+    stat::FileAttr,
     symlink_extent::SymlinkExtents,
 };
 
 #[derive(Debug)]
 pub enum DiU {
-    Blk,
+    Blk(XfsDev),
     Bmbt((BmdrBlock, Vec<BmbtKey>, Vec<XfsBmbtPtr>)),
     Bmx(Vec<BmbtRec>),
-    Chr,
+    Chr(XfsDev),
     Dir2Sf(Dir2Sf),
     Fifo,
     Socket,
@@ -85,249 +101,267 @@ pub struct Dinode {
     attributes:  Option<Attributes>,
 }
 
+/// Does this extent list describe a Block directory?  Like the kernel's xfs_dir2_isblock, decide
+/// that by where the data fork ends rather than by how many extents it has.  A Block directory's
+/// single directory block may span several extents, and a Leaf directory's first data block need
+/// not be at offset 0.
+fn is_block_dir(bmbtv: &[BmbtRec], sb: &Sb) -> bool {
+    bmbtv
+        .last()
+        .map(|rec| rec.br_startoff + rec.br_blockcount == 1 << sb.sb_dirblklog)
+        .unwrap_or(false)
+}
+
+/// Decode a fork's list of `count` extents
+fn decode_bmx<D: Decoder>(decoder: &mut D, count: u64) -> Result<Vec<BmbtRec>, DecodeError> {
+    let mut bmx = Vec::new();
+    for _ in 0..count {
+        bmx.push(BmbtRec::decode(decoder)?);
+    }
+    Ok(bmx)
+}
+
+/// Decode a fork's btree root: its header, keys, and pointers.  `gap` gives the padding between
+/// the keys and the pointers, for a given number of records.
+fn decode_bmbt<D, G>(
+    decoder: &mut D,
+    gap: G,
+) -> Result<(BmdrBlock, Vec<BmbtKey>, Vec<XfsBmbtPtr>), DecodeError>
+where
+    D: Decoder,
+    G: FnOnce(u16) -> Option<usize>,
+{
+    let bmbt = BmdrBlock::decode(decoder)?;
+    let mut keys = Vec::<BmbtKey>::new();
+    for _ in 0..bmbt.bb_numrecs {
+        keys.push(BmbtKey::decode(decoder)?);
+    }
+    let gap = gap(bmbt.bb_numrecs).ok_or_else(|| {
+        DecodeError::OtherString(format!(
+            "btree root has {} records, more than its fork can hold",
+            bmbt.bb_numrecs
+        ))
+    })?;
+    decoder.reader().consume(gap);
+    let mut pointers = Vec::<XfsBmbtPtr>::new();
+    for _ in 0..bmbt.bb_numrecs {
+        pointers.push(XfsBmbtPtr::decode(decoder)?);
+    }
+    Ok((bmbt, keys, pointers))
+}
+
 impl Dinode {
     pub fn from<R: bincode::de::read::Reader + BufRead + Seek>(
         buf_reader: &mut R,
         superblock: &Sb,
         inode_number: XfsIno,
-    ) -> Dinode {
-        let ag_no: u64 = inode_number >> (superblock.sb_agblklog + superblock.sb_inopblog);
-        if ag_no >= superblock.sb_agcount.into() {
-            panic!("Wrong AG number!");
-        }
+    ) -> Result<Dinode, c_int> {
+        let off = superblock.ino_to_offset(inode_number).map_err(|e| {
+            error!(
+                "Inode number {} lies outside of the file system",
+                inode_number
+            );
+            e
+        })?;
 
-        let ag_blk: u64 =
-            (inode_number >> superblock.sb_inopblog) & ((1 << superblock.sb_agblklog) - 1);
-        let blk_ino = inode_number & ((1 << superblock.sb_inopblog) - 1);
+        let mut raw = vec![0u8; superblock.inode_size()];
+        buf_reader
+            .seek(SeekFrom::Start(off))
+            .and_then(|_| buf_reader.read_exact(&mut raw))
+            .map_err(|e| {
+                if e.kind() == ErrorKind::UnexpectedEof {
+                    // Most likely a truncated image
+                    error!(
+                        "Inode {} at offset {} lies beyond the end of the device",
+                        inode_number, off
+                    );
+                } else {
+                    error!(
+                        "Cannot read inode {} at offset {}: {}",
+                        inode_number, off, e
+                    );
+                }
+                e.raw_os_error().unwrap_or(EIO)
+            })?;
+        let (di_core, di_u, di_a) = Self::decode(&raw, superblock, inode_number).map_err(|e| {
+            error!("Cannot decode inode {}: {}", inode_number, e);
+            EIO
+        })?;
 
-        let off: u64 = ((ag_no * u64::from(superblock.sb_agblocks)) << superblock.sb_blocklog)
-            + (ag_blk << superblock.sb_blocklog)
-            + (blk_ino << superblock.sb_inodelog);
+        Ok(Dinode {
+            di_core,
+            di_u,
+            di_a,
+            directory: None,
+            attributes: None,
+        })
+    }
 
-        buf_reader.seek(SeekFrom::Start(off)).unwrap();
-        let mut raw = vec![0u8; superblock.inode_size()];
-        buf_reader.read_exact(&mut raw).unwrap();
+    /// Decode an inode's core and both of its forks.  A format that doesn't suit the inode's
+    /// type, and an inode type that XFS doesn't have, are errors, like any other corruption.
+    fn decode(
+        raw: &[u8],
+        superblock: &Sb,
+        inode_number: XfsIno,
+    ) -> Result<(DinodeCore, DiU, Option<DiA>), DecodeError> {
         let config = bincode::config::standard()
             .with_big_endian()
             .with_fixed_int_encoding();
-        let reader = bincode::de::read::SliceReader::new(&raw[..]);
+        let reader = bincode::de::read::SliceReader::new(raw);
         let mut decoder = bincode::de::DecoderImpl::new(reader, config);
 
-        let di_core = DinodeCore::decode(&mut decoder).unwrap();
+        let di_core = DinodeCore::decode(&mut decoder)?;
+        let unexpected = |what: &str, format: XfsDinodeFmt| {
+            DecodeError::OtherString(format!("{} in unexpected format {:?}", what, format))
+        };
 
-        let di_u: Option<DiU>;
-        match (di_core.di_mode as mode_t) & S_IFMT {
+        let di_u = match (di_core.di_mode as mode_t) & S_IFMT {
             S_IFREG => match di_core.di_format {
-                XfsDinodeFmt::Extents => {
-                    let mut bmx = Vec::<BmbtRec>::new();
-                    for _i in 0..di_core.di_nextents {
-                        bmx.push(BmbtRec::decode(&mut decoder).unwrap())
-                    }
-                    di_u = Some(DiU::Bmx(bmx));
-                }
-                XfsDinodeFmt::Btree => {
-                    let bmbt = BmdrBlock::decode(&mut decoder).unwrap();
-
-                    let mut keys = Vec::<BmbtKey>::new();
-                    for _i in 0..bmbt.bb_numrecs {
-                        keys.push(BmbtKey::decode(&mut decoder).unwrap())
-                    }
-
-                    let gap = di_core.dfork_btree_ptr_gap(superblock.inode_size(), bmbt.bb_numrecs);
-                    decoder.reader().consume(gap as usize);
-
-                    let mut pointers = Vec::<XfsBmbtPtr>::new();
-                    for _i in 0..bmbt.bb_numrecs {
-                        let pointer = u64::decode(&mut decoder).unwrap();
-                        pointers.push(pointer)
-                    }
-
-                    di_u = Some(DiU::Bmbt((bmbt, keys, pointers)));
-                }
-                _ => {
-                    panic!("Directory format not yet supported.");
+                XfsDinodeFmt::Extents => DiU::Bmx(decode_bmx(&mut decoder, di_core.di_nextents)?),
+                XfsDinodeFmt::Btree => DiU::Bmbt(decode_bmbt(&mut decoder, |numrecs| {
+                    di_core.dfork_btree_ptr_gap(superblock.inode_size(), numrecs)
+                })?),
+                XfsDinodeFmt::Local => {
+                    // XFS never stores a regular file's data in the inode, but a file in local
+                    // format has no extents either, so it's all hole.
+                    DiU::Bmx(Vec::new())
                 }
+                format => return Err(unexpected("regular file", format)),
             },
             S_IFDIR => match di_core.di_format {
                 XfsDinodeFmt::Local => {
-                    let mut dir_sf = Dir2Sf::decode(&mut decoder).unwrap();
-                    dir_sf.set_ino(inode_number);
-                    di_u = Some(DiU::Dir2Sf(dir_sf));
-                }
-                XfsDinodeFmt::Extents => {
-                    let mut bmx = Vec::<BmbtRec>::new();
-                    for _i in 0..di_core.di_nextents {
-                        bmx.push(BmbtRec::decode(&mut decoder).unwrap())
-                    }
-                    di_u = Some(DiU::Bmx(bmx));
-                }
-                XfsDinodeFmt::Btree => {
-                    let bmbt = BmdrBlock::decode(&mut decoder).unwrap();
-
-                    let mut keys = Vec::<BmbtKey>::new();
-                    for _i in 0..bmbt.bb_numrecs {
-                        keys.push(BmbtKey::decode(&mut decoder).unwrap());
-                    }
-
-                    let gap = di_core.dfork_btree_ptr_gap(superblock.inode_size(), bmbt.bb_numrecs);
-                    decoder.reader().consume(gap as usize);
-
-                    let mut pointers = Vec::<XfsBmbtPtr>::new();
-                    for _i in 0..bmbt.bb_numrecs {
-                        let pointer = u64::decode(&mut decoder).unwrap();
-                        pointers.push(pointer)
-                    }
-
-                    di_u = Some(DiU::Bmbt((bmbt, keys, pointers)));
-                }
-                _ => {
-                    panic!("Directory format not yet supported.");
+                    // Confine it to the data fork, so a corrupt name length can't reach into the
+                    // attribute fork.
+                    let start = di_core.literal_area_offset();
+                    let end = start + di_core.dfork_size(superblock.inode_size());
+                    let fork = raw.get(start..end).ok_or_else(|| {
+                        DecodeError::OtherString(format!(
+                            "data fork of {} bytes is beyond the inode",
+                            end - start
+                        ))
+                    })?;
+                    let reader = bincode::de::read::SliceReader::new(fork);
+                    let mut decoder = bincode::de::DecoderImpl::new(reader, config);
+                    DiU::Dir2Sf(Dir2Sf::decode(&mut decoder, superblock, inode_number)?)
                 }
+                XfsDinodeFmt::Extents => DiU::Bmx(decode_bmx(&mut decoder, di_core.di_nextents)?),
+                XfsDinodeFmt::Btree => DiU::Bmbt(decode_bmbt(&mut decoder, |numrecs| {
+                    di_core.dfork_btree_ptr_gap(superblock.inode_size(), numrecs)
+                })?),
+                format => return Err(unexpected("directory", format)),
             },
             S_IFLNK => match di_core.di_format {
                 XfsDinodeFmt::Local => {
                     let mut data = vec![0u8; di_core.di_size as usize];
-                    decoder.reader().read(&mut data[..]).unwrap();
-                    di_u = Some(DiU::Symlink(data))
-                }
-                XfsDinodeFmt::Extents => {
-                    let mut bmx = Vec::<BmbtRec>::new();
-                    for _i in 0..di_core.di_nextents {
-                        bmx.push(BmbtRec::decode(&mut decoder).unwrap());
-                    }
-                    di_u = Some(DiU::Bmx(bmx));
-                }
-                _ => {
-                    panic!("Unexpected format for symlink");
+                    decoder.reader().read(&mut data[..])?;
+                    DiU::Symlink(data)
                 }
+                XfsDinodeFmt::Extents => DiU::Bmx(decode_bmx(&mut decoder, di_core.di_nextents)?),
+                format => return Err(unexpected("symlink", format)),
             },
-            S_IFBLK => di_u = Some(DiU::Blk),
-            S_IFCHR => di_u = Some(DiU::Chr),
-            S_IFIFO => di_u = Some(DiU::Fifo),
-            S_IFSOCK => di_u = Some(DiU::Socket),
-            x => panic!("Inode type ({:#o}) not yet supported.", x),
-        }
+            S_IFBLK => DiU::Blk(XfsDev::decode(&mut decoder)?),
+            S_IFCHR => DiU::Chr(XfsDev::decode(&mut decoder)?),
+            S_IFIFO => DiU::Fifo,
+            S_IFSOCK => DiU::Socket,
+            x => {
+                return Err(DecodeError::OtherString(format!(
+                    "unknown inode type {:#o}",
+                    x
+                )))
+            }
+        };
 
-        let di_a: Option<DiA>;
-        if di_core.di_forkoff != 0 {
+        let di_a = if di_core.di_forkoff != 0 {
             let attr_fork_ofs = di_core.literal_area_offset() + di_core.di_forkoff as usize * 8;
-            let config = bincode::config::standard()
-                .with_big_endian()
-                .with_fixed_int_encoding();
-            let reader = bincode::de::read::SliceReader::new(&raw[attr_fork_ofs..]);
+            let fork = raw.get(attr_fork_ofs..).ok_or_else(|| {
+                DecodeError::OtherString(format!(
+                    "attribute fork offset {} is beyond the inode",
+                    attr_fork_ofs
+                ))
+            })?;
+            let reader = bincode::de::read::SliceReader::new(fork);
             let mut decoder = bincode::de::DecoderImpl::new(reader, config);
 
-            match di_core.di_aformat {
-                XfsDinodeFmt::Local => {
-                    let attr_shortform = AttrShortform::decode(&mut decoder).unwrap();
-                    di_a = Some(DiA::Attrsf(attr_shortform));
-                }
+            Some(match di_core.di_aformat {
+                XfsDinodeFmt::Local => DiA::Attrsf(AttrShortform::decode(&mut decoder)?),
                 XfsDinodeFmt::Extents => {
-                    let mut bmx = Vec::<BmbtRec>::new();
-                    for _i in 0..di_core.di_anextents {
-                        bmx.push(BmbtRec::decode(&mut decoder).unwrap());
-                    }
-                    di_a = Some(DiA::Abmx(bmx));
+                    DiA::Abmx(decode_bmx(&mut decoder, di_core.di_anextents.into())?)
                 }
-                XfsDinodeFmt::Btree => {
-                    let bmbt = BmdrBlock::decode(&mut decoder).unwrap();
-
-                    let mut keys = Vec::<BmbtKey>::new();
-                    for _i in 0..bmbt.bb_numrecs {
-                        keys.push(BmbtKey::decode(&mut decoder).unwrap());
-                    }
-
-                    let gap = di_core.afork_btree_ptr_gap(superblock.inode_size(), bmbt.bb_numrecs);
-                    decoder.reader().consume(gap as usize);
-                    let mut pointers = Vec::<XfsBmbtPtr>::new();
-                    for _i in 0..bmbt.bb_numrecs {
-                        pointers.push(XfsBmbtPtr::decode(&mut decoder).unwrap());
-                    }
-
-                    di_a = Some(DiA::Abmbt((bmbt, keys, pointers)));
-                }
-                _ => {
-                    panic!("Attributes format not yet supported.");
-                }
-            }
+                XfsDinodeFmt::Btree => DiA::Abmbt(decode_bmbt(&mut decoder, |numrecs| {
+                    di_core.afork_btree_ptr_gap(superblock.inode_size(), numrecs)
+                })?),
+                format => return Err(unexpected("attribute fork", format)),
+            })
         } else {
-            di_a = None;
-        }
+            None
+        };
 
-        Dinode {
-            di_core,
-            di_u: di_u.unwrap(),
-            di_a,
-            directory: None,
-            attributes: None,
-        }
+        Ok((di_core, di_u, di_a))
     }
 
     pub fn get_dir<R: bincode::de::read::Reader + BufRead + Seek>(
         &mut self,
         buf_reader: &mut R,
         sb: &Sb,
-    ) -> &Directory {
+    ) -> Result<&Directory, c_int> {
         if self.directory.is_none() {
             let directory = match &self.di_u {
                 DiU::Dir2Sf(dir) => Directory::Sf(dir.clone()),
                 DiU::Bmx(bmbtv) => {
-                    if bmbtv.len() == 1 {
-                        Directory::Block(Dir2Block::new(
-                            buf_reader.by_ref(),
-                            sb,
-                            bmbtv[0].br_startblock,
-                        ))
+                    if is_block_dir(bmbtv, sb) {
+                        Directory::Block(Dir2Block::new(buf_reader.by_ref(), sb, &Bmx::new(bmbtv))?)
                     } else {
                         let bmx = Bmx::new(bmbtv);
-                        Directory::Lf(Dir2Lf::from_bmx(bmx))
+                        Directory::Lf(Dir2Lf::from_bmx(bmx, self.di_core.di_size as u64))
                     }
                 }
                 DiU::Bmbt((bmbt, keys, pointers)) => Directory::Lf(Dir2Lf::from_btree(
                     bmbt.clone(),
                     keys.clone(),
                     pointers.clone(),
+                    self.di_core.di_size as u64,
                 )),
-                _ => {
-                    panic!("Unsupported dir format!");
-                }
+                // Dinode::from has already rejected any directory with another fork
+                _ => return Err(libc::ENOTDIR),
             };
             self.directory = Some(directory);
         }
-        self.directory.as_ref().unwrap()
+        Ok(self.directory.as_ref().unwrap())
     }
 
     pub fn get_file<R: bincode::de::read::Reader + BufRead + Seek>(
         &self,
         _buf_reader: &mut R,
-    ) -> Box<dyn File<R>> {
+    ) -> Result<Box<dyn File<R>>, c_int> {
         match &self.di_u {
-            DiU::Bmx(bmx) => Box::new(FileExtentList {
-                bmx:  Bmx::new(bmx),
-                size: self.di_core.di_size,
-            }),
-            DiU::Bmbt((bmdr, keys, pointers)) => Box::new(FileBtree {
-                btree: BtreeRoot::new(bmdr.clone(), keys.clone(), pointers.clone()),
-                size:  self.di_core.di_size,
-            }),
-            _ => {
-                panic!("Unsupported file format!");
-            }
+            DiU::Bmx(bmx) => Ok(Box::new(FileExtentList {
+                bmx:      Bmx::new(bmx),
+                size:     self.di_core.di_size,
+                realtime: self.di_core.realtime(),
+            })),
+            DiU::Bmbt((bmdr, keys, pointers)) => Ok(Box::new(FileBtree {
+                btree:    BtreeRoot::new(bmdr.clone(), keys.clone(), pointers.clone()),
+                size:     self.di_core.di_size,
+                realtime: self.di_core.realtime(),
+            })),
+            DiU::Dir2Sf(_) => Err(libc::EISDIR),
+            _ => Err(libc::EINVAL),
         }
     }
 
-    pub fn get_link_data<R>(&self, buf_reader: &mut R, superblock: &Sb) -> CString
+    pub fn get_link_data<R>(&self, buf_reader: &mut R, superblock: &Sb) -> Result<CString, c_int>
     where
         R: BufRead + Reader + Seek,
     {
         match &self.di_u {
-            DiU::Symlink(data) => CString::new(data.clone()).unwrap(),
+            DiU::Symlink(data) => CString::new(data.clone()).map_err(|_| {
+                error!("Symlink target contains a NUL byte");
+                EIO
+            }),
             DiU::Bmx(bmbtv) => {
                 SymlinkExtents::get_target(buf_reader.by_ref(), &Bmx::new(bmbtv), superblock)
             }
-            _ => {
-                panic!("Unsupported link format!");
-            }
+            _ => Err(libc::EINVAL),
         }
     }
 
@@ -337,10 +371,14 @@ impl Dinode {
         superblock: &Sb,
     ) -> &mut Option<Attributes> {
         if self.attributes.is_none() {
+            // An attribute fork can exist yet hold no attributes, for example after the last one
+            // was removed.  Treat every such fork exactly like a missing one, so the caller needn't
+            // distinguish between them.
             self.attributes = match &self.di_a {
+                Some(DiA::Attrsf(attr)) if attr.list.is_empty() => None,
                 Some(DiA::Attrsf(attr)) => Some(Attributes::Sf(attr.clone())),
                 Some(DiA::Abmx(bmbtv)) => {
-                    if self.di_core.di_anextents > 0 {
+                    if self.di_core.di_anextents > 0 && !bmbtv.is_empty() {
                         Some(crate::libxfuse::attr::open(
                             buf_reader.by_ref(),
                             superblock,
@@ -350,17 +388,280 @@ impl Dinode {
                         None
                     }
                 }
+                Some(DiA::Abmbt((bmdr, _, _))) if bmdr.bb_numrecs == 0 => None,
                 Some(DiA::Abmbt((bmdr, keys, pointers))) => {
                     let btree_root = BtreeRoot::new(bmdr.clone(), keys.clone(), pointers.clone());
-                    Some(Attributes::Btree(AttrBtree::new(
-                        buf_reader.by_ref(),
-                        superblock,
-                        btree_root,
-                    )))
+                    // A fork that can't be read has already been logged.  Its attributes are
+                    // simply unavailable.
+                    AttrBtree::new(buf_reader.by_ref(), superblock, btree_root)
+                        .map(Attributes::Btree)
+                        .ok()
                 }
                 None => None,
             };
         }
         &mut self.attributes
     }
+
+    /// Like [`DinodeCore::stat`], but with the details that it can't know: the device number
+    /// that device files store in their data forks, and the file system's block size.
+    pub fn stat(&self, ino: XfsIno, sb: &Sb) -> Result<FileAttr, c_int> {
+        let mut attr = self.di_core.stat(ino)?;
+        if let DiU::Blk(dev) | DiU::Chr(dev) = self.di_u {
+            // XFS always stores Linux's old 32-bit encoding.  FUSE has room for only 32 bits of
+            // the host's.
+            attr.rdev = libc::makedev(dev >> 18, dev & 0x3ffff) as u32;
+        }
+        attr.blksize = sb.sb_blocksize;
+        Ok(attr)
+    }
+
+    /// Discard the cached directory object, if any, returning whether there was one
+    pub fn drop_dir_cache(&mut self) -> bool {
+        self.directory.take().is_some()
+    }
+
+    /// Discard the cached attribute object, if any, returning whether there was one
+    pub fn drop_attr_cache(&mut self) -> bool {
+        self.attributes.take().is_some()
+    }
+
+    /// Evict the cached directory's least recently used blocks until it occupies at most `target`
+    /// bytes, or discard it entirely if it can't be shrunk that far.
+    pub fn shrink_dir_cache(&mut self, target: usize) {
+        if let Some(dir) = &self.directory {
+            dir.shrink_cache(target);
+        }
+        if self.dir_cache_bytes() > target {
+            self.drop_dir_cache();
+        }
+    }
+
+    /// Evict the cached attribute object's least recently used leaves until it occupies at most
+    /// `target` bytes, or discard it entirely if it can't be shrunk that far.
+    pub fn shrink_attr_cache(&mut self, target: usize) {
+        if let Some(attr) = &self.attributes {
+            attr.shrink_cache(target);
+        }
+        if self.attr_cache_bytes() > target {
+            self.drop_attr_cache();
+        }
+    }
+
+    /// Roughly how many bytes of memory the inode itself occupies, including its forks' extent
+    /// lists and shortform contents, but not its directory or attribute caches
+    pub fn inode_bytes(&self) -> usize {
+        fn btree_bytes(keys: &[BmbtKey], ptrs: &[XfsBmbtPtr]) -> usize {
+            mem::size_of_val(keys) + mem::size_of_val(ptrs)
+        }
+        let data = match &self.di_u {
+            DiU::Bmbt((_, keys, ptrs)) => btree_bytes(keys, ptrs),
+            DiU::Bmx(bmx) => mem::size_of_val(bmx.as_slice()),
+            DiU::Dir2Sf(dir) => dir.cache_bytes(),
+            DiU::Symlink(target) => target.len(),
+            DiU::Blk(_) | DiU::Chr(_) | DiU::Fifo | DiU::Socket => 0,
+        };
+        let attr = match &self.di_a {
+            Some(DiA::Attrsf(attr)) => attr.cache_bytes(),
+            Some(DiA::Abmx(bmx)) => mem::size_of_val(bmx.as_slice()),
+            Some(DiA::Abmbt((_, keys, ptrs))) => btree_bytes(keys, ptrs),
+            None => 0,
+        };
+        mem::size_of::<Self>() + data + attr
+    }
+
+    /// Roughly how many bytes of memory the cached directory object occupies
+    pub fn dir_cache_bytes(&self) -> usize {
+        self.directory.as_ref().map_or(0, Directory::cache_bytes)
+    }
+
+    /// Roughly how many bytes of memory the cached attribute object occupies
+    pub fn attr_cache_bytes(&self) -> usize {
+        self.attributes.as_ref().map_or(0, Attributes::cache_bytes)
+    }
+}
+
+#[cfg(test)]
+impl Dinode {
+    /// Build an inode with an extents-format attribute fork, for unit tests in other modules
+    pub fn with_attr_extents(recs: Vec<BmbtRec>) -> Self {
+        Dinode {
+            di_core:    DinodeCore {
+                di_anextents: recs.len().try_into().unwrap(),
+                ..Default::default()
+            },
+            di_u:       DiU::Fifo,
+            di_a:       Some(DiA::Abmx(recs)),
+            directory:  None,
+            attributes: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::OsStr,
+        io::{BufReader, Cursor},
+    };
+
+    use rstest::rstest;
+
+    use super::*;
+    use crate::libxfuse::{bmbt_rec::tests::superblock, test_util::di_mode};
+
+    /// Every shape of attribute fork that holds no attributes should look just like a missing
+    /// attribute fork.
+    #[rstest]
+    #[case::none(None)]
+    #[case::sf(Some(DiA::Attrsf(AttrShortform {
+        list: Vec::new(),
+        total_size: 0
+    })))]
+    #[case::extents(Some(DiA::Abmx(Vec::new())))]
+    #[case::btree(Some(DiA::Abmbt((
+        BmdrBlock {
+            bb_level: 1,
+            bb_numrecs: 0
+        },
+        Vec::new(),
+        Vec::new()
+    ))))]
+    fn get_attrs_empty(#[case] di_a: Option<DiA>) {
+        let mut dinode = Dinode {
+            di_core: DinodeCore::default(),
+            di_u: DiU::Fifo,
+            di_a,
+            directory: None,
+            attributes: None,
+        };
+        let sb = Sb::default();
+        let mut br = BufReader::new(Cursor::new(Vec::<u8>::new()));
+
+        assert!(dinode.get_attrs(&mut br, &sb).is_none());
+    }
+
+    fn rec(br_startoff: u64, br_blockcount: u64) -> BmbtRec {
+        BmbtRec {
+            br_startoff,
+            br_startblock: 1000 + br_startoff,
+            br_blockcount,
+            br_flag: false,
+        }
+    }
+
+    /// Block vs Leaf directory classification must not depend on the number of extents
+    #[rstest]
+    #[case::block(0, vec![rec(0, 1)], true)]
+    #[case::block_fragmented(1, vec![rec(0, 1), rec(1, 1)], true)]
+    #[case::leaf(0, vec![rec(0, 1), rec(1 << 23, 1)], false)]
+    #[case::leaf_data_not_at_0(0, vec![rec(2, 1), rec(1 << 23, 1)], false)]
+    #[case::single_extent_not_at_0(0, vec![rec(1, 1)], false)]
+    #[case::empty(0, vec![], false)]
+    fn is_block_dir(#[case] dirblklog: u8, #[case] bmbtv: Vec<BmbtRec>, #[case] expected: bool) {
+        let mut sb = Sb::default();
+        sb.sb_blocklog = 12;
+        sb.sb_dirblklog = dirblklog;
+        assert_eq!(super::is_block_dir(&bmbtv, &sb), expected);
+    }
+
+    /// A single-AG file system with 512 byte inodes
+    fn one_ag() -> Sb {
+        superblock().clone()
+    }
+
+    /// A shortform directory loaded by Dinode::from, as readdir does to learn file types on file
+    /// systems without ftype, must know its own inode number as well as its parent's.
+    #[test]
+    fn sf_dir_dot_entries() {
+        const INO: XfsIno = 131;
+        const PARENT: XfsIno = 128;
+
+        let sb = one_ag();
+        let offset = sb.ino_to_offset(INO).unwrap() as usize;
+        let mut img = vec![0u8; offset + sb.inode_size()];
+        let inode = &mut img[offset..];
+        inode[0..2].copy_from_slice(&XFS_DINODE_MAGIC.to_be_bytes());
+        inode[2..4].copy_from_slice(&di_mode(S_IFDIR | 0o755).to_be_bytes());
+        inode[4] = 2;
+        inode[5] = XfsDinodeFmt::Local as u8;
+        inode[83] = XfsDinodeFmt::Extents as u8;
+        // An empty shortform directory: no entries, 4-byte inode numbers, and the parent
+        inode[100..106].copy_from_slice(&[0, 0, 0, 0, 0, PARENT as u8]);
+
+        let mut br = BufReader::new(Cursor::new(img));
+        let mut dinode = Dinode::from(&mut br, &sb, INO).unwrap();
+        let dir = dinode.get_dir(&mut br, &sb).unwrap();
+        assert_eq!(dir.lookup(&mut br, &sb, OsStr::new(".")), Ok(INO));
+        assert_eq!(dir.lookup(&mut br, &sb, OsStr::new("..")), Ok(PARENT));
+    }
+
+    /// A shortform directory entry's name may not reach past the data fork into the attribute
+    /// fork, even if it would still fit within the inode.
+    #[test]
+    fn sf_dir_name_overruns_data_fork() {
+        const INO: XfsIno = 131;
+
+        let sb = one_ag();
+        let offset = sb.ino_to_offset(INO).unwrap() as usize;
+        let mut img = vec![0u8; offset + sb.inode_size()];
+        let inode = &mut img[offset..];
+        inode[0..2].copy_from_slice(&XFS_DINODE_MAGIC.to_be_bytes());
+        inode[2..4].copy_from_slice(&di_mode(S_IFDIR | 0o755).to_be_bytes());
+        inode[4] = 2;
+        inode[5] = XfsDinodeFmt::Local as u8;
+        // A 16-byte data fork, followed by an empty shortform attribute fork
+        inode[82] = 2;
+        inode[83] = XfsDinodeFmt::Local as u8;
+        inode[116..120].copy_from_slice(&[0, 4, 0, 0]);
+        // One entry, whose name claims 20 bytes though only 7 remain in the data fork
+        inode[100..109].copy_from_slice(&[1, 0, 0, 0, 0, 128, 20, 0, 0x60]);
+
+        let mut br = BufReader::new(Cursor::new(img));
+        assert_eq!(Dinode::from(&mut br, &sb, INO).err(), Some(EIO));
+    }
+
+    /// Device files report the device number from their data forks, in the host's encoding,
+    /// and every file reports the file system's block size.
+    #[rstest]
+    #[case::blk(S_IFBLK, 1 << 18 | 2, libc::makedev(1, 2))]
+    #[case::chr(S_IFCHR, 4 << 18 | 0x20000, libc::makedev(4, 0x20000))]
+    #[case::fifo(S_IFIFO, 1 << 18 | 2, 0)]
+    fn stat_rdev(#[case] mode: mode_t, #[case] dev: XfsDev, #[case] expected: libc::dev_t) {
+        const INO: XfsIno = 131;
+
+        let sb = one_ag();
+        let offset = sb.ino_to_offset(INO).unwrap() as usize;
+        let mut img = vec![0u8; offset + sb.inode_size()];
+        let inode = &mut img[offset..];
+        inode[0..2].copy_from_slice(&XFS_DINODE_MAGIC.to_be_bytes());
+        inode[2..4].copy_from_slice(&di_mode(mode | 0o644).to_be_bytes());
+        inode[4] = 2;
+        inode[5] = XfsDinodeFmt::Dev as u8;
+        inode[83] = XfsDinodeFmt::Extents as u8;
+        inode[100..104].copy_from_slice(&dev.to_be_bytes());
+
+        let mut br = BufReader::new(Cursor::new(img));
+        let attr = Dinode::from(&mut br, &sb, INO)
+            .unwrap()
+            .stat(INO, &sb)
+            .unwrap();
+        assert_eq!(libc::dev_t::from(attr.rdev), expected);
+        assert_eq!(attr.blksize, sb.sb_blocksize);
+    }
+
+    /// An inode that lies in the file system, but beyond the end of a truncated image, can't be
+    /// read.
+    #[rstest]
+    #[case::missing(0)]
+    #[case::partial(100)]
+    fn truncated(#[case] partial: usize) {
+        const INO: XfsIno = 131;
+
+        let sb = one_ag();
+        let offset = sb.ino_to_offset(INO).unwrap() as usize;
+        let img = vec![0u8; offset + partial];
+        let mut br = BufReader::new(Cursor::new(img));
+        assert_eq!(Dinode::from(&mut br, &sb, INO).err(), Some(EIO));
+    }
 }
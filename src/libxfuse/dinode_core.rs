@@ -28,7 +28,6 @@
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use bincode::{de::Decoder, error::DecodeError, impl_borrow_decode, Decode};
-use fuser::FileAttr;
 use libc::c_int;
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
@@ -36,7 +35,9 @@ use num_traits::FromPrimitive;
 use super::{
     btree::{BmbtKey, BmdrBlock},
     definitions::*,
-    utils::{get_file_type, FileKind, Uuid},
+    stat::FileAttr,
+    utils::{get_file_type, FileKind, FromBytes, Uuid},
+    validate,
     S_IFMT,
 };
 
@@ -55,15 +56,87 @@ pub enum XfsDinodeFmt {
 impl bincode::Decode for XfsDinodeFmt {
     fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError> {
         let discriminant: u8 = Decode::decode(decoder)?;
-        Ok(XfsDinodeFmt::from_u8(discriminant).expect("Unknown dinode fmt"))
+        XfsDinodeFmt::from_u8(discriminant).ok_or_else(|| {
+            DecodeError::OtherString(format!("unknown fork format {}", discriminant))
+        })
     }
 }
 impl_borrow_decode!(XfsDinodeFmt);
 
-#[derive(Debug, Decode, Default)]
-pub struct XfsTimestamp {
-    pub t_sec:  i32,
-    pub t_nsec: u32,
+/// An inode timestamp, as stored on disk.  The classic format is a signed 32-bit count of seconds
+/// since the Unix epoch, followed by nanoseconds.  With bigtime, it's a single unsigned 64-bit
+/// count of nanoseconds since the classic format's earliest time, in December 1901.
+#[derive(Clone, Copy, Debug, Decode, Default)]
+pub struct XfsTimestamp(u64);
+
+impl XfsTimestamp {
+    /// Seconds between the bigtime epoch and the Unix epoch.  Same as the kernel's
+    /// XFS_BIGTIME_EPOCH_OFFSET.
+    const BIGTIME_EPOCH_OFFSET: u64 = 1 << 31;
+    const NSEC_PER_SEC: u64 = 1_000_000_000;
+
+    #[cfg(test)]
+    pub const fn classic(t_sec: i32, t_nsec: u32) -> Self {
+        XfsTimestamp(((t_sec as u32 as u64) << 32) | t_nsec as u64)
+    }
+
+    fn to_classic(self) -> SystemTime {
+        let t_sec = (self.0 >> 32) as u32 as i32;
+        let t_nsec = self.0 as u32;
+        unix_time(i64::from(t_sec)) + Duration::from_nanos(t_nsec.into())
+    }
+
+    fn to_bigtime(self) -> SystemTime {
+        let secs = self.0 / Self::NSEC_PER_SEC;
+        let nsec = self.0 % Self::NSEC_PER_SEC;
+        // Both fit in an i64
+        unix_time(secs as i64 - Self::BIGTIME_EPOCH_OFFSET as i64) + Duration::from_nanos(nsec)
+    }
+
+    /// Encode a time in the classic format, clamped to the range that it can represent
+    fn from_classic(t: SystemTime) -> Self {
+        let (secs, nsec) = secs_since_epoch(t);
+        match i32::try_from(secs) {
+            Ok(secs) => XfsTimestamp(((secs as u32 as u64) << 32) | u64::from(nsec)),
+            Err(_) if secs < 0 => XfsTimestamp((i32::MIN as u32 as u64) << 32),
+            Err(_) => XfsTimestamp(((i32::MAX as u32 as u64) << 32) | 999_999_999),
+        }
+    }
+
+    /// Encode a time in the bigtime format, clamped to the range that it can represent
+    fn from_bigtime(t: SystemTime) -> Self {
+        let (secs, nsec) = secs_since_epoch(t);
+        let secs = (secs + Self::BIGTIME_EPOCH_OFFSET as i64).max(0) as u64;
+        XfsTimestamp(
+            secs.saturating_mul(Self::NSEC_PER_SEC)
+                .saturating_add(nsec.into()),
+        )
+    }
+}
+
+/// Split a time into whole seconds since the Unix epoch, which may be negative, and nanoseconds
+fn secs_since_epoch(t: SystemTime) -> (i64, u32) {
+    match t.duration_since(UNIX_EPOCH) {
+        Ok(d) => (d.as_secs().try_into().unwrap_or(i64::MAX), d.subsec_nanos()),
+        Err(e) => {
+            let d = e.duration();
+            let secs = i64::try_from(d.as_secs()).unwrap_or(i64::MAX);
+            if d.subsec_nanos() == 0 {
+                (-secs, 0)
+            } else {
+                (-secs - 1, 1_000_000_000 - d.subsec_nanos())
+            }
+        }
+    }
+}
+
+/// Convert seconds since the Unix epoch, which may be negative, to a SystemTime
+fn unix_time(secs: i64) -> SystemTime {
+    if secs >= 0 {
+        UNIX_EPOCH + Duration::from_secs(secs.unsigned_abs())
+    } else {
+        UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs())
+    }
 }
 
 #[allow(dead_code)]
@@ -84,7 +157,11 @@ mod constants {
     pub const XFS_DIFLAG_NODEFRAG: u16 = 1 << 13;
     pub const XFS_DIFLAG_FILESTREAMS: u16 = 1 << 14;
 
+    pub const XFS_DIFLAG2_DAX: u64 = 1 << 0;
+    pub const XFS_DIFLAG2_REFLINK: u64 = 1 << 1;
+    pub const XFS_DIFLAG2_COWEXTSIZE: u64 = 1 << 2;
     pub const XFS_DIFLAG2_BIGTIME: u64 = 1 << 3;
+    pub const XFS_DIFLAG2_NREXT64: u64 = 1 << 4;
 }
 
 #[derive(Debug)]
@@ -100,26 +177,29 @@ pub struct DinodeCore {
     pub di_nlink:     u32,
     //_di_projid: u16,
     //_di_projid_hi: u16,
-    //_di_pad: [u8; 6],
-    //_di_flushiter: u16,
+    // With NREXT64, di_big_nextents.  Otherwise padding.
+    //_di_v3_pad: u64,
     pub di_atime:     XfsTimestamp,
     pub di_mtime:     XfsTimestamp,
     pub di_ctime:     XfsTimestamp,
     pub di_size:      XfsFsize,
     pub di_nblocks:   XfsRfsblock,
     //_di_extsize: XfsExtlen,
+    /// Stored in 32 bits, or 64 with NREXT64
     pub di_nextents:  XfsExtnum,
+    /// Stored in 16 bits, or 32 with NREXT64
     pub di_anextents: XfsAextnum,
     pub di_forkoff:   u8,
     pub di_aformat:   XfsDinodeFmt,
     //_di_dmevmask: u32,
     //_di_dmstate: u16,
-    //_di_flags: u16,
+    pub di_flags:     u16,
     pub di_gen:       u32,
 
-    //_di_next_unlinked: u32,
-
+    /// The next inode on this AG's unlinked list, or NULLAGINO
+    pub di_next_unlinked: u32,
     /* Version 5 file system (inode version 3) fields start here */
+
     //_di_crc: u32,
     //_di_changecount: u64,
     //_di_lsn: u64,
@@ -132,9 +212,16 @@ pub struct DinodeCore {
 }
 
 impl DinodeCore {
+    const ATIME_OFFSET: usize = 32;
+    const CHANGECOUNT_OFFSET: usize = 104;
+    const CRC_OFFSET: usize = 100;
+    const CTIME_OFFSET: usize = 48;
+    const MTIME_OFFSET: usize = 40;
+    const SIZE_OFFSET: usize = 56;
+
     /// Compute the gap in bytes between the end of the keys and the start of the pointers, for
-    /// BTree-formatted inodes only, for the data fork.
-    pub const fn dfork_btree_ptr_gap(&self, inode_size: usize, bb_numrecs: u16) -> usize {
+    /// BTree-formatted inodes only, for the data fork.  None if the keys don't fit.
+    pub const fn dfork_btree_ptr_gap(&self, inode_size: usize, bb_numrecs: u16) -> Option<usize> {
         debug_assert!(matches!(self.di_format, XfsDinodeFmt::Btree));
         // The XFS Algorithms and Data Structures document contains an error here.  It says that
         // the array of xfs_bmbt_ptr_t values immediately follows the array of xfs_bmbt_key_t
@@ -155,14 +242,17 @@ impl DinodeCore {
                 space + 8 - rem
             }
         };
-        let gap = space - BmdrBlock::SIZE - bb_numrecs as usize * BmbtKey::SIZE;
+        let gap = match space.checked_sub(BmdrBlock::SIZE + bb_numrecs as usize * BmbtKey::SIZE) {
+            Some(gap) => gap,
+            None => return None,
+        };
         // Round down to a multiple of 8
-        gap - gap % 8
+        Some(gap - gap % 8)
     }
 
     /// Compute the gap in bytes between the end of the keys and the start of the pointers, for
-    /// BTree-formatted inodes only, for the attr fork.
-    pub const fn afork_btree_ptr_gap(&self, inode_size: usize, bb_numrecs: u16) -> usize {
+    /// BTree-formatted inodes only, for the attr fork.  None if the keys don't fit.
+    pub const fn afork_btree_ptr_gap(&self, inode_size: usize, bb_numrecs: u16) -> Option<usize> {
         debug_assert!(matches!(self.di_aformat, XfsDinodeFmt::Btree));
         debug_assert!(self.di_forkoff != 0);
         // The XFS Algorithms and Data Structures document, section 15.4, isn't really specific
@@ -174,11 +264,24 @@ impl DinodeCore {
         }
         let mut attr_fork_ofs = self.literal_area_offset() + self.di_forkoff as usize * 8;
         attr_fork_ofs -= attr_fork_ofs % 8;
-        let mut ptr_ofs = (inode_size - attr_fork_ofs) / 2 + attr_fork_ofs;
+        let mut ptr_ofs = match inode_size.checked_sub(attr_fork_ofs) {
+            Some(len) => len / 2 + attr_fork_ofs,
+            None => return None,
+        };
         if ptr_ofs % 8 > 0 {
             ptr_ofs += 8 - ptr_ofs % 8
         }
-        ptr_ofs - attr_fork_ofs - already
+        (ptr_ofs - attr_fork_ofs).checked_sub(already)
+    }
+
+    /// The size in bytes of the data fork, which extends from the literal area to the attribute
+    /// fork, if any, or else to the end of the inode
+    pub const fn dfork_size(&self, inode_size: usize) -> usize {
+        if self.di_forkoff == 0 {
+            inode_size.saturating_sub(self.literal_area_offset())
+        } else {
+            self.di_forkoff as usize * 8
+        }
     }
 
     pub const fn literal_area_offset(&self) -> usize {
@@ -203,7 +306,7 @@ impl DinodeCore {
             atime: self.timestamp(&self.di_atime),
             mtime: self.timestamp(&self.di_mtime),
             ctime: self.timestamp(&self.di_ctime),
-            crtime: self.timestamp(&self.di_crtime),
+            crtime: self.crtime(),
             kind,
             perm: self.di_mode & !S_IFMT,
             nlink: self.di_nlink,
@@ -211,22 +314,65 @@ impl DinodeCore {
             gid: self.di_gid,
             rdev: 0,
             blksize: 0,
-            flags: 0,
         })
     }
 
-    fn timestamp(&self, ts: &XfsTimestamp) -> SystemTime {
-        if self.di_version >= 3 && (self.di_flags2 & constants::XFS_DIFLAG2_BIGTIME != 0) {
-            // XXX this could be made a const if the Rust const_trait_impl
-            // feature stabilizes.
-            let classic_epoch: SystemTime = UNIX_EPOCH - Duration::from_secs(i32::MAX as u64 + 1);
+    /// Birth time.  Inodes older than v3 don't record it, so report ctime instead, like the
+    /// kernel does.
+    fn crtime(&self) -> SystemTime {
+        if self.di_version >= 3 {
+            self.timestamp(&self.di_crtime)
+        } else {
+            self.timestamp(&self.di_ctime)
+        }
+    }
+
+    /// Might this file share data blocks with others?  XFS sets this flag on both files of a
+    /// reflink, and clears it only when it can prove that nothing is shared anymore, so the
+    /// refcount btree need not be searched for any file without it.
+    pub fn reflink(&self) -> bool {
+        self.di_version >= 3 && (self.di_flags2 & constants::XFS_DIFLAG2_REFLINK != 0)
+    }
+
+    /// Is this file's data on the realtime device, rather than the data device?
+    pub fn realtime(&self) -> bool {
+        self.di_flags & constants::XFS_DIFLAG_REALTIME != 0
+    }
 
-            classic_epoch
-                + Duration::from_nanos(
-                    u64::from(ts.t_sec as u32) * (1u64 << 32) + u64::from(ts.t_nsec),
-                )
+    fn bigtime(&self) -> bool {
+        self.di_version >= 3 && (self.di_flags2 & constants::XFS_DIFLAG2_BIGTIME != 0)
+    }
+
+    pub(super) fn timestamp(&self, ts: &XfsTimestamp) -> SystemTime {
+        if self.bigtime() {
+            ts.to_bigtime()
         } else {
-            UNIX_EPOCH + Duration::new(ts.t_sec as u64, ts.t_nsec)
+            ts.to_classic()
+        }
+    }
+
+    /// Encode a time in whichever format this inode uses
+    pub fn encode_timestamp(&self, t: SystemTime) -> XfsTimestamp {
+        if self.bigtime() {
+            XfsTimestamp::from_bigtime(t)
+        } else {
+            XfsTimestamp::from_classic(t)
+        }
+    }
+
+    /// Store this core's timestamps and size into `raw`, the inode as it's stored on disk.  A V3
+    /// inode's change counter is incremented too, as the kernel does for every change, and its
+    /// CRC updated.  Nothing else is ever changed.
+    pub fn write_back(&self, raw: &mut [u8]) {
+        raw[Self::ATIME_OFFSET..][..8].copy_from_slice(&self.di_atime.0.to_be_bytes());
+        raw[Self::MTIME_OFFSET..][..8].copy_from_slice(&self.di_mtime.0.to_be_bytes());
+        raw[Self::CTIME_OFFSET..][..8].copy_from_slice(&self.di_ctime.0.to_be_bytes());
+        raw[Self::SIZE_OFFSET..][..8].copy_from_slice(&self.di_size.to_be_bytes());
+        if self.di_version >= 3 {
+            let changecount = &mut raw[Self::CHANGECOUNT_OFFSET..][..8];
+            let count = u64::from_be_bytes((*changecount).try_into().unwrap());
+            changecount.copy_from_slice(&count.wrapping_add(1).to_be_bytes());
+            validate::set_crc(raw, Self::CRC_OFFSET);
         }
     }
 }
@@ -238,13 +384,20 @@ impl Decode for DinodeCore {
         let mut di_ino = 0;
 
         let di_magic: u16 = Decode::decode(decoder)?;
-        assert_eq!(di_magic, XFS_DINODE_MAGIC, "Inode magic number is invalid");
+        if di_magic != XFS_DINODE_MAGIC {
+            return Err(DecodeError::OtherString(format!(
+                "bad magic number {:#x}",
+                di_magic
+            )));
+        }
         let di_mode: u16 = Decode::decode(decoder)?;
         let di_version: i8 = Decode::decode(decoder)?;
-        assert!(
-            di_version == 2 || di_version == 3,
-            "Only inode versions 2 and 3 are supported"
-        );
+        if di_version != 2 && di_version != 3 {
+            return Err(DecodeError::OtherString(format!(
+                "unsupported version {}",
+                di_version
+            )));
+        }
         let di_format: XfsDinodeFmt = Decode::decode(decoder)?;
         let _di_onlink: u16 = Decode::decode(decoder)?;
         let di_uid: u32 = Decode::decode(decoder)?;
@@ -252,23 +405,22 @@ impl Decode for DinodeCore {
         let di_nlink: u32 = Decode::decode(decoder)?;
         let _di_projid: u16 = Decode::decode(decoder)?;
         let _di_projid_hi: u16 = Decode::decode(decoder)?;
-        let _di_pad: [u8; 6] = Decode::decode(decoder)?;
-        let _di_flushiter: u16 = Decode::decode(decoder)?;
+        let di_v3_pad: u64 = Decode::decode(decoder)?;
         let di_atime: XfsTimestamp = Decode::decode(decoder)?;
         let di_mtime: XfsTimestamp = Decode::decode(decoder)?;
         let di_ctime: XfsTimestamp = Decode::decode(decoder)?;
         let di_size: XfsFsize = Decode::decode(decoder)?;
         let di_nblocks: XfsRfsblock = Decode::decode(decoder)?;
         let _di_extsize: XfsExtlen = Decode::decode(decoder)?;
-        let di_nextents: XfsExtnum = Decode::decode(decoder)?;
-        let di_anextents: XfsAextnum = Decode::decode(decoder)?;
+        let nextents32: u32 = Decode::decode(decoder)?;
+        let anextents16: u16 = Decode::decode(decoder)?;
         let di_forkoff: u8 = Decode::decode(decoder)?;
         let di_aformat: XfsDinodeFmt = Decode::decode(decoder)?;
         let _di_dmevmask: u32 = Decode::decode(decoder)?;
         let _di_dmstate: u16 = Decode::decode(decoder)?;
-        let _di_flags: u16 = Decode::decode(decoder)?;
+        let di_flags: u16 = Decode::decode(decoder)?;
         let di_gen: u32 = Decode::decode(decoder)?;
-        let _di_next_unlinked: u32 = Decode::decode(decoder)?;
+        let di_next_unlinked: u32 = Decode::decode(decoder)?;
         if di_version >= 3 {
             let _di_crc: u32 = Decode::decode(decoder)?;
             let _di_changecount: u64 = Decode::decode(decoder)?;
@@ -280,6 +432,13 @@ impl Decode for DinodeCore {
             di_ino = Decode::decode(decoder)?;
             let _di_uuid: Uuid = Decode::decode(decoder)?;
         }
+        // Large extent counters move the data fork's count into the old padding, and widen the
+        // attribute fork's into the space where the data fork's used to be.
+        let (di_nextents, di_anextents) = if di_flags2 & constants::XFS_DIFLAG2_NREXT64 != 0 {
+            (di_v3_pad, nextents32)
+        } else {
+            (nextents32.into(), anextents16.into())
+        };
 
         Ok(DinodeCore {
             di_mode,
@@ -297,7 +456,9 @@ impl Decode for DinodeCore {
             di_anextents,
             di_forkoff,
             di_aformat,
+            di_flags,
             di_gen,
+            di_next_unlinked,
             di_flags2,
             di_crtime,
             di_ino,
@@ -308,9 +469,29 @@ impl_borrow_decode!(DinodeCore);
 
 #[cfg(test)]
 mod tests {
+    use libc::{mode_t, S_IFDIR, S_IFREG};
     use rstest::rstest;
 
     use super::*;
+    use crate::libxfuse::{stat::FileType, test_util::di_mode, utils::decode_from};
+
+    /// The setuid, setgid, and sticky bits are part of the permissions, and fuser passes them
+    /// to the kernel unmasked.
+    #[rstest]
+    #[case::setuid(S_IFREG | 0o4755, FileType::RegularFile)]
+    #[case::setgid(S_IFREG | 0o2711, FileType::RegularFile)]
+    #[case::setgid_dir(S_IFDIR | 0o2775, FileType::Directory)]
+    #[case::sticky_dir(S_IFDIR | 0o1777, FileType::Directory)]
+    #[case::all(S_IFREG | 0o7777, FileType::RegularFile)]
+    fn stat_special_bits(#[case] mode: mode_t, #[case] kind: FileType) {
+        let core = DinodeCore {
+            di_mode: di_mode(mode),
+            ..Default::default()
+        };
+        let attr = core.stat(128).unwrap();
+        assert_eq!(attr.kind, kind);
+        assert_eq!(attr.perm, di_mode(mode) & 0o7777);
+    }
 
     /// Test the afork_btree_ptr_gap function against data from real live file systems.  The XFS
     /// Algorithms & Data Structures book does not accurately document this gap.
@@ -331,7 +512,7 @@ mod tests {
             di_aformat: XfsDinodeFmt::Btree,
             ..Default::default()
         };
-        assert_eq!(dic.afork_btree_ptr_gap(inode_size, bb_numrecs), gap);
+        assert_eq!(dic.afork_btree_ptr_gap(inode_size, bb_numrecs), Some(gap));
     }
 
     /// Test the dfork_btree_ptr_gap function against data from real live file systems.  The XFS
@@ -362,6 +543,153 @@ mod tests {
             di_format: XfsDinodeFmt::Btree,
             ..Default::default()
         };
-        assert_eq!(dic.dfork_btree_ptr_gap(inode_size, bb_numrecs), gap);
+        assert_eq!(dic.dfork_btree_ptr_gap(inode_size, bb_numrecs), Some(gap));
+    }
+
+    /// v2 inodes have no crtime, so it should be reported as equal to ctime
+    #[rstest]
+    #[case::v2(2, 1000)]
+    #[case::v3(3, 500)]
+    fn crtime(#[case] di_version: i8, #[case] expected: u64) {
+        let dic = DinodeCore {
+            di_version,
+            di_mode: di_mode(libc::S_IFREG | 0o644),
+            di_ctime: XfsTimestamp::classic(1000, 0),
+            di_crtime: XfsTimestamp::classic(500, 0),
+            ..Default::default()
+        };
+        let attr = dic.stat(1).unwrap();
+        assert_eq!(attr.crtime, UNIX_EPOCH + Duration::from_secs(expected));
+    }
+
+    /// Boundary values from the kernel's xfs_bigtime_to_unix and xfs_inode_from_disk_ts
+    #[rstest]
+    #[case::epoch_min(0, -(1 << 31), 0)]
+    #[case::unix_epoch(1 << 31, 0, 0)]
+    #[case::y2038((1 << 32) - 1, (1 << 31) - 1, 0)]
+    #[case::y2038_plus_1(1 << 32, 1 << 31, 0)]
+    #[case::nsec(1 << 31, 0, 999_999_999)]
+    #[case::max(u64::MAX / 1_000_000_000, 16_299_260_425, 0)]
+    fn bigtime(#[case] secs: u64, #[case] unix_secs: i64, #[case] nsec: u32) {
+        let dic = DinodeCore {
+            di_version: 3,
+            di_flags2: constants::XFS_DIFLAG2_BIGTIME,
+            ..Default::default()
+        };
+        let raw = secs * 1_000_000_000 + u64::from(nsec);
+        let expected = unix_time(unix_secs) + Duration::from_nanos(nsec.into());
+        assert_eq!(dic.timestamp(&XfsTimestamp(raw)), expected);
+    }
+
+    /// The very last nanosecond that bigtime can represent, around the year 2486
+    #[test]
+    fn bigtime_max() {
+        let dic = DinodeCore {
+            di_version: 3,
+            di_flags2: constants::XFS_DIFLAG2_BIGTIME,
+            ..Default::default()
+        };
+        let expected = UNIX_EPOCH + Duration::new(16_299_260_425, 709_551_615);
+        assert_eq!(dic.timestamp(&XfsTimestamp(u64::MAX)), expected);
+    }
+
+    /// Classic timestamps are signed, so they can predate the Unix epoch back to December 1901
+    #[rstest]
+    #[case::min(i32::MIN, 0)]
+    #[case::before_unix_epoch(-1, 500_000_000)]
+    #[case::unix_epoch(0, 0)]
+    #[case::max(i32::MAX, 999_999_999)]
+    fn classic(#[case] t_sec: i32, #[case] t_nsec: u32) {
+        let dic = DinodeCore {
+            di_version: 3,
+            ..Default::default()
+        };
+        let expected = unix_time(t_sec.into()) + Duration::from_nanos(t_nsec.into());
+        assert_eq!(
+            dic.timestamp(&XfsTimestamp::classic(t_sec, t_nsec)),
+            expected
+        );
+    }
+
+    /// Encoding a time and decoding it again should be lossless, within either format's range
+    #[rstest]
+    #[case::classic_before_unix_epoch(0, -1, 500_000_000)]
+    #[case::classic_unix_epoch(0, 0, 0)]
+    #[case::classic_max(0, i32::MAX.into(), 999_999_999)]
+    #[case::bigtime_min(constants::XFS_DIFLAG2_BIGTIME, -(1 << 31), 0)]
+    #[case::bigtime_y2038_plus_1(constants::XFS_DIFLAG2_BIGTIME, 1 << 31, 42)]
+    fn encode_timestamp(#[case] di_flags2: u64, #[case] unix_secs: i64, #[case] nsec: u32) {
+        let dic = DinodeCore {
+            di_version: 3,
+            di_flags2,
+            ..Default::default()
+        };
+        let t = unix_time(unix_secs) + Duration::from_nanos(nsec.into());
+        assert_eq!(dic.timestamp(&dic.encode_timestamp(t)), t);
+    }
+
+    /// Times beyond the classic format's range are clamped, rather than wrapping around
+    #[rstest]
+    #[case::too_early(-(1 << 40), i32::MIN.into(), 0)]
+    #[case::too_late(1 << 40, i32::MAX.into(), 999_999_999)]
+    fn encode_timestamp_clamp(#[case] secs: i64, #[case] clamped: i64, #[case] nsec: u32) {
+        let dic = DinodeCore {
+            di_version: 2,
+            ..Default::default()
+        };
+        let expected = unix_time(clamped) + Duration::from_nanos(nsec.into());
+        assert_eq!(
+            dic.timestamp(&dic.encode_timestamp(unix_time(secs))),
+            expected
+        );
+    }
+
+    /// write_back should change only the timestamps, the size, the change counter, and the CRC
+    #[test]
+    fn write_back() {
+        let dic = DinodeCore {
+            di_version: 3,
+            di_size: 0x0102_0304_0506_0708,
+            di_mtime: XfsTimestamp::classic(1, 2),
+            ..Default::default()
+        };
+        let mut raw = vec![0xffu8; 512];
+        raw[104..112].copy_from_slice(&41u64.to_be_bytes());
+        dic.write_back(&mut raw);
+        assert_eq!(raw[..32], [0xff; 32]);
+        assert_eq!(raw[32..40], [0; 8]);
+        assert_eq!(raw[40..48], [0, 0, 0, 1, 0, 0, 0, 2]);
+        assert_eq!(raw[56..64], [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert!(validate::crc_ok(&raw, DinodeCore::CRC_OFFSET));
+        assert_eq!(raw[104..112], 42u64.to_be_bytes());
+        assert_eq!(raw[112..], [0xff; 400]);
+    }
+
+    /// With NREXT64, the extent counts are wider, and stored in different places
+    #[rstest]
+    #[case::classic(false, 7, 3)]
+    #[case::nrext64(true, 1 << 40, 1 << 20)]
+    fn extent_counts(
+        #[case] nrext64: bool,
+        #[case] nextents: XfsExtnum,
+        #[case] anextents: XfsAextnum,
+    ) {
+        let mut raw = vec![0u8; 176];
+        raw[0..2].copy_from_slice(&XFS_DINODE_MAGIC.to_be_bytes());
+        raw[2..4].copy_from_slice(&di_mode(S_IFREG | 0o644).to_be_bytes());
+        raw[4] = 3;
+        raw[5] = XfsDinodeFmt::Extents as u8;
+        raw[83] = XfsDinodeFmt::Extents as u8;
+        if nrext64 {
+            raw[24..32].copy_from_slice(&nextents.to_be_bytes());
+            raw[76..80].copy_from_slice(&anextents.to_be_bytes());
+            raw[120..128].copy_from_slice(&constants::XFS_DIFLAG2_NREXT64.to_be_bytes());
+        } else {
+            raw[76..80].copy_from_slice(&(nextents as u32).to_be_bytes());
+            raw[80..82].copy_from_slice(&(anextents as u16).to_be_bytes());
+        }
+        let dic: DinodeCore = decode_from(bincode::de::read::SliceReader::new(&raw)).unwrap();
+        assert_eq!(dic.di_nextents, nextents);
+        assert_eq!(dic.di_anextents, anextents);
     }
 }
@@ -36,14 +36,13 @@ use bincode::{
     error::DecodeError,
     Decode,
 };
-use fuser::FileType;
 use libc::c_int;
 
 use super::{
     definitions::*,
     sb::Sb,
-    utils::{decode, Uuid},
-    volume::SUPERBLOCK,
+    stat::FileType,
+    utils::{FromBytes, Uuid},
 };
 
 type XfsDir2DataOff = u16;
@@ -63,6 +62,9 @@ mod constants {
     pub const XFS_DIR3_FT_SOCK: u8 = 6;
     pub const XFS_DIR3_FT_SYMLINK: u8 = 7;
     pub const XFS_DIR3_FT_WHT: u8 = 8;
+
+    /// The longest name that a directory entry may have, since its length is stored in a byte
+    pub const XFS_NAME_MAX: usize = 255;
 }
 pub use constants::*;
 
@@ -77,6 +79,8 @@ pub struct Dir3BlkHdr {
 }
 
 impl Dir3BlkHdr {
+    /// Offset of the CRC, which covers the entire directory block
+    pub const CRC_OFFSET: usize = 4;
     pub const SIZE: u64 = 48;
 }
 
@@ -120,52 +124,48 @@ pub struct Dir2DataEntry {
     pub tag:     XfsDir2DataOff,
 }
 
-impl Dir2DataEntry {
-    pub fn get_length(sb: &Sb, raw: &[u8]) -> i64 {
-        let namelen: u8 = decode(&raw[8..]).unwrap().0;
-        if sb.has_ftype() {
-            ((namelen as i64 + 19) / 8) * 8
-        } else {
-            ((namelen as i64 + 18) / 8) * 8
-        }
-    }
+#[cfg(test)]
+thread_local! {
+    /// The number of [`Dir2DataEntry`] decodes performed by this thread, for unit tests
+    pub static ENTRY_DECODES: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
 }
 
-impl Decode for Dir2DataEntry {
-    fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError> {
-        let inumber = Decode::decode(decoder)?;
-        let sb = SUPERBLOCK.get().unwrap();
-        let namelen: u8 = Decode::decode(decoder)?;
-        let mut namebytes = vec![0u8; namelen.into()];
-        decoder.reader().read(&mut namebytes[..])?;
-        let name = OsString::from_vec(namebytes);
-        let ftype: Option<u8> = if sb.has_ftype() {
-            Some(Decode::decode(decoder)?)
-        } else {
-            None
-        };
-        // Pad up to 1 less than a multiple of 8 bytes
-        let pad: usize = if sb.has_ftype() {
-            // current offset is 9 + 1 + namelen + 1
-            4 - namelen as i16
-        } else {
-            // current offset is 9 + 1 + namelen
-            5 - namelen as i16
+impl Dir2DataEntry {
+    /// Decode an entry from the start of `raw`, and return it along with its length.  Whether it
+    /// includes a file type byte depends on the file system's features, not on the entry itself.
+    pub fn decode(raw: &[u8], sb: &Sb) -> Result<(Self, usize), DecodeError> {
+        #[cfg(test)]
+        ENTRY_DECODES.with(|n| n.set(n.get() + 1));
+        let namelen = *raw.get(8).ok_or_else(|| DecodeError::UnexpectedEnd {
+            additional: 9 - raw.len(),
+        })?;
+        let len = entry_len(sb, namelen);
+        if raw.len() < len {
+            return Err(DecodeError::UnexpectedEnd {
+                additional: len - raw.len(),
+            });
         }
-        .rem_euclid(8)
-        .try_into()
-        .unwrap();
-        decoder.reader().consume(pad);
-        let tag = Decode::decode(decoder)?;
-        Ok(Dir2DataEntry {
+        let inumber = u64::from_be_bytes(raw[0..8].try_into().unwrap());
+        let name_end = 9 + usize::from(namelen);
+        let name = OsString::from_vec(raw[9..name_end].to_vec());
+        let ftype = sb.has_ftype().then(|| raw[name_end]);
+        let tag = u16::from_be_bytes(raw[len - 2..len].try_into().unwrap());
+        let entry = Dir2DataEntry {
             inumber,
             name,
             ftype,
             tag,
-        })
+        };
+        Ok((entry, len))
     }
 }
 
+/// The on-disk length of a directory data entry whose name is `namelen` bytes long: its inode
+/// number, name length, name, file type if any, and tag, padded to a multiple of 8 bytes.
+fn entry_len(sb: &Sb, namelen: u8) -> usize {
+    (8 + 1 + usize::from(namelen) + usize::from(sb.has_ftype()) + 2).next_multiple_of(8)
+}
+
 #[derive(Debug)]
 pub struct Dir2DataUnused {
     _freetag: u16,
@@ -187,15 +187,67 @@ impl Decode for Dir2DataUnused {
     }
 }
 
-#[derive(Clone, Copy, Debug, Decode, Default)]
+/// The length of the entry or unused region at `ofs` within a directory data block, if that's
+/// really where one begins, as its tag confirms.
+fn region_len(raw: &[u8], sb: &Sb, ofs: usize) -> Option<usize> {
+    let len = if raw.get(ofs..ofs + 2)? == [0xff, 0xff] {
+        usize::from(u16::from_be_bytes(
+            raw.get(ofs + 2..ofs + 4)?.try_into().unwrap(),
+        ))
+    } else {
+        entry_len(sb, *raw.get(ofs + 8)?)
+    };
+    if len < 8 || len % 8 != 0 {
+        return None;
+    }
+    let tag = raw.get(ofs + len - 2..ofs + len)?;
+    (usize::from(u16::from_be_bytes(tag.try_into().unwrap())) == ofs).then_some(len)
+}
+
+/// Reject names that no directory entry could have, before hashing or comparing them: an empty
+/// name is EINVAL, and one longer than [`XFS_NAME_MAX`] is ENAMETOOLONG.
+pub fn check_name(name: &OsStr) -> Result<(), c_int> {
+    match name.len() {
+        0 => Err(libc::EINVAL),
+        len if len > XFS_NAME_MAX => Err(libc::ENAMETOOLONG),
+        _ => Ok(()),
+    }
+}
+
+/// Where to resume reading a directory data block, given a readdir offset within it.  Normally
+/// that's the offset itself, which a previous readdir returned as the start of the next entry.
+/// But a stale offset, like an NFS client's cookie from before the directory changed, may point
+/// anywhere.  Then, like Linux, resume at the first entry at or after it, by walking the block
+/// from its first entry at `start`.  Return `raw.len()` if there's none.
+pub fn resume_offset(raw: &[u8], sb: &Sb, start: usize, offset: usize) -> usize {
+    if offset >= start && region_len(raw, sb, offset).is_some() {
+        return offset;
+    }
+    let mut ofs = start;
+    while ofs < offset {
+        match region_len(raw, sb, ofs) {
+            Some(len) => ofs += len,
+            None => return raw.len(),
+        }
+    }
+    ofs.min(raw.len())
+}
+
+#[derive(Clone, Copy, Debug, Decode, Default, PartialEq)]
 pub struct Dir2LeafEntry {
     pub hashval: XfsDahash,
     pub address: XfsDir2Dataptr,
 }
 
-impl Dir2LeafEntry {
-    /// On-disk size in bytes
-    pub const SIZE: usize = 8;
+impl FromBytes for Dir2LeafEntry {
+    const SIZE: usize = 8;
+
+    fn from_bytes(raw: &[u8]) -> Self {
+        Dir2LeafEntry {
+            hashval: u32::from_be_bytes(raw[0..4].try_into().unwrap()),
+            address: u32::from_be_bytes(raw[4..8].try_into().unwrap()),
+        }
+    }
 }
 
 #[enum_dispatch::enum_dispatch]
@@ -205,7 +257,18 @@ pub trait Dir3 {
         buf_reader: &mut R,
         sb: &Sb,
         name: &OsStr,
-    ) -> Result<u64, c_int>;
+    ) -> Result<u64, c_int> {
+        self.lookup_entry(buf_reader, sb, name).map(|(ino, _)| ino)
+    }
+
+    /// Like [`lookup`](Self::lookup), but also return the entry's file type byte, if the file
+    /// system records one
+    fn lookup_entry<R: Reader + BufRead + Seek>(
+        &self,
+        buf_reader: &mut R,
+        sb: &Sb,
+        name: &OsStr,
+    ) -> Result<(XfsIno, Option<u8>), c_int>;
 
     /// Read the next dirent from a Directory
     fn next<R: Reader + BufRead + Seek>(
@@ -214,6 +277,13 @@ pub trait Dir3 {
         sb: &Sb,
         offset: i64,
     ) -> Result<(XfsIno, i64, Option<FileType>, OsString), c_int>;
+
+    /// Roughly how many bytes of memory the directory's cached entries and blocks occupy
+    fn cache_bytes(&self) -> usize;
+
+    /// Evict cached blocks, least recently used first, until the cache occupies at most `target`
+    /// bytes.  Directories whose cache can't be partly evicted keep all of it.
+    fn shrink_cache(&self, _target: usize) {}
 }
 
 #[derive(Debug)]
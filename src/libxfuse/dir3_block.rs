@@ -26,21 +26,37 @@
  * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
  */
 use std::{
+    cell::OnceCell,
     convert::TryInto,
     ffi::{OsStr, OsString},
     io::{BufRead, Seek, SeekFrom},
+    mem,
+    os::unix::ffi::OsStrExt,
 };
 
 use bincode::{de::read::Reader, Decode};
-use fuser::FileType;
 use libc::{c_int, ENOENT};
+use tracing::error;
 
 use super::{
+    bmbt_rec::Bmx,
     da_btree::hashname,
     definitions::*,
-    dir3::{Dir2DataEntry, Dir2DataHdr, Dir2DataUnused, Dir2LeafEntry, Dir3, Dir3DataHdr},
+    dir3::{
+        check_name,
+        resume_offset,
+        Dir2DataEntry,
+        Dir2DataHdr,
+        Dir2DataUnused,
+        Dir2LeafEntry,
+        Dir3,
+        Dir3BlkHdr,
+        Dir3DataHdr,
+    },
     sb::Sb,
-    utils::{decode, get_file_type, FileKind},
+    stat::FileType,
+    utils::{check_count, decode, decode_array, get_file_type, FileKind, FromBytes},
+    validate,
 };
 
 #[derive(Debug, Decode)]
@@ -54,70 +70,59 @@ impl Dir2BlockTail {
     pub const SIZE: usize = 8;
 }
 
-#[derive(Debug)]
-pub struct Dir2BlockDisk {
-    pub leaf:    Vec<Dir2LeafEntry>,
-    tail:        Dir2BlockTail,
-    raw:         Vec<u8>,
-    /// Start of directory entries within the directory block
-    data_offset: usize,
-}
-
-impl Dir2BlockDisk {
-    pub fn new<T>(buf_reader: &mut T, offset: u64, size: u32) -> Dir2BlockDisk
-    where
-        T: BufRead + Seek,
-    {
-        buf_reader.seek(SeekFrom::Start(offset)).unwrap();
-        let mut raw = vec![0u8; size as usize];
-        buf_reader.read_exact(&mut raw).unwrap();
-
-        let magic: u32 = decode(&raw[..]).unwrap().0;
-        let data_offset = match magic {
-            XFS_DIR2_BLOCK_MAGIC => {
-                let hdr: Dir2DataHdr = decode(&raw[..]).unwrap().0;
-                assert_eq!(hdr.magic, XFS_DIR2_BLOCK_MAGIC);
-                Dir2DataHdr::SIZE as usize
-            }
-            XFS_DIR3_BLOCK_MAGIC => {
-                let hdr: Dir3DataHdr = decode(&raw[..]).unwrap().0;
-                assert_eq!(hdr.hdr.magic, XFS_DIR3_BLOCK_MAGIC);
-                Dir3DataHdr::SIZE as usize
-            }
-            _ => panic!("Unknown magic number for block directory {:#x}", magic),
+/// Read part of a directory block, which may be split across several extents if it's larger
+/// than a file system block.  `offset` is relative to the start of the directory block.
+fn read_dirblock<T>(
+    buf_reader: &mut T,
+    superblock: &Sb,
+    bmx: &Bmx,
+    offset: usize,
+    buf: &mut [u8],
+) -> Result<(), c_int>
+where
+    T: BufRead + Seek,
+{
+    let blocksize = superblock.sb_blocksize as usize;
+    let mut done = 0;
+    while done < buf.len() {
+        let pos = offset + done;
+        let dblock = (pos >> superblock.sb_blocklog) as XfsFileoff;
+        let (mapping, len) = bmx.get_extent(dblock);
+        let (Some(fsblock), Some(len)) = (mapping.fsblock(), len) else {
+            validate::failure(format_args!(
+                "Block directory has a hole at block {}",
+                dblock
+            ))?;
+            return Err(libc::EIO);
         };
-
-        let tail_offset = (size as usize) - Dir2BlockTail::SIZE;
-        let tail: Dir2BlockTail = decode(&raw[tail_offset..]).unwrap().0;
-
-        let mut leaf_offset = tail_offset - Dir2LeafEntry::SIZE * tail.count as usize;
-
-        let mut leaf = Vec::with_capacity(tail.count as usize);
-        for _i in 0..tail.count {
-            leaf.push(decode(&raw[leaf_offset..]).unwrap().0);
-            leaf_offset += Dir2LeafEntry::SIZE;
-        }
-
-        Dir2BlockDisk {
-            leaf,
-            tail,
-            raw,
-            data_offset,
-        }
-    }
-
-    /// get the length of the raw data region
-    fn get_data_len(&self, directory_block_size: u32) -> u64 {
-        directory_block_size as u64
-            - Dir2BlockTail::SIZE as u64
-            - Dir2LeafEntry::SIZE as u64 * (self.tail.count as u64)
+        let extent_end = ((dblock + len) << superblock.sb_blocklog) as usize;
+        let n = (extent_end - pos).min(buf.len() - done);
+        buf_reader
+            .seek(SeekFrom::Start(
+                superblock.fsb_to_offset(fsblock) + (pos % blocksize) as u64,
+            ))
+            .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))?;
+        buf_reader
+            .read_exact(&mut buf[done..done + n])
+            .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))?;
+        done += n;
     }
+    Ok(())
 }
 
+/// A block directory.
+///
+/// Only the leaf entries and tail are read when the directory is opened, which is all that's
+/// needed to find a name's candidate entries.  The data region is read on demand.
 #[derive(Debug)]
 pub struct Dir2Block {
     ents:        Vec<Dir2LeafEntry>,
-    raw:         Box<[u8]>,
+    /// Location of the directory block on disk
+    bmx:         Bmx,
+    /// Length of the data region, which precedes the leaf entries
+    data_len:    usize,
+    /// The data region, if it has been read yet
+    raw:         OnceCell<Box<[u8]>>,
     /// Start of directory entries within the directory block
     data_offset: usize,
 }
@@ -126,23 +131,78 @@ impl Dir2Block {
     pub fn new<T: BufRead + Seek>(
         buf_reader: &mut T,
         superblock: &Sb,
-        start_block: XfsFsblock,
-    ) -> Dir2Block {
-        let offset = superblock.fsb_to_offset(start_block);
-        let dir_blk_size = superblock.sb_blocksize << superblock.sb_dirblklog;
+        bmx: &Bmx,
+    ) -> Result<Dir2Block, c_int> {
+        let dir_blk_size = (superblock.sb_blocksize << superblock.sb_dirblklog) as usize;
+        let data_offset = if superblock.version() >= 5 {
+            Dir3DataHdr::SIZE as usize
+        } else {
+            Dir2DataHdr::SIZE as usize
+        };
 
-        let dir_disk = Dir2BlockDisk::new(buf_reader.by_ref(), offset, dir_blk_size);
+        let tail_offset = dir_blk_size - Dir2BlockTail::SIZE;
+        let mut raw = [0u8; Dir2BlockTail::SIZE];
+        read_dirblock(buf_reader, superblock, bmx, tail_offset, &mut raw)?;
+        let tail: Dir2BlockTail = decode(&raw[..]).unwrap().0;
 
-        let data_len = dir_disk.get_data_len(dir_blk_size);
-        assert!(data_len as usize <= dir_disk.raw.len());
-        let mut raw = dir_disk.raw;
-        raw.truncate(data_len as usize);
+        // The leaf entries, which precede the tail, mustn't overlap the header
+        let count = tail.count as usize;
+        check_count::<Dir2LeafEntry>("Block directory", count, tail_offset - data_offset).map_err(
+            |e| {
+                error!("Cannot decode block directory: {}", e);
+                libc::EIO
+            },
+        )?;
+        let data_len = tail_offset - Dir2LeafEntry::SIZE * count;
+        let mut raw = vec![0u8; Dir2LeafEntry::SIZE * count];
+        read_dirblock(buf_reader, superblock, bmx, data_len, &mut raw)?;
+        let ents = decode_array(&raw, count).unwrap();
+
+        Ok(Dir2Block {
+            ents,
+            bmx: bmx.clone(),
+            data_len,
+            raw: OnceCell::new(),
+            data_offset,
+        })
+    }
 
-        Dir2Block {
-            raw:         raw.into(),
-            ents:        dir_disk.leaf,
-            data_offset: dir_disk.data_offset,
+    /// Return the data region, reading it if necessary.
+    fn data<T: BufRead + Seek>(&self, buf_reader: &mut T, superblock: &Sb) -> Result<&[u8], c_int> {
+        if let Some(raw) = self.raw.get() {
+            return Ok(raw);
         }
+        // In strict mode, read the whole directory block, so we can check its CRC.
+        let len = if validate::strict() {
+            (superblock.sb_blocksize << superblock.sb_dirblklog) as usize
+        } else {
+            self.data_len
+        };
+        let mut raw = vec![0u8; len];
+        read_dirblock(buf_reader, superblock, &self.bmx, 0, &mut raw)?;
+        let magic: u32 = decode(&raw[..]).unwrap().0;
+        let data_offset = match magic {
+            XFS_DIR2_BLOCK_MAGIC => {
+                let hdr: Dir2DataHdr = decode(&raw[..]).unwrap().0;
+                assert_eq!(hdr.magic, XFS_DIR2_BLOCK_MAGIC);
+                Dir2DataHdr::SIZE as usize
+            }
+            XFS_DIR3_BLOCK_MAGIC => {
+                let hdr: Dir3DataHdr = decode(&raw[..]).unwrap().0;
+                assert_eq!(hdr.hdr.magic, XFS_DIR3_BLOCK_MAGIC);
+                if validate::strict() && !validate::crc_ok(&raw, Dir3BlkHdr::CRC_OFFSET) {
+                    validate::failure(format_args!("Block directory has a bad CRC"))?;
+                }
+                Dir3DataHdr::SIZE as usize
+            }
+            _ => {
+                error!("Unknown magic number for block directory {:#x}", magic);
+                return Err(libc::EIO);
+            }
+        };
+        assert_eq!(data_offset, self.data_offset);
+        raw.truncate(self.data_len);
+        Ok(self.raw.get_or_init(|| raw.into()))
     }
 
     fn get_addresses(&self, hash: XfsDahash) -> impl Iterator<Item = usize> + '_ {
@@ -156,19 +216,56 @@ impl Dir2Block {
 }
 
 impl Dir3 for Dir2Block {
-    fn lookup<R: Reader + BufRead + Seek>(
+    fn cache_bytes(&self) -> usize {
+        self.ents.len() * mem::size_of::<Dir2LeafEntry>()
+            + self.raw.get().map_or(0, |raw| raw.len())
+    }
+
+    fn lookup_entry<R: Reader + BufRead + Seek>(
         &self,
-        _buf_reader: &mut R,
-        _sb: &Sb,
+        buf_reader: &mut R,
+        sb: &Sb,
         name: &OsStr,
-    ) -> Result<u64, c_int> {
+    ) -> Result<(XfsIno, Option<u8>), c_int> {
+        check_name(name)?;
         let hash = hashname(name);
+        if validate::strict() {
+            // Checking the CRC requires the whole block anyway.
+            self.data(buf_reader, sb)?;
+        }
 
         for offset in self.get_addresses(hash) {
-            assert!(offset < self.raw.len());
-            let entry: Dir2DataEntry = decode(&self.raw[offset..]).unwrap().0;
-            if entry.name == name {
-                return Ok(entry.inumber);
+            if offset < self.data_offset || offset >= self.data_len {
+                validate::failure(format_args!(
+                    "Block directory leaf entry has invalid address {:#x}",
+                    offset
+                ))?;
+                return Err(libc::EIO);
+            }
+            // Unless the data region has already been read, read just the candidate's inode
+            // number, name length, name, and file type.
+            let mut buf = [0u8; 10 + u8::MAX as usize];
+            let entry = match self.raw.get() {
+                Some(raw) => &raw[offset..],
+                None => {
+                    let len = buf.len().min(self.data_len - offset);
+                    read_dirblock(buf_reader, sb, &self.bmx, offset, &mut buf[..len])?;
+                    &buf[..len]
+                }
+            };
+            let ftype_len = usize::from(sb.has_ftype());
+            if entry.len() < 9 || entry.len() < 9 + usize::from(entry[8]) + ftype_len {
+                validate::failure(format_args!(
+                    "Block directory entry at {:#x} overlaps the leaf",
+                    offset
+                ))?;
+                return Err(libc::EIO);
+            }
+            let inumber = u64::from_be_bytes(entry[0..8].try_into().unwrap());
+            let namelen = usize::from(entry[8]);
+            if &entry[9..9 + namelen] == name.as_bytes() {
+                let ftype = sb.has_ftype().then(|| entry[9 + namelen]);
+                return Ok((inumber, ftype));
             }
         }
         Err(libc::ENOENT)
@@ -177,38 +274,485 @@ impl Dir3 for Dir2Block {
     /// Read the next dirent from a Directory
     fn next<R: Reader + BufRead + Seek>(
         &self,
-        _buf_reader: &mut R,
+        buf_reader: &mut R,
         sb: &Sb,
         offset: i64,
     ) -> Result<(XfsIno, i64, Option<FileType>, OsString), c_int> {
-        let mut offset: usize = offset.try_into().unwrap();
-        assert!(offset < self.raw.len());
-        let mut next = offset == 0;
-
-        if offset == 0 {
-            offset += self.data_offset;
-        }
+        let raw = self.data(buf_reader, sb)?;
+        // The offset is usually 0, or the one that we returned along with the previous entry,
+        // which is where the following entry begins.  A negative one is a cookie beyond i64::MAX.
+        let mut offset = match usize::try_from(offset) {
+            Ok(0) => self.data_offset,
+            Ok(offset) if offset < raw.len() => resume_offset(raw, sb, self.data_offset, offset),
+            _ => return Err(ENOENT),
+        };
 
-        while offset < self.raw.len() {
-            let freetag: u16 = decode(&self.raw[offset..]).unwrap().0;
+        while offset < raw.len() {
+            let freetag: u16 = decode(&raw[offset..]).unwrap().0;
             if freetag == 0xffff {
-                let (_, length) = decode::<Dir2DataUnused>(&self.raw[offset..]).unwrap();
+                let (_, length) = decode::<Dir2DataUnused>(&raw[offset..]).unwrap();
                 offset += length;
-            } else if !next {
-                let length = Dir2DataEntry::get_length(sb, &self.raw[offset..]);
-                offset += length as usize;
-                next = true;
             } else {
-                let (entry, _l) = decode::<Dir2DataEntry>(&self.raw[offset..]).unwrap();
+                let (entry, length) = Dir2DataEntry::decode(&raw[offset..], sb).unwrap();
+                debug_assert_eq!(usize::from(entry.tag), offset);
+                if entry.name.is_empty() {
+                    // The kernel would reject it, and nobody could look it up anyway
+                    validate::failure(format_args!(
+                        "Block directory entry at offset {} has an empty name",
+                        offset
+                    ))?;
+                    offset += length;
+                    continue;
+                }
                 let kind = match entry.ftype {
                     Some(ftype) => Some(get_file_type(FileKind::Type(ftype))?),
                     None => None,
                 };
-                let name = entry.name;
-                let entry_offset = entry.tag as u64;
-                return Ok((entry.inumber, entry_offset as i64, kind, name));
+                let next_offset = offset + length;
+                return Ok((entry.inumber, next_offset as i64, kind, entry.name));
             }
         }
         Err(ENOENT)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufReader, Cursor},
+        sync::Arc,
+    };
+
+    use rstest::rstest;
+
+    use super::*;
+    use crate::libxfuse::{
+        bmbt_rec::{tests::superblock, BmbtRec},
+        dir3::ENTRY_DECODES,
+        test_util::CountingReader,
+        validate::Validator,
+    };
+
+    const DIRBLKLOG: u8 = 1;
+
+    fn sb() -> Sb {
+        let mut sb = superblock().clone();
+        sb.sb_dirblklog = DIRBLKLOG;
+        sb
+    }
+
+    /// Build a block directory, without file types.  On V5 it has a valid CRC.
+    fn block_dir(sb: &Sb, entries: &[(String, u64)]) -> Vec<u8> {
+        let size = (sb.sb_blocksize as usize) << sb.sb_dirblklog;
+        let mut raw = vec![0u8; size];
+        let v5 = sb.version() >= 5;
+        let magic = if v5 {
+            XFS_DIR3_BLOCK_MAGIC
+        } else {
+            XFS_DIR2_BLOCK_MAGIC
+        };
+        raw[0..4].copy_from_slice(&magic.to_be_bytes());
+        let mut ofs = if v5 {
+            Dir3DataHdr::SIZE as usize
+        } else {
+            Dir2DataHdr::SIZE as usize
+        };
+        let mut leaf = Vec::new();
+        for (name, ino) in entries {
+            let len = (name.len() + 18) / 8 * 8;
+            raw[ofs..ofs + 8].copy_from_slice(&ino.to_be_bytes());
+            raw[ofs + 8] = name.len() as u8;
+            raw[ofs + 9..ofs + 9 + name.len()].copy_from_slice(name.as_bytes());
+            raw[ofs + len - 2..ofs + len].copy_from_slice(&(ofs as u16).to_be_bytes());
+            leaf.push((hashname(OsStr::new(name)), (ofs >> 3) as u32));
+            ofs += len;
+        }
+        leaf.sort();
+        let tail_ofs = size - Dir2BlockTail::SIZE;
+        let leaf_ofs = tail_ofs - Dir2LeafEntry::SIZE * leaf.len();
+        let unused = leaf_ofs - ofs;
+        raw[ofs..ofs + 2].copy_from_slice(&0xffffu16.to_be_bytes());
+        raw[ofs + 2..ofs + 4].copy_from_slice(&(unused as u16).to_be_bytes());
+        raw[leaf_ofs - 2..leaf_ofs].copy_from_slice(&(ofs as u16).to_be_bytes());
+        for (i, (hashval, address)) in leaf.iter().enumerate() {
+            let o = leaf_ofs + i * Dir2LeafEntry::SIZE;
+            raw[o..o + 4].copy_from_slice(&hashval.to_be_bytes());
+            raw[o + 4..o + 8].copy_from_slice(&address.to_be_bytes());
+        }
+        raw[tail_ofs..tail_ofs + 4].copy_from_slice(&(leaf.len() as u32).to_be_bytes());
+        if v5 {
+            validate::set_crc(&mut raw, Dir3BlkHdr::CRC_OFFSET);
+        }
+        raw
+    }
+
+    /// Build an image containing a chain of block directories, each split across two extents.
+    /// Directory `i` contains many files, plus a subdirectory named "d" whose "inode number" is
+    /// `i + 1`.
+    fn dir_chain(sb: &Sb, depth: usize) -> (Vec<Bmx>, Vec<u8>) {
+        let blocksize = sb.sb_blocksize as usize;
+        let mut image = vec![0u8; 10 * depth * blocksize];
+        let mut bmxs = Vec::new();
+        for i in 0..depth {
+            let mut entries = (0..100)
+                .map(|j| (format!("file{j}"), 1000 + j))
+                .collect::<Vec<_>>();
+            entries.push(("d".to_string(), i as u64 + 1));
+            let raw = block_dir(sb, &entries);
+            let fsblocks = [10 * i as u64 + 2, 10 * i as u64 + 7];
+            for (chunk, fsblock) in raw.chunks(blocksize).zip(fsblocks) {
+                let ofs = sb.fsb_to_offset(fsblock) as usize;
+                image[ofs..ofs + blocksize].copy_from_slice(chunk);
+            }
+            let recs = fsblocks
+                .iter()
+                .enumerate()
+                .map(|(dblock, fsblock)| BmbtRec {
+                    br_startoff:   dblock as u64,
+                    br_startblock: *fsblock,
+                    br_blockcount: 1,
+                    br_flag:       false,
+                })
+                .collect::<Vec<_>>();
+            bmxs.push(Bmx::new(&recs));
+        }
+        (bmxs, image)
+    }
+
+    /// Resolving a path through block directories should read only a small part of each one.
+    #[test]
+    fn lookup_is_lazy() {
+        const DEPTH: usize = 8;
+        let sb = sb();
+        let (bmxs, image) = dir_chain(&sb, DEPTH);
+        let (cr, counts) = CountingReader::new(image);
+        let mut br = BufReader::with_capacity(512, cr);
+
+        let mut ino = 0;
+        while (ino as usize) < DEPTH {
+            let dir = Dir2Block::new(&mut br, &sb, &bmxs[ino as usize]).unwrap();
+            ino = dir.lookup(&mut br, &sb, OsStr::new("d")).unwrap();
+        }
+        assert_eq!(ino, DEPTH as u64);
+        let dirblksize = (sb.sb_blocksize as usize) << DIRBLKLOG;
+        assert!(
+            counts.bytes.get() < DEPTH * dirblksize / 2,
+            "Read {} bytes",
+            counts.bytes.get()
+        );
+    }
+
+    #[test]
+    fn lookup_enoent() {
+        let sb = sb();
+        let (bmxs, image) = dir_chain(&sb, 1);
+        let mut br = BufReader::new(Cursor::new(image));
+        let dir = Dir2Block::new(&mut br, &sb, &bmxs[0]).unwrap();
+        assert_eq!(dir.lookup(&mut br, &sb, OsStr::new("e")), Err(libc::ENOENT));
+        assert_eq!(dir.lookup(&mut br, &sb, OsStr::new("file42")), Ok(1042));
+    }
+
+    /// A damaged leaf count that would overlap the block's header is EIO, rather than a panic
+    #[rstest]
+    #[case::header(1022)]
+    #[case::max(u32::MAX)]
+    fn oversized_count(#[case] count: u32) {
+        let sb = sb();
+        let (bmxs, mut image) = dir_chain(&sb, 1);
+        let tail = sb.fsb_to_offset(7) as usize + sb.sb_blocksize as usize - Dir2BlockTail::SIZE;
+        image[tail..tail + 4].copy_from_slice(&count.to_be_bytes());
+        let mut br = BufReader::new(Cursor::new(image));
+        assert_eq!(
+            Dir2Block::new(&mut br, &sb, &bmxs[0]).err(),
+            Some(libc::EIO)
+        );
+    }
+
+    /// Listing a directory reads its whole data region, after which lookups use it too.
+    #[test]
+    fn next() {
+        let sb = sb();
+        let (bmxs, image) = dir_chain(&sb, 1);
+        let mut br = BufReader::new(Cursor::new(image));
+        let dir = Dir2Block::new(&mut br, &sb, &bmxs[0]).unwrap();
+
+        let mut names = Vec::new();
+        let mut offset = 0;
+        while let Ok((ino, next_offset, _, name)) = dir.next(&mut br, &sb, offset) {
+            names.push((name.into_string().unwrap(), ino));
+            offset = next_offset;
+        }
+        assert_eq!(names.len(), 101);
+        assert_eq!(names[0], ("file0".to_string(), 1000));
+        assert_eq!(names[100], ("d".to_string(), 1));
+        assert!(dir.raw.get().is_some());
+        assert_eq!(dir.lookup(&mut br, &sb, OsStr::new("file7")), Ok(1007));
+    }
+
+    /// With 64k blocks, a block directory's entries' offsets and tags use nearly all of their 16
+    /// bits.
+    #[test]
+    fn blocksize_64k() {
+        let mut sb = superblock().clone();
+        sb.sb_blocksize = 65536;
+        sb.sb_blocklog = 16;
+        sb.sb_dirblklog = 0;
+        // Each entry takes 24 bytes, plus 8 in the leaf
+        let entries = (0..2000)
+            .map(|j| (format!("f{j:05}"), 1000 + j))
+            .collect::<Vec<_>>();
+        let mut image = vec![0u8; 65536];
+        image.extend(block_dir(&sb, &entries));
+        let bmx = Bmx::new(&[BmbtRec {
+            br_startoff:   0,
+            br_startblock: 1,
+            br_blockcount: 1,
+            br_flag:       false,
+        }]);
+        let mut br = BufReader::new(Cursor::new(image));
+        let dir = Dir2Block::new(&mut br, &sb, &bmx).unwrap();
+
+        let mut count = 0;
+        let mut offset = 0;
+        while let Ok((ino, next_offset, _, name)) = dir.next(&mut br, &sb, offset) {
+            assert_eq!(name.into_string().unwrap(), format!("f{:05}", ino - 1000));
+            count += 1;
+            offset = next_offset;
+        }
+        assert_eq!(count, 2000);
+        assert!(offset > 32768, "offset {offset}");
+        assert_eq!(dir.lookup(&mut br, &sb, OsStr::new("f01999")), Ok(2999));
+    }
+
+    /// A stale offset, such as an NFS client's cookie from before the directory changed,
+    /// resumes at the next entry after it, or reports the end.
+    #[rstest]
+    #[case::exact(16, Ok("file1"))]
+    #[case::mid_entry(19, Ok("file2"))]
+    #[case::unaligned(1, Ok("file1"))]
+    #[case::unused_region(5000, Err(libc::ENOENT))]
+    #[case::past_data(1 << 20, Err(libc::ENOENT))]
+    #[case::u32_max(u32::MAX.into(), Err(libc::ENOENT))]
+    #[case::negative(-1, Err(libc::ENOENT))]
+    #[case::i64_min(i64::MIN, Err(libc::ENOENT))]
+    fn next_stale_offset(#[case] after_hdr: i64, #[case] expected: Result<&str, c_int>) {
+        let sb = sb();
+        let (bmxs, image) = dir_chain(&sb, 1);
+        let mut br = BufReader::new(Cursor::new(image));
+        let dir = Dir2Block::new(&mut br, &sb, &bmxs[0]).unwrap();
+        let offset = if after_hdr > 0 {
+            after_hdr.saturating_add(dir.data_offset as i64)
+        } else {
+            after_hdr
+        };
+        let name = dir
+            .next(&mut br, &sb, offset)
+            .map(|(_, _, _, name)| name.into_string().unwrap());
+        assert_eq!(name, expected.map(str::to_string));
+    }
+
+    /// With dirblklog > 0, a directory block spans several file system blocks, which needn't be
+    /// contiguous.  Entries in the last one, just before the leaf, must be found too.
+    #[test]
+    fn multi_fsblock() {
+        let mut sb = sb();
+        sb.sb_dirblklog = 2;
+        let blocksize = sb.sb_blocksize as usize;
+        // 60 entries of 264 bytes each nearly fill a 16 KiB directory block
+        let entries = (0..60)
+            .map(|j| (format!("{:0>250}", j), 1000 + j))
+            .collect::<Vec<_>>();
+        let raw = block_dir(&sb, &entries);
+        let fsblocks = [20u64, 14, 8, 2];
+        let mut image = vec![0u8; 21 * blocksize];
+        let mut recs = Vec::new();
+        for (dblock, (chunk, fsblock)) in raw.chunks(blocksize).zip(fsblocks).enumerate() {
+            let ofs = sb.fsb_to_offset(fsblock) as usize;
+            image[ofs..ofs + blocksize].copy_from_slice(chunk);
+            recs.push(BmbtRec {
+                br_startoff:   dblock as u64,
+                br_startblock: fsblock,
+                br_blockcount: 1,
+                br_flag:       false,
+            });
+        }
+        let bmx = Bmx::new(&recs);
+        let mut br = BufReader::new(Cursor::new(image));
+        let dir = Dir2Block::new(&mut br, &sb, &bmx).unwrap();
+
+        for (name, ino) in entries.iter() {
+            assert_eq!(dir.lookup(&mut br, &sb, OsStr::new(name)), Ok(*ino));
+        }
+
+        let mut names = Vec::new();
+        let mut offset = 0;
+        while let Ok((ino, next_offset, _, name)) = dir.next(&mut br, &sb, offset) {
+            names.push((name.into_string().unwrap(), ino));
+            offset = next_offset;
+        }
+        assert_eq!(names, entries);
+        // The last entry really is in the last file system block
+        assert!(offset as usize > 3 * blocksize + 264);
+
+        for (name, ino) in entries.iter() {
+            assert_eq!(dir.lookup(&mut br, &sb, OsStr::new(name)), Ok(*ino));
+        }
+    }
+
+    /// A hole in the directory block is corruption, whether it's where the tail and leaf are or
+    /// only in the data region
+    #[rstest]
+    #[case::tail(&[0, 1, 2])]
+    #[case::data(&[1, 2, 3])]
+    fn hole(#[case] mapped: &[u64]) {
+        let mut sb = sb();
+        sb.sb_dirblklog = 2;
+        let blocksize = sb.sb_blocksize as usize;
+        let raw = block_dir(&sb, &[("a".to_string(), 1000)]);
+        let mut image = vec![0u8; 8 * blocksize];
+        let mut recs = Vec::new();
+        for &dblock in mapped {
+            let ofs = (4 + dblock as usize) * blocksize;
+            let chunk = &raw[dblock as usize * blocksize..][..blocksize];
+            image[ofs..ofs + blocksize].copy_from_slice(chunk);
+            recs.push(BmbtRec {
+                br_startoff:   dblock,
+                br_startblock: 4 + dblock,
+                br_blockcount: 1,
+                br_flag:       false,
+            });
+        }
+        let bmx = Bmx::new(&recs);
+        let mut br = BufReader::new(Cursor::new(image));
+
+        let validator = Arc::new(Validator::new(validate::Config::default()));
+        validator.enter();
+        let r = Dir2Block::new(&mut br, &sb, &bmx)
+            .and_then(|dir| dir.lookup(&mut br, &sb, OsStr::new("a")));
+        assert_eq!(r, Err(libc::EIO));
+        assert_eq!(validator.failures(), 1);
+    }
+
+    /// A leaf entry pointing outside of the data region is corruption
+    #[test]
+    fn lookup_bad_address() {
+        let sb = sb();
+        let (bmxs, mut image) = dir_chain(&sb, 1);
+        let blocksize = sb.sb_blocksize as usize;
+        // Point every leaf entry at the tail
+        let second = sb.fsb_to_offset(7) as usize;
+        let tail = second + blocksize - Dir2BlockTail::SIZE;
+        let count = u32::from_be_bytes(image[tail..tail + 4].try_into().unwrap()) as usize;
+        for i in 0..count {
+            let o = tail - (i + 1) * Dir2LeafEntry::SIZE + 4;
+            image[o..o + 4].copy_from_slice(&(((2 * blocksize) >> 3) as u32 - 1).to_be_bytes());
+        }
+        let mut br = BufReader::new(Cursor::new(image));
+        let dir = Dir2Block::new(&mut br, &sb, &bmxs[0]).unwrap();
+        assert_eq!(dir.lookup(&mut br, &sb, OsStr::new("d")), Err(libc::EIO));
+    }
+
+    /// A corrupt data header should fail readdir with EIO, not end the listing early.
+    #[test]
+    fn next_bad_magic() {
+        let sb = sb();
+        let (bmxs, mut image) = dir_chain(&sb, 1);
+        image[sb.fsb_to_offset(2) as usize] ^= 0xff;
+        let mut br = BufReader::new(Cursor::new(image));
+        let dir = Dir2Block::new(&mut br, &sb, &bmxs[0]).unwrap();
+        assert_eq!(dir.next(&mut br, &sb, 0), Err(libc::EIO));
+    }
+
+    /// A full readdir pass should decode each entry exactly once.
+    #[test]
+    fn next_decodes_once() {
+        let sb = sb();
+        let (bmxs, image) = dir_chain(&sb, 1);
+        let mut br = BufReader::new(Cursor::new(image));
+        let dir = Dir2Block::new(&mut br, &sb, &bmxs[0]).unwrap();
+
+        ENTRY_DECODES.with(|n| n.set(0));
+        let mut count = 0;
+        let mut offset = 0;
+        while let Ok((_, next_offset, _, _)) = dir.next(&mut br, &sb, offset) {
+            assert!(next_offset > offset);
+            count += 1;
+            offset = next_offset;
+        }
+        assert_eq!(count, 101);
+        assert_eq!(ENTRY_DECODES.with(|n| n.get()), count);
+    }
+
+    /// An entry with an empty name is corrupt.  readdir skips it, counting one failure, unless
+    /// strict, where it's EIO.
+    #[rstest]
+    fn next_empty_name(#[values(false, true)] strict: bool) {
+        let sb = sb();
+        let entries = [("a", 1000), ("", 1001), ("b", 1002)]
+            .map(|(name, ino)| (name.to_string(), ino))
+            .to_vec();
+        let mut image = vec![0u8; sb.sb_blocksize as usize];
+        image.extend(block_dir(&sb, &entries));
+        let bmx = Bmx::new(&[BmbtRec {
+            br_startoff:   0,
+            br_startblock: 1,
+            br_blockcount: 1 << DIRBLKLOG,
+            br_flag:       false,
+        }]);
+        let mut br = BufReader::new(Cursor::new(image));
+        let dir = Dir2Block::new(&mut br, &sb, &bmx).unwrap();
+
+        let validator = Arc::new(Validator::new(validate::Config { strict }));
+        validator.enter();
+        let mut names = Vec::new();
+        let mut offset = 0;
+        let r = loop {
+            match dir.next(&mut br, &sb, offset) {
+                Ok((ino, next_offset, _, name)) => {
+                    names.push((name.into_string().unwrap(), ino));
+                    offset = next_offset;
+                }
+                Err(e) => break e,
+            }
+        };
+        assert_eq!(validator.failures(), 1);
+        if strict {
+            assert_eq!(r, libc::EIO);
+            assert_eq!(names, [("a".to_string(), 1000)]);
+        } else {
+            assert_eq!(r, libc::ENOENT);
+            assert_eq!(names, [("a".to_string(), 1000), ("b".to_string(), 1002)]);
+        }
+    }
+
+    /// A V5 block directory's CRC is only checked in strict mode, where a bad one is EIO.
+    #[rstest]
+    #[case::lenient(false, true, Ok(1000))]
+    #[case::strict_good(true, false, Ok(1000))]
+    #[case::strict_bad(true, true, Err(libc::EIO))]
+    fn crc(#[case] strict: bool, #[case] corrupt: bool, #[case] expected: Result<u64, c_int>) {
+        let mut sb = sb();
+        sb.set_version(5);
+        let blocksize = sb.sb_blocksize as usize;
+        let mut raw = block_dir(&sb, &[("file0".to_string(), 1000)]);
+        if corrupt {
+            // Damage the best free table, which lookups never read
+            raw[Dir3BlkHdr::SIZE as usize] ^= 0x10;
+        }
+        let mut image = vec![0u8; blocksize];
+        image.extend_from_slice(&raw);
+        let bmx = Bmx::new(&[BmbtRec {
+            br_startoff:   0,
+            br_startblock: 1,
+            br_blockcount: 1 << DIRBLKLOG,
+            br_flag:       false,
+        }]);
+        let mut br = BufReader::new(Cursor::new(image));
+
+        let validator = Arc::new(Validator::new(validate::Config { strict }));
+        validator.enter();
+        let dir = Dir2Block::new(&mut br, &sb, &bmx).unwrap();
+        assert_eq!(dir.lookup(&mut br, &sb, OsStr::new("file0")), expected);
+        assert_eq!(validator.failures(), u64::from(expected.is_err()));
+    }
+}
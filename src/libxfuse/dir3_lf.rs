@@ -26,31 +26,40 @@
  * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
  */
 use std::{
-    cell::{Ref, RefCell},
-    collections::{btree_map::Entry, BTreeMap},
+    cell::{Cell, RefCell},
     ffi::{OsStr, OsString},
     io::{BufRead, Seek, SeekFrom},
     ops::{Deref, Range},
+    sync::Arc,
 };
 
-use bincode::{
-    de::{read::Reader, Decoder},
-    error::DecodeError,
-    Decode,
-};
-use fuser::FileType;
+use bincode::{de::read::Reader, error::DecodeError, Decode};
 use libc::c_int;
-use tracing::error;
+use tracing::{error, warn};
 
 use super::{
     bmbt_rec::Bmx,
     btree::{BmbtKey, BmdrBlock, Btree, BtreeRoot, XfsBmbtPtr},
-    da_btree::{hashname, XfsDa3Blkinfo, XfsDa3Intnode, XfsDaBlkinfo},
+    cache_budget::LruMap,
+    da_btree::{check_uuid, hashname, XfsDa3Blkinfo, XfsDa3Intnode, XfsDaBlkinfo},
     definitions::*,
-    dir3::{Dir2DataEntry, Dir2DataHdr, Dir2DataUnused, Dir3, Dir3DataHdr, XfsDir2Dataptr},
+    dir3::{
+        check_name,
+        resume_offset,
+        Dir2DataEntry,
+        Dir2DataHdr,
+        Dir2DataUnused,
+        Dir2LeafEntry,
+        Dir3,
+        Dir3BlkHdr,
+        Dir3DataHdr,
+        XfsDir2Dataptr,
+    },
     sb::Sb,
-    utils::{decode, get_file_type, FileKind},
-    volume::SUPERBLOCK,
+    stat::FileType,
+    stats::{self, Event},
+    utils::{check_count, decode, decode_array, get_file_type, FileKind},
+    validate,
 };
 
 /// All of the different ways that a directory can store its data fork.
@@ -63,29 +72,51 @@ enum Dfork {
     Btree(BtreeRoot),
 }
 
+#[cfg(test)]
+thread_local! {
+    /// The number of [`Dfork`] lookups performed by this thread, for unit tests
+    static DFORK_LOOKUPS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
 impl Dfork {
-    fn lseek<R>(&self, buf_reader: &mut R, offset: u64, whence: i32) -> Result<u64, i32>
+    fn lseek<R>(&self, buf_reader: &mut R, sb: &Sb, offset: u64, whence: i32) -> Result<u64, i32>
     where
         R: BufRead + Reader + Seek,
     {
+        #[cfg(test)]
+        DFORK_LOOKUPS.with(|n| n.set(n.get() + 1));
         match self {
-            Dfork::Bmx(bmx) => bmx.lseek(offset, whence),
-            Dfork::Btree(btree_root) => btree_root.lseek(buf_reader, offset, whence),
+            Dfork::Bmx(bmx) => bmx.lseek(sb, offset, whence),
+            Dfork::Btree(btree_root) => btree_root.lseek(buf_reader, sb, offset, whence),
         }
     }
 
     fn map_dblock<R: Reader + BufRead + Seek>(
         &self,
         buf_reader: &mut R,
+        sb: &Sb,
         dblock: XfsDablk,
     ) -> Result<XfsFsblock, i32> {
-        match self {
-            Dfork::Bmx(bmx) => bmx.map_dblock(dblock).ok_or(libc::ENOENT),
-            Dfork::Btree(root) => root
-                .map_block(buf_reader, dblock.into())?
-                .0
-                .ok_or(libc::ENOENT),
-        }
+        self.map_extent(buf_reader, sb, dblock.into())
+            .map(|(fsblock, _)| fsblock)
+    }
+
+    /// Map a file system block within the directory to the disk, and return how many blocks,
+    /// starting with it, are contiguous there.
+    fn map_extent<R: Reader + BufRead + Seek>(
+        &self,
+        buf_reader: &mut R,
+        sb: &Sb,
+        fileoff: XfsFileoff,
+    ) -> Result<(XfsFsblock, u64), i32> {
+        #[cfg(test)]
+        DFORK_LOOKUPS.with(|n| n.set(n.get() + 1));
+        let (mapping, len) = match self {
+            Dfork::Bmx(bmx) => bmx.get_extent(fileoff),
+            Dfork::Btree(root) => root.map_block(buf_reader, sb, fileoff)?,
+        };
+        // Only a hole's length may be unknown
+        mapping.fsblock().zip(len).ok_or(libc::ENOENT)
     }
 }
 
@@ -104,19 +135,39 @@ struct Dir3LeafHdr {
     _pad:      u32,
 }
 
-#[derive(Clone, Copy, Debug, Decode, Default)]
-struct Dir2LeafEntry {
-    pub hashval: XfsDahash,
-    pub address: XfsDir2Dataptr,
-}
-
 #[derive(Debug)]
-struct Dir2LeafNDisk {
+pub struct Dir2LeafNDisk {
     forw:     u32,
     pub ents: Vec<Dir2LeafEntry>,
 }
 
 impl Dir2LeafNDisk {
+    /// Decode a leaf from a whole directory block.
+    pub fn from_block(raw: &[u8]) -> Result<Self, DecodeError> {
+        let magic: u16 = decode(raw.get(8..).unwrap_or_default())?.0;
+        let (count, forw, hdrlen) = match magic {
+            XFS_DIR2_LEAF1_MAGIC | XFS_DIR2_LEAFN_MAGIC => {
+                let (hdr, hdrlen) = decode::<Dir2LeafHdr>(raw)?;
+                (hdr.count, hdr.info.forw, hdrlen)
+            }
+            XFS_DIR3_LEAF1_MAGIC | XFS_DIR3_LEAFN_MAGIC => {
+                let (hdr, hdrlen) = decode::<Dir3LeafHdr>(raw)?;
+                (hdr.count, hdr.info.forw, hdrlen)
+            }
+            _ => {
+                return Err(DecodeError::OtherString(format!(
+                    "Unexpected magic {:#x} in directory leaf block",
+                    magic
+                )))
+            }
+        };
+        let count = usize::from(count);
+        check_count::<Dir2LeafEntry>("Directory leaf block", count, raw.len() - hdrlen)?;
+        let ents = decode_array(&raw[hdrlen..], count)?;
+
+        Ok(Dir2LeafNDisk { forw, ents })
+    }
+
     /// Return the range of entry indices that include the given hash
     pub fn get_address_range(&self, hash: XfsDahash) -> Range<usize> {
         let l = self.ents.len();
@@ -126,28 +177,32 @@ impl Dir2LeafNDisk {
     }
 }
 
-impl Decode for Dir2LeafNDisk {
-    fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError> {
-        let magic: u16 = decode(&decoder.reader().peek_read(10).unwrap()[8..])?.0;
-        let (count, forw) = match magic {
-            XFS_DIR2_LEAF1_MAGIC | XFS_DIR2_LEAFN_MAGIC => {
-                let hdr: Dir2LeafHdr = Decode::decode(decoder)?;
-                (hdr.count, hdr.info.forw)
-            }
-            XFS_DIR3_LEAF1_MAGIC | XFS_DIR3_LEAFN_MAGIC => {
-                let hdr: Dir3LeafHdr = Decode::decode(decoder)?;
-                (hdr.count, hdr.info.forw)
-            }
-            _ => panic!("Unexpected magic {:#x}", magic),
-        };
-        let mut ents = Vec::<Dir2LeafEntry>::new();
-        for _i in 0..count {
-            let leaf_entry: Dir2LeafEntry = Decode::decode(decoder)?;
-            ents.push(leaf_entry);
-        }
+/// Log a directory leaf or node block that can't be decoded, and return the errno for it
+fn leaf_error(dblock: XfsDablk, e: DecodeError) -> i32 {
+    error!("Cannot decode directory block {}: {}", dblock, e);
+    libc::EIO
+}
 
-        Ok(Dir2LeafNDisk { forw, ents })
+/// Is this one of a Node directory's freeindex blocks?  Unlike leaves and nodes, they begin with
+/// a 32-bit magic number.
+fn is_freeindex(raw: &[u8]) -> bool {
+    matches!(
+        decode::<u32>(raw),
+        Ok((XFS_DIR2_FREE_MAGIC | XFS_DIR3_FREE_MAGIC, _))
+    )
+}
+
+/// Decode the leaf block that a node or sibling pointer led to.  In a damaged directory, that may
+/// be a freeindex block instead.  It holds no hashes, so the search ends there without a match.
+fn decode_leaf(dblock: XfsDablk, raw: &[u8]) -> Result<Dir2LeafNDisk, i32> {
+    if is_freeindex(raw) {
+        warn!(
+            "Directory block {} is a freeindex block, not a leaf",
+            dblock
+        );
+        return Err(libc::ENOENT);
     }
+    Dir2LeafNDisk::from_block(raw).map_err(|e| leaf_error(dblock, e))
 }
 
 /// Stores the "leaf" info (the hash => address map) for Leaf, Node, and Btree directories.  But
@@ -160,30 +215,14 @@ enum Leaf {
 }
 
 impl Leaf {
-    fn open(raw: &[u8]) -> Self {
-        let magic: u16 = decode(&raw[8..]).unwrap().0;
-        let config = bincode::config::standard()
-            .with_big_endian()
-            .with_fixed_int_encoding();
-        let reader = bincode::de::read::SliceReader::new(raw);
-        let mut decoder = bincode::de::DecoderImpl::new(reader, config);
+    /// Open the directory's first leaf block, `dblock`.
+    fn open(dblock: XfsDablk, raw: &[u8]) -> Result<Self, i32> {
+        let magic: u16 = decode(&raw[8..]).map_err(|e| leaf_error(dblock, e))?.0;
         match magic {
-            XFS_DA_NODE_MAGIC | XFS_DA3_NODE_MAGIC => {
-                let leaf_btree = XfsDa3Intnode::decode(&mut decoder)
-                    .map_err(|_| libc::EIO)
-                    .unwrap();
-                assert!(
-                    leaf_btree.magic == XFS_DA3_NODE_MAGIC || leaf_btree.magic == XFS_DA_NODE_MAGIC
-                );
-                Self::Btree(leaf_btree)
-            }
-            XFS_DIR2_LEAFN_MAGIC | XFS_DIR3_LEAFN_MAGIC => {
-                Self::LeafN(Dir2LeafNDisk::decode(&mut decoder).unwrap())
-            }
-            XFS_DIR2_LEAF1_MAGIC | XFS_DIR3_LEAF1_MAGIC => {
-                Self::LeafN(Dir2LeafNDisk::decode(&mut decoder).unwrap())
-            }
-            magic => panic!("Bad magic in Leaf block! {:#x}", magic),
+            XFS_DA_NODE_MAGIC | XFS_DA3_NODE_MAGIC => XfsDa3Intnode::from_block(raw)
+                .map(Self::Btree)
+                .map_err(|e| leaf_error(dblock, e)),
+            _ => decode_leaf(dblock, raw).map(Self::LeafN),
         }
     }
 
@@ -202,10 +241,11 @@ impl Leaf {
             Leaf::Btree(btree) => {
                 let dablk: XfsDablk =
                     btree.lookup(buf_reader.by_ref(), sb, hash, |block, br| {
-                        dir.dfork.map_dblock(br, block).unwrap()
+                        dir.dfork.map_dblock(br, sb, block).unwrap()
                     })?;
                 let raw = dir.read_dblock(buf_reader.by_ref(), sb, dablk)?;
-                Ok(decode(&raw).unwrap().0)
+                check_uuid(sb, &raw);
+                decode_leaf(dablk, &raw)
             }
         }
     }
@@ -219,20 +259,22 @@ struct NodeLikeAddressIterator<'a, R: Reader + BufRead + Seek + 'a> {
     leaf:       Dir2LeafNDisk,
     leaf_range: Range<usize>,
     brrc:       &'a RefCell<&'a mut R>,
+    sb:         &'a Sb,
 }
 
 impl<'a, R: Reader + BufRead + Seek + 'a> NodeLikeAddressIterator<'a, R> {
     pub fn new(
         dir: &'a Dir2Lf,
         brrc: &'a RefCell<&'a mut R>,
+        sb: &'a Sb,
         hash: XfsDahash,
     ) -> Result<Self, i32> {
-        let sb = SUPERBLOCK.get().unwrap();
         let dblock = sb.get_dir3_leaf_offset();
         let mut buf_reader = brrc.borrow_mut();
         let leaf_btree = {
             let raw = dir.read_dblock(buf_reader.by_ref(), sb, dblock)?;
-            Leaf::open(raw.deref())
+            check_uuid(sb, &raw);
+            Leaf::open(dblock, raw.deref())?
         };
         let leaf = leaf_btree.lookup_leaf_blk(buf_reader.by_ref(), sb, dir, hash)?;
 
@@ -244,6 +286,7 @@ impl<'a, R: Reader + BufRead + Seek + 'a> NodeLikeAddressIterator<'a, R> {
             leaf,
             leaf_range,
             brrc,
+            sb,
         })
     }
 }
@@ -267,8 +310,7 @@ impl<'a, R: Reader + BufRead + Seek + 'a> Iterator for NodeLikeAddressIterator<'
                     // Traverse the forw pointer
                     let forw = self.leaf.forw;
                     let mut buf_reader = self.brrc.borrow_mut();
-                    let sb = SUPERBLOCK.get().unwrap();
-                    let raw = match self.dir.read_dblock(buf_reader.by_ref(), sb, forw) {
+                    let raw = match self.dir.read_dblock(buf_reader.by_ref(), self.sb, forw) {
                         Ok(raw) => raw,
                         Err(e) => {
                             // It would be nice to print inode number here
@@ -276,7 +318,11 @@ impl<'a, R: Reader + BufRead + Seek + 'a> Iterator for NodeLikeAddressIterator<'
                             return None;
                         }
                     };
-                    self.leaf = decode(raw.deref()).unwrap().0;
+                    check_uuid(self.sb, &raw);
+                    self.leaf = match decode_leaf(forw, raw.deref()) {
+                        Ok(leaf) => leaf,
+                        Err(_) => return None,
+                    };
                     self.leaf_range = self.leaf.get_address_range(self.hash);
                 } else {
                     return None;
@@ -302,101 +348,167 @@ pub struct Dir2Lf {
     dfork: Dfork,
 
     /// A cache of directory blocks, indexed by directory block number
-    blocks: RefCell<BTreeMap<XfsDablk, Vec<u8>>>,
+    blocks: RefCell<LruMap<XfsDablk, Arc<[u8]>>>,
+
+    /// The total size of the cached blocks
+    cached: Cell<usize>,
+
+    /// The directory's size, from its inode.  That's the end of its data section.
+    size: u64,
 }
 
 impl Dir2Lf {
-    pub fn from_bmx(bmx: Bmx) -> Self {
+    pub fn from_bmx(bmx: Bmx, size: u64) -> Self {
         let dfork = Dfork::Bmx(bmx);
         let blocks = Default::default();
-        Dir2Lf { dfork, blocks }
+        Dir2Lf {
+            dfork,
+            blocks,
+            cached: Cell::new(0),
+            size,
+        }
     }
 
-    pub fn from_btree(bmbt: BmdrBlock, keys: Vec<BmbtKey>, pointers: Vec<XfsBmbtPtr>) -> Self {
+    pub fn from_btree(
+        bmbt: BmdrBlock,
+        keys: Vec<BmbtKey>,
+        pointers: Vec<XfsBmbtPtr>,
+        size: u64,
+    ) -> Self {
         let root = BtreeRoot::new(bmbt, keys, pointers);
         let dfork = Dfork::Btree(root);
         let blocks = Default::default();
-        Dir2Lf { dfork, blocks }
+        Dir2Lf {
+            dfork,
+            blocks,
+            cached: Cell::new(0),
+            size,
+        }
+    }
+
+    /// The offset at which the directory's entries end.  Usually that's its size, which is the end
+    /// of its last data block.  But if the size is implausible, fall back to the start of the leaf
+    /// section.
+    fn data_end(&self, sb: &Sb) -> u64 {
+        let dblksize: u64 = 1 << (sb.sb_blocklog + sb.sb_dirblklog);
+        let leaf_offset = u64::from(sb.get_dir3_leaf_offset()) << sb.sb_blocklog;
+        if self.size % dblksize == 0 && self.size <= leaf_offset {
+            self.size
+        } else {
+            leaf_offset
+        }
     }
 
     fn get_addresses<'a, R>(
         &'a self,
         buf_reader: &'a RefCell<&'a mut R>,
+        sb: &'a Sb,
         hash: XfsDahash,
     ) -> Box<dyn Iterator<Item = XfsDir2Dataptr> + 'a>
     where
         R: Reader + BufRead + Seek + 'a,
     {
-        if let Ok(ai) = NodeLikeAddressIterator::new(self, buf_reader, hash) {
+        if let Ok(ai) = NodeLikeAddressIterator::new(self, buf_reader, sb, hash) {
             Box::new(ai)
         } else {
             Box::new(std::iter::empty())
         }
     }
 
-    fn read_dblock<'a, R>(
-        &'a self,
-        mut buf_reader: R,
-        sb: &Sb,
-        dblock: XfsDablk,
-    ) -> Result<impl Deref<Target = [u8]> + 'a, i32>
+    /// Read a directory block, through the cache.  The cache is never borrowed beyond this call,
+    /// so callers may hold on to any number of blocks while reading others.
+    fn read_dblock<R>(&self, mut buf_reader: R, sb: &Sb, dblock: XfsDablk) -> Result<Arc<[u8]>, i32>
     where
         R: Reader + BufRead + Seek,
     {
-        let mut cache_guard = self.blocks.borrow_mut();
-        let entry = cache_guard.entry(dblock);
-        if matches!(entry, Entry::Vacant(_)) {
-            let fsblock = self.dfork.map_dblock(buf_reader.by_ref(), dblock)?;
-            let buf = self.read_fsblock(buf_reader.by_ref(), sb, fsblock)?;
-            entry.or_insert(buf);
+        if let Some(buf) = self.blocks.borrow_mut().get(&dblock) {
+            stats::count(Event::DirBlockHit);
+            return Ok(buf.clone());
         }
-        // Annoyingly, there's no function to downgrade a RefMut into a Ref.
-        drop(cache_guard);
-        let cache_guard = self.blocks.borrow();
-        Ok(Ref::map(cache_guard, |v| &v[&dblock][..]))
+        stats::count(Event::DirBlockMiss);
+        let buf = self.read_extents(buf_reader.by_ref(), sb, dblock)?;
+        if validate::strict()
+            && buf[0..4] == XFS_DIR3_DATA_MAGIC.to_be_bytes()
+            && !validate::crc_ok(&buf, Dir3BlkHdr::CRC_OFFSET)
+        {
+            validate::failure(format_args!(
+                "Directory data block {} has a bad CRC",
+                dblock
+            ))?;
+        }
+        let buf = Arc::<[u8]>::from(buf);
+        self.cached.set(self.cached.get() + buf.len());
+        self.blocks.borrow_mut().insert(dblock, buf.clone());
+        Ok(buf)
     }
 
+    /// Read a whole directory block from disk.  When it's larger than a file system block, its
+    /// file system blocks needn't be contiguous on disk, so map each extent that it spans.
     // NB: this code could be combined with File::read_sectors.  However, the latter must contend
     // with much larger extents, and with reads of partial sectors.
-    fn read_fsblock<R>(
-        &self,
-        mut buf_reader: R,
-        sb: &Sb,
-        fsblock: XfsFsblock,
-    ) -> Result<Vec<u8>, i32>
+    fn read_extents<R>(&self, mut buf_reader: R, sb: &Sb, dblock: XfsDablk) -> Result<Vec<u8>, i32>
     where
         R: Reader + BufRead + Seek,
     {
         let dblksize: usize = 1 << (sb.sb_blocklog + sb.sb_dirblklog);
 
         let mut buf = vec![0; dblksize];
-        buf_reader
-            .seek(SeekFrom::Start(sb.fsb_to_offset(fsblock)))
-            .unwrap();
-        buf_reader.read_exact(&mut buf).unwrap();
+        let mut done = 0;
+        while done < dblksize {
+            let fileoff = XfsFileoff::from(dblock) + (done >> sb.sb_blocklog) as u64;
+            let (fsblock, len) = match self.dfork.map_extent(buf_reader.by_ref(), sb, fileoff) {
+                Ok(extent) => extent,
+                Err(libc::ENOENT) if done > 0 => {
+                    error!("Directory block {} is partly a hole", dblock);
+                    return Err(libc::EIO);
+                }
+                Err(e) => return Err(e),
+            };
+            let n = (len << sb.sb_blocklog).min((dblksize - done) as u64) as usize;
+            let offset = sb.fsb_to_offset(fsblock);
+            buf_reader
+                .seek(SeekFrom::Start(offset))
+                .and_then(|_| buf_reader.read_exact(&mut buf[done..done + n]))
+                .map_err(|e| {
+                    error!("Cannot read directory block at offset {}: {}", offset, e);
+                    e.raw_os_error().unwrap_or(libc::EIO)
+                })?;
+            done += n;
+        }
         Ok(buf)
     }
 }
 
 impl Dir3 for Dir2Lf {
-    fn lookup<R: Reader + BufRead + Seek>(
+    fn cache_bytes(&self) -> usize {
+        self.cached.get()
+    }
+
+    fn shrink_cache(&self, target: usize) {
+        let excess = self.cached.get().saturating_sub(target);
+        let freed = self.blocks.borrow_mut().evict(excess, |block| block.len());
+        self.cached.set(self.cached.get() - freed);
+    }
+
+    fn lookup_entry<R: Reader + BufRead + Seek>(
         &self,
         buf_reader: &mut R,
         sb: &Sb,
         name: &OsStr,
-    ) -> Result<u64, c_int> {
+    ) -> Result<(XfsIno, Option<u8>), c_int> {
+        check_name(name)?;
         let hash = hashname(name);
 
         let brrc = RefCell::new(buf_reader);
-        for address in self.get_addresses(&brrc, hash) {
+        for address in self.get_addresses(&brrc, sb, hash) {
             let blk_offset =
                 (address & ((1u32 << (sb.sb_dirblklog + sb.sb_blocklog)) - 1)) as usize;
             let dblock = address >> sb.sb_blocklog & !((1u32 << sb.sb_dirblklog) - 1);
             let mut guard = brrc.borrow_mut();
             let raw = self.read_dblock(guard.by_ref(), sb, dblock)?;
-            let entry: Dir2DataEntry = decode(&raw[blk_offset..]).unwrap().0;
+            let entry = Dir2DataEntry::decode(&raw[blk_offset..], sb).unwrap().0;
             if entry.name == name {
-                return Ok(entry.inumber);
+                return Ok((entry.inumber, entry.ftype));
             }
         }
         Err(libc::ENOENT)
@@ -410,62 +522,416 @@ impl Dir3 for Dir2Lf {
     ) -> Result<(XfsIno, i64, Option<FileType>, OsString), c_int> {
         let dblksize: u64 = 1 << (sb.sb_blocklog + sb.sb_dirblklog);
         let dblkmask: u64 = dblksize - 1;
-        let mut offset: u64 = offset.try_into().unwrap();
-        let mut next = offset == 0;
+        // The offset is usually 0, or the one that we returned along with the previous entry,
+        // which is where the following entry begins.  A negative one is a cookie beyond i64::MAX.
+        let Ok(mut offset) = u64::try_from(offset) else {
+            return Err(libc::ENOENT);
+        };
+        let data_end = self.data_end(sb);
+        let dblock_of = |offset: u64| -> XfsDablk {
+            (offset >> sb.sb_blocklog & !((1u64 << sb.sb_dirblklog) - 1))
+                .try_into()
+                .unwrap()
+        };
 
         loop {
-            // Skip any holes in the directory
-            let newoffset = self
-                .dfork
-                .lseek(buf_reader.by_ref(), offset, libc::SEEK_DATA)
-                .map_err(|e| if e == libc::ENXIO { libc::ENOENT } else { e })?;
-            if newoffset >= u64::from(sb.get_dir3_leaf_offset()) << sb.sb_blocklog {
+            // Stop at the end of the data section, without consulting the data fork.  That would
+            // cost a bmap btree walk after every directory's final entry.
+            if offset >= data_end {
                 return Err(libc::ENOENT);
             }
-            offset = newoffset;
+            // Skip any holes in the directory.  A cached block can't be one.
+            if !self.blocks.borrow().contains_key(&dblock_of(offset)) {
+                offset = self
+                    .dfork
+                    .lseek(buf_reader.by_ref(), sb, offset, libc::SEEK_DATA)
+                    .map_err(|e| if e == libc::ENXIO { libc::ENOENT } else { e })?;
+                if offset >= data_end {
+                    return Err(libc::ENOENT);
+                }
+            }
 
             // Byte offset within this directory block
-            let dir_block_offset = offset & ((1 << (sb.sb_dirblklog + sb.sb_blocklog)) - 1);
+            let dir_block_offset = offset & dblkmask;
             // Offset of this directory block within the directory
             let doffset = offset - dir_block_offset;
 
-            let dblock = (offset >> sb.sb_blocklog & !((1u64 << sb.sb_dirblklog) - 1))
-                .try_into()
-                .unwrap();
+            let dblock = dblock_of(offset);
             let raw = self.read_dblock(buf_reader.by_ref(), sb, dblock)?;
 
-            let mut blk_offset = if offset & dblkmask > 0 {
-                (offset & dblkmask) as usize
-            } else {
-                let magic: u32 = decode(&raw[..]).unwrap().0;
-                match magic {
-                    XFS_DIR2_BLOCK_MAGIC | XFS_DIR2_DATA_MAGIC => Dir2DataHdr::SIZE as usize,
-                    XFS_DIR3_BLOCK_MAGIC | XFS_DIR3_DATA_MAGIC => Dir3DataHdr::SIZE as usize,
-                    _ => panic!("Unknown magic number for block directory {:#x}", magic),
+            let magic: u32 = decode(&raw[..]).unwrap().0;
+            let hdr_size = match magic {
+                XFS_DIR2_BLOCK_MAGIC | XFS_DIR2_DATA_MAGIC => Dir2DataHdr::SIZE as usize,
+                XFS_DIR3_BLOCK_MAGIC | XFS_DIR3_DATA_MAGIC => Dir3DataHdr::SIZE as usize,
+                _ => {
+                    error!("Unknown magic number for leaf directory data {:#x}", magic);
+                    return Err(libc::EIO);
                 }
             };
+            let mut blk_offset = resume_offset(&raw, sb, hdr_size, dir_block_offset as usize);
+            offset = doffset + blk_offset as u64;
             while blk_offset < raw.len() {
                 let freetag: u16 = decode(&raw[blk_offset..]).unwrap().0;
                 if freetag == 0xffff {
                     let (_, length) = decode::<Dir2DataUnused>(&raw[blk_offset..]).unwrap();
                     offset += length as u64;
                     blk_offset += length;
-                } else if !next {
-                    let length = Dir2DataEntry::get_length(sb, &raw[blk_offset..]);
-                    blk_offset += length as usize;
-                    offset += length as u64;
-                    next = true;
                 } else {
-                    let (entry, _l) = decode::<Dir2DataEntry>(&raw[blk_offset..]).unwrap();
+                    let (entry, length) = Dir2DataEntry::decode(&raw[blk_offset..], sb).unwrap();
+                    debug_assert_eq!(usize::from(entry.tag), blk_offset);
+                    if entry.name.is_empty() {
+                        // The kernel would reject it, and nobody could look it up anyway
+                        validate::failure(format_args!(
+                            "Directory entry at offset {} has an empty name",
+                            offset
+                        ))?;
+                        offset += length as u64;
+                        blk_offset += length;
+                        continue;
+                    }
                     let kind = match entry.ftype {
                         Some(ftype) => Some(get_file_type(FileKind::Type(ftype))?),
                         None => None,
                     };
-                    let name = entry.name;
-                    let entry_offset = doffset + entry.tag as u64;
-                    return Ok((entry.inumber, entry_offset as i64, kind, name));
+                    let next_offset = doffset + (blk_offset + length) as u64;
+                    return Ok((entry.inumber, next_offset as i64, kind, entry.name));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, Cursor};
+
+    use rstest::rstest;
+
+    use super::*;
+    use crate::libxfuse::{
+        bmbt_rec::{tests::superblock, BmbtRec},
+        utils::FromBytes,
+    };
+
+    const HASH: XfsDahash = 0x1234;
+
+    /// A V4 node directory's leaf block, with the given entries
+    fn leafn(forw: XfsDablk, ents: &[(XfsDahash, XfsDir2Dataptr)]) -> Vec<u8> {
+        let sb = superblock();
+        let mut raw = vec![0u8; sb.sb_blocksize as usize];
+        raw[0..4].copy_from_slice(&forw.to_be_bytes());
+        raw[8..10].copy_from_slice(&XFS_DIR2_LEAFN_MAGIC.to_be_bytes());
+        raw[12..14].copy_from_slice(&(ents.len() as u16).to_be_bytes());
+        for (i, (hashval, address)) in ents.iter().enumerate() {
+            let ofs = 16 + i * Dir2LeafEntry::SIZE;
+            raw[ofs..ofs + 4].copy_from_slice(&hashval.to_be_bytes());
+            raw[ofs + 4..ofs + 8].copy_from_slice(&address.to_be_bytes());
+        }
+        raw
+    }
+
+    /// Entries whose hashes collide may continue into the next leaf block.  Following the forw
+    /// pointer must work even while the caller still holds an earlier directory block.
+    #[test]
+    fn collision_continues_past_held_block() {
+        let sb = superblock();
+        let leaf = sb.get_dir3_leaf_offset();
+        let mut img = vec![0u8; sb.sb_blocksize as usize];
+        img.extend(leafn(leaf + 1, &[(HASH - 1, 1), (HASH, 2)]));
+        img.extend(leafn(0, &[(HASH, 3), (HASH + 1, 4)]));
+        let dir = Dir2Lf::from_bmx(
+            Bmx::new(&[BmbtRec {
+                br_startoff:   leaf.into(),
+                br_startblock: 1,
+                br_blockcount: 2,
+                br_flag:       false,
+            }]),
+            0,
+        );
+        let mut br = BufReader::new(Cursor::new(img));
+
+        let held = dir.read_dblock(&mut br, sb, leaf).unwrap();
+        let brrc = RefCell::new(&mut br);
+        let addresses = NodeLikeAddressIterator::new(&dir, &brrc, sb, HASH)
+            .unwrap()
+            .collect::<Vec<_>>();
+        assert_eq!(addresses, [2 << 3, 3 << 3]);
+        drop(held);
+    }
+
+    /// A freeindex block, which begins with a 32-bit magic number rather than a da_blkinfo
+    fn freeindex(magic: u32) -> Vec<u8> {
+        let mut raw = vec![0u8; superblock().sb_blocksize as usize];
+        raw[0..4].copy_from_slice(&magic.to_be_bytes());
+        // nvalid and nused
+        raw[8..12].copy_from_slice(&1u32.to_be_bytes());
+        raw[12..16].copy_from_slice(&1u32.to_be_bytes());
+        raw
+    }
+
+    /// A Node directory of the given blocks, which start at its leaf offset
+    fn node_dir(blocks: &[Vec<u8>]) -> (Dir2Lf, BufReader<Cursor<Vec<u8>>>) {
+        let sb = superblock();
+        let img = [vec![0u8; sb.sb_blocksize as usize]]
+            .iter()
+            .chain(blocks)
+            .flatten()
+            .copied()
+            .collect::<Vec<_>>();
+        let dir = Dir2Lf::from_bmx(
+            Bmx::new(&[BmbtRec {
+                br_startoff:   sb.get_dir3_leaf_offset().into(),
+                br_startblock: 1,
+                br_blockcount: blocks.len() as u64,
+                br_flag:       false,
+            }]),
+            0,
+        );
+        (dir, BufReader::new(Cursor::new(img)))
+    }
+
+    /// A hash collision's forw pointer that leads to a freeindex block ends the search, with the
+    /// entries found so far
+    #[rstest]
+    #[case::v4(XFS_DIR2_FREE_MAGIC)]
+    #[case::v5(XFS_DIR3_FREE_MAGIC)]
+    fn forw_to_freeindex(#[case] magic: u32) {
+        let sb = superblock();
+        let leaf = sb.get_dir3_leaf_offset();
+        let (dir, mut br) = node_dir(&[
+            leafn(leaf + 1, &[(HASH - 1, 1), (HASH, 2)]),
+            freeindex(magic),
+        ]);
+        let brrc = RefCell::new(&mut br);
+        let addresses = NodeLikeAddressIterator::new(&dir, &brrc, sb, HASH)
+            .unwrap()
+            .collect::<Vec<_>>();
+        assert_eq!(addresses, [2 << 3]);
+    }
+
+    /// A node entry that leads to a freeindex block, or a freeindex block where the first leaf
+    /// or node should be, means the name isn't there
+    #[rstest]
+    #[case::node(true)]
+    #[case::first(false)]
+    fn lookup_reaches_freeindex(#[case] node: bool) {
+        let sb = superblock();
+        let leaf = sb.get_dir3_leaf_offset();
+        let mut blocks = vec![freeindex(XFS_DIR2_FREE_MAGIC)];
+        if node {
+            // A V4 node of level 1, whose one entry covers every hash
+            let mut raw = vec![0u8; sb.sb_blocksize as usize];
+            raw[8..10].copy_from_slice(&XFS_DA_NODE_MAGIC.to_be_bytes());
+            raw[12..14].copy_from_slice(&1u16.to_be_bytes());
+            raw[14..16].copy_from_slice(&1u16.to_be_bytes());
+            raw[16..20].copy_from_slice(&u32::MAX.to_be_bytes());
+            raw[20..24].copy_from_slice(&(leaf + 1).to_be_bytes());
+            blocks.insert(0, raw);
+        }
+        let (dir, mut br) = node_dir(&blocks);
+        let brrc = RefCell::new(&mut br);
+        let r = NodeLikeAddressIterator::new(&dir, &brrc, sb, HASH).map(|_| ());
+        assert_eq!(r, Err(libc::ENOENT));
+        assert_eq!(
+            dir.lookup_entry(&mut br, sb, OsStr::new("frame000000")),
+            Err(libc::ENOENT)
+        );
+    }
+
+    /// Old mkfs versions may leave a V4 Leaf directory's single LEAF1 block, rather than a LEAFN
+    /// block, beneath a node.  Either way, and first in the directory, it must be searched like a
+    /// LEAFN block.
+    #[rstest]
+    #[case::node(true)]
+    #[case::first(false)]
+    fn leaf1(#[case] node: bool) {
+        let sb = superblock();
+        let leaf = sb.get_dir3_leaf_offset();
+        let mut raw = leafn(0, &[(HASH - 1, 1), (HASH, 2), (HASH + 1, 3)]);
+        raw[8..10].copy_from_slice(&XFS_DIR2_LEAF1_MAGIC.to_be_bytes());
+        let mut blocks = vec![raw];
+        if node {
+            // A V4 node of level 1, whose one entry covers every hash
+            let mut raw = vec![0u8; sb.sb_blocksize as usize];
+            raw[8..10].copy_from_slice(&XFS_DA_NODE_MAGIC.to_be_bytes());
+            raw[12..14].copy_from_slice(&1u16.to_be_bytes());
+            raw[14..16].copy_from_slice(&1u16.to_be_bytes());
+            raw[16..20].copy_from_slice(&u32::MAX.to_be_bytes());
+            raw[20..24].copy_from_slice(&(leaf + 1).to_be_bytes());
+            blocks.insert(0, raw);
+        }
+        let (dir, mut br) = node_dir(&blocks);
+        let brrc = RefCell::new(&mut br);
+        let addresses = NodeLikeAddressIterator::new(&dir, &brrc, sb, HASH)
+            .unwrap()
+            .collect::<Vec<_>>();
+        assert_eq!(addresses, [2 << 3]);
+    }
+
+    /// A leaf block with an unknown magic number is an error, not a panic
+    #[test]
+    fn leafn_bad_magic() {
+        let mut raw = leafn(0, &[(HASH, 2)]);
+        raw[8..10].copy_from_slice(&0xbeefu16.to_be_bytes());
+        let Err(DecodeError::OtherString(e)) = Dir2LeafNDisk::from_block(&raw) else {
+            panic!("The magic must be rejected");
+        };
+        assert_eq!(e, "Unexpected magic 0xbeef in directory leaf block");
+        assert_eq!(decode_leaf(7, &raw).unwrap_err(), libc::EIO);
+    }
+
+    /// A damaged entry count that overflows a leaf block is an error, before any entry is decoded.
+    /// A V4 leaf in a 4k block has room for 510 entries.
+    #[test]
+    fn leafn_count_overflow() {
+        let mut raw = leafn(0, &[(HASH, 2)]);
+        raw[12..14].copy_from_slice(&511u16.to_be_bytes());
+        let Err(DecodeError::OtherString(e)) = Dir2LeafNDisk::from_block(&raw) else {
+            panic!("The count must be rejected");
+        };
+        assert_eq!(
+            e,
+            "Directory leaf block has a count of 511, but room for only 510"
+        );
+    }
+
+    /// A V4 directory data block holding entries with the given names, followed by free space
+    fn data_block(names: &[&str]) -> Vec<u8> {
+        data_block_sized(superblock().sb_blocksize as usize, names)
+    }
+
+    /// Like [`data_block`], but for a directory block of the given size
+    fn data_block_sized(size: usize, names: &[&str]) -> Vec<u8> {
+        let mut raw = vec![0u8; size];
+        raw[0..4].copy_from_slice(&XFS_DIR2_DATA_MAGIC.to_be_bytes());
+        let mut ofs = Dir2DataHdr::SIZE as usize;
+        for (i, name) in names.iter().enumerate() {
+            // inumber, namelen, name, and tag, padded to a multiple of 8 bytes
+            let len = (8 + 1 + name.len() + 2).next_multiple_of(8);
+            raw[ofs..ofs + 8].copy_from_slice(&(128 + i as u64).to_be_bytes());
+            raw[ofs + 8] = name.len() as u8;
+            raw[ofs + 9..ofs + 9 + name.len()].copy_from_slice(name.as_bytes());
+            raw[ofs + len - 2..ofs + len].copy_from_slice(&(ofs as u16).to_be_bytes());
+            ofs += len;
+        }
+        let len = raw.len() - ofs;
+        raw[ofs..ofs + 2].copy_from_slice(&0xffffu16.to_be_bytes());
+        raw[ofs + 2..ofs + 4].copy_from_slice(&(len as u16).to_be_bytes());
+        raw[ofs + len - 2..ofs + len].copy_from_slice(&(ofs as u16).to_be_bytes());
+        raw
+    }
+
+    /// Once readdir has returned a directory's final entry, the next call should report the end
+    /// without consulting the data fork again.
+    #[test]
+    fn readdir_end_without_lookup() {
+        let sb = superblock();
+        let dblksize = u64::from(sb.sb_blocksize);
+        // Data blocks 0 and 2, with a hole between, on disk blocks 1 and 2
+        let mut img = vec![0u8; sb.sb_blocksize as usize];
+        img.extend(data_block(&["a", "b"]));
+        img.extend(data_block(&["c"]));
+        let extent = |startoff, startblock| BmbtRec {
+            br_startoff:   startoff,
+            br_startblock: startblock,
+            br_blockcount: 1,
+            br_flag:       false,
+        };
+        let dir = Dir2Lf::from_bmx(Bmx::new(&[extent(0, 1), extent(2, 2)]), 3 * dblksize);
+        let mut br = BufReader::new(Cursor::new(img));
+
+        let mut names = Vec::new();
+        let mut offset = 0;
+        let last = loop {
+            let lookups = DFORK_LOOKUPS.with(|n| n.get());
+            match dir.next(&mut br, sb, offset) {
+                Ok((_, next, _, name)) => {
+                    names.push(name);
+                    offset = next;
+                }
+                Err(e) => {
+                    assert_eq!(e, libc::ENOENT);
+                    break DFORK_LOOKUPS.with(|n| n.get()) - lookups;
                 }
             }
+        };
+        assert_eq!(names, ["a", "b", "c"]);
+        assert_eq!(last, 0);
+    }
+
+    /// A stale offset, such as an NFS client's cookie from before the directory changed,
+    /// resumes at the next entry after it, or reports the end.
+    #[test]
+    fn readdir_stale_offset() {
+        let sb = superblock();
+        let dblksize = i64::from(sb.sb_blocksize);
+        let hdr = Dir2DataHdr::SIZE as i64;
+        let img = [
+            vec![0u8; sb.sb_blocksize as usize],
+            data_block(&["a", "bb", "c"]),
+        ]
+        .concat();
+        let extent = BmbtRec {
+            br_startoff:   0,
+            br_startblock: 1,
+            br_blockcount: 1,
+            br_flag:       false,
+        };
+        let dir = Dir2Lf::from_bmx(Bmx::new(&[extent]), dblksize as u64);
+        let mut br = BufReader::new(Cursor::new(img));
+        let mut next = |offset| {
+            dir.next(&mut br, sb, offset)
+                .map(|(_, next, _, name)| (next, name.into_string().unwrap()))
+        };
+
+        // Each entry is 16 bytes long
+        assert_eq!(next(hdr + 16), Ok((hdr + 32, "bb".to_string())));
+        assert_eq!(next(hdr + 17), Ok((hdr + 48, "c".to_string())));
+        assert_eq!(next(3), Ok((hdr + 16, "a".to_string())));
+        assert_eq!(next(hdr + 49), Err(libc::ENOENT));
+        assert_eq!(next(dblksize + 1), Err(libc::ENOENT));
+        assert_eq!(next(u32::MAX.into()), Err(libc::ENOENT));
+        assert_eq!(next(-1), Err(libc::ENOENT));
+        assert_eq!(next(i64::MIN), Err(libc::ENOENT));
+    }
+    /// Resuming readdir from every offset that it returns must list each entry exactly once, even
+    /// when directory blocks span several file system blocks that aren't contiguous on disk.
+    #[test]
+    fn readdir_discontiguous_dblock() {
+        let mut sb = superblock().clone();
+        sb.sb_dirblklog = 1;
+        let bs = sb.sb_blocksize as usize;
+        let names = (0..600).map(|i| format!("f{i:07}")).collect::<Vec<_>>();
+        let names = names.iter().map(String::as_str).collect::<Vec<_>>();
+        // 300 entries, of 24 bytes each, fill most of both halves of a directory block
+        let dblk0 = data_block_sized(2 * bs, &names[..300]);
+        let dblk1 = data_block_sized(2 * bs, &names[300..]);
+        // Directory block 1's halves are on disk blocks 5 and 3, with garbage in disk block 4
+        let img = [
+            vec![0u8; bs],
+            dblk0,
+            dblk1[bs..].to_vec(),
+            vec![0xffu8; bs],
+            dblk1[..bs].to_vec(),
+        ]
+        .concat();
+        let extent = |startoff, startblock, blockcount| BmbtRec {
+            br_startoff:   startoff,
+            br_startblock: startblock,
+            br_blockcount: blockcount,
+            br_flag:       false,
+        };
+        let bmx = Bmx::new(&[extent(0, 1, 2), extent(2, 5, 1), extent(3, 3, 1)]);
+        let dir = Dir2Lf::from_bmx(bmx, 4 * bs as u64);
+        let mut br = BufReader::new(Cursor::new(img));
+
+        let mut listed = Vec::new();
+        let mut offset = 0;
+        while let Ok((_, next, _, name)) = dir.next(&mut br, &sb, offset) {
+            assert!(next > offset);
+            listed.push(name.into_string().unwrap());
+            offset = next;
         }
+        assert_eq!(listed, names);
     }
 }
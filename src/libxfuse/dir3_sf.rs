@@ -28,6 +28,7 @@
 use std::{
     ffi::{OsStr, OsString},
     io::{BufRead, Seek},
+    mem,
     os::unix::ffi::{OsStrExt, OsStringExt},
 };
 
@@ -36,15 +37,15 @@ use bincode::{
     error::DecodeError,
     Decode,
 };
-use fuser::FileType;
 use libc::{c_int, ENOENT};
 
 use super::{
     definitions::*,
-    dir3::{Dir3, XFS_DIR3_FT_DIR},
+    dir3::{check_name, Dir3, XFS_DIR3_FT_DIR},
     sb::Sb,
+    stat::FileType,
     utils::{get_file_type, FileKind},
-    volume::SUPERBLOCK,
+    validate,
 };
 
 // pub type XfsDir2SfOff = [u8; 2];
@@ -73,6 +74,19 @@ impl Decode for Dir2SfHdr {
     }
 }
 
+/// Decode an entry's name.  Its length comes from a single byte, which may be corrupt, so it
+/// mustn't overrun the data fork.
+fn decode_name<D: Decoder>(decoder: &mut D, namelen: u8) -> Result<OsString, DecodeError> {
+    let mut namebytes = vec![0u8; namelen.into()];
+    decoder.reader().read(&mut namebytes[..]).map_err(|_| {
+        DecodeError::OtherString(format!(
+            "Shortform directory entry's {}-byte name overruns the data fork",
+            namelen
+        ))
+    })?;
+    Ok(OsString::from_vec(namebytes))
+}
+
 #[derive(Debug, Clone)]
 struct Dir2SfEntry32 {
     offset:  u16,
@@ -81,15 +95,12 @@ struct Dir2SfEntry32 {
     inumber: u32,
 }
 
-impl Decode for Dir2SfEntry32 {
-    fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError> {
-        let sb = SUPERBLOCK.get().unwrap();
+impl Dir2SfEntry32 {
+    fn decode<D: Decoder>(decoder: &mut D, has_ftype: bool) -> Result<Self, DecodeError> {
         let namelen: u8 = Decode::decode(decoder)?;
         let offset: u16 = Decode::decode(decoder)?;
-        let mut namebytes = vec![0u8; namelen.into()];
-        decoder.reader().read(&mut namebytes[..])?;
-        let name = OsString::from_vec(namebytes);
-        let ftype: Option<u8> = if sb.has_ftype() {
+        let name = decode_name(decoder, namelen)?;
+        let ftype: Option<u8> = if has_ftype {
             Some(Decode::decode(decoder)?)
         } else {
             None
@@ -113,26 +124,11 @@ struct Dir2SfEntry64 {
 }
 
 impl Dir2SfEntry64 {
-    pub fn new(name: &[u8], ftype: u8, offset: u16, inumber: XfsIno) -> Self {
-        let name = OsStr::from_bytes(name).to_owned();
-        Self {
-            offset,
-            name,
-            ftype: Some(ftype),
-            inumber,
-        }
-    }
-}
-
-impl Decode for Dir2SfEntry64 {
-    fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError> {
-        let sb = SUPERBLOCK.get().unwrap();
+    fn decode<D: Decoder>(decoder: &mut D, has_ftype: bool) -> Result<Self, DecodeError> {
         let namelen: u8 = Decode::decode(decoder)?;
         let offset: u16 = Decode::decode(decoder)?;
-        let mut namebytes = vec![0u8; namelen.into()];
-        decoder.reader().read(&mut namebytes[..])?;
-        let name = OsString::from_vec(namebytes);
-        let ftype: Option<u8> = if sb.has_ftype() {
+        let name = decode_name(decoder, namelen)?;
+        let ftype: Option<u8> = if has_ftype {
             Some(Decode::decode(decoder)?)
         } else {
             None
@@ -163,59 +159,79 @@ impl From<Dir2SfEntry32> for Dir2SfEntry64 {
 
 #[derive(Debug, Clone)]
 pub struct Dir2Sf {
-    list: Vec<Dir2SfEntry64>,
+    /// The directory's own inode number, for its "." entry
+    ino:    XfsIno,
+    /// The parent directory's inode number, for its ".." entry
+    parent: XfsIno,
+    /// Every entry but "." and "..".  For an empty directory, this doesn't allocate.
+    list:   Vec<Dir2SfEntry64>,
 }
 
 impl Dir2Sf {
-    /// Set the inode of this directory.  Annoyingly, we need to know it, but it
-    /// isn't stored on disk in this header.
-    pub fn set_ino(&mut self, ino: XfsIno) {
-        self.list[0].inumber = ino;
+    /// Decode the shortform directory stored in inode `ino`.  Annoyingly, we need to know the
+    /// directory's own inode number for its "." entry, but it isn't stored in this header.
+    pub fn decode<D: Decoder>(decoder: &mut D, sb: &Sb, ino: XfsIno) -> Result<Self, DecodeError> {
+        Dir2Sf::decode_with_ftype(decoder, ino, sb.has_ftype())
     }
-}
 
-impl Decode for Dir2Sf {
-    fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError> {
+    /// Like [`Dir2Sf::decode`].  Whether the entries include a file type byte depends on the file
+    /// system's features, not on anything in the inode.
+    fn decode_with_ftype<D: Decoder>(
+        decoder: &mut D,
+        ino: XfsIno,
+        has_ftype: bool,
+    ) -> Result<Self, DecodeError> {
         let hdr: Dir2SfHdr = Decode::decode(decoder)?;
 
-        let mut list = Vec::<Dir2SfEntry64>::new();
-        // Alone out of all the directory types, SF directories to not store the
-        // "." and ".." entries on disk.  We must synthesize them here.
-        list.push(Dir2SfEntry64::new(b".", XFS_DIR3_FT_DIR, 1, u64::MAX));
-        list.push(Dir2SfEntry64::new(b"..", XFS_DIR3_FT_DIR, 2, hdr.parent));
+        // Alone out of all the directory types, SF directories to not store the "." and ".."
+        // entries on disk.  Lookup and next synthesize them.
+        let mut list = Vec::<Dir2SfEntry64>::with_capacity(hdr.count.into());
         for _i in 0..hdr.count {
             if hdr.i8count > 0 {
-                list.push(Decode::decode(decoder)?);
+                list.push(Dir2SfEntry64::decode(decoder, has_ftype)?);
             } else {
-                let e32: Dir2SfEntry32 = Decode::decode(decoder)?;
-                list.push(e32.into());
+                list.push(Dir2SfEntry32::decode(decoder, has_ftype)?.into());
             }
         }
 
-        Ok(Dir2Sf { list })
+        Ok(Dir2Sf {
+            ino,
+            parent: hdr.parent,
+            list,
+        })
     }
 }
 
 impl Dir3 for Dir2Sf {
-    fn lookup<R: bincode::de::read::Reader + BufRead + Seek>(
+    fn cache_bytes(&self) -> usize {
+        self.list
+            .iter()
+            .map(|e| mem::size_of::<Dir2SfEntry64>() + e.name.len())
+            .sum()
+    }
+
+    fn lookup_entry<R: bincode::de::read::Reader + BufRead + Seek>(
         &self,
         _buf_reader: &mut R,
         _super_block: &Sb,
         name: &OsStr,
-    ) -> Result<u64, c_int> {
-        let mut inode: Option<XfsIno> = None;
+    ) -> Result<(XfsIno, Option<u8>), c_int> {
+        check_name(name)?;
+        match name.as_bytes() {
+            b"." => return Ok((self.ino, Some(XFS_DIR3_FT_DIR))),
+            b".." => return Ok((self.parent, Some(XFS_DIR3_FT_DIR))),
+            _ => (),
+        }
+
+        let mut inode: Option<(XfsIno, Option<u8>)> = None;
 
         for entry in self.list.iter() {
             if entry.name == name {
-                inode = Some(entry.inumber);
+                inode = Some((entry.inumber, entry.ftype));
             }
         }
 
-        if let Some(ino) = inode {
-            Ok(ino)
-        } else {
-            Err(ENOENT)
-        }
+        inode.ok_or(ENOENT)
     }
 
     fn next<R: bincode::de::read::Reader + BufRead + Seek>(
@@ -224,10 +240,28 @@ impl Dir3 for Dir2Sf {
         _super_block: &Sb,
         offset: i64,
     ) -> Result<(XfsIno, i64, Option<FileType>, OsString), c_int> {
+        // The synthetic "." and ".." come first, at offsets 1 and 2.  A negative offset is a
+        // cookie beyond i64::MAX.
+        let kind = Some(FileType::Directory);
+        match offset {
+            ..=-1 => return Err(libc::ENOENT),
+            0 => return Ok((self.ino, 1, kind, OsString::from("."))),
+            1 => return Ok((self.parent, 2, kind, OsString::from(".."))),
+            _ => (),
+        }
+
         for entry in self.list.iter() {
             if i64::from(entry.offset) <= offset {
                 continue;
             }
+            if entry.name.is_empty() {
+                // The kernel would reject it, and nobody could look it up anyway
+                validate::failure(format_args!(
+                    "Shortform directory {} has an entry with an empty name at offset {}",
+                    self.ino, entry.offset
+                ))?;
+                continue;
+            }
 
             let ino = entry.inumber;
 
@@ -241,6 +275,215 @@ impl Dir3 for Dir2Sf {
             return Ok((ino, entry.offset as i64, kind, name));
         }
 
-        Err(-1)
+        Err(libc::ENOENT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, Cursor};
+
+    use proptest::prelude::*;
+    use rstest::rstest;
+
+    use super::*;
+    use crate::libxfuse::{
+        alloc_count::allocations,
+        bmbt_rec::tests::superblock,
+        dir3::{XFS_DIR3_FT_REG_FILE, XFS_NAME_MAX},
+    };
+
+    /// Decode a shortform directory from exactly `buf`, as if it filled the data fork
+    fn decode_slice(buf: &[u8], has_ftype: bool) -> Result<Dir2Sf, DecodeError> {
+        let config = bincode::config::standard()
+            .with_big_endian()
+            .with_fixed_int_encoding();
+        let reader = bincode::de::read::SliceReader::new(buf);
+        let mut decoder = bincode::de::DecoderImpl::new(reader, config);
+        Dir2Sf::decode_with_ftype(&mut decoder, 96, has_ftype)
+    }
+
+    /// Hand-build a shortform directory, the way mkfs would lay it out: the header, then each
+    /// entry's namelen, offset, name, optional ftype and a 4 or 8 byte inode number.
+    fn shortform(parent: u64, entries: &[(&[u8], u16, u64)], i8: bool, has_ftype: bool) -> Vec<u8> {
+        let mut buf = vec![
+            entries.len() as u8,
+            if i8 { entries.len() as u8 } else { 0 },
+        ];
+        let put_ino = |buf: &mut Vec<u8>, ino: u64| {
+            if i8 {
+                buf.extend_from_slice(&ino.to_be_bytes());
+            } else {
+                buf.extend_from_slice(&u32::try_from(ino).unwrap().to_be_bytes());
+            }
+        };
+        put_ino(&mut buf, parent);
+        for (name, offset, ino) in entries {
+            buf.push(name.len() as u8);
+            buf.extend_from_slice(&offset.to_be_bytes());
+            buf.extend_from_slice(name);
+            if has_ftype {
+                buf.push(XFS_DIR3_FT_REG_FILE);
+            }
+            put_ino(&mut buf, *ino);
+        }
+        buf
+    }
+
+    /// Every combination of inode number width and ftype presence must decode to the same
+    /// entries.
+    #[rstest]
+    fn decode(#[values(false, true)] i8: bool, #[values(false, true)] has_ftype: bool) {
+        let sb = superblock();
+        let (parent, inos) = if i8 {
+            (0x1_0000_0080, [0x2_0000_0083, 0x2_0000_0084, 0x3_0000_0085])
+        } else {
+            (128, [131, 132, 133])
+        };
+        let entries: [(&[u8], u16, u64); 3] = [
+            (b"a", 0x60, inos[0]),
+            (b"bb", 0x70, inos[1]),
+            (b"a_rather_long_name", 0x80, inos[2]),
+        ];
+        let buf = shortform(parent, &entries, i8, has_ftype);
+        let config = bincode::config::standard()
+            .with_big_endian()
+            .with_fixed_int_encoding();
+        let len = buf.len() as u64;
+        let mut cursor = BufReader::new(Cursor::new(buf));
+        let mut decoder = bincode::de::DecoderImpl::new(&mut cursor, config);
+        let sf = Dir2Sf::decode_with_ftype(&mut decoder, 96, has_ftype).unwrap();
+        assert_eq!(cursor.stream_position().unwrap(), len);
+
+        assert_eq!(sf.lookup(&mut cursor, sb, OsStr::new(".")), Ok(96));
+        assert_eq!(sf.lookup(&mut cursor, sb, OsStr::new("..")), Ok(parent));
+        for (name, _, ino) in entries.iter() {
+            assert_eq!(
+                sf.lookup_entry(&mut cursor, sb, OsStr::from_bytes(name)),
+                Ok((*ino, has_ftype.then_some(XFS_DIR3_FT_REG_FILE)))
+            );
+        }
+
+        let mut offset = 2;
+        for (name, ofs, ino) in entries.iter() {
+            let (next_ino, next_ofs, kind, next_name) = sf.next(&mut cursor, sb, offset).unwrap();
+            assert_eq!(next_ino, *ino);
+            assert_eq!(next_ofs, i64::from(*ofs));
+            assert_eq!(next_name, OsStr::from_bytes(name));
+            assert_eq!(kind, has_ftype.then_some(FileType::RegularFile));
+            offset = next_ofs;
+        }
+        assert_eq!(sf.next(&mut cursor, sb, offset), Err(ENOENT));
+    }
+
+    /// Offsets beyond the last entry, including ones that don't fit in an off_t, are the end.
+    #[rstest]
+    fn next_past_end(#[values(0x81, 0x1_0000, i64::MAX, -1, i64::MIN)] offset: i64) {
+        let sb = superblock();
+        let config = bincode::config::standard()
+            .with_big_endian()
+            .with_fixed_int_encoding();
+        let entries: [(&[u8], u16, u64); 2] = [(b"a", 0x60, 131), (b"bb", 0x70, 132)];
+        let mut cursor = BufReader::new(Cursor::new(shortform(128, &entries, false, true)));
+        let mut decoder = bincode::de::DecoderImpl::new(&mut cursor, config);
+        let sf = Dir2Sf::decode_with_ftype(&mut decoder, 96, true).unwrap();
+        assert_eq!(sf.next(&mut cursor, sb, offset), Err(ENOENT));
+    }
+
+    /// An empty directory needs no allocations to decode or clone, and reading it allocates
+    /// nothing but the names of "." and ".."
+    #[test]
+    fn empty() {
+        let sb = superblock();
+        let config = bincode::config::standard()
+            .with_big_endian()
+            .with_fixed_int_encoding();
+        let mut cursor = BufReader::new(Cursor::new(shortform(128, &[], false, true)));
+        let mut decoder = bincode::de::DecoderImpl::new(&mut cursor, config);
+
+        let (sf, n) = allocations(|| Dir2Sf::decode_with_ftype(&mut decoder, 96, true).unwrap());
+        assert_eq!(n, 0);
+        let (sf, n) = allocations(|| sf.clone());
+        assert_eq!(n, 0);
+
+        let ((dot, dotdot, end), n) = allocations(|| {
+            (
+                sf.next(&mut cursor, sb, 0),
+                sf.next(&mut cursor, sb, 1),
+                sf.next(&mut cursor, sb, 2),
+            )
+        });
+        assert_eq!(n, 2);
+        let kind = Some(FileType::Directory);
+        assert_eq!(dot, Ok((96, 1, kind, OsString::from("."))));
+        assert_eq!(dotdot, Ok((128, 2, kind, OsString::from(".."))));
+        assert_eq!(end, Err(ENOENT));
+    }
+
+    /// A name length that runs past the end of the data fork is an error, not a panic
+    #[rstest]
+    fn namelen_overrun(#[values(false, true)] i8: bool, #[values(false, true)] has_ftype: bool) {
+        let entries: [(&[u8], u16, u64); 2] = [(b"a", 0x60, 131), (b"bb", 0x70, 132)];
+        let mut buf = shortform(128, &entries, i8, has_ftype);
+        let last = buf.len() - if i8 { 8 } else { 4 } - usize::from(has_ftype) - 2 - 3;
+        assert_eq!(buf[last], 2);
+        buf[last] = 0xff;
+        let Err(DecodeError::OtherString(e)) = decode_slice(&buf, has_ftype) else {
+            panic!("The name length must be rejected");
+        };
+        assert_eq!(
+            e,
+            "Shortform directory entry's 255-byte name overruns the data fork"
+        );
+    }
+
+    /// Names that no entry could have are rejected before searching
+    #[rstest]
+    #[case::empty(0, Err(libc::EINVAL))]
+    #[case::longest(XFS_NAME_MAX, Err(ENOENT))]
+    #[case::too_long(XFS_NAME_MAX + 1, Err(libc::ENAMETOOLONG))]
+    #[case::way_too_long(300, Err(libc::ENAMETOOLONG))]
+    fn lookup_name_len(#[case] len: usize, #[case] expected: Result<XfsIno, c_int>) {
+        let entries: [(&[u8], u16, u64); 1] = [(b"a", 0x60, 131)];
+        let sf = decode_slice(&shortform(128, &entries, false, true), true).unwrap();
+        let mut cursor = BufReader::new(Cursor::new(Vec::new()));
+        let name = OsString::from("a".repeat(len));
+        assert_eq!(sf.lookup(&mut cursor, superblock(), &name), expected);
+    }
+
+    proptest! {
+        /// However its bytes are corrupted, a shortform directory either fails to decode or can
+        /// be listed and searched without panicking.
+        #[test]
+        fn fuzz(
+            corruption in prop::collection::vec((any::<prop::sample::Index>(), any::<u8>()), 1..8),
+            i8 in any::<bool>(),
+            has_ftype in any::<bool>(),
+        ) {
+            let entries: [(&[u8], u16, u64); 3] = [
+                (b"a", 0x60, 131),
+                (b"bb", 0x70, 132),
+                (b"a_rather_long_name", 0x80, 133),
+            ];
+            let mut buf = shortform(128, &entries, i8, has_ftype);
+            for (index, byte) in corruption {
+                let i = index.index(buf.len());
+                buf[i] = byte;
+            }
+            if let Ok(sf) = decode_slice(&buf, has_ftype) {
+                let sb = superblock();
+                let mut cursor = BufReader::new(Cursor::new(Vec::new()));
+                let mut offset = 0;
+                while let Ok((_, next, _, name)) = sf.next(&mut cursor, sb, offset) {
+                    prop_assert!(next > offset);
+                    prop_assert!(name.len() <= XFS_NAME_MAX);
+                    let _ = sf.lookup_entry(&mut cursor, sb, &name);
+                    offset = next;
+                }
+                for (name, _, _) in entries.iter() {
+                    let _ = sf.lookup(&mut cursor, sb, OsStr::from_bytes(name));
+                }
+            }
+        }
     }
 }
@@ -0,0 +1,262 @@
+/*
+ * BSD 2-Clause License
+ *
+ * Copyright (c) 2021, Khaled Emara
+ * All rights reserved.
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice, this
+ *    list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice,
+ *    this list of conditions and the following disclaimer in the documentation
+ *    and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+ * AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+ * IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+ * FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+ * SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+ * CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+ * OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+ * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ */
+//! Printing the superblock and an inode's on-disk metadata as JSON, used by `--dump`.
+//!
+//! It decodes only what it must, so that it can describe inodes that are too damaged to mount.
+use std::{
+    ffi::OsStr,
+    io::{self, prelude::*, SeekFrom},
+    os::unix::ffi::OsStrExt,
+    path::Path,
+};
+
+use super::{
+    block_reader::BlockReader,
+    bmbt_rec::BmbtRec,
+    btree::{BmbtKey, BmdrBlock, XfsBmbtPtr},
+    definitions::*,
+    dinode::{DiA, DiU, Dinode},
+    dinode_core::{DinodeCore, XfsDinodeFmt},
+    list::{unix_secs, write_json_str},
+    sb::Sb,
+    S_IFMT,
+};
+
+/// The name that xfs_db uses for an inode fork format
+fn format_name(format: &XfsDinodeFmt) -> &'static str {
+    match format {
+        XfsDinodeFmt::Dev => "dev",
+        XfsDinodeFmt::Local => "local",
+        XfsDinodeFmt::Extents => "extents",
+        XfsDinodeFmt::Btree => "btree",
+        XfsDinodeFmt::Uuid => "uuid",
+        XfsDinodeFmt::Rmap => "rmap",
+    }
+}
+
+/// Write an extent list as the members of a fork's JSON object
+fn write_extents<W: Write>(out: &mut W, recs: &[BmbtRec]) -> io::Result<()> {
+    out.write_all(b",\"extents\":[")?;
+    for (i, rec) in recs.iter().enumerate() {
+        if i > 0 {
+            out.write_all(b",")?;
+        }
+        write!(
+            out,
+            "{{\"offset\":{},\"block\":{},\"count\":{},\"unwritten\":{}}}",
+            rec.br_startoff, rec.br_startblock, rec.br_blockcount, rec.br_flag
+        )?;
+    }
+    out.write_all(b"]")
+}
+
+/// Write a btree's root, stored in the inode itself, as the members of a fork's JSON object
+fn write_btree_root<W: Write>(
+    out: &mut W,
+    (root, keys, ptrs): &(BmdrBlock, Vec<BmbtKey>, Vec<XfsBmbtPtr>),
+) -> io::Result<()> {
+    let keys = keys
+        .iter()
+        .map(|key| key.br_startoff.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+    let ptrs = ptrs
+        .iter()
+        .map(|ptr| ptr.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+    write!(
+        out,
+        ",\"level\":{},\"numrecs\":{},\"keys\":[{}],\"ptrs\":[{}]",
+        root.bb_level, root.bb_numrecs, keys, ptrs
+    )
+}
+
+/// Write the inode core's fields as the members of a JSON object
+fn write_core<W: Write>(out: &mut W, core: &DinodeCore) -> io::Result<()> {
+    write!(
+        out,
+        "\"mode\":{},\"version\":{},\"uid\":{},\"gid\":{},\"nlink\":{},\"size\":{},",
+        core.di_mode, core.di_version, core.di_uid, core.di_gid, core.di_nlink, core.di_size,
+    )?;
+    write!(
+        out,
+        "\"nblocks\":{},\"nextents\":{},\"anextents\":{},\"forkoff\":{},\"gen\":{},",
+        core.di_nblocks, core.di_nextents, core.di_anextents, core.di_forkoff, core.di_gen,
+    )?;
+    write!(
+        out,
+        "\"next_unlinked\":{},\"flags2\":{},\"atime\":{},\"mtime\":{},\"ctime\":{},\"crtime\":",
+        core.di_next_unlinked,
+        core.di_flags2,
+        unix_secs(core.timestamp(&core.di_atime)),
+        unix_secs(core.timestamp(&core.di_mtime)),
+        unix_secs(core.timestamp(&core.di_ctime)),
+    )?;
+    // Only v3 inodes record their birth time
+    if core.di_version >= 3 {
+        write!(out, "{}", unix_secs(core.timestamp(&core.di_crtime)))
+    } else {
+        out.write_all(b"null")
+    }
+}
+
+/// Write an inode's metadata and both of its forks as a JSON object
+fn write_inode<W: Write>(out: &mut W, ino: XfsIno, dinode: &Dinode) -> io::Result<()> {
+    let core = &dinode.di_core;
+    write!(out, "{{\"ino\":{},", ino)?;
+    write_core(out, core)?;
+
+    write!(
+        out,
+        ",\"data\":{{\"format\":\"{}\"",
+        format_name(&core.di_format)
+    )?;
+    match &dinode.di_u {
+        DiU::Bmx(recs) => write_extents(out, recs)?,
+        DiU::Bmbt(root) => write_btree_root(out, root)?,
+        DiU::Blk(dev) | DiU::Chr(dev) => write!(out, ",\"rdev\":{}", dev)?,
+        DiU::Symlink(target) => {
+            out.write_all(b",\"target\":")?;
+            write_json_str(out, OsStr::from_bytes(target))?;
+        }
+        DiU::Dir2Sf(_) | DiU::Fifo | DiU::Socket => (),
+    }
+    out.write_all(b"},\"attr\":")?;
+
+    match &dinode.di_a {
+        None => out.write_all(b"null")?,
+        Some(di_a) => {
+            write!(out, "{{\"format\":\"{}\"", format_name(&core.di_aformat))?;
+            match di_a {
+                DiA::Attrsf(sf) => write!(out, ",\"count\":{}", sf.list.len())?,
+                DiA::Abmx(recs) => write_extents(out, recs)?,
+                DiA::Abmbt(root) => write_btree_root(out, root)?,
+            }
+            out.write_all(b"}")?;
+        }
+    }
+    out.write_all(b"}")
+}
+
+/// Read an inode, checking first that it is one.
+fn read_inode(reader: &mut BlockReader, sb: &Sb, ino: XfsIno) -> io::Result<Dinode> {
+    let offset = sb.ino_to_offset(ino).map_err(|e| {
+        let msg = format!("invalid inode number {}", ino);
+        io::Error::new(io::Error::from_raw_os_error(e).kind(), msg)
+    })?;
+    // Check the magic and the file type first, since Dinode::from would panic
+    let mut head = [0u8; 4];
+    reader.set_bufsize(sb.inode_size());
+    reader.seek(SeekFrom::Start(offset))?;
+    reader.read_exact(&mut head)?;
+    if u16::from_be_bytes([head[0], head[1]]) != XFS_DINODE_MAGIC {
+        return Err(io::Error::other(format!("inode {} has bad magic", ino)));
+    }
+    if u16::from_be_bytes([head[2], head[3]]) & S_IFMT == 0 {
+        return Err(io::Error::other(format!("inode {} is free", ino)));
+    }
+    Dinode::from(reader.by_ref(), sb, ino).map_err(io::Error::from_raw_os_error)
+}
+
+/// Print the superblock, and the inode `ino` if given, as JSON without mounting the file system.
+pub fn dump<W: Write>(
+    device: &Path,
+    ino: Option<XfsIno>,
+    no_mmap: bool,
+    mut out: W,
+) -> io::Result<()> {
+    let mut reader = BlockReader::open(device)?;
+    if no_mmap {
+        reader.unmap();
+    }
+    let sb = Sb::from(reader.by_ref())
+        .map_err(|e| io::Error::other(format!("{}: {}", device.display(), e)))?;
+    // Read the inode before printing anything, so an error doesn't leave half a JSON object
+    let dinode = ino
+        .map(|ino| read_inode(&mut reader, &sb, ino))
+        .transpose()?;
+
+    write!(out, "{{\"superblock\":{}", sb.to_json())?;
+    if let (Some(ino), Some(dinode)) = (ino, dinode) {
+        out.write_all(b",\"inode\":")?;
+        write_inode(&mut out, ino, &dinode)?;
+    }
+    out.write_all(b"}\n")?;
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extents() {
+        let recs = [
+            BmbtRec {
+                br_startoff:   0,
+                br_startblock: 100,
+                br_blockcount: 2,
+                br_flag:       false,
+            },
+            BmbtRec {
+                br_startoff:   4,
+                br_startblock: 7,
+                br_blockcount: 1,
+                br_flag:       true,
+            },
+        ];
+        let mut out = Vec::new();
+        write_extents(&mut out, &recs).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            concat!(
+                ",\"extents\":[{\"offset\":0,\"block\":100,\"count\":2,\"unwritten\":false},",
+                "{\"offset\":4,\"block\":7,\"count\":1,\"unwritten\":true}]"
+            )
+        );
+    }
+
+    #[test]
+    fn btree_root() {
+        let root = (
+            BmdrBlock {
+                bb_level:   1,
+                bb_numrecs: 2,
+            },
+            vec![BmbtKey { br_startoff: 0 }, BmbtKey { br_startoff: 512 }],
+            vec![1234, 5678],
+        );
+        let mut out = Vec::new();
+        write_btree_root(&mut out, &root).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            ",\"level\":1,\"numrecs\":2,\"keys\":[0,512],\"ptrs\":[1234,5678]"
+        );
+    }
+}
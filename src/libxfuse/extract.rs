@@ -0,0 +1,536 @@
+/*
+ * BSD 2-Clause License
+ *
+ * Copyright (c) 2021, Khaled Emara
+ * All rights reserved.
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice, this
+ *    list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice,
+ *    this list of conditions and the following disclaimer in the documentation
+ *    and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+ * AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+ * IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+ * FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+ * SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+ * CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+ * OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+ * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ */
+//! Copying a subtree of the file system into a local directory, used by `--extract`.
+//!
+//! Everything is created relative to an open descriptor of its parent directory, never through
+//! a symlink, and never over anything that already exists.  So however the image's directories
+//! are damaged, nothing can be written outside of the destination.
+use std::{
+    collections::HashMap,
+    ffi::{CString, OsStr},
+    fs,
+    io::{self, prelude::*},
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::{ffi::OsStrExt, fs::FileExt},
+    },
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use nix::{
+    fcntl::{openat, OFlag},
+    sys::{
+        stat::{
+            fchmod,
+            fchmodat,
+            futimens,
+            mkdirat,
+            mknodat,
+            utimensat,
+            FchmodatFlags,
+            Mode,
+            SFlag,
+            UtimensatFlags,
+        },
+        time::TimeSpec,
+    },
+    unistd::{fchown, fchownat, linkat, symlinkat, FchownatFlags, Gid, LinkatFlags, Uid},
+};
+use tracing::error;
+
+use super::{
+    attr::Attr,
+    block_reader::BlockReader,
+    definitions::XfsIno,
+    dinode::Dinode,
+    list::{read_dir, resolve, Visited, DEFAULT_MAX_DEPTH},
+    sb::Sb,
+    stat::{FileAttr, FileType},
+    volume::{Options, Volume},
+};
+
+/// Regular files are copied this many bytes at a time
+const CHUNK: u32 = 1 << 20;
+
+/// What [`extract`] did
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct Summary {
+    /// Directories created
+    pub directories: u64,
+    /// Everything else created, including hard links
+    pub files:       u64,
+    /// Bytes of regular files' data written, not counting holes
+    pub bytes:       u64,
+    /// Files and extended attributes deliberately not extracted
+    pub skipped:     u64,
+    /// Files that couldn't be extracted, or only partly
+    pub errors:      u64,
+}
+
+impl Summary {
+    /// Write the summary as JSON
+    pub fn write<W: Write>(&self, mut out: W) -> io::Result<()> {
+        writeln!(
+            out,
+            "{{\"directories\":{},\"files\":{},\"bytes\":{},\"skipped\":{},\"errors\":{}}}",
+            self.directories, self.files, self.bytes, self.skipped, self.errors
+        )?;
+        out.flush()
+    }
+}
+
+/// A file that was just created: open, or else known by its name within its directory
+#[derive(Clone, Copy)]
+enum Created<'a> {
+    Fd(RawFd),
+    At(RawFd, &'a OsStr),
+}
+
+/// Could this name create anything other than a new entry in its parent directory?
+fn is_unsafe_name(name: &OsStr) -> bool {
+    let name = name.as_bytes();
+    name.is_empty() || name == b"." || name == b".." || name.contains(&b'/') || name.contains(&0)
+}
+
+/// Convert a time to a timespec, which unlike SystemTime may be before the Epoch
+fn timespec(t: SystemTime) -> TimeSpec {
+    match t.duration_since(UNIX_EPOCH) {
+        Ok(d) => TimeSpec::from_duration(d),
+        Err(e) => -TimeSpec::from_duration(e.duration()),
+    }
+}
+
+/// Set an extended attribute, named as it's listed, on an open file
+#[cfg(target_os = "linux")]
+fn set_xattr(fd: RawFd, name: &OsStr, value: &[u8]) -> io::Result<()> {
+    let name = CString::new(name.as_bytes())?;
+    // The name and value outlive the call
+    let r = unsafe { libc::fsetxattr(fd, name.as_ptr(), value.as_ptr().cast(), value.len(), 0) };
+    if r < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Set an extended attribute, named as it's listed, on an open file
+#[cfg(target_os = "freebsd")]
+fn set_xattr(fd: RawFd, name: &OsStr, value: &[u8]) -> io::Result<()> {
+    let (ns, name) = super::attr::parse_name(name).map_err(io::Error::from_raw_os_error)?;
+    // FreeBSD has no namespaces for XFS's trusted and secure, but only root may use its system
+    // namespace, like them.
+    let namespace = if ns == 0 {
+        libc::EXTATTR_NAMESPACE_USER
+    } else {
+        libc::EXTATTR_NAMESPACE_SYSTEM
+    };
+    let name = CString::new(name.as_bytes())?;
+    // The name and value outlive the call
+    let r = unsafe {
+        libc::extattr_set_fd(
+            fd,
+            namespace,
+            name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+        )
+    };
+    if r < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "freebsd")))]
+fn set_xattr(_fd: RawFd, _name: &OsStr, _value: &[u8]) -> io::Result<()> {
+    Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+}
+
+struct Extractor {
+    sb:      Sb,
+    device:  BlockReader,
+    /// The destination directory
+    dest:    OwnedFd,
+    /// Create block and character devices, rather than skipping them
+    devices: bool,
+    /// Copy files' owners, and their setuid and setgid bits and privileged extended attributes
+    owner:   bool,
+    visited: Visited,
+    /// Where each multiply linked file was first extracted to, relative to the destination
+    links:   HashMap<XfsIno, PathBuf>,
+    summary: Summary,
+}
+
+impl Extractor {
+    /// Extract inode `ino`, found at `path` in the file system, as `name` within the directory
+    /// `dir`, which is `rel` relative to the destination.  Directories are extracted with all of
+    /// their descendants.  `depth` is the number of levels below the root of the extraction.
+    /// Failures are logged and counted, rather than returned, so that one damaged file doesn't
+    /// stop the rest from being recovered.
+    fn extract(
+        &mut self,
+        dir: RawFd,
+        rel: &Path,
+        name: &OsStr,
+        path: &Path,
+        ino: XfsIno,
+        depth: usize,
+    ) {
+        if let Err(e) = self.try_extract(dir, &rel.join(name), name, path, ino, depth) {
+            error!("{}: {}", path.display(), e);
+            self.summary.errors += 1;
+        }
+    }
+
+    fn try_extract(
+        &mut self,
+        dir: RawFd,
+        rel: &Path,
+        name: &OsStr,
+        path: &Path,
+        ino: XfsIno,
+        depth: usize,
+    ) -> io::Result<()> {
+        self.device.set_bufsize(self.sb.inode_size());
+        let mut dinode = Dinode::from(self.device.by_ref(), &self.sb, ino)
+            .map_err(io::Error::from_raw_os_error)?;
+        let attr = dinode
+            .stat(ino, &self.sb)
+            .map_err(io::Error::from_raw_os_error)?;
+
+        if attr.kind != FileType::Directory && attr.nlink > 1 {
+            if let Some(first) = self.links.get(&ino) {
+                linkat(
+                    Some(self.dest.as_raw_fd()),
+                    first.as_path(),
+                    Some(dir),
+                    Path::new(name),
+                    LinkatFlags::NoSymlinkFollow,
+                )?;
+                self.summary.files += 1;
+                return Ok(());
+            }
+        }
+
+        let perm = Mode::from_bits_truncate(0o600);
+        match attr.kind {
+            FileType::Directory => {
+                mkdirat(dir, name, Mode::from_bits_truncate(0o700))?;
+                self.summary.directories += 1;
+                let fd = openat(
+                    dir,
+                    name,
+                    OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC,
+                    Mode::empty(),
+                )?;
+                // openat just returned it, and nothing else owns it
+                let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+                self.fill_dir(fd.as_raw_fd(), rel, path, &mut dinode, ino, depth);
+                return self.set_metadata(Created::Fd(fd.as_raw_fd()), &mut dinode, &attr);
+            }
+            FileType::RegularFile => {
+                let fd = openat(
+                    dir,
+                    name,
+                    OFlag::O_WRONLY
+                        | OFlag::O_CREAT
+                        | OFlag::O_EXCL
+                        | OFlag::O_NOFOLLOW
+                        | OFlag::O_CLOEXEC,
+                    perm,
+                )?;
+                // openat just returned it, and nothing else owns it
+                let f = unsafe { fs::File::from_raw_fd(fd) };
+                self.summary.files += 1;
+                self.copy_sparse(&dinode, &f)?;
+                self.set_metadata(Created::Fd(f.as_raw_fd()), &mut dinode, &attr)?;
+            }
+            FileType::Symlink => {
+                self.device.set_bufsize(self.sb.sb_blocksize as usize);
+                let target = dinode
+                    .get_link_data(self.device.by_ref(), &self.sb)
+                    .map_err(io::Error::from_raw_os_error)?;
+                symlinkat(target.as_c_str(), Some(dir), name)?;
+                self.summary.files += 1;
+                self.set_metadata(Created::At(dir, name), &mut dinode, &attr)?;
+            }
+            FileType::BlockDevice | FileType::CharDevice if !self.devices => {
+                self.summary.skipped += 1;
+                return Ok(());
+            }
+            FileType::BlockDevice | FileType::CharDevice | FileType::NamedPipe => {
+                let kind = match attr.kind {
+                    FileType::BlockDevice => SFlag::S_IFBLK,
+                    FileType::CharDevice => SFlag::S_IFCHR,
+                    _ => SFlag::S_IFIFO,
+                };
+                mknodat(dir, name, kind, perm, libc::dev_t::from(attr.rdev))?;
+                self.summary.files += 1;
+                self.set_metadata(Created::At(dir, name), &mut dinode, &attr)?;
+            }
+            // A socket is useless without the process that was listening on it
+            FileType::Socket => {
+                self.summary.skipped += 1;
+                return Ok(());
+            }
+        }
+        if attr.nlink > 1 {
+            self.links.insert(ino, rel.to_owned());
+        }
+        Ok(())
+    }
+
+    /// Extract all of a directory's entries into `fd`, which is `rel` relative to the
+    /// destination
+    fn fill_dir(
+        &mut self,
+        fd: RawFd,
+        rel: &Path,
+        path: &Path,
+        dinode: &mut Dinode,
+        ino: XfsIno,
+        depth: usize,
+    ) {
+        if let Err(msg) = self.visited.enter(ino, path, depth) {
+            error!("{}", msg);
+            self.summary.errors += 1;
+            return;
+        }
+        let ents = match read_dir(&mut self.device, &self.sb, dinode) {
+            Ok(ents) => ents,
+            Err(e) => {
+                error!("{}: {}", path.display(), io::Error::from_raw_os_error(e));
+                self.summary.errors += 1;
+                return;
+            }
+        };
+        for (name, child) in ents {
+            let child_path = path.join(&name);
+            if is_unsafe_name(&name) {
+                error!("{}: unsafe name {:?}", path.display(), name);
+                self.summary.errors += 1;
+                continue;
+            }
+            self.extract(fd, rel, &name, &child_path, child, depth + 1);
+        }
+    }
+
+    /// Write a regular file's data to `f`, leaving its holes as holes
+    fn copy_sparse(&mut self, dinode: &Dinode, f: &fs::File) -> io::Result<()> {
+        self.device.set_bufsize(self.sb.sb_blocksize as usize);
+        let file = dinode
+            .get_file(self.device.by_ref())
+            .map_err(io::Error::from_raw_os_error)?;
+        let size = u64::try_from(file.size()).unwrap_or(0);
+        let mut offset = 0;
+        while offset < size {
+            let data = match file.lseek(self.device.by_ref(), &self.sb, offset, libc::SEEK_DATA) {
+                Ok(data) => data,
+                Err(libc::ENXIO) => break,
+                Err(e) => return Err(io::Error::from_raw_os_error(e)),
+            };
+            let hole = file
+                .lseek(self.device.by_ref(), &self.sb, data, libc::SEEK_HOLE)
+                .map_err(io::Error::from_raw_os_error)?;
+            offset = data;
+            while offset < hole {
+                let len = CHUNK.min(u32::try_from(hole - offset).unwrap_or(CHUNK));
+                let (v, skip) = file
+                    .read(self.device.by_ref(), &self.sb, offset as i64, len)
+                    .map_err(io::Error::from_raw_os_error)?;
+                let v = &v[skip..];
+                if v.is_empty() {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+                }
+                f.write_all_at(v, offset)?;
+                offset += v.len() as u64;
+                self.summary.bytes += v.len() as u64;
+            }
+        }
+        f.set_len(size)
+    }
+
+    /// Copy a file's extended attributes, if it's open, and then its owner, permissions, and
+    /// timestamps, in that order so that neither changing the owner nor writing the attributes
+    /// can disturb the rest.
+    fn set_metadata(
+        &mut self,
+        created: Created<'_>,
+        dinode: &mut Dinode,
+        attr: &FileAttr,
+    ) -> io::Result<()> {
+        if let Created::Fd(fd) = created {
+            self.set_xattrs(fd, dinode)?;
+        }
+        if self.owner {
+            let (uid, gid) = (Some(Uid::from_raw(attr.uid)), Some(Gid::from_raw(attr.gid)));
+            match created {
+                Created::Fd(fd) => fchown(fd, uid, gid)?,
+                Created::At(dir, name) => {
+                    fchownat(Some(dir), name, uid, gid, FchownatFlags::NoFollowSymlink)?
+                }
+            }
+        }
+        let mut perm = libc::mode_t::from(attr.perm & 0o7777);
+        if !self.owner {
+            perm &= !(libc::S_ISUID | libc::S_ISGID);
+        }
+        let mode = Mode::from_bits_truncate(perm);
+        match created {
+            Created::Fd(fd) => fchmod(fd, mode)?,
+            // Symlinks' permissions mean nothing
+            Created::At(..) if attr.kind == FileType::Symlink => (),
+            Created::At(dir, name) => {
+                fchmodat(Some(dir), name, mode, FchmodatFlags::FollowSymlink)?
+            }
+        }
+        let (atime, mtime) = (timespec(attr.atime), timespec(attr.mtime));
+        match created {
+            Created::Fd(fd) => futimens(fd, &atime, &mtime)?,
+            Created::At(dir, name) => utimensat(
+                Some(dir),
+                name,
+                &atime,
+                &mtime,
+                UtimensatFlags::NoFollowSymlink,
+            )?,
+        }
+        Ok(())
+    }
+
+    /// Copy all of a file's extended attributes.  Those outside of the user namespace need
+    /// privileges to set, so they're skipped unless copying owners too.
+    fn set_xattrs(&mut self, fd: RawFd, dinode: &mut Dinode) -> io::Result<()> {
+        self.device.set_bufsize(self.sb.sb_blocksize as usize);
+        let Some(attrs) = dinode.get_attrs(self.device.by_ref(), &self.sb) else {
+            return Ok(());
+        };
+        let names = attrs.list(self.device.by_ref(), &self.sb);
+        for name in names.split(|c| *c == 0).filter(|name| !name.is_empty()) {
+            if !self.owner && !name.starts_with(b"user.") {
+                self.summary.skipped += 1;
+                continue;
+            }
+            let name = OsStr::from_bytes(name);
+            let value = attrs
+                .get(self.device.by_ref(), &self.sb, name)
+                .map_err(io::Error::from_raw_os_error)?;
+            set_xattr(fd, name, &value)
+                .map_err(|e| io::Error::new(e.kind(), format!("{:?}: {}", name, e)))?;
+        }
+        Ok(())
+    }
+}
+
+/// Without mounting the file system, copy the file or directory tree at `src` into the local
+/// directory `dest`, preserving permissions, timestamps, extended attributes, hard links, and
+/// holes.  Like `cp -R`, it's extracted as a new entry in `dest`, named like `src`, except that
+/// the root's entries are extracted straight into `dest`.  Block and character devices are
+/// created only if `devices`, and owners and privileged attributes copied only if `owner`.
+/// Return what was extracted, including how many files failed.
+pub fn extract(
+    device: &Path,
+    src: &Path,
+    dest: &Path,
+    devices: bool,
+    owner: bool,
+    no_mmap: bool,
+) -> io::Result<Summary> {
+    if (devices || owner) && !Uid::effective().is_root() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "creating devices and changing owners require root",
+        ));
+    }
+    let opts = Options {
+        no_mmap,
+        ..Default::default()
+    };
+    let vol = Volume::open(device, opts)?;
+    let dest = fs::File::open(dest)
+        .and_then(|f| {
+            if f.metadata()?.is_dir() {
+                Ok(f)
+            } else {
+                Err(io::Error::from_raw_os_error(libc::ENOTDIR))
+            }
+        })
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", dest.display(), e)))?;
+    let mut ex = Extractor {
+        sb: vol.sb,
+        device: vol.device,
+        dest: OwnedFd::from(dest),
+        devices,
+        owner,
+        visited: Visited::new(DEFAULT_MAX_DEPTH),
+        links: HashMap::new(),
+        summary: Summary::default(),
+    };
+
+    let (path, ino) = resolve(&mut ex.device, &ex.sb, src)?;
+    let dest = ex.dest.as_raw_fd();
+    match path.file_name() {
+        Some(name) => ex.extract(dest, Path::new(""), name, &path, ino, 0),
+        None => {
+            ex.device.set_bufsize(ex.sb.inode_size());
+            let mut root = Dinode::from(ex.device.by_ref(), &ex.sb, ino)
+                .map_err(io::Error::from_raw_os_error)?;
+            ex.fill_dir(dest, Path::new(""), &path, &mut root, ino, 0);
+        }
+    }
+    Ok(ex.summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case::plain("hello.txt", false)]
+    #[case::dots("...", false)]
+    #[case::empty("", true)]
+    #[case::dot(".", true)]
+    #[case::dotdot("..", true)]
+    #[case::slash("../../etc/passwd", true)]
+    #[case::nul("a\0b", true)]
+    fn unsafe_name(#[case] name: &str, #[case] expected: bool) {
+        assert_eq!(is_unsafe_name(OsStr::new(name)), expected);
+    }
+
+    /// Times before the Epoch round towards negative infinity, like stat(2) reports them
+    #[rstest]
+    #[case::after(UNIX_EPOCH + Duration::new(5, 7), TimeSpec::new(5, 7))]
+    #[case::before(UNIX_EPOCH - Duration::new(5, 7), TimeSpec::new(-6, 999_999_993))]
+    fn timespec_epoch(#[case] t: SystemTime, #[case] expected: TimeSpec) {
+        assert_eq!(timespec(t), expected);
+    }
+}
@@ -27,32 +27,129 @@
  */
 use std::{
     cmp::min,
-    io::{BufRead, Seek, SeekFrom},
+    io::{BufRead, Read, Seek, SeekFrom},
 };
 
 use bincode::de::read::Reader;
+use tracing::error;
 
 use super::{
-    definitions::{XfsFileoff, XfsFsblock, XfsFsize},
-    volume::SUPERBLOCK,
+    bmbt_rec::Mapping,
+    definitions::{XfsFileoff, XfsFsize},
+    sb::Sb,
 };
 
+/// The realtime device, where realtime files keep their data.  Only file data is ever read from it.
+pub trait RtDevice: Read + Seek {}
+
+impl<T: Read + Seek> RtDevice for T {}
+
+/// Where one piece of a file's data comes from
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Piece {
+    /// A hole or an unwritten extent, this many bytes long, which reads as zeros
+    Zeros(usize),
+    /// This many bytes at this offset of the data device
+    Data(u64, usize),
+    /// This many bytes at this offset of the realtime device
+    Rt(u64, usize),
+}
+
+/// A read whose blocks have all been mapped, so that it needs nothing more from the file's
+/// metadata
+#[derive(Debug, Default)]
+pub struct ReadPlan {
+    pieces: Vec<Piece>,
+    /// How many of the pieces' bytes to return, including `skip`
+    len:    usize,
+    /// How many bytes at the head of the data the caller should ignore
+    skip:   usize,
+}
+
+impl ReadPlan {
+    /// Read the planned data from `device`, or for a realtime file from `rtdev`.  Like
+    /// [`File::read`], return the data plus a number of bytes to ignore from its head.
+    pub fn execute<D: Read + Seek>(
+        &self,
+        device: &mut D,
+        mut rtdev: Option<&mut dyn RtDevice>,
+    ) -> Result<(Vec<u8>, usize), i32> {
+        let mut data = Vec::<u8>::with_capacity(self.pieces.iter().map(Piece::len).sum());
+        for piece in self.pieces.iter() {
+            let oldlen = data.len();
+            data.resize(oldlen + piece.len(), 0u8);
+            let (dev, ofs): (&mut dyn RtDevice, u64) = match *piece {
+                Piece::Zeros(_) => continue,
+                Piece::Data(ofs, _) => (&mut *device, ofs),
+                Piece::Rt(ofs, _) => {
+                    let Some(rtdev) = rtdev.as_deref_mut() else {
+                        error!(
+                            "Cannot read a realtime file without a realtime device.  Use --rtdev"
+                        );
+                        return Err(libc::EIO);
+                    };
+                    (rtdev, ofs)
+                }
+            };
+            dev.seek(SeekFrom::Start(ofs))
+                .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))?;
+            dev.read_exact(&mut data[oldlen..])
+                .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))?;
+        }
+        data.resize(self.len, 0);
+        Ok((data, self.skip))
+    }
+}
+
+impl Piece {
+    fn len(&self) -> usize {
+        match *self {
+            Piece::Zeros(len) | Piece::Data(_, len) | Piece::Rt(_, len) => len,
+        }
+    }
+}
+
 pub trait File<R: BufRead + Reader + Seek> {
     /// Return the extent, if any, that contains the given data block within the file.
-    /// Return its starting position as an FSblock, and its length in file system block units
-    fn get_extent(&self, buf_reader: &mut R, block: XfsFileoff) -> (Option<XfsFsblock>, u64);
+    /// Return how it is mapped, and its length in file system block units
+    fn get_extent(&self, buf_reader: &mut R, sb: &Sb, block: XfsFileoff) -> (Mapping, u64);
+
+    /// Search the file's extent map for the next hole or data region at or after `offset`,
+    /// without regard to the file's size.  A hole extends from the end of the last extent to
+    /// infinity.
+    fn lseek_extents(
+        &self,
+        buf_reader: &mut R,
+        sb: &Sb,
+        offset: u64,
+        whence: i32,
+    ) -> Result<u64, i32>;
 
-    /// Like lseek(2), but only works for SEEK_HOLE and SEEK_DATA
-    fn lseek(&self, buf_reader: &mut R, offset: u64, whence: i32) -> Result<u64, i32>;
+    /// Like lseek(2), but only works for SEEK_HOLE and SEEK_DATA.
+    ///
+    /// Like XFS on Linux and UFS and ZFS on FreeBSD, any offset at or beyond EOF is ENXIO, for
+    /// either whence.  The virtual hole at EOF is therefore only found from an offset before
+    /// EOF.  The kernel passes our answer through unchanged on both platforms.
+    fn lseek(&self, buf_reader: &mut R, sb: &Sb, offset: u64, whence: i32) -> Result<u64, i32> {
+        let size = u64::try_from(self.size()).unwrap_or(0);
+        if offset >= size {
+            return Err(libc::ENXIO);
+        }
+        match self.lseek_extents(buf_reader, sb, offset, whence)? {
+            // Blocks beyond EOF may be allocated, but they don't contain any of the file's data
+            ofs if ofs >= size && whence == libc::SEEK_DATA => Err(libc::ENXIO),
+            ofs => Ok(ofs.min(size)),
+        }
+    }
 
-    /// Perform a sector-size aligned read of the file
-    fn read_sectors(
+    /// Map a sector-size aligned range of the file to the pieces of the devices that hold it.
+    fn map_sectors(
         &self,
         buf_reader: &mut R,
+        sb: &Sb,
         offset: i64,
         mut size: usize,
-    ) -> Result<Vec<u8>, i32> {
-        let sb = SUPERBLOCK.get().unwrap();
+    ) -> Result<Vec<Piece>, i32> {
         debug_assert_eq!(
             offset & ((1i64 << sb.sb_blocklog) - 1),
             0,
@@ -68,44 +165,82 @@ pub trait File<R: BufRead + Reader + Seek> {
             size
         );
 
-        let mut data = Vec::<u8>::with_capacity(size);
+        let mut pieces = Vec::new();
 
         let mut logical_block = u64::try_from(offset >> sb.sb_blocklog).unwrap();
         let mut block_offset: u64 = 0;
 
         while size > 0 {
-            let (blk, blocks) = self.get_extent(buf_reader.by_ref(), logical_block);
+            let (blk, blocks) = self.get_extent(buf_reader.by_ref(), sb, logical_block);
             let z = usize::try_from(min(
                 u64::try_from(size).unwrap(),
                 (blocks << sb.sb_blocklog) - block_offset,
             ))
             .unwrap();
 
-            let oldlen = data.len();
-            data.resize(oldlen + z, 0u8);
-            if let Some(blk) = blk {
-                buf_reader
-                    .seek(SeekFrom::Start(sb.fsb_to_offset(blk) + block_offset))
-                    .map_err(|e| e.raw_os_error().unwrap())?;
-
-                buf_reader
-                    .read_exact(&mut data[oldlen..])
-                    .map_err(|e| e.raw_os_error().unwrap())?;
-            } else {
-                // A hole
-            }
+            pieces.push(match blk {
+                Mapping::Data(blk) if self.realtime() => {
+                    if blk.saturating_add(blocks) > sb.sb_rblocks {
+                        error!(
+                            "Realtime extent at block {} of length {} lies beyond the realtime \
+                             device's {} blocks",
+                            blk, blocks, sb.sb_rblocks
+                        );
+                        return Err(libc::EIO);
+                    }
+                    Piece::Rt(sb.rtb_to_offset(blk) + block_offset, z)
+                }
+                Mapping::Data(blk) => Piece::Data(sb.fsb_to_offset(blk) + block_offset, z),
+                // A hole or an unwritten extent, both of which read as zeros
+                _ => Piece::Zeros(z),
+            });
             logical_block += blocks;
             size -= z;
             block_offset = 0;
         }
 
-        Ok(data)
+        Ok(pieces)
     }
 
     /// Return from a file.  Return a buffer containing the requested data, plus a number of bytes
     /// that the caller should ignore from the head of the vector.
-    fn read(&self, buf_reader: &mut R, offset: i64, size: u32) -> Result<(Vec<u8>, usize), i32> {
-        let sb = SUPERBLOCK.get().unwrap();
+    fn read(
+        &self,
+        buf_reader: &mut R,
+        sb: &Sb,
+        offset: i64,
+        size: u32,
+    ) -> Result<(Vec<u8>, usize), i32> {
+        self.read_rt(buf_reader, None, sb, offset, size)
+    }
+
+    /// Like `read`, but read a realtime file's data from `rtdev`.  Without one, reading a
+    /// realtime file's data fails with EIO.
+    fn read_rt(
+        &self,
+        buf_reader: &mut R,
+        rtdev: Option<&mut dyn RtDevice>,
+        sb: &Sb,
+        offset: i64,
+        size: u32,
+    ) -> Result<(Vec<u8>, usize), i32> {
+        self.plan_read(buf_reader.by_ref(), sb, offset, size)?
+            .execute(buf_reader, rtdev)
+    }
+
+    /// Map the blocks that a read would need, without reading them.  The plan can then be
+    /// executed without the file, even by another thread.
+    fn plan_read(
+        &self,
+        buf_reader: &mut R,
+        sb: &Sb,
+        offset: i64,
+        size: u32,
+    ) -> Result<ReadPlan, i32> {
+        if offset >= self.size() {
+            // The kernel shouldn't read at or beyond EOF, but if it does, return EOF
+            return Ok(ReadPlan::default());
+        }
         let size = u32::try_from(i64::from(size).min(self.size() - offset)).unwrap();
 
         let block_offset = usize::try_from(offset & ((1i64 << sb.sb_blocklog) - 1)).unwrap();
@@ -117,10 +252,16 @@ pub trait File<R: BufRead + Reader + Seek> {
             size_with_leader
         };
         let actual_offset = offset - i64::try_from(block_offset).unwrap();
-        let mut v = self.read_sectors(buf_reader, actual_offset, actual_size)?;
-        v.resize(size_with_leader, 0);
-        Ok((v, block_offset))
+        let pieces = self.map_sectors(buf_reader, sb, actual_offset, actual_size)?;
+        Ok(ReadPlan {
+            pieces,
+            len: size_with_leader,
+            skip: block_offset,
+        })
     }
 
     fn size(&self) -> XfsFsize;
+
+    /// Is the file's data on the realtime device?
+    fn realtime(&self) -> bool;
 }
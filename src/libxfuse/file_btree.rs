@@ -30,31 +30,107 @@ use std::io::{BufRead, Seek};
 use bincode::de::read::Reader;
 
 use super::{
+    bmbt_rec::Mapping,
     btree::{Btree, BtreeRoot},
-    definitions::{XfsFileoff, XfsFsblock, XfsFsize},
+    definitions::{XfsFileoff, XfsFsize},
     file::File,
-    volume::SUPERBLOCK,
+    sb::Sb,
 };
 
 #[derive(Debug)]
 pub struct FileBtree {
-    pub btree: BtreeRoot,
-    pub size:  XfsFsize,
+    pub btree:    BtreeRoot,
+    pub size:     XfsFsize,
+    /// Are the file's extents on the realtime device?  Its btree blocks never are.
+    pub realtime: bool,
 }
 
 impl<R: BufRead + Reader + Seek> File<R> for FileBtree {
-    fn get_extent(&self, buf_reader: &mut R, block: XfsFileoff) -> (Option<XfsFsblock>, u64) {
-        let sb = SUPERBLOCK.get().unwrap();
-        let (start, len) = self.btree.map_block(buf_reader.by_ref(), block).unwrap();
-        let len = len.unwrap_or((self.size as u64).div_ceil(sb.sb_blocksize.into()) - block);
+    fn get_extent(&self, buf_reader: &mut R, sb: &Sb, block: XfsFileoff) -> (Mapping, u64) {
+        let (start, len) = self
+            .btree
+            .map_block(buf_reader.by_ref(), sb, block)
+            .unwrap();
+        let len = len.unwrap_or(
+            (self.size as u64)
+                .div_ceil(sb.sb_blocksize.into())
+                .saturating_sub(block),
+        );
         (start, len)
     }
 
-    fn lseek(&self, buf_reader: &mut R, offset: u64, whence: i32) -> Result<u64, i32> {
-        self.btree.lseek(buf_reader, offset, whence)
+    fn lseek_extents(
+        &self,
+        buf_reader: &mut R,
+        sb: &Sb,
+        offset: u64,
+        whence: i32,
+    ) -> Result<u64, i32> {
+        self.btree.lseek(buf_reader, sb, offset, whence)
     }
 
     fn size(&self) -> XfsFsize {
         self.size
     }
+
+    fn realtime(&self) -> bool {
+        self.realtime
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::libxfuse::{
+        bmbt_rec::{tests::superblock, BmbtRec},
+        btree::tests::single_leaf,
+    };
+
+    /// A fully preallocated file, whose unwritten extents are stored in a btree.  They point at
+    /// the leaf block itself, so reading them from disk would return garbage.
+    fn unwritten() -> (FileBtree, impl BufRead + Reader + Seek) {
+        let recs = (0..4)
+            .map(|i| BmbtRec {
+                br_startoff:   2 * i,
+                br_startblock: 1,
+                br_blockcount: 2,
+                br_flag:       true,
+            })
+            .collect::<Vec<_>>();
+        let (btree, br) = single_leaf(&recs);
+        let file = FileBtree {
+            btree,
+            size: 8 * XfsFsize::from(superblock().sb_blocksize),
+            realtime: false,
+        };
+        (file, br)
+    }
+
+    #[test]
+    fn read_unwritten() {
+        let bs = superblock().sb_blocksize;
+        let (file, mut br) = unwritten();
+        let (v, skip) = file.read(&mut br, superblock(), 0, 8 * bs).unwrap();
+        assert_eq!(v.len() - skip, 8 * bs as usize);
+        assert!(v[skip..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn lseek_unwritten() {
+        let bs = u64::from(superblock().sb_blocksize);
+        let (file, mut br) = unwritten();
+        assert_eq!(
+            file.lseek(&mut br, superblock(), 0, libc::SEEK_DATA),
+            Err(libc::ENXIO)
+        );
+        assert_eq!(file.lseek(&mut br, superblock(), 0, libc::SEEK_HOLE), Ok(0));
+        assert_eq!(
+            file.lseek(&mut br, superblock(), 5 * bs + 1, libc::SEEK_DATA),
+            Err(libc::ENXIO)
+        );
+        assert_eq!(
+            file.lseek(&mut br, superblock(), 5 * bs + 1, libc::SEEK_HOLE),
+            Ok(5 * bs + 1)
+        );
+    }
 }
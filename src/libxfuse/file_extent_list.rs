@@ -30,31 +30,239 @@ use std::io::{BufRead, Seek};
 use bincode::de::read::Reader;
 
 use super::{
-    bmbt_rec::Bmx,
-    definitions::{XfsFileoff, XfsFsblock, XfsFsize},
+    bmbt_rec::{Bmx, Mapping},
+    definitions::{XfsFileoff, XfsFsize},
     file::File,
-    volume::SUPERBLOCK,
+    sb::Sb,
 };
 
 #[derive(Debug)]
 pub struct FileExtentList {
-    pub bmx:  Bmx,
-    pub size: XfsFsize,
+    pub bmx:      Bmx,
+    pub size:     XfsFsize,
+    /// Are the file's extents on the realtime device?
+    pub realtime: bool,
 }
 
 impl<R: BufRead + Reader + Seek> File<R> for FileExtentList {
-    fn get_extent(&self, _buf_reader: &mut R, block: XfsFileoff) -> (Option<XfsFsblock>, u64) {
-        let sb = SUPERBLOCK.get().unwrap();
+    fn get_extent(&self, _buf_reader: &mut R, sb: &Sb, block: XfsFileoff) -> (Mapping, u64) {
         let (start, len) = self.bmx.get_extent(block);
-        let len = len.unwrap_or((self.size as u64).div_ceil(sb.sb_blocksize.into()) - block);
+        let len = len.unwrap_or(
+            (self.size as u64)
+                .div_ceil(sb.sb_blocksize.into())
+                .saturating_sub(block),
+        );
         (start, len)
     }
 
-    fn lseek(&self, _buf_reader: &mut R, offset: u64, whence: i32) -> Result<u64, i32> {
-        self.bmx.lseek(offset, whence)
+    fn lseek_extents(
+        &self,
+        _buf_reader: &mut R,
+        sb: &Sb,
+        offset: u64,
+        whence: i32,
+    ) -> Result<u64, i32> {
+        self.bmx.lseek(sb, offset, whence)
     }
 
     fn size(&self) -> XfsFsize {
         self.size
     }
+
+    fn realtime(&self) -> bool {
+        self.realtime
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, Cursor};
+
+    use rstest::rstest;
+
+    use super::*;
+    use crate::libxfuse::bmbt_rec::{tests::superblock, BmbtRec};
+
+    const GIB: u64 = 1 << 30;
+    const DATA: &[u8; 16] = b"0123456789abcdef";
+
+    /// A sparse 5 GiB file, whose only data is one block at 4 GiB + 1 block
+    fn sparse() -> (FileExtentList, BufReader<Cursor<Vec<u8>>>) {
+        let bs = u64::from(superblock().sb_blocksize);
+        let mut image = vec![0u8; 3 * bs as usize];
+        image[2 * bs as usize..2 * bs as usize + DATA.len()].copy_from_slice(DATA);
+        let bmx = Bmx::new(&[BmbtRec {
+            br_startoff:   (4 * GIB + bs) / bs,
+            br_startblock: 2,
+            br_blockcount: 1,
+            br_flag:       false,
+        }]);
+        let file = FileExtentList {
+            bmx,
+            size: 5 * GIB as XfsFsize,
+            realtime: false,
+        };
+        (file, BufReader::new(Cursor::new(image)))
+    }
+
+    /// Offsets beyond 4 GiB must not be truncated anywhere along the read path
+    #[test]
+    fn read_above_4gib() {
+        let bs = u64::from(superblock().sb_blocksize);
+        let (file, mut br) = sparse();
+
+        let (v, skip) = file
+            .read(&mut br, superblock(), (4 * GIB + bs) as i64, 16)
+            .unwrap();
+        assert_eq!(&v[skip..], DATA);
+        let (v, skip) = file
+            .read(&mut br, superblock(), (4 * GIB + bs + 4) as i64, 4)
+            .unwrap();
+        assert_eq!(&v[skip..], &DATA[4..8]);
+        // The holes on either side
+        let (v, skip) = file
+            .read(&mut br, superblock(), (4 * GIB) as i64, 16)
+            .unwrap();
+        assert_eq!(&v[skip..], &[0u8; 16]);
+        let (v, skip) = file
+            .read(&mut br, superblock(), (5 * GIB - 16) as i64, 16)
+            .unwrap();
+        assert_eq!(&v[skip..], &[0u8; 16]);
+    }
+
+    #[test]
+    fn lseek_above_4gib() {
+        let bs = u64::from(superblock().sb_blocksize);
+        let (file, mut br) = sparse();
+
+        assert_eq!(
+            file.lseek(&mut br, superblock(), 0, libc::SEEK_DATA),
+            Ok(4 * GIB + bs)
+        );
+        assert_eq!(file.lseek(&mut br, superblock(), 0, libc::SEEK_HOLE), Ok(0));
+        assert_eq!(
+            file.lseek(&mut br, superblock(), 4 * GIB + bs + 8, libc::SEEK_DATA),
+            Ok(4 * GIB + bs + 8)
+        );
+        assert_eq!(
+            file.lseek(&mut br, superblock(), 4 * GIB + bs, libc::SEEK_HOLE),
+            Ok(4 * GIB + 2 * bs)
+        );
+        assert_eq!(
+            file.lseek(&mut br, superblock(), 4 * GIB + 2 * bs, libc::SEEK_DATA),
+            Err(libc::ENXIO)
+        );
+    }
+
+    /// A file of one block plus 100 bytes, whose single extent spans two blocks, followed by a
+    /// stray allocated block after EOF
+    fn short() -> (FileExtentList, BufReader<Cursor<Vec<u8>>>) {
+        let bs = u64::from(superblock().sb_blocksize);
+        let bmx = Bmx::new(&[
+            BmbtRec {
+                br_startoff:   0,
+                br_startblock: 0,
+                br_blockcount: 2,
+                br_flag:       false,
+            },
+            BmbtRec {
+                br_startoff:   4,
+                br_startblock: 2,
+                br_blockcount: 1,
+                br_flag:       false,
+            },
+        ]);
+        let file = FileExtentList {
+            bmx,
+            size: (bs + 100) as XfsFsize,
+            realtime: false,
+        };
+        (
+            file,
+            BufReader::new(Cursor::new(vec![0u8; 3 * bs as usize])),
+        )
+    }
+
+    /// Offsets at or beyond EOF are ENXIO for either whence, and no result lies beyond EOF
+    #[rstest]
+    #[case::data_at_start(0, libc::SEEK_DATA, Ok(0))]
+    #[case::virtual_hole(0, libc::SEEK_HOLE, Ok(4196))]
+    #[case::virtual_hole_last_byte(4195, libc::SEEK_HOLE, Ok(4196))]
+    #[case::data_last_byte(4195, libc::SEEK_DATA, Ok(4195))]
+    #[case::eof_data(4196, libc::SEEK_DATA, Err(libc::ENXIO))]
+    #[case::eof_hole(4196, libc::SEEK_HOLE, Err(libc::ENXIO))]
+    #[case::beyond_eof_data(4197, libc::SEEK_DATA, Err(libc::ENXIO))]
+    #[case::beyond_eof_hole(4197, libc::SEEK_HOLE, Err(libc::ENXIO))]
+    #[case::stray_block(5 * 4096, libc::SEEK_DATA, Err(libc::ENXIO))]
+    fn lseek_eof(#[case] offset: u64, #[case] whence: i32, #[case] expected: Result<u64, i32>) {
+        let (file, mut br) = short();
+        assert_eq!(file.lseek(&mut br, superblock(), offset, whence), expected);
+    }
+
+    /// Reads at or beyond EOF return nothing
+    #[rstest]
+    #[case::eof(4196)]
+    #[case::beyond_eof(8192)]
+    fn read_eof(#[case] offset: i64) {
+        let (file, mut br) = short();
+        let (v, skip) = file.read(&mut br, superblock(), offset, 4096).unwrap();
+        assert!(v[skip..].is_empty());
+    }
+
+    /// A file, its data device, its realtime device, and their superblock
+    type RtFixture = (
+        FileExtentList,
+        BufReader<Cursor<Vec<u8>>>,
+        Cursor<Vec<u8>>,
+        Sb,
+    );
+
+    /// A realtime file whose only extent is block 1 of a 2-block realtime device.  The data
+    /// device has different contents at the same block.
+    fn realtime() -> RtFixture {
+        let bs = u64::from(superblock().sb_blocksize);
+        let mut sb = superblock().clone();
+        sb.sb_rblocks = 2;
+        let mut rtdev = vec![0u8; 2 * bs as usize];
+        rtdev[bs as usize..bs as usize + DATA.len()].copy_from_slice(DATA);
+        let bmx = Bmx::new(&[BmbtRec {
+            br_startoff:   0,
+            br_startblock: 1,
+            br_blockcount: 1,
+            br_flag:       false,
+        }]);
+        let file = FileExtentList {
+            bmx,
+            size: DATA.len() as XfsFsize,
+            realtime: true,
+        };
+        let br = BufReader::new(Cursor::new(vec![0xffu8; 2 * bs as usize]));
+        (file, br, Cursor::new(rtdev), sb)
+    }
+
+    #[test]
+    fn read_realtime() {
+        let (file, mut br, mut rtdev, sb) = realtime();
+        let (v, skip) = file.read_rt(&mut br, Some(&mut rtdev), &sb, 4, 8).unwrap();
+        assert_eq!(&v[skip..], &DATA[4..12]);
+    }
+
+    /// Without a realtime device, reading a realtime file must not return the data device's
+    /// blocks
+    #[test]
+    fn read_realtime_without_rtdev() {
+        let (file, mut br, _, sb) = realtime();
+        assert_eq!(file.read(&mut br, &sb, 0, 16), Err(libc::EIO));
+    }
+
+    /// An extent beyond the end of the realtime device is corrupt
+    #[test]
+    fn read_realtime_beyond_rblocks() {
+        let (file, mut br, mut rtdev, mut sb) = realtime();
+        sb.sb_rblocks = 1;
+        assert_eq!(
+            file.read_rt(&mut br, Some(&mut rtdev), &sb, 0, 16),
+            Err(libc::EIO)
+        );
+    }
 }
@@ -0,0 +1,274 @@
+/*
+ * BSD 2-Clause License
+ *
+ * Copyright (c) 2021, Khaled Emara
+ * All rights reserved.
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice, this
+ *    list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice,
+ *    this list of conditions and the following disclaimer in the documentation
+ *    and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+ * AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+ * IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+ * FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+ * SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+ * CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+ * OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+ * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ */
+//! Enumerating allocated inodes by walking each AG's inode B+tree, used by `-o inodes_dir`.
+use std::io::{self, prelude::*, SeekFrom};
+
+use super::{definitions::*, sb::Sb};
+
+/// Byte offsets of the inode btree's root and height within the AGI
+const AGI_ROOT: usize = 20;
+const AGI_LEVEL: usize = 24;
+
+/// Size of a short-format btree block header
+const SBLOCK_LEN: usize = 16;
+const SBLOCK_CRC_LEN: usize = 56;
+
+/// Size of an inobt record, and of an interior node's key and pointer
+const REC_LEN: usize = 16;
+const KEY_LEN: usize = 4;
+const PTR_LEN: usize = 4;
+
+/// Inodes per inobt record
+const INODES_PER_CHUNK: u32 = 64;
+
+/// No valid inobt is taller than this
+const MAX_LEVELS: u32 = 9;
+
+fn be32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn be16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_at<R: Read + Seek>(reader: &mut R, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+    reader.seek(SeekFrom::Start(offset))?;
+    reader.read_exact(buf)
+}
+
+/// Return every allocated inode in one AG, in ascending order.
+pub fn allocated_in_ag<R: Read + Seek>(
+    reader: &mut R,
+    sb: &Sb,
+    agno: XfsAgnumber,
+) -> io::Result<Vec<XfsIno>> {
+    let err = |msg: String| io::Error::other(format!("AG {}: {}", agno, msg));
+    let ag_block = |agbno: XfsAgblock| {
+        sb.fsb_to_offset((u64::from(agno) << sb.sb_agblklog) | u64::from(agbno))
+    };
+
+    // The AGI is the third sector of the AG
+    let mut agi = [0u8; AGI_LEVEL + 4];
+    read_at(
+        reader,
+        ag_block(0) + 2 * u64::from(sb.sb_sectsize),
+        &mut agi,
+    )?;
+    if be32(&agi, 0) != XFS_AGI_MAGIC {
+        return Err(err(format!("bad AGI magic {:#x}", be32(&agi, 0))));
+    }
+    let mut agbno = be32(&agi, AGI_ROOT);
+    let levels = be32(&agi, AGI_LEVEL);
+    if levels == 0 || levels > MAX_LEVELS {
+        return Err(err(format!("invalid inobt height {}", levels)));
+    }
+
+    let (magic, hdrlen) = if sb.version() == 5 {
+        (XFS_IBT_CRC_MAGIC, SBLOCK_CRC_LEN)
+    } else {
+        (XFS_IBT_MAGIC, SBLOCK_LEN)
+    };
+    let blocksize = sb.sb_blocksize as usize;
+    let read_block = |reader: &mut R, agbno: XfsAgblock, level: u32| -> io::Result<Vec<u8>> {
+        if agbno >= sb.sb_agblocks {
+            return Err(err(format!("inobt block {} is beyond the AG", agbno)));
+        }
+        let mut block = vec![0u8; blocksize];
+        read_at(reader, ag_block(agbno), &mut block)?;
+        if be32(&block, 0) != magic {
+            return Err(err(format!("inobt block {} has bad magic", agbno)));
+        }
+        if u32::from(be16(&block, 4)) != level {
+            return Err(err(format!("inobt block {} has the wrong level", agbno)));
+        }
+        Ok(block)
+    };
+
+    // Descend the left edge of the tree to the first leaf
+    for level in (1..levels).rev() {
+        let node = read_block(reader, agbno, level)?;
+        if be16(&node, 6) == 0 {
+            return Err(err(format!("inobt node {} is empty", agbno)));
+        }
+        let maxrecs = (blocksize - hdrlen) / (KEY_LEN + PTR_LEN);
+        agbno = be32(&node, hdrlen + maxrecs * KEY_LEN);
+    }
+
+    // Then walk the leaves through their right sibling pointers
+    let mut inos = Vec::new();
+    let maxleaves = sb.sb_agblocks;
+    let mut nleaves = 0;
+    while agbno != NULLAGBLOCK {
+        nleaves += 1;
+        if nleaves > maxleaves {
+            return Err(err("inobt leaves form a loop".to_string()));
+        }
+        let leaf = read_block(reader, agbno, 0)?;
+        let numrecs = usize::from(be16(&leaf, 6));
+        if hdrlen + numrecs * REC_LEN > blocksize {
+            return Err(err(format!("inobt leaf {} has too many records", agbno)));
+        }
+        for rec in leaf[hdrlen..hdrlen + numrecs * REC_LEN].chunks_exact(REC_LEN) {
+            let startino = be32(rec, 0);
+            // Holes in sparse inode chunks are also marked free
+            let free = u64::from_be_bytes(rec[8..16].try_into().unwrap());
+            inos.extend(
+                (0..INODES_PER_CHUNK)
+                    .filter(|i| free & (1 << i) == 0)
+                    .map(|i| sb.agino_to_ino(agno, startino + i)),
+            );
+        }
+        agbno = be32(&leaf, 12);
+    }
+    Ok(inos)
+}
+
+/// Return every allocated inode in the file system, in ascending order.
+pub fn allocated<R: Read + Seek>(reader: &mut R, sb: &Sb) -> io::Result<Vec<XfsIno>> {
+    let mut inos = Vec::new();
+    for agno in 0..sb.sb_agcount {
+        inos.extend(allocated_in_ag(reader, sb, agno)?);
+    }
+    Ok(inos)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use rstest::rstest;
+
+    use super::*;
+
+    /// Two AGs of 16 4 KiB blocks, with 8 inodes per block
+    fn sb(version: u16) -> Sb {
+        Sb::for_test(version, 2, 16, 32)
+    }
+
+    /// Write an inobt block, with `body` following the header
+    fn btree_block(
+        img: &mut [u8],
+        sb: &Sb,
+        offset: usize,
+        level: u16,
+        numrecs: u16,
+        rightsib: XfsAgblock,
+        body: &[u8],
+    ) {
+        let (magic, hdrlen) = if sb.version() == 5 {
+            (XFS_IBT_CRC_MAGIC, SBLOCK_CRC_LEN)
+        } else {
+            (XFS_IBT_MAGIC, SBLOCK_LEN)
+        };
+        let block = &mut img[offset..offset + sb.sb_blocksize as usize];
+        block[0..4].copy_from_slice(&magic.to_be_bytes());
+        block[4..6].copy_from_slice(&level.to_be_bytes());
+        block[6..8].copy_from_slice(&numrecs.to_be_bytes());
+        block[8..12].copy_from_slice(&NULLAGBLOCK.to_be_bytes());
+        block[12..16].copy_from_slice(&rightsib.to_be_bytes());
+        block[hdrlen..hdrlen + body.len()].copy_from_slice(body);
+    }
+
+    fn rec(startino: XfsAgino, free: u64) -> Vec<u8> {
+        let mut rec = startino.to_be_bytes().to_vec();
+        rec.extend_from_slice(&[0; 4]);
+        rec.extend_from_slice(&free.to_be_bytes());
+        rec
+    }
+
+    /// AG 0 has a two-level inobt with two leaves, and AG 1 has a single leaf.
+    fn image(sb: &Sb) -> Vec<u8> {
+        let mut img = vec![0u8; (sb.sb_dblocks << sb.sb_blocklog) as usize];
+        let bs = sb.sb_blocksize as usize;
+        let agsize = bs * sb.sb_agblocks as usize;
+        for (agno, root, level) in [(0usize, 4u32, 2u32), (1, 4, 1)] {
+            let agi = &mut img[agno * agsize + 1024..agno * agsize + 1536];
+            agi[0..4].copy_from_slice(&XFS_AGI_MAGIC.to_be_bytes());
+            agi[AGI_ROOT..AGI_ROOT + 4].copy_from_slice(&root.to_be_bytes());
+            agi[AGI_LEVEL..AGI_LEVEL + 4].copy_from_slice(&level.to_be_bytes());
+        }
+
+        let hdrlen = if sb.version() == 5 {
+            SBLOCK_CRC_LEN
+        } else {
+            SBLOCK_LEN
+        };
+        let maxrecs = (bs - hdrlen) / (KEY_LEN + PTR_LEN);
+        let mut node = vec![0u8; maxrecs * (KEY_LEN + PTR_LEN)];
+        node[0..4].copy_from_slice(&64u32.to_be_bytes());
+        node[4..8].copy_from_slice(&128u32.to_be_bytes());
+        let ptrs = maxrecs * KEY_LEN;
+        node[ptrs..ptrs + 4].copy_from_slice(&5u32.to_be_bytes());
+        node[ptrs + 4..ptrs + 8].copy_from_slice(&6u32.to_be_bytes());
+        btree_block(&mut img, sb, 4 * bs, 1, 2, NULLAGBLOCK, &node);
+        btree_block(&mut img, sb, 5 * bs, 0, 1, 6, &rec(64, !0b101));
+        btree_block(&mut img, sb, 6 * bs, 0, 1, NULLAGBLOCK, &rec(128, !0 << 32));
+        btree_block(
+            &mut img,
+            sb,
+            agsize + 4 * bs,
+            0,
+            1,
+            NULLAGBLOCK,
+            &rec(64, !(1 << 63)),
+        );
+        img
+    }
+
+    #[rstest]
+    fn allocated_inodes(#[values(4, 5)] version: u16) {
+        let sb = sb(version);
+        let mut cursor = Cursor::new(image(&sb));
+        let mut expected = vec![64, 66];
+        expected.extend(128..160);
+        expected.push(sb.agino_to_ino(1, 127));
+        assert_eq!(allocated(&mut cursor, &sb).unwrap(), expected);
+    }
+
+    /// A sibling pointer loop must not hang
+    #[test]
+    fn leaf_loop() {
+        let sb = sb(5);
+        let mut img = image(&sb);
+        let bs = sb.sb_blocksize as usize;
+        img[6 * bs + 12..6 * bs + 16].copy_from_slice(&5u32.to_be_bytes());
+        let e = allocated_in_ag(&mut Cursor::new(img), &sb, 0).unwrap_err();
+        assert!(e.to_string().contains("loop"), "{}", e);
+    }
+
+    #[test]
+    fn bad_magic() {
+        let sb = sb(4);
+        let mut img = image(&sb);
+        let bs = sb.sb_blocksize as usize;
+        img[5 * bs] = 0;
+        let e = allocated_in_ag(&mut Cursor::new(img), &sb, 0).unwrap_err();
+        assert!(e.to_string().contains("bad magic"), "{}", e);
+    }
+}
@@ -0,0 +1,545 @@
+/*
+ * BSD 2-Clause License
+ *
+ * Copyright (c) 2021, Khaled Emara
+ * All rights reserved.
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice, this
+ *    list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice,
+ *    this list of conditions and the following disclaimer in the documentation
+ *    and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+ * AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+ * IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+ * FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+ * SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+ * CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+ * OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+ * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ */
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    ffi::{OsStr, OsString},
+    io::{self, Read, Write},
+    num::NonZeroUsize,
+    os::unix::ffi::OsStrExt,
+    path::{Component, Path, PathBuf},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use libc::c_int;
+use tracing::error;
+
+use super::{
+    attr::Attr,
+    block_reader::BlockReader,
+    definitions::XfsIno,
+    dinode::Dinode,
+    dir3::Dir3,
+    sb::Sb,
+    stat::{FileAttr, FileType},
+    volume::{Options, Volume},
+};
+
+/// The most threads that will be used to read inodes
+const MAX_THREADS: usize = 8;
+
+/// The default for `--max-depth`
+pub const DEFAULT_MAX_DEPTH: usize = 256;
+
+/// Output formats for [`list`]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, clap::ValueEnum)]
+pub enum Format {
+    /// One JSON object per line
+    Json,
+    /// Comma-separated values, with a header line
+    Csv,
+    /// Space-separated type, size, mtime, uid, gid, nlink, and path, like find -printf
+    #[default]
+    Find,
+}
+
+/// Everything that gets reported about a single file
+#[derive(Debug)]
+struct Record {
+    /// Absolute path within the file system
+    path:   PathBuf,
+    dinode: Dinode,
+    attr:   FileAttr,
+    /// Names of all extended attributes, including their namespaces
+    xattrs: Vec<OsString>,
+}
+
+impl Record {
+    fn new(br: &mut BlockReader, sb: &Sb, path: PathBuf, ino: XfsIno) -> io::Result<Self> {
+        br.set_bufsize(sb.inode_size());
+        let mut dinode =
+            Dinode::from(br.by_ref(), sb, ino).map_err(io::Error::from_raw_os_error)?;
+        let attr = dinode
+            .di_core
+            .stat(ino)
+            .map_err(io::Error::from_raw_os_error)?;
+        br.set_bufsize(sb.sb_blocksize as usize);
+        let mut xattrs = match dinode.get_attrs(br.by_ref(), sb) {
+            Some(attrs) => attrs
+                .list(br.by_ref(), sb)
+                .split(|c| *c == 0)
+                .filter(|name| !name.is_empty())
+                .map(|name| OsStr::from_bytes(name).to_owned())
+                .collect(),
+            None => Vec::new(),
+        };
+        // Attributes are stored in hash order, which isn't very useful to a reader.
+        xattrs.sort_unstable();
+        Ok(Record {
+            path,
+            dinode,
+            attr,
+            xattrs,
+        })
+    }
+
+    /// The file's type, as reported by find's %y directive
+    fn kind(&self) -> char {
+        match self.attr.kind {
+            FileType::NamedPipe => 'p',
+            FileType::CharDevice => 'c',
+            FileType::BlockDevice => 'b',
+            FileType::Directory => 'd',
+            FileType::RegularFile => 'f',
+            FileType::Symlink => 'l',
+            FileType::Socket => 's',
+        }
+    }
+
+    /// Modification time, in whole seconds since the Epoch
+    fn mtime(&self) -> i64 {
+        unix_secs(self.attr.mtime)
+    }
+}
+
+/// Convert a time to whole seconds since the Epoch
+pub(super) fn unix_secs(t: SystemTime) -> i64 {
+    match t.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i64,
+        Err(e) => {
+            // Round towards negative infinity, like stat(2) does.
+            let d = e.duration();
+            -(d.as_secs() as i64) - i64::from(d.subsec_nanos() > 0)
+        }
+    }
+}
+
+/// Write `s` as a JSON string literal
+pub(super) fn write_json_str<W: Write>(out: &mut W, s: &OsStr) -> io::Result<()> {
+    out.write_all(b"\"")?;
+    for c in s.to_string_lossy().chars() {
+        match c {
+            '"' => out.write_all(b"\\\"")?,
+            '\\' => out.write_all(b"\\\\")?,
+            '\n' => out.write_all(b"\\n")?,
+            '\r' => out.write_all(b"\\r")?,
+            '\t' => out.write_all(b"\\t")?,
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32)?,
+            c => write!(out, "{}", c)?,
+        }
+    }
+    out.write_all(b"\"")
+}
+
+/// Write `s` as a CSV field, quoting it only if necessary
+fn write_csv_field<W: Write>(out: &mut W, s: &[u8]) -> io::Result<()> {
+    if s.iter().any(|c| matches!(c, b',' | b'"' | b'\n' | b'\r')) {
+        out.write_all(b"\"")?;
+        for chunk in s.split_inclusive(|c| *c == b'"') {
+            out.write_all(chunk)?;
+            if chunk.last() == Some(&b'"') {
+                out.write_all(b"\"")?;
+            }
+        }
+        out.write_all(b"\"")
+    } else {
+        out.write_all(s)
+    }
+}
+
+/// Guards a walk against corrupt directories.  A directory that contains one of its own
+/// ancestors would otherwise make the walk loop forever.
+#[derive(Debug)]
+pub(super) struct Visited {
+    /// Every directory descended into so far, and its path.  Directories can't be hard linked,
+    /// so seeing any of them twice means the tree is damaged.
+    dirs:      HashMap<XfsIno, PathBuf>,
+    /// Don't descend into directories this far below the walk's root
+    max_depth: usize,
+}
+
+impl Visited {
+    pub(super) fn new(max_depth: usize) -> Self {
+        Visited {
+            dirs: HashMap::new(),
+            max_depth,
+        }
+    }
+
+    /// Should the walk descend into this directory, `depth` levels below its root?  If not,
+    /// explain why.
+    pub(super) fn enter(&mut self, ino: XfsIno, path: &Path, depth: usize) -> Result<(), String> {
+        if depth >= self.max_depth {
+            return Err(format!(
+                "{}: not descending past the maximum depth of {}",
+                path.display(),
+                self.max_depth
+            ));
+        }
+        match self.dirs.entry(ino) {
+            Entry::Occupied(e) => Err(format!(
+                "cycle detected: {} is the same directory as {}",
+                path.display(),
+                e.get().display()
+            )),
+            Entry::Vacant(e) => {
+                e.insert(path.to_owned());
+                Ok(())
+            }
+        }
+    }
+}
+
+struct Lister<W: Write> {
+    sb:       Sb,
+    /// Used for reading directories
+    device:   BlockReader,
+    /// Used for reading inodes.  One per thread.
+    readers:  Vec<BlockReader>,
+    format:   Format,
+    out:      W,
+    visited:  Visited,
+    /// False if any directory was skipped
+    complete: bool,
+}
+
+impl<W: Write> Lister<W> {
+    fn emit(&mut self, r: &Record) -> io::Result<()> {
+        let out = &mut self.out;
+        match self.format {
+            Format::Json => {
+                out.write_all(b"{\"path\":")?;
+                write_json_str(out, r.path.as_os_str())?;
+                write!(
+                    out,
+                    ",\"type\":\"{}\",\"size\":{},\"mtime\":{},\"uid\":{},\"gid\":{},\"nlink\":{},\
+                     \"xattrs\":[",
+                    r.kind(),
+                    r.attr.size,
+                    r.mtime(),
+                    r.attr.uid,
+                    r.attr.gid,
+                    r.attr.nlink
+                )?;
+                for (i, name) in r.xattrs.iter().enumerate() {
+                    if i > 0 {
+                        out.write_all(b",")?;
+                    }
+                    write_json_str(out, name)?;
+                }
+                out.write_all(b"]}\n")
+            }
+            Format::Csv => {
+                write_csv_field(out, r.path.as_os_str().as_bytes())?;
+                write!(
+                    out,
+                    ",{},{},{},{},{},{},",
+                    r.kind(),
+                    r.attr.size,
+                    r.mtime(),
+                    r.attr.uid,
+                    r.attr.gid,
+                    r.attr.nlink
+                )?;
+                let xattrs = r.xattrs.join(OsStr::new(";"));
+                write_csv_field(out, xattrs.as_bytes())?;
+                out.write_all(b"\n")
+            }
+            Format::Find => {
+                write!(
+                    out,
+                    "{} {} {} {} {} {} ",
+                    r.kind(),
+                    r.attr.size,
+                    r.mtime(),
+                    r.attr.uid,
+                    r.attr.gid,
+                    r.attr.nlink
+                )?;
+                out.write_all(r.path.as_os_str().as_bytes())?;
+                out.write_all(b"\n")
+            }
+        }
+    }
+
+    /// Stat all of a directory's entries, spreading the work across all reader threads.  The
+    /// results will be in the same order as the entries.
+    fn stat_all(&mut self, parent: &Path, ents: &[(OsString, XfsIno)]) -> Vec<io::Result<Record>> {
+        let sb = &self.sb;
+        let chunksize = ents.len().div_ceil(self.readers.len()).max(1);
+        thread::scope(|s| {
+            let handles = self
+                .readers
+                .iter_mut()
+                .zip(ents.chunks(chunksize))
+                .map(|(br, chunk)| {
+                    s.spawn(move || {
+                        chunk
+                            .iter()
+                            .map(|(name, ino)| Record::new(br, sb, parent.join(name), *ino))
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .flat_map(|h| h.join().unwrap())
+                .collect()
+        })
+    }
+
+    /// List a file and, if it's a directory, all of its descendants in depth-first order.
+    /// `depth` is the number of levels below the root of the listing.
+    fn walk(&mut self, mut record: Record, depth: usize) -> io::Result<()> {
+        self.emit(&record)?;
+        if record.attr.kind != FileType::Directory {
+            return Ok(());
+        }
+        if let Err(msg) = self.visited.enter(record.attr.ino, &record.path, depth) {
+            error!("{}", msg);
+            self.complete = false;
+            return Ok(());
+        }
+        let ents = read_dir(&mut self.device, &self.sb, &mut record.dinode)
+            .map_err(io::Error::from_raw_os_error)?;
+        for child in self.stat_all(&record.path, &ents) {
+            self.walk(child?, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+/// Read all of a directory's entries, except for "." and "..", sorted by name.
+pub(super) fn read_dir(
+    device: &mut BlockReader,
+    sb: &Sb,
+    dinode: &mut Dinode,
+) -> Result<Vec<(OsString, XfsIno)>, c_int> {
+    let dirsize = sb.sb_blocksize << sb.sb_dirblklog;
+    device.set_bufsize(dirsize as usize);
+    let dir = dinode.get_dir(device.by_ref(), sb)?;
+
+    let mut ents = Vec::new();
+    let mut off = 0;
+    loop {
+        match dir.next(device.by_ref(), sb, off) {
+            Ok((ino, offset, _kind, name)) => {
+                if name != "." && name != ".." {
+                    ents.push((name, ino));
+                }
+                off = offset;
+            }
+            Err(libc::ENOENT) => break,
+            Err(e) => return Err(e),
+        }
+    }
+    ents.sort_unstable();
+    Ok(ents)
+}
+
+/// Find the inode at the given path within the file system.  Symlinks are never followed, so one
+/// before the last component is ENOTDIR.
+pub(super) fn resolve(
+    device: &mut BlockReader,
+    sb: &Sb,
+    path: &Path,
+) -> io::Result<(PathBuf, XfsIno)> {
+    let mut resolved = PathBuf::from("/");
+    let mut ino = sb.sb_rootino;
+    for component in path.components() {
+        let name = match component {
+            Component::RootDir | Component::CurDir => continue,
+            Component::Normal(name) => name,
+            Component::ParentDir | Component::Prefix(_) => {
+                return Err(io::Error::from_raw_os_error(libc::EINVAL))
+            }
+        };
+        device.set_bufsize(sb.inode_size());
+        let mut dinode =
+            Dinode::from(device.by_ref(), sb, ino).map_err(io::Error::from_raw_os_error)?;
+        let kind = get_kind(&dinode, ino)?;
+        if kind != FileType::Directory {
+            return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+        }
+        let dirsize = sb.sb_blocksize << sb.sb_dirblklog;
+        device.set_bufsize(dirsize as usize);
+        let dir = dinode
+            .get_dir(device.by_ref(), sb)
+            .map_err(io::Error::from_raw_os_error)?;
+        ino = dir
+            .lookup(device.by_ref(), sb, name)
+            .map_err(io::Error::from_raw_os_error)?;
+        resolved.push(name);
+    }
+    Ok((resolved, ino))
+}
+
+fn get_kind(dinode: &Dinode, ino: XfsIno) -> io::Result<FileType> {
+    dinode
+        .di_core
+        .stat(ino)
+        .map(|attr| attr.kind)
+        .map_err(io::Error::from_raw_os_error)
+}
+
+/// Print a recursive listing of the file system, or of the subtree rooted at `prefix`, without
+/// mounting it.  Every directory's entries are listed in sorted order, so the output is
+/// deterministic.  Directories more than `max_depth` levels below `prefix`, or that were already
+/// listed, are not descended into.  Return whether the listing is complete.
+pub fn list<W: Write>(
+    device: &Path,
+    prefix: &Path,
+    format: Format,
+    max_depth: usize,
+    no_mmap: bool,
+    out: W,
+) -> io::Result<bool> {
+    let opts = Options {
+        no_mmap,
+        ..Default::default()
+    };
+    let vol = Volume::open(device, opts)?;
+    let nthreads = thread::available_parallelism()
+        .map(NonZeroUsize::get)
+        .unwrap_or(1)
+        .min(MAX_THREADS);
+    let readers = (0..nthreads)
+        .map(|_| vol.device.try_clone())
+        .collect::<io::Result<Vec<_>>>()?;
+    let mut lister = Lister {
+        sb: vol.sb,
+        device: vol.device,
+        readers,
+        format,
+        out,
+        visited: Visited::new(max_depth),
+        complete: true,
+    };
+
+    if format == Format::Csv {
+        lister
+            .out
+            .write_all(b"path,type,size,mtime,uid,gid,nlink,xattrs\n")?;
+    }
+    let (path, ino) = resolve(&mut lister.device, &lister.sb, prefix)?;
+    let root = Record::new(&mut lister.device, &lister.sb, path, ino)?;
+    lister.walk(root, 0)?;
+    lister.out.flush()?;
+    Ok(lister.complete)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_field_plain() {
+        let mut v = Vec::new();
+        write_csv_field(&mut v, b"/files/hello.txt").unwrap();
+        assert_eq!(v, b"/files/hello.txt");
+    }
+
+    #[test]
+    fn csv_field_quoted() {
+        let mut v = Vec::new();
+        write_csv_field(&mut v, b"a,\"b\"").unwrap();
+        assert_eq!(v, b"\"a,\"\"b\"\"\"");
+    }
+
+    #[test]
+    fn json_str() {
+        let mut v = Vec::new();
+        write_json_str(&mut v, OsStr::new("a\"b\\c\n\u{1}")).unwrap();
+        assert_eq!(v, b"\"a\\\"b\\\\c\\n\\u0001\"");
+    }
+
+    /// A synthetic directory tree: each directory's entries, by inode number.  Inodes that
+    /// aren't keys are regular files.
+    type Graph = HashMap<XfsIno, Vec<(&'static str, XfsIno)>>;
+
+    /// Walk `graph` the way [`Lister::walk`] does, and return every path visited and every
+    /// diagnostic.
+    fn walk_graph(graph: &Graph, max_depth: usize) -> (Vec<String>, Vec<String>) {
+        fn walk(
+            graph: &Graph,
+            visited: &mut Visited,
+            ino: XfsIno,
+            path: PathBuf,
+            depth: usize,
+            out: &mut (Vec<String>, Vec<String>),
+        ) {
+            out.0.push(path.display().to_string());
+            let Some(ents) = graph.get(&ino) else {
+                return;
+            };
+            if let Err(msg) = visited.enter(ino, &path, depth) {
+                out.1.push(msg);
+                return;
+            }
+            for (name, child) in ents {
+                walk(graph, visited, *child, path.join(name), depth + 1, out);
+            }
+        }
+
+        let mut out = (Vec::new(), Vec::new());
+        let mut visited = Visited::new(max_depth);
+        walk(graph, &mut visited, 1, PathBuf::from("/"), 0, &mut out);
+        out
+    }
+
+    /// A directory containing its own ancestor must not make the walk loop, but hard linked
+    /// files are fine.
+    #[test]
+    fn cycle() {
+        let graph = Graph::from([
+            (1, vec![("a", 2), ("f", 10)]),
+            (2, vec![("b", 3), ("link", 10)]),
+            (3, vec![("up", 1)]),
+        ]);
+        let (paths, diags) = walk_graph(&graph, DEFAULT_MAX_DEPTH);
+        assert_eq!(paths, ["/", "/a", "/a/b", "/a/b/up", "/a/link", "/f"]);
+        assert_eq!(
+            diags,
+            ["cycle detected: /a/b/up is the same directory as /"]
+        );
+    }
+
+    /// Directories at the depth limit are listed, but not descended into
+    #[test]
+    fn max_depth() {
+        let graph = Graph::from([
+            (1, vec![("a", 2)]),
+            (2, vec![("b", 3)]),
+            (3, vec![("c", 4)]),
+            (4, vec![("d", 10)]),
+        ]);
+        let (paths, diags) = walk_graph(&graph, 2);
+        assert_eq!(paths, ["/", "/a", "/a/b"]);
+        assert_eq!(diags, ["/a/b: not descending past the maximum depth of 2"]);
+    }
+}
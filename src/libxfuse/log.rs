@@ -0,0 +1,265 @@
+/*
+ * BSD 2-Clause License
+ *
+ * Copyright (c) 2021, Khaled Emara
+ * All rights reserved.
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice, this
+ *    list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice,
+ *    this list of conditions and the following disclaimer in the documentation
+ *    and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+ * AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+ * IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+ * FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+ * SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+ * CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+ * OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+ * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ */
+//! Just enough of the XFS log to tell whether it's clean, like `xfs_repair -n` does.
+//!
+//! Reading never needs it: a dirty log only means that the newest metadata may be missing.  But
+//! writing into a file system whose log still needs recovery would be undone, or worse, when the
+//! kernel replays it.
+use std::io::{self, prelude::*, SeekFrom};
+
+use super::{definitions::*, sb::Sb};
+
+/// The log is read and stamped in basic blocks of this size, whatever its sector size
+const BBSIZE: u64 = 512;
+
+/// Byte offsets within a log record header
+const H_CYCLE: usize = 4;
+const H_VERSION: usize = 8;
+const H_LEN: usize = 12;
+const H_NUM_LOGOPS: usize = 40;
+const H_SIZE: usize = 320;
+
+/// Set in h_version by V2 logs, whose record headers may span several basic blocks
+const XLOG_VERSION_2: u32 = 2;
+/// How much of a record a single header basic block describes
+const XLOG_HEADER_CYCLE_SIZE: u32 = 32 * 1024;
+/// No log record is longer than this, in basic blocks, plus its headers
+const XLOG_MAX_RECORD_BBS: u64 = 256 * 1024 / BBSIZE;
+
+/// Byte offset of oh_flags within a log operation header
+const OH_FLAGS: usize = 9;
+/// Set in oh_flags by the record that a clean unmount writes last
+const XLOG_UNMOUNT_TRANS: u8 = 0x20;
+
+fn be32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+/// The internal log's basic blocks
+struct Log<'a, R> {
+    reader: &'a mut R,
+    /// Device offset of the first basic block
+    start:  u64,
+    /// Length in basic blocks
+    len:    u64,
+}
+
+impl<R: Read + Seek> Log<'_, R> {
+    /// Read basic block `bb`, wrapping around the end of the log.
+    fn read(&mut self, bb: u64) -> io::Result<[u8; BBSIZE as usize]> {
+        let mut buf = [0u8; BBSIZE as usize];
+        self.reader
+            .seek(SeekFrom::Start(self.start + bb % self.len * BBSIZE))?;
+        self.reader.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn is_header(buf: &[u8]) -> bool {
+        be32(buf, 0) == XLOG_HEADER_MAGIC_NUM
+    }
+
+    /// The cycle in which basic block `bb` was last written.  Record headers hold it after the
+    /// magic number, and every other block holds it in place of its first word.
+    fn cycle(&mut self, bb: u64) -> io::Result<u32> {
+        let buf = self.read(bb)?;
+        Ok(if Self::is_header(&buf) {
+            be32(&buf, H_CYCLE)
+        } else {
+            be32(&buf, 0)
+        })
+    }
+
+    /// Find the head: the first block that the current cycle hasn't overwritten yet.  Blocks
+    /// before it belong to the same cycle as block 0, and the rest to the one before.
+    fn head(&mut self) -> io::Result<u64> {
+        let first = self.cycle(0)?;
+        if self.cycle(self.len - 1)? == first {
+            return Ok(self.len);
+        }
+        let (mut lo, mut hi) = (0, self.len - 1);
+        while hi - lo > 1 {
+            let mid = lo + (hi - lo) / 2;
+            if self.cycle(mid)? == first {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        Ok(hi)
+    }
+}
+
+/// Check that the file system's log is clean, as it is after an orderly unmount: the last record
+/// before the head must be a lone unmount record.  An external log can't be checked.
+pub fn check_clean<R: Read + Seek>(reader: &mut R, sb: &Sb) -> Result<(), String> {
+    if sb.sb_logstart == 0 {
+        return Err("the log is on an external device, so it can't be checked".to_string());
+    }
+    let mut log = Log {
+        reader,
+        start: sb.fsb_to_offset(sb.sb_logstart),
+        len: u64::from(sb.sb_logblocks) * u64::from(sb.sb_blocksize) / BBSIZE,
+    };
+    let err = |e: io::Error| format!("cannot read the log: {}", e);
+    if log.len == 0 {
+        return Err("the log is empty".to_string());
+    }
+    // xfs_repair -L zeroes the log entirely, and that's clean too
+    if log.cycle(0).map_err(err)? == 0 {
+        return Ok(());
+    }
+    let head = log.head().map_err(err)?;
+    let search = log.len.min(XLOG_MAX_RECORD_BBS * 2);
+    let mut rhead = None;
+    for back in 1..=search {
+        let bb = head + log.len - back;
+        let buf = log.read(bb).map_err(err)?;
+        if Log::<R>::is_header(&buf) {
+            rhead = Some((bb, buf));
+            break;
+        }
+    }
+    let Some((rhead, header)) = rhead else {
+        return Err("the log has no record before its head".to_string());
+    };
+    let h_size = be32(&header, H_SIZE);
+    let hblks = if be32(&header, H_VERSION) & XLOG_VERSION_2 != 0 && h_size > XLOG_HEADER_CYCLE_SIZE
+    {
+        u64::from(h_size.div_ceil(XLOG_HEADER_CYCLE_SIZE))
+    } else {
+        1
+    };
+    let len_bbs = u64::from(be32(&header, H_LEN)).div_ceil(BBSIZE);
+    let op = log.read(rhead + hblks).map_err(err)?;
+    if (rhead + hblks + len_bbs) % log.len != head % log.len
+        || be32(&header, H_NUM_LOGOPS) != 1
+        || op[OH_FLAGS] & XLOG_UNMOUNT_TRANS == 0
+    {
+        return Err(
+            "the log is dirty and needs recovery; mount it with the kernel first, or repair it \
+             with xfs_repair"
+                .to_string(),
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use rstest::rstest;
+
+    use super::*;
+
+    /// Blocks in the test log, which starts at block 1
+    const LOGBLOCKS: u64 = 2;
+    const LOGBBS: u64 = LOGBLOCKS * 4096 / BBSIZE;
+
+    fn sb() -> Sb {
+        let mut sb = Sb::for_test(5, 1, 16, 16);
+        sb.sb_logstart = 1;
+        sb.sb_logblocks = LOGBLOCKS as u32;
+        sb
+    }
+
+    /// Stamp basic blocks `from..to` of the log with `cycle`, as if each had been written in it.
+    /// Beyond the end, they wrap around.
+    fn stamp(img: &mut [u8], from: u64, to: u64, cycle: u32) {
+        for bb in from..to {
+            let ofs = 4096 + (bb % LOGBBS * BBSIZE) as usize;
+            img[ofs..ofs + 4].copy_from_slice(&cycle.to_be_bytes());
+        }
+    }
+
+    /// Turn the stamped basic block `bb` into a record header, followed by one data block,
+    /// whose first operation has these flags
+    fn record(img: &mut [u8], bb: u64, num_logops: u32, flags: u8) {
+        let ofs = 4096 + (bb % LOGBBS * BBSIZE) as usize;
+        let h = &mut img[ofs..ofs + BBSIZE as usize];
+        h.copy_within(0..4, H_CYCLE);
+        h[0..4].copy_from_slice(&XLOG_HEADER_MAGIC_NUM.to_be_bytes());
+        h[H_VERSION..H_VERSION + 4].copy_from_slice(&XLOG_VERSION_2.to_be_bytes());
+        h[H_LEN..H_LEN + 4].copy_from_slice(&512u32.to_be_bytes());
+        h[H_NUM_LOGOPS..H_NUM_LOGOPS + 4].copy_from_slice(&num_logops.to_be_bytes());
+        h[H_SIZE..H_SIZE + 4].copy_from_slice(&XLOG_HEADER_CYCLE_SIZE.to_be_bytes());
+        let ofs = 4096 + ((bb + 1) % LOGBBS * BBSIZE) as usize;
+        img[ofs + OH_FLAGS] = flags;
+    }
+
+    fn check(img: Vec<u8>) -> Result<(), String> {
+        check_clean(&mut Cursor::new(img), &sb())
+    }
+
+    /// The last record before the head must be a lone unmount record, even when it wraps around
+    /// the end of the log.
+    #[rstest]
+    #[case::clean(4, 1, XLOG_UNMOUNT_TRANS, true)]
+    #[case::clean_wrapped(LOGBBS - 1, 1, XLOG_UNMOUNT_TRANS, true)]
+    #[case::not_unmount(4, 1, 0, false)]
+    #[case::more_ops(4, 2, XLOG_UNMOUNT_TRANS, false)]
+    fn unmount_record(
+        #[case] bb: u64,
+        #[case] num_logops: u32,
+        #[case] flags: u8,
+        #[case] clean: bool,
+    ) {
+        // Cycle 6 filled the log, and cycle 7 ends with the record, perhaps wrapping into 8
+        let mut img = vec![0u8; 16 * 4096];
+        stamp(&mut img, 0, LOGBBS, 6);
+        stamp(&mut img, 0, (bb + 2).min(LOGBBS), 7);
+        stamp(&mut img, LOGBBS, bb + 2, 8);
+        record(&mut img, bb, num_logops, flags);
+        assert_eq!(check(img).is_ok(), clean);
+    }
+
+    /// A record after the unmount record means that the file system was used since
+    #[test]
+    fn written_since() {
+        let mut img = vec![0u8; 16 * 4096];
+        stamp(&mut img, 0, LOGBBS, 6);
+        stamp(&mut img, 0, 6, 7);
+        record(&mut img, 0, 1, XLOG_UNMOUNT_TRANS);
+        record(&mut img, 2, 3, 0);
+        record(&mut img, 4, 1, 0);
+        assert!(check(img).is_err());
+    }
+
+    /// A zeroed log is clean
+    #[test]
+    fn zeroed() {
+        assert_eq!(check(vec![0u8; 16 * 4096]), Ok(()));
+    }
+
+    #[test]
+    fn external() {
+        let mut sb = sb();
+        sb.sb_logstart = 0;
+        assert!(check_clean(&mut Cursor::new(vec![0u8; 16 * 4096]), &sb).is_err());
+    }
+}
@@ -25,28 +25,53 @@
  * OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
  * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
  */
-mod attr;
+pub mod ag;
+#[cfg(test)]
+pub mod alloc_count;
+pub mod attr;
 mod attr_bptree;
 mod attr_leaf;
 mod attr_node;
 mod attr_shortform;
-mod block_reader;
-mod bmbt_rec;
+// Much of audit, trace, and volume is used only by the FUSE operations
+#[cfg_attr(not(feature = "fuse"), allow(dead_code))]
+mod audit;
+pub mod block_reader;
+pub mod bmbt_rec;
 mod btree;
-mod da_btree;
+mod cache_budget;
+pub mod cat;
+pub mod da_btree;
 mod definitions;
-mod dinode;
-mod dinode_core;
-mod dir3;
+pub mod dinode;
+pub mod dinode_core;
+pub mod dir3;
 mod dir3_block;
-mod dir3_lf;
+pub mod dir3_lf;
 mod dir3_sf;
+pub mod dump;
+pub mod extract;
 mod file;
 mod file_btree;
 mod file_extent_list;
-mod sb;
+mod inobt;
+pub mod list;
+mod log;
+pub mod orphans;
+#[cfg_attr(not(feature = "fuse"), allow(dead_code))]
+mod read_pool;
+mod refcount;
+pub mod sb;
+pub mod stat;
+pub mod stats;
 mod symlink_extent;
-mod utils;
+#[cfg(test)]
+mod test_util;
+#[cfg_attr(not(feature = "fuse"), allow(dead_code))]
+mod trace;
+pub mod utils;
+mod validate;
+#[cfg_attr(not(feature = "fuse"), allow(dead_code))]
 pub mod volume;
 
 #[allow(clippy::unnecessary_cast)] // It isn't unnecessary on all platforms.
@@ -0,0 +1,227 @@
+/*
+ * BSD 2-Clause License
+ *
+ * Copyright (c) 2021, Khaled Emara
+ * All rights reserved.
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice, this
+ *    list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice,
+ *    this list of conditions and the following disclaimer in the documentation
+ *    and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+ * AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+ * IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+ * FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+ * SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+ * CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+ * OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+ * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ */
+//! Finding and recovering orphaned inodes, used by `--orphans`.
+//!
+//! An inode that is unlinked while still open stays allocated until it is closed.  Meanwhile,
+//! XFS keeps it on one of the 64 unlinked lists in its AG's AGI, chained through
+//! `di_next_unlinked`.  If the system crashes first, the inode is left on the list, unreachable
+//! from any directory, until log recovery frees it.
+use std::{
+    collections::HashSet,
+    fs,
+    io::{self, prelude::*, SeekFrom},
+    path::Path,
+};
+
+use super::{
+    block_reader::BlockReader,
+    cat::copy,
+    definitions::*,
+    dinode::Dinode,
+    list::{unix_secs, write_json_str},
+    sb::Sb,
+    volume::{Options, Volume},
+};
+
+/// Byte offset of the unlinked list heads within the AGI
+const AGI_UNLINKED: usize = 40;
+const AGI_UNLINKED_BUCKETS: usize = 64;
+
+/// Read the heads of an AG's unlinked lists.
+fn unlinked_heads<R: Read + Seek>(
+    reader: &mut R,
+    sb: &Sb,
+    agno: XfsAgnumber,
+) -> io::Result<Vec<XfsAgino>> {
+    // The AGI is the third sector of the AG
+    let sectsize = u64::from(sb.sb_sectsize);
+    let start = sb.fsb_to_offset(u64::from(agno) << sb.sb_agblklog) + 2 * sectsize;
+    let mut agi = vec![0u8; AGI_UNLINKED + 4 * AGI_UNLINKED_BUCKETS];
+    reader.seek(SeekFrom::Start(start))?;
+    reader.read_exact(&mut agi)?;
+    let magic = u32::from_be_bytes(agi[0..4].try_into().unwrap());
+    if magic != XFS_AGI_MAGIC {
+        let msg = format!("AG {}: bad AGI magic {:#x}", agno, magic);
+        return Err(io::Error::other(msg));
+    }
+    Ok(agi[AGI_UNLINKED..]
+        .chunks_exact(4)
+        .map(|b| u32::from_be_bytes(b.try_into().unwrap()))
+        .filter(|agino| *agino != NULLAGINO)
+        .collect())
+}
+
+/// Everything that gets reported about an orphan
+#[derive(Debug)]
+struct Orphan {
+    agno:   XfsAgnumber,
+    ino:    XfsIno,
+    dinode: Dinode,
+}
+
+/// Walk every AG's unlinked lists.
+fn find(reader: &mut BlockReader, sb: &Sb) -> io::Result<Vec<Orphan>> {
+    let mut orphans = Vec::new();
+    let mut seen = HashSet::new();
+    reader.set_bufsize(sb.inode_size());
+    for agno in 0..sb.sb_agcount {
+        for head in unlinked_heads(reader, sb, agno)? {
+            let mut agino = head;
+            while agino != NULLAGINO {
+                let ino = sb.agino_to_ino(agno, agino);
+                if !seen.insert(ino) {
+                    let msg = format!("AG {}: unlinked list loops at inode {}", agno, ino);
+                    return Err(io::Error::other(msg));
+                }
+                let offset = sb.ino_to_offset(ino).map_err(|e| {
+                    let msg = format!("AG {}: invalid unlinked inode {}", agno, ino);
+                    io::Error::new(io::Error::from_raw_os_error(e).kind(), msg)
+                })?;
+                // Check the magic first, since Dinode::from would panic
+                let mut magic = [0u8; 2];
+                reader.seek(SeekFrom::Start(offset))?;
+                reader.read_exact(&mut magic)?;
+                if u16::from_be_bytes(magic) != XFS_DINODE_MAGIC {
+                    let msg = format!("AG {}: unlinked inode {} has bad magic", agno, ino);
+                    return Err(io::Error::other(msg));
+                }
+                let dinode =
+                    Dinode::from(reader.by_ref(), sb, ino).map_err(io::Error::from_raw_os_error)?;
+                agino = dinode.di_core.di_next_unlinked;
+                orphans.push(Orphan { agno, ino, dinode });
+            }
+        }
+    }
+    Ok(orphans)
+}
+
+/// Copy a regular file's contents to `path`.
+fn recover(reader: &mut BlockReader, sb: &Sb, dinode: &Dinode, path: &Path) -> io::Result<()> {
+    copy(
+        reader,
+        sb,
+        dinode,
+        io::BufWriter::new(fs::File::create(path)?),
+    )
+}
+
+/// Find every orphaned inode without mounting the file system, and print them as JSON.  If
+/// `recover_dir` is given, also copy each orphaned regular file's contents there, named by inode
+/// number.
+pub fn orphans<W: Write>(
+    device: &Path,
+    recover_dir: Option<&Path>,
+    no_mmap: bool,
+    mut out: W,
+) -> io::Result<()> {
+    let opts = Options {
+        no_mmap,
+        ..Default::default()
+    };
+    let mut vol = Volume::open(device, opts)?;
+    let sb = vol.sb;
+    let orphans = find(&mut vol.device, &sb)?;
+
+    out.write_all(b"{\"orphans\":[")?;
+    for (i, orphan) in orphans.iter().enumerate() {
+        if i > 0 {
+            out.write_all(b",")?;
+        }
+        let core = &orphan.dinode.di_core;
+        let attr = core
+            .stat(orphan.ino)
+            .map_err(io::Error::from_raw_os_error)?;
+        write!(
+            out,
+            "{{\"agno\":{},\"ino\":{},\"mode\":{},\"nlink\":{},\"size\":{},\"mtime\":{},\"\
+             nextents\":{},\"nblocks\":{},\"recovered\":",
+            orphan.agno,
+            orphan.ino,
+            core.di_mode,
+            core.di_nlink,
+            core.di_size,
+            unix_secs(attr.mtime),
+            core.di_nextents,
+            core.di_nblocks,
+        )?;
+        match recover_dir {
+            Some(dir) if libc::mode_t::from(core.di_mode) & libc::S_IFMT == libc::S_IFREG => {
+                let path = dir.join(orphan.ino.to_string());
+                recover(&mut vol.device, &sb, &orphan.dinode, &path)
+                    .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+                write_json_str(&mut out, path.as_os_str())?;
+            }
+            _ => out.write_all(b"null")?,
+        }
+        out.write_all(b"}")?;
+    }
+    out.write_all(b"]}\n")?;
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// Two AGs of 16 4 KiB blocks each, with 512-byte inodes
+    fn sb() -> Sb {
+        Sb::for_test(0, 2, 16, 32)
+    }
+
+    #[test]
+    fn heads() {
+        let sb = sb();
+        let mut img = vec![0u8; 32 * 4096];
+        for agno in 0..2 {
+            let agi = agno * 16 * 4096 + 2 * 512;
+            img[agi..agi + 4].copy_from_slice(&XFS_AGI_MAGIC.to_be_bytes());
+            for bucket in 0..AGI_UNLINKED_BUCKETS {
+                let ofs = agi + AGI_UNLINKED + 4 * bucket;
+                img[ofs..ofs + 4].copy_from_slice(&NULLAGINO.to_be_bytes());
+            }
+        }
+        let ag1 = 16 * 4096 + 2 * 512 + AGI_UNLINKED;
+        img[ag1 + 4 * 5..ag1 + 4 * 6].copy_from_slice(&69u32.to_be_bytes());
+        img[ag1 + 4 * 9..ag1 + 4 * 10].copy_from_slice(&73u32.to_be_bytes());
+
+        let mut cursor = Cursor::new(img);
+        assert!(unlinked_heads(&mut cursor, &sb, 0).unwrap().is_empty());
+        assert_eq!(unlinked_heads(&mut cursor, &sb, 1).unwrap(), vec![69, 73]);
+        assert_eq!(sb.agino_to_ino(1, 69), (1 << 7) | 69);
+    }
+
+    #[test]
+    fn bad_magic() {
+        let sb = sb();
+        let mut cursor = Cursor::new(vec![0u8; 32 * 4096]);
+        let e = unlinked_heads(&mut cursor, &sb, 1).unwrap_err();
+        assert_eq!(e.to_string(), "AG 1: bad AGI magic 0x0");
+    }
+}
@@ -0,0 +1,197 @@
+/*
+ * BSD 2-Clause License
+ *
+ * Copyright (c) 2021, Khaled Emara
+ * All rights reserved.
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice, this
+ *    list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice,
+ *    this list of conditions and the following disclaimer in the documentation
+ *    and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+ * AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+ * IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+ * FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+ * SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+ * CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+ * OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+ * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ */
+//! The threads that read regular files' data for `-o threads`.
+//!
+//! FUSE operations are dispatched one at a time, so one slow read would delay every operation
+//! behind it.  Instead, the dispatcher only maps a read's blocks, which are usually cached, and
+//! hands the actual reading to this pool.  Each of its threads has its own readers of the devices,
+//! and replies to the kernel itself.
+use std::{
+    fmt,
+    io,
+    sync::{
+        mpsc::{self, Sender},
+        Arc,
+        Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+use super::block_reader::BlockReader;
+
+/// Work for one of the pool's threads, given its readers of the data and realtime devices
+type Job = Box<dyn FnOnce(&mut BlockReader, Option<&mut BlockReader>) + Send>;
+
+pub struct ReadPool {
+    tx:      Option<Sender<Job>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl ReadPool {
+    /// Start `n` threads, each with its own clones of `device` and `rtdev`, whose bufsizes are set
+    /// to `bufsize`.
+    pub fn new(
+        n: usize,
+        device: &BlockReader,
+        rtdev: Option<&BlockReader>,
+        bufsize: usize,
+    ) -> io::Result<Self> {
+        let (tx, rx) = mpsc::channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+        let mut threads = Vec::with_capacity(n);
+        for i in 0..n {
+            let mut device = device.try_clone()?;
+            device.set_bufsize(bufsize);
+            let mut rtdev = rtdev.map(BlockReader::try_clone).transpose()?;
+            if let Some(rtdev) = rtdev.as_mut() {
+                rtdev.set_bufsize(bufsize);
+            }
+            let rx = rx.clone();
+            let thread = thread::Builder::new()
+                .name(format!("xfs-fuse-pool{}", i))
+                .spawn(move || loop {
+                    // Only hold the lock while waiting for a job, not while doing it
+                    let job = rx.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => job(&mut device, rtdev.as_mut()),
+                        // The pool has been dropped
+                        Err(_) => break,
+                    }
+                })?;
+            threads.push(thread);
+        }
+        Ok(ReadPool {
+            tx: Some(tx),
+            threads,
+        })
+    }
+
+    /// Run `job` on whichever of the pool's threads is free first.
+    pub fn submit<F>(&self, job: F)
+    where
+        F: FnOnce(&mut BlockReader, Option<&mut BlockReader>) + Send + 'static,
+    {
+        self.tx
+            .as_ref()
+            .unwrap()
+            .send(Box::new(job))
+            .expect("every read pool thread has exited");
+    }
+}
+
+impl Drop for ReadPool {
+    /// Finish every job already submitted, so each of them still replies.
+    fn drop(&mut self) {
+        drop(self.tx.take());
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl fmt::Debug for ReadPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReadPool")
+            .field("threads", &self.threads.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Seek, SeekFrom},
+        os::unix::fs::FileExt,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Barrier,
+        },
+        time::Duration,
+    };
+
+    use super::*;
+
+    fn device() -> (tempfile::NamedTempFile, BlockReader) {
+        let f = tempfile::NamedTempFile::new().unwrap();
+        f.as_file().set_len(1 << 16).unwrap();
+        f.as_file().write_all_at(b"hello", 4096).unwrap();
+        let br = BlockReader::open(f.path()).unwrap();
+        (f, br)
+    }
+
+    /// Jobs run at the same time, each on its own thread with its own reader
+    #[test]
+    fn concurrent() {
+        const N: usize = 4;
+        let (_f, br) = device();
+        let pool = ReadPool::new(N, &br, None, 4096).unwrap();
+        // Every job must be running before any can finish
+        let barrier = Arc::new(Barrier::new(N));
+        let (tx, rx) = mpsc::channel();
+        for _ in 0..N {
+            let barrier = barrier.clone();
+            let tx = tx.clone();
+            pool.submit(move |device, rtdev| {
+                assert!(rtdev.is_none());
+                barrier.wait();
+                let mut buf = [0u8; 5];
+                device.seek(SeekFrom::Start(4096)).unwrap();
+                device.read_exact(&mut buf).unwrap();
+                tx.send((thread::current().id(), buf)).unwrap();
+            });
+        }
+        let mut ids = (0..N)
+            .map(|_| {
+                let (id, buf) = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+                assert_eq!(&buf, b"hello");
+                id
+            })
+            .collect::<Vec<_>>();
+        ids.sort_by_key(|id| format!("{:?}", id));
+        ids.dedup();
+        assert_eq!(ids.len(), N);
+    }
+
+    /// Dropping the pool waits for every job that was already submitted
+    #[test]
+    fn drop_finishes_jobs() {
+        let (_f, br) = device();
+        let pool = ReadPool::new(2, &br, Some(&br), 4096).unwrap();
+        let done = Arc::new(AtomicUsize::new(0));
+        for _ in 0..16 {
+            let done = done.clone();
+            pool.submit(move |_, rtdev| {
+                assert!(rtdev.is_some());
+                thread::sleep(Duration::from_millis(10));
+                done.fetch_add(1, Ordering::Relaxed);
+            });
+        }
+        drop(pool);
+        assert_eq!(done.load(Ordering::Relaxed), 16);
+    }
+}
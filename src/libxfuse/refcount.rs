@@ -0,0 +1,321 @@
+/*
+ * BSD 2-Clause License
+ *
+ * Copyright (c) 2021, Khaled Emara
+ * All rights reserved.
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice, this
+ *    list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice,
+ *    this list of conditions and the following disclaimer in the documentation
+ *    and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+ * AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+ * IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+ * FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+ * SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+ * CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+ * OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+ * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ */
+//! Finding shared data blocks by searching each AG's reference count B+tree, on file systems with
+//! the reflink feature.
+//!
+//! Reading never needs it.  Each file's data fork always maps that file's own current data, and
+//! copy-on-write staging extents are recorded only here until they're remapped into a fork.
+use std::io::{self, prelude::*, SeekFrom};
+
+use super::{definitions::*, sb::Sb};
+
+/// Byte offsets of the refcount btree's root and height within the AGF
+const AGF_REFCOUNT_ROOT: usize = 88;
+const AGF_REFCOUNT_LEVEL: usize = 92;
+
+/// Size of a V5 short-format btree block header.  The refcount btree only exists on V5.
+const SBLOCK_CRC_LEN: usize = 56;
+
+/// Size of a refcount record, and of an interior node's key and pointer
+const REC_LEN: usize = 12;
+const KEY_LEN: usize = 4;
+const PTR_LEN: usize = 4;
+
+/// Set in the start block of records that track copy-on-write staging extents, rather than
+/// shared ones.  They sort after all of the others.
+const XFS_REFC_COWFLAG: u32 = 1 << 31;
+
+/// No valid refcount btree is taller than this
+const MAX_LEVELS: u32 = 9;
+
+fn be32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn be16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_at<R: Read + Seek>(reader: &mut R, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+    reader.seek(SeekFrom::Start(offset))?;
+    reader.read_exact(buf)
+}
+
+/// Return the parts of the `len` blocks at `agbno` in AG `agno` that more than one file
+/// references, in ascending order, as (agbno, length) pairs.  Adjacent parts are merged.
+pub fn shared_in_ag<R: Read + Seek>(
+    reader: &mut R,
+    sb: &Sb,
+    agno: XfsAgnumber,
+    agbno: XfsAgblock,
+    len: XfsExtlen,
+) -> io::Result<Vec<(XfsAgblock, XfsExtlen)>> {
+    let err = |msg: String| io::Error::other(format!("AG {}: {}", agno, msg));
+    let ag_block = |agbno: XfsAgblock| {
+        sb.fsb_to_offset((u64::from(agno) << sb.sb_agblklog) | u64::from(agbno))
+    };
+    let end = agbno.saturating_add(len);
+
+    // The AGF is the second sector of the AG
+    let mut agf = [0u8; AGF_REFCOUNT_LEVEL + 4];
+    read_at(reader, ag_block(0) + u64::from(sb.sb_sectsize), &mut agf)?;
+    if be32(&agf, 0) != XFS_AGF_MAGIC {
+        return Err(err(format!("bad AGF magic {:#x}", be32(&agf, 0))));
+    }
+    let mut bno = be32(&agf, AGF_REFCOUNT_ROOT);
+    let levels = be32(&agf, AGF_REFCOUNT_LEVEL);
+    if levels == 0 || levels > MAX_LEVELS {
+        return Err(err(format!("invalid refcount btree height {}", levels)));
+    }
+
+    let blocksize = sb.sb_blocksize as usize;
+    let read_block = |reader: &mut R, bno: XfsAgblock, level: u32| -> io::Result<Vec<u8>> {
+        if bno >= sb.sb_agblocks {
+            return Err(err(format!(
+                "refcount btree block {} is beyond the AG",
+                bno
+            )));
+        }
+        let mut block = vec![0u8; blocksize];
+        read_at(reader, ag_block(bno), &mut block)?;
+        if be32(&block, 0) != XFS_REFC_CRC_MAGIC {
+            return Err(err(format!("refcount btree block {} has bad magic", bno)));
+        }
+        if u32::from(be16(&block, 4)) != level {
+            return Err(err(format!(
+                "refcount btree block {} has the wrong level",
+                bno
+            )));
+        }
+        Ok(block)
+    };
+
+    // Descend to the leaf that holds the last record starting at or before agbno.  Records never
+    // overlap, so any that covers agbno must be that one.
+    let maxrecs = (blocksize - SBLOCK_CRC_LEN) / (KEY_LEN + PTR_LEN);
+    for level in (1..levels).rev() {
+        let node = read_block(reader, bno, level)?;
+        let numrecs = usize::from(be16(&node, 6));
+        if numrecs == 0 || numrecs > maxrecs {
+            return Err(err(format!(
+                "refcount btree node {} has {} records",
+                bno, numrecs
+            )));
+        }
+        let keys = &node[SBLOCK_CRC_LEN..SBLOCK_CRC_LEN + numrecs * KEY_LEN];
+        let i = keys
+            .chunks_exact(KEY_LEN)
+            .take_while(|key| be32(key, 0) <= agbno)
+            .count()
+            .max(1);
+        bno = be32(
+            &node,
+            SBLOCK_CRC_LEN + maxrecs * KEY_LEN + (i - 1) * PTR_LEN,
+        );
+    }
+
+    // Then walk the leaves through their right sibling pointers, until the records pass the end
+    // of the range
+    let mut shared = Vec::new();
+    let mut nleaves = 0;
+    while bno != NULLAGBLOCK {
+        nleaves += 1;
+        if nleaves > sb.sb_agblocks {
+            return Err(err("refcount btree leaves form a loop".to_string()));
+        }
+        let leaf = read_block(reader, bno, 0)?;
+        let numrecs = usize::from(be16(&leaf, 6));
+        if SBLOCK_CRC_LEN + numrecs * REC_LEN > blocksize {
+            return Err(err(format!(
+                "refcount btree leaf {} has too many records",
+                bno
+            )));
+        }
+        for rec in leaf[SBLOCK_CRC_LEN..SBLOCK_CRC_LEN + numrecs * REC_LEN].chunks_exact(REC_LEN) {
+            let startblock = be32(rec, 0);
+            if startblock >= end || startblock & XFS_REFC_COWFLAG != 0 {
+                return Ok(shared);
+            }
+            let recend = startblock.saturating_add(be32(rec, 4));
+            // Blocks referenced only once are not recorded, except as CoW staging extents
+            if recend > agbno && be32(rec, 8) > 1 {
+                let start = startblock.max(agbno);
+                let len = recend.min(end) - start;
+                // Adjacent records differ only in their counts, which we don't report
+                match shared.last_mut() {
+                    Some((prev, prevlen)) if *prev + *prevlen == start => *prevlen += len,
+                    _ => shared.push((start, len)),
+                }
+            }
+        }
+        bno = be32(&leaf, 12);
+    }
+    Ok(shared)
+}
+
+/// Return the parts of the `len` blocks at file system block `fsbno` that more than one file
+/// references, as (fsbno, length) pairs.  Always empty without the reflink feature.
+pub fn shared<R: Read + Seek>(
+    reader: &mut R,
+    sb: &Sb,
+    fsbno: XfsFsblock,
+    len: XfsExtlen,
+) -> io::Result<Vec<(XfsFsblock, XfsExtlen)>> {
+    if !sb.has_reflink() {
+        return Ok(Vec::new());
+    }
+    let agno = XfsAgnumber::try_from(fsbno >> sb.sb_agblklog)
+        .map_err(|_| io::Error::other(format!("block {} is beyond the last AG", fsbno)))?;
+    let agbno = (fsbno & ((1 << sb.sb_agblklog) - 1)) as XfsAgblock;
+    Ok(shared_in_ag(reader, sb, agno, agbno, len)?
+        .into_iter()
+        .map(|(bno, len)| ((u64::from(agno) << sb.sb_agblklog) | u64::from(bno), len))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use rstest::rstest;
+
+    use super::*;
+
+    /// One AG of 16 4 KiB blocks, with reflink
+    fn sb() -> Sb {
+        let mut sb = Sb::for_test(5, 1, 16, 16);
+        sb.set_reflink(true);
+        sb
+    }
+
+    /// Write a refcount btree block, with `body` following the header
+    fn btree_block(
+        img: &mut [u8],
+        agbno: usize,
+        level: u16,
+        numrecs: u16,
+        rightsib: XfsAgblock,
+        body: &[u8],
+    ) {
+        let block = &mut img[agbno * 4096..(agbno + 1) * 4096];
+        block[0..4].copy_from_slice(&XFS_REFC_CRC_MAGIC.to_be_bytes());
+        block[4..6].copy_from_slice(&level.to_be_bytes());
+        block[6..8].copy_from_slice(&numrecs.to_be_bytes());
+        block[8..12].copy_from_slice(&NULLAGBLOCK.to_be_bytes());
+        block[12..16].copy_from_slice(&rightsib.to_be_bytes());
+        block[SBLOCK_CRC_LEN..SBLOCK_CRC_LEN + body.len()].copy_from_slice(body);
+    }
+
+    fn recs(recs: &[(u32, u32, u32)]) -> Vec<u8> {
+        recs.iter()
+            .flat_map(|(start, len, refcount)| {
+                [
+                    start.to_be_bytes(),
+                    len.to_be_bytes(),
+                    refcount.to_be_bytes(),
+                ]
+                .concat()
+            })
+            .collect()
+    }
+
+    /// A two-level refcount btree with two leaves.  Blocks 8-9 and 11-12 are shared, and 13-14
+    /// are a CoW staging extent.
+    fn image() -> Vec<u8> {
+        let mut img = vec![0u8; 16 * 4096];
+        let agf = &mut img[512..1024];
+        agf[0..4].copy_from_slice(&XFS_AGF_MAGIC.to_be_bytes());
+        agf[AGF_REFCOUNT_ROOT..AGF_REFCOUNT_ROOT + 4].copy_from_slice(&4u32.to_be_bytes());
+        agf[AGF_REFCOUNT_LEVEL..AGF_REFCOUNT_LEVEL + 4].copy_from_slice(&2u32.to_be_bytes());
+
+        let maxrecs = (4096 - SBLOCK_CRC_LEN) / (KEY_LEN + PTR_LEN);
+        let mut node = vec![0u8; maxrecs * (KEY_LEN + PTR_LEN)];
+        node[0..4].copy_from_slice(&8u32.to_be_bytes());
+        node[4..8].copy_from_slice(&11u32.to_be_bytes());
+        let ptrs = maxrecs * KEY_LEN;
+        node[ptrs..ptrs + 4].copy_from_slice(&5u32.to_be_bytes());
+        node[ptrs + 4..ptrs + 8].copy_from_slice(&6u32.to_be_bytes());
+        btree_block(&mut img, 4, 1, 2, NULLAGBLOCK, &node);
+        btree_block(&mut img, 5, 0, 1, 6, &recs(&[(8, 2, 2)]));
+        btree_block(
+            &mut img,
+            6,
+            0,
+            3,
+            NULLAGBLOCK,
+            &recs(&[(11, 1, 3), (12, 1, 2), (13 | XFS_REFC_COWFLAG, 2, 1)]),
+        );
+        img
+    }
+
+    #[rstest]
+    #[case::before(0, 8, &[])]
+    #[case::exact(8, 2, &[(8, 2)])]
+    #[case::inside(9, 1, &[(9, 1)])]
+    #[case::overlapping(7, 5, &[(8, 2), (11, 1)])]
+    #[case::merged(11, 5, &[(11, 2)])]
+    #[case::cow(13, 2, &[])]
+    fn shared_blocks(
+        #[case] agbno: XfsAgblock,
+        #[case] len: XfsExtlen,
+        #[case] expected: &[(XfsAgblock, XfsExtlen)],
+    ) {
+        let mut cursor = Cursor::new(image());
+        assert_eq!(
+            shared_in_ag(&mut cursor, &sb(), 0, agbno, len).unwrap(),
+            expected
+        );
+    }
+
+    /// Without reflink, the AGF's refcount fields are unused, so they mustn't be read.
+    #[test]
+    fn no_reflink() {
+        let mut sb = sb();
+        sb.set_reflink(false);
+        let mut cursor = Cursor::new(vec![0u8; 16 * 4096]);
+        assert!(shared(&mut cursor, &sb, 8, 2).unwrap().is_empty());
+    }
+
+    /// A sibling pointer loop must not hang
+    #[test]
+    fn leaf_loop() {
+        let mut img = image();
+        img[6 * 4096 + 12..6 * 4096 + 16].copy_from_slice(&5u32.to_be_bytes());
+        img[6 * 4096 + 6..6 * 4096 + 8].copy_from_slice(&1u16.to_be_bytes());
+        let e = shared_in_ag(&mut Cursor::new(img), &sb(), 0, 8, 8).unwrap_err();
+        assert!(e.to_string().contains("loop"), "{}", e);
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut img = image();
+        img[5 * 4096] = 0;
+        let e = shared_in_ag(&mut Cursor::new(img), &sb(), 0, 8, 2).unwrap_err();
+        assert!(e.to_string().contains("bad magic"), "{}", e);
+    }
+}
@@ -25,11 +25,15 @@
  * OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
  * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
  */
-use std::io::{prelude::*, SeekFrom};
+use std::{
+    fmt,
+    io::{self, prelude::*, SeekFrom},
+};
 
 use bitflags::bitflags;
 use byteorder::{BigEndian, LittleEndian, ReadBytesExt};
 use crc::{Crc, CRC_32_ISCSI};
+use libc::c_int;
 
 use super::{definitions::*, utils::Uuid};
 
@@ -45,7 +49,7 @@ mod constants {
     pub const XFS_SB_VERSION_SECTORBIT: u16 = 0x0800;
     pub const XFS_SB_VERSION_EXTFLGBIT: u16 = 0x1000;
     pub const XFS_SB_VERSION_DIRV2BIT: u16 = 0x2000;
-    pub const XFS_SB_VERSION_MOREBITSBIT: u16 = 0x4000;
+    pub const XFS_SB_VERSION_MOREBITSBIT: u16 = 0x8000;
 
     pub const XFS_UQUOTA_ACCT: u16 = 0x0001;
     pub const XFS_UQUOTA_ENFD: u16 = 0x0002;
@@ -68,6 +72,11 @@ mod constants {
     pub const XFS_SB_VERSION2_CRCBIT: u32 = 0x00000100;
     pub const XFS_SB_VERSION2_FTYPE: u32 = 0x00000200;
 
+    pub const XFS_SB_FEAT_RO_COMPAT_FINOBT: u32 = 0x00000001;
+    pub const XFS_SB_FEAT_RO_COMPAT_RMAPBT: u32 = 0x00000002;
+    pub const XFS_SB_FEAT_RO_COMPAT_REFLINK: u32 = 0x00000004;
+    pub const XFS_SB_FEAT_RO_COMPAT_INOBTCNT: u32 = 0x00000008;
+
     pub const XFS_SB_FEAT_INCOMPAT_FTYPE: u32 = 0x00000001;
     pub const XFS_SB_FEAT_INCOMPAT_SPINODES: u32 = 0x00000002;
     pub const XFS_SB_FEAT_INCOMPAT_META_UUID: u32 = 0x00000004;
@@ -79,6 +88,7 @@ mod constants {
 bitflags! {
     #[repr(transparent)]
     #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    #[cfg_attr(test, derive(Default))]
     pub struct SbFeatures2: u32 {
         const LazySbCount = constants::XFS_SB_VERSION2_LAZYSBCOUNTBIT;
         const Attr2 = constants::XFS_SB_VERSION2_ATTR2BIT;
@@ -107,6 +117,32 @@ impl SbFeatures2 {
 bitflags! {
     #[repr(transparent)]
     #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    #[cfg_attr(test, derive(Default))]
+    pub struct SbFeaturesRoCompat: u32 {
+        const FiNoBt = constants::XFS_SB_FEAT_RO_COMPAT_FINOBT;
+        const RmapBt = constants::XFS_SB_FEAT_RO_COMPAT_RMAPBT;
+        const Reflink = constants::XFS_SB_FEAT_RO_COMPAT_REFLINK;
+        const InoBtCnt = constants::XFS_SB_FEAT_RO_COMPAT_INOBTCNT;
+        const _ = !0;
+    }
+}
+
+impl SbFeaturesRoCompat {
+    /// Every feature that we know.  Unlike `all()`, this excludes unnamed bits.
+    const KNOWN: Self = Self::FiNoBt
+        .union(Self::RmapBt)
+        .union(Self::Reflink)
+        .union(Self::InoBtCnt);
+
+    pub const fn reflink(&self) -> bool {
+        self.contains(SbFeaturesRoCompat::Reflink)
+    }
+}
+
+bitflags! {
+    #[repr(transparent)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    #[cfg_attr(test, derive(Default))]
     pub struct SbFeaturesIncompat: u32 {
         const Ftype = constants::XFS_SB_FEAT_INCOMPAT_FTYPE;
         const SpInodes = constants::XFS_SB_FEAT_INCOMPAT_SPINODES;
@@ -140,9 +176,11 @@ impl SbFeaturesIncompat {
         self.contains(SbFeaturesIncompat::NeedsRepair)
     }
 
-    pub const fn large_extent_counters(&self) -> bool {
-        self.contains(SbFeaturesIncompat::NrExt64)
-    }
+    // Like bigtime, this is redundant with DinodeCore.di_flags2, which says whether each inode
+    // uses the large extent counters.
+    //pub const fn large_extent_counters(&self) -> bool {
+    //    self.contains(SbFeaturesIncompat::NrExt64)
+    //}
 }
 
 bitflags! {
@@ -151,39 +189,50 @@ bitflags! {
     pub struct SbFeaturesLogIncompat: u32 {}
 }
 
-#[derive(Clone, Copy, Debug)]
+/// File system usage, as reported by statfs
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Statfs {
+    pub blocks: u64,
+    pub bfree:  u64,
+    pub bavail: u64,
+    pub files:  u64,
+    pub ffree:  u64,
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(test, derive(Default))]
 pub struct Sb {
     // sb_magicnum: u32,
-    pub sb_blocksize:     u32,
-    pub sb_dblocks:       XfsRfsblock,
-    // sb_rblocks: XfsRfsblock,
+    pub sb_blocksize:      u32,
+    pub sb_dblocks:        XfsRfsblock,
+    pub sb_rblocks:        XfsRfsblock,
     // sb_rextents: XfsRtblock,
-    pub sb_uuid:          Uuid,
-    // sb_logstart: XfsFsblock,
-    pub sb_rootino:       XfsIno,
+    pub sb_uuid:           Uuid,
+    pub sb_logstart:       XfsFsblock,
+    pub sb_rootino:        XfsIno,
     // sb_rbmino: XfsIno,
     // sb_rsumino: XfsIno,
-    // sb_rextsize: XfsAgblock,
-    pub sb_agblocks:      XfsAgblock,
-    pub sb_agcount:       XfsAgnumber,
+    pub sb_rextsize:       XfsAgblock,
+    pub sb_agblocks:       XfsAgblock,
+    pub sb_agcount:        XfsAgnumber,
     // sb_rbmblocks: XfsExtlen,
-    pub sb_logblocks:     XfsExtlen,
-    sb_versionnum:        u16,
-    // sb_sectsize: u16,
-    sb_inodesize:         u16,
+    pub sb_logblocks:      XfsExtlen,
+    sb_versionnum:         u16,
+    pub sb_sectsize:       u16,
+    sb_inodesize:          u16,
     // sb_inopblock: u16,
     // sb_fname: [u8; 12],
-    pub sb_blocklog:      u8,
+    pub sb_blocklog:       u8,
     // sb_sectlog: u8,
-    pub sb_inodelog:      u8,
-    pub sb_inopblog:      u8,
-    pub sb_agblklog:      u8,
+    pub sb_inodelog:       u8,
+    pub sb_inopblog:       u8,
+    pub sb_agblklog:       u8,
     // sb_rextslog: u8,
     // sb_inprogress: u8,
-    // sb_imax_pct: u8,
-    pub sb_icount:        u64,
-    pub sb_ifree:         u64,
-    pub sb_fdblocks:      u64,
+    pub sb_imax_pct:       u8,
+    pub sb_icount:         u64,
+    pub sb_ifree:          u64,
+    pub sb_fdblocks:       u64,
     // sb_frextents: u64,
     // sb_uquotino: XfsIno,
     // sb_gquotino: XfsIno,
@@ -191,157 +240,389 @@ pub struct Sb {
     // sb_flags: u8,
     // sb_shared_vn: u8,
     // sb_inoalignmt: XfsExtlen,
-    // sb_unit: u32,
-    // sb_width: u32,
-    pub sb_dirblklog:     u8,
+    /// Stripe unit, in blocks, or 0 if the file system wasn't made on a striped device
+    pub sb_unit:           u32,
+    /// Stripe width, in blocks
+    pub sb_width:          u32,
+    pub sb_dirblklog:      u8,
     // sb_logsectlog: u8,
     // sb_logsectsize: u16,
     // sb_logsunit: u32,
-    sb_features2:         SbFeatures2,
+    sb_features2:          SbFeatures2,
     // sb_bad_features2: u32,
     // sb_features_compat: u32,
-    // sb_features_ro_compat: u32,
-    sb_features_incompat: SbFeaturesIncompat,
+    sb_features_ro_compat: SbFeaturesRoCompat,
+    sb_features_incompat:  SbFeaturesIncompat,
     // sb_features_log_incompat: u32,
+    /// Disk byte offset of the start of each AG
+    ag_offsets:            Vec<u64>,
+}
+
+/// Why a device doesn't hold a usable superblock
+#[derive(Debug)]
+pub enum SbError {
+    Io(io::Error),
+    /// The device doesn't begin with an XFS superblock.  If we can tell what it holds instead, say
+    /// so.
+    BadMagic(Option<String>),
+    /// The version number, in the low bits of sb_versionnum, is neither 4 nor 5
+    UnsupportedVersion(u16),
+    /// The superblock's checksum doesn't match its contents
+    BadCrc {
+        stored:   u32,
+        computed: u32,
+    },
+    /// The file system uses a feature that we can't read
+    UnsupportedFeature(String),
+}
+
+impl fmt::Display for SbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SbError::Io(e) => write!(f, "cannot read the superblock: {}", e),
+            SbError::BadMagic(None) => write!(f, "superblock magic number is invalid"),
+            SbError::BadMagic(Some(hint)) => {
+                write!(f, "superblock magic number is invalid; {}", hint)
+            }
+            SbError::UnsupportedVersion(v) => write!(f, "unsupported file system version {}", v),
+            SbError::BadCrc { stored, computed } => write!(
+                f,
+                "superblock checksum is invalid: stored {:#010x}, computed {:#010x}",
+                stored, computed
+            ),
+            SbError::UnsupportedFeature(what) => write!(f, "unsupported feature: {}", what),
+        }
+    }
+}
+
+impl From<io::Error> for SbError {
+    fn from(e: io::Error) -> Self {
+        SbError::Io(e)
+    }
+}
+
+/// How much of a device to examine when guessing what it holds instead of an XFS file system.
+/// Enough for a GPT's header and 128 partition entries, with 4096-byte sectors.
+const PROBE_LEN: usize = 6 * 4096;
+
+/// One entry of a partition table
+struct Partition {
+    /// 1-based index in the table
+    index:  usize,
+    /// MBR partition type, if that's the kind of table
+    mbr:    Option<u8>,
+    offset: u64,
+    len:    u64,
+}
+
+/// Describe a partition table's entries, so that the user can attach the right one without
+/// having to look them up.
+fn partitions_hint(table: &str, partitions: &[Partition]) -> String {
+    let mut hint = format!(
+        "this looks like a disk with {} partition table; mount the partition that holds the file \
+         system instead, for example by attaching it with losetup --offset and --sizelimit on \
+         Linux or with mdconfig on FreeBSD.  Partitions:",
+        table
+    );
+    if partitions.is_empty() {
+        hint.push_str(" none");
+    }
+    for (i, p) in partitions.iter().enumerate() {
+        let sep = if i > 0 { ";" } else { "" };
+        hint.push_str(&format!("{} {}", sep, p.index));
+        if let Some(ty) = p.mbr {
+            hint.push_str(&format!(" (type {:#04x})", ty));
+        }
+        hint.push_str(&format!(" at offset {}, {} bytes", p.offset, p.len));
+    }
+    hint
+}
+
+fn le32(buf: &[u8], ofs: usize) -> u32 {
+    u32::from_le_bytes(buf[ofs..ofs + 4].try_into().unwrap())
+}
+
+fn le64(buf: &[u8], ofs: usize) -> u64 {
+    u64::from_le_bytes(buf[ofs..ofs + 8].try_into().unwrap())
+}
+
+/// Find the partitions of a GPT, whose header is in the second sector.  Entries that lie beyond
+/// `head` are omitted.
+fn gpt_partitions(head: &[u8]) -> Option<Vec<Partition>> {
+    const ENTRY_LBA: usize = 72;
+    const NUM_ENTRIES: usize = 80;
+    const ENTRY_SIZE: usize = 84;
+
+    let sectsize = [512, 4096]
+        .into_iter()
+        .find(|ss| head.get(*ss..*ss + 8) == Some(&b"EFI PART"[..]))?;
+    let hdr = head.get(sectsize..sectsize + 92)?;
+    let start = usize::try_from(le64(hdr, ENTRY_LBA))
+        .ok()?
+        .checked_mul(sectsize)?;
+    let entsize = le32(hdr, ENTRY_SIZE) as usize;
+    if entsize < 48 {
+        return Some(Vec::new());
+    }
+    let ss = sectsize as u64;
+    let partitions = (0..le32(hdr, NUM_ENTRIES) as usize)
+        .map_while(|i| {
+            head.get(start + i * entsize..start + (i + 1) * entsize)
+                .map(|e| (i, e))
+        })
+        // An unused entry has a nil type GUID
+        .filter(|(_, e)| e[0..16].iter().any(|b| *b != 0))
+        .map(|(i, e)| {
+            let (first, last) = (le64(e, 32), le64(e, 40));
+            Partition {
+                index:  i + 1,
+                mbr:    None,
+                offset: first.saturating_mul(ss),
+                len:    last
+                    .saturating_sub(first)
+                    .saturating_add(1)
+                    .saturating_mul(ss),
+            }
+        })
+        .collect();
+    Some(partitions)
+}
+
+/// Find the primary partitions of an MBR
+fn mbr_partitions(head: &[u8]) -> Option<Vec<Partition>> {
+    const ENTRIES: usize = 446;
+
+    if head.get(510..512) != Some(&[0x55, 0xaa][..]) {
+        return None;
+    }
+    let partitions = (0..4)
+        .map(|i| (i, &head[ENTRIES + 16 * i..ENTRIES + 16 * (i + 1)]))
+        // The boot indicator must be 0 or 0x80, and an unused entry has type 0.  Real
+        // partitions never start at the MBR itself.
+        .filter(|(_, e)| e[0] & 0x7f == 0 && e[4] != 0 && le32(e, 8) != 0 && le32(e, 12) != 0)
+        .map(|(i, e)| Partition {
+            index:  i + 1,
+            mbr:    Some(e[4]),
+            offset: u64::from(le32(e, 8)) * 512,
+            len:    u64::from(le32(e, 12)) * 512,
+        })
+        .collect::<Vec<_>>();
+    // A boot sector of some other file system may end with the same signature
+    (!partitions.is_empty()).then_some(partitions)
+}
+
+/// Guess what a device holds instead of an XFS file system, from its first few KiB, to help users
+/// who pointed us at the wrong device.
+fn identify(head: &[u8]) -> Option<String> {
+    if head[0..4] == XLOG_HEADER_MAGIC_NUM.to_be_bytes() {
+        Some("this appears to be an XFS external log device; mount the data device instead".into())
+    } else if head.starts_with(b"LUKS\xba\xbe") {
+        Some(
+            "this looks like a LUKS encrypted volume; unlock it with cryptsetup open, and mount \
+             the unlocked device instead"
+                .into(),
+        )
+    } else if head.starts_with(b"QFI\xfb") {
+        Some(
+            "this looks like a qcow2 image; convert it with qemu-img convert -O raw, or attach it \
+             with qemu-nbd, and mount the result instead"
+                .into(),
+        )
+    } else if head.get(512..520) == Some(&b"LABELONE"[..])
+        && head.get(536..544) == Some(&b"LVM2 001"[..])
+    {
+        Some(
+            "this looks like an LVM physical volume; activate its volume group with vgchange -ay, \
+             and mount the logical volume that holds the file system instead"
+                .into(),
+        )
+    } else if let Some(partitions) = gpt_partitions(head) {
+        Some(partitions_hint("a GPT", &partitions))
+    } else if let Some(partitions) = mbr_partitions(head) {
+        Some(partitions_hint("an MBR", &partitions))
+    } else if head[..512].iter().all(|b| *b == 0) {
+        Some(
+            "the first sector is all zeros.  If this is a whole disk, mount the partition that \
+             holds the file system instead.  If it's part of a multi-device XFS file system, it \
+             may be the realtime device; mount the data device instead"
+                .into(),
+        )
+    } else {
+        None
+    }
 }
 
 impl Sb {
+    /// Blocks per AG withheld from the free count, XFS_ALLOCBT_AGFL_RESERVE + 4
+    const ALLOC_SET_ASIDE: u64 = 8;
     const BBSHIFT: u8 = 9;
+    const INODES_PER_CHUNK: u64 = 64;
+    const MAXINUMBER: u64 = (1 << 56) - 1;
 
-    pub fn from<T: BufRead + Seek>(buf_reader: &mut T) -> Sb {
-        let sb_magicnum = buf_reader.read_u32::<BigEndian>().unwrap();
-        if sb_magicnum != XFS_SB_MAGIC {
-            panic!("Superblock magic number is invalid");
+    pub fn from<T: BufRead + Seek>(buf_reader: &mut T) -> Result<Sb, SbError> {
+        let mut first_sector = [0u8; 512];
+        buf_reader.read_exact(&mut first_sector)?;
+        if first_sector[0..4] != XFS_SB_MAGIC.to_be_bytes() {
+            // Look further, but a device smaller than PROBE_LEN is fine.
+            let mut head = Vec::with_capacity(PROBE_LEN);
+            if buf_reader.seek(SeekFrom::Start(0)).is_ok() {
+                let _ = buf_reader
+                    .by_ref()
+                    .take(PROBE_LEN as u64)
+                    .read_to_end(&mut head);
+            }
+            if head.len() < first_sector.len() {
+                head = first_sector.to_vec();
+            }
+            return Err(SbError::BadMagic(identify(&head)));
         }
+        buf_reader.seek(SeekFrom::Start(4))?;
 
-        let sb_blocksize = buf_reader.read_u32::<BigEndian>().unwrap();
-        let sb_dblocks = buf_reader.read_u64::<BigEndian>().unwrap();
-        let _sb_rblocks = buf_reader.read_u64::<BigEndian>().unwrap();
-        let _sb_rextents = buf_reader.read_u64::<BigEndian>().unwrap();
-        let sb_uuid = Uuid::from_u128(buf_reader.read_u128::<BigEndian>().unwrap());
-        let _sb_logstart = buf_reader.read_u64::<BigEndian>().unwrap();
-        let sb_rootino = buf_reader.read_u64::<BigEndian>().unwrap();
-        let _sb_rbmino = buf_reader.read_u64::<BigEndian>().unwrap();
-        let _sb_rsumino = buf_reader.read_u64::<BigEndian>().unwrap();
-        let _sb_rextsize = buf_reader.read_u32::<BigEndian>().unwrap();
-        let sb_agblocks = buf_reader.read_u32::<BigEndian>().unwrap();
-        let sb_agcount = buf_reader.read_u32::<BigEndian>().unwrap();
-        let _sb_rbmblocks = buf_reader.read_u32::<BigEndian>().unwrap();
-        let sb_logblocks = buf_reader.read_u32::<BigEndian>().unwrap();
-        let sb_versionnum = buf_reader.read_u16::<BigEndian>().unwrap();
-        let sb_sectsize = buf_reader.read_u16::<BigEndian>().unwrap();
-        let sb_inodesize = buf_reader.read_u16::<BigEndian>().unwrap();
-        let _sb_inopblock = buf_reader.read_u16::<BigEndian>().unwrap();
+        let sb_blocksize = buf_reader.read_u32::<BigEndian>()?;
+        let sb_dblocks = buf_reader.read_u64::<BigEndian>()?;
+        let sb_rblocks = buf_reader.read_u64::<BigEndian>()?;
+        let _sb_rextents = buf_reader.read_u64::<BigEndian>()?;
+        let sb_uuid = Uuid::from_u128(buf_reader.read_u128::<BigEndian>()?);
+        let sb_logstart = buf_reader.read_u64::<BigEndian>()?;
+        let sb_rootino = buf_reader.read_u64::<BigEndian>()?;
+        let _sb_rbmino = buf_reader.read_u64::<BigEndian>()?;
+        let _sb_rsumino = buf_reader.read_u64::<BigEndian>()?;
+        let sb_rextsize = buf_reader.read_u32::<BigEndian>()?;
+        let sb_agblocks = buf_reader.read_u32::<BigEndian>()?;
+        let sb_agcount = buf_reader.read_u32::<BigEndian>()?;
+        let _sb_rbmblocks = buf_reader.read_u32::<BigEndian>()?;
+        let sb_logblocks = buf_reader.read_u32::<BigEndian>()?;
+        let sb_versionnum = buf_reader.read_u16::<BigEndian>()?;
+        let sb_sectsize = buf_reader.read_u16::<BigEndian>()?;
+        let sb_inodesize = buf_reader.read_u16::<BigEndian>()?;
+        let _sb_inopblock = buf_reader.read_u16::<BigEndian>()?;
 
         let mut buf_fname = [0u8; 12];
-        buf_reader.read_exact(&mut buf_fname[..]).unwrap();
+        buf_reader.read_exact(&mut buf_fname[..])?;
         let _sb_fname = buf_fname;
 
-        let sb_blocklog = buf_reader.read_u8().unwrap();
-        let _sb_sectlog = buf_reader.read_u8().unwrap();
-        let sb_inodelog = buf_reader.read_u8().unwrap();
-        let sb_inopblog = buf_reader.read_u8().unwrap();
-        let sb_agblklog = buf_reader.read_u8().unwrap();
-        let _sb_rextslog = buf_reader.read_u8().unwrap();
-        let _sb_inprogress = buf_reader.read_u8().unwrap();
-        let _sb_imax_pct = buf_reader.read_u8().unwrap();
-        let sb_icount = buf_reader.read_u64::<BigEndian>().unwrap();
-        let sb_ifree = buf_reader.read_u64::<BigEndian>().unwrap();
-        let sb_fdblocks = buf_reader.read_u64::<BigEndian>().unwrap();
-        let _sb_frextents = buf_reader.read_u64::<BigEndian>().unwrap();
-        let _sb_uquotino = buf_reader.read_u64::<BigEndian>().unwrap();
-        let _sb_gquotino = buf_reader.read_u64::<BigEndian>().unwrap();
-        let _sb_qflags = buf_reader.read_u16::<BigEndian>().unwrap();
-        let _sb_flags = buf_reader.read_u8().unwrap();
-        let _sb_shared_vn = buf_reader.read_u8().unwrap();
-        let _sb_inoalignmt = buf_reader.read_u32::<BigEndian>().unwrap();
-        let _sb_unit = buf_reader.read_u32::<BigEndian>().unwrap();
-        let _sb_width = buf_reader.read_u32::<BigEndian>().unwrap();
-        let sb_dirblklog = buf_reader.read_u8().unwrap();
-        let _sb_logsectlog = buf_reader.read_u8().unwrap();
-        let _sb_logsectsize = buf_reader.read_u16::<BigEndian>().unwrap();
-        let _sb_logsunit = buf_reader.read_u32::<BigEndian>().unwrap();
-        let sb_features2 =
-            SbFeatures2::from_bits(buf_reader.read_u32::<BigEndian>().unwrap()).unwrap();
-        let _sb_bad_features2 = buf_reader.read_u32::<BigEndian>().unwrap();
+        let sb_blocklog = buf_reader.read_u8()?;
+        let _sb_sectlog = buf_reader.read_u8()?;
+        let sb_inodelog = buf_reader.read_u8()?;
+        let sb_inopblog = buf_reader.read_u8()?;
+        let sb_agblklog = buf_reader.read_u8()?;
+        let _sb_rextslog = buf_reader.read_u8()?;
+        let _sb_inprogress = buf_reader.read_u8()?;
+        let sb_imax_pct = buf_reader.read_u8()?;
+        let sb_icount = buf_reader.read_u64::<BigEndian>()?;
+        let sb_ifree = buf_reader.read_u64::<BigEndian>()?;
+        let sb_fdblocks = buf_reader.read_u64::<BigEndian>()?;
+        let _sb_frextents = buf_reader.read_u64::<BigEndian>()?;
+        let _sb_uquotino = buf_reader.read_u64::<BigEndian>()?;
+        let _sb_gquotino = buf_reader.read_u64::<BigEndian>()?;
+        let _sb_qflags = buf_reader.read_u16::<BigEndian>()?;
+        let _sb_flags = buf_reader.read_u8()?;
+        let _sb_shared_vn = buf_reader.read_u8()?;
+        let _sb_inoalignmt = buf_reader.read_u32::<BigEndian>()?;
+        let sb_unit = buf_reader.read_u32::<BigEndian>()?;
+        let sb_width = buf_reader.read_u32::<BigEndian>()?;
+        let sb_dirblklog = buf_reader.read_u8()?;
+        let _sb_logsectlog = buf_reader.read_u8()?;
+        let _sb_logsectsize = buf_reader.read_u16::<BigEndian>()?;
+        let _sb_logsunit = buf_reader.read_u32::<BigEndian>()?;
+        let sb_features2 = SbFeatures2::from_bits_retain(buf_reader.read_u32::<BigEndian>()?);
+        let _sb_bad_features2 = buf_reader.read_u32::<BigEndian>()?;
 
         /* Version 5 superblock features */
-        let _sb_features_compat = buf_reader.read_u32::<BigEndian>().unwrap();
-        let _sb_features_ro_compat = buf_reader.read_u32::<BigEndian>().unwrap();
-        let incompat_raw = buf_reader.read_u32::<BigEndian>().unwrap();
-        let sb_features_incompat = SbFeaturesIncompat::from_bits(incompat_raw)
-            .unwrap_or_else(|| panic!("Unknown value in sb_features_incompat: {:?}", incompat_raw));
-        let log_incompat_raw = buf_reader.read_u32::<BigEndian>().unwrap();
-        let _sb_features_log_incompat = SbFeaturesLogIncompat::from_bits(log_incompat_raw)
-            .unwrap_or_else(|| {
-                panic!(
-                    "Unknown value in sb_features_log_incompat: {:?}",
-                    log_incompat_raw
-                )
-            });
-
-        buf_reader.seek(SeekFrom::Start(0)).unwrap();
+        let _sb_features_compat = buf_reader.read_u32::<BigEndian>()?;
+        let sb_features_ro_compat =
+            SbFeaturesRoCompat::from_bits_retain(buf_reader.read_u32::<BigEndian>()?);
+        let incompat_raw = buf_reader.read_u32::<BigEndian>()?;
+        let sb_features_incompat =
+            SbFeaturesIncompat::from_bits(incompat_raw).ok_or_else(|| {
+                let unknown = incompat_raw & !SbFeaturesIncompat::all().bits();
+                SbError::UnsupportedFeature(format!("unknown incompatible features {:#x}", unknown))
+            })?;
+        let log_incompat_raw = buf_reader.read_u32::<BigEndian>()?;
+        if SbFeaturesLogIncompat::from_bits(log_incompat_raw).is_none() {
+            let unknown = log_incompat_raw & !SbFeaturesLogIncompat::all().bits();
+            let msg = format!("unknown log incompatible features {:#x}", unknown);
+            return Err(SbError::UnsupportedFeature(msg));
+        }
+
+        buf_reader.seek(SeekFrom::Start(0))?;
 
         const CASTAGNOLI: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
         let mut digest = CASTAGNOLI.digest();
 
         let mut buf_bcrc = [0u8; 224];
-        buf_reader.read_exact(&mut buf_bcrc).unwrap();
+        buf_reader.read_exact(&mut buf_bcrc)?;
         digest.update(&buf_bcrc);
         digest.update(&[0u8; 4]);
 
-        let sb_crc = buf_reader.read_u32::<LittleEndian>().unwrap();
+        let sb_crc = buf_reader.read_u32::<LittleEndian>()?;
 
-        let mut buf_acrc = vec![0u8; usize::from(sb_sectsize) - 228];
-        buf_reader.read_exact(&mut buf_acrc).unwrap();
+        let mut buf_acrc = vec![0u8; usize::from(sb_sectsize).saturating_sub(228)];
+        buf_reader.read_exact(&mut buf_acrc)?;
         digest.update(&buf_acrc);
 
         if ![4, 5].contains(&(sb_versionnum & 0xF)) {
-            panic!(
-                "Unsupported filesystem version number {}",
-                sb_versionnum & 0xF
-            );
+            return Err(SbError::UnsupportedVersion(sb_versionnum & 0xF));
         }
-        if !sb_features2.attr2() {
-            panic!("Version 1 extended attributes are not supported");
+        if sb_features2.crc() {
+            let computed = digest.finalize();
+            if computed != sb_crc {
+                return Err(SbError::BadCrc {
+                    stored: sb_crc,
+                    computed,
+                });
+            }
         }
         if sb_versionnum & 0xF == 5 && !sb_features2.crc() {
-            panic!("Version 5 file systems must set the CRC bit in sb_features2");
-        }
-        if sb_features2.crc() && digest.finalize() != sb_crc {
-            panic!("Crc check failed!");
+            let msg = "a version 5 file system without checksums".to_string();
+            return Err(SbError::UnsupportedFeature(msg));
         }
         if sb_features_incompat.meta_uuid() {
-            panic!("The Metadata UUID feature is not supported");
+            return Err(SbError::UnsupportedFeature("metadata UUID".to_string()));
         }
         if sb_features_incompat.needs_repair() {
-            panic!("The NeedsRepair feature is not supported");
-        }
-        if sb_features_incompat.large_extent_counters() {
-            panic!("The Large Extent Counters feature is not supported");
+            let msg = "needs repair; run xfs_repair first".to_string();
+            return Err(SbError::UnsupportedFeature(msg));
         }
 
-        Sb {
+        let mut sb = Sb {
             sb_blocksize,
             sb_dblocks,
+            sb_rblocks,
             sb_uuid,
+            sb_logstart,
             sb_rootino,
+            sb_rextsize,
             sb_agblocks,
             sb_agcount,
             sb_logblocks,
             sb_versionnum,
+            sb_sectsize,
             sb_inodesize,
             sb_blocklog,
             sb_inodelog,
             sb_inopblog,
             sb_agblklog,
+            sb_imax_pct,
             sb_icount,
             sb_ifree,
             sb_fdblocks,
+            sb_unit,
+            sb_width,
             sb_dirblklog,
             sb_features2,
+            sb_features_ro_compat,
             sb_features_incompat,
-        }
+            ag_offsets: Vec::new(),
+        };
+        sb.ag_offsets = sb.compute_ag_offsets();
+        Ok(sb)
+    }
+
+    fn compute_ag_offsets(&self) -> Vec<u64> {
+        (0..self.sb_agcount)
+            .map(|agno| (u64::from(agno) * u64::from(self.sb_agblocks)) << self.sb_blocklog)
+            .collect()
     }
 
     #[inline]
@@ -354,6 +635,106 @@ impl Sb {
         self.sb_inodesize.into()
     }
 
+    /// Check that an inode number could possibly refer to an inode.  Like the kernel's
+    /// xfs_verify_ino, it must lie within an existing AG, within that AG's blocks, and past the AG
+    /// headers.
+    pub fn validate_ino(&self, ino: XfsIno) -> Result<(), c_int> {
+        let agno = self.ino_to_agno(ino);
+        let agbno = (ino >> self.sb_inopblog) & ((1 << self.sb_agblklog) - 1);
+        if agno >= u64::from(self.sb_agcount) {
+            return Err(libc::EIO);
+        }
+        let ag_blocks = self.ag_length(agno as XfsAgnumber);
+        // The superblock, AGF, AGI, and AGFL each occupy one sector at the start of the AG.
+        let first_agbno = (4 * u64::from(self.sb_sectsize)).div_ceil(u64::from(self.sb_blocksize));
+        if agbno < first_agbno || agbno >= ag_blocks {
+            return Err(libc::EIO);
+        }
+        Ok(())
+    }
+
+    /// The number of the allocation group that contains this inode.  Not range checked.
+    pub fn ino_to_agno(&self, ino: XfsIno) -> u64 {
+        ino >> (self.sb_agblklog + self.sb_inopblog)
+    }
+
+    /// Convert an AG number and AG-relative inode number to an absolute inode number.
+    pub fn agino_to_ino(&self, agno: XfsAgnumber, agino: XfsAgino) -> XfsIno {
+        (u64::from(agno) << (self.sb_agblklog + self.sb_inopblog)) | u64::from(agino)
+    }
+
+    /// The disk byte offset of an inode.  Fails with EIO if the inode number doesn't pass
+    /// [`Sb::validate_ino`], so an inode past the end of a short last AG can't be read from beyond
+    /// the end of the device.
+    pub fn ino_to_offset(&self, ino: XfsIno) -> Result<u64, c_int> {
+        self.validate_ino(ino)?;
+        let agno = self.ino_to_agno(ino) as usize;
+        let agbno = (ino >> self.sb_inopblog) & ((1 << self.sb_agblklog) - 1);
+        let blk_ino = ino & ((1 << self.sb_inopblog) - 1);
+        let base = self.ag_offsets.get(agno).ok_or(libc::EIO)?;
+        Ok(base + (agbno << self.sb_blocklog) + (blk_ino << self.sb_inodelog))
+    }
+
+    /// The length of an allocation group, in file system blocks.  The last AG may be shorter than
+    /// the others.
+    pub fn ag_length(&self, agno: XfsAgnumber) -> u64 {
+        if agno == self.sb_agcount - 1 {
+            self.sb_dblocks - u64::from(agno) * u64::from(self.sb_agblocks)
+        } else {
+            u64::from(self.sb_agblocks)
+        }
+    }
+
+    /// Compute file system usage for statfs, the same way as the kernel's xfs_fs_statfs.
+    ///
+    /// An internal log is excluded from the total, since `sb_fdblocks` doesn't count it as free.
+    /// The blocks that xfs_alloc_set_aside withholds to refill the AGFL are excluded from the free
+    /// count, so that `df` agrees with Linux.  The inode count is the number of allocated inodes
+    /// plus the number that could be allocated in the free space, limited by `sb_imax_pct`.
+    pub fn statfs(&self) -> Statfs {
+        let logblocks = if self.sb_logstart != 0 {
+            u64::from(self.sb_logblocks)
+        } else {
+            0
+        };
+        // A corrupt superblock mustn't make these underflow or overflow
+        let blocks = self.sb_dblocks.saturating_sub(logblocks);
+        let bfree = self
+            .sb_fdblocks
+            .saturating_sub(Self::ALLOC_SET_ASIDE * u64::from(self.sb_agcount));
+
+        let inuse = self.sb_icount.saturating_sub(self.sb_ifree);
+        let mut files = self
+            .sb_icount
+            .saturating_add(bfree << self.sb_inopblog)
+            .min(Self::MAXINUMBER);
+        if self.sb_imax_pct != 0 {
+            // Like xfs_ialloc_setup_geometry, round down to a whole number of inode chunks
+            let ialloc_blks = (Self::INODES_PER_CHUNK >> self.sb_inopblog).max(1);
+            let maxiblocks =
+                self.sb_dblocks.saturating_mul(u64::from(self.sb_imax_pct)) / 100 / ialloc_blks
+                    * ialloc_blks;
+            files = files.min(maxiblocks << self.sb_inopblog);
+        }
+        let files = files.max(self.sb_icount);
+        Statfs {
+            blocks,
+            bfree,
+            bavail: bfree,
+            files,
+            ffree: files - inuse,
+        }
+    }
+
+    /// The optimal I/O size: a full stripe if the file system was made on a striped device, or
+    /// else a single block.
+    pub fn iosize(&self) -> u32 {
+        self.sb_width
+            .checked_mul(self.sb_blocksize)
+            .filter(|&width| width != 0)
+            .unwrap_or(self.sb_blocksize)
+    }
+
     /// Given a file system block number, calculate its disk address in units of 512B blocks
     fn fsb_to_daddr(&self, fsbno: XfsFsblock) -> u64 {
         let blkbb_log = self.sb_blocklog - Self::BBSHIFT;
@@ -367,15 +748,672 @@ impl Sb {
         self.fsb_to_daddr(fsbno) << Self::BBSHIFT
     }
 
+    /// Given a block number on the realtime device, as found in a realtime file's extents,
+    /// calculate its byte offset there.  Unlike file system block numbers, these have no AG part.
+    pub fn rtb_to_offset(&self, rtbno: XfsRtblock) -> u64 {
+        rtbno << self.sb_blocklog
+    }
+
     /// Does this file system record file type in its directory inodes?
     pub fn has_ftype(&self) -> bool {
-        // Though it isn't documented, it seems that the ftype bit was originally part of the
-        // sb_features2 field, and then later moved to the sb_features_incompat field.
-        self.sb_features2.ftype() || self.sb_features_incompat.ftype()
+        // Like xfs_sb_version_hasftype: V5 file systems record it in sb_features_incompat.  V4
+        // file systems record it in sb_features2, but only if the MOREBITS bit says that
+        // sb_features2 is valid at all.  sb_features_incompat means nothing on V4.
+        if self.version() == 5 {
+            self.sb_features_incompat.ftype()
+        } else {
+            self.sb_versionnum & constants::XFS_SB_VERSION_MOREBITSBIT != 0
+                && self.sb_features2.ftype()
+        }
+    }
+
+    /// Can files share data blocks, whose reference counts are kept in each AG's refcount btree?
+    pub fn has_reflink(&self) -> bool {
+        // Like every V5 feature bit, sb_features_ro_compat means nothing on V4.
+        self.version() == 5 && self.sb_features_ro_compat.reflink()
     }
 
     /// Return the file system version (usually 4 or 5)
     pub fn version(&self) -> u16 {
         self.sb_versionnum & 0xF
     }
+
+    /// The names of the read-only compatible features that this file system uses, among those
+    /// that we know.  Readers may ignore all of them, but writers may not.
+    pub fn ro_compat_names(&self) -> Vec<&'static str> {
+        if self.version() < 5 {
+            return Vec::new();
+        }
+        [
+            (SbFeaturesRoCompat::FiNoBt, "finobt"),
+            (SbFeaturesRoCompat::RmapBt, "rmapbt"),
+            (SbFeaturesRoCompat::Reflink, "reflink"),
+            (SbFeaturesRoCompat::InoBtCnt, "inobtcount"),
+        ]
+        .into_iter()
+        .filter(|(flag, _)| self.sb_features_ro_compat.contains(*flag))
+        .map(|(_, name)| name)
+        .collect()
+    }
+
+    /// Any read-only compatible feature bits that we don't know.  A file system with any may be
+    /// read, but not written.
+    pub fn unknown_ro_compat(&self) -> u32 {
+        if self.version() < 5 {
+            return 0;
+        }
+        self.sb_features_ro_compat
+            .difference(SbFeaturesRoCompat::KNOWN)
+            .bits()
+    }
+
+    /// The names of the optional features that this file system uses, among those that we know
+    fn feature_names(&self) -> Vec<&'static str> {
+        let mut names = Vec::new();
+        if self.version() == 5 {
+            names.push("crc");
+        }
+        if self.has_ftype() {
+            names.push("ftype");
+        }
+        let morebits = self.sb_versionnum & constants::XFS_SB_VERSION_MOREBITSBIT != 0;
+        if morebits && self.sb_features2.attr2() {
+            names.push("attr2");
+        } else if self.sb_versionnum & constants::XFS_SB_VERSION_ATTRBIT != 0 {
+            // attr1 places each attribute fork at a fixed offset, rather than moving it to make
+            // room for the data fork.  Either way, di_forkoff records where it is.
+            names.push("attr");
+        }
+        if morebits && self.sb_features2.contains(SbFeatures2::ProjId32) {
+            names.push("projid32bit");
+        }
+        names.extend(self.ro_compat_names());
+        if self.version() == 5 {
+            for (flag, name) in [
+                (SbFeaturesIncompat::SpInodes, "sparse"),
+                (SbFeaturesIncompat::MetaUuid, "meta_uuid"),
+                (SbFeaturesIncompat::Bigtime, "bigtime"),
+                (SbFeaturesIncompat::NeedsRepair, "needsrepair"),
+                (SbFeaturesIncompat::NrExt64, "nrext64"),
+            ] {
+                if self.sb_features_incompat.contains(flag) {
+                    names.push(name);
+                }
+            }
+        }
+        names
+    }
+
+    /// Describe the file system's geometry and features as a JSON object
+    pub fn to_json(&self) -> String {
+        let features = self
+            .feature_names()
+            .iter()
+            .map(|name| format!("\"{}\"", name))
+            .collect::<Vec<_>>()
+            .join(",");
+        let fields = [
+            ("blocksize", u64::from(self.sb_blocksize)),
+            ("sectsize", self.sb_sectsize.into()),
+            ("inodesize", self.sb_inodesize.into()),
+            (
+                "dirblocksize",
+                u64::from(self.sb_blocksize) << self.sb_dirblklog,
+            ),
+            ("dblocks", self.sb_dblocks),
+            ("rblocks", self.sb_rblocks),
+            ("rextsize", self.sb_rextsize.into()),
+            ("agcount", self.sb_agcount.into()),
+            ("agblocks", self.sb_agblocks.into()),
+            ("rootino", self.sb_rootino),
+            ("logstart", self.sb_logstart),
+            ("logblocks", self.sb_logblocks.into()),
+            ("icount", self.sb_icount),
+            ("ifree", self.sb_ifree),
+            ("fdblocks", self.sb_fdblocks),
+            ("unit", self.sb_unit.into()),
+            ("width", self.sb_width.into()),
+        ];
+        let mut json = format!(
+            "{{\"uuid\":\"{}\",\"version\":{},\"features\":[{}]",
+            self.sb_uuid,
+            self.version(),
+            features
+        );
+        for (name, value) in fields {
+            json += &format!(",\"{}\":{}", name, value);
+        }
+        json.push('}');
+        json
+    }
+}
+
+#[cfg(test)]
+impl Sb {
+    /// The geometry of a small file system, for unit tests: `agcount` AGs of `agblocks` 4 KiB
+    /// blocks each, the last of which may be cut short by `dblocks`, with 512 byte sectors and
+    /// inodes.
+    pub fn for_test(version: u16, agcount: u32, agblocks: u32, dblocks: u64) -> Sb {
+        let mut sb = Sb {
+            sb_blocksize: 4096,
+            sb_blocklog: 12,
+            sb_sectsize: 512,
+            sb_agblocks: agblocks,
+            sb_agblklog: (u32::BITS - (agblocks - 1).leading_zeros()) as u8,
+            sb_agcount: agcount,
+            sb_dblocks: dblocks,
+            sb_inopblog: 3,
+            ..Default::default()
+        };
+        sb.set_inodesize(9);
+        sb.set_version(version);
+        sb.set_ag_offsets();
+        sb
+    }
+
+    /// Set the file system version number, for unit tests in other modules
+    pub fn set_version(&mut self, version: u16) {
+        self.sb_versionnum = (self.sb_versionnum & !0xF) | version;
+    }
+
+    /// Enable or disable the reflink feature, for unit tests in other modules
+    pub fn set_reflink(&mut self, reflink: bool) {
+        self.sb_features_ro_compat
+            .set(SbFeaturesRoCompat::Reflink, reflink);
+    }
+
+    /// Set the raw read-only compatible feature bits, for unit tests in other modules
+    pub fn set_ro_compat(&mut self, bits: u32) {
+        self.sb_features_ro_compat = SbFeaturesRoCompat::from_bits_retain(bits);
+    }
+
+    /// Set the inode size and its log, for unit tests in other modules
+    pub fn set_inodesize(&mut self, inodelog: u8) {
+        self.sb_inodelog = inodelog;
+        self.sb_inodesize = 1 << inodelog;
+    }
+
+    /// Recompute the cached AG offsets after changing the geometry, for unit tests in other
+    /// modules
+    pub fn set_ag_offsets(&mut self) {
+        self.ag_offsets = self.compute_ag_offsets();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+    use crate::libxfuse::validate;
+
+    /// Geometry of a small file system with 4 AGs of 1000 blocks each, except the last which is
+    /// shorter.  agblklog is 10 and inopblog is 3.
+    fn sb() -> Sb {
+        Sb::for_test(0, 4, 1000, 3900)
+    }
+
+    /// A device that isn't an XFS data device should get a hint about what it is instead
+    #[rstest]
+    #[case::log(
+        &XLOG_HEADER_MAGIC_NUM.to_be_bytes(),
+        "superblock magic number is invalid; this appears to be an XFS external log device; \
+         mount the data device instead"
+    )]
+    #[case::zeros(&[], "superblock magic number is invalid; the first sector is all zeros.  If \
+        this is a whole disk, mount the partition that holds the file system instead.  If it's \
+        part of a multi-device XFS file system, it may be the realtime device; mount the data \
+        device instead")]
+    #[case::other(b"hello world", "superblock magic number is invalid")]
+    fn bad_magic(#[case] head: &[u8], #[case] expected: &str) {
+        let mut raw = vec![0u8; 4096];
+        raw[..head.len()].copy_from_slice(head);
+        let e = Sb::from(&mut io::Cursor::new(raw)).unwrap_err();
+        assert!(matches!(e, SbError::BadMagic(_)));
+        assert_eq!(e.to_string(), expected);
+    }
+
+    /// Containers that often hold XFS file systems should each get their own hint
+    #[rstest]
+    #[case::luks(
+        0,
+        b"LUKS\xba\xbe\x00\x02",
+        "a LUKS encrypted volume; unlock it with cryptsetup"
+    )]
+    #[case::qcow2(
+        0,
+        b"QFI\xfb\x00\x00\x00\x03",
+        "a qcow2 image; convert it with qemu-img"
+    )]
+    #[case::lvm(
+        512,
+        b"LABELONE\x01\0\0\0\0\0\0\0\0\0\0\0\x20\0\0\0LVM2 001",
+        "an LVM physical"
+    )]
+    fn container(#[case] ofs: usize, #[case] head: &[u8], #[case] expected: &str) {
+        let mut raw = vec![0xffu8; 4096];
+        raw[ofs..ofs + head.len()].copy_from_slice(head);
+        let e = Sb::from(&mut io::Cursor::new(raw)).unwrap_err().to_string();
+        assert!(
+            e.contains(&format!("this looks like {}", expected)),
+            "{}",
+            e
+        );
+    }
+
+    /// Build a GPT disk's first few sectors, with a partition at each of `lbas`
+    fn gpt(sectsize: usize, lbas: &[(u64, u64)]) -> Vec<u8> {
+        let mut raw = vec![0u8; 4 * sectsize + 128 * 128];
+        // The protective MBR
+        raw[446 + 4] = 0xee;
+        raw[446 + 8..446 + 12].copy_from_slice(&1u32.to_le_bytes());
+        raw[446 + 12..446 + 16].copy_from_slice(&u32::MAX.to_le_bytes());
+        raw[510..512].copy_from_slice(&[0x55, 0xaa]);
+        let hdr = &mut raw[sectsize..2 * sectsize];
+        hdr[0..8].copy_from_slice(b"EFI PART");
+        hdr[72..80].copy_from_slice(&2u64.to_le_bytes());
+        hdr[80..84].copy_from_slice(&128u32.to_le_bytes());
+        hdr[84..88].copy_from_slice(&128u32.to_le_bytes());
+        // Leave the first entry unused, to check that numbering skips it
+        for (i, (first, last)) in lbas.iter().enumerate() {
+            let e = &mut raw[2 * sectsize + 128 * (i + 1)..];
+            e[0..16].copy_from_slice(&[0xab; 16]);
+            e[32..40].copy_from_slice(&first.to_le_bytes());
+            e[40..48].copy_from_slice(&last.to_le_bytes());
+        }
+        raw
+    }
+
+    /// A GPT's partitions should be listed with their byte offsets, whatever the sector size
+    #[rstest]
+    #[case::sect512(
+        512,
+        "2 at offset 1048576, 104857600 bytes; 3 at offset 105906176, 512 bytes"
+    )]
+    #[case::sect4096(
+        4096,
+        "2 at offset 8388608, 838860800 bytes; 3 at offset 847249408, 4096 bytes"
+    )]
+    fn gpt_hint(#[case] sectsize: usize, #[case] expected: &str) {
+        let raw = gpt(sectsize, &[(2048, 206847), (206848, 206848)]);
+        let e = Sb::from(&mut io::Cursor::new(raw)).unwrap_err().to_string();
+        assert!(e.contains("disk with a GPT partition table"), "{}", e);
+        assert!(e.ends_with(&format!("Partitions: {}", expected)), "{}", e);
+    }
+
+    /// An MBR's partitions should be listed with their types and byte offsets
+    #[test]
+    fn mbr_hint() {
+        let mut raw = vec![0u8; 4096];
+        raw[0..3].copy_from_slice(&[0xeb, 0x63, 0x90]);
+        let e = &mut raw[446 + 16..446 + 32];
+        e[0] = 0x80;
+        e[4] = 0x83;
+        e[8..12].copy_from_slice(&2048u32.to_le_bytes());
+        e[12..16].copy_from_slice(&204800u32.to_le_bytes());
+        raw[510..512].copy_from_slice(&[0x55, 0xaa]);
+        let e = Sb::from(&mut io::Cursor::new(raw)).unwrap_err().to_string();
+        assert!(e.contains("disk with an MBR partition table"), "{}", e);
+        assert!(
+            e.ends_with("Partitions: 2 (type 0x83) at offset 1048576, 104857600 bytes"),
+            "{}",
+            e
+        );
+    }
+
+    /// A boot sector that has the MBR's signature but no partitions isn't a partition table
+    #[test]
+    fn mbr_empty() {
+        let mut raw = vec![0u8; 4096];
+        raw[0..3].copy_from_slice(&[0xeb, 0x3c, 0x90]);
+        raw[510..512].copy_from_slice(&[0x55, 0xaa]);
+        let e = Sb::from(&mut io::Cursor::new(raw)).unwrap_err().to_string();
+        assert_eq!(e, "superblock magic number is invalid");
+    }
+
+    #[test]
+    fn short_device() {
+        let e = Sb::from(&mut io::Cursor::new(vec![0u8; 100])).unwrap_err();
+        assert!(matches!(e, SbError::Io(_)));
+    }
+
+    fn ino(agno: u64, agbno: u64, offset: u64) -> XfsIno {
+        (agno << 13) | (agbno << 3) | offset
+    }
+
+    #[rstest]
+    #[case::first(ino(0, 1, 0))]
+    #[case::last_in_ag(ino(0, 999, 7))]
+    #[case::last_ag(ino(3, 1, 0))]
+    #[case::end_of_last_ag(ino(3, 899, 7))]
+    fn validate_ino_ok(#[case] ino: XfsIno) {
+        assert_eq!(sb().validate_ino(ino), Ok(()));
+    }
+
+    #[rstest]
+    #[case::ag_headers(ino(0, 0, 1))]
+    #[case::past_agblocks(ino(0, 1000, 0))]
+    #[case::past_agblklog(ino(0, 1023, 7))]
+    #[case::past_end_of_last_ag(ino(3, 900, 0))]
+    #[case::bad_ag(ino(4, 1, 0))]
+    #[case::huge(u64::MAX)]
+    fn validate_ino_bad(#[case] ino: XfsIno) {
+        assert_eq!(sb().validate_ino(ino), Err(libc::EIO));
+    }
+
+    #[rstest]
+    #[case::first_ag(ino(0, 1, 0), 4096)]
+    #[case::first_ag_last_inode(ino(0, 999, 7), 999 * 4096 + 7 * 512)]
+    #[case::last_ag(ino(3, 1, 0), 3001 * 4096)]
+    #[case::end_of_last_ag(ino(3, 899, 7), 3899 * 4096 + 7 * 512)]
+    fn ino_to_offset(#[case] ino: XfsIno, #[case] offset: u64) {
+        assert_eq!(sb().ino_to_offset(ino), Ok(offset));
+    }
+
+    /// The last AG is only 900 blocks long, so inode numbers that would be valid in the other AGs
+    /// lie past the end of the device.
+    #[rstest]
+    #[case::one_past_last_inode(ino(3, 899, 7) + 1)]
+    #[case::short_last_ag(ino(3, 999, 0))]
+    #[case::bad_ag(ino(4, 1, 0))]
+    fn ino_to_offset_bad(#[case] ino: XfsIno) {
+        assert_eq!(sb().ino_to_offset(ino), Err(libc::EIO));
+    }
+
+    /// Values from the golden images
+    #[rstest]
+    #[case::golden4k(
+        Sb {
+            sb_dblocks: 24576,
+            sb_logstart: 16390,
+            sb_agcount: 4,
+            sb_logblocks: 1368,
+            sb_inopblog: 3,
+            sb_imax_pct: 13,
+            sb_icount: 896,
+            sb_ifree: 146,
+            sb_fdblocks: 16545,
+            ..Default::default()
+        },
+        Statfs { blocks: 23208, bfree: 16513, bavail: 16513, files: 25536, ffree: 24786 }
+    )]
+    #[case::v4(
+        Sb {
+            sb_dblocks: 131072,
+            sb_logstart: 65543,
+            sb_agcount: 4,
+            sb_logblocks: 4806,
+            sb_inopblog: 1,
+            sb_imax_pct: 15,
+            sb_icount: 22144,
+            sb_ifree: 2824,
+            sb_fdblocks: 90624,
+            ..Default::default()
+        },
+        Statfs { blocks: 126266, bfree: 90592, bavail: 90592, files: 39296, ffree: 19976 }
+    )]
+    #[case::external_log(
+        Sb {
+            sb_dblocks: 24576,
+            sb_logstart: 0,
+            sb_agcount: 4,
+            sb_logblocks: 1368,
+            sb_inopblog: 3,
+            sb_imax_pct: 0,
+            sb_icount: 896,
+            sb_ifree: 146,
+            sb_fdblocks: 16545,
+            ..Default::default()
+        },
+        Statfs { blocks: 24576, bfree: 16513, bavail: 16513, files: 133000, ffree: 132250 }
+    )]
+    fn statfs(#[case] sb: Sb, #[case] expected: Statfs) {
+        let st = sb.statfs();
+        assert_eq!(st, expected);
+        assert_eq!(st.files - st.ffree, sb.sb_icount - sb.sb_ifree);
+    }
+
+    /// A corrupt superblock's counters may be inconsistent, but statfs must still not panic
+    #[rstest]
+    #[case::underflow(
+        Sb {
+            sb_dblocks: 100,
+            sb_logstart: 1,
+            sb_agcount: 4,
+            sb_logblocks: 1368,
+            sb_inopblog: 3,
+            sb_imax_pct: 25,
+            sb_icount: 10,
+            sb_ifree: 20,
+            sb_fdblocks: 16545,
+            ..Default::default()
+        },
+        Statfs { blocks: 0, bfree: 16513, bavail: 16513, files: 192, ffree: 192 }
+    )]
+    #[case::overflow(
+        Sb {
+            sb_dblocks: u64::MAX,
+            sb_logstart: 16390,
+            sb_agcount: 4,
+            sb_logblocks: 1368,
+            sb_inopblog: 3,
+            sb_imax_pct: 25,
+            sb_icount: u64::MAX,
+            sb_ifree: 0,
+            sb_fdblocks: 16545,
+            ..Default::default()
+        },
+        Statfs { blocks: u64::MAX - 1368, bfree: 16513, bavail: 16513, files: u64::MAX, ffree: 0 }
+    )]
+    fn statfs_corrupt(#[case] sb: Sb, #[case] expected: Statfs) {
+        assert_eq!(sb.statfs(), expected);
+    }
+
+    #[rstest]
+    #[case::unstriped(0, 0, 4096)]
+    #[case::striped(16, 64, 262144)]
+    #[case::overflow(1 << 20, 1 << 20, 4096)]
+    fn iosize(#[case] sb_unit: u32, #[case] sb_width: u32, #[case] expected: u32) {
+        let sb = Sb {
+            sb_unit,
+            sb_width,
+            ..sb()
+        };
+        assert_eq!(sb.iosize(), expected);
+    }
+
+    /// V4 file systems may have stray bits in the V5-only sb_features_incompat field, and an
+    /// sb_features2 field that isn't valid unless MOREBITS is set.
+    #[rstest]
+    #[case::v4(0x0004, SbFeatures2::empty(), SbFeaturesIncompat::empty(), false)]
+    #[case::v4_ftype(0x8004, SbFeatures2::Ftype, SbFeaturesIncompat::empty(), true)]
+    #[case::v4_no_morebits(0x0004, SbFeatures2::Ftype, SbFeaturesIncompat::empty(), false)]
+    #[case::v4_incompat(0x8004, SbFeatures2::Attr2, SbFeaturesIncompat::Ftype, false)]
+    #[case::v4_golden(
+        0xb4b4,
+        SbFeatures2::from_bits_retain(0x28a),
+        SbFeaturesIncompat::empty(),
+        true
+    )]
+    #[case::v5(0x8005, SbFeatures2::Crc, SbFeaturesIncompat::empty(), false)]
+    #[case::v5_ftype(0x8005, SbFeatures2::Crc, SbFeaturesIncompat::Ftype, true)]
+    fn has_ftype(
+        #[case] sb_versionnum: u16,
+        #[case] sb_features2: SbFeatures2,
+        #[case] sb_features_incompat: SbFeaturesIncompat,
+        #[case] expected: bool,
+    ) {
+        let sb = Sb {
+            sb_versionnum,
+            sb_features2,
+            sb_features_incompat,
+            ..sb()
+        };
+        assert_eq!(sb.has_ftype(), expected);
+    }
+
+    #[rstest]
+    #[case::v4_golden(
+        0xb4b4,
+        SbFeatures2::from_bits_retain(0x28a),
+        SbFeaturesIncompat::empty(),
+        "\"ftype\",\"attr2\",\"projid32bit\""
+    )]
+    #[case::v4_attr1(
+        0xb4b4,
+        SbFeatures2::from_bits_retain(0x282),
+        SbFeaturesIncompat::empty(),
+        "\"ftype\",\"attr\",\"projid32bit\""
+    )]
+    #[case::v5_golden(
+        0xb4a5,
+        SbFeatures2::from_bits_retain(0x18a),
+        SbFeaturesIncompat::Ftype | SbFeaturesIncompat::SpInodes | SbFeaturesIncompat::Bigtime,
+        "\"crc\",\"ftype\",\"attr2\",\"projid32bit\",\"sparse\",\"bigtime\""
+    )]
+    fn to_json(
+        #[case] sb_versionnum: u16,
+        #[case] sb_features2: SbFeatures2,
+        #[case] sb_features_incompat: SbFeaturesIncompat,
+        #[case] features: &str,
+    ) {
+        let sb = Sb {
+            sb_uuid: Uuid::from_u128(0x0123456789abcdef0011223344556677),
+            sb_rootino: 128,
+            sb_versionnum,
+            sb_features2,
+            sb_features_incompat,
+            ..sb()
+        };
+        let expected = format!(
+            "{{\"uuid\":\"01234567-89ab-cdef-0011-223344556677\",\"version\":{},\"features\":[{}],\
+             \"blocksize\":4096,\"sectsize\":512,\"inodesize\":512,\"dirblocksize\":4096,\"\
+             dblocks\":3900,\"rblocks\":0,\"rextsize\":0,\"agcount\":4,\"agblocks\":1000,\"\
+             rootino\":128,\"logstart\":0,\"logblocks\":0,\"icount\":0,\"ifree\":0,\"fdblocks\":0,\
+             \"unit\":0,\"width\":0}}",
+            sb.version(),
+            features
+        );
+        assert_eq!(sb.to_json(), expected);
+    }
+
+    /// A V5 superblock, with a valid CRC, whose only optional features are ftype and the given
+    /// read-only compatible ones
+    fn v5_with_ro_compat(ro_compat: u32) -> Vec<u8> {
+        let mut buf = vec![0u8; 512];
+        buf[0..4].copy_from_slice(&XFS_SB_MAGIC.to_be_bytes());
+        buf[4..8].copy_from_slice(&4096u32.to_be_bytes());
+        buf[84..88].copy_from_slice(&1000u32.to_be_bytes());
+        buf[88..92].copy_from_slice(&4u32.to_be_bytes());
+        buf[100..102].copy_from_slice(&0xb4a5u16.to_be_bytes());
+        buf[102..104].copy_from_slice(&512u16.to_be_bytes());
+        buf[104..106].copy_from_slice(&512u16.to_be_bytes());
+        buf[120] = 12;
+        buf[200..204].copy_from_slice(&0x18au32.to_be_bytes());
+        buf[212..216].copy_from_slice(&ro_compat.to_be_bytes());
+        buf[216..220].copy_from_slice(&constants::XFS_SB_FEAT_INCOMPAT_FTYPE.to_be_bytes());
+        validate::set_crc(&mut buf, 224);
+        buf
+    }
+
+    /// Every read-only compatible feature should be parsed and reported, including unknown ones,
+    /// since none of them stops us from reading.
+    #[rstest]
+    #[case::none(0, &[], 0)]
+    #[case::finobt(constants::XFS_SB_FEAT_RO_COMPAT_FINOBT, &["finobt"], 0)]
+    #[case::rmapbt(constants::XFS_SB_FEAT_RO_COMPAT_RMAPBT, &["rmapbt"], 0)]
+    #[case::reflink(constants::XFS_SB_FEAT_RO_COMPAT_REFLINK, &["reflink"], 0)]
+    #[case::inobtcount(constants::XFS_SB_FEAT_RO_COMPAT_INOBTCNT, &["inobtcount"], 0)]
+    #[case::all(0xf, &["finobt", "rmapbt", "reflink", "inobtcount"], 0)]
+    #[case::unknown(1 << 31 | constants::XFS_SB_FEAT_RO_COMPAT_REFLINK, &["reflink"], 1 << 31)]
+    fn ro_compat(#[case] bits: u32, #[case] names: &[&str], #[case] unknown: u32) {
+        let buf = v5_with_ro_compat(bits);
+        let sb = Sb::from(&mut io::Cursor::new(buf)).unwrap();
+        assert_eq!(sb.ro_compat_names(), names);
+        assert_eq!(sb.unknown_ro_compat(), unknown);
+        assert_eq!(sb.has_reflink(), names.contains(&"reflink"));
+        let json = sb.to_json();
+        for name in names {
+            assert!(json.contains(&format!("\"{}\"", name)), "{}", json);
+        }
+    }
+
+    /// V4 file systems have no read-only compatible features, whatever that field holds
+    #[test]
+    fn ro_compat_v4() {
+        let sb = Sb {
+            sb_versionnum: 0xb4b4,
+            sb_features_ro_compat: SbFeaturesRoCompat::from_bits_retain(!0),
+            ..sb()
+        };
+        assert!(sb.ro_compat_names().is_empty());
+        assert_eq!(sb.unknown_ro_compat(), 0);
+    }
+
+    /// Old V4 file systems may use attr1, which we can read just the same
+    #[test]
+    fn attr1() {
+        let mut buf = v5_with_ro_compat(0);
+        buf[100..102].copy_from_slice(&0xb4b4u16.to_be_bytes());
+        buf[200..204].copy_from_slice(&0x282u32.to_be_bytes());
+        let sb = Sb::from(&mut io::Cursor::new(buf)).unwrap();
+        assert_eq!(sb.version(), 4);
+        assert_eq!(sb.feature_names(), ["ftype", "attr", "projid32bit"]);
+    }
+
+    /// A damaged or unsupported superblock should be reported, not panic
+    #[rstest]
+    #[case::flipped_crc(
+        |buf: &mut Vec<u8>| buf[224] ^= 1,
+        |e: &SbError| matches!(e, SbError::BadCrc { .. })
+    )]
+    #[case::damaged(
+        |buf: &mut Vec<u8>| buf[8] ^= 1,
+        |e: &SbError| matches!(e, SbError::BadCrc { .. })
+    )]
+    #[case::wrong_magic(
+        |buf: &mut Vec<u8>| buf[0] = b'Y',
+        |e: &SbError| matches!(e, SbError::BadMagic(_))
+    )]
+    #[case::version3(
+        |buf: &mut Vec<u8>| {
+            buf[100..102].copy_from_slice(&0xb4a3u16.to_be_bytes());
+            validate::set_crc(buf, 224);
+        },
+        |e: &SbError| matches!(e, SbError::UnsupportedVersion(3))
+    )]
+    #[case::needs_repair(
+        |buf: &mut Vec<u8>| {
+            let incompat = constants::XFS_SB_FEAT_INCOMPAT_FTYPE
+                | constants::XFS_SB_FEAT_INCOMPAT_NEEDSREPAIR;
+            buf[216..220].copy_from_slice(&incompat.to_be_bytes());
+            validate::set_crc(buf, 224);
+        },
+        |e: &SbError| matches!(e, SbError::UnsupportedFeature(_))
+    )]
+    #[case::unknown_incompat(
+        |buf: &mut Vec<u8>| {
+            buf[216..220].copy_from_slice(&(1u32 << 31).to_be_bytes());
+            validate::set_crc(buf, 224);
+        },
+        |e: &SbError| matches!(e, SbError::UnsupportedFeature(_))
+    )]
+    fn rejected(#[case] damage: fn(&mut Vec<u8>), #[case] expected: fn(&SbError) -> bool) {
+        let mut buf = v5_with_ro_compat(0);
+        damage(&mut buf);
+        let e = Sb::from(&mut io::Cursor::new(buf)).unwrap_err();
+        assert!(expected(&e), "{:?}", e);
+    }
+
+    #[test]
+    fn bad_crc_message() {
+        let mut buf = v5_with_ro_compat(0);
+        buf[224..228].copy_from_slice(&0x12345678u32.to_le_bytes());
+        let e = Sb::from(&mut io::Cursor::new(buf)).unwrap_err().to_string();
+        assert!(
+            e.starts_with("superblock checksum is invalid: stored 0x12345678, computed 0x"),
+            "{}",
+            e
+        );
+    }
 }
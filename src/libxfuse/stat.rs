@@ -0,0 +1,120 @@
+/*
+ * BSD 2-Clause License
+ *
+ * Copyright (c) 2021, Khaled Emara
+ * All rights reserved.
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice, this
+ *    list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice,
+ *    this list of conditions and the following disclaimer in the documentation
+ *    and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+ * AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+ * IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+ * FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+ * SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+ * CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+ * OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+ * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ */
+//! File types and attributes, as reported by stat.  They mirror fuser's, so that the decoders
+//! don't depend on FUSE, and convert to them when the `fuse` feature is enabled.
+use std::time::SystemTime;
+
+/// The root directory's inode number.  Inodes are numbered as FUSE numbers them, which for
+/// every other inode is its XFS inode number.
+pub const ROOT_INO: u64 = 1;
+
+/// The type of a file
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum FileType {
+    /// Named pipe (S_IFIFO)
+    NamedPipe,
+    /// Character device (S_IFCHR)
+    CharDevice,
+    /// Block device (S_IFBLK)
+    BlockDevice,
+    /// Directory (S_IFDIR)
+    Directory,
+    /// Regular file (S_IFREG)
+    RegularFile,
+    /// Symbolic link (S_IFLNK)
+    Symlink,
+    /// Unix domain socket (S_IFSOCK)
+    Socket,
+}
+
+/// A file's attributes
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FileAttr {
+    /// Inode number
+    pub ino:     u64,
+    /// Size in bytes
+    pub size:    u64,
+    /// Allocated size, in file system blocks
+    pub blocks:  u64,
+    /// Time of last access
+    pub atime:   SystemTime,
+    /// Time of last modification
+    pub mtime:   SystemTime,
+    /// Time of last change
+    pub ctime:   SystemTime,
+    /// Time of creation, or of last change for inodes that don't record it
+    pub crtime:  SystemTime,
+    pub kind:    FileType,
+    /// Permissions, including the setuid, setgid, and sticky bits
+    pub perm:    u16,
+    pub nlink:   u32,
+    pub uid:     u32,
+    pub gid:     u32,
+    /// Device number, for block and character devices
+    pub rdev:    u32,
+    /// Preferred I/O size, or 0 to let the kernel choose
+    pub blksize: u32,
+}
+
+#[cfg(feature = "fuse")]
+impl From<FileType> for fuser::FileType {
+    fn from(kind: FileType) -> Self {
+        match kind {
+            FileType::NamedPipe => fuser::FileType::NamedPipe,
+            FileType::CharDevice => fuser::FileType::CharDevice,
+            FileType::BlockDevice => fuser::FileType::BlockDevice,
+            FileType::Directory => fuser::FileType::Directory,
+            FileType::RegularFile => fuser::FileType::RegularFile,
+            FileType::Symlink => fuser::FileType::Symlink,
+            FileType::Socket => fuser::FileType::Socket,
+        }
+    }
+}
+
+#[cfg(feature = "fuse")]
+impl From<FileAttr> for fuser::FileAttr {
+    fn from(attr: FileAttr) -> Self {
+        fuser::FileAttr {
+            ino:     attr.ino,
+            size:    attr.size,
+            blocks:  attr.blocks,
+            atime:   attr.atime,
+            mtime:   attr.mtime,
+            ctime:   attr.ctime,
+            crtime:  attr.crtime,
+            kind:    attr.kind.into(),
+            perm:    attr.perm,
+            nlink:   attr.nlink,
+            uid:     attr.uid,
+            gid:     attr.gid,
+            rdev:    attr.rdev,
+            blksize: attr.blksize,
+            flags:   0,
+        }
+    }
+}
@@ -0,0 +1,255 @@
+/*
+ * BSD 2-Clause License
+ *
+ * Copyright (c) 2021, Khaled Emara
+ * All rights reserved.
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice, this
+ *    list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice,
+ *    this list of conditions and the following disclaimer in the documentation
+ *    and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+ * AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+ * IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+ * FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+ * SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+ * CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+ * OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+ * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ */
+//! Counters of a mount's activity, for performance debugging.  The root directory's
+//! `user.xfuse.stats` extended attribute reports them, and xfs-fuse logs them on SIGUSR1.
+//!
+//! They're atomic, so that another thread may report them while the dispatcher and any
+//! [`ReadPool`](super::read_pool::ReadPool) threads update them.
+use std::{
+    cell::RefCell,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+/// The FUSE operations that are counted
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Op {
+    Lookup,
+    Forget,
+    Getattr,
+    Setattr,
+    Readlink,
+    Open,
+    Read,
+    Write,
+    Release,
+    Fsync,
+    Opendir,
+    Readdir,
+    Readdirplus,
+    Statfs,
+    Getxattr,
+    Listxattr,
+    Lseek,
+    Bmap,
+}
+
+impl Op {
+    pub const ALL: [Op; 18] = [
+        Op::Lookup,
+        Op::Forget,
+        Op::Getattr,
+        Op::Setattr,
+        Op::Readlink,
+        Op::Open,
+        Op::Read,
+        Op::Write,
+        Op::Release,
+        Op::Fsync,
+        Op::Opendir,
+        Op::Readdir,
+        Op::Readdirplus,
+        Op::Statfs,
+        Op::Getxattr,
+        Op::Listxattr,
+        Op::Lseek,
+        Op::Bmap,
+    ];
+
+    /// The name used for this operation in the stats JSON
+    pub fn name(self) -> &'static str {
+        match self {
+            Op::Lookup => "lookup",
+            Op::Forget => "forget",
+            Op::Getattr => "getattr",
+            Op::Setattr => "setattr",
+            Op::Readlink => "readlink",
+            Op::Open => "open",
+            Op::Read => "read",
+            Op::Write => "write",
+            Op::Release => "release",
+            Op::Fsync => "fsync",
+            Op::Opendir => "opendir",
+            Op::Readdir => "readdir",
+            Op::Readdirplus => "readdirplus",
+            Op::Statfs => "statfs",
+            Op::Getxattr => "getxattr",
+            Op::Listxattr => "listxattr",
+            Op::Lseek => "lseek",
+            Op::Bmap => "bmap",
+        }
+    }
+}
+
+/// Things that happen deep within the decoders, which don't know which mount they're decoding
+/// for.  They're counted with [`count`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Event {
+    /// An inode was used while its Dinode was cached
+    InodeHit,
+    /// An inode's Dinode had to be read from disk
+    InodeMiss,
+    /// A Leaf, Node, or Btree directory's block was found in its cache
+    DirBlockHit,
+    /// A Leaf, Node, or Btree directory's block had to be read from disk
+    DirBlockMiss,
+    /// An on-disk structure couldn't be decoded
+    DecodeError,
+}
+
+impl Event {
+    const COUNT: usize = 5;
+}
+
+thread_local! {
+    // The Volume sets this on the thread serving each operation, before performing it, so that
+    // every mount served by a process has its own counters.
+    /// The counters that [`count`] updates
+    static CURRENT: RefCell<Option<Arc<Stats>>> = const { RefCell::new(None) };
+}
+
+/// Count an event for the mount that this thread is serving, if any.
+pub fn count(event: Event) {
+    CURRENT.with(|current| {
+        if let Some(stats) = current.borrow().as_ref() {
+            stats.events[event as usize].fetch_add(1, Ordering::Relaxed);
+        }
+    });
+}
+
+/// The counters of one mount
+#[derive(Debug, Default)]
+pub struct Stats {
+    ops:          [AtomicU64; Op::ALL.len()],
+    events:       [AtomicU64; Event::COUNT],
+    /// Bytes read from the data and realtime devices.  For a memory-mapped image, the bytes
+    /// copied out of the map.
+    device_bytes: AtomicU64,
+}
+
+impl Stats {
+    /// Make these the counters that [`count`] updates on this thread.
+    pub fn enter(self: &Arc<Self>) {
+        CURRENT.with(|current| {
+            let mut current = current.borrow_mut();
+            if !current
+                .as_ref()
+                .is_some_and(|stats| Arc::ptr_eq(stats, self))
+            {
+                *current = Some(self.clone());
+            }
+        });
+    }
+
+    /// Count a FUSE operation, and make these the counters for whatever it does.
+    pub fn op(self: &Arc<Self>, op: Op) {
+        self.enter();
+        self.ops[op as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count bytes read from a device.
+    pub fn read_bytes(&self, bytes: usize) {
+        self.device_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// The number of times that `op` was performed
+    pub fn ops(&self, op: Op) -> u64 {
+        self.ops[op as usize].load(Ordering::Relaxed)
+    }
+
+    /// The number of times that `event` happened
+    pub fn events(&self, event: Event) -> u64 {
+        self.events[event as usize].load(Ordering::Relaxed)
+    }
+
+    /// The bytes read from the devices so far
+    pub fn device_bytes(&self) -> u64 {
+        self.device_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Every counter, as JSON.  Operations that never happened are omitted.
+    pub fn to_json(&self) -> String {
+        let ops = Op::ALL
+            .iter()
+            .filter(|op| self.ops(**op) > 0)
+            .map(|op| format!("\"{}\":{}", op.name(), self.ops(*op)))
+            .collect::<Vec<_>>()
+            .join(",");
+        format!(
+            "{{\"ops\":{{{}}},\"inode_cache\":{{\"hits\":{},\"misses\":{}}},\"\
+             directory_block_cache\":{{\"hits\":{},\"misses\":{}}},\"device_bytes\":{},\"\
+             decode_errors\":{}}}",
+            ops,
+            self.events(Event::InodeHit),
+            self.events(Event::InodeMiss),
+            self.events(Event::DirBlockHit),
+            self.events(Event::DirBlockMiss),
+            self.device_bytes(),
+            self.events(Event::DecodeError)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_json() {
+        let stats = Arc::new(Stats::default());
+        stats.op(Op::Lookup);
+        stats.op(Op::Read);
+        stats.op(Op::Read);
+        count(Event::InodeMiss);
+        count(Event::DirBlockHit);
+        count(Event::DirBlockHit);
+        stats.read_bytes(4096);
+        assert_eq!(
+            stats.to_json(),
+            "{\"ops\":{\"lookup\":1,\"read\":2},\"inode_cache\":{\"hits\":0,\"misses\":1},\"\
+             directory_block_cache\":{\"hits\":2,\"misses\":0},\"device_bytes\":4096,\"\
+             decode_errors\":0}"
+        );
+    }
+
+    /// Events count toward whichever mount's operation the thread performed last
+    #[test]
+    fn enter() {
+        let a = Arc::new(Stats::default());
+        let b = Arc::new(Stats::default());
+        a.op(Op::Getattr);
+        count(Event::DecodeError);
+        b.op(Op::Getattr);
+        count(Event::DecodeError);
+        count(Event::DecodeError);
+        assert_eq!(a.events(Event::DecodeError), 1);
+        assert_eq!(b.events(Event::DecodeError), 2);
+    }
+}
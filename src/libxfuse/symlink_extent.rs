@@ -31,6 +31,8 @@ use std::{
 };
 
 use bincode::{de::read::Reader, Decode};
+use libc::{c_int, EIO};
+use tracing::error;
 
 use super::{
     bmbt_rec::Bmx,
@@ -59,45 +61,63 @@ impl SymlinkExtents {
         buf_reader: &mut T,
         bmx: &Bmx,
         superblock: &Sb,
-    ) -> CString {
+    ) -> Result<CString, c_int> {
         let mut data = Vec::<u8>::with_capacity(1024);
 
         let mut dblock = 0;
         loop {
-            let (ofsb, oblocks) = bmx.get_extent(dblock);
-            if ofsb.is_none() {
+            let (mapping, oblocks) = bmx.get_extent(dblock);
+            let Some(fsb) = mapping.fsblock() else {
                 break;
-            }
-            let fsb = ofsb.unwrap();
-            let blocks = oblocks.unwrap();
+            };
+            let blocks = oblocks.ok_or(EIO)?;
+            let io_error = |e: std::io::Error| {
+                error!("Cannot read symlink block at {}: {}", fsb, e);
+                e.raw_os_error().unwrap_or(EIO)
+            };
             buf_reader
                 .seek(SeekFrom::Start(superblock.fsb_to_offset(fsb)))
-                .unwrap();
+                .map_err(io_error)?;
 
             let bytes = match superblock.version() {
                 5 => {
-                    let hdr: DsymlinkHdr = decode_from(buf_reader.by_ref()).unwrap();
-                    assert_eq!(XFS_SYMLINK_MAGIC, hdr.sl_magic);
+                    let hdr: DsymlinkHdr = decode_from(buf_reader.by_ref()).map_err(|e| {
+                        error!("Cannot decode symlink block at {}: {}", fsb, e);
+                        EIO
+                    })?;
+                    if hdr.sl_magic != XFS_SYMLINK_MAGIC {
+                        error!(
+                            "Symlink block at {} has bad magic number {:#x}",
+                            fsb, hdr.sl_magic
+                        );
+                        return Err(EIO);
+                    }
+                    if hdr.sl_bytes as usize > (blocks as usize) << superblock.sb_blocklog {
+                        error!(
+                            "Symlink block at {} claims {} bytes, more than its extent holds",
+                            fsb, hdr.sl_bytes
+                        );
+                        return Err(EIO);
+                    }
 
                     buf_reader
                         .seek(SeekFrom::Current(hdr.sl_offset as i64))
-                        .unwrap();
+                        .map_err(io_error)?;
                     hdr.sl_bytes as usize
                 }
-                4 => {
-                    // Version 4 file systems do not have the DsymlinkHdr
-                    (blocks as usize) << superblock.sb_blocklog
-                }
-                _ => unimplemented!(),
+                // Version 4 file systems do not have the DsymlinkHdr
+                _ => (blocks as usize) << superblock.sb_blocklog,
             };
 
             let oldlen = data.len();
             data.resize(oldlen + bytes, 0);
-            buf_reader.read_exact(&mut data[oldlen..]).unwrap();
+            buf_reader
+                .read_exact(&mut data[oldlen..])
+                .map_err(io_error)?;
             dblock += blocks;
         }
 
-        match CString::new(data) {
+        Ok(match CString::new(data) {
             Ok(s) => s,
             Err(ne) => {
                 // A V4 file system does not store the length of the symlink target, so we must
@@ -108,6 +128,6 @@ impl SymlinkExtents {
                 v.truncate(p);
                 CString::new(v).unwrap()
             }
-        }
+        })
     }
 }
@@ -0,0 +1,94 @@
+/*
+ * BSD 2-Clause License
+ *
+ * Copyright (c) 2021, Khaled Emara
+ * All rights reserved.
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice, this
+ *    list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice,
+ *    this list of conditions and the following disclaimer in the documentation
+ *    and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+ * AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+ * IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+ * FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+ * SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+ * CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+ * OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+ * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ */
+//! Helpers shared by the unit tests of several modules
+use std::{
+    cell::Cell,
+    io::{self, Cursor, Read, Seek, SeekFrom},
+    rc::Rc,
+};
+
+use libc::mode_t;
+
+/// What a [`CountingReader`] has counted
+#[derive(Debug, Default)]
+pub struct Counts {
+    /// Calls to `read`
+    pub reads: Cell<usize>,
+    /// Bytes read at or beyond the reader's watched offset
+    pub bytes: Cell<usize>,
+}
+
+/// Reads an in-memory image, counting what's read from it
+pub struct CountingReader {
+    inner:  Cursor<Vec<u8>>,
+    watch:  u64,
+    counts: Rc<Counts>,
+}
+
+impl CountingReader {
+    /// Count every read of `image`.
+    pub fn new(image: Vec<u8>) -> (Self, Rc<Counts>) {
+        Self::watching(image, 0)
+    }
+
+    /// Count every read of `image`, but only the bytes of those that start at or beyond
+    /// `watch`.
+    pub fn watching(image: Vec<u8>, watch: u64) -> (Self, Rc<Counts>) {
+        let counts = Rc::new(Counts::default());
+        let cr = CountingReader {
+            inner: Cursor::new(image),
+            watch,
+            counts: counts.clone(),
+        };
+        (cr, counts)
+    }
+}
+
+impl Read for CountingReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let watched = self.inner.position() >= self.watch;
+        let r = self.inner.read(buf)?;
+        self.counts.reads.set(self.counts.reads.get() + 1);
+        if watched {
+            self.counts.bytes.set(self.counts.bytes.get() + r);
+        }
+        Ok(r)
+    }
+}
+
+impl Seek for CountingReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+/// `mode` as an on-disk inode's 16-bit di_mode
+#[allow(clippy::unnecessary_cast)] // It isn't unnecessary on all platforms.
+pub fn di_mode(mode: mode_t) -> u16 {
+    mode as u16
+}
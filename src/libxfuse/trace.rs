@@ -0,0 +1,77 @@
+/*
+ * BSD 2-Clause License
+ *
+ * Copyright (c) 2021, Khaled Emara
+ * All rights reserved.
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice, this
+ *    list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice,
+ *    this list of conditions and the following disclaimer in the documentation
+ *    and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+ * AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+ * IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+ * FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+ * SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+ * CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+ * OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+ * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ */
+use std::cell::RefCell;
+
+thread_local! {
+    /// Device reads made by the current operation, as (offset, length) pairs, if recording.
+    /// While an operation on a traced inode is in progress, `BlockReader` records every read
+    /// here, so they can be logged along with the operation.
+    static READS: RefCell<Option<Vec<(u64, usize)>>> = const { RefCell::new(None) };
+}
+
+/// Start recording device reads made by this thread.
+pub fn start() {
+    READS.with(|r| *r.borrow_mut() = Some(Vec::new()));
+}
+
+/// Is this thread recording device reads?
+pub fn enabled() -> bool {
+    READS.with(|r| r.borrow().is_some())
+}
+
+/// Record a device read, if recording.
+pub fn record(offset: u64, len: usize) {
+    READS.with(|r| {
+        if let Some(reads) = r.borrow_mut().as_mut() {
+            reads.push((offset, len));
+        }
+    });
+}
+
+/// Stop recording, and return the device reads made since [`start`].
+pub fn finish() -> Vec<(u64, usize)> {
+    READS.with(|r| r.borrow_mut().take().unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record() {
+        super::record(0, 512);
+        assert!(!enabled());
+        start();
+        assert!(enabled());
+        super::record(4096, 512);
+        super::record(8192, 1024);
+        assert_eq!(finish(), vec![(4096, 512), (8192, 1024)]);
+        assert!(!enabled());
+        assert!(finish().is_empty());
+    }
+}
@@ -25,17 +25,18 @@
  * OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
  * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
  */
+use std::fmt;
+
 use bincode::{
     de::{read::Reader, Decoder},
     error::DecodeError,
     impl_borrow_decode,
     Decode,
 };
-use fuser::FileType;
 use libc::{
     c_int,
     mode_t,
-    ENOENT,
+    EIO,
     S_IFBLK,
     S_IFCHR,
     S_IFDIR,
@@ -47,14 +48,18 @@ use libc::{
 };
 use tracing::error;
 
-use super::dir3::{
-    XFS_DIR3_FT_BLKDEV,
-    XFS_DIR3_FT_CHRDEV,
-    XFS_DIR3_FT_DIR,
-    XFS_DIR3_FT_FIFO,
-    XFS_DIR3_FT_REG_FILE,
-    XFS_DIR3_FT_SOCK,
-    XFS_DIR3_FT_SYMLINK,
+use super::{
+    dir3::{
+        XFS_DIR3_FT_BLKDEV,
+        XFS_DIR3_FT_CHRDEV,
+        XFS_DIR3_FT_DIR,
+        XFS_DIR3_FT_FIFO,
+        XFS_DIR3_FT_REG_FILE,
+        XFS_DIR3_FT_SOCK,
+        XFS_DIR3_FT_SYMLINK,
+    },
+    stat::FileType,
+    stats::{self, Event},
 };
 
 /// xfs-fuse UUID type
@@ -73,6 +78,12 @@ impl Uuid {
     }
 }
 
+impl fmt::Display for Uuid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.hyphenated().fmt(f)
+    }
+}
+
 impl bincode::Decode for Uuid {
     fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError> {
         <[u8; 16]>::decode(decoder).map(|v| Uuid(uuid::Uuid::from_bytes(v)))
@@ -97,7 +108,7 @@ pub fn get_file_type(kind: FileKind) -> Result<FileType, c_int> {
             XFS_DIR3_FT_FIFO => Ok(FileType::NamedPipe),
             _ => {
                 error!("Unknown file type {:?}.", file_type);
-                Err(ENOENT)
+                Err(EIO)
             }
         },
         FileKind::Mode(file_mode) => match (file_mode as mode_t) & S_IFMT {
@@ -110,7 +121,7 @@ pub fn get_file_type(kind: FileKind) -> Result<FileType, c_int> {
             S_IFIFO => Ok(FileType::NamedPipe),
             _ => {
                 error!("Unknown file type {:?}.", (file_mode as mode_t) & S_IFMT);
-                Err(ENOENT)
+                Err(EIO)
             }
         },
     }
@@ -124,7 +135,10 @@ where
     let config = bincode::config::standard()
         .with_big_endian()
         .with_fixed_int_encoding();
-    bincode::decode_from_slice(bytes, config)
+    bincode::decode_from_slice(bytes, config).map_err(|e| {
+        stats::count(Event::DecodeError);
+        e
+    })
 }
 
 /// Decode a Bincode structure from a Reader
@@ -136,5 +150,143 @@ where
     let config = bincode::config::standard()
         .with_big_endian()
         .with_fixed_int_encoding();
-    bincode::decode_from_reader(r, config)
+    bincode::decode_from_reader(r, config).map_err(|e| {
+        stats::count(Event::DecodeError);
+        e
+    })
+}
+
+/// A fixed-layout on-disk structure that can be parsed directly from a byte slice.
+///
+/// This skips bincode's decoder machinery, which dominates the cost of the tight loops that parse
+/// arrays of small structures.  Every implementor also implements `Decode`, and the two must
+/// agree.
+pub trait FromBytes: Sized {
+    /// On-disk size in bytes
+    const SIZE: usize;
+
+    /// Parse the structure from exactly `SIZE` bytes.
+    fn from_bytes(raw: &[u8]) -> Self;
+}
+
+impl FromBytes for u64 {
+    const SIZE: usize = 8;
+
+    fn from_bytes(raw: &[u8]) -> Self {
+        u64::from_be_bytes(raw.try_into().unwrap())
+    }
+}
+
+/// Parse an array of `count` structures from the start of a byte slice.
+pub fn decode_array<T: FromBytes>(bytes: &[u8], count: usize) -> Result<Vec<T>, DecodeError> {
+    let len = count * T::SIZE;
+    let raw = bytes.get(..len).ok_or_else(|| {
+        stats::count(Event::DecodeError);
+        DecodeError::UnexpectedEnd {
+            additional: len - bytes.len(),
+        }
+    })?;
+    Ok(raw.chunks_exact(T::SIZE).map(T::from_bytes).collect())
+}
+
+/// Check that the `count` structures that an on-disk header claims fit within the `room` bytes
+/// that hold them, before decoding any.  A damaged count would otherwise decode whatever follows
+/// them.  `what` names the structure, for the error.
+pub fn check_count<T: FromBytes>(what: &str, count: usize, room: usize) -> Result<(), DecodeError> {
+    let max = room / T::SIZE;
+    if count > max {
+        stats::count(Event::DecodeError);
+        return Err(DecodeError::OtherString(format!(
+            "{what} has a count of {count}, but room for only {max}"
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Debug;
+
+    use proptest::prelude::*;
+    use rstest::rstest;
+
+    use super::*;
+    use crate::libxfuse::{
+        attr::AttrLeafEntry,
+        btree::BmbtKey,
+        da_btree::XfsDa3NodeEntry,
+        dir3::Dir2LeafEntry,
+    };
+
+    /// Parse an array both ways, and check that they agree.
+    fn agree<T>(raw: &[u8]) -> Result<(), TestCaseError>
+    where
+        T: Decode + FromBytes + Debug + PartialEq,
+    {
+        let count = raw.len() / T::SIZE;
+        let fast = decode_array::<T>(raw, count).unwrap();
+        let mut ofs = 0;
+        for item in fast.iter() {
+            let (slow, len) = decode::<T>(&raw[ofs..]).unwrap();
+            prop_assert_eq!(len, T::SIZE);
+            prop_assert_eq!(&slow, item);
+            ofs += len;
+        }
+        prop_assert_eq!(fast.len(), count);
+        Ok(())
+    }
+
+    proptest! {
+        #[test]
+        fn attr_leaf_entry(raw in prop::collection::vec(any::<u8>(), 0..256)) {
+            agree::<AttrLeafEntry>(&raw)?;
+        }
+
+        #[test]
+        fn bmbt_key(raw in prop::collection::vec(any::<u8>(), 0..256)) {
+            agree::<BmbtKey>(&raw)?;
+        }
+
+        #[test]
+        fn bmbt_ptr(raw in prop::collection::vec(any::<u8>(), 0..256)) {
+            agree::<u64>(&raw)?;
+        }
+
+        #[test]
+        fn dir2_leaf_entry(raw in prop::collection::vec(any::<u8>(), 0..256)) {
+            agree::<Dir2LeafEntry>(&raw)?;
+        }
+
+        #[test]
+        fn da_node_entry(raw in prop::collection::vec(any::<u8>(), 0..256)) {
+            agree::<XfsDa3NodeEntry>(&raw)?;
+        }
+    }
+
+    /// A short buffer is an error, just as it is for Decode.
+    #[test]
+    fn decode_array_short() {
+        let r = decode_array::<u64>(&[0u8; 20], 3);
+        assert!(matches!(
+            r,
+            Err(DecodeError::UnexpectedEnd { additional: 4 })
+        ));
+    }
+
+    #[rstest]
+    #[case::empty(0, 0, true)]
+    #[case::full(3, 24, true)]
+    #[case::slack(3, 31, true)]
+    #[case::over(4, 31, false)]
+    fn check_count_room(#[case] count: usize, #[case] room: usize, #[case] ok: bool) {
+        let r = check_count::<u64>("Widget", count, room);
+        match r {
+            Ok(()) => assert!(ok),
+            Err(DecodeError::OtherString(e)) => {
+                assert!(!ok);
+                assert_eq!(e, "Widget has a count of 4, but room for only 3");
+            }
+            Err(e) => panic!("{e:?}"),
+        }
+    }
 }
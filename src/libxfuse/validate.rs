@@ -0,0 +1,184 @@
+/*
+ * BSD 2-Clause License
+ *
+ * Copyright (c) 2021, Khaled Emara
+ * All rights reserved.
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice, this
+ *    list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice,
+ *    this list of conditions and the following disclaimer in the documentation
+ *    and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+ * AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+ * IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+ * FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+ * SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+ * CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+ * OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+ * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ */
+//! Strict-mode metadata validation.
+//!
+//! By default, xfs-fuse is lenient: it skips the more expensive checks, like directory data and
+//! remote attribute CRCs, and it makes a best effort to work around the damage that it does
+//! find.  With `-o strict`, every check is enabled and every failure is EIO, much like
+//! `xfs_repair -n`.
+use std::{
+    cell::RefCell,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use crc::{Crc, CRC_32_ISCSI};
+use libc::{c_int, EIO};
+use tracing::error;
+
+/// The validation policy.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Config {
+    /// Enable every check, and fail with EIO whenever one of them does
+    pub strict: bool,
+}
+
+thread_local! {
+    // Like stats::CURRENT, the Volume sets this before each operation, so that every mount served
+    // by a process has its own policy and counter.  The decoders that check metadata all run on
+    // the thread serving the operation; the read pool's threads only copy file data.
+    /// The validator that [`strict`] and [`failure`] consult
+    static CURRENT: RefCell<Option<Arc<Validator>>> = const { RefCell::new(None) };
+}
+
+/// One mount's validation policy, and the failures that it has found
+#[derive(Debug, Default)]
+pub struct Validator {
+    config:   Config,
+    failures: AtomicU64,
+}
+
+impl Validator {
+    pub fn new(config: Config) -> Self {
+        Validator {
+            config,
+            ..Default::default()
+        }
+    }
+
+    /// Make this the validator that [`strict`] and [`failure`] consult on this thread.
+    pub fn enter(self: &Arc<Self>) {
+        CURRENT.with(|current| {
+            let mut current = current.borrow_mut();
+            if !current.as_ref().is_some_and(|v| Arc::ptr_eq(v, self)) {
+                *current = Some(self.clone());
+            }
+        });
+    }
+
+    /// Is strict validation enabled?
+    pub fn strict(&self) -> bool {
+        self.config.strict
+    }
+
+    /// The number of validation failures found so far.
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+}
+
+/// Is strict validation enabled for the mount that this thread is serving?
+pub fn strict() -> bool {
+    CURRENT.with(|current| current.borrow().as_ref().is_some_and(|v| v.strict()))
+}
+
+/// Record a validation failure.  In strict mode, the caller must fail with the returned error.
+/// Otherwise it may carry on as best it can.
+pub fn failure(msg: fmt::Arguments) -> Result<(), c_int> {
+    error!("{}", msg);
+    let strict = CURRENT.with(|current| {
+        let current = current.borrow();
+        let v = current.as_ref()?;
+        v.failures.fetch_add(1, Ordering::Relaxed);
+        Some(v.strict())
+    });
+    if strict == Some(true) {
+        Err(EIO)
+    } else {
+        Ok(())
+    }
+}
+
+/// Does this block's CRC match?  Like the superblock's, it's computed with the CRC field zeroed.
+pub fn crc_ok(buf: &[u8], crc_offset: usize) -> bool {
+    const CASTAGNOLI: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
+    let mut digest = CASTAGNOLI.digest();
+    digest.update(&buf[..crc_offset]);
+    digest.update(&[0u8; 4]);
+    digest.update(&buf[crc_offset + 4..]);
+    let stored = u32::from_le_bytes(buf[crc_offset..crc_offset + 4].try_into().unwrap());
+    digest.finalize() == stored
+}
+
+/// Store a block's CRC, computed the same way
+pub fn set_crc(buf: &mut [u8], crc_offset: usize) {
+    const CASTAGNOLI: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
+    buf[crc_offset..crc_offset + 4].fill(0);
+    let crc = CASTAGNOLI.checksum(buf);
+    buf[crc_offset..crc_offset + 4].copy_from_slice(&crc.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lenient() {
+        let v = Arc::new(Validator::new(Config { strict: false }));
+        v.enter();
+        assert_eq!(failure(format_args!("oops")), Ok(()));
+        assert_eq!(v.failures(), 1);
+    }
+
+    #[test]
+    fn strict_fails() {
+        let v = Arc::new(Validator::new(Config { strict: true }));
+        v.enter();
+        assert_eq!(failure(format_args!("oops")), Err(EIO));
+        assert_eq!(v.failures(), 1);
+    }
+
+    /// Failures count toward whichever mount's operation the thread performed last, and follow
+    /// its policy
+    #[test]
+    fn enter() {
+        let a = Arc::new(Validator::new(Config { strict: true }));
+        let b = Arc::new(Validator::new(Config { strict: false }));
+        a.enter();
+        assert!(strict());
+        assert_eq!(failure(format_args!("oops")), Err(EIO));
+        b.enter();
+        assert!(!strict());
+        assert_eq!(failure(format_args!("oops")), Ok(()));
+        assert_eq!(failure(format_args!("oops")), Ok(()));
+        assert_eq!(a.failures(), 1);
+        assert_eq!(b.failures(), 2);
+    }
+
+    #[test]
+    fn crc() {
+        let mut buf = vec![0x5au8; 512];
+        set_crc(&mut buf, 4);
+        assert!(crc_ok(&buf, 4));
+        buf[100] ^= 1;
+        assert!(!crc_ok(&buf, 4));
+    }
+}
@@ -26,407 +26,4468 @@
  * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
  */
 use std::{
-    collections::HashMap,
-    ffi::OsStr,
-    io::Read,
-    os::unix::ffi::OsStrExt,
-    path::Path,
-    sync::OnceLock,
-    time::Duration,
+    collections::{BTreeMap, HashMap, HashSet},
+    ffi::{CString, OsStr, OsString},
+    fmt,
+    io::{self, BufRead, Read, Seek, SeekFrom},
+    mem,
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
 };
 
+use bincode::de::read::Reader;
+#[cfg(feature = "fuse")]
 use fuser::{
     consts::{
         FOPEN_CACHE_DIR,
+        FOPEN_DIRECT_IO,
         FOPEN_KEEP_CACHE,
         FUSE_ASYNC_READ,
+        FUSE_DO_READDIRPLUS,
         FUSE_EXPORT_SUPPORT,
         FUSE_NO_OPENDIR_SUPPORT,
         FUSE_NO_OPEN_SUPPORT,
+        FUSE_READDIRPLUS_AUTO,
     },
     Filesystem,
     KernelConfig,
     ReplyAttr,
+    ReplyBmap,
     ReplyDirectory,
+    ReplyDirectoryPlus,
+    ReplyEmpty,
     ReplyEntry,
     ReplyLseek,
     ReplyOpen,
     ReplyStatfs,
+    ReplyWrite,
     ReplyXattr,
     Request,
-    FUSE_ROOT_ID,
+    TimeOrNow,
 };
-use libc::ERANGE;
-use tracing::warn;
+use libc::{c_int, mode_t, ERANGE, S_IFDIR, S_IFMT};
+use tracing::{debug, error, info, warn};
 
+#[cfg(feature = "fuse")]
+use super::stats::Op;
 use super::{
+    ag,
     attr::Attr,
-    block_reader::BlockReader,
-    definitions::XfsIno,
+    audit::{AuditLog, Record},
+    block_reader::{BlockReader, ReadTimeout},
+    bmbt_rec::Mapping,
+    cache_budget::{CacheBudget, CacheKind, LruMap},
+    definitions::{XfsExtlen, XfsIno},
     dinode::Dinode,
-    dir3::Dir3,
+    dinode_core::{DinodeCore, XfsDinodeFmt},
+    dir3::{check_name, Dir3},
+    file::{ReadPlan, RtDevice},
+    inobt,
+    list::{unix_secs, write_json_str},
+    log,
+    read_pool::ReadPool,
+    refcount,
     sb::Sb,
+    stat::{FileAttr, FileType, ROOT_INO},
+    stats::{self, Event, Stats},
+    trace,
+    utils::{decode_from, get_file_type, FileKind},
+    validate::{self, Validator},
 };
 
-/// We must store the Superblock in a global variable.  This is unfortunate, and limits us to only
-/// opening one disk image at a time, but it's necessary in order to use information from the
-/// superblock within a Decode::decode implementation.
-pub(super) static SUPERBLOCK: OnceLock<Sb> = OnceLock::new();
+/// FUSE capabilities that we would like to use, if the kernel supports them
+#[cfg(feature = "fuse")]
+const WANTED_CAPABILITIES: u32 = FUSE_ASYNC_READ
+    | FUSE_DO_READDIRPLUS
+    | FUSE_EXPORT_SUPPORT
+    | FUSE_NO_OPENDIR_SUPPORT
+    | FUSE_NO_OPEN_SUPPORT
+    | FUSE_READDIRPLUS_AUTO;
 
-#[derive(Debug)]
-struct OpenInode {
-    dinode: Dinode,
-    count:  u64,
+/// Request as many of [`WANTED_CAPABILITIES`] as the kernel supports, all at once, and return
+/// the ones that were granted.  `add_capabilities` has the semantics of
+/// [`KernelConfig::add_capabilities`]: on failure it adds nothing and returns the unsupported
+/// bits.
+#[cfg(feature = "fuse")]
+fn negotiate_capabilities<F>(mut add_capabilities: F) -> u32
+where
+    F: FnMut(u32) -> Result<(), u32>,
+{
+    match add_capabilities(WANTED_CAPABILITIES) {
+        Ok(()) => WANTED_CAPABILITIES,
+        Err(unsupported) => {
+            let caps = WANTED_CAPABILITIES & !unsupported;
+            match add_capabilities(caps) {
+                Ok(()) => caps,
+                Err(unsupported) => {
+                    error!("Kernel rejected FUSE capabilities {:#x}", unsupported);
+                    0
+                }
+            }
+        }
+    }
 }
 
-#[derive(Debug)]
-pub struct Volume {
-    pub device: BlockReader,
-    pub sb:     Sb,
-    open_files: HashMap<u64, OpenInode>,
-    no_open:    bool,
-    no_opendir: bool,
+/// Describe the consequences of any [`WANTED_CAPABILITIES`] that the kernel didn't grant, for the
+/// mount-time log.
+#[cfg(feature = "fuse")]
+fn missing_capabilities(caps: u32) -> Vec<&'static str> {
+    let mut notes = Vec::new();
+    if caps & FUSE_ASYNC_READ == 0 {
+        notes.push("no FUSE_ASYNC_READ: the kernel will issue only one read per file at a time");
+    }
+    if caps & FUSE_EXPORT_SUPPORT == 0 {
+        notes.push("no FUSE_EXPORT_SUPPORT: the file system cannot be exported over NFS");
+    }
+    if caps & FUSE_DO_READDIRPLUS == 0 {
+        notes.push("no FUSE_DO_READDIRPLUS: the kernel will look up each entry that readdir lists");
+    }
+    notes
 }
 
-impl Volume {
-    // Allow the kernel to cache attributes and entries for an unlimited amount
-    // of time, since nothing will ever change.
-    const TTL: Duration = Duration::from_secs(u64::MAX);
+/// Check that the device can address the file system's metadata.  BlockReader rounds every read
+/// to the device's sector size, so a device sector larger than the file system's is tolerable.
+/// But one larger than a file system block isn't, since blocks needn't be contiguous.
+fn check_sectorsize(sb: &Sb, device_sectorsize: Option<usize>) -> Result<(), String> {
+    let Some(devsect) = device_sectorsize else {
+        return Ok(());
+    };
+    if devsect > sb.sb_blocksize as usize {
+        return Err(format!(
+            "the device's {}-byte sectors are larger than the file system's {}-byte blocks",
+            devsect, sb.sb_blocksize
+        ));
+    }
+    if devsect > usize::from(sb.sb_sectsize) {
+        warn!(
+            "The device's {}-byte sectors are larger than the file system's {}-byte sectors.  \
+             Reads will be rounded up to the device's sector size.",
+            devsect, sb.sb_sectsize
+        );
+    }
+    Ok(())
+}
 
-    pub fn from(device_name: &Path) -> Volume {
-        let mut device = BlockReader::open(device_name).unwrap();
+/// Report the file system's read-only compatible features.  By definition, readers may ignore
+/// them all, even unknown ones.  But anything that we don't know might be corrupted by a write.
+fn check_ro_compat(sb: &Sb, rw: bool) -> Result<(), String> {
+    let names = sb.ro_compat_names();
+    if !names.is_empty() {
+        info!("Read-only compatible features: {}", names.join(","));
+    }
+    match sb.unknown_ro_compat() {
+        0 => Ok(()),
+        unknown if rw => Err(format!(
+            "unknown read-only compatible features {:#x}; it can only be mounted read-only",
+            unknown
+        )),
+        unknown => {
+            info!(
+                "Ignoring unknown read-only compatible features {:#x}",
+                unknown
+            );
+            Ok(())
+        }
+    }
+}
 
-        let superblock = Sb::from(device.by_ref());
-        SUPERBLOCK.set(superblock).unwrap();
+/// Check that the root inode is a directory in a format that we can read.  Otherwise the mount
+/// would succeed, and the first readdir would fail with the mountpoint already live.
+fn check_root(core: &DinodeCore) -> Result<(), String> {
+    let mode = mode_t::from(core.di_mode);
+    if mode & S_IFMT != S_IFDIR {
+        return Err(format!(
+            "the root inode is not a directory (mode {:#o})",
+            mode
+        ));
+    }
+    match core.di_format {
+        XfsDinodeFmt::Local | XfsDinodeFmt::Extents | XfsDinodeFmt::Btree => Ok(()),
+        ref fmt => Err(format!(
+            "the root directory has an unsupported format ({:?})",
+            fmt
+        )),
+    }
+}
 
-        let root_inode = Dinode::from(device.by_ref(), &superblock, superblock.sb_rootino);
-        let mut open_files = HashMap::new();
-        // Prepopulate the root inode into the cache, since fusefs never sends a lookup for it.
-        open_files.insert(
-            FUSE_ROOT_ID,
-            OpenInode {
-                dinode: root_inode,
-                count:  1,
-            },
-        );
+/// FUSE requires the file system's root directory to have a fixed inode number.  Convert an XFS
+/// inode number to the one that FUSE uses.
+fn fuse_ino(sb: &Sb, ino: XfsIno) -> u64 {
+    if ino == sb.sb_rootino {
+        ROOT_INO
+    } else {
+        ino
+    }
+}
 
-        Volume {
-            device,
-            sb: superblock,
-            open_files,
-            no_open: false,
-            no_opendir: false,
+/// The inverse of [`fuse_ino`]
+fn xfs_ino(sb: &Sb, ino: u64) -> XfsIno {
+    if ino == ROOT_INO {
+        sb.sb_rootino
+    } else {
+        ino
+    }
+}
+
+/// The number of references that the kernel holds to an inode.
+///
+/// Only replies that hand the kernel an entry take a reference: LOOKUP's, and any others that
+/// fill in a `fuse_entry_out`.  Loading an inode for our own purposes never does, so an inode that
+/// was only loaded that way has none, and may be dropped at any time.  FORGET releases
+/// references, but never more than were taken.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+struct Lookups(u64);
+
+/// What a FORGET did to an inode's [`Lookups`]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Forgotten {
+    /// The kernel still holds this many references
+    Remaining(u64),
+    /// The kernel released its last reference
+    Released,
+    /// The kernel tried to release this many more references than it held
+    Underflow(u64),
+}
+
+impl Lookups {
+    /// Not referenced by the kernel; loaded only for our own use
+    const INTERNAL: Lookups = Lookups(0);
+
+    /// A reply has handed the kernel an entry for the inode.
+    fn entry(self) -> Self {
+        Lookups(self.0.saturating_add(1))
+    }
+
+    /// The kernel has forgotten `nlookup` references.  Return the new count, and what happened.
+    fn forget(self, nlookup: u64) -> (Self, Forgotten) {
+        match self.0.checked_sub(nlookup) {
+            Some(0) => (Lookups::INTERNAL, Forgotten::Released),
+            Some(n) => (Lookups(n), Forgotten::Remaining(n)),
+            None => (Lookups::INTERNAL, Forgotten::Underflow(nlookup - self.0)),
         }
     }
 
-    fn open_inode(&mut self, ino: u64) -> &mut OpenInode {
-        let sb = &self.sb;
-        self.open_files
-            .entry(ino)
-            .and_modify(|e| e.count += 1)
-            .or_insert_with(|| {
-                self.device.set_bufsize(sb.inode_size());
-                let dinode = Dinode::from(
-                    self.device.by_ref(),
-                    sb,
-                    if ino == FUSE_ROOT_ID {
-                        sb.sb_rootino
-                    } else {
-                        ino as XfsIno
-                    },
-                );
-                OpenInode { dinode, count: 1 }
-            })
+    /// Does the kernel hold any references?
+    fn is_referenced(self) -> bool {
+        self.0 > 0
     }
 }
 
-impl Filesystem for Volume {
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        let parent_oi = &mut self.open_files.get_mut(&parent).unwrap();
-        let dirsize = self.sb.sb_blocksize << self.sb.sb_dirblklog;
-        self.device.set_bufsize(dirsize as usize);
-        let dir = parent_oi.dinode.get_dir(self.device.by_ref(), &self.sb);
-        match dir.lookup(self.device.by_ref(), &self.sb, name) {
-            Ok(ino) => {
-                let oi = self.open_inode(ino);
-                match oi.dinode.di_core.stat(ino) {
-                    Ok(attr) => {
-                        // We don't need to report the inode generation since this is a read-only
-                        // file system.  But we'll do it anyway.
-                        reply.entry(&Self::TTL, &attr, oi.dinode.di_core.di_gen.into())
-                    }
-                    Err(err) => reply.error(err),
-                }
-            }
-            Err(err) => reply.error(err),
+#[derive(Debug)]
+struct OpenInode {
+    dinode:         Dinode,
+    lookups:        Lookups,
+    /// The name and value length of the last extended attribute that didn't fit in the caller's
+    /// buffer.  Applications usually respond to ERANGE by asking for the length, so this lets us
+    /// answer without looking the attribute up again.
+    xattr_len:      Option<(OsString, u32)>,
+    /// Names of extended attributes that were recently found not to exist.  Tools like `cp -a`
+    /// probe the same few names on every file, and the file system never changes, so this lets
+    /// repeated probes skip the attribute fork.  Whole names are kept rather than their hashes,
+    /// since it's easy for different names to share a hash.
+    missing_xattrs: HashSet<OsString>,
+    /// When this inode was last used, as a tick of [`OpenFiles`]'s clock
+    last_used:      u64,
+    /// The bytes charged to the [`CacheBudget`] for this inode and its caches, by [`CacheKind`]
+    charged:        [u64; CacheKind::ALL.len()],
+}
+
+impl OpenInode {
+    /// The most names to remember in `missing_xattrs`
+    const MISSING_XATTRS_MAX: usize = 8;
+
+    fn new(dinode: Dinode) -> Self {
+        OpenInode {
+            dinode,
+            lookups: Lookups::INTERNAL,
+            xattr_len: None,
+            missing_xattrs: HashSet::new(),
+            last_used: 0,
+            charged: [0; CacheKind::ALL.len()],
         }
     }
 
-    fn lseek(
-        &mut self,
-        _req: &Request<'_>,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        whence: i32,
-        reply: ReplyLseek,
-    ) {
-        let uoffset = if let Ok(offs) = u64::try_from(offset) {
-            offs
-        } else {
-            reply.error(libc::EINVAL);
-            return;
-        };
+    /// Roughly how many bytes of memory this inode and each of its caches occupy, by [`CacheKind`]
+    fn cache_bytes(&self) -> [u64; CacheKind::ALL.len()] {
+        let missing: usize = self.missing_xattrs.iter().map(|name| name.len()).sum();
+        let inode = mem::size_of::<Self>() + self.dinode.inode_bytes() + missing;
+        [
+            inode as u64,
+            self.dinode.dir_cache_bytes() as u64,
+            self.dinode.attr_cache_bytes() as u64,
+            // Read-ahead data belongs to the Volume's file handles, not to any inode
+            0,
+        ]
+    }
 
-        let oi = &self.open_files.get(&ino).unwrap();
-        let file = oi.dinode.get_file(self.device.by_ref());
-        if offset > file.size() {
-            reply.error(libc::ENXIO);
-            return;
+    /// Update the budget's charges to match what this inode currently caches.
+    fn recharge(&mut self, budget: &CacheBudget) {
+        let bytes = self.cache_bytes();
+        for kind in CacheKind::ALL {
+            let (old, new) = (self.charged[kind as usize], bytes[kind as usize]);
+            if new > old {
+                budget.charge(kind, new - old);
+            } else if new < old {
+                budget.release(kind, old - new);
+            }
         }
+        self.charged = bytes;
+    }
 
-        match file.lseek(self.device.by_ref(), uoffset, whence) {
-            Ok(ofs) => reply.offset(i64::try_from(ofs).unwrap()),
-            Err(e) => reply.error(e),
+    /// Release everything that this inode charged to the budget, before dropping it.
+    fn discharge(&mut self, budget: &CacheBudget) {
+        for kind in CacheKind::ALL {
+            budget.release(kind, self.charged[kind as usize]);
         }
+        self.charged = [0; CacheKind::ALL.len()];
     }
 
-    fn forget(&mut self, _req: &Request, ino: u64, nlookup: u64) {
-        if ino == FUSE_ROOT_ID {
-            // Special case: since fusefs never does a lookup for the root
-            // inode, its FORGETs may be "unmatched"
-            return;
+    /// Is this extended attribute already known not to exist, without reading anything?
+    fn lacks_xattr(&self, name: &OsStr) -> bool {
+        // di_a is None exactly when di_forkoff is 0
+        self.dinode.di_a.is_none() || self.missing_xattrs.contains(name)
+    }
+
+    /// Look up an extended attribute for a getxattr request with a buffer of `size` bytes.
+    /// Return the value's length, and the value itself unless only the length was requested.
+    /// The value is never read if it wouldn't fit.
+    fn getxattr<R: Reader + BufRead + Seek>(
+        &mut self,
+        buf_reader: &mut R,
+        sb: &Sb,
+        name: &OsStr,
+        size: u32,
+    ) -> Result<(u32, Option<Vec<u8>>), c_int> {
+        if self.lacks_xattr(name) {
+            return Err(libc::ENOATTR);
         }
-        match self.open_files.get_mut(&ino) {
-            Some(oi) => {
-                oi.count -= nlookup;
-                if oi.count == 0 {
-                    self.open_files.remove(&ino);
-                } else {
-                    // AFAICT the kernel will never send a partial forget.  Alert the admin if it
-                    // ever happens.
-                    warn!("Partial forget for ino {}", ino);
+        let Some(attrs) = self.dinode.get_attrs(buf_reader.by_ref(), sb) else {
+            return Err(libc::ENOATTR);
+        };
+        let len = match &self.xattr_len {
+            Some((n, len)) if n == name => *len,
+            _ => match attrs.value_len(buf_reader.by_ref(), sb, name) {
+                Err(libc::ENOATTR) => {
+                    if self.missing_xattrs.len() < Self::MISSING_XATTRS_MAX {
+                        self.missing_xattrs.insert(name.to_owned());
+                    }
+                    return Err(libc::ENOATTR);
                 }
-            }
-            None => warn!("Forget without lookup for inode {}", ino),
+                r => r?,
+            },
+        };
+        if size == 0 {
+            Ok((len, None))
+        } else if len > size {
+            self.xattr_len = Some((name.to_owned(), len));
+            Err(ERANGE)
+        } else {
+            let value = attrs.get(buf_reader.by_ref(), sb, name)?;
+            Ok((len, Some(value)))
         }
     }
+}
 
-    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        let attr = self
-            .open_files
-            .get(&ino)
-            .expect("getattr before lookup")
-            .dinode
-            .di_core
-            .stat(ino)
-            .expect("Unknown file type");
+/// Pass the `entries` that follow `offset` to `add`, until it's full, and return how many were
+/// added.
+fn add_synthetic<F>(entries: &[(i64, u64, OsString)], offset: i64, add: &mut F) -> usize
+where
+    F: FnMut(u64, i64, FileType, &OsStr) -> bool,
+{
+    let mut count = 0;
+    for (off, ino, name) in entries.iter().filter(|(off, ..)| *off > offset) {
+        if add(*ino, *off, FileType::Directory, name) {
+            break;
+        }
+        count += 1;
+    }
+    count
+}
 
-        reply.attr(&Self::TTL, &attr)
+/// Reply to a getxattr request with a buffer of `size` bytes for an attribute that's already in
+/// memory.
+fn sized_xattr(value: Vec<u8>, size: u32) -> Result<(u32, Option<Vec<u8>>), c_int> {
+    let len = value.len() as u32;
+    match size {
+        0 => Ok((len, None)),
+        _ if size < len => Err(ERANGE),
+        _ => Ok((len, Some(value))),
     }
+}
 
-    fn init(&mut self, _req: &Request, config: &mut KernelConfig) -> Result<(), i32> {
-        if config.add_capabilities(FUSE_NO_OPEN_SUPPORT).is_ok() {
-            self.no_open = true;
-        }
-        if config.add_capabilities(FUSE_NO_OPENDIR_SUPPORT).is_ok() {
-            self.no_opendir = true;
+/// An inode that the kernel has looked up, but whose [`OpenInode`] has been evicted.  It holds
+/// just enough to account for FORGET, and to check the inode when it's reloaded.
+#[derive(Clone, Copy, Debug)]
+struct ColdInode {
+    gen:     u32,
+    lookups: Lookups,
+}
+
+/// Every inode that the kernel has looked up and not yet forgotten, and others that we've loaded
+/// for our own use.
+///
+/// Since attributes and entries have an infinite TTL, the kernel may hold onto an inode for the
+/// life of the mount, long after its last use.  So only the most recently used inodes are kept
+/// "hot", with their Dinodes and the caches that hang off of them.  The rest are "cold", and are
+/// reloaded from disk if they're ever used again.  Inodes that the kernel doesn't reference are
+/// simply dropped instead of going cold.
+///
+/// Hot inodes and their caches are charged to a [`CacheBudget`].  Cold ones aren't, since they're
+/// small and can't be dropped until the kernel forgets them.
+#[derive(Debug)]
+struct OpenFiles {
+    hot:         HashMap<u64, OpenInode>,
+    /// Hot inodes by when they were last used, least recent first
+    lru:         BTreeMap<u64, u64>,
+    cold:        HashMap<u64, ColdInode>,
+    /// The most hot inodes to keep.  At least 1.
+    capacity:    usize,
+    /// A clock that ticks once per use of any inode
+    tick:        u64,
+    budget:      Arc<CacheBudget>,
+    /// Hot inodes used since the last [`settle`](Self::settle), whose caches may have grown
+    touched:     HashSet<u64>,
+    /// The hot inodes that hold anything in each kind of cache but [`CacheKind::Inodes`], so
+    /// eviction needn't search every hot inode for the few with a directory or attributes cached
+    holders:     [HashSet<u64>; CacheKind::ALL.len()],
+    /// The kind of cache to evict from next, as an index into [`CacheKind::ALL`]
+    next_victim: usize,
+}
+
+impl OpenFiles {
+    fn new(capacity: usize, budget: Arc<CacheBudget>) -> Self {
+        OpenFiles {
+            hot: HashMap::new(),
+            lru: BTreeMap::new(),
+            cold: HashMap::new(),
+            capacity: capacity.max(1),
+            tick: 0,
+            budget,
+            touched: HashSet::new(),
+            holders: Default::default(),
+            next_victim: 0,
         }
-        let _ = config.add_capabilities(FUSE_ASYNC_READ | FUSE_EXPORT_SUPPORT);
-        Ok(())
     }
 
-    fn readlink(&mut self, _req: &Request, ino: u64, reply: fuser::ReplyData) {
-        self.device.set_bufsize(self.sb.sb_blocksize as usize);
-        reply.data(
-            self.open_files
-                .get(&ino)
-                .expect("readlink before lookup")
-                .dinode
-                .get_link_data(self.device.by_ref(), &self.sb)
-                .as_bytes(),
-        );
+    /// Is this inode hot, or referenced by the kernel?
+    fn contains(&self, ino: u64) -> bool {
+        self.hot.contains_key(&ino) || self.cold.contains_key(&ino)
     }
 
-    fn open(&mut self, _req: &Request, _ino: u64, _flags: i32, reply: ReplyOpen) {
-        if self.no_open {
-            reply.error(libc::ENOSYS)
-        } else {
-            reply.opened(0, FOPEN_KEEP_CACHE)
+    /// The number of inodes with their Dinodes cached
+    fn hot_len(&self) -> usize {
+        self.hot.len()
+    }
+
+    /// The number of inodes that the kernel has looked up
+    fn len(&self) -> usize {
+        let hot = self
+            .hot
+            .values()
+            .filter(|oi| oi.lookups.is_referenced())
+            .count();
+        hot + self.cold.len()
+    }
+
+    /// Apply `f` to every hot inode's Dinode, and return how many times it returned true.
+    fn count_hot<F>(&mut self, mut f: F) -> usize
+    where
+        F: FnMut(&mut Dinode) -> bool,
+    {
+        let budget = &self.budget;
+        let holders = &mut self.holders;
+        self.hot
+            .iter_mut()
+            .map(|(ino, oi)| {
+                let dropped = f(&mut oi.dinode);
+                oi.recharge(budget);
+                note_holder(holders, *ino, oi);
+                dropped
+            })
+            .filter(|&dropped| dropped)
+            .count()
+    }
+
+    /// Forget every inode
+    fn clear(&mut self) {
+        for oi in self.hot.values_mut() {
+            oi.discharge(&self.budget);
         }
+        self.hot.clear();
+        self.lru.clear();
+        self.cold.clear();
+        self.touched.clear();
+        self.holders = Default::default();
     }
 
-    fn read(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        size: u32,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        reply: fuser::ReplyData,
-    ) {
-        let oi = &self.open_files.get(&ino).unwrap();
-        self.device.set_bufsize(self.sb.sb_blocksize as usize);
+    /// Drop a hot inode's Dinode and caches.  It goes cold if the kernel references it.
+    fn evict(&mut self, ino: u64) {
+        let mut evicted = self.hot.remove(&ino).unwrap();
+        evicted.discharge(&self.budget);
+        note_holder(&mut self.holders, ino, &evicted);
+        self.lru.remove(&evicted.last_used);
+        self.touched.remove(&ino);
+        if evicted.lookups.is_referenced() {
+            self.cold.insert(
+                ino,
+                ColdInode {
+                    gen:     evicted.dinode.di_core.di_gen,
+                    lookups: evicted.lookups,
+                },
+            );
+        }
+    }
 
-        let file = oi.dinode.get_file(self.device.by_ref());
+    /// Add a hot inode, evicting the least recently used if necessary.
+    fn insert(&mut self, ino: u64, mut oi: OpenInode) -> &mut OpenInode {
+        while self.hot.len() >= self.capacity {
+            let Some(&victim) = self.lru.values().next() else {
+                break;
+            };
+            self.evict(victim);
+        }
+        self.tick += 1;
+        oi.last_used = self.tick;
+        self.lru.insert(self.tick, ino);
+        self.touched.insert(ino);
+        let oi = self.hot.entry(ino).or_insert(oi);
+        oi.recharge(&self.budget);
+        note_holder(&mut self.holders, ino, oi);
+        oi
+    }
 
-        match file.read(self.device.by_ref(), offset, size) {
-            Ok((v, ignore)) => reply.data(&v[ignore..]),
-            Err(e) => reply.error(e),
+    /// Get an inode that the kernel has already looked up, or that is still cached from an
+    /// internal load, loading it with `load` if it's cold.
+    fn get<F>(&mut self, ino: u64, load: F) -> Result<&mut OpenInode, c_int>
+    where
+        F: FnOnce(u64) -> Result<Dinode, c_int>,
+    {
+        if self.hot.contains_key(&ino) {
+            stats::count(Event::InodeHit);
+            self.tick += 1;
+            let oi = self.hot.get_mut(&ino).unwrap();
+            self.lru.remove(&oi.last_used);
+            oi.last_used = self.tick;
+            self.lru.insert(self.tick, ino);
+            self.touched.insert(ino);
+            return Ok(oi);
+        }
+        let Some(cold) = self.cold.get(&ino).copied() else {
+            error!("Operation on inode {} before lookup", ino);
+            return Err(libc::ESTALE);
+        };
+        stats::count(Event::InodeMiss);
+        let dinode = load(ino)?;
+        if dinode.di_core.di_gen != cold.gen {
+            error!(
+                "Inode {}'s generation changed from {} to {}",
+                ino, cold.gen, dinode.di_core.di_gen
+            );
+            return Err(libc::ESTALE);
         }
+        self.cold.remove(&ino);
+        let mut oi = OpenInode::new(dinode);
+        oi.lookups = cold.lookups;
+        Ok(self.insert(ino, oi))
     }
 
-    fn opendir(&mut self, _req: &Request, _ino: u64, _flags: i32, reply: ReplyOpen) {
-        if self.no_opendir {
-            reply.error(libc::ENOSYS)
+    /// Get an inode for our own use, loading it with `load` if necessary.  Unlike
+    /// [`entry`](Self::entry), this doesn't take a reference.
+    fn load<F>(&mut self, ino: u64, load: F) -> Result<&mut OpenInode, c_int>
+    where
+        F: FnOnce(u64) -> Result<Dinode, c_int>,
+    {
+        if self.hot.contains_key(&ino) || self.cold.contains_key(&ino) {
+            self.get(ino, load)
         } else {
-            reply.opened(0, FOPEN_CACHE_DIR)
+            stats::count(Event::InodeMiss);
+            Ok(self.insert(ino, OpenInode::new(load(ino)?)))
         }
     }
 
-    fn readdir(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        mut reply: ReplyDirectory,
-    ) {
-        let dirsize = self.sb.sb_blocksize << self.sb.sb_dirblklog;
-        self.device.set_bufsize(dirsize as usize);
-        let oi = &mut self.open_files.get_mut(&ino).unwrap();
+    /// Take a reference on behalf of the kernel, once a reply is certain to hand it an entry
+    /// for this inode, which must already be loaded.
+    fn entry(&mut self, ino: u64) {
+        if let Some(oi) = self.hot.get_mut(&ino) {
+            oi.lookups = oi.lookups.entry();
+        } else if let Some(cold) = self.cold.get_mut(&ino) {
+            cold.lookups = cold.lookups.entry();
+        } else {
+            panic!(
+                "Replying with an entry for inode {}, which isn't loaded",
+                ino
+            );
+        }
+    }
+
+    /// Release the kernel's references, and stop tracking the inode once none remain.  Return
+    /// what happened, or None if the inode wasn't being tracked.
+    fn forget(&mut self, ino: u64, nlookup: u64) -> Option<Forgotten> {
+        let forgotten = if let Some(cold) = self.cold.get_mut(&ino) {
+            let forgotten;
+            (cold.lookups, forgotten) = cold.lookups.forget(nlookup);
+            forgotten
+        } else {
+            let oi = self.hot.get_mut(&ino)?;
+            let forgotten;
+            (oi.lookups, forgotten) = oi.lookups.forget(nlookup);
+            forgotten
+        };
+        if !matches!(forgotten, Forgotten::Remaining(_)) {
+            self.cold.remove(&ino);
+            if let Some(mut oi) = self.hot.remove(&ino) {
+                oi.discharge(&self.budget);
+                note_holder(&mut self.holders, ino, &oi);
+                self.lru.remove(&oi.last_used);
+                self.touched.remove(&ino);
+            }
+        }
+        Some(forgotten)
+    }
 
-        let dir = oi.dinode.get_dir(self.device.by_ref(), &self.sb);
+    /// Charge the budget for whatever the inodes used since the last call have cached, and then
+    /// evict until it's within its limit.  The kinds of cache take turns giving up their least
+    /// recently used inode's entry, or as many of its blocks as that takes.
+    fn settle(&mut self) {
+        for ino in mem::take(&mut self.touched) {
+            if let Some(oi) = self.hot.get_mut(&ino) {
+                oi.recharge(&self.budget);
+                note_holder(&mut self.holders, ino, oi);
+            }
+        }
+        while self.budget.exceeded() {
+            if !self.evict_one() {
+                break;
+            }
+        }
+    }
 
-        let mut off = offset;
-        loop {
-            let res = dir.next(self.device.by_ref(), &self.sb, off);
-            match res {
-                Ok((ino, offset, kind, name)) => {
-                    // FUSE requires the file system's root directory to have a
-                    // fixed inode number.
-                    let ino = if ino == self.sb.sb_rootino {
-                        FUSE_ROOT_ID
-                    } else {
-                        ino
-                    };
-                    let kind = match kind {
-                        Some(kind) => kind,
-                        None => {
-                            // This is very inefficient.  Frequently, getattr will be called for
-                            // every entry returned by readdir.  In such cases, this code will read
-                            // the inode twice.  The best solution is for everybody to use the
-                            // ftype option in their XFS format.
-                            self.device.set_bufsize(self.sb.inode_size());
-                            let dinode = Dinode::from(
-                                self.device.by_ref(),
-                                &self.sb,
-                                if ino == FUSE_ROOT_ID {
-                                    self.sb.sb_rootino
-                                } else {
-                                    ino as XfsIno
-                                },
-                            );
-                            match dinode.di_core.stat(ino) {
-                                Ok(attr) => attr.kind,
-                                Err(e) => {
-                                    reply.error(e);
-                                    return;
-                                }
-                            }
-                        }
-                    };
-                    let res = reply.add(ino, offset, kind, name);
-                    if res {
-                        reply.ok();
-                        return;
-                    }
-                    off = offset;
-                }
-                // TODO: don't ignore errors other than ENOENT
-                Err(_) => {
-                    reply.ok();
-                    return;
+    /// Evict one entry from the next kind of cache that has any.  Return false if none do.
+    fn evict_one(&mut self) -> bool {
+        for _ in CacheKind::ALL {
+            let kind = CacheKind::ALL[self.next_victim];
+            self.next_victim = (self.next_victim + 1) % CacheKind::ALL.len();
+            let victim = if kind == CacheKind::Inodes {
+                self.lru.values().next().copied()
+            } else {
+                self.holders[kind as usize]
+                    .iter()
+                    .copied()
+                    .min_by_key(|ino| self.hot[ino].last_used)
+            };
+            let Some(ino) = victim else {
+                continue;
+            };
+            if kind == CacheKind::Inodes {
+                self.evict(ino);
+            } else {
+                // Give up only as many of the inode's blocks as needed to get back within the
+                // limit, so a large directory that's still in use needn't be read all over again.
+                let excess = self.budget.excess();
+                let oi = self.hot.get_mut(&ino).unwrap();
+                let target = oi.charged[kind as usize].saturating_sub(excess) as usize;
+                if kind == CacheKind::Directories {
+                    oi.dinode.shrink_dir_cache(target);
+                } else {
+                    oi.dinode.shrink_attr_cache(target);
                 }
+                oi.recharge(&self.budget);
+                note_holder(&mut self.holders, ino, oi);
             }
+            return true;
         }
+        false
     }
+}
 
-    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
-        reply.statfs(
-            self.sb.sb_dblocks - u64::from(self.sb.sb_logblocks),
-            self.sb.sb_fdblocks,
-            self.sb.sb_fdblocks,
-            self.sb.sb_icount,
-            self.sb.sb_ifree,
-            self.sb.sb_blocksize,
-            255,
-            self.sb.sb_blocksize,
-        )
+/// Record whether a hot inode holds anything in each kind of cache but [`CacheKind::Inodes`], after
+/// its charges change.
+fn note_holder(holders: &mut [HashSet<u64>; CacheKind::ALL.len()], ino: u64, oi: &OpenInode) {
+    for kind in [CacheKind::Directories, CacheKind::Attributes] {
+        if oi.charged[kind as usize] > 0 {
+            holders[kind as usize].insert(ino);
+        } else {
+            holders[kind as usize].remove(&ino);
+        }
     }
+}
 
-    fn getxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
-        let mut nameparts = name.as_bytes().splitn(2, |c| *c == b'.');
-        let _namespace = nameparts.next().unwrap();
-        let name = OsStr::from_bytes(nameparts.next().unwrap());
+/// Read an inode from disk, given its FUSE inode number.
+fn load_dinode(device: &mut BlockReader, sb: &Sb, ino: u64) -> Result<Dinode, c_int> {
+    device.set_bufsize(sb.inode_size());
+    Dinode::from(device.by_ref(), sb, xfs_ino(sb, ino))
+}
 
-        let oi = &mut self.open_files.get_mut(&ino).unwrap();
-        self.device.set_bufsize(self.sb.sb_blocksize as usize);
-        match oi.dinode.get_attrs(self.device.by_ref(), &self.sb) {
-            Some(attrs) => match attrs.get(self.device.by_ref(), &self.sb, name) {
-                Ok(value) => {
-                    let len: u32 = value.len().try_into().unwrap();
-                    if size == 0 {
-                        reply.size(len);
-                    } else if len > size {
-                        reply.error(ERANGE);
-                    } else {
-                        reply.data(value.as_slice())
-                    }
-                }
-                Err(e) => reply.error(e),
-            },
-            None => {
-                reply.error(libc::ENOATTR);
-            }
+/// Widen permissions as if by `chmod a+rX`: readable by everyone, and searchable or executable by
+/// everyone if it's a directory or already executable by anyone.
+fn unrestrict(kind: FileType, perm: u16) -> u16 {
+    if kind == FileType::Directory || perm & 0o111 != 0 {
+        perm | 0o555
+    } else {
+        perm | 0o444
+    }
+}
+
+/// A virtual extended attribute that reports whether a file's operations are being traced
+const TRACE_XATTR: &[u8] = b"user.xfuse.trace";
+
+/// A virtual extended attribute of every regular file that describes its extents as JSON, and
+/// which of their blocks are shared with other files
+const LAYOUT_XATTR: &[u8] = b"user.xfuse.layout";
+
+/// A virtual extended attribute on the root directory that reports statistics as JSON, like the
+/// number of validation failures found so far and the number of inodes cached
+const STATS_XATTR: &[u8] = b"user.xfuse.stats";
+
+/// The virtual extended attributes that the root directory lists, unless it has real ones of the
+/// same names
+const ROOT_XATTRS: &[&[u8]] = &[STATS_XATTR];
+
+/// The name of the synthetic root directory that holds every allocated inode, with
+/// [`Options::inodes_dir`].  It hides any real entry of the same name.
+const INODES_DIR: &[u8] = b".inodes";
+
+/// The FUSE inode number of [`INODES_DIR`].  No XFS inode can have this number, since it would lie
+/// within AG 0's headers.
+const INODES_DIR_INO: u64 = 2;
+
+/// The root directory's readdir offset for [`INODES_DIR`], after all of its real entries
+const INODES_DIR_OFFSET: i64 = i64::MAX;
+
+/// The name of the synthetic directory of file system metadata, with [`Options::metadir`].  If
+/// the root directory has a real entry of the same name, a numeric suffix is added instead.
+const METADIR: &str = ".xfuse";
+
+/// The FUSE inode number of [`METADIR`].  Its files' numbers follow.  Like [`INODES_DIR_INO`], no
+/// XFS inode can have these numbers.
+const METADIR_INO: u64 = 3;
+
+/// The root directory's readdir offset for [`METADIR`], after all of its real entries but before
+/// [`INODES_DIR`]
+const METADIR_OFFSET: i64 = i64::MAX - 1;
+
+/// The virtual files in [`METADIR`]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum MetaFile {
+    /// The file system's geometry and features
+    Superblock,
+    /// The same counters as [`STATS_XATTR`]
+    Stats,
+    /// The device, options, and FUSE capabilities of this mount
+    Mount,
+}
+
+impl MetaFile {
+    const ALL: [MetaFile; 3] = [MetaFile::Superblock, MetaFile::Stats, MetaFile::Mount];
+
+    fn ino(self) -> u64 {
+        METADIR_INO + 1 + self as u64
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            MetaFile::Superblock => "superblock.json",
+            MetaFile::Stats => "stats.json",
+            MetaFile::Mount => "mount.json",
         }
     }
+}
 
-    fn listxattr(&mut self, _req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
-        let oi = &mut self
-            .open_files
-            .get_mut(&ino)
-            .expect("listxattr before lookup");
-        self.device.set_bufsize(self.sb.sb_blocksize as usize);
-        match oi.dinode.get_attrs(self.device.by_ref(), &self.sb) {
-            Some(ref mut attrs) => {
-                let attrs_size = attrs.get_total_size(self.device.by_ref(), &self.sb);
+/// Choose a name for [`METADIR`] that none of the root directory's real entries has.
+fn metadir_name(device: &mut BlockReader, sb: &Sb, root: &mut Dinode) -> Result<OsString, c_int> {
+    let dirsize = sb.sb_blocksize << sb.sb_dirblklog;
+    device.set_bufsize(dirsize as usize);
+    let dir = root.get_dir(device.by_ref(), sb)?;
+    let mut name = OsString::from(METADIR);
+    for i in 1.. {
+        match dir.lookup(device.by_ref(), sb, &name) {
+            Ok(_) => name = OsString::from(format!("{}.{}", METADIR, i)),
+            Err(libc::ENOENT) => break,
+            Err(e) => return Err(e),
+        }
+    }
+    if name != METADIR {
+        warn!(
+            "The root directory already has an entry named {:?}, so the metadata directory is \
+             named {:?} instead",
+            METADIR, name
+        );
+    }
+    Ok(name)
+}
 
-                if size == 0 {
-                    reply.size(attrs_size);
-                    return;
-                }
+/// The longest extended attribute list that listxattr will return, like Linux's XATTR_LIST_MAX.
+/// No sane file has one this long, but a corrupt attribute fork could claim to.
+const XATTR_LIST_MAX: u32 = 1 << 16;
 
-                if attrs_size > size {
-                    reply.error(ERANGE);
-                    return;
-                }
+/// The default for [`Options::hot_inodes`]
+const DEFAULT_HOT_INODES: usize = 16384;
 
-                let list = attrs.list(self.device.by_ref(), &self.sb);
-                // Assert that we calculated the list size correctly.  This assertion is only
-                // safe since we're a read-only file system.
-                assert_eq!(
-                    list.len(),
-                    attrs_size as usize,
-                    "size calculation was wrong!"
-                );
-                reply.data(list.as_slice());
-            }
-            None => {
-                reply.size(0);
-            }
+/// The [`Options::readahead`] that xfs-fuse uses unless told otherwise
+pub const DEFAULT_READAHEAD: u32 = 1 << 20;
+
+/// The most file handles whose [`Readahead`] to keep.  When the kernel skips opens, every reader
+/// of an inode shares one, and there's no release to drop it.
+const READAHEAD_HANDLES: usize = 64;
+
+/// Where a file handle's reads have reached, and what was read ahead for it
+#[derive(Debug, Default)]
+struct Readahead {
+    /// Where the last read ended.  A read from here is sequential.
+    next:  i64,
+    /// The offset of `data` within the file
+    start: i64,
+    data:  Vec<u8>,
+    /// Does `data` end at EOF?
+    eof:   bool,
+}
+
+impl Readahead {
+    /// Copy out `size` bytes from `offset`, if they were read ahead, or as many as there are
+    /// before EOF.
+    fn take(&mut self, offset: i64, size: u32) -> Option<Vec<u8>> {
+        let from = usize::try_from(offset.checked_sub(self.start)?).ok()?;
+        if from > self.data.len() {
+            return None;
         }
+        let to = from.saturating_add(size as usize).min(self.data.len());
+        if to - from < size as usize && !self.eof {
+            return None;
+        }
+        self.next = offset + (to - from) as i64;
+        Some(self.data[from..to].to_vec())
+    }
+}
+
+/// Runtime settings for a [`Volume`], mostly controlled by mount options
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// Fail device reads with EIO if they take too long
+    pub read_timeout:     Option<ReadTimeout>,
+    /// Omit directory entries with invalid inode numbers from readdir, rather than failing with
+    /// EIO
+    pub skip_bad_entries: bool,
+    /// Log every operation on these inodes, along with the device reads that it makes
+    pub trace_inos:       HashSet<XfsIno>,
+    /// Check every allocation group's headers at mount time, and refuse to mount if the root
+    /// directory's AG is damaged
+    pub check_ags:        bool,
+    /// Enable every metadata check, and fail with EIO whenever one of them does.  Implies
+    /// `check_ags`, and refuses to mount if any AG is damaged.
+    pub strict:           bool,
+    /// Append a record of every lookup, read, and similar operation to this file
+    pub audit_log:        Option<PathBuf>,
+    /// The most inodes whose Dinodes to keep cached.  Others that the kernel still references
+    /// are reloaded from disk when needed.
+    pub hot_inodes:       Option<usize>,
+    /// The most bytes of memory that all caches together may use.  Beyond that, they evict their
+    /// least recently used entries.
+    pub mem_limit:        Option<u64>,
+    /// Expose every allocated inode, named by its number, in a synthetic `/.inodes` directory
+    pub inodes_dir:       bool,
+    /// Report every inode as readable, and directories as searchable, by everyone
+    pub norestrict:       bool,
+    /// Expose the file system's geometry, our statistics, and this mount's settings as JSON files
+    /// in a synthetic `/.xfuse` directory
+    pub metadir:          bool,
+    /// Open the device for writing, and allow overwriting data that regular files already have
+    /// blocks for.  Nothing is ever allocated or freed.
+    pub rw:               bool,
+    /// The realtime device, where realtime files keep their data
+    pub rtdev:            Option<PathBuf>,
+    /// How many threads read regular files' data, so that slow reads don't delay other
+    /// operations.  With 0, reads are done in turn with everything else.
+    pub threads:          usize,
+    /// How many bytes to read at once when a file handle reads sequentially.  Its later reads
+    /// are served from them.  With 0, or no more than a read asks for, nothing is read ahead.
+    pub readahead:        u32,
+    /// Read the device with sector-aligned reads, even if it's a regular file that could be
+    /// memory-mapped
+    pub no_mmap:          bool,
+}
+
+/// A directory entry, as listed by [`Volume::read_dir`]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DirEntry {
+    pub ino:  u64,
+    pub kind: FileType,
+    pub name: OsString,
+}
+
+/// An XFS file system.  It implements fuser's `Filesystem` when the `fuse` feature is enabled,
+/// and can also be used directly by inode number, from [`ROOT_INO`] down.
+#[derive(Debug)]
+pub struct Volume {
+    pub device:       BlockReader,
+    device_name:      PathBuf,
+    /// The realtime device, if [`Options::rtdev`] was given
+    rtdev:            Option<BlockReader>,
+    pub sb:           Sb,
+    open_files:       OpenFiles,
+    /// The memory used by every cache, shared with the caches themselves
+    cache_budget:     Arc<CacheBudget>,
+    /// FUSE capabilities negotiated during init.  Empty until then.
+    capabilities:     u32,
+    opts:             Options,
+    audit:            Option<AuditLog>,
+    /// Every allocated inode, in ascending order.  Loaded when [`INODES_DIR`] is first used.
+    inodes:           Option<Vec<XfsIno>>,
+    /// The number of directory entries found whose file type disagrees with their inode's
+    ftype_mismatches: u64,
+    /// When the file system was opened
+    opened:           SystemTime,
+    /// The name of [`METADIR`] in the root directory
+    metadir_name:     OsString,
+    /// The contents of each open [`MetaFile`], by file handle, generated when it was opened
+    meta_handles:     HashMap<u64, Vec<u8>>,
+    /// The next file handle for an open file.  Without opens, as when the kernel skips them,
+    /// reads use 0.
+    next_fh:          u64,
+    /// Each file handle's [`Readahead`], by inode and handle
+    readahead:        LruMap<(u64, u64), Readahead>,
+    /// The threads that read regular files' data, if [`Options::threads`] is nonzero
+    read_pool:        Option<ReadPool>,
+    /// Counters of operations, cache hits, and device reads, shared with the devices
+    stats:            Arc<Stats>,
+    /// This mount's validation policy, and the failures it has found
+    validator:        Arc<Validator>,
+}
+
+impl Volume {
+    // Allow the kernel to cache attributes and entries for an unlimited amount
+    // of time, since nothing will ever change.
+    const TTL: Duration = Duration::from_secs(u64::MAX);
+
+    /// Open the file system on the given device.
+    pub fn open(device_name: &Path, opts: Options) -> io::Result<Volume> {
+        let mut device = if opts.rw {
+            BlockReader::open_rw(device_name)?
+        } else {
+            BlockReader::open(device_name)?
+        };
+        device.set_read_timeout(opts.read_timeout)?;
+        // A mapped image makes no device reads to trace
+        if opts.no_mmap || !opts.trace_inos.is_empty() {
+            device.unmap();
+        }
+        let stats = device.stats().clone();
+        stats.enter();
+
+        let superblock = Sb::from(device.by_ref())
+            .map_err(|e| io::Error::other(format!("{}: {}", device_name.display(), e)))?;
+        check_sectorsize(&superblock, device.device_sectorsize())
+            .map_err(|msg| io::Error::other(format!("{}: {}", device_name.display(), msg)))?;
+        check_ro_compat(&superblock, opts.rw)
+            .map_err(|msg| io::Error::other(format!("{}: {}", device_name.display(), msg)))?;
+        if opts.rw {
+            log::check_clean(&mut device, &superblock)
+                .map_err(|msg| io::Error::other(format!("{}: {}", device_name.display(), msg)))?;
+        }
+        let rtdev = match opts.rtdev {
+            Some(ref path) => {
+                if superblock.sb_rblocks == 0 {
+                    let msg = format!(
+                        "{}: the file system has no realtime section, but --rtdev was given",
+                        device_name.display()
+                    );
+                    return Err(io::Error::other(msg));
+                }
+                let mut rtdev = BlockReader::open(path)
+                    .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+                rtdev.set_read_timeout(opts.read_timeout)?;
+                if opts.no_mmap || !opts.trace_inos.is_empty() {
+                    rtdev.unmap();
+                }
+                rtdev.set_stats(stats.clone());
+                Some(rtdev)
+            }
+            None => {
+                if superblock.sb_rblocks > 0 {
+                    warn!(
+                        "{}: the file system has a realtime section, but no --rtdev was given.  \
+                         Reading realtime files will fail with EIO.",
+                        device_name.display()
+                    );
+                }
+                None
+            }
+        };
+        let validator = Arc::new(Validator::new(validate::Config {
+            strict: opts.strict,
+        }));
+        validator.enter();
+
+        if opts.check_ags || opts.strict {
+            let root_agno = superblock.ino_to_agno(superblock.sb_rootino);
+            for status in ag::check_all(&mut device, &superblock) {
+                if status.is_ok() {
+                    continue;
+                }
+                let msg = format!(
+                    "AG {} is damaged: {}",
+                    status.agno,
+                    status.problems.join("; ")
+                );
+                if u64::from(status.agno) == root_agno {
+                    let msg = format!(
+                        "{}: {}, and it contains the root directory",
+                        device_name.display(),
+                        msg
+                    );
+                    return Err(io::Error::other(msg));
+                }
+                if opts.strict {
+                    let msg = format!("{}: {}", device_name.display(), msg);
+                    return Err(io::Error::other(msg));
+                }
+                warn!("{}", msg);
+            }
+        }
+
+        let audit = match opts.audit_log {
+            Some(ref path) => Some(
+                AuditLog::open(path)
+                    .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?,
+            ),
+            None => None,
+        };
+
+        // Check the root inode's core before decoding the rest of it, since Dinode::from can't
+        // cope with a directory format on a non-directory.
+        let root_offset = superblock
+            .ino_to_offset(superblock.sb_rootino)
+            .map_err(|_| {
+                io::Error::other(format!(
+                    "{}: invalid root inode number {}",
+                    device_name.display(),
+                    superblock.sb_rootino
+                ))
+            })?;
+        device.seek(SeekFrom::Start(root_offset))?;
+        let root_core: DinodeCore = decode_from(device.by_ref()).map_err(io::Error::other)?;
+        check_root(&root_core)
+            .map_err(|msg| io::Error::other(format!("{}: {}", device_name.display(), msg)))?;
+        let mut root_inode = Dinode::from(device.by_ref(), &superblock, superblock.sb_rootino)
+            .map_err(io::Error::from_raw_os_error)?;
+        let metadir_name = if opts.metadir {
+            metadir_name(&mut device, &superblock, &mut root_inode)
+                .map_err(io::Error::from_raw_os_error)?
+        } else {
+            OsString::from(METADIR)
+        };
+        let cache_budget = Arc::new(CacheBudget::new(opts.mem_limit));
+        let mut open_files = OpenFiles::new(
+            opts.hot_inodes.unwrap_or(DEFAULT_HOT_INODES),
+            cache_budget.clone(),
+        );
+        // Prepopulate the root inode into the cache, since fusefs never sends a lookup for it.
+        // The kernel references it from the start, so it can never be dropped.
+        open_files.insert(ROOT_INO, OpenInode::new(root_inode));
+        open_files.entry(ROOT_INO);
+        let read_pool = match opts.threads {
+            0 => None,
+            n => Some(ReadPool::new(
+                n,
+                &device,
+                rtdev.as_ref(),
+                superblock.sb_blocksize as usize,
+            )?),
+        };
+
+        Ok(Volume {
+            device,
+            device_name: device_name.to_owned(),
+            rtdev,
+            sb: superblock,
+            open_files,
+            cache_budget,
+            capabilities: 0,
+            opts,
+            audit,
+            inodes: None,
+            ftype_mismatches: 0,
+            opened: SystemTime::now(),
+            metadir_name,
+            meta_handles: HashMap::new(),
+            next_fh: 1,
+            readahead: LruMap::default(),
+            read_pool,
+            stats,
+            validator,
+        })
+    }
+
+    /// Did the kernel agree that open is optional?  If an open arrives before init completes,
+    /// this is false, and we'll handle it normally.
+    #[cfg(feature = "fuse")]
+    fn no_open(&self) -> bool {
+        self.capabilities & FUSE_NO_OPEN_SUPPORT != 0
+    }
+
+    /// Can the kernel look up inodes by file handle, as an NFS server does?  Only then do inode
+    /// generation numbers matter.
+    #[cfg(feature = "fuse")]
+    fn export_support(&self) -> bool {
+        self.capabilities & FUSE_EXPORT_SUPPORT != 0
+    }
+
+    /// Without FUSE, there's no kernel to use file handles.
+    #[cfg(not(feature = "fuse"))]
+    fn export_support(&self) -> bool {
+        false
+    }
+
+    /// Did the kernel agree that opendir is optional?
+    #[cfg(feature = "fuse")]
+    fn no_opendir(&self) -> bool {
+        self.capabilities & FUSE_NO_OPENDIR_SUPPORT != 0
+    }
+
+    /// Are this inode's operations being traced?
+    fn is_traced(&self, ino: u64) -> bool {
+        self.opts.trace_inos.contains(&xfs_ino(&self.sb, ino))
+    }
+
+    /// Start recording device reads for an operation, if any inodes are being traced.
+    fn trace_begin(&self) {
+        if !self.opts.trace_inos.is_empty() {
+            trace::start();
+        }
+    }
+
+    /// Log an operation, its result, and the device reads that it made, if it touched a traced
+    /// inode.
+    fn trace_end<F, T>(&self, inos: &[u64], op: F, result: &T)
+    where
+        F: FnOnce() -> String,
+        T: fmt::Debug,
+    {
+        if self.opts.trace_inos.is_empty() {
+            return;
+        }
+        let reads = trace::finish();
+        if inos.iter().any(|ino| self.is_traced(*ino)) {
+            info!(
+                "ino {:?}: {} => {:?}; device reads (offset, length): {:?}",
+                inos,
+                op(),
+                result,
+                reads
+            );
+        }
+    }
+
+    /// Append an operation to the audit log, if there is one.
+    fn audit(&self, op: &str, ino: u64, rec: Record) {
+        if let Some(ref audit) = self.audit {
+            audit.record(op, ino, &rec);
+        }
+    }
+
+    /// Is this the synthetic [`INODES_DIR`]?
+    fn is_inodes_dir(&self, ino: u64) -> bool {
+        self.opts.inodes_dir && ino == INODES_DIR_INO
+    }
+
+    /// Return every allocated inode, walking the inode btrees on first use.
+    fn allocated_inodes(&mut self) -> Result<&[XfsIno], c_int> {
+        if self.inodes.is_none() {
+            self.device.set_bufsize(self.sb.sb_blocksize as usize);
+            let inodes = inobt::allocated(&mut self.device, &self.sb).map_err(|e| {
+                error!("Cannot enumerate allocated inodes: {}", e);
+                libc::EIO
+            })?;
+            self.inodes = Some(inodes);
+        }
+        Ok(self.inodes.as_deref().unwrap())
+    }
+
+    /// Is this the synthetic [`METADIR`]?
+    fn is_metadir(&self, ino: u64) -> bool {
+        self.opts.metadir && ino == METADIR_INO
+    }
+
+    /// Which [`MetaFile`], if any, has this inode number?
+    fn meta_file(&self, ino: u64) -> Option<MetaFile> {
+        if !self.opts.metadir {
+            return None;
+        }
+        MetaFile::ALL.into_iter().find(|file| file.ino() == ino)
+    }
+
+    /// Is this a synthetic inode, with no XFS inode behind it?
+    fn is_synthetic(&self, ino: u64) -> bool {
+        self.is_inodes_dir(ino) || self.is_metadir(ino) || self.meta_file(ino).is_some()
+    }
+
+    /// The attributes of a synthetic inode, which borrows its ownership and times from the root.
+    fn synthetic_attr(&mut self, ino: u64, kind: FileType, size: u64) -> Result<FileAttr, c_int> {
+        let root = self
+            .open_files
+            .get(ROOT_INO, |ino| load_dinode(&mut self.device, &self.sb, ino))?;
+        let mut attr = root.dinode.stat(ROOT_INO, &self.sb)?;
+        attr.ino = ino;
+        attr.kind = kind;
+        attr.size = size;
+        attr.blocks = size.div_ceil(512);
+        if kind == FileType::Directory {
+            attr.nlink = 2;
+            attr.perm = 0o555;
+        } else {
+            attr.nlink = 1;
+            attr.perm = 0o444;
+        }
+        Ok(attr)
+    }
+
+    /// The attributes of [`INODES_DIR`]
+    fn inodes_dir_attr(&mut self) -> Result<FileAttr, c_int> {
+        self.synthetic_attr(INODES_DIR_INO, FileType::Directory, 0)
+    }
+
+    /// The attributes of a [`MetaFile`].  Its size is that of its current contents.
+    fn meta_attr(&mut self, file: MetaFile) -> Result<FileAttr, c_int> {
+        let len = self.meta_contents(file).len() as u64;
+        self.synthetic_attr(file.ino(), FileType::RegularFile, len)
+    }
+
+    /// The counters reported by [`STATS_XATTR`], as JSON
+    fn stats_json(&self) -> String {
+        format!(
+            "{{\"validation_failures\":{},\"hot_inodes\":{},\"tracked_inodes\":{},\"\
+             ftype_mismatches\":{},\"cache\":{},\"activity\":{}}}",
+            self.validator.failures(),
+            self.open_files.hot_len(),
+            self.open_files.len(),
+            self.ftype_mismatches,
+            self.cache_budget.to_json(),
+            self.stats.to_json()
+        )
+    }
+
+    /// The counters of this mount's operations, cache hits, and device reads.  Other threads may
+    /// report them while it's mounted.
+    pub fn stats(&self) -> Arc<Stats> {
+        self.stats.clone()
+    }
+
+    /// Count a FUSE operation, and make this mount's counters and validation policy the ones that
+    /// it uses.
+    #[cfg(feature = "fuse")]
+    fn enter(&self, op: Op) {
+        self.stats.op(op);
+        self.validator.enter();
+    }
+
+    /// The extents reported by [`LAYOUT_XATTR`], as JSON, or None if the inode isn't a regular
+    /// file.  Offsets, block numbers, and lengths are in file system blocks.  Like FIEMAP, an
+    /// extent that's partly shared is split into shared and unshared pieces.
+    fn layout_json(&mut self, ino: u64) -> Result<Option<String>, c_int> {
+        if self.is_synthetic(ino) {
+            return Ok(None);
+        }
+        let oi = self
+            .open_files
+            .get(ino, |ino| load_dinode(&mut self.device, &self.sb, ino))?;
+        if oi.dinode.di_core.stat(ino)?.kind != FileType::RegularFile {
+            return Ok(None);
+        }
+        let reflink = oi.dinode.di_core.reflink();
+        self.device.set_bufsize(self.sb.sb_blocksize as usize);
+        let file = oi.dinode.get_file(self.device.by_ref())?;
+        let nblocks = (file.size() as u64).div_ceil(self.sb.sb_blocksize.into());
+        let mut extents = Vec::new();
+        let mut offset = 0;
+        while offset < nblocks {
+            let (mapping, len) = file.get_extent(self.device.by_ref(), &self.sb, offset);
+            if len == 0 {
+                break;
+            }
+            match mapping {
+                Mapping::Hole => (),
+                Mapping::Unwritten => extents.push(format!(
+                    "{{\"offset\":{},\"block\":null,\"length\":{},\"unwritten\":true,\"shared\":\
+                     false}}",
+                    offset, len
+                )),
+                Mapping::Data(fsbno) => {
+                    let shared = if reflink {
+                        refcount::shared(
+                            &mut self.device,
+                            &self.sb,
+                            fsbno,
+                            XfsExtlen::try_from(len).map_err(|_| libc::EIO)?,
+                        )
+                        .map_err(|e| {
+                            error!("ino {}: cannot search the refcount btree: {}", ino, e);
+                            libc::EIO
+                        })?
+                    } else {
+                        Vec::new()
+                    };
+                    let mut pieces = Vec::new();
+                    let mut start = fsbno;
+                    for (bno, blen) in shared {
+                        if bno > start {
+                            pieces.push((start, bno - start, false));
+                        }
+                        pieces.push((bno, u64::from(blen), true));
+                        start = bno + u64::from(blen);
+                    }
+                    if start < fsbno + len {
+                        pieces.push((start, fsbno + len - start, false));
+                    }
+                    for (bno, blen, shared) in pieces {
+                        extents.push(format!(
+                            "{{\"offset\":{},\"block\":{},\"length\":{},\"unwritten\":false,\"\
+                             shared\":{}}}",
+                            offset + bno - fsbno,
+                            bno,
+                            blen,
+                            shared
+                        ));
+                    }
+                }
+            }
+            offset += len;
+        }
+        Ok(Some(format!(
+            "{{\"blocksize\":{},\"extents\":[{}]}}",
+            self.sb.sb_blocksize,
+            extents.join(",")
+        )))
+    }
+
+    /// The device, whether it's memory-mapped, options, and FUSE capabilities of this mount, as
+    /// JSON
+    fn mount_json(&self) -> String {
+        let mut device = Vec::new();
+        write_json_str(&mut device, self.device_name.as_os_str()).unwrap();
+        let opts = &self.opts;
+        let rtdev = match opts.rtdev {
+            Some(ref path) => {
+                let mut rtdev = Vec::new();
+                write_json_str(&mut rtdev, path.as_os_str()).unwrap();
+                String::from_utf8(rtdev).unwrap()
+            }
+            None => "null".to_string(),
+        };
+        format!(
+            "{{\"device\":{},\"mmap\":{},\"opened\":{},\"version\":\"{}\",\"capabilities\":{},\"\
+             options\":{{\"check_ags\":{},\"strict\":{},\"skip_bad_entries\":{},\"inodes_dir\":{},\
+             \"norestrict\":{},\"hot_inodes\":{},\"mem_limit\":{},\"auditlog\":{},\"rw\":{},\"\
+             rtdev\":{},\"threads\":{}}}}}",
+            String::from_utf8(device).unwrap(),
+            self.device.is_mapped(),
+            unix_secs(self.opened),
+            env!("CARGO_PKG_VERSION"),
+            self.capabilities,
+            opts.check_ags,
+            opts.strict,
+            opts.skip_bad_entries,
+            opts.inodes_dir,
+            opts.norestrict,
+            opts.hot_inodes.unwrap_or(DEFAULT_HOT_INODES),
+            opts.mem_limit
+                .map(|limit| limit.to_string())
+                .unwrap_or_else(|| "null".to_string()),
+            opts.audit_log.is_some(),
+            opts.rw,
+            rtdev,
+            opts.threads
+        )
+    }
+
+    /// Generate the current contents of a [`MetaFile`].
+    fn meta_contents(&self, file: MetaFile) -> Vec<u8> {
+        let json = match file {
+            MetaFile::Superblock => self.sb.to_json(),
+            MetaFile::Stats => self.stats_json(),
+            MetaFile::Mount => self.mount_json(),
+        };
+        format!("{}\n", json).into_bytes()
+    }
+
+    /// How long the kernel may cache an inode's attributes.  A [`MetaFile`]'s size may change, but
+    /// nothing else ever does.
+    fn ttl(&self, ino: u64) -> Duration {
+        if self.meta_file(ino).is_some() {
+            Duration::ZERO
+        } else {
+            Self::TTL
+        }
+    }
+
+    /// Adjust an inode's attributes, as reported to the kernel, according to the mount options.
+    fn present(&self, mut attr: FileAttr) -> FileAttr {
+        if self.opts.norestrict {
+            attr.perm = unrestrict(attr.kind, attr.perm);
+        }
+        attr
+    }
+
+    /// Charge the cache budget for whatever the last operation cached, and evict entries if that
+    /// exceeds the memory limit.  Every operation that uses an inode does this before replying.
+    pub(crate) fn settle_caches(&mut self) {
+        // What was read ahead is the cheapest to give up, since it's read again only if needed.
+        while self.cache_budget.exceeded() && self.evict_readahead() {}
+        self.open_files.settle();
+    }
+
+    /// The memory used by every cache, and their limit
+    #[cfg(test)]
+    pub(crate) fn cache_budget(&self) -> &CacheBudget {
+        &self.cache_budget
+    }
+
+    /// Drop the caches, largest first, timing each stage.  Dropping them all at once when the
+    /// Volume is dropped would work too, but would be slow for a big tree and impossible to
+    /// diagnose.
+    fn teardown(&mut self) {
+        let start = Instant::now();
+        let dirs = self.open_files.count_hot(Dinode::drop_dir_cache);
+        debug!(
+            "Teardown: dropped {} directories in {:?}",
+            dirs,
+            start.elapsed()
+        );
+
+        let start = Instant::now();
+        let attrs = self.open_files.count_hot(Dinode::drop_attr_cache);
+        debug!(
+            "Teardown: dropped {} attribute forks in {:?}",
+            attrs,
+            start.elapsed()
+        );
+
+        let start = Instant::now();
+        let inodes = self.open_files.len();
+        self.open_files.clear();
+        self.inodes = None;
+        self.meta_handles.clear();
+        self.forget_readahead(|_| false);
+        debug!(
+            "Teardown: dropped {} open files in {:?}",
+            inodes,
+            start.elapsed()
+        );
+    }
+
+    pub(crate) fn do_lookup(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+    ) -> Result<(FileAttr, u64), c_int> {
+        // No entry may have an empty name, and readdir skips any corrupt ones that do, so don't
+        // let a lookup find one either.
+        check_name(name)?;
+        if self.opts.inodes_dir && parent == ROOT_INO && name.as_bytes() == INODES_DIR {
+            return Ok((self.inodes_dir_attr()?, 0));
+        }
+        if self.opts.metadir && parent == ROOT_INO && name == self.metadir_name {
+            return Ok((self.synthetic_attr(METADIR_INO, FileType::Directory, 0)?, 0));
+        }
+        if self.is_inodes_dir(parent) {
+            return self.do_lookup_inodes_dir(name);
+        }
+        if self.is_metadir(parent) {
+            return self.do_lookup_metadir(name);
+        }
+        if name.as_bytes() == b"." {
+            // With FUSE_EXPORT_SUPPORT, the kernel decodes an NFS file handle by looking up "."
+            // in the inode that it names.  That may be any type of file, and the kernel may have
+            // forgotten it long ago.  The kernel checks the generation itself.
+            if !self.open_files.contains(parent) {
+                self.check_handle(parent)?;
+            }
+            return self.lookup_ino(parent);
+        }
+        let parent_oi = self
+            .open_files
+            .get(parent, |ino| load_dinode(&mut self.device, &self.sb, ino))?;
+        let dirsize = self.sb.sb_blocksize << self.sb.sb_dirblklog;
+        self.device.set_bufsize(dirsize as usize);
+        let dir = parent_oi.dinode.get_dir(self.device.by_ref(), &self.sb)?;
+        let (ino, ftype) = dir.lookup_entry(self.device.by_ref(), &self.sb, name)?;
+        if let Err(e) = self.sb.validate_ino(ino) {
+            error!(
+                "Directory {} has an invalid entry {:?}: {}",
+                parent, name, ino
+            );
+            return Err(e);
+        }
+        // The root's entry may be looked up as "..", from the root itself or its children.
+        let (attr, gen) = self.entry_attr(fuse_ino(&self.sb, ino))?;
+        self.check_ftype(parent, name, ftype, attr.kind)?;
+        self.open_files.entry(attr.ino);
+        Ok((attr, gen))
+    }
+
+    /// Check a directory entry's file type, if it has one, against its inode's.  Damaged
+    /// directories sometimes disagree with their inodes, and the inode is more trustworthy, so
+    /// that's the type that we report.  But in strict mode, a disagreement is EIO.
+    fn check_ftype(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        ftype: Option<u8>,
+        kind: FileType,
+    ) -> Result<(), c_int> {
+        let Some(ftype) = ftype else {
+            return Ok(());
+        };
+        if get_file_type(FileKind::Type(ftype)) == Ok(kind) {
+            return Ok(());
+        }
+        self.ftype_mismatches += 1;
+        validate::failure(format_args!(
+            "Directory {} entry {:?} has file type {}, but its inode is a {:?}",
+            parent, name, ftype, kind
+        ))
+    }
+
+    /// Look up an entry of [`INODES_DIR`], whose names are the decimal numbers of allocated
+    /// inodes.
+    fn do_lookup_inodes_dir(&mut self, name: &OsStr) -> Result<(FileAttr, u64), c_int> {
+        match name.as_bytes() {
+            b"." => return Ok((self.inodes_dir_attr()?, 0)),
+            b".." => return self.lookup_ino(ROOT_INO),
+            _ => (),
+        }
+        let ino = name
+            .to_str()
+            .and_then(|s| s.parse::<XfsIno>().ok())
+            // Only accept the canonical spelling, the one that readdir returns
+            .filter(|ino| ino.to_string().as_bytes() == name.as_bytes())
+            .ok_or(libc::ENOENT)?;
+        if self.allocated_inodes()?.binary_search(&ino).is_err() {
+            return Err(libc::ENOENT);
+        }
+        self.lookup_ino(fuse_ino(&self.sb, ino))
+    }
+
+    /// Look up an entry of [`METADIR`].
+    fn do_lookup_metadir(&mut self, name: &OsStr) -> Result<(FileAttr, u64), c_int> {
+        match name.as_bytes() {
+            b"." => Ok((self.synthetic_attr(METADIR_INO, FileType::Directory, 0)?, 0)),
+            b".." => self.lookup_ino(ROOT_INO),
+            _ => {
+                let file = MetaFile::ALL
+                    .into_iter()
+                    .find(|file| name == file.name())
+                    .ok_or(libc::ENOENT)?;
+                Ok((self.meta_attr(file)?, 0))
+            }
+        }
+    }
+
+    /// Take a lookup reference on an inode, and return its attributes and generation.
+    /// Check that an inode that the kernel isn't referencing, named by a file handle, is still
+    /// allocated.  A number that doesn't address an inode at all is just as stale as a freed one.
+    fn check_handle(&mut self, ino: u64) -> Result<(), c_int> {
+        let xino = xfs_ino(&self.sb, ino);
+        let offset = self.sb.ino_to_offset(xino).map_err(|_| libc::ESTALE)?;
+        self.device.set_bufsize(self.sb.inode_size());
+        self.device
+            .seek(SeekFrom::Start(offset))
+            .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))?;
+        match decode_from::<DinodeCore, _>(self.device.by_ref()) {
+            Ok(core) if core.di_mode != 0 => Ok(()),
+            _ => Err(libc::ESTALE),
+        }
+    }
+
+    fn lookup_ino(&mut self, ino: u64) -> Result<(FileAttr, u64), c_int> {
+        let r = self.entry_attr(ino)?;
+        self.open_files.entry(ino);
+        Ok(r)
+    }
+
+    /// Load an inode, without taking a lookup reference, and return the attributes and
+    /// generation to reply with.  The caller takes the reference once the reply is certain.
+    fn entry_attr(&mut self, ino: u64) -> Result<(FileAttr, u64), c_int> {
+        let export_support = self.export_support();
+        let oi = self
+            .open_files
+            .load(ino, |ino| load_dinode(&mut self.device, &self.sb, ino))?;
+        let attr = oi.dinode.stat(ino, &self.sb)?;
+        // The generation only distinguishes file handles, so don't bother the kernel with it
+        // unless it uses them.
+        let gen = if export_support {
+            oi.dinode.di_core.di_gen.into()
+        } else {
+            0
+        };
+        Ok((attr, gen))
+    }
+
+    fn do_forget(&mut self, ino: u64, nlookup: u64) {
+        if ino == ROOT_INO {
+            // Special case: since fusefs never does a lookup for the root inode, its FORGETs may
+            // be "unmatched".  It must never be dropped, since it was loaded at mount time and
+            // nothing would reload it.  Lookups of ".." that find the root count toward it too,
+            // but they're harmless to leave unbalanced.
+            return;
+        }
+        if self.is_synthetic(ino) {
+            // Synthetic inodes aren't in open_files, so there's nothing to forget
+            return;
+        }
+        match self.open_files.forget(ino, nlookup) {
+            Some(Forgotten::Released) => self.drop_readahead(ino),
+            // AFAICT the kernel will never send a partial forget.  Alert the admin if it ever
+            // happens.
+            Some(Forgotten::Remaining(_)) => warn!("Partial forget for ino {}", ino),
+            Some(Forgotten::Underflow(n)) => {
+                warn!("Forget of {} more lookups than inode {} had", n, ino)
+            }
+            None => warn!("Forget without lookup for inode {}", ino),
+        }
+    }
+
+    fn do_getattr(&mut self, ino: u64) -> Result<FileAttr, c_int> {
+        if self.is_inodes_dir(ino) {
+            return self.inodes_dir_attr();
+        }
+        if self.is_metadir(ino) {
+            return self.synthetic_attr(METADIR_INO, FileType::Directory, 0);
+        }
+        if let Some(file) = self.meta_file(ino) {
+            return self.meta_attr(file);
+        }
+        self.open_files
+            .get(ino, |ino| load_dinode(&mut self.device, &self.sb, ino))
+            .and_then(|oi| oi.dinode.stat(ino, &self.sb))
+    }
+
+    fn do_readlink(&mut self, ino: u64) -> Result<CString, c_int> {
+        let oi = self
+            .open_files
+            .get(ino, |ino| load_dinode(&mut self.device, &self.sb, ino))?;
+        self.device.set_bufsize(self.sb.sb_blocksize as usize);
+        oi.dinode.get_link_data(self.device.by_ref(), &self.sb)
+    }
+
+    /// Read up to `size` bytes.  Return a buffer, and the number of bytes at its start to skip.
+    pub(crate) fn do_read(
+        &mut self,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+    ) -> Result<(Vec<u8>, usize), c_int> {
+        if size == 0 {
+            // Some tools probe with zero-length reads.  There's no need to build the File or map
+            // any blocks, once we know that the inode exists.
+            if self.meta_file(ino).is_none() {
+                self.open_files
+                    .get(ino, |ino| load_dinode(&mut self.device, &self.sb, ino))?;
+            }
+            return Ok((Vec::new(), 0));
+        }
+        if let Some(file) = self.meta_file(ino) {
+            // Without an open, as when the kernel skips them, there's no snapshot to read from.
+            let mut data = match self.meta_handles.get(&fh) {
+                Some(data) => data.clone(),
+                None => self.meta_contents(file),
+            };
+            let start = usize::try_from(offset)
+                .map_err(|_| libc::EINVAL)?
+                .min(data.len());
+            data.truncate(start.saturating_add(size as usize));
+            return Ok((data, start));
+        }
+        if self.opts.readahead <= size {
+            return self.read_file(ino, offset, size);
+        }
+
+        let key = (ino, fh);
+        let sequential = match self.readahead.get_mut(&key) {
+            Some(ra) => match ra.take(offset, size) {
+                Some(data) => return Ok((data, 0)),
+                None => ra.next == offset,
+            },
+            None => offset == 0,
+        };
+        if !sequential {
+            // Remember where this read ends, but don't hold on to what was read ahead
+            let r = self.read_file(ino, offset, size);
+            let next =
+                offset.saturating_add(r.as_ref().map_or(0, |(v, skip)| v.len() - skip) as i64);
+            self.keep_readahead(
+                key,
+                Readahead {
+                    next,
+                    ..Default::default()
+                },
+            );
+            return r;
+        }
+        let (mut data, skip) = self.read_file(ino, offset, self.opts.readahead)?;
+        data.drain(..skip);
+        let mut ra = Readahead {
+            next: offset,
+            start: offset,
+            eof: data.len() < self.opts.readahead as usize,
+            data,
+        };
+        let r = ra.take(offset, size).unwrap();
+        self.keep_readahead(key, ra);
+        Ok((r, 0))
+    }
+
+    /// Store a handle's [`Readahead`], making room for it if there are too many.
+    fn keep_readahead(&mut self, key: (u64, u64), ra: Readahead) {
+        self.cache_budget
+            .charge(CacheKind::Readahead, ra.data.capacity() as u64);
+        if let Some(old) = self.readahead.insert(key, ra) {
+            self.cache_budget
+                .release(CacheKind::Readahead, old.data.capacity() as u64);
+        }
+        if self.readahead.len() > READAHEAD_HANDLES {
+            self.evict_readahead();
+        }
+    }
+
+    /// Drop the least recently used handle's [`Readahead`].  Return false if there are none.
+    fn evict_readahead(&mut self) -> bool {
+        let Some(ra) = self.readahead.pop_lru() else {
+            return false;
+        };
+        self.cache_budget
+            .release(CacheKind::Readahead, ra.data.capacity() as u64);
+        true
+    }
+
+    /// Drop the [`Readahead`] of every handle that doesn't satisfy `keep`.
+    fn forget_readahead<F: Fn(&(u64, u64)) -> bool>(&mut self, keep: F) {
+        let budget = &self.cache_budget;
+        self.readahead.retain(|key, ra| {
+            keep(key) || {
+                budget.release(CacheKind::Readahead, ra.data.capacity() as u64);
+                false
+            }
+        });
+    }
+
+    /// Read up to `size` bytes of a regular file, as [`do_read`](Self::do_read) does.
+    fn read_file(&mut self, ino: u64, offset: i64, size: u32) -> Result<(Vec<u8>, usize), c_int> {
+        let plan = self.plan_read(ino, offset, size)?;
+        let rtdev = self.rtdev.as_mut().map(|rtdev| {
+            rtdev.set_bufsize(self.sb.sb_blocksize as usize);
+            rtdev as &mut dyn RtDevice
+        });
+        plan.execute(&mut self.device, rtdev)
+    }
+
+    /// Forget what was read ahead of any of an inode's handles, since its data has changed.
+    fn drop_readahead(&mut self, ino: u64) {
+        self.forget_readahead(|(i, _)| *i != ino);
+    }
+
+    /// Map the blocks that a read of a regular file needs, without reading them
+    fn plan_read(&mut self, ino: u64, offset: i64, size: u32) -> Result<ReadPlan, c_int> {
+        let oi = self
+            .open_files
+            .get(ino, |ino| load_dinode(&mut self.device, &self.sb, ino))?;
+        self.device.set_bufsize(self.sb.sb_blocksize as usize);
+        let file = oi.dinode.get_file(self.device.by_ref())?;
+        file.plan_read(self.device.by_ref(), &self.sb, offset, size)
+    }
+
+    /// Like [`do_read`](Self::do_read), but pass the result to `done`.  With a [`ReadPool`], only
+    /// map the blocks here, and leave reading them and calling `done` to one of its threads.  Those
+    /// reads aren't read ahead, since their data never comes back here to be kept.
+    pub(crate) fn do_read_pooled<F>(&mut self, ino: u64, fh: u64, offset: i64, size: u32, done: F)
+    where
+        F: FnOnce(Result<(Vec<u8>, usize), c_int>) + Send + 'static,
+    {
+        if self.read_pool.is_none() || size == 0 || self.meta_file(ino).is_some() {
+            return done(self.do_read(ino, fh, offset, size));
+        }
+        match self.plan_read(ino, offset, size) {
+            Ok(plan) => self
+                .read_pool
+                .as_ref()
+                .unwrap()
+                .submit(move |device, rtdev| {
+                    done(plan.execute(device, rtdev.map(|rtdev| rtdev as &mut dyn RtDevice)))
+                }),
+            Err(e) => done(Err(e)),
+        }
+    }
+
+    fn do_lseek(&mut self, ino: u64, offset: i64, whence: i32) -> Result<i64, c_int> {
+        let uoffset = u64::try_from(offset).map_err(|_| libc::EINVAL)?;
+
+        if let Some(file) = self.meta_file(ino) {
+            // A MetaFile has no holes
+            let len = self.meta_contents(file).len() as i64;
+            return match whence {
+                _ if offset >= len => Err(libc::ENXIO),
+                libc::SEEK_DATA => Ok(offset),
+                libc::SEEK_HOLE => Ok(len),
+                _ => Err(libc::EINVAL),
+            };
+        }
+
+        let oi = self
+            .open_files
+            .get(ino, |ino| load_dinode(&mut self.device, &self.sb, ino))?;
+        let file = oi.dinode.get_file(self.device.by_ref())?;
+        let ofs = file.lseek(self.device.by_ref(), &self.sb, uoffset, whence)?;
+        Ok(i64::try_from(ofs).unwrap())
+    }
+
+    /// Map block `idx` of a regular file, in units of `blocksize` bytes, to the device block in
+    /// the same units that holds it, as FIBMAP does.  Holes and unwritten extents are 0, as they
+    /// are for lseek's SEEK_DATA.  So is all of a realtime file, whose blocks aren't on this
+    /// device.
+    pub(crate) fn do_bmap(&mut self, ino: u64, blocksize: u32, idx: u64) -> Result<u64, c_int> {
+        if !blocksize.is_power_of_two() {
+            return Err(libc::EINVAL);
+        }
+        if self.is_synthetic(ino) {
+            return Err(libc::EINVAL);
+        }
+        let oi = self
+            .open_files
+            .get(ino, |ino| load_dinode(&mut self.device, &self.sb, ino))?;
+        if oi.dinode.di_core.stat(ino)?.kind != FileType::RegularFile {
+            return Err(libc::EINVAL);
+        }
+        if oi.dinode.di_core.realtime() {
+            return Ok(0);
+        }
+        let offset = idx.checked_mul(u64::from(blocksize)).ok_or(libc::EINVAL)?;
+        self.device.set_bufsize(self.sb.sb_blocksize as usize);
+        let file = oi.dinode.get_file(self.device.by_ref())?;
+        let block = offset >> self.sb.sb_blocklog;
+        match file.get_extent(self.device.by_ref(), &self.sb, block) {
+            (Mapping::Data(fsbno), len) if len > 0 => {
+                let within = offset & (u64::from(self.sb.sb_blocksize) - 1);
+                Ok((self.sb.fsb_to_offset(fsbno) + within) / u64::from(blocksize))
+            }
+            _ => Ok(0),
+        }
+    }
+
+    /// Find where on the device each part of `len` bytes at `offset` in a regular file lies, as
+    /// (offset within the range, device offset, length).  Anything that would need blocks
+    /// allocated, or shared blocks copied, fails with ENOSPC, except that holes and unwritten
+    /// extents are simply omitted if `skip_holes`.
+    fn map_for_write(
+        &mut self,
+        ino: u64,
+        offset: u64,
+        len: u64,
+        skip_holes: bool,
+    ) -> Result<Vec<(u64, u64, u64)>, c_int> {
+        let oi = self
+            .open_files
+            .get(ino, |ino| load_dinode(&mut self.device, &self.sb, ino))?;
+        if oi.dinode.di_core.stat(ino)?.kind != FileType::RegularFile {
+            return Err(libc::EINVAL);
+        }
+        if oi.dinode.di_core.realtime() {
+            // The realtime device is only ever opened for reading
+            error!("ino {}: cannot write to a realtime file", ino);
+            return Err(libc::EROFS);
+        }
+        let reflink = oi.dinode.di_core.reflink();
+        let bs = u64::from(self.sb.sb_blocksize);
+        self.device.set_bufsize(bs as usize);
+        let file = oi.dinode.get_file(self.device.by_ref())?;
+        let end = offset.checked_add(len).ok_or(libc::EFBIG)?;
+        let mut parts = Vec::new();
+        let mut pos = offset;
+        while pos < end {
+            let block = pos / bs;
+            let (mapping, blocks) = file.get_extent(self.device.by_ref(), &self.sb, block);
+            if blocks == 0 {
+                // Beyond the last extent
+                if skip_holes {
+                    break;
+                }
+                return Err(libc::ENOSPC);
+            }
+            let run_end = (block + blocks).saturating_mul(bs).min(end);
+            match mapping {
+                Mapping::Data(fsbno) => {
+                    let nblocks = (run_end.div_ceil(bs) - block).try_into().unwrap();
+                    let shared = reflink
+                        && !refcount::shared(&mut self.device, &self.sb, fsbno, nblocks)
+                            .map_err(|e| {
+                                error!("ino {}: cannot search the refcount btree: {}", ino, e);
+                                libc::EIO
+                            })?
+                            .is_empty();
+                    if shared {
+                        // Overwriting it would need copy-on-write
+                        return Err(libc::ENOSPC);
+                    }
+                    parts.push((
+                        pos - offset,
+                        self.sb.fsb_to_offset(fsbno) + pos % bs,
+                        run_end - pos,
+                    ));
+                }
+                _ if skip_holes => (),
+                _ => return Err(libc::ENOSPC),
+            }
+            pos = run_end;
+        }
+        Ok(parts)
+    }
+
+    /// Store an inode's size and timestamps, as changed in memory, back on disk
+    fn write_inode_core(&mut self, ino: u64) -> Result<(), c_int> {
+        let xino = xfs_ino(&self.sb, ino);
+        let ofs = self.sb.ino_to_offset(xino)?;
+        let mut raw = vec![0u8; self.sb.inode_size()];
+        self.device.set_bufsize(self.sb.inode_size());
+        self.device
+            .seek(SeekFrom::Start(ofs))
+            .and_then(|_| self.device.read_exact(&mut raw))
+            .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))?;
+        let oi = self
+            .open_files
+            .get(ino, |ino| load_dinode(&mut self.device, &self.sb, ino))?;
+        oi.dinode.di_core.write_back(&mut raw);
+        self.device.write_at(&raw, ofs).map_err(|e| {
+            error!("Cannot write inode {}: {}", xino, e);
+            e.raw_os_error().unwrap_or(libc::EIO)
+        })
+    }
+
+    /// Overwrite part of a regular file.  Only blocks that the file already has, and doesn't
+    /// share, can be written.  The size may grow, but only into blocks allocated beyond EOF.
+    fn do_write(&mut self, ino: u64, offset: i64, data: &[u8]) -> Result<u32, c_int> {
+        if !self.opts.rw || self.is_synthetic(ino) {
+            return Err(libc::EROFS);
+        }
+        let offset = u64::try_from(offset).map_err(|_| libc::EINVAL)?;
+        // Map everything first, so a write that can't be done doesn't happen in part
+        let parts = self.map_for_write(ino, offset, data.len() as u64, false)?;
+        self.drop_readahead(ino);
+        for (start, dev_ofs, len) in parts {
+            let start = start as usize;
+            self.device
+                .write_at(&data[start..start + len as usize], dev_ofs)
+                .map_err(|e| {
+                    error!("ino {}: cannot write at offset {}: {}", ino, dev_ofs, e);
+                    e.raw_os_error().unwrap_or(libc::EIO)
+                })?;
+        }
+        let oi = self
+            .open_files
+            .get(ino, |ino| load_dinode(&mut self.device, &self.sb, ino))?;
+        let core = &mut oi.dinode.di_core;
+        let end = i64::try_from(offset + data.len() as u64).map_err(|_| libc::EFBIG)?;
+        core.di_size = core.di_size.max(end);
+        core.di_mtime = core.encode_timestamp(SystemTime::now());
+        core.di_ctime = core.di_mtime;
+        self.write_inode_core(ino)?;
+        Ok(data.len() as u32)
+    }
+
+    /// Change a regular file's size or timestamps.  Growing a file zeroes whatever blocks it
+    /// already has between the old and new sizes, and leaves holes as they are.  Nothing else
+    /// can be changed.  `now` is the time of the change.
+    fn do_setattr(
+        &mut self,
+        ino: u64,
+        size: Option<u64>,
+        atime: Option<SystemTime>,
+        mtime: Option<SystemTime>,
+        now: SystemTime,
+    ) -> Result<FileAttr, c_int> {
+        if !self.opts.rw || self.is_synthetic(ino) {
+            return Err(libc::EROFS);
+        }
+        if let Some(size) = size {
+            let new_size = i64::try_from(size).map_err(|_| libc::EFBIG)?;
+            let oi = self
+                .open_files
+                .get(ino, |ino| load_dinode(&mut self.device, &self.sb, ino))?;
+            if oi.dinode.di_core.stat(ino)?.kind != FileType::RegularFile {
+                return Err(libc::EINVAL);
+            }
+            let old_size = oi.dinode.di_core.di_size as u64;
+            if size > old_size {
+                // Bytes beyond EOF may be stale, and must read as zeros once they're within it
+                let parts = self.map_for_write(ino, old_size, size - old_size, true)?;
+                for (_, dev_ofs, len) in parts {
+                    let zeros = vec![0u8; len as usize];
+                    self.device.write_at(&zeros, dev_ofs).map_err(|e| {
+                        error!("ino {}: cannot write at offset {}: {}", ino, dev_ofs, e);
+                        e.raw_os_error().unwrap_or(libc::EIO)
+                    })?;
+                }
+            }
+            let oi = self
+                .open_files
+                .get(ino, |ino| load_dinode(&mut self.device, &self.sb, ino))?;
+            let core = &mut oi.dinode.di_core;
+            core.di_size = new_size;
+            core.di_mtime = core.encode_timestamp(now);
+            self.drop_readahead(ino);
+        }
+        let oi = self
+            .open_files
+            .get(ino, |ino| load_dinode(&mut self.device, &self.sb, ino))?;
+        let core = &mut oi.dinode.di_core;
+        if let Some(atime) = atime {
+            core.di_atime = core.encode_timestamp(atime);
+        }
+        if let Some(mtime) = mtime {
+            core.di_mtime = core.encode_timestamp(mtime);
+        }
+        core.di_ctime = core.encode_timestamp(now);
+        self.write_inode_core(ino)?;
+        self.do_getattr(ino)
+    }
+
+    /// Pass as many entries as will fit to `add`, which returns true once the reply is full, and
+    /// return how many were added.
+    pub(crate) fn do_readdir<F>(
+        &mut self,
+        ino: u64,
+        offset: i64,
+        mut add: F,
+    ) -> Result<usize, c_int>
+    where
+        F: FnMut(u64, i64, FileType, &OsStr) -> bool,
+    {
+        if self.is_inodes_dir(ino) {
+            return self.do_readdir_inodes_dir(offset, add);
+        }
+        if self.is_metadir(ino) {
+            return self.do_readdir_metadir(offset, add);
+        }
+        // The root's synthetic entries are listed after all of its real ones
+        let synthetic = if ino == ROOT_INO {
+            self.synthetic_root_entries()
+        } else {
+            Vec::new()
+        };
+        if !synthetic.is_empty() && offset >= METADIR_OFFSET {
+            return Ok(add_synthetic(&synthetic, offset, &mut add));
+        }
+        let dir_ino = ino;
+        let oi = self
+            .open_files
+            .get(ino, |ino| load_dinode(&mut self.device, &self.sb, ino))?;
+        let dirsize = self.sb.sb_blocksize << self.sb.sb_dirblklog;
+        self.device.set_bufsize(dirsize as usize);
+
+        let dir = oi.dinode.get_dir(self.device.by_ref(), &self.sb)?;
+
+        let mut off = offset;
+        let mut count = 0;
+        // Inodes read to learn their entries' types
+        let mut loaded = Vec::new();
+        let r = loop {
+            let res = dir.next(self.device.by_ref(), &self.sb, off);
+            match res {
+                Ok((ino, offset, kind, name)) => {
+                    if let Err(e) = self.sb.validate_ino(ino) {
+                        let r = validate::failure(format_args!(
+                            "Directory {} has an invalid entry {:?}: {}",
+                            dir_ino, name, ino
+                        ));
+                        if r.is_ok() && self.opts.skip_bad_entries {
+                            off = offset;
+                            continue;
+                        }
+                        break Err(e);
+                    }
+                    let ino = fuse_ino(&self.sb, ino);
+                    let kind = match kind {
+                        Some(kind) => kind,
+                        // This is very inefficient, since it reads every entry's inode.  At least
+                        // they're kept afterwards, since getattr is frequently called for every
+                        // entry returned by readdir.  The best solution is for everybody to use
+                        // the ftype option in their XFS format.
+                        None => match load_dinode(&mut self.device, &self.sb, ino)
+                            .and_then(|dinode| Ok((dinode.di_core.stat(ino)?.kind, dinode)))
+                        {
+                            Ok((kind, dinode)) => {
+                                loaded.push((ino, dinode));
+                                kind
+                            }
+                            Err(e) => {
+                                let r = validate::failure(format_args!(
+                                    "Directory {} has an unreadable entry {:?}: {}",
+                                    dir_ino, name, ino
+                                ));
+                                if r.is_ok() && self.opts.skip_bad_entries {
+                                    off = offset;
+                                    continue;
+                                }
+                                break Err(e);
+                            }
+                        },
+                    };
+                    if add(ino, offset, kind, &name) {
+                        break Ok(count);
+                    }
+                    count += 1;
+                    off = offset;
+                }
+                Err(libc::ENOENT) => {
+                    break Ok(count + add_synthetic(&synthetic, off, &mut add));
+                }
+                Err(e) => {
+                    error!(
+                        "Reading directory {} at offset {} failed: {}",
+                        dir_ino,
+                        off,
+                        io::Error::from_raw_os_error(e)
+                    );
+                    // Deliver the entries that we already have, unless strict.  The error will
+                    // recur on the next call, which resumes from the same offset.
+                    break if count > 0 && !self.validator.strict() {
+                        Ok(count)
+                    } else {
+                        Err(e)
+                    };
+                }
+            }
+        };
+        // Keep those inodes, so the getattr or lookup that often follows each entry needn't read
+        // them again.  The kernel hasn't looked them up, so they take no reference.
+        for (ino, dinode) in loaded {
+            let _ = self.open_files.load(ino, |_| Ok(dinode));
+        }
+        r
+    }
+
+    /// The synthetic entries that the mount options add to the root directory: their readdir
+    /// offsets, inode numbers, and names
+    fn synthetic_root_entries(&self) -> Vec<(i64, u64, OsString)> {
+        let mut entries = Vec::new();
+        if self.opts.metadir {
+            entries.push((METADIR_OFFSET, METADIR_INO, self.metadir_name.clone()));
+        }
+        if self.opts.inodes_dir {
+            let name = OsStr::from_bytes(INODES_DIR).to_owned();
+            entries.push((INODES_DIR_OFFSET, INODES_DIR_INO, name));
+        }
+        entries
+    }
+
+    /// Like [`Self::do_readdir`], but for [`METADIR`].  "." and ".." have offsets 1 and 2, and
+    /// the files follow.
+    fn do_readdir_metadir<F>(&mut self, offset: i64, mut add: F) -> Result<usize, c_int>
+    where
+        F: FnMut(u64, i64, FileType, &OsStr) -> bool,
+    {
+        let dirs = [(METADIR_INO, "."), (ROOT_INO, "..")]
+            .into_iter()
+            .map(|(ino, name)| (ino, FileType::Directory, name));
+        let files = MetaFile::ALL
+            .into_iter()
+            .map(|file| (file.ino(), FileType::RegularFile, file.name()));
+        let mut count = 0;
+        for (off, (ino, kind, name)) in (1..).zip(dirs.chain(files)) {
+            if offset < off {
+                if add(ino, off, kind, OsStr::new(name)) {
+                    break;
+                }
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Like [`Self::do_readdir`], but for [`INODES_DIR`].  "." and ".." have offsets 1 and 2, and
+    /// the i'th allocated inode has offset i + 3.
+    fn do_readdir_inodes_dir<F>(&mut self, offset: i64, mut add: F) -> Result<usize, c_int>
+    where
+        F: FnMut(u64, i64, FileType, &OsStr) -> bool,
+    {
+        let mut count = 0;
+        for (off, ino, name) in [(1, INODES_DIR_INO, "."), (2, ROOT_INO, "..")] {
+            if offset < off {
+                if add(ino, off, FileType::Directory, OsStr::new(name)) {
+                    return Ok(count);
+                }
+                count += 1;
+            }
+        }
+        let start = usize::try_from(offset.max(2) - 2).map_err(|_| libc::EINVAL)?;
+        let nino = self.allocated_inodes()?.len();
+        for i in start..nino {
+            let xfs_ino = self.inodes.as_ref().unwrap()[i];
+            let ino = fuse_ino(&self.sb, xfs_ino);
+            let kind = self
+                .open_files
+                .load(ino, |ino| load_dinode(&mut self.device, &self.sb, ino))?
+                .dinode
+                .di_core
+                .stat(ino)?
+                .kind;
+            if add(ino, i as i64 + 3, kind, OsStr::new(&xfs_ino.to_string())) {
+                return Ok(count);
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Like [`Self::do_readdir`], but also pass each entry's attributes, along with their TTL and
+    /// generation, to `add`.  The kernel counts every entry that it accepts, except "." and "..",
+    /// as a lookup, so each of those takes a reference.
+    pub(crate) fn do_readdirplus<F>(
+        &mut self,
+        ino: u64,
+        offset: i64,
+        mut add: F,
+    ) -> Result<usize, c_int>
+    where
+        F: FnMut(u64, i64, &OsStr, &Duration, &FileAttr, u64) -> bool,
+    {
+        /// How many entries to list at a time, before loading their inodes
+        const BATCH: usize = 64;
+
+        let dir_ino = ino;
+        let mut off = offset;
+        let mut count = 0;
+        loop {
+            let mut entries = Vec::with_capacity(BATCH);
+            let r = self.do_readdir(dir_ino, off, |ino, offset, _, name| {
+                entries.push((ino, offset, name.to_owned()));
+                entries.len() == BATCH
+            });
+            if let Err(e) = r {
+                // The entries already added hold references, so return them, and let the next call
+                // report the error.
+                return if count > 0 { Ok(count) } else { Err(e) };
+            }
+            let last = entries.len() < BATCH;
+            for (ino, offset, name) in entries {
+                let synthetic = self.is_synthetic(ino);
+                let r = if synthetic {
+                    self.do_getattr(ino).map(|attr| (attr, 0))
+                } else {
+                    self.entry_attr(ino)
+                };
+                let (attr, gen) = match r {
+                    Ok(r) => r,
+                    Err(e) => {
+                        let r = validate::failure(format_args!(
+                            "Directory {} has an unreadable entry {:?}: {}",
+                            dir_ino, name, ino
+                        ));
+                        if r.is_ok() && self.opts.skip_bad_entries {
+                            off = offset;
+                            continue;
+                        }
+                        return if count > 0 { Ok(count) } else { Err(e) };
+                    }
+                };
+                if add(ino, offset, &name, &self.ttl(ino), &self.present(attr), gen) {
+                    return Ok(count);
+                }
+                if !synthetic && name != "." && name != ".." {
+                    self.open_files.entry(ino);
+                }
+                count += 1;
+                off = offset;
+            }
+            if last {
+                return Ok(count);
+            }
+        }
+    }
+
+    fn do_getxattr(
+        &mut self,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+    ) -> Result<(u32, Option<Vec<u8>>), c_int> {
+        let virtual_value = if name.as_bytes() == TRACE_XATTR {
+            let traced = if self.is_traced(ino) { b"1" } else { b"0" };
+            Some(traced.to_vec())
+        } else if ino == ROOT_INO && name.as_bytes() == STATS_XATTR {
+            Some(self.stats_json().into_bytes())
+        } else if name.as_bytes() == LAYOUT_XATTR {
+            self.layout_json(ino)?.map(String::into_bytes)
+        } else {
+            None
+        };
+        match virtual_value {
+            // A real attribute on the root takes precedence over a virtual one.  Nobody would
+            // create one by accident, so whoever did must want to see it.
+            Some(value) if ino == ROOT_INO => match self.real_getxattr(ino, name, size) {
+                Err(libc::ENOATTR) => sized_xattr(value, size),
+                r => r,
+            },
+            Some(value) => sized_xattr(value, size),
+            None => self.real_getxattr(ino, name, size),
+        }
+    }
+
+    /// Get an extended attribute from the inode's attribute fork
+    fn real_getxattr(
+        &mut self,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+    ) -> Result<(u32, Option<Vec<u8>>), c_int> {
+        if self.is_synthetic(ino) {
+            return Err(libc::ENOATTR);
+        }
+
+        let oi = self
+            .open_files
+            .get(ino, |ino| load_dinode(&mut self.device, &self.sb, ino))?;
+        if oi.lacks_xattr(name) {
+            return Err(libc::ENOATTR);
+        }
+        self.device.set_bufsize(self.sb.sb_blocksize as usize);
+        let r = oi.getxattr(self.device.by_ref(), &self.sb, name, size);
+        if r == Err(ERANGE) {
+            // fuser can't send the required size along with ERANGE, so at least log it.
+            debug!(
+                "ino {}: extended attribute {:?} needs {} bytes, but the buffer holds only {}",
+                ino,
+                name,
+                oi.xattr_len.as_ref().unwrap().1,
+                size
+            );
+        }
+        r
+    }
+
+    /// The virtual extended attributes that this inode lists, except for any hidden by real ones
+    fn virtual_xattr_names(&mut self, ino: u64) -> Result<Vec<&'static [u8]>, c_int> {
+        if ino != ROOT_INO {
+            return Ok(Vec::new());
+        }
+        let mut names = Vec::new();
+        for name in ROOT_XATTRS {
+            match self.real_getxattr(ino, OsStr::from_bytes(name), 0) {
+                Err(libc::ENOATTR) => names.push(*name),
+                r => {
+                    r?;
+                }
+            }
+        }
+        Ok(names)
+    }
+
+    /// Return the size of the extended attribute list, and the list itself unless the kernel
+    /// only asked for the size.  The root's list includes its virtual attributes, after its real
+    /// ones.
+    pub(crate) fn do_listxattr(
+        &mut self,
+        ino: u64,
+        size: u32,
+    ) -> Result<(u32, Option<Vec<u8>>), c_int> {
+        if self.is_synthetic(ino) {
+            return Ok((0, (size > 0).then(Vec::new)));
+        }
+        let virtual_names = self.virtual_xattr_names(ino)?;
+        let virtual_size = virtual_names
+            .iter()
+            .map(|name| name.len() as u32 + 1)
+            .sum::<u32>();
+        let oi = self
+            .open_files
+            .get(ino, |ino| load_dinode(&mut self.device, &self.sb, ino))?;
+        self.device.set_bufsize(self.sb.sb_blocksize as usize);
+        // A file without any attributes has an empty list.  But we must still reply in the form
+        // that the kernel asked for.
+        let mut attrs = oi.dinode.get_attrs(self.device.by_ref(), &self.sb).as_mut();
+        let real_size = match attrs {
+            Some(ref mut attrs) => attrs.get_total_size(self.device.by_ref(), &self.sb),
+            None => 0,
+        };
+        let attrs_size = real_size + virtual_size;
+
+        if attrs_size > XATTR_LIST_MAX {
+            error!(
+                "ino {}: extended attribute list is {} bytes, more than the maximum of {}",
+                ino, attrs_size, XATTR_LIST_MAX
+            );
+            Err(libc::E2BIG)
+        } else if size == 0 {
+            Ok((attrs_size, None))
+        } else if attrs_size > size {
+            Err(ERANGE)
+        } else {
+            let mut list = match attrs {
+                Some(attrs) => attrs.list(self.device.by_ref(), &self.sb),
+                None => Vec::new(),
+            };
+            // Assert that we calculated the list size correctly.  This assertion is only safe
+            // since we're a read-only file system.
+            assert_eq!(
+                list.len(),
+                real_size as usize,
+                "size calculation was wrong!"
+            );
+            for name in virtual_names {
+                list.extend_from_slice(name);
+                list.push(0);
+            }
+            Ok((attrs_size, Some(list)))
+        }
+    }
+}
+
+/// The library's operations.  Unlike the FUSE ones, they need no lookup before an inode can be
+/// used, and leave no references to forget afterwards.  Every error is an errno.
+impl Volume {
+    /// Take a reference on `ino`, as a lookup would, for the duration of `f`, and then settle the
+    /// caches.
+    fn with_inode<T, F>(&mut self, ino: u64, f: F) -> io::Result<T>
+    where
+        F: FnOnce(&mut Self) -> Result<T, c_int>,
+    {
+        // Another Volume may have served this thread last
+        self.stats.enter();
+        self.validator.enter();
+        let referenced = ino != ROOT_INO && !self.is_synthetic(ino);
+        let r = if referenced {
+            self.sb
+                .validate_ino(xfs_ino(&self.sb, ino))
+                .and_then(|_| self.lookup_ino(ino))
+                .and_then(|_| {
+                    let r = f(self);
+                    self.do_forget(ino, 1);
+                    r
+                })
+        } else {
+            f(self)
+        };
+        self.settle_caches();
+        r.map_err(io::Error::from_raw_os_error)
+    }
+
+    /// Find the inode at `path`, relative to the root directory.  Symlinks are never followed.
+    pub fn resolve<P: AsRef<Path>>(&mut self, path: P) -> io::Result<u64> {
+        let mut ino = ROOT_INO;
+        for component in path.as_ref().components() {
+            let name = match component {
+                Component::Normal(name) => name,
+                Component::ParentDir => OsStr::new(".."),
+                Component::RootDir | Component::CurDir | Component::Prefix(_) => continue,
+            };
+            ino = self.with_inode(ino, |vol| {
+                if vol.do_getattr(ino)?.kind != FileType::Directory {
+                    return Err(libc::ENOTDIR);
+                }
+                let (attr, _) = vol.do_lookup(ino, name)?;
+                vol.do_forget(attr.ino, 1);
+                Ok(attr.ino)
+            })?;
+        }
+        Ok(ino)
+    }
+
+    /// Get an inode's attributes.
+    pub fn getattr(&mut self, ino: u64) -> io::Result<FileAttr> {
+        self.with_inode(ino, |vol| {
+            let attr = vol.do_getattr(ino)?;
+            Ok(vol.present(attr))
+        })
+    }
+
+    /// Read up to `size` bytes of a regular file, from `offset`.  Nothing is returned at or
+    /// beyond EOF.
+    pub fn read(&mut self, ino: u64, offset: u64, size: u32) -> io::Result<Vec<u8>> {
+        let offset =
+            i64::try_from(offset).map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+        self.with_inode(ino, |vol| {
+            match vol.do_getattr(ino)?.kind {
+                FileType::RegularFile => (),
+                FileType::Directory => return Err(libc::EISDIR),
+                _ => return Err(libc::EINVAL),
+            }
+            let (mut data, skip) = vol.do_read(ino, 0, offset, size)?;
+            data.drain(..skip);
+            Ok(data)
+        })
+    }
+
+    /// List a directory's entries, except for "." and "..", in the order that they're stored.
+    pub fn read_dir(&mut self, ino: u64) -> io::Result<Vec<DirEntry>> {
+        self.with_inode(ino, |vol| {
+            if vol.do_getattr(ino)?.kind != FileType::Directory {
+                return Err(libc::ENOTDIR);
+            }
+            let mut entries = Vec::new();
+            vol.do_readdir(ino, 0, |ino, _, kind, name| {
+                if name != "." && name != ".." {
+                    entries.push(DirEntry {
+                        ino,
+                        kind,
+                        name: name.to_owned(),
+                    });
+                }
+                false
+            })?;
+            Ok(entries)
+        })
+    }
+
+    /// Read a symlink's target.
+    pub fn readlink(&mut self, ino: u64) -> io::Result<OsString> {
+        self.with_inode(ino, |vol| {
+            if vol.do_getattr(ino)?.kind != FileType::Symlink {
+                return Err(libc::EINVAL);
+            }
+            let target = vol.do_readlink(ino)?;
+            Ok(OsString::from_vec(target.into_bytes()))
+        })
+    }
+
+    /// List the names of an inode's extended attributes, including their namespaces.  The root's
+    /// include its virtual attributes.
+    pub fn list_xattrs(&mut self, ino: u64) -> io::Result<Vec<OsString>> {
+        self.with_inode(ino, |vol| {
+            let (_, list) = vol.do_listxattr(ino, XATTR_LIST_MAX)?;
+            Ok(list
+                .unwrap_or_default()
+                .split(|c| *c == 0)
+                .filter(|name| !name.is_empty())
+                .map(|name| OsStr::from_bytes(name).to_owned())
+                .collect())
+        })
+    }
+
+    /// Get an extended attribute's value, given its name including its namespace.
+    pub fn get_xattr<N: AsRef<OsStr>>(&mut self, ino: u64, name: N) -> io::Result<Vec<u8>> {
+        self.with_inode(ino, |vol| {
+            let (_, value) = vol.do_getxattr(ino, name.as_ref(), u32::MAX)?;
+            Ok(value.unwrap_or_default())
+        })
+    }
+}
+
+#[cfg(feature = "fuse")]
+impl Filesystem for Volume {
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        self.enter(Op::Lookup);
+        self.trace_begin();
+        let r = self.do_lookup(parent, name);
+        self.settle_caches();
+        let ino = r.as_ref().map(|(attr, _)| attr.ino).unwrap_or(0);
+        self.trace_end(&[parent, ino], || format!("LOOKUP name={:?}", name), &r);
+        if let Some(ref mut audit) = self.audit {
+            audit.lookup(parent, name, r.as_ref().map(|_| ino).map_err(|e| *e));
+        }
+        match r {
+            Ok((attr, gen)) => reply.entry(&self.ttl(attr.ino), &self.present(attr).into(), gen),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn lseek(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        whence: i32,
+        reply: ReplyLseek,
+    ) {
+        self.enter(Op::Lseek);
+        self.trace_begin();
+        let r = self.do_lseek(ino, offset, whence);
+        self.settle_caches();
+        self.trace_end(
+            &[ino],
+            || format!("LSEEK offset={} whence={}", offset, whence),
+            &r,
+        );
+        match r {
+            Ok(ofs) => reply.offset(ofs),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn bmap(&mut self, _req: &Request<'_>, ino: u64, blocksize: u32, idx: u64, reply: ReplyBmap) {
+        self.enter(Op::Bmap);
+        self.trace_begin();
+        let r = self.do_bmap(ino, blocksize, idx);
+        self.settle_caches();
+        self.trace_end(
+            &[ino],
+            || format!("BMAP blocksize={} idx={}", blocksize, idx),
+            &r,
+        );
+        match r {
+            Ok(block) => reply.bmap(block),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn forget(&mut self, _req: &Request, ino: u64, nlookup: u64) {
+        self.enter(Op::Forget);
+        self.trace_begin();
+        self.do_forget(ino, nlookup);
+        self.trace_end(&[ino], || format!("FORGET nlookup={}", nlookup), &());
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        self.enter(Op::Getattr);
+        self.trace_begin();
+        let r = self.do_getattr(ino);
+        self.settle_caches();
+
+        self.trace_end(&[ino], || "GETATTR".to_string(), &r);
+        match r {
+            Ok(attr) => reply.attr(&self.ttl(ino), &self.present(attr).into()),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn destroy(&mut self) {
+        info!("Tearing down");
+        self.teardown();
+        info!("Teardown complete");
+    }
+
+    fn init(&mut self, _req: &Request, config: &mut KernelConfig) -> Result<(), i32> {
+        self.capabilities = negotiate_capabilities(|caps| config.add_capabilities(caps));
+        info!("Negotiated FUSE capabilities {:#x}", self.capabilities);
+        for note in missing_capabilities(self.capabilities) {
+            info!("Kernel offers {}", note);
+        }
+        Ok(())
+    }
+
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: fuser::ReplyData) {
+        self.enter(Op::Readlink);
+        self.trace_begin();
+        let r = self.do_readlink(ino);
+        self.settle_caches();
+        self.trace_end(&[ino], || "READLINK".to_string(), &r);
+        self.audit(
+            "READLINK",
+            ino,
+            Record {
+                len: r.as_ref().ok().map(|data| data.as_bytes().len() as u64),
+                errno: r.as_ref().err().copied().unwrap_or(0),
+                ..Default::default()
+            },
+        );
+        match r {
+            Ok(data) => reply.data(data.as_bytes()),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn open(&mut self, _req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        self.enter(Op::Open);
+        if let Some(file) = self.meta_file(ino) {
+            // Snapshot the contents, so every read of this handle sees the same ones.  Bypass the
+            // page cache, since the size that the kernel last saw may be stale.
+            let fh = self.next_fh;
+            self.next_fh += 1;
+            self.meta_handles.insert(fh, self.meta_contents(file));
+            reply.opened(fh, FOPEN_DIRECT_IO);
+            return;
+        }
+        self.trace_begin();
+        let no_open = self.no_open();
+        self.trace_end(
+            &[ino],
+            || format!("OPEN flags={:#x}", flags),
+            &if no_open { Err(libc::ENOSYS) } else { Ok(()) },
+        );
+        self.audit(
+            "OPEN",
+            ino,
+            Record {
+                errno: if no_open { libc::ENOSYS } else { 0 },
+                ..Default::default()
+            },
+        );
+        if no_open {
+            reply.error(libc::ENOSYS)
+        } else {
+            // Each handle gets its own Readahead
+            let fh = self.next_fh;
+            self.next_fh += 1;
+            reply.opened(fh, FOPEN_KEEP_CACHE)
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: fuser::ReplyData,
+    ) {
+        self.enter(Op::Read);
+        if self.audit.is_none() && !self.is_traced(ino) {
+            // Nothing more needs the result here, so a ReadPool may read it and reply
+            self.do_read_pooled(ino, fh, offset, size, move |r| match r {
+                Ok((v, ignore)) => reply.data(&v[ignore..]),
+                Err(e) => reply.error(e),
+            });
+            self.settle_caches();
+            return;
+        }
+        self.trace_begin();
+        let r = self.do_read(ino, fh, offset, size);
+        self.settle_caches();
+        self.trace_end(
+            &[ino],
+            || format!("READ offset={} size={}", offset, size),
+            &r.as_ref().map(|(v, ignore)| v.len() - ignore),
+        );
+        self.audit(
+            "READ",
+            ino,
+            Record {
+                offset: Some(offset),
+                len: r.as_ref().ok().map(|(v, ignore)| (v.len() - ignore) as u64),
+                errno: r.as_ref().err().copied().unwrap_or(0),
+                ..Default::default()
+            },
+        );
+        match r {
+            Ok((v, ignore)) => reply.data(&v[ignore..]),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        self.enter(Op::Write);
+        self.trace_begin();
+        let r = self.do_write(ino, offset, data);
+        self.settle_caches();
+        self.trace_end(
+            &[ino],
+            || format!("WRITE offset={} size={}", offset, data.len()),
+            &r,
+        );
+        self.audit(
+            "WRITE",
+            ino,
+            Record {
+                offset: Some(offset),
+                len: r.as_ref().ok().map(|len| u64::from(*len)),
+                errno: r.as_ref().err().copied().unwrap_or(0),
+                ..Default::default()
+            },
+        );
+        match r {
+            Ok(len) => reply.written(len),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        self.enter(Op::Setattr);
+        self.trace_begin();
+        let r = if mode.is_some() || uid.is_some() || gid.is_some() || crtime.is_some() {
+            // Only regular files' data may change
+            Err(if self.opts.rw {
+                libc::EPERM
+            } else {
+                libc::EROFS
+            })
+        } else if flags.is_some() {
+            Err(libc::EOPNOTSUPP)
+        } else {
+            let now = SystemTime::now();
+            let resolve = |t| match t {
+                TimeOrNow::SpecificTime(t) => t,
+                TimeOrNow::Now => now,
+            };
+            self.do_setattr(ino, size, atime.map(resolve), mtime.map(resolve), now)
+        };
+        self.settle_caches();
+        self.trace_end(&[ino], || format!("SETATTR size={:?}", size), &r);
+        self.audit(
+            "SETATTR",
+            ino,
+            Record {
+                len: size,
+                errno: r.as_ref().err().copied().unwrap_or(0),
+                ..Default::default()
+            },
+        );
+        match r {
+            Ok(attr) => reply.attr(&self.ttl(ino), &self.present(attr).into()),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn fsync(&mut self, _req: &Request, ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        self.enter(Op::Fsync);
+        self.trace_begin();
+        let r = self.device.sync().map_err(|e| {
+            error!("Cannot sync the device: {}", e);
+            e.raw_os_error().unwrap_or(libc::EIO)
+        });
+        self.trace_end(&[ino], || "FSYNC".to_string(), &r);
+        match r {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.enter(Op::Release);
+        self.meta_handles.remove(&fh);
+        self.forget_readahead(|key| *key != (ino, fh));
+        reply.ok();
+    }
+
+    fn opendir(&mut self, _req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        self.enter(Op::Opendir);
+        self.trace_begin();
+        let no_opendir = self.no_opendir();
+        self.trace_end(
+            &[ino],
+            || format!("OPENDIR flags={:#x}", flags),
+            &if no_opendir {
+                Err(libc::ENOSYS)
+            } else {
+                Ok(())
+            },
+        );
+        if no_opendir {
+            reply.error(libc::ENOSYS)
+        } else {
+            reply.opened(0, FOPEN_CACHE_DIR)
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        self.enter(Op::Readdir);
+        self.trace_begin();
+        let r = self.do_readdir(ino, offset, |ino, offset, kind, name| {
+            reply.add(ino, offset, kind.into(), name)
+        });
+        self.settle_caches();
+        self.trace_end(&[ino], || format!("READDIR offset={}", offset), &r);
+        self.audit(
+            "READDIR",
+            ino,
+            Record {
+                offset: Some(offset),
+                len: r.as_ref().ok().map(|count| *count as u64),
+                errno: r.as_ref().err().copied().unwrap_or(0),
+                ..Default::default()
+            },
+        );
+        match r {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn readdirplus(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectoryPlus,
+    ) {
+        self.enter(Op::Readdirplus);
+        self.trace_begin();
+        let r = self.do_readdirplus(ino, offset, |ino, offset, name, ttl, attr, gen| {
+            reply.add(ino, offset, name, ttl, &(*attr).into(), gen)
+        });
+        self.settle_caches();
+        self.trace_end(&[ino], || format!("READDIRPLUS offset={}", offset), &r);
+        self.audit(
+            "READDIRPLUS",
+            ino,
+            Record {
+                offset: Some(offset),
+                len: r.as_ref().ok().map(|count| *count as u64),
+                errno: r.as_ref().err().copied().unwrap_or(0),
+                ..Default::default()
+            },
+        );
+        match r {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
+        self.enter(Op::Statfs);
+        let st = self.sb.statfs();
+        reply.statfs(
+            st.blocks,
+            st.bfree,
+            st.bavail,
+            st.files,
+            st.ffree,
+            self.sb.iosize(),
+            255,
+            self.sb.sb_blocksize,
+        )
+    }
+
+    fn getxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        self.enter(Op::Getxattr);
+        self.trace_begin();
+        let r = self.do_getxattr(ino, name, size);
+        self.settle_caches();
+        self.trace_end(
+            &[ino],
+            || format!("GETXATTR name={:?} size={}", name, size),
+            &r.as_ref().map(|(len, _)| len),
+        );
+        self.audit(
+            "GETXATTR",
+            ino,
+            Record {
+                attr: Some(name),
+                len: r.as_ref().ok().map(|(len, _)| u64::from(*len)),
+                errno: r.as_ref().err().copied().unwrap_or(0),
+                ..Default::default()
+            },
+        );
+        match r {
+            Ok((_, Some(value))) => reply.data(value.as_slice()),
+            Ok((len, None)) => reply.size(len),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn listxattr(&mut self, _req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        self.enter(Op::Listxattr);
+        self.trace_begin();
+        let r = self.do_listxattr(ino, size);
+        self.settle_caches();
+        self.trace_end(
+            &[ino],
+            || format!("LISTXATTR size={}", size),
+            &r.as_ref().map(|(len, _)| *len),
+        );
+        self.audit(
+            "LISTXATTR",
+            ino,
+            Record {
+                len: r.as_ref().ok().map(|(len, _)| u64::from(*len)),
+                errno: r.as_ref().err().copied().unwrap_or(0),
+                ..Default::default()
+            },
+        );
+        match r {
+            Ok((len, None)) => reply.size(len),
+            Ok((_, Some(list))) => reply.data(list.as_slice()),
+            Err(e) => reply.error(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, io::BufReader, os::unix::fs::FileExt};
+
+    use libc::{S_IFDIR, S_IFLNK, S_IFREG};
+    use rstest::rstest;
+
+    use super::*;
+    use crate::libxfuse::{
+        bmbt_rec::{tests::superblock, BmbtRec},
+        da_btree::hashname,
+        definitions::{XFS_ATTR_LEAF_MAGIC, XFS_DINODE_MAGIC},
+        dir3::XFS_NAME_MAX,
+        test_util::{di_mode, CountingReader},
+    };
+
+    /// Emulate KernelConfig::add_capabilities for a kernel that supports `supported`, and
+    /// return the capabilities that would be sent in the INIT reply.
+    #[cfg(feature = "fuse")]
+    fn negotiate(supported: u32) -> (u32, u32) {
+        let mut requested = 0;
+        let caps = negotiate_capabilities(|caps| {
+            if caps & supported == caps {
+                requested |= caps;
+                Ok(())
+            } else {
+                Err(caps & !supported)
+            }
+        });
+        (caps, requested)
+    }
+
+    #[rstest]
+    #[case::file(None, Ok(()))]
+    #[case::same(Some(512), Ok(()))]
+    #[case::larger_than_sector(Some(4096), Ok(()))]
+    #[case::larger_than_block(Some(8192), Err(()))]
+    fn sectorsize(#[case] devsect: Option<usize>, #[case] expected: Result<(), ()>) {
+        let mut sb = Sb::default();
+        sb.sb_blocksize = 4096;
+        sb.sb_sectsize = 512;
+        assert_eq!(check_sectorsize(&sb, devsect).map_err(drop), expected);
+    }
+
+    /// Unknown read-only compatible features only prevent read-write mounts
+    #[rstest]
+    #[case::known_ro(0xf, false, true)]
+    #[case::known_rw(0xf, true, true)]
+    #[case::unknown_ro(1 << 31, false, true)]
+    #[case::unknown_rw(1 << 31, true, false)]
+    fn ro_compat(#[case] bits: u32, #[case] rw: bool, #[case] ok: bool) {
+        let mut sb = Sb::default();
+        sb.set_version(5);
+        sb.set_ro_compat(bits);
+        assert_eq!(check_ro_compat(&sb, rw).is_ok(), ok);
+    }
+
+    #[rstest]
+    #[case::local(S_IFDIR | 0o755, XfsDinodeFmt::Local, true)]
+    #[case::extents(S_IFDIR | 0o755, XfsDinodeFmt::Extents, true)]
+    #[case::btree(S_IFDIR | 0o755, XfsDinodeFmt::Btree, true)]
+    #[case::dev(S_IFDIR | 0o755, XfsDinodeFmt::Dev, false)]
+    #[case::file(S_IFREG | 0o644, XfsDinodeFmt::Extents, false)]
+    #[case::symlink(S_IFLNK | 0o777, XfsDinodeFmt::Local, false)]
+    fn root(#[case] mode: mode_t, #[case] di_format: XfsDinodeFmt, #[case] ok: bool) {
+        let core = DinodeCore {
+            di_mode: di_mode(mode),
+            di_format,
+            ..Default::default()
+        };
+        assert_eq!(check_root(&core).is_ok(), ok);
+    }
+
+    #[rstest]
+    #[case::private_dir(FileType::Directory, 0o700, 0o755)]
+    #[case::private_file(FileType::RegularFile, 0o600, 0o644)]
+    #[case::private_executable(FileType::RegularFile, 0o700, 0o755)]
+    #[case::group_executable(FileType::RegularFile, 0o010, 0o555)]
+    #[case::unreadable(FileType::RegularFile, 0o000, 0o444)]
+    #[case::setuid(FileType::RegularFile, 0o4700, 0o4755)]
+    #[case::sticky_dir(FileType::Directory, 0o1777, 0o1777)]
+    fn unrestrict(#[case] kind: FileType, #[case] perm: u16, #[case] expected: u16) {
+        assert_eq!(super::unrestrict(kind, perm), expected);
+    }
+
+    /// A getxattr with too small a buffer, followed by the usual size probe and retry, should
+    /// read a remote value's blocks only once.
+    #[test]
+    fn getxattr_erange() {
+        const VALUELEN: usize = 10000;
+        let sb = superblock();
+        let blocksize = sb.sb_blocksize as usize;
+        // The attribute fork is one leaf block at fsblock 2, then the value's blocks
+        let mut image = vec![0u8; 6 * blocksize];
+        let leaf = &mut image[2 * blocksize..3 * blocksize];
+        leaf[8..10].copy_from_slice(&XFS_ATTR_LEAF_MAGIC.to_be_bytes());
+        leaf[12..14].copy_from_slice(&1u16.to_be_bytes());
+        let nameidx = 4000;
+        leaf[32..36].copy_from_slice(&hashname(OsStr::new("big")).to_be_bytes());
+        leaf[36..38].copy_from_slice(&(nameidx as u16).to_be_bytes());
+        leaf[nameidx..nameidx + 4].copy_from_slice(&1u32.to_be_bytes());
+        leaf[nameidx + 4..nameidx + 8].copy_from_slice(&(VALUELEN as u32).to_be_bytes());
+        leaf[nameidx + 8] = 3;
+        leaf[nameidx + 9..nameidx + 12].copy_from_slice(b"big");
+        let value = (0..VALUELEN).map(|i| i as u8).collect::<Vec<_>>();
+        image[3 * blocksize..3 * blocksize + VALUELEN].copy_from_slice(&value);
+
+        let (cr, counts) = CountingReader::watching(image, 3 * blocksize as u64);
+        let mut br = BufReader::with_capacity(blocksize, cr);
+        let mut oi = OpenInode::new(Dinode::with_attr_extents(vec![BmbtRec {
+            br_startoff:   0,
+            br_startblock: 2,
+            br_blockcount: 4,
+            br_flag:       false,
+        }]));
+        let name = OsStr::new("user.big");
+
+        assert_eq!(oi.getxattr(&mut br, sb, name, 100), Err(ERANGE));
+        assert_eq!(
+            oi.getxattr(&mut br, sb, name, 0),
+            Ok((VALUELEN as u32, None))
+        );
+        assert_eq!(counts.bytes.get(), 0);
+        assert_eq!(
+            oi.getxattr(&mut br, sb, name, VALUELEN as u32),
+            Ok((VALUELEN as u32, Some(value)))
+        );
+        assert_eq!(counts.bytes.get(), 3 * blocksize);
+        assert_eq!(
+            oi.getxattr(&mut br, sb, OsStr::new("user.missing"), 0),
+            Err(libc::ENOATTR)
+        );
+    }
+
+    /// Probing for a missing extended attribute should read the attribute fork only once, and
+    /// not at all if the inode has no attribute fork.
+    #[test]
+    fn getxattr_missing() {
+        let sb = superblock();
+        let blocksize = sb.sb_blocksize as usize;
+        // The attribute fork is one empty leaf block at fsblock 2
+        let mut image = vec![0u8; 3 * blocksize];
+        image[2 * blocksize + 8..2 * blocksize + 10]
+            .copy_from_slice(&XFS_ATTR_LEAF_MAGIC.to_be_bytes());
+        let (cr, counts) = CountingReader::watching(image, 0);
+        let mut br = BufReader::with_capacity(blocksize, cr);
+        let extents = vec![BmbtRec {
+            br_startoff:   0,
+            br_startblock: 2,
+            br_blockcount: 1,
+            br_flag:       false,
+        }];
+        let name = OsStr::new("user.capability");
+
+        let mut oi = OpenInode::new(Dinode::with_attr_extents(extents.clone()));
+        assert_eq!(oi.getxattr(&mut br, sb, name, 0), Err(libc::ENOATTR));
+        let read = counts.bytes.get();
+        assert!(read > 0);
+        for _ in 0..1000 {
+            assert_eq!(oi.getxattr(&mut br, sb, name, 0), Err(libc::ENOATTR));
+        }
+        assert_eq!(counts.bytes.get(), read);
+
+        // Only a few names are remembered
+        for i in 0..100 {
+            let name = OsString::from(format!("user.missing{}", i));
+            assert_eq!(oi.getxattr(&mut br, sb, &name, 0), Err(libc::ENOATTR));
+        }
+        assert_eq!(oi.missing_xattrs.len(), OpenInode::MISSING_XATTRS_MAX);
+
+        let mut dinode = Dinode::with_attr_extents(extents);
+        dinode.di_a = None;
+        let mut oi = OpenInode::new(dinode);
+        counts.bytes.set(0);
+        assert_eq!(oi.getxattr(&mut br, sb, name, 0), Err(libc::ENOATTR));
+        assert_eq!(counts.bytes.get(), 0);
+        assert!(oi.missing_xattrs.is_empty());
+    }
+
+    /// Look up an inode as a LOOKUP does: load it, then take a reference for the reply.
+    fn lookup<F>(of: &mut OpenFiles, ino: u64, load: F)
+    where
+        F: FnOnce(u64) -> Result<Dinode, c_int>,
+    {
+        of.load(ino, load).unwrap();
+        of.entry(ino);
+    }
+
+    #[rstest]
+    #[case::released(Lookups(2), 2, Lookups(0), Forgotten::Released)]
+    #[case::remaining(Lookups(3), 1, Lookups(2), Forgotten::Remaining(2))]
+    #[case::underflow(Lookups(1), 3, Lookups(0), Forgotten::Underflow(2))]
+    #[case::internal(Lookups::INTERNAL, 1, Lookups(0), Forgotten::Underflow(1))]
+    fn lookups_forget(
+        #[case] lookups: Lookups,
+        #[case] nlookup: u64,
+        #[case] after: Lookups,
+        #[case] forgotten: Forgotten,
+    ) {
+        assert_eq!(lookups.forget(nlookup), (after, forgotten));
+    }
+
+    #[test]
+    fn lookups_entry_saturates() {
+        assert_eq!(Lookups(u64::MAX).entry(), Lookups(u64::MAX));
+        assert_eq!(Lookups::INTERNAL.entry(), Lookups(1));
+    }
+
+    /// An inode that the kernel looked up is tracked until it forgets it.
+    #[test]
+    fn lookup_then_forget() {
+        let load = |_| Ok(Dinode::with_attr_extents(Vec::new()));
+        let mut of = OpenFiles::new(DEFAULT_HOT_INODES, Default::default());
+        lookup(&mut of, 5, load);
+        assert_eq!(of.len(), 1);
+        assert_eq!(of.forget(5, 1), Some(Forgotten::Released));
+        assert_eq!(of.len(), 0);
+        assert_eq!(of.hot_len(), 0);
+        assert_eq!(of.get(5, load).err(), Some(libc::ESTALE));
+    }
+
+    /// An internal load takes no reference, so a FORGET for it underflows, and drops it rather
+    /// than leaving a count that no lookup ever took.
+    #[test]
+    fn load_then_forget() {
+        let load = |_| Ok(Dinode::with_attr_extents(Vec::new()));
+        let mut of = OpenFiles::new(DEFAULT_HOT_INODES, Default::default());
+        of.load(5, load).unwrap();
+        assert_eq!(of.hot_len(), 1);
+        assert_eq!(of.len(), 0);
+        assert_eq!(of.forget(5, 1), Some(Forgotten::Underflow(1)));
+        assert_eq!(of.hot_len(), 0);
+
+        // But a lookup after an internal load takes exactly one reference
+        of.load(6, load).unwrap();
+        lookup(&mut of, 6, load);
+        assert_eq!(of.forget(6, 1), Some(Forgotten::Released));
+        assert_eq!(of.hot_len(), 0);
+    }
+
+    /// An entry handed out by some reply other than LOOKUP, such as READDIRPLUS's, is forgotten
+    /// like any other.  A GETATTR that arrives afterwards fails cleanly.
+    #[test]
+    fn entry_then_forget_then_getattr() {
+        let loads = Cell::new(0);
+        let load = |_| {
+            loads.set(loads.get() + 1);
+            Ok(Dinode::with_attr_extents(Vec::new()))
+        };
+        let mut of = OpenFiles::new(DEFAULT_HOT_INODES, Default::default());
+        of.load(5, load).unwrap();
+        of.entry(5);
+        of.get(5, load).unwrap();
+        assert_eq!(loads.get(), 1);
+        assert_eq!(of.forget(5, 1), Some(Forgotten::Released));
+        assert_eq!(of.get(5, load).err(), Some(libc::ESTALE));
+        assert_eq!(loads.get(), 1);
+    }
+
+    /// Evicting an inode that the kernel doesn't reference drops it entirely, but one that it
+    /// does goes cold.
+    #[test]
+    fn evict_internal() {
+        let load = |_| Ok(Dinode::with_attr_extents(Vec::new()));
+        let mut of = OpenFiles::new(1, Default::default());
+        of.load(5, load).unwrap();
+        lookup(&mut of, 6, load);
+        assert!(of.cold.is_empty());
+        of.load(7, load).unwrap();
+        assert_eq!(of.cold.len(), 1);
+        assert!(of.cold.contains_key(&6));
+        assert_eq!(of.get(5, load).err(), Some(libc::ESTALE));
+    }
+
+    /// Walking many files keeps only the most recently used hot, while still tracking every
+    /// kernel lookup, and a cold inode can still be used.
+    #[test]
+    fn hot_inodes() {
+        const N: usize = 100;
+        const FILES: u64 = 100_000;
+        let loads = Cell::new(0);
+        let load = |_| {
+            loads.set(loads.get() + 1);
+            Ok(Dinode::with_attr_extents(Vec::new()))
+        };
+        let mut of = OpenFiles::new(N, Default::default());
+        for ino in 2..FILES + 2 {
+            lookup(&mut of, ino, load);
+            assert!(of.hot_len() <= N);
+        }
+        // Look some up twice, like the kernel does for hard links
+        for ino in 2..12 {
+            lookup(&mut of, ino, load);
+        }
+        assert_eq!(of.hot_len(), N);
+        assert_eq!(of.len(), FILES as usize);
+        assert_eq!(loads.get(), FILES + 10);
+
+        // A cold inode is reloaded, and keeps its lookup count
+        let oi = of.get(FILES / 2, load).unwrap();
+        assert_eq!(oi.lookups, Lookups(1));
+        assert_eq!(loads.get(), FILES + 11);
+        // A hot one isn't
+        of.get(FILES / 2, load).unwrap();
+        assert_eq!(loads.get(), FILES + 11);
+
+        // Forgetting frees both tiers
+        assert_eq!(of.forget(2, 1), Some(Forgotten::Remaining(1)));
+        lookup(&mut of, 2, load);
+        for ino in 2..FILES + 2 {
+            let nlookup = if ino < 12 { 2 } else { 1 };
+            assert_eq!(of.forget(ino, nlookup), Some(Forgotten::Released));
+        }
+        assert_eq!(of.len(), 0);
+        assert_eq!(of.hot_len(), 0);
+        assert!(of.lru.is_empty());
+        assert_eq!(of.forget(2, 1), None);
+        assert_eq!(of.get(2, load).err(), Some(libc::ESTALE));
+    }
+
+    /// With a memory limit, settling after each operation keeps the accounted total within it,
+    /// by evicting hot inodes, while still tracking every kernel lookup.  Dropping inodes
+    /// releases exactly what they charged.
+    #[test]
+    fn mem_limit() {
+        const FILES: u64 = 1000;
+        let rec = BmbtRec {
+            br_startoff:   0,
+            br_startblock: 0,
+            br_blockcount: 1,
+            br_flag:       false,
+        };
+        let load = |_| Ok(Dinode::with_attr_extents(vec![rec; 16]));
+        let per_inode = OpenInode::new(load(0).unwrap()).cache_bytes()[0];
+        let limit = 10 * per_inode;
+        let budget = Arc::new(CacheBudget::new(Some(limit)));
+        let mut of = OpenFiles::new(DEFAULT_HOT_INODES, budget.clone());
+        for ino in 2..FILES + 2 {
+            lookup(&mut of, ino, load);
+            of.settle();
+            assert!(budget.total() <= limit);
+        }
+        assert_eq!(of.hot_len(), 10);
+        assert_eq!(of.len(), FILES as usize);
+        assert_eq!(budget.used(CacheKind::Inodes), limit);
+
+        // A cold inode can still be used
+        of.get(2, load).unwrap();
+        of.settle();
+        assert!(budget.total() <= limit);
+
+        for ino in 2..FILES + 2 {
+            assert_eq!(of.forget(ino, 1), Some(Forgotten::Released));
+        }
+        assert_eq!(budget.total(), 0);
+    }
+
+    #[rstest]
+    #[case::root(128, ROOT_INO)]
+    #[case::other(131, 131)]
+    fn ino_mapping(#[case] ino: XfsIno, #[case] expected: u64) {
+        let mut sb = Sb::default();
+        sb.sb_rootino = 128;
+        assert_eq!(fuse_ino(&sb, ino), expected);
+        assert_eq!(xfs_ino(&sb, expected), ino);
+    }
+
+    /// A Volume on a single-AG device that begins with `img`, with 512 byte inodes
+    fn volume_on(img: &[u8], opts: Options) -> Volume {
+        let f = tempfile::NamedTempFile::new().unwrap();
+        f.as_file().set_len(1 << 20).unwrap();
+        f.as_file().write_all_at(img, 0).unwrap();
+        let mut sb = superblock().clone();
+        sb.sb_rootino = 128;
+        let device = if opts.rw {
+            BlockReader::open_rw(f.path()).unwrap()
+        } else {
+            BlockReader::open(f.path()).unwrap()
+        };
+        let cache_budget = Arc::new(CacheBudget::new(opts.mem_limit));
+        let stats = device.stats().clone();
+        stats.enter();
+        let validator = Arc::new(Validator::new(validate::Config {
+            strict: opts.strict,
+        }));
+        validator.enter();
+        Volume {
+            device,
+            device_name: f.path().to_owned(),
+            rtdev: None,
+            sb,
+            open_files: OpenFiles::new(
+                opts.hot_inodes.unwrap_or(DEFAULT_HOT_INODES),
+                cache_budget.clone(),
+            ),
+            cache_budget,
+            capabilities: 0,
+            opts,
+            audit: None,
+            inodes: None,
+            ftype_mismatches: 0,
+            opened: SystemTime::UNIX_EPOCH,
+            metadir_name: OsString::from(METADIR),
+            meta_handles: HashMap::new(),
+            next_fh: 1,
+            readahead: LruMap::default(),
+            read_pool: None,
+            stats,
+            validator,
+        }
+    }
+
+    /// A Volume on an empty device, for testing operations that shouldn't read anything
+    fn empty_volume(opts: Options) -> Volume {
+        volume_on(&[], opts)
+    }
+
+    /// Write a v3 inode core into `img`, at inode `ino`'s location in [`volume_on`]'s geometry,
+    /// and return the inode's data fork.
+    fn write_inode(
+        img: &mut Vec<u8>,
+        ino: XfsIno,
+        mode: mode_t,
+        format: XfsDinodeFmt,
+    ) -> &mut [u8] {
+        // (ino >> inopblog) << blocklog, plus (ino & inopmask) << inodelog
+        let offset = ((ino >> 3) << 12 | (ino & 7) << 9) as usize;
+        img.resize(img.len().max(offset + 512), 0);
+        let inode = &mut img[offset..offset + 512];
+        inode[0..2].copy_from_slice(&XFS_DINODE_MAGIC.to_be_bytes());
+        inode[2..4].copy_from_slice(&di_mode(mode).to_be_bytes());
+        inode[4] = 3;
+        inode[5] = format as u8;
+        inode[83] = XfsDinodeFmt::Extents as u8;
+        inode[152..160].copy_from_slice(&ino.to_be_bytes());
+        &mut inode[176..]
+    }
+
+    /// Write a root directory, inode 128, in shortform without file types, holding `entries`.
+    fn write_root(img: &mut Vec<u8>, entries: &[(&str, XfsIno)]) {
+        let mut sf = vec![entries.len() as u8, 0, 0, 0, 0, 128];
+        for (i, (name, ino)) in entries.iter().enumerate() {
+            sf.push(name.len() as u8);
+            sf.extend_from_slice(&(0x60 + 0x10 * i as u16).to_be_bytes());
+            sf.extend_from_slice(name.as_bytes());
+            sf.extend_from_slice(&(*ino as u32).to_be_bytes());
+        }
+        write_inode(img, 128, S_IFDIR | 0o755, XfsDinodeFmt::Local)[..sf.len()]
+            .copy_from_slice(&sf);
+        let root = 128 << 9;
+        img[root + 16..root + 20].copy_from_slice(&2u32.to_be_bytes());
+        img[root + 56..root + 64].copy_from_slice(&(sf.len() as u64).to_be_bytes());
+    }
+
+    /// Like [`volume_on`], but with the root directory loaded, as by [`Volume::open`]
+    fn root_volume(img: &[u8], opts: Options) -> Volume {
+        let mut vol = volume_on(img, opts);
+        let root = load_dinode(&mut vol.device, &vol.sb, ROOT_INO).unwrap();
+        vol.open_files.insert(ROOT_INO, OpenInode::new(root));
+        vol.open_files.entry(ROOT_INO);
+        vol
+    }
+
+    /// Forgetting the root, as some kernels do at unmount, mustn't drop it, since getattr may
+    /// still follow.  Not even after a lookup of ".." has found it.
+    #[rstest]
+    #[case::once(1)]
+    #[case::all(u64::MAX)]
+    fn forget_root(#[case] nlookup: u64) {
+        let mut img = Vec::new();
+        write_root(&mut img, &[]);
+        let mut vol = root_volume(&img, Options::default());
+        let (attr, _) = vol.do_lookup(ROOT_INO, OsStr::new("..")).unwrap();
+        assert_eq!(attr.ino, ROOT_INO);
+
+        vol.do_forget(ROOT_INO, nlookup);
+        assert_eq!(vol.do_getattr(ROOT_INO).unwrap().ino, ROOT_INO);
+        vol.do_forget(ROOT_INO, nlookup);
+        assert_eq!(vol.do_getattr(ROOT_INO).unwrap().ino, ROOT_INO);
+    }
+
+    /// A corrupt entry with an empty name can't be looked up, since readdir never reports it.
+    #[test]
+    fn lookup_empty_name() {
+        let mut img = Vec::new();
+        write_root(&mut img, &[("", 131)]);
+        let mut vol = root_volume(&img, Options::default());
+        assert_eq!(vol.do_lookup(ROOT_INO, OsStr::new("")), Err(libc::EINVAL));
+    }
+
+    /// No entry can have a name longer than 255 bytes, so don't bother searching for one
+    #[test]
+    fn lookup_long_name() {
+        let mut img = Vec::new();
+        write_root(&mut img, &[("a", 131)]);
+        let mut vol = root_volume(&img, Options::default());
+        let name = OsString::from("a".repeat(XFS_NAME_MAX + 1));
+        assert_eq!(vol.do_lookup(ROOT_INO, &name), Err(libc::ENAMETOOLONG));
+        let name = OsString::from("a".repeat(XFS_NAME_MAX));
+        assert_eq!(vol.do_lookup(ROOT_INO, &name), Err(libc::ENOENT));
+    }
+
+    /// Every way of reaching an inode's attributes should report exactly the same ones, or file
+    /// integrity monitors will think that it changed.
+    #[cfg(feature = "fuse")]
+    #[test]
+    fn attr_invariance() {
+        const FILE: XfsIno = 131;
+
+        let mut img = Vec::new();
+        write_root(&mut img, &[("a", FILE)]);
+
+        // "a": a regular file whose bigtime timestamps predate the Unix epoch and have nsec
+        write_inode(&mut img, FILE, S_IFREG | 0o640, XfsDinodeFmt::Extents);
+        let file = (FILE as usize >> 3 << 12) | (FILE as usize & 7) << 9;
+        let inode = &mut img[file..file + 512];
+        inode[8..12].copy_from_slice(&1234u32.to_be_bytes());
+        inode[12..16].copy_from_slice(&5678u32.to_be_bytes());
+        inode[16..20].copy_from_slice(&1u32.to_be_bytes());
+        for (i, ts) in [32, 40, 48, 144].into_iter().enumerate() {
+            let raw = ((1u64 << 31) - 100 + i as u64) * 1_000_000_000 + 123_456_789;
+            inode[ts..ts + 8].copy_from_slice(&raw.to_be_bytes());
+        }
+        inode[56..64].copy_from_slice(&14u64.to_be_bytes());
+        inode[92..96].copy_from_slice(&42u32.to_be_bytes());
+        inode[120..128].copy_from_slice(&8u64.to_be_bytes()); // XFS_DIFLAG2_BIGTIME
+
+        // A single hot inode, so every other operation evicts "a" and it must be reloaded
+        let opts = Options {
+            hot_inodes: Some(1),
+            ..Default::default()
+        };
+        let mut vol = root_volume(&img, opts);
+        vol.capabilities = WANTED_CAPABILITIES;
+
+        let (looked_up, gen) = vol.do_lookup(ROOT_INO, OsStr::new("a")).unwrap();
+        assert_eq!(gen, 42);
+        assert_eq!(looked_up.ino, FILE);
+        assert_eq!(looked_up.kind, FileType::RegularFile);
+        assert_eq!(looked_up.uid, 1234);
+        assert_ne!(looked_up.crtime, looked_up.ctime);
+
+        assert_eq!(vol.do_getattr(FILE), Ok(looked_up));
+        vol.do_getattr(ROOT_INO).unwrap();
+        assert_eq!(vol.do_getattr(FILE), Ok(looked_up), "after reload");
+
+        // Without ftype, readdir reads each entry's inode separately to learn its type
+        let mut entries = Vec::new();
+        vol.do_readdir(ROOT_INO, 0, |ino, _, kind, name| {
+            entries.push((ino, kind, name.to_owned()));
+            false
+        })
+        .unwrap();
+        assert!(entries.contains(&(FILE, looked_up.kind, OsString::from("a"))));
+        assert_eq!(vol.do_getattr(FILE), Ok(looked_up), "after readdir");
+    }
+
+    /// Probing for an in-memory attribute's size, and then fetching it, should work like for an
+    /// on-disk one.
+    #[rstest]
+    #[case::probe(0, Ok((5, None)))]
+    #[case::short(4, Err(ERANGE))]
+    #[case::exact(5, Ok((5, Some(b"hello".to_vec()))))]
+    #[case::long(4096, Ok((5, Some(b"hello".to_vec()))))]
+    fn sized(#[case] size: u32, #[case] expected: Result<(u32, Option<Vec<u8>>), c_int>) {
+        assert_eq!(sized_xattr(b"hello".to_vec(), size), expected);
+    }
+
+    #[test]
+    fn getxattr_stats() {
+        let mut img = Vec::new();
+        write_root(&mut img, &[]);
+        let mut vol = root_volume(&img, Options::default());
+        let name = OsStr::from_bytes(STATS_XATTR);
+        let (len, value) = vol.do_getxattr(ROOT_INO, name, 0).unwrap();
+        assert_eq!(value, None);
+        assert_eq!(vol.do_getxattr(ROOT_INO, name, len - 1), Err(ERANGE));
+        let (len2, value) = vol.do_getxattr(ROOT_INO, name, len).unwrap();
+        assert_eq!(len2, len);
+        let value = String::from_utf8(value.unwrap()).unwrap();
+        assert!(value.starts_with("{\"validation_failures\":"), "{}", value);
+    }
+
+    /// Uses of a cached inode count as inode cache hits, which the stats attribute reports
+    #[test]
+    fn getxattr_stats_inode_hits() {
+        let mut img = Vec::new();
+        write_root(&mut img, &[]);
+        let mut vol = root_volume(&img, Options::default());
+        let stats = vol.stats();
+        vol.do_getattr(ROOT_INO).unwrap();
+        vol.do_getattr(ROOT_INO).unwrap();
+        assert_eq!(stats.events(Event::InodeHit), 2);
+        assert_eq!(stats.events(Event::InodeMiss), 0);
+        let name = OsStr::from_bytes(STATS_XATTR);
+        let (_, value) = vol.do_getxattr(ROOT_INO, name, 1 << 12).unwrap();
+        let value = String::from_utf8(value.unwrap()).unwrap();
+        assert!(
+            value.contains(",\"activity\":{\"ops\":{},\"inode_cache\":{\"hits\":2,\"misses\":0},"),
+            "{}",
+            value
+        );
+    }
+
+    /// Without ftype, readdir reads each entry's inode to learn its type.  It keeps them for the
+    /// getattr that usually follows, but takes no reference, so a lookup and a forget that follow
+    /// still balance.
+    #[test]
+    fn readdir_fallback_load() {
+        const FILE: XfsIno = 131;
+        let mut img = Vec::new();
+        write_root(&mut img, &[("a", FILE)]);
+        write_inode(&mut img, FILE, S_IFREG | 0o644, XfsDinodeFmt::Extents);
+        let mut vol = root_volume(&img, Options::default());
+        let tracked = vol.open_files.len();
+
+        readdir_all(&mut vol, ROOT_INO, 0);
+        assert!(vol.open_files.hot.contains_key(&FILE));
+        assert_eq!(vol.open_files.len(), tracked);
+        vol.do_getattr(FILE).unwrap();
+
+        vol.do_lookup(ROOT_INO, OsStr::new("a")).unwrap();
+        assert_eq!(vol.open_files.len(), tracked + 1);
+        vol.do_forget(FILE, 1);
+        assert_eq!(vol.open_files.len(), tracked);
+        assert_eq!(vol.do_getattr(FILE), Err(libc::ESTALE));
+    }
+
+    /// An NFS server may present a file handle long after the kernel forgot its inode.  The
+    /// kernel then looks up "." in it, which must reload it from disk.
+    #[test]
+    fn lookup_handle_after_forget() {
+        const FILE: XfsIno = 131;
+        let mut img = Vec::new();
+        write_root(&mut img, &[("a", FILE)]);
+        let fork = write_inode(&mut img, FILE, S_IFREG | 0o644, XfsDinodeFmt::Extents);
+        let rec = 2u128 << 21 | 1;
+        fork[..16].copy_from_slice(&rec.to_be_bytes());
+        let inode = (FILE as usize) << 9;
+        img[inode + 56..inode + 64].copy_from_slice(&4096u64.to_be_bytes());
+        img[inode + 76..inode + 80].copy_from_slice(&1u32.to_be_bytes());
+        img[2 << 12..3 << 12].fill(0xaa);
+        let mut vol = root_volume(&img, Options::default());
+        let tracked = vol.open_files.len();
+
+        let (attr, gen) = vol.do_lookup(ROOT_INO, OsStr::new("a")).unwrap();
+        vol.do_forget(FILE, 1);
+        assert_eq!(vol.do_getattr(FILE), Err(libc::ESTALE));
+
+        assert_eq!(vol.do_lookup(FILE, OsStr::new(".")), Ok((attr, gen)));
+        let (data, skip) = vol.do_read(FILE, 0, 0, 4096).unwrap();
+        assert_eq!(&data[skip..], &[0xaa; 4096][..]);
+        vol.do_forget(FILE, 1);
+        assert_eq!(vol.open_files.len(), tracked);
+    }
+
+    /// A file handle whose inode is free, or that doesn't name an inode at all, is stale
+    #[rstest]
+    #[case::freed(131)]
+    #[case::never_allocated(132)]
+    #[case::ag_headers(3)]
+    #[case::beyond_fs(1 << 40)]
+    fn lookup_stale_handle(#[case] ino: u64) {
+        let mut img = Vec::new();
+        write_root(&mut img, &[]);
+        // A freed inode keeps its magic and version, but has no mode
+        write_inode(&mut img, 131, 0, XfsDinodeFmt::Extents);
+        let mut vol = root_volume(&img, Options::default());
+        assert_eq!(vol.do_lookup(ino, OsStr::new(".")), Err(libc::ESTALE));
+        assert_eq!(vol.open_files.hot_len(), 1);
+    }
+
+    /// The trace attribute works on any inode, and reports whether that inode is traced.  Since
+    /// the root is remapped, it must be traced by its XFS inode number.
+    #[rstest]
+    #[case::root(ROOT_INO, b"1")]
+    #[case::traced(131, b"1")]
+    #[case::untraced(132, b"0")]
+    fn getxattr_trace(#[case] ino: u64, #[case] expected: &[u8]) {
+        let opts = Options {
+            trace_inos: HashSet::from([128, 131]),
+            ..Default::default()
+        };
+        let mut img = Vec::new();
+        write_root(&mut img, &[]);
+        let mut vol = root_volume(&img, opts);
+        let name = OsStr::from_bytes(TRACE_XATTR);
+        assert_eq!(vol.do_getxattr(ino, name, 0), Ok((1, None)));
+        assert_eq!(
+            vol.do_getxattr(ino, name, 1),
+            Ok((1, Some(expected.to_vec())))
+        );
+    }
+
+    #[rstest]
+    #[case::set(libc::SEEK_SET)]
+    #[case::data(libc::SEEK_DATA)]
+    #[case::hole(libc::SEEK_HOLE)]
+    fn lseek_negative(#[case] whence: i32) {
+        let mut vol = empty_volume(Options::default());
+        assert_eq!(vol.do_lseek(131, -1, whence), Err(libc::EINVAL));
+    }
+
+    /// A regular file with no extents, whatever its fork's format, is one hole up to EOF
+    #[rstest]
+    #[case::extents_data(XfsDinodeFmt::Extents, 0, libc::SEEK_DATA, Err(libc::ENXIO))]
+    #[case::extents_hole(XfsDinodeFmt::Extents, 4096, libc::SEEK_HOLE, Ok(4096))]
+    #[case::extents_eof(XfsDinodeFmt::Extents, 1 << 20, libc::SEEK_HOLE, Err(libc::ENXIO))]
+    #[case::local_data(XfsDinodeFmt::Local, 0, libc::SEEK_DATA, Err(libc::ENXIO))]
+    #[case::local_hole(XfsDinodeFmt::Local, 4096, libc::SEEK_HOLE, Ok(4096))]
+    fn lseek_no_extents(
+        #[case] format: XfsDinodeFmt,
+        #[case] offset: i64,
+        #[case] whence: i32,
+        #[case] expected: Result<i64, c_int>,
+    ) {
+        let mut img = Vec::new();
+        write_root(&mut img, &[("a", 131)]);
+        write_inode(&mut img, 131, S_IFREG | 0o644, format);
+        let inode = 131 << 9;
+        img[inode + 56..inode + 64].copy_from_slice(&(1u64 << 20).to_be_bytes());
+        let mut vol = root_volume(&img, Options::default());
+        vol.do_lookup(ROOT_INO, OsStr::new("a")).unwrap();
+        assert_eq!(vol.do_lseek(131, offset, whence), expected);
+        let (data, skip) = vol.do_read(131, 0, 0, 4096).unwrap();
+        assert_eq!(&data[skip..], &[0u8; 4096][..]);
+    }
+
+    /// FIBMAP maps data blocks to device blocks in the kernel's units, and everything else to 0
+    #[rstest]
+    #[case::data(4096, 0, Ok(2))]
+    #[case::sector(512, 1, Ok(17))]
+    #[case::sector_last(512, 7, Ok(23))]
+    #[case::hole(4096, 1, Ok(0))]
+    #[case::unwritten(4096, 2, Ok(0))]
+    #[case::beyond_eof(4096, 100, Ok(0))]
+    #[case::overflow(4096, u64::MAX, Err(libc::EINVAL))]
+    #[case::bad_blocksize(1000, 0, Err(libc::EINVAL))]
+    fn bmap(#[case] blocksize: u32, #[case] idx: u64, #[case] expected: Result<u64, c_int>) {
+        let mut img = Vec::new();
+        write_root(&mut img, &[("a", 131)]);
+        let fork = write_inode(&mut img, 131, S_IFREG | 0o644, XfsDinodeFmt::Extents);
+        // Block 0 at fsblock 2, a hole, then block 2 unwritten at fsblock 5
+        let recs = [2u128 << 21 | 1, 1 << 127 | 2 << 73 | 5 << 21 | 1];
+        for (i, rec) in recs.iter().enumerate() {
+            fork[16 * i..16 * (i + 1)].copy_from_slice(&rec.to_be_bytes());
+        }
+        let inode = 131 << 9;
+        img[inode + 56..inode + 64].copy_from_slice(&(3u64 << 12).to_be_bytes());
+        img[inode + 76..inode + 80].copy_from_slice(&2u32.to_be_bytes());
+        let mut vol = root_volume(&img, Options::default());
+        vol.do_lookup(ROOT_INO, OsStr::new("a")).unwrap();
+        assert_eq!(vol.do_bmap(131, blocksize, idx), expected);
+    }
+
+    /// Only regular files have blocks to map
+    #[test]
+    fn bmap_dir() {
+        let mut img = Vec::new();
+        write_root(&mut img, &[]);
+        let mut vol = root_volume(&img, Options::default());
+        assert_eq!(vol.do_bmap(ROOT_INO, 4096, 0), Err(libc::EINVAL));
+    }
+
+    /// An inode that can't be decoded, because its type or a fork's format is unknown or doesn't
+    /// suit it, fails with EIO.  The rest of the file system stays usable.
+    #[rstest]
+    #[case::dir_dev(|i: &mut [u8]| {
+        i[2..4].copy_from_slice(&di_mode(S_IFDIR | 0o755).to_be_bytes());
+        i[5] = XfsDinodeFmt::Dev as u8;
+    })]
+    #[case::file_uuid(|i: &mut [u8]| i[5] = XfsDinodeFmt::Uuid as u8)]
+    #[case::symlink_btree(|i: &mut [u8]| {
+        i[2..4].copy_from_slice(&di_mode(S_IFLNK | 0o777).to_be_bytes());
+        i[5] = XfsDinodeFmt::Btree as u8;
+    })]
+    #[case::unknown_format(|i: &mut [u8]| i[5] = 0x7f)]
+    #[case::unknown_type(|i: &mut [u8]| i[2..4].copy_from_slice(&0o160644u16.to_be_bytes()))]
+    #[case::attr_dev(|i: &mut [u8]| {
+        i[82] = 18;
+        i[83] = XfsDinodeFmt::Dev as u8;
+    })]
+    #[case::attr_beyond_inode(|i: &mut [u8]| {
+        i[82] = 0xff;
+        i[83] = XfsDinodeFmt::Local as u8;
+    })]
+    #[case::btree_root_overflow(|i: &mut [u8]| {
+        i[5] = XfsDinodeFmt::Btree as u8;
+        i[178..180].copy_from_slice(&0xffffu16.to_be_bytes());
+    })]
+    #[case::bad_magic(|i: &mut [u8]| i[0] = 0)]
+    #[case::bad_version(|i: &mut [u8]| i[4] = 7)]
+    fn undecodable_inode(#[case] corrupt: fn(&mut [u8])) {
+        let mut img = Vec::new();
+        write_root(&mut img, &[("bad", 131), ("good", 132)]);
+        write_inode(&mut img, 131, S_IFREG | 0o644, XfsDinodeFmt::Extents);
+        corrupt(&mut img[131 << 9..132 << 9]);
+        let fork = write_inode(&mut img, 132, S_IFREG | 0o644, XfsDinodeFmt::Extents);
+        let rec = 2u128 << 21 | 1;
+        fork[..16].copy_from_slice(&rec.to_be_bytes());
+        let good = 132 << 9;
+        img[good + 56..good + 64].copy_from_slice(&4096u64.to_be_bytes());
+        img[good + 76..good + 80].copy_from_slice(&1u32.to_be_bytes());
+        img[2 << 12..3 << 12].fill(0xaa);
+        let mut vol = root_volume(&img, Options::default());
+
+        assert_eq!(
+            vol.do_lookup(ROOT_INO, OsStr::new("bad")).unwrap_err(),
+            libc::EIO
+        );
+        let (attr, _) = vol.do_lookup(ROOT_INO, OsStr::new("good")).unwrap();
+        let (data, skip) = vol.do_read(attr.ino, 0, 0, 4096).unwrap();
+        assert_eq!(&data[skip..], &[0xaa; 4096][..]);
+        assert_eq!(
+            vol.do_lookup(ROOT_INO, OsStr::new("bad")).unwrap_err(),
+            libc::EIO
+        );
+        assert_eq!(vol.do_getattr(ROOT_INO).unwrap().ino, ROOT_INO);
+    }
+
+    /// After INODES_DIR, the root has no more entries to list
+    #[test]
+    fn readdir_after_inodes_dir() {
+        let opts = Options {
+            inodes_dir: true,
+            ..Default::default()
+        };
+        let mut vol = empty_volume(opts);
+        let r = vol.do_readdir(ROOT_INO, INODES_DIR_OFFSET, |_, _, _, _| {
+            panic!("No more entries expected")
+        });
+        assert_eq!(r, Ok(0));
+    }
+
+    /// A full reply buffer should stop INODES_DIR's listing before it walks the inode btrees
+    #[rstest]
+    #[case::dot(0, 1, ".")]
+    #[case::dotdot(1, 2, "..")]
+    fn readdir_inodes_dir_full(#[case] offset: i64, #[case] next: i64, #[case] expected: &str) {
+        let opts = Options {
+            inodes_dir: true,
+            ..Default::default()
+        };
+        let mut vol = empty_volume(opts);
+        let mut entries = Vec::new();
+        let r = vol.do_readdir(INODES_DIR_INO, offset, |ino, offset, kind, name| {
+            entries.push((ino, offset, kind, name.to_owned()));
+            true
+        });
+        assert_eq!(r, Ok(0));
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].1, next);
+        assert_eq!(entries[0].2, FileType::Directory);
+        assert_eq!(entries[0].3, OsStr::new(expected));
+    }
+
+    /// List a directory's entries from `offset` onwards: their inode numbers, offsets, types, and
+    /// names
+    fn readdir_all(vol: &mut Volume, ino: u64, offset: i64) -> Vec<(u64, i64, FileType, OsString)> {
+        let mut entries = Vec::new();
+        vol.do_readdir(ino, offset, |ino, offset, kind, name| {
+            entries.push((ino, offset, kind, name.to_owned()));
+            false
+        })
+        .unwrap();
+        entries
+    }
+
+    /// readdirplus reports each entry's attributes, and takes a reference for every entry that
+    /// the reply accepted, except "." and "..".
+    #[test]
+    fn readdirplus() {
+        let mut img = Vec::new();
+        write_root(&mut img, &[("a", 131), ("b", 132), ("c", 133)]);
+        write_inode(&mut img, 131, S_IFREG | 0o644, XfsDinodeFmt::Extents);
+        write_inode(&mut img, 132, S_IFREG | 0o600, XfsDinodeFmt::Extents);
+        write_inode(&mut img, 133, S_IFLNK | 0o777, XfsDinodeFmt::Local);
+        let mut vol = root_volume(&img, Options::default());
+
+        let mut entries = Vec::new();
+        let r = vol.do_readdirplus(ROOT_INO, 0, |ino, offset, name, _, attr, _| {
+            if ino == 133 {
+                return true;
+            }
+            entries.push((ino, offset, name.to_owned(), *attr));
+            false
+        });
+        assert_eq!(r, Ok(4));
+        let names = entries.iter().map(|e| e.2.clone()).collect::<Vec<_>>();
+        assert_eq!(names, [".", "..", "a", "b"]);
+        for (ino, _, name, attr) in entries.iter() {
+            assert_eq!(attr.ino, *ino, "{:?}", name);
+            assert_eq!(Ok(*attr), vol.do_getattr(*ino), "{:?}", name);
+        }
+        assert_eq!(entries[2].3.kind, FileType::RegularFile);
+        assert_eq!(entries[2].3.perm, 0o644);
+        assert_eq!(entries[3].3.perm, 0o600);
+        assert_eq!(vol.open_files.forget(131, 1), Some(Forgotten::Released));
+        assert_eq!(vol.open_files.forget(132, 1), Some(Forgotten::Released));
+        // The rejected entry took no reference
+        assert_eq!(vol.open_files.forget(133, 1), Some(Forgotten::Underflow(1)));
+
+        // Resuming from the last accepted entry finds the rejected one
+        let mut rest = Vec::new();
+        vol.do_readdirplus(ROOT_INO, entries[3].1, |ino, _, _, _, attr, _| {
+            rest.push((ino, attr.kind));
+            false
+        })
+        .unwrap();
+        assert_eq!(rest, [(133, FileType::Symlink)]);
+        // Neither did "." and "..", so the root holds only its mount time reference
+        assert_eq!(
+            vol.open_files.forget(ROOT_INO, 1),
+            Some(Forgotten::Released)
+        );
+    }
+
+    #[test]
+    fn metadir() {
+        let mut img = Vec::new();
+        write_root(&mut img, &[("a", 131)]);
+        write_inode(&mut img, 131, S_IFREG | 0o644, XfsDinodeFmt::Extents);
+        let opts = Options {
+            metadir: true,
+            inodes_dir: true,
+            ..Default::default()
+        };
+        let mut vol = root_volume(&img, opts);
+
+        let (attr, _) = vol.do_lookup(ROOT_INO, OsStr::new(".xfuse")).unwrap();
+        assert_eq!(attr.ino, METADIR_INO);
+        assert_eq!(attr.kind, FileType::Directory);
+        assert_eq!(attr.perm, 0o555);
+        assert_eq!(vol.do_getattr(METADIR_INO), Ok(attr));
+        assert_eq!(vol.do_lookup(METADIR_INO, OsStr::new(".")), Ok((attr, 0)));
+
+        // The synthetic directories follow the root's real entries
+        let names = readdir_all(&mut vol, ROOT_INO, 0)
+            .into_iter()
+            .map(|(_, _, _, name)| name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, [".", "..", "a", ".xfuse", ".inodes"]);
+        let after = readdir_all(&mut vol, ROOT_INO, METADIR_OFFSET);
+        assert_eq!(after.len(), 1);
+        assert_eq!(after[0].1, INODES_DIR_OFFSET);
+
+        let entries = readdir_all(&mut vol, METADIR_INO, 0);
+        let names = entries.iter().map(|e| e.3.clone()).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [".", "..", "superblock.json", "stats.json", "mount.json"]
+        );
+        assert_eq!(
+            readdir_all(&mut vol, METADIR_INO, entries[2].1),
+            entries[3..]
+        );
+
+        // Every file's size is that of the contents that it would have if opened now
+        for (ino, _, kind, name) in entries[2..].iter() {
+            assert_eq!(*kind, FileType::RegularFile);
+            let (attr, _) = vol.do_lookup(METADIR_INO, name).unwrap();
+            assert_eq!(attr.ino, *ino);
+            assert_eq!(attr.perm, 0o444);
+            let (data, skip) = vol.do_read(*ino, 0, 0, 1 << 16).unwrap();
+            assert_eq!(data.len() - skip, attr.size as usize, "{:?}", name);
+            assert_eq!(data.last(), Some(&b'\n'));
+        }
+        assert_eq!(
+            vol.do_lookup(METADIR_INO, OsStr::new("foo.json")),
+            Err(libc::ENOENT)
+        );
+        assert_eq!(vol.do_listxattr(METADIR_INO, 0), Ok((0, None)));
+    }
+
+    /// An open MetaFile keeps the contents that it had when opened
+    #[test]
+    fn metadir_read_snapshot() {
+        let opts = Options {
+            metadir: true,
+            ..Default::default()
+        };
+        let mut vol = empty_volume(opts);
+        let ino = MetaFile::Stats.ino();
+        vol.meta_handles.insert(7, b"{\"old\":true}\n".to_vec());
+        let (data, skip) = vol.do_read(ino, 7, 1, 3).unwrap();
+        assert_eq!(&data[skip..], b"\"ol");
+        let (data, skip) = vol.do_read(ino, 7, 100, 3).unwrap();
+        assert_eq!(&data[skip..], b"");
+        // Without a handle, the contents are generated for each read
+        let (data, skip) = vol.do_read(ino, 0, 0, 1 << 16).unwrap();
+        assert!(data[skip..].starts_with(b"{\"validation_failures\":"));
+        assert_eq!(vol.do_read(ino, 0, -1, 1), Err(libc::EINVAL));
+    }
+
+    /// Without -o metadir, there's no trace of it
+    #[test]
+    fn metadir_disabled() {
+        let mut img = Vec::new();
+        write_root(&mut img, &[]);
+        let mut vol = root_volume(&img, Options::default());
+        assert_eq!(
+            vol.do_lookup(ROOT_INO, OsStr::new(".xfuse")).err(),
+            Some(libc::ENOENT)
+        );
+        assert_eq!(readdir_all(&mut vol, ROOT_INO, 0).len(), 2);
+        assert!(vol.meta_file(MetaFile::Superblock.ino()).is_none());
+    }
+
+    /// A real entry named .xfuse must not be hidden, so the synthetic one gets a suffix.
+    #[rstest]
+    #[case::free(&[], ".xfuse")]
+    #[case::taken(&[".xfuse"], ".xfuse.1")]
+    #[case::suffix_taken(&[".xfuse", ".xfuse.1"], ".xfuse.2")]
+    #[case::only_suffix_taken(&[".xfuse.1"], ".xfuse")]
+    fn metadir_name_collision(#[case] names: &[&str], #[case] expected: &str) {
+        let mut img = Vec::new();
+        let entries = names.iter().map(|name| (*name, 131)).collect::<Vec<_>>();
+        write_root(&mut img, &entries);
+        let mut vol = volume_on(&img, Options::default());
+        let mut root = load_dinode(&mut vol.device, &vol.sb, ROOT_INO).unwrap();
+        assert_eq!(
+            metadir_name(&mut vol.device, &vol.sb, &mut root),
+            Ok(OsString::from(expected))
+        );
+    }
+
+    #[cfg(feature = "fuse")]
+    #[rstest]
+    #[case::all(u32::MAX, WANTED_CAPABILITIES)]
+    #[case::none(0, 0)]
+    #[case::no_export(!FUSE_EXPORT_SUPPORT, WANTED_CAPABILITIES & !FUSE_EXPORT_SUPPORT)]
+    #[case::no_open(
+        FUSE_ASYNC_READ | FUSE_EXPORT_SUPPORT,
+        FUSE_ASYNC_READ | FUSE_EXPORT_SUPPORT
+    )]
+    fn capabilities(#[case] supported: u32, #[case] expected: u32) {
+        let (caps, requested) = negotiate(supported);
+        assert_eq!(caps, expected);
+        // Whatever we think we negotiated must be what was actually requested
+        assert_eq!(requested, expected);
+    }
+
+    /// The generation is reported only if the kernel can use it
+    #[cfg(feature = "fuse")]
+    #[rstest]
+    #[case::export(WANTED_CAPABILITIES, 42)]
+    #[case::no_export(WANTED_CAPABILITIES & !FUSE_EXPORT_SUPPORT, 0)]
+    #[case::before_init(0, 0)]
+    fn generation(#[case] caps: u32, #[case] expected: u64) {
+        const FILE: XfsIno = 131;
+
+        let mut img = Vec::new();
+        write_root(&mut img, &[("a", FILE)]);
+        write_inode(&mut img, FILE, S_IFREG | 0o644, XfsDinodeFmt::Extents);
+        let file = (FILE as usize >> 3 << 12) | (FILE as usize & 7) << 9;
+        img[file + 92..file + 96].copy_from_slice(&42u32.to_be_bytes());
+        let mut vol = root_volume(&img, Options::default());
+        vol.capabilities = caps;
+
+        let (attr, gen) = vol.do_lookup(ROOT_INO, OsStr::new("a")).unwrap();
+        assert_eq!(attr.ino, FILE);
+        assert_eq!(gen, expected);
+    }
+
+    #[cfg(feature = "fuse")]
+    #[rstest]
+    #[case::all(WANTED_CAPABILITIES, &[])]
+    #[case::no_async_read(WANTED_CAPABILITIES & !FUSE_ASYNC_READ, &["FUSE_ASYNC_READ"])]
+    #[case::no_export(WANTED_CAPABILITIES & !FUSE_EXPORT_SUPPORT, &["FUSE_EXPORT_SUPPORT"])]
+    #[case::no_readdirplus(
+        WANTED_CAPABILITIES & !FUSE_DO_READDIRPLUS,
+        &["FUSE_DO_READDIRPLUS"]
+    )]
+    #[case::none(0, &["FUSE_ASYNC_READ", "FUSE_EXPORT_SUPPORT", "FUSE_DO_READDIRPLUS"])]
+    fn missing_capability_notes(#[case] caps: u32, #[case] expected: &[&str]) {
+        let notes = missing_capabilities(caps);
+        assert_eq!(notes.len(), expected.len());
+        for (note, cap) in notes.iter().zip(expected) {
+            assert!(note.contains(cap), "{:?} doesn't mention {}", note, cap);
+        }
+    }
+
+    /// Teardown should drop every cache, even those of inodes that the kernel never forgot
+    #[test]
+    fn teardown() {
+        let mut img = Vec::new();
+        write_root(&mut img, &[("a", 131)]);
+        write_inode(&mut img, 131, S_IFREG | 0o644, XfsDinodeFmt::Extents);
+        let opts = Options {
+            inodes_dir: true,
+            metadir: true,
+            ..Default::default()
+        };
+        let mut vol = root_volume(&img, opts);
+        vol.do_lookup(ROOT_INO, OsStr::new("a")).unwrap();
+        readdir_all(&mut vol, ROOT_INO, 0);
+        vol.meta_handles.insert(1, Vec::new());
+        vol.inodes = Some(vec![128, 131]);
+
+        vol.teardown();
+        assert_eq!(vol.open_files.len(), 0);
+        assert!(vol.inodes.is_none());
+        assert!(vol.meta_handles.is_empty());
+    }
+
+    /// A zero-length read of a cached file needs no device reads, but one of a nonexistent inode
+    /// still fails.
+    #[test]
+    fn read_zero() {
+        let mut img = Vec::new();
+        write_root(&mut img, &[("a", 131)]);
+        let fork = write_inode(&mut img, 131, S_IFREG | 0o644, XfsDinodeFmt::Extents);
+        let rec = 2u128 << 21 | 1;
+        fork[..16].copy_from_slice(&rec.to_be_bytes());
+        let file = (131 >> 3 << 12) | (131 & 7) << 9;
+        img[file + 56..file + 64].copy_from_slice(&4096u64.to_be_bytes());
+        img[file + 76..file + 80].copy_from_slice(&1u32.to_be_bytes());
+        let mut vol = root_volume(&img, Options::default());
+        vol.do_lookup(ROOT_INO, OsStr::new("a")).unwrap();
+
+        trace::start();
+        assert_eq!(vol.do_read(131, 0, 0, 0), Ok((Vec::new(), 0)));
+        assert_eq!(vol.do_read(131, 0, 100, 0), Ok((Vec::new(), 0)));
+        assert_eq!(trace::finish(), Vec::new());
+        assert!(vol.do_read(132, 0, 0, 0).is_err());
+    }
+
+    /// A volume with a 4096-byte regular file, inode 131 named "a", whose only block is block 2
+    /// and is filled with 0xaa.  Its size is `size`.
+    fn one_block_volume(size: u64, opts: Options) -> Volume {
+        let mut img = Vec::new();
+        write_root(&mut img, &[("a", 131)]);
+        let fork = write_inode(&mut img, 131, S_IFREG | 0o644, XfsDinodeFmt::Extents);
+        let rec = 2u128 << 21 | 1;
+        fork[..16].copy_from_slice(&rec.to_be_bytes());
+        let file = (131 >> 3 << 12) | (131 & 7) << 9;
+        img[file + 56..file + 64].copy_from_slice(&size.to_be_bytes());
+        img[file + 76..file + 80].copy_from_slice(&1u32.to_be_bytes());
+        img[2 << 12..3 << 12].fill(0xaa);
+        let mut vol = root_volume(&img, opts);
+        vol.do_lookup(ROOT_INO, OsStr::new("a")).unwrap();
+        vol
+    }
+
+    /// Read inode 131 back from the device, as [`one_block_volume`] left it, checking its CRC
+    fn reread_inode(vol: &mut Volume) -> DinodeCore {
+        let ofs = vol.sb.ino_to_offset(131).unwrap();
+        let mut raw = vec![0u8; 512];
+        vol.device.seek(SeekFrom::Start(ofs)).unwrap();
+        vol.device.read_exact(&mut raw).unwrap();
+        assert!(validate::crc_ok(&raw, 100));
+        decode_from(bincode::de::read::SliceReader::new(&raw)).unwrap()
+    }
+
+    /// Writes may only overwrite blocks that the file already has, though its size may grow
+    /// into them.
+    #[rstest]
+    #[case::overwrite(4096, 100, 5, Ok(5), 4096)]
+    #[case::whole_block(4096, 0, 4096, Ok(4096), 4096)]
+    #[case::beyond_eof(100, 200, 5, Ok(5), 205)]
+    #[case::unallocated(4096, 4094, 4, Err(libc::ENOSPC), 4096)]
+    #[case::hole(4096, 8192, 1, Err(libc::ENOSPC), 4096)]
+    fn write(
+        #[case] size: u64,
+        #[case] offset: i64,
+        #[case] len: usize,
+        #[case] expected: Result<u32, c_int>,
+        #[case] new_size: i64,
+    ) {
+        let opts = Options {
+            rw: true,
+            ..Default::default()
+        };
+        let mut vol = one_block_volume(size, opts);
+        let data = vec![0x55u8; len];
+        assert_eq!(vol.do_write(131, offset, &data), expected);
+
+        let mut block = vec![0u8; 4096];
+        vol.device.seek(SeekFrom::Start(2 << 12)).unwrap();
+        vol.device.read_exact(&mut block).unwrap();
+        for (i, b) in block.iter().enumerate() {
+            let written = expected.is_ok() && (offset as usize..offset as usize + len).contains(&i);
+            assert_eq!(*b, if written { 0x55 } else { 0xaa }, "byte {}", i);
+        }
+        assert_eq!(vol.do_getattr(131).unwrap().size, new_size as u64);
+        if expected.is_ok() {
+            assert_eq!(reread_inode(&mut vol).di_size, new_size);
+        }
+    }
+
+    /// Write a regular file with inode `ino` whose data fork holds `extents`, as (file block,
+    /// device block, length), and whose di_flags2 is `flags2`.
+    fn write_file(img: &mut Vec<u8>, ino: XfsIno, extents: &[(u64, u64, u64)], flags2: u64) {
+        let fork = write_inode(img, ino, S_IFREG | 0o644, XfsDinodeFmt::Extents);
+        for (i, (off, bno, len)) in extents.iter().enumerate() {
+            let rec = u128::from(*off) << 73 | u128::from(*bno) << 21 | u128::from(*len);
+            fork[i * 16..i * 16 + 16].copy_from_slice(&rec.to_be_bytes());
+        }
+        let size = extents
+            .iter()
+            .map(|(off, _, len)| off + len)
+            .max()
+            .unwrap_or(0)
+            << 12;
+        let file = ((ino >> 3) << 12 | (ino & 7) << 9) as usize;
+        img[file + 56..file + 64].copy_from_slice(&size.to_be_bytes());
+        img[file + 76..file + 80].copy_from_slice(&(extents.len() as u32).to_be_bytes());
+        img[file + 120..file + 128].copy_from_slice(&flags2.to_be_bytes());
+    }
+
+    /// Reflinked files each read their own data, even after one was partly overwritten, and so
+    /// no longer shares that block.
+    #[test]
+    fn reflink_read() {
+        const REFLINK: u64 = 2; // XFS_DIFLAG2_REFLINK
+        let mut img = Vec::new();
+        write_root(&mut img, &[("a", 131), ("b", 132)]);
+        write_file(&mut img, 131, &[(0, 2, 2)], REFLINK);
+        write_file(&mut img, 132, &[(0, 2, 1), (1, 4, 1)], REFLINK);
+        img.resize(img.len().max(5 << 12), 0);
+        img[2 << 12..3 << 12].fill(0xaa);
+        img[3 << 12..4 << 12].fill(0xbb);
+        img[4 << 12..5 << 12].fill(0xcc);
+        let mut vol = root_volume(&img, Options::default());
+        vol.sb.set_version(5);
+        vol.sb.set_reflink(true);
+
+        for (name, second) in [("a", 0xbb), ("b", 0xcc)] {
+            let (attr, _) = vol.do_lookup(ROOT_INO, OsStr::new(name)).unwrap();
+            let (data, skip) = vol.do_read(attr.ino, 0, 0, 8192).unwrap();
+            let data = &data[skip..];
+            assert_eq!(data.len(), 8192, "{}", name);
+            assert!(data[..4096].iter().all(|b| *b == 0xaa), "{}", name);
+            assert!(data[4096..].iter().all(|b| *b == second), "{}", name);
+        }
+    }
+
+    /// Only files flagged as reflinked can share blocks, so only theirs need the refcount btree
+    /// searched before they're overwritten.  This image has none, so searching fails.
+    #[rstest]
+    #[case::unflagged(0, Ok(1))]
+    #[case::reflinked(2, Err(libc::EIO))]
+    fn write_reflink(#[case] flags2: u64, #[case] expected: Result<u32, c_int>) {
+        let mut img = Vec::new();
+        write_root(&mut img, &[("a", 131)]);
+        write_file(&mut img, 131, &[(0, 2, 1)], flags2);
+        let opts = Options {
+            rw: true,
+            ..Default::default()
+        };
+        let mut vol = root_volume(&img, opts);
+        vol.sb.set_version(5);
+        vol.sb.set_reflink(true);
+        vol.do_lookup(ROOT_INO, OsStr::new("a")).unwrap();
+        assert_eq!(vol.do_write(131, 0, b"x"), expected);
+    }
+
+    /// Without -o rw, nothing may be written
+    #[test]
+    fn write_ro() {
+        let mut vol = one_block_volume(4096, Options::default());
+        assert_eq!(vol.do_write(131, 0, b"x"), Err(libc::EROFS));
+        assert_eq!(
+            vol.do_setattr(131, Some(0), None, None, SystemTime::now())
+                .unwrap_err(),
+            libc::EROFS
+        );
+    }
+
+    /// Truncating a file down and then up again must zero what used to lie beyond the new EOF.
+    #[test]
+    fn truncate() {
+        let opts = Options {
+            rw: true,
+            ..Default::default()
+        };
+        let mut vol = one_block_volume(4096, opts);
+        assert_eq!(
+            vol.do_setattr(131, Some(10), None, None, SystemTime::now())
+                .unwrap()
+                .size,
+            10
+        );
+        assert_eq!(
+            vol.do_setattr(131, Some(20), None, None, SystemTime::now())
+                .unwrap()
+                .size,
+            20
+        );
+        let (data, skip) = vol.do_read(131, 0, 0, 100).unwrap();
+        assert_eq!(data[skip..], [[0xaa; 10], [0; 10]].concat());
+        assert_eq!(reread_inode(&mut vol).di_size, 20);
+
+        // Growing into a hole needs no allocation
+        assert_eq!(
+            vol.do_setattr(131, Some(10000), None, None, SystemTime::now())
+                .unwrap()
+                .size,
+            10000
+        );
+    }
+
+    /// A handle that reads sequentially, even a few bytes at a time, touches the device only
+    /// once per [`Options::readahead`].  Another handle's reads elsewhere don't disturb it.
+    #[test]
+    fn readahead() {
+        let mut img = Vec::new();
+        write_root(&mut img, &[("a", 131)]);
+        write_file(&mut img, 131, &[(0, 2, 3)], 0);
+        img.resize(img.len().max(5 << 12), 0);
+        for (i, b) in img[2 << 12..5 << 12].iter_mut().enumerate() {
+            *b = (i / 7) as u8;
+        }
+        let opts = Options {
+            readahead: 8192,
+            ..Default::default()
+        };
+        let mut vol = root_volume(&img, opts);
+        vol.do_lookup(ROOT_INO, OsStr::new("a")).unwrap();
+        let stats = vol.stats();
+
+        let mut data = Vec::new();
+        let mut fills = 0;
+        loop {
+            let before = stats.device_bytes();
+            let (v, skip) = vol.do_read(131, 1, data.len() as i64, 16).unwrap();
+            fills += usize::from(stats.device_bytes() != before);
+            if v.len() == skip {
+                break;
+            }
+            data.extend_from_slice(&v[skip..]);
+            if data.len() == 4096 {
+                let (v, skip) = vol.do_read(131, 2, 10000, 16).unwrap();
+                assert_eq!(v[skip..], img[(2 << 12) + 10000..(2 << 12) + 10016]);
+            }
+        }
+        assert_eq!(data, img[2 << 12..5 << 12]);
+        assert_eq!(fills, 2);
+    }
+
+    /// Writing drops what was read ahead, so later reads see the new data.
+    #[test]
+    fn readahead_write() {
+        let opts = Options {
+            rw: true,
+            readahead: 8192,
+            ..Default::default()
+        };
+        let mut vol = one_block_volume(4096, opts);
+        let (v, skip) = vol.do_read(131, 1, 0, 1).unwrap();
+        assert_eq!(v[skip..], [0xaa]);
+        assert_eq!(vol.do_write(131, 1, b"x"), Ok(1));
+        let (v, skip) = vol.do_read(131, 1, 1, 1).unwrap();
+        assert_eq!(v[skip..], *b"x");
+    }
+
+    /// What was read ahead is charged to the cache budget until it's dropped, and is the first
+    /// thing evicted to get back within the memory limit.
+    #[rstest]
+    fn readahead_budget(#[values(None, Some(4096))] mem_limit: Option<u64>) {
+        let mut img = Vec::new();
+        write_root(&mut img, &[("a", 131)]);
+        write_file(&mut img, 131, &[(0, 2, 3)], 0);
+        img.resize(img.len().max(5 << 12), 0);
+        let opts = Options {
+            readahead: 8192,
+            mem_limit,
+            ..Default::default()
+        };
+        let mut vol = root_volume(&img, opts);
+        vol.do_lookup(ROOT_INO, OsStr::new("a")).unwrap();
+        vol.do_read(131, 1, 0, 16).unwrap();
+        vol.do_read(131, 2, 0, 16).unwrap();
+        assert!(vol.cache_budget().used(CacheKind::Readahead) >= 2 * 8192);
+
+        vol.settle_caches();
+        if mem_limit.is_some() {
+            assert_eq!(vol.cache_budget().used(CacheKind::Readahead), 0);
+        } else {
+            vol.drop_readahead(131);
+            assert_eq!(vol.cache_budget().used(CacheKind::Readahead), 0);
+        }
+    }
+
+    /// Give the root directory written by [`write_root`] a shortform attribute fork holding these
+    /// user attributes.
+    fn write_root_xattrs(img: &mut [u8], xattrs: &[(&str, &str)]) {
+        const FORKOFF: usize = 18;
+        let root = 128 << 9;
+        let mut sf = vec![0, 0, xattrs.len() as u8, 0];
+        for (name, value) in xattrs {
+            sf.extend_from_slice(&[name.len() as u8, value.len() as u8, 0]);
+            sf.extend_from_slice(name.as_bytes());
+            sf.extend_from_slice(value.as_bytes());
+        }
+        let totsize = sf.len() as u16;
+        sf[0..2].copy_from_slice(&totsize.to_be_bytes());
+        img[root + 82] = FORKOFF as u8;
+        img[root + 83] = XfsDinodeFmt::Local as u8;
+        let afork = root + 176 + FORKOFF * 8;
+        img[afork..afork + sf.len()].copy_from_slice(&sf);
+    }
+
+    /// The root's list holds its real attributes and the virtual ones, each exactly once, and its
+    /// size agrees with the list itself.
+    #[rstest]
+    #[case::real(&[("attr", "value")], b"user.attr\0user.xfuse.stats\0")]
+    #[case::shadowed(
+        &[("xfuse.stats", "real"), ("attr", "value")],
+        b"user.xfuse.stats\0user.attr\0"
+    )]
+    #[case::none(&[], b"user.xfuse.stats\0")]
+    fn listxattr_root(#[case] xattrs: &[(&str, &str)], #[case] expected: &[u8]) {
+        let mut img = Vec::new();
+        write_root(&mut img, &[]);
+        write_root_xattrs(&mut img, xattrs);
+        let mut vol = root_volume(&img, Options::default());
+
+        let (len, list) = vol.do_listxattr(ROOT_INO, 0).unwrap();
+        assert_eq!(list, None);
+        assert_eq!(len as usize, expected.len());
+        assert_eq!(vol.do_listxattr(ROOT_INO, len - 1), Err(ERANGE));
+        assert_eq!(
+            vol.do_listxattr(ROOT_INO, len),
+            Ok((len, Some(expected.to_vec())))
+        );
+    }
+
+    /// A real attribute on the root hides a virtual one of the same name
+    #[test]
+    fn getxattr_root_shadowed() {
+        let mut img = Vec::new();
+        write_root(&mut img, &[]);
+        write_root_xattrs(&mut img, &[("xfuse.stats", "real"), ("attr", "value")]);
+        let mut vol = root_volume(&img, Options::default());
+
+        let stats = OsStr::from_bytes(STATS_XATTR);
+        assert_eq!(vol.do_getxattr(ROOT_INO, stats, 0), Ok((4, None)));
+        assert_eq!(
+            vol.do_getxattr(ROOT_INO, stats, 4),
+            Ok((4, Some(b"real".to_vec())))
+        );
+        assert_eq!(
+            vol.do_getxattr(ROOT_INO, OsStr::new("user.attr"), 5),
+            Ok((5, Some(b"value".to_vec())))
+        );
+        // The trace attribute is still virtual
+        assert_eq!(
+            vol.do_getxattr(ROOT_INO, OsStr::from_bytes(TRACE_XATTR), 1),
+            Ok((1, Some(b"0".to_vec())))
+        );
     }
 }
@@ -25,65 +25,455 @@
  * OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
  * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
  */
-use std::path::PathBuf;
+use std::{
+    env,
+    ffi::OsString,
+    fs,
+    io::{self, BufWriter},
+    os::unix::net::UnixDatagram,
+    path::{Path, PathBuf},
+    process,
+    sync::Arc,
+    thread,
+};
 
 use clap::{crate_version, Parser};
-use fuser::{mount2, MountOption};
-use libxfuse::volume::Volume;
+use fuser::{MountOption, Session};
+use nix::{
+    sys::signal::{SigSet, Signal},
+    unistd::{self, Gid, Uid, User},
+};
+use tracing::{info, warn};
 use tracing_subscriber::EnvFilter;
+use xfuse::libxfuse::{
+    ag::check,
+    cat::cat,
+    dump::dump,
+    extract::extract,
+    list::{list, Format, DEFAULT_MAX_DEPTH},
+    orphans::orphans,
+    stats::Stats,
+    volume::Volume,
+};
 
-mod libxfuse;
+mod options;
 
 #[derive(Parser, Clone, Debug)]
 #[clap(version = crate_version!())]
 struct App {
     /// Mount options, comma delimited.
     #[clap(short = 'o', long, value_delimiter(','))]
-    options:    Vec<String>,
-    device:     PathBuf,
-    mountpoint: String,
+    options:         Vec<String>,
+    /// Print a recursive listing of the file system instead of mounting it.
+    #[clap(long)]
+    list:            bool,
+    /// Check the allocation group headers and print their status as JSON, instead of mounting.
+    #[clap(long, conflicts_with = "list")]
+    check:           bool,
+    /// Output format for --list.
+    #[clap(long, value_enum, default_value_t, requires = "list")]
+    format:          Format,
+    /// Only list the subtree rooted at this path within the file system.
+    #[clap(long, default_value = "/", requires = "list")]
+    path:            PathBuf,
+    /// Don't descend into directories more than this many levels below --path.
+    #[clap(long, default_value_t = DEFAULT_MAX_DEPTH, requires = "list")]
+    max_depth:       usize,
+    /// List the inodes on the AGIs' unlinked lists as JSON, instead of mounting.
+    #[clap(long, conflicts_with_all = ["list", "check"])]
+    orphans:         bool,
+    /// Copy each orphaned regular file's contents into this directory, named by inode number.
+    #[clap(long, value_name = "DIR", requires = "orphans")]
+    recover_orphans: Option<PathBuf>,
+    /// Print the contents of the regular file at this path within the file system, instead of
+    /// mounting.
+    #[clap(long, value_name = "PATH", conflicts_with_all = ["list", "check", "orphans"])]
+    cat:             Option<PathBuf>,
+    /// With --cat, print the value of this extended attribute instead, which may be of any file.
+    #[clap(long, value_name = "NAME", requires = "cat")]
+    xattr:           Option<OsString>,
+    /// Copy the file or directory tree at this path within the file system into --dest, instead
+    /// of mounting.
+    #[clap(
+        long,
+        value_name = "PATH",
+        requires = "dest",
+        conflicts_with_all = ["list", "check", "orphans", "cat"]
+    )]
+    extract:         Option<PathBuf>,
+    /// The local directory that --extract copies into.  It must already exist.
+    #[clap(long, value_name = "DIR", requires = "extract")]
+    dest:            Option<PathBuf>,
+    /// With --extract, create block and character devices too.  Requires root.
+    #[clap(long, requires = "extract")]
+    devices:         bool,
+    /// With --extract, copy owners, setuid and setgid bits, and extended attributes outside of
+    /// the user namespace too.  Requires root.
+    #[clap(long, requires = "extract")]
+    owner:           bool,
+    /// Print the superblock, and the inode given by --inode if any, as JSON instead of mounting.
+    #[clap(long, conflicts_with_all = ["list", "check", "orphans", "cat", "extract"])]
+    dump:            bool,
+    /// With --dump, also print this inode's metadata and fork contents.
+    #[clap(long, value_name = "INO", requires = "dump")]
+    inode:           Option<u64>,
+    /// Write the process ID to this file once the file system is mounted.
+    #[clap(long, conflicts_with_all = ["list", "check", "orphans", "cat", "extract", "dump"])]
+    pidfile:         Option<PathBuf>,
+    /// Read the device with read(2), even if it's a regular file that could be memory-mapped.
+    #[clap(long)]
+    no_mmap:         bool,
+    /// Limit the memory used by all caches together, like -o mem_limit, which it overrides.
+    #[clap(long, value_name = "BYTES", value_parser = parse_cache_size)]
+    cache_size:      Option<u64>,
+    /// Read realtime files' data from this device, the file system's realtime section.
+    #[clap(long, value_name = "PATH", conflicts_with_all = [
+        "list", "check", "orphans", "cat", "extract", "dump"
+    ])]
+    rtdev:           Option<PathBuf>,
+    /// Describe every mount option that -o accepts, and exit.
+    #[clap(long, exclusive = true)]
+    help_options:    bool,
+    #[clap(required_unless_present = "help_options")]
+    device:          Option<PathBuf>,
+    #[clap(required_unless_present_any = [
+        "list", "check", "orphans", "cat", "extract", "dump", "help_options"
+    ])]
+    mountpoint:      Option<String>,
+}
+
+/// Exit status when --list skipped part of the tree, because of a directory cycle or --max-depth,
+/// or --extract couldn't extract some files, from sysexits(3)
+const EX_DATAERR: i32 = 65;
+/// Exit status when the device doesn't exist, from sysexits(3)
+const EX_NOINPUT: i32 = 66;
+/// Exit status when the device can't be opened for lack of permission, from sysexits(3)
+const EX_NOPERM: i32 = 77;
+
+/// The group whose members may usually read disk devices
+#[cfg(target_os = "freebsd")]
+const DISK_GROUP: &str = "operator";
+#[cfg(not(target_os = "freebsd"))]
+const DISK_GROUP: &str = "disk";
+
+/// Where to look for how to change a device's permissions
+#[cfg(target_os = "freebsd")]
+const DEVICE_PERMS_HINT: &str = "devfs.rules(5)";
+#[cfg(not(target_os = "freebsd"))]
+const DEVICE_PERMS_HINT: &str = "udev(7)";
+
+/// Parse --cache-size, which accepts the same sizes as -o mem_limit.
+fn parse_cache_size(s: &str) -> Result<u64, String> {
+    match options::parse_size(s) {
+        Some(n) if n > 0 => Ok(n),
+        _ => Err(format!("invalid size: {}", s)),
+    }
+}
+
+/// Check that the device can be opened, before doing anything else with it.  If it can't, explain
+/// why in terms that a user can act on, and exit.
+fn check_device(device: &Path) {
+    let e = match fs::File::open(device) {
+        Ok(_) => return,
+        Err(e) => e,
+    };
+    match e.raw_os_error() {
+        Some(libc::EACCES | libc::EPERM) => {
+            eprintln!(
+                "xfs-fuse: cannot open {}: permission denied",
+                device.display()
+            );
+            eprintln!(
+                "Reading it requires root, or membership in the \"{}\" group.  Try again with \
+                 sudo, or change the device's permissions; see {}.",
+                DISK_GROUP, DEVICE_PERMS_HINT
+            );
+            process::exit(EX_NOPERM);
+        }
+        Some(libc::ENOENT) => {
+            eprintln!(
+                "xfs-fuse: cannot open {}: no such file or device",
+                device.display()
+            );
+            eprintln!("Check the device's path.");
+            process::exit(EX_NOINPUT);
+        }
+        _ => {
+            eprintln!("xfs-fuse: cannot open {}: {}", device.display(), e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Who to run as once the file system is mounted: the `dropto` user, or else the user who invoked
+/// sudo.  None if we weren't started as root, or there's nobody else to be.
+fn privilege_target(dropto: Option<&str>) -> Result<Option<(Uid, Gid)>, String> {
+    if !Uid::effective().is_root() {
+        if let Some(user) = dropto {
+            warn!("Not started as root, so ignoring dropto={}", user);
+        }
+        return Ok(None);
+    }
+    let Some(user) = dropto else {
+        let id = |var| env::var(var).ok().and_then(|id| id.parse().ok());
+        return Ok(match (id("SUDO_UID"), id("SUDO_GID")) {
+            (Some(uid), Some(gid)) if uid != 0 => Some((Uid::from_raw(uid), Gid::from_raw(gid))),
+            _ => None,
+        });
+    };
+    let pw = match user.parse() {
+        Ok(uid) => User::from_uid(Uid::from_raw(uid)),
+        Err(_) => User::from_name(user),
+    };
+    match pw {
+        Ok(Some(pw)) => Ok(Some((pw.uid, pw.gid))),
+        Ok(None) => Err(format!("dropto: no such user: {}", user)),
+        Err(e) => Err(format!("dropto: cannot look up {}: {}", user, e)),
+    }
+}
+
+/// Give up root, keeping only the file descriptors that are already open: the device's, the
+/// FUSE session's, and the audit log's.
+fn drop_privileges(uid: Uid, gid: Gid) -> nix::Result<()> {
+    unistd::setgroups(&[gid])?;
+    unistd::setgid(gid)?;
+    unistd::setuid(uid)?;
+    // If root can be regained, then it wasn't really given up
+    if unistd::setuid(Uid::from_raw(0)).is_ok() {
+        return Err(nix::Error::EPERM);
+    }
+    Ok(())
+}
+
+/// Tell the service manager, if any, that the file system is mounted.  Like sd_notify(3), this
+/// sends "READY=1" to the datagram socket named by `$NOTIFY_SOCKET`.
+fn notify_ready() {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let r = UnixDatagram::unbound().and_then(|sock| {
+        // Linux also allows sockets in the abstract namespace
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::{
+                linux::net::SocketAddrExt,
+                unix::{ffi::OsStrExt, net::SocketAddr},
+            };
+
+            if let Some(name) = path.as_bytes().strip_prefix(b"@") {
+                let addr = SocketAddr::from_abstract_name(name)?;
+                return sock.send_to_addr(b"READY=1", &addr);
+            }
+        }
+        sock.send_to(b"READY=1", Path::new(&path))
+    });
+    if let Err(e) = r {
+        warn!("Cannot notify {:?} of readiness: {}", path, e);
+    }
+}
+
+/// Log the mount's statistics whenever SIGUSR1 arrives.  The signal must already be blocked in
+/// every thread, so that only this one receives it, with sigwait(2).
+fn log_stats_on_sigusr1(sigusr1: SigSet, stats: Arc<Stats>) {
+    let r = thread::Builder::new()
+        .name("xfs-fuse-stats".to_string())
+        .spawn(move || loop {
+            match sigusr1.wait() {
+                Ok(_) => info!("Statistics: {}", stats.to_json()),
+                Err(e) => {
+                    warn!("Cannot wait for SIGUSR1: {}", e);
+                    return;
+                }
+            }
+        });
+    if let Err(e) = r {
+        warn!("Cannot start the statistics thread: {}", e);
+    }
 }
 
 fn main() {
     tracing_subscriber::fmt()
         .pretty()
         .with_env_filter(EnvFilter::from_default_env())
+        .with_writer(io::stderr)
         .init();
 
     let app = App::parse();
+    if app.help_options {
+        print!("{}", options::help());
+        return;
+    }
+    // Report every bad option before touching the device
+    let mut parsed = match options::parse(&app.options) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("xfs-fuse: {}", e);
+            process::exit(2);
+        }
+    };
+    let target = match privilege_target(parsed.dropto.as_deref()) {
+        Ok(target) => target,
+        Err(e) => {
+            eprintln!("xfs-fuse: {}", e);
+            process::exit(2);
+        }
+    };
+    let device = app.device.unwrap();
+    check_device(&device);
+    if app.cache_size.is_some() {
+        parsed.vol.mem_limit = app.cache_size;
+    }
+    if let Some(rtdev) = &app.rtdev {
+        check_device(rtdev);
+    }
+    parsed.vol.rtdev = app.rtdev;
+    parsed.vol.no_mmap = app.no_mmap;
+
+    if app.list {
+        let out = BufWriter::new(io::stdout().lock());
+        match list(
+            &device,
+            &app.path,
+            app.format,
+            app.max_depth,
+            app.no_mmap,
+            out,
+        ) {
+            Ok(true) => (),
+            Ok(false) => process::exit(EX_DATAERR),
+            Err(e) => {
+                eprintln!("xfs-fuse: {}", e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if app.check {
+        let out = BufWriter::new(io::stdout().lock());
+        match check(&device, app.no_mmap, out) {
+            Ok(true) => (),
+            Ok(false) => process::exit(1),
+            Err(e) => {
+                eprintln!("xfs-fuse: {}", e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if app.orphans {
+        let out = BufWriter::new(io::stdout().lock());
+        if let Err(e) = orphans(&device, app.recover_orphans.as_deref(), app.no_mmap, out) {
+            eprintln!("xfs-fuse: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(path) = &app.cat {
+        let out = BufWriter::new(io::stdout().lock());
+        if let Err(e) = cat(&device, path, app.xattr.as_deref(), app.no_mmap, out) {
+            eprintln!("xfs-fuse: {}: {}", path.display(), e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(path) = &app.extract {
+        let dest = app.dest.as_deref().unwrap();
+        let summary = match extract(&device, path, dest, app.devices, app.owner, app.no_mmap) {
+            Ok(summary) => summary,
+            Err(e) => {
+                eprintln!("xfs-fuse: {}: {}", path.display(), e);
+                process::exit(1);
+            }
+        };
+        if let Err(e) = summary.write(io::stdout().lock()) {
+            eprintln!("xfs-fuse: {}", e);
+            process::exit(1);
+        }
+        if summary.errors > 0 {
+            process::exit(EX_DATAERR);
+        }
+        return;
+    }
+
+    if app.dump {
+        let out = BufWriter::new(io::stdout().lock());
+        if let Err(e) = dump(&device, app.inode, app.no_mmap, out) {
+            eprintln!("xfs-fuse: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
 
     let mut opts = vec![
         MountOption::FSName("fusefs".to_string()),
         MountOption::Subtype("xfs".to_string()),
-        MountOption::RO,
+        if parsed.vol.rw {
+            MountOption::RW
+        } else {
+            MountOption::RO
+        },
     ];
     // geteuid is always safe
     if unsafe { libc::geteuid() } == 0 {
         opts.push(MountOption::AllowOther);
         opts.push(MountOption::DefaultPermissions);
     }
-    for o in app.options.iter() {
-        opts.push(match o.as_str() {
-            "auto_unmount" => MountOption::AutoUnmount,
-            "allow_other" => MountOption::AllowOther,
-            "allow_root" => MountOption::AllowRoot,
-            "default_permissions" => MountOption::DefaultPermissions,
-            "dev" => MountOption::Dev,
-            "nodev" => MountOption::NoDev,
-            "suid" => MountOption::Suid,
-            "nosuid" => MountOption::NoSuid,
-            "exec" => MountOption::Exec,
-            "noexec" => MountOption::NoExec,
-            "atime" => MountOption::Atime,
-            "noatime" => MountOption::NoAtime,
-            "dirsync" => MountOption::DirSync,
-            "sync" => MountOption::Sync,
-            "async" => MountOption::Async,
-            custom => MountOption::CUSTOM(custom.to_string()),
-        });
-    }
+    opts.extend(parsed.mount);
 
-    let vol = Volume::from(&app.device);
+    // Block SIGUSR1 before Volume::open starts any threads, since they inherit the mask
+    let mut sigusr1 = SigSet::empty();
+    sigusr1.add(Signal::SIGUSR1);
+    let sigusr1 = match sigusr1.thread_block() {
+        Ok(()) => Some(sigusr1),
+        Err(e) => {
+            warn!("Cannot block SIGUSR1, so it won't log statistics: {}", e);
+            None
+        }
+    };
+    let vol = match Volume::open(&device, parsed.vol) {
+        Ok(vol) => vol,
+        Err(e) => {
+            eprintln!("xfs-fuse: {}", e);
+            process::exit(1);
+        }
+    };
+    if let Some(sigusr1) = sigusr1 {
+        log_stats_on_sigusr1(sigusr1, vol.stats());
+    }
 
-    mount2(vol, app.mountpoint, &opts[..]).unwrap();
+    let mountpoint = app.mountpoint.unwrap();
+    let mut session = match Session::new(vol, Path::new(&mountpoint), &opts[..]) {
+        Ok(session) => session,
+        Err(e) => {
+            eprintln!("xfs-fuse: {}: {}", mountpoint, e);
+            process::exit(1);
+        }
+    };
+    // Only now is the file system ready for use.
+    if let Some(pidfile) = &app.pidfile {
+        if let Err(e) = fs::write(pidfile, format!("{}\n", process::id())) {
+            eprintln!("xfs-fuse: {}: {}", pidfile.display(), e);
+            drop(session);
+            process::exit(1);
+        }
+    }
+    // Root is only needed to open the device and to mount.  Don't serve untrusted images with it.
+    if let Some((uid, gid)) = target {
+        if let Err(e) = drop_privileges(uid, gid) {
+            eprintln!("xfs-fuse: cannot switch to uid {}: {}", uid, e);
+            drop(session);
+            process::exit(1);
+        }
+        info!("Switched to uid {} and gid {}", uid, gid);
+    }
+    notify_ready();
+    let r = session.run();
+    if let Some(pidfile) = &app.pidfile {
+        let _ = fs::remove_file(pidfile);
+    }
+    r.unwrap();
 }
@@ -0,0 +1,595 @@
+/*
+ * BSD 2-Clause License
+ *
+ * Copyright (c) 2021, Khaled Emara
+ * All rights reserved.
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions are met:
+ *
+ * 1. Redistributions of source code must retain the above copyright notice, this
+ *    list of conditions and the following disclaimer.
+ *
+ * 2. Redistributions in binary form must reproduce the above copyright notice,
+ *    this list of conditions and the following disclaimer in the documentation
+ *    and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+ * AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+ * IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+ * DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+ * FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+ * SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+ * CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+ * OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+ * OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+ */
+//! Parsing and validation of the `-o` mount options
+
+use std::{collections::HashMap, fmt, path::PathBuf, time::Duration};
+
+use fuser::MountOption;
+use xfuse::libxfuse::{
+    block_reader::ReadTimeout,
+    volume::{Options, DEFAULT_READAHEAD},
+};
+
+/// Everything that the mount options control
+#[derive(Debug, Default)]
+pub struct Parsed {
+    /// Options for the kernel
+    pub mount:    Vec<MountOption>,
+    /// Options for the [`Volume`](xfuse::Volume)
+    pub vol:      Options,
+    /// The user to run as once mounted, by name or number
+    pub dropto:   Option<String>,
+    read_timeout: Option<Duration>,
+    read_retries: u32,
+    readahead:    Option<u32>,
+}
+
+/// A recognized mount option
+struct Spec {
+    name:  &'static str,
+    /// What to call the option's value in the help, if it takes one
+    value: Option<&'static str>,
+    help:  &'static str,
+    /// Apply the option, given its value, or "" if it takes none.  Return false if the value is
+    /// invalid.
+    apply: fn(&mut Parsed, &str) -> bool,
+}
+
+/// Every recognized mount option.  Both the parser and `--help-options` use this table.
+const SPECS: &[Spec] = &[
+    Spec {
+        name:  "auditlog",
+        value: Some("path"),
+        help:  "Append a record of every lookup, read, and similar operation to this file",
+        apply: |p, path| {
+            p.vol.audit_log = Some(PathBuf::from(path));
+            true
+        },
+    },
+    Spec {
+        name:  "check_ags",
+        value: None,
+        help:  "Check every allocation group's headers at mount time",
+        apply: |p, _| {
+            p.vol.check_ags = true;
+            true
+        },
+    },
+    Spec {
+        name:  "dropto",
+        value: Some("user"),
+        help:  "When started as root, switch to this user once mounted",
+        apply: |p, user| {
+            p.dropto = Some(user.to_owned());
+            !user.is_empty()
+        },
+    },
+    Spec {
+        name:  "hot_inodes",
+        value: Some("n"),
+        help:  "Keep at most this many inodes cached",
+        apply: |p, n| match n.parse::<usize>() {
+            Ok(n) if n > 0 => {
+                p.vol.hot_inodes = Some(n);
+                true
+            }
+            _ => false,
+        },
+    },
+    Spec {
+        name:  "inodes_dir",
+        value: None,
+        help:  "Expose every allocated inode in a synthetic /.inodes directory",
+        apply: |p, _| {
+            p.vol.inodes_dir = true;
+            true
+        },
+    },
+    Spec {
+        name:  "mem_limit",
+        value: Some("bytes"),
+        help:  "Limit the memory used by all caches together, optionally in K, M, or G",
+        apply: |p, bytes| match parse_size(bytes) {
+            Some(n) if n > 0 => {
+                p.vol.mem_limit = Some(n);
+                true
+            }
+            _ => false,
+        },
+    },
+    Spec {
+        name:  "metadir",
+        value: None,
+        help:  "Expose geometry and statistics as JSON in a synthetic /.xfuse directory",
+        apply: |p, _| {
+            p.vol.metadir = true;
+            true
+        },
+    },
+    Spec {
+        name:  "norestrict",
+        value: None,
+        help:  "Report every inode as readable, and directories as searchable, by everyone",
+        apply: |p, _| {
+            p.vol.norestrict = true;
+            true
+        },
+    },
+    Spec {
+        name:  "read_retries",
+        value: Some("n"),
+        help:  "Retry a timed out device read this many times",
+        apply: |p, n| match n.parse::<u32>() {
+            Ok(n) => {
+                p.read_retries = n;
+                true
+            }
+            Err(_) => false,
+        },
+    },
+    Spec {
+        name:  "read_timeout",
+        value: Some("secs"),
+        help:  "Fail device reads with EIO if they take longer than this",
+        apply: |p, secs| match secs
+            .parse::<f64>()
+            .ok()
+            .and_then(|s| Duration::try_from_secs_f64(s).ok())
+        {
+            Some(d) if !d.is_zero() => {
+                p.read_timeout = Some(d);
+                true
+            }
+            _ => false,
+        },
+    },
+    Spec {
+        name:  "readahead",
+        value: Some("bytes"),
+        help:  "Read this much at once when a file is read sequentially, or 0 not to",
+        apply: |p, bytes| match parse_size(bytes).and_then(|n| u32::try_from(n).ok()) {
+            Some(n) => {
+                p.readahead = Some(n);
+                true
+            }
+            None => false,
+        },
+    },
+    Spec {
+        name:  "rw",
+        value: None,
+        help:  "Allow overwriting the existing data of regular files, without allocating",
+        apply: |p, _| {
+            p.vol.rw = true;
+            true
+        },
+    },
+    Spec {
+        name:  "skip_bad_entries",
+        value: None,
+        help:  "Omit directory entries with invalid inode numbers from listings",
+        apply: |p, _| {
+            p.vol.skip_bad_entries = true;
+            true
+        },
+    },
+    Spec {
+        name:  "strict",
+        value: None,
+        help:  "Validate metadata strictly, and fail with EIO whenever a check does",
+        apply: |p, _| {
+            p.vol.strict = true;
+            true
+        },
+    },
+    Spec {
+        name:  "threads",
+        value: Some("n"),
+        help:  "Read regular files' data on this many threads, apart from other operations",
+        apply: |p, n| match n.parse::<usize>() {
+            Ok(n) => {
+                p.vol.threads = n;
+                true
+            }
+            Err(_) => false,
+        },
+    },
+    Spec {
+        name:  "trace_inos",
+        value: Some("ino[:ino...]"),
+        help:  "Log every operation on these inodes, and the device reads that it makes",
+        apply: |p, inos| {
+            // Mount options are comma-delimited, so inode numbers are colon-delimited
+            for ino in inos.split(':') {
+                match ino.parse() {
+                    Ok(ino) => p.vol.trace_inos.insert(ino),
+                    Err(_) => return false,
+                };
+            }
+            true
+        },
+    },
+    // The rest are passed to the kernel
+    Spec {
+        name:  "allow_other",
+        value: None,
+        help:  "Allow all users to access the file system",
+        apply: |p, _| {
+            p.mount.push(MountOption::AllowOther);
+            true
+        },
+    },
+    Spec {
+        name:  "allow_root",
+        value: None,
+        help:  "Allow root, as well as the mounting user, to access the file system",
+        apply: |p, _| {
+            p.mount.push(MountOption::AllowRoot);
+            true
+        },
+    },
+    Spec {
+        name:  "async",
+        value: None,
+        help:  "Perform all I/O asynchronously",
+        apply: |p, _| {
+            p.mount.push(MountOption::Async);
+            true
+        },
+    },
+    Spec {
+        name:  "atime",
+        value: None,
+        help:  "Update access times",
+        apply: |p, _| {
+            p.mount.push(MountOption::Atime);
+            true
+        },
+    },
+    Spec {
+        name:  "auto_unmount",
+        value: None,
+        help:  "Unmount the file system when the process exits",
+        apply: |p, _| {
+            p.mount.push(MountOption::AutoUnmount);
+            true
+        },
+    },
+    Spec {
+        name:  "default_permissions",
+        value: None,
+        help:  "Let the kernel enforce file permissions",
+        apply: |p, _| {
+            p.mount.push(MountOption::DefaultPermissions);
+            true
+        },
+    },
+    Spec {
+        name:  "dev",
+        value: None,
+        help:  "Interpret character and block special files",
+        apply: |p, _| {
+            p.mount.push(MountOption::Dev);
+            true
+        },
+    },
+    Spec {
+        name:  "dirsync",
+        value: None,
+        help:  "Perform directory updates synchronously",
+        apply: |p, _| {
+            p.mount.push(MountOption::DirSync);
+            true
+        },
+    },
+    Spec {
+        name:  "exec",
+        value: None,
+        help:  "Allow execution of binaries",
+        apply: |p, _| {
+            p.mount.push(MountOption::Exec);
+            true
+        },
+    },
+    Spec {
+        name:  "noatime",
+        value: None,
+        help:  "Don't update access times",
+        apply: |p, _| {
+            p.mount.push(MountOption::NoAtime);
+            true
+        },
+    },
+    Spec {
+        name:  "nodev",
+        value: None,
+        help:  "Don't interpret character and block special files",
+        apply: |p, _| {
+            p.mount.push(MountOption::NoDev);
+            true
+        },
+    },
+    Spec {
+        name:  "noexec",
+        value: None,
+        help:  "Don't allow execution of binaries",
+        apply: |p, _| {
+            p.mount.push(MountOption::NoExec);
+            true
+        },
+    },
+    Spec {
+        name:  "nosuid",
+        value: None,
+        help:  "Ignore setuid and setgid bits",
+        apply: |p, _| {
+            p.mount.push(MountOption::NoSuid);
+            true
+        },
+    },
+    Spec {
+        name:  "suid",
+        value: None,
+        help:  "Honor setuid and setgid bits",
+        apply: |p, _| {
+            p.mount.push(MountOption::Suid);
+            true
+        },
+    },
+    Spec {
+        name:  "sync",
+        value: None,
+        help:  "Perform all I/O synchronously",
+        apply: |p, _| {
+            p.mount.push(MountOption::Sync);
+            true
+        },
+    },
+];
+
+/// Pairs of options that may not be used together
+const CONFLICTS: &[(&str, &str)] = &[
+    ("allow_other", "allow_root"),
+    ("async", "sync"),
+    ("atime", "noatime"),
+    ("dev", "nodev"),
+    ("exec", "noexec"),
+    ("nosuid", "suid"),
+    // strict would make skip_bad_entries ineffective
+    ("skip_bad_entries", "strict"),
+];
+
+/// Parse a size in bytes, with an optional binary K, M, or G suffix.
+pub fn parse_size(s: &str) -> Option<u64> {
+    let (digits, shift) = match s.as_bytes().last()?.to_ascii_uppercase() {
+        b'K' => (&s[..s.len() - 1], 10),
+        b'M' => (&s[..s.len() - 1], 20),
+        b'G' => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+/// Every problem found with the mount options
+#[derive(Debug, Eq, PartialEq)]
+pub struct OptionsError(pub Vec<String>);
+
+impl fmt::Display for OptionsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid mount options:")?;
+        for problem in self.0.iter() {
+            write!(f, "\n  {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse the `-o` options.  Unrecognized options are passed to the kernel.  Every problem is
+/// reported at once, rather than only the first.
+pub fn parse(options: &[String]) -> Result<Parsed, OptionsError> {
+    let mut parsed = Parsed::default();
+    let mut problems = Vec::new();
+    let mut seen = HashMap::<&str, &str>::new();
+    for o in options {
+        let (name, value) = match o.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (o.as_str(), None),
+        };
+        let Some(spec) = SPECS.iter().find(|spec| spec.name == name) else {
+            parsed.mount.push(MountOption::CUSTOM(o.clone()));
+            continue;
+        };
+        let value = match (spec.value, value) {
+            (None, Some(_)) => {
+                problems.push(format!("{} takes no value", name));
+                continue;
+            }
+            (Some(_), None) => {
+                problems.push(format!("{} requires a value", name));
+                continue;
+            }
+            (_, value) => value.unwrap_or(""),
+        };
+        match seen.get(name) {
+            Some(prev) if *prev != value => {
+                problems.push(format!(
+                    "{} is given different values: {} and {}",
+                    name, prev, value
+                ));
+                continue;
+            }
+            // Repeating an option exactly is harmless
+            Some(_) => continue,
+            None => seen.insert(name, value),
+        };
+        if !(spec.apply)(&mut parsed, value) {
+            problems.push(format!("invalid {}: {}", name, value));
+        }
+    }
+    for (a, b) in CONFLICTS {
+        if seen.contains_key(a) && seen.contains_key(b) {
+            problems.push(format!("{} and {} are mutually exclusive", a, b));
+        }
+    }
+    if !problems.is_empty() {
+        return Err(OptionsError(problems));
+    }
+
+    parsed.vol.read_timeout = parsed.read_timeout.map(|timeout| ReadTimeout {
+        timeout,
+        retries: parsed.read_retries,
+    });
+    parsed.vol.readahead = parsed.readahead.unwrap_or(DEFAULT_READAHEAD);
+    Ok(parsed)
+}
+
+/// Describe every recognized mount option, one per line
+pub fn help() -> String {
+    let mut s = String::from("Mount options, for use with -o:\n");
+    for spec in SPECS {
+        let usage = match spec.value {
+            Some(value) => format!("{}={}", spec.name, value),
+            None => spec.name.to_owned(),
+        };
+        s.push_str(&format!("  {:<26}{}\n", usage, spec.help));
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    fn parse_strs(options: &[&str]) -> Result<Parsed, OptionsError> {
+        let options = options.iter().map(|o| o.to_string()).collect::<Vec<_>>();
+        parse(&options)
+    }
+
+    #[test]
+    fn ok() {
+        let Parsed {
+            mount, vol, dropto, ..
+        } = parse_strs(&[
+            "strict",
+            "hot_inodes=7",
+            "mem_limit=256M",
+            "read_timeout=1.5",
+            "read_retries=2",
+            "trace_inos=128:131",
+            "threads=4",
+            "readahead=256K",
+            "dropto=nobody",
+            "nosuid",
+            "nosuid",
+            "fsname=other",
+        ])
+        .unwrap();
+        assert_eq!(
+            mount,
+            vec![
+                MountOption::NoSuid,
+                MountOption::CUSTOM("fsname=other".to_string())
+            ]
+        );
+        assert!(vol.strict);
+        assert_eq!(vol.hot_inodes, Some(7));
+        assert_eq!(vol.mem_limit, Some(256 << 20));
+        let timeout = vol.read_timeout.unwrap();
+        assert_eq!(timeout.timeout, Duration::from_millis(1500));
+        assert_eq!(timeout.retries, 2);
+        assert_eq!(vol.trace_inos.len(), 2);
+        assert_eq!(vol.threads, 4);
+        assert_eq!(vol.readahead, 256 << 10);
+        assert_eq!(dropto.as_deref(), Some("nobody"));
+    }
+
+    #[rstest]
+    #[case::invalid_value(&["hot_inodes=abc"], "invalid hot_inodes: abc")]
+    #[case::zero_timeout(&["read_timeout=0"], "invalid read_timeout: 0")]
+    #[case::bad_suffix(&["mem_limit=1T"], "invalid mem_limit: 1T")]
+    #[case::huge(&["mem_limit=99999999999G"], "invalid mem_limit: 99999999999G")]
+    #[case::huge_readahead(&["readahead=4G"], "invalid readahead: 4G")]
+    #[case::bad_ino(&["trace_inos=128:x"], "invalid trace_inos: 128:x")]
+    #[case::missing_value(&["hot_inodes"], "hot_inodes requires a value")]
+    #[case::empty_user(&["dropto="], "invalid dropto: ")]
+    #[case::unexpected_value(&["strict=1"], "strict takes no value")]
+    #[case::different_values(
+        &["hot_inodes=1", "hot_inodes=2"],
+        "hot_inodes is given different values: 1 and 2"
+    )]
+    #[case::exclusive(&["strict", "skip_bad_entries"], "skip_bad_entries and strict are mutually exclusive")]
+    #[case::exclusive_kernel(&["nodev", "dev"], "dev and nodev are mutually exclusive")]
+    #[case::allow(&["allow_root", "allow_other"], "allow_other and allow_root are mutually exclusive")]
+    fn error(#[case] options: &[&str], #[case] expected: &str) {
+        assert_eq!(
+            parse_strs(options).unwrap_err(),
+            OptionsError(vec![expected.to_string()])
+        );
+    }
+
+    /// Readahead is on unless disabled
+    #[rstest]
+    #[case::default(&[], DEFAULT_READAHEAD)]
+    #[case::disabled(&["readahead=0"], 0)]
+    fn readahead(#[case] options: &[&str], #[case] expected: u32) {
+        assert_eq!(parse_strs(options).unwrap().vol.readahead, expected);
+    }
+
+    /// Every problem is reported, not just the first
+    #[test]
+    fn aggregate() {
+        let e = parse_strs(&["hot_inodes=abc", "sync", "read_retries=-1", "async"]).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "invalid mount options:\n  invalid hot_inodes: abc\n  invalid read_retries: -1\n  \
+             async and sync are mutually exclusive"
+        );
+    }
+
+    #[test]
+    fn conflicts_are_registered() {
+        for (a, b) in CONFLICTS {
+            assert!(SPECS.iter().any(|spec| spec.name == *a), "{}", a);
+            assert!(SPECS.iter().any(|spec| spec.name == *b), "{}", b);
+        }
+    }
+
+    #[test]
+    fn help_lists_every_option() {
+        let help = help();
+        for spec in SPECS {
+            assert!(
+                help.lines().any(|l| l.trim_start().starts_with(spec.name)),
+                "{} is missing from the help",
+                spec.name
+            );
+        }
+    }
+}
@@ -0,0 +1,247 @@
+//! Differential test of the two directory search paths: the hash-based lookup and the sequential
+//! readdir.
+//!
+//! For every directory in every golden image, every name that readdir returns must look up to the
+//! same inode, names that it doesn't return must not look up at all, and the number of
+//! subdirectories must agree with the directory's link count.  It uses the decoders directly,
+//! without FUSE, so it needs neither fusefs nor root.
+//!
+//! Every image is checked by this one process, even though their geometries differ, and each is
+//! checked twice: memory-mapped, and with sector-aligned reads.
+use std::{
+    collections::HashSet,
+    ffi::OsString,
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::{Path, PathBuf},
+    process,
+};
+
+// Test targets with harness = false are built with cfg(test), so the unit tests' helpers and
+// imports go unused.
+#[allow(dead_code, deprecated, unused_imports)]
+#[path = "../src/libxfuse/mod.rs"]
+mod libxfuse;
+#[allow(dead_code)]
+mod util;
+
+use libxfuse::{block_reader::BlockReader, dinode::Dinode, dir3::Dir3, sb::Sb, stat::FileType};
+use util::{
+    GOLDEN1K,
+    GOLDEN4K,
+    GOLDEN4KN,
+    GOLDENPREALLOCATED,
+    GOLDENV4,
+    GOLDENV4_ATTR1,
+    GOLDEN_NOFTYPE,
+};
+
+/// Names that are similar to `name`, but might not be present in its directory
+fn mutations(name: &[u8]) -> Vec<Vec<u8>> {
+    let mut muts = Vec::new();
+    if name.len() < 255 {
+        muts.push([name, b"~"].concat());
+    }
+    let mut flipped = name.to_vec();
+    let last = flipped.last_mut().unwrap();
+    *last ^= 1;
+    if *last != b'/' && *last != 0 {
+        muts.push(flipped);
+    }
+    if name.len() > 1 {
+        muts.push(name[..name.len() - 1].to_vec());
+    }
+    muts
+}
+
+struct Checker {
+    image:    PathBuf,
+    device:   BlockReader,
+    sb:       Sb,
+    failures: Vec<String>,
+}
+
+impl Checker {
+    fn fail(&mut self, path: &Path, msg: String) {
+        self.failures.push(format!(
+            "{}: {}: {}",
+            self.image.display(),
+            path.display(),
+            msg
+        ));
+    }
+
+    fn load(&mut self, ino: u64) -> Result<Dinode, i32> {
+        self.device.set_bufsize(self.sb.inode_size());
+        Dinode::from(&mut self.device, &self.sb, ino)
+    }
+
+    fn kind(&mut self, ino: u64) -> Result<FileType, i32> {
+        self.load(ino)?.di_core.stat(ino).map(|attr| attr.kind)
+    }
+
+    /// Check the directory `ino`, at `path`, and return its subdirectories
+    fn check_dir(&mut self, path: &Path, ino: u64) -> Vec<(PathBuf, u64)> {
+        let mut dinode = match self.load(ino) {
+            Ok(dinode) => dinode,
+            Err(e) => {
+                self.fail(path, format!("cannot read inode {}: errno {}", ino, e));
+                return Vec::new();
+            }
+        };
+        let nlink = dinode.di_core.stat(ino).map(|attr| attr.nlink).unwrap_or(0);
+        let dirsize = self.sb.sb_blocksize << self.sb.sb_dirblklog;
+        self.device.set_bufsize(dirsize as usize);
+        let dir = match dinode.get_dir(&mut self.device, &self.sb) {
+            Ok(dir) => dir,
+            Err(e) => {
+                self.fail(path, format!("cannot open directory: errno {}", e));
+                return Vec::new();
+            }
+        };
+
+        // Everything that readdir returns
+        let mut ents = Vec::new();
+        let mut offset = 0;
+        loop {
+            match dir.next(&mut self.device, &self.sb, offset) {
+                Ok((ent_ino, next, kind, name)) => {
+                    if next <= offset {
+                        self.fail(
+                            path,
+                            format!("entry {:?}'s offset {} isn't after {}", name, next, offset),
+                        );
+                        break;
+                    }
+                    ents.push((name, ent_ino, kind));
+                    offset = next;
+                }
+                Err(libc::ENOENT) => break,
+                Err(e) => {
+                    self.fail(path, format!("readdir at offset {}: errno {}", offset, e));
+                    break;
+                }
+            }
+        }
+
+        let mut names = HashSet::new();
+        for (name, _, _) in ents.iter() {
+            if !names.insert(name.as_bytes()) {
+                self.fail(path, format!("readdir returned {:?} twice", name));
+            }
+        }
+
+        // Every entry must look up to the same inode
+        for (name, ent_ino, _) in ents.iter() {
+            match dir.lookup(&mut self.device, &self.sb, name) {
+                Ok(found) if found == *ent_ino => (),
+                r => self.fail(
+                    path,
+                    format!(
+                        "entry {:?}: readdir says inode {}, but lookup says {:?}",
+                        name, ent_ino, r
+                    ),
+                ),
+            }
+        }
+
+        // And similar names that aren't entries must not
+        for (name, _, _) in ents.iter() {
+            for m in mutations(name.as_bytes()) {
+                if names.contains(&m[..]) || m == b"." || m == b".." {
+                    continue;
+                }
+                let m = OsString::from_vec(m);
+                match dir.lookup(&mut self.device, &self.sb, &m) {
+                    Err(libc::ENOENT) => (),
+                    r => self.fail(
+                        path,
+                        format!("entry {:?} isn't in readdir, but lookup says {:?}", m, r),
+                    ),
+                }
+            }
+        }
+
+        // A directory's link count is 2, plus one for every subdirectory's ".."
+        let mut subdirs = Vec::new();
+        for (name, ent_ino, kind) in ents {
+            if name == "." || name == ".." {
+                continue;
+            }
+            let kind = match kind.map_or_else(|| self.kind(ent_ino), Ok) {
+                Ok(kind) => kind,
+                Err(e) => {
+                    self.fail(path, format!("entry {:?}: errno {}", name, e));
+                    continue;
+                }
+            };
+            if kind == FileType::Directory {
+                subdirs.push((path.join(name), ent_ino));
+            }
+        }
+        if nlink as usize != subdirs.len() + 2 {
+            self.fail(
+                path,
+                format!(
+                    "link count is {}, but readdir found {} subdirectories",
+                    nlink,
+                    subdirs.len()
+                ),
+            );
+        }
+        subdirs
+    }
+}
+
+/// Check every directory of one image, and return the failures
+fn check_image(image: &Path, mapped: bool) -> Vec<String> {
+    let mut device = BlockReader::open(image).unwrap();
+    if !mapped {
+        device.unmap();
+    }
+    assert_eq!(device.is_mapped(), mapped);
+    let sb = Sb::from(&mut device).unwrap();
+    let mut checker = Checker {
+        image: image.to_owned(),
+        device,
+        sb,
+        failures: Vec::new(),
+    };
+    let mut visited = HashSet::new();
+    let mut pending = vec![(PathBuf::from("/"), checker.sb.sb_rootino)];
+    while let Some((path, ino)) = pending.pop() {
+        if visited.insert(ino) {
+            pending.extend(checker.check_dir(&path, ino));
+        }
+    }
+    checker.failures
+}
+
+fn main() {
+    let mut ok = true;
+    for image in [
+        &*GOLDEN1K,
+        &*GOLDEN4K,
+        &*GOLDEN4KN,
+        &*GOLDENPREALLOCATED,
+        &*GOLDENV4,
+        &*GOLDENV4_ATTR1,
+        &*GOLDEN_NOFTYPE,
+    ] {
+        for mapped in [true, false] {
+            let failures = check_image(image, mapped);
+            for failure in failures.iter() {
+                eprintln!("{}", failure);
+            }
+            println!(
+                "consistency {} ({}) ... {}",
+                image.file_name().unwrap().to_string_lossy(),
+                if mapped { "mmap" } else { "read" },
+                if failures.is_empty() { "ok" } else { "FAILED" }
+            );
+            ok &= failures.is_empty();
+        }
+    }
+    if !ok {
+        process::exit(1);
+    }
+}
@@ -6,13 +6,14 @@ use std::{
         fd::AsRawFd,
         unix::{
             ffi::{OsStrExt, OsStringExt},
-            fs::{DirEntryExt, FileExt, MetadataExt, OpenOptionsExt},
+            fs::{DirEntryExt, FileExt, FileTypeExt, MetadataExt, OpenOptionsExt, PermissionsExt},
+            net::UnixDatagram,
         },
     },
     path::{Path, PathBuf},
-    process::{Child, Command},
+    process::{Child, Command, Output, Stdio},
     thread::sleep,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use assert_cmd::cargo::CommandCargoExt;
@@ -26,7 +27,20 @@ use rstest_reuse::{self, apply, template};
 use tempfile::{tempdir, TempDir};
 
 mod util;
-use util::{waitfor, GOLDEN1K, GOLDEN4K, GOLDEN4KN, GOLDENPREALLOCATED, GOLDENV4, GOLDEN_NOFTYPE};
+use util::{
+    patch_inode,
+    set_crc,
+    waitfor,
+    GOLDEN1K,
+    GOLDEN4K,
+    GOLDEN4KN,
+    GOLDEN4K_OFFSET_LEAF,
+    GOLDEN64K,
+    GOLDENPREALLOCATED,
+    GOLDENV4,
+    GOLDENV4_ATTR1,
+    GOLDEN_NOFTYPE,
+};
 
 #[derive(Clone, Debug, Eq, PartialEq, PartialOrd, Ord)]
 struct ExpectedXattr {
@@ -60,6 +74,7 @@ fn local_attrs_per_file(f: &str) -> usize {
         "xattrs/btree3" => 0,
         "btree2.with-xattrs" => 1,
         "xattrs/extents4" => 0,
+        "xattrs/extents1024" => 1024,
         _ => unimplemented!(),
     }
 }
@@ -74,6 +89,7 @@ fn remote_attrs_per_file(f: &str) -> usize {
         "xattrs/btree3" => 512,
         "btree2.with-xattrs" => 0,
         "xattrs/extents4" => 16,
+        "xattrs/extents1024" => 0,
         _ => unimplemented!(),
     }
 }
@@ -109,10 +125,14 @@ fn ents_per_dir_shortnames(path: &Path, d: &str) -> usize {
         (_, "sf") => 2,
         ("xfs4096.img", "block") => 32,
         ("xfs4096.img", "leaf") => 384,
+        ("xfs4096_offset_leaf.img", "leaf") => 384,
         ("xfs4096.img", "node1") => 512,
         ("xfs4096.img", "all_name_lengths") => 255,
         ("xfsv4.img", "leaf") => 128,
         ("xfsv4.img", "node") => 512,
+        ("xfs65536.img", "block") => 1024,
+        ("xfs65536.img", "leaf") => 4096,
+        ("xfs65536.img", "node") => 16384,
         _ => unimplemented!(),
     }
 }
@@ -124,13 +144,17 @@ struct Harness {
 }
 
 fn harness(img: &Path) -> Harness {
+    harness_opts(img, &[])
+}
+
+/// Like [`harness`], but mount with the given `-o` options
+fn harness_opts(img: &Path, opts: &[&str]) -> Harness {
     let d = tempdir().unwrap();
-    let child = Command::cargo_bin("xfs-fuse")
-        .unwrap()
-        .arg(img)
-        .arg(d.path())
-        .spawn()
-        .unwrap();
+    let mut cmd = Command::cargo_bin("xfs-fuse").unwrap();
+    for o in opts {
+        cmd.arg("-o").arg(o);
+    }
+    let child = cmd.arg(img).arg(d.path()).spawn().unwrap();
 
     waitfor(Duration::from_secs(5), || {
         let s = nix::sys::statfs::statfs(d.path()).unwrap();
@@ -145,6 +169,85 @@ fn harness(img: &Path) -> Harness {
     }
 }
 
+/// Copy the 4k golden image, and turn /files/single_extent.txt into a sparse 5 GiB file whose
+/// only data block is at 4 GiB + 4096, by editing its inode's size and extent.  Adding such a file
+/// to mkimg.sh instead would disturb every test that counts inodes or free blocks.
+fn sparse_5g(d: &TempDir) -> PathBuf {
+    // single_extent.txt's inode number
+    const INO: u64 = 142539;
+    // Offsets of di_size and of the first extent record within the inode
+    const DI_SIZE: usize = 56;
+    const EXTENT: usize = 176;
+
+    let scratch = d.path().join("scratch.img");
+    fs::copy(GOLDEN4K.as_path(), &scratch).unwrap();
+    patch_inode(&scratch, INO, |inode| {
+        inode[DI_SIZE..][..8].copy_from_slice(&(5i64 << 30).to_be_bytes());
+        let rec = u128::from_be_bytes(inode[EXTENT..][..16].try_into().unwrap());
+        let startoff = ((4u128 << 30) + 4096) / 4096;
+        let rec = rec & !(((1u128 << 54) - 1) << 73) | startoff << 73;
+        inode[EXTENT..][..16].copy_from_slice(&rec.to_be_bytes());
+    });
+    scratch
+}
+
+/// Copy the preallocated golden image, and convert /files/preallocated from the extent list format
+/// to the btree format, by splitting its single unwritten extent into several and moving them
+/// into a bmap btree leaf in a free block.  mkfs.xfs only uses the btree format for files with
+/// many extents, which would need a larger image.
+fn preallocated_btree(d: &TempDir) -> PathBuf {
+    // preallocated's inode number
+    const INO: u64 = 11076;
+    // Offsets of di_format and di_nextents within the inode, and of its data fork
+    const DI_FORMAT: usize = 5;
+    const DI_NEXTENTS: usize = 76;
+    const DFORK: usize = 176;
+    // The root's pointers follow space for 20 keys, in a 512 byte inode with no attribute fork
+    const DFORK_PTRS: usize = DFORK + 4 + 20 * 8;
+    const XFS_DINODE_FMT_BTREE: u8 = 3;
+    // A free block for the leaf, and the extent that mkfs_preallocated created
+    const LEAF: u64 = 4000;
+    const STARTBLOCK: u128 = 1392;
+    const NRECS: u64 = 8;
+    const RECLEN: u128 = 2048 / NRECS as u128;
+
+    let scratch = d.path().join("scratch.img");
+    fs::copy(GOLDENPREALLOCATED.as_path(), &scratch).unwrap();
+    let f = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&scratch)
+        .unwrap();
+    let mut uuid = [0u8; 16];
+    f.read_exact_at(&mut uuid, 32).unwrap();
+
+    let mut leaf = vec![0u8; 4096];
+    leaf[0..4].copy_from_slice(b"BMA3");
+    leaf[6..8].copy_from_slice(&(NRECS as u16).to_be_bytes());
+    leaf[8..24].fill(0xff);
+    leaf[24..32].copy_from_slice(&(LEAF * 8).to_be_bytes());
+    leaf[40..56].copy_from_slice(&uuid);
+    leaf[56..64].copy_from_slice(&INO.to_be_bytes());
+    for i in 0..NRECS as u128 {
+        let rec = 1u128 << 127 | (i * RECLEN) << 73 | (STARTBLOCK + i * RECLEN) << 21 | RECLEN;
+        let ofs = 72 + 16 * i as usize;
+        leaf[ofs..ofs + 16].copy_from_slice(&rec.to_be_bytes());
+    }
+    f.write_all_at(&leaf, LEAF * 4096).unwrap();
+    drop(f);
+
+    patch_inode(&scratch, INO, |inode| {
+        let dfork = &mut inode[DFORK..];
+        dfork.fill(0);
+        dfork[0..4].copy_from_slice(&[0, 1, 0, 1]);
+        let ptrs = DFORK_PTRS - DFORK;
+        dfork[ptrs..ptrs + 8].copy_from_slice(&LEAF.to_be_bytes());
+        inode[DI_FORMAT] = XFS_DINODE_FMT_BTREE;
+        inode[DI_NEXTENTS..][..4].copy_from_slice(&(NRECS as u32).to_be_bytes());
+    });
+    scratch
+}
+
 #[fixture]
 fn harness1k() -> Harness {
     harness(GOLDEN1K.as_path())
@@ -155,11 +258,21 @@ fn harness4k() -> Harness {
     harness(GOLDEN4K.as_path())
 }
 
+#[fixture]
+fn harness4k_offset_leaf() -> Harness {
+    harness(GOLDEN4K_OFFSET_LEAF.as_path())
+}
+
 #[fixture]
 fn harness4kn() -> Harness {
     harness(GOLDEN4KN.as_path())
 }
 
+#[fixture]
+fn harness64k() -> Harness {
+    harness(GOLDEN64K.as_path())
+}
+
 #[fixture]
 fn harness_preallocated() -> Harness {
     harness(GOLDENPREALLOCATED.as_path())
@@ -170,6 +283,11 @@ fn harnessv4() -> Harness {
     harness(GOLDENV4.as_path())
 }
 
+#[fixture]
+fn harnessv4_attr1() -> Harness {
+    harness(GOLDENV4_ATTR1.as_path())
+}
+
 #[fixture]
 fn harness_noftype() -> Harness {
     harness(GOLDEN_NOFTYPE.as_path())
@@ -243,11 +361,16 @@ fn all_dir_types_longnames(h: fn() -> Harness, d: &str) {}
 #[case::sf(harness4k, "sf")]
 #[case::block(harness4k, "block")]
 #[case::leaf(harness4k, "leaf")]
+#[case::offset_leaf(harness4k_offset_leaf, "leaf")]
 #[case::v4_sf(harnessv4, "sf")]
 #[case::v4_leaf(harnessv4, "leaf")] // TODO check in xfs_db.  Might not be a leaf dir.
 #[case::v4_node(harnessv4, "node")]
 #[case::noftype_sf(harness_noftype, "sf")]
 #[case::fourkn_sf(harness4kn, "sf")]
+#[case::sixtyfourk_sf(harness64k, "sf")]
+#[case::sixtyfourk_block(harness64k, "block")]
+#[case::sixtyfourk_leaf(harness64k, "leaf")]
+#[case::sixtyfourk_node(harness64k, "node")]
 fn all_dir_types_shortnames(h: fn() -> Harness, d: &str) {}
 
 #[template]
@@ -260,8 +383,12 @@ fn all_dir_types_shortnames(h: fn() -> Harness, d: &str) {}
 #[case::btree2_with_xattrs(harness1k, "btree2.with-xattrs")]
 #[case::v4_local(harnessv4, "xattrs/local")]
 #[case::v4_extents(harnessv4, "xattrs/extents")]
+#[case::v4_attr1_local(harnessv4_attr1, "xattrs/local")]
+#[case::v4_attr1_extents(harnessv4_attr1, "xattrs/extents")]
 #[case::four4kn_local(harness4kn, "xattrs/local")]
 #[case::four4kn_extents(harness4kn, "xattrs/extents4")]
+#[case::sixtyfourk_local(harness64k, "xattrs/local")]
+#[case::sixtyfourk_extents(harness64k, "xattrs/extents1024")]
 fn all_xattr_fork_types(h: fn() -> Harness, d: &str) {}
 
 #[template]
@@ -274,6 +401,490 @@ fn all_xattr_fork_types(h: fn() -> Harness, d: &str) {}
 #[case::btree3(harness1k, "xattrs/btree3")]
 fn all_xattr_fork_types_with_none(h: fn() -> Harness, d: &str) {}
 
+/// Check allocation group headers without mounting
+/// With NOTIFY_SOCKET set, xfs-fuse should send READY=1 once the file system is mounted, and
+/// not before.
+#[named]
+#[test]
+fn notify_socket() {
+    require_fusefs!();
+
+    let sockdir = tempdir().unwrap();
+    let sockpath = sockdir.path().join("notify");
+    let sock = UnixDatagram::bind(&sockpath).unwrap();
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let d = tempdir().unwrap();
+    let child = Command::cargo_bin("xfs-fuse")
+        .unwrap()
+        .env("NOTIFY_SOCKET", &sockpath)
+        .arg(GOLDEN4K.as_path())
+        .arg(d.path())
+        .spawn()
+        .unwrap();
+    let mut buf = [0u8; 64];
+    let n = sock.recv(&mut buf).unwrap();
+    let s = nix::sys::statfs::statfs(d.path()).unwrap();
+    let h = Harness {
+        d,
+        child,
+        path: GOLDEN4K.to_path_buf(),
+    };
+    assert_eq!(&buf[..n], b"READY=1");
+    assert_eq!(s.filesystem_type_name(), "fusefs.xfs");
+    drop(h);
+}
+
+/// --pidfile should hold the daemon's pid while the file system is mounted
+#[named]
+#[test]
+fn pidfile() {
+    require_fusefs!();
+
+    let piddir = tempdir().unwrap();
+    let pidfile = piddir.path().join("xfs-fuse.pid");
+    let d = tempdir().unwrap();
+    // Under sudo, the daemon would switch users and be unable to remove the pidfile
+    let child = Command::cargo_bin("xfs-fuse")
+        .unwrap()
+        .env_remove("SUDO_UID")
+        .arg("--pidfile")
+        .arg(&pidfile)
+        .arg(GOLDEN4K.as_path())
+        .arg(d.path())
+        .spawn()
+        .unwrap();
+    waitfor(Duration::from_secs(5), || {
+        fs::read_to_string(&pidfile).is_ok_and(|pid| pid.ends_with('\n'))
+    })
+    .unwrap();
+    let s = nix::sys::statfs::statfs(d.path()).unwrap();
+    assert_eq!(s.filesystem_type_name(), "fusefs.xfs");
+    let pid = fs::read_to_string(&pidfile).unwrap();
+    assert_eq!(pid, format!("{}\n", child.id()));
+
+    let h = Harness {
+        d,
+        child,
+        path: GOLDEN4K.to_path_buf(),
+    };
+    drop(h);
+    assert!(!pidfile.exists());
+}
+
+/// When started as root, the daemon should switch to the dropto user once mounted, and still
+/// serve files through the device that it opened as root.
+#[named]
+#[test]
+fn dropto() {
+    require_fusefs!();
+    require_root!();
+
+    let nobody = nix::unistd::User::from_name("nobody").unwrap().unwrap();
+    let h = harness_opts(GOLDEN4K.as_path(), &["dropto=nobody"]);
+    let pid = h.child.id().to_string();
+    let uids = || {
+        let output = Command::new("ps")
+            .args(["-o", "uid=,ruid=,gid=", "-p", &pid])
+            .output()
+            .unwrap();
+        String::from_utf8(output.stdout)
+            .unwrap()
+            .split_whitespace()
+            .map(|id| id.parse::<u32>().unwrap())
+            .collect::<Vec<_>>()
+    };
+    let expected = vec![
+        nobody.uid.as_raw(),
+        nobody.uid.as_raw(),
+        nobody.gid.as_raw(),
+    ];
+    waitfor(Duration::from_secs(5), || uids() == expected).unwrap();
+
+    let data = fs::read(h.d.path().join("files").join("single_extent.txt")).unwrap();
+    assert_eq!(data.len(), 4096);
+}
+
+/// A device that the user may not read should get an explanation and a distinct exit status
+#[named]
+#[test]
+fn device_eacces() {
+    if nix::unistd::Uid::current().is_root() {
+        skip!(
+            "{} requires an unprivileged user.  Skipping test.",
+            function_name!()
+        );
+    }
+
+    let d = tempdir().unwrap();
+    let device = d.path().join("device");
+    fs::copy(GOLDEN4K.as_path(), &device).unwrap();
+    fs::set_permissions(&device, fs::Permissions::from_mode(0o000)).unwrap();
+    let output = Command::cargo_bin("xfs-fuse")
+        .unwrap()
+        .arg(&device)
+        .arg(d.path())
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(77));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains(&device.display().to_string()), "{}", stderr);
+    assert!(stderr.contains("permission denied"), "{}", stderr);
+    assert!(stderr.contains("\"operator\" group"), "{}", stderr);
+}
+
+/// So should a mistyped device path
+#[test]
+fn device_enoent() {
+    let d = tempdir().unwrap();
+    let device = d.path().join("nonexistent");
+    let output = Command::cargo_bin("xfs-fuse")
+        .unwrap()
+        .arg(&device)
+        .arg(d.path())
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(66));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains(&device.display().to_string()), "{}", stderr);
+    assert!(stderr.contains("no such file or device"), "{}", stderr);
+}
+
+mod auditlog {
+    use super::*;
+
+    /// The op, path, and attribute name of each record in the log
+    fn records(log: &Path) -> Vec<(String, String, String)> {
+        fs::read_to_string(log)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let fields = line.split('\t').collect::<Vec<_>>();
+                assert_eq!(fields.len(), 8, "{}", line);
+                (
+                    fields[1].to_string(),
+                    fields[3].to_string(),
+                    fields[4].to_string(),
+                )
+            })
+            .collect()
+    }
+
+    /// Files and attributes that were accessed are logged in order, and untouched files aren't
+    #[named]
+    #[test]
+    fn accesses() {
+        require_fusefs!();
+
+        let d = tempdir().unwrap();
+        let log = d.path().join("audit.log");
+        let opt = format!("auditlog={}", log.display());
+        let h = harness_opts(GOLDEN4K.as_path(), &[opt.as_str()]);
+        assert_eq!(
+            fs::read(h.d.path().join("files/hello.txt")).unwrap(),
+            b"Hello, World!\n"
+        );
+        let local = h.d.path().join("xattrs/local");
+        xattr::get(&local, "user.attr.000000").unwrap().unwrap();
+        xattr::list(&local).unwrap().count();
+        drop(h);
+
+        let records = records(&log);
+        let expected = [
+            ("LOOKUP", "/files/hello.txt", "-"),
+            ("READ", "/files/hello.txt", "-"),
+            ("LOOKUP", "/xattrs/local", "-"),
+            ("GETXATTR", "/xattrs/local", "user.attr.000000"),
+            ("LISTXATTR", "/xattrs/local", "-"),
+        ];
+        let mut it = records.iter();
+        for (op, path, attr) in expected {
+            assert!(
+                it.any(|r| r.0 == op && r.1 == path && r.2 == attr),
+                "{} {} {} missing or out of order in {:?}",
+                op,
+                path,
+                attr,
+                records
+            );
+        }
+        assert!(
+            !records
+                .iter()
+                .any(|r| r.1.contains("hello2.txt") || r.1.contains("single_extent")),
+            "{:?}",
+            records
+        );
+    }
+}
+
+/// Map file blocks to device blocks with FIOBMAP2, which fusefs implements with FUSE_BMAP
+#[cfg(target_os = "freebsd")]
+mod bmap {
+    use super::*;
+
+    #[repr(C)]
+    struct Fiobmap2Arg {
+        bn:   i64,
+        runp: libc::c_int,
+        runb: libc::c_int,
+    }
+
+    nix::ioctl_readwrite!(fiobmap2, b'f', 99, Fiobmap2Arg);
+
+    /// The device address, in 512-byte units, of each of a file's blocks, in units of the file
+    /// system's I/O size
+    fn daddrs(path: &Path) -> (Vec<i64>, i64) {
+        let f = fs::File::open(path).unwrap();
+        let iosize = nix::sys::statfs::fstatfs(&f)
+            .unwrap()
+            .optimal_transfer_size() as i64;
+        let nblocks = f.metadata().unwrap().size().div_ceil(iosize as u64) as i64;
+        let daddrs = (0..nblocks)
+            .map(|bn| {
+                let mut arg = Fiobmap2Arg {
+                    bn,
+                    runp: 0,
+                    runb: 0,
+                };
+                unsafe { fiobmap2(f.as_raw_fd(), &mut arg) }.unwrap();
+                arg.bn
+            })
+            .collect();
+        (daddrs, iosize)
+    }
+
+    /// Every block is mapped, and they form four separate runs on the device
+    #[named]
+    #[rstest]
+    fn four_extents(harness4k: Harness) {
+        require_fusefs!();
+
+        let path = harness4k.d.path().join("files/four_extents.txt");
+        let (daddrs, iosize) = daddrs(&path);
+        assert_eq!(iosize, 4096);
+        assert_eq!(daddrs.len(), 4);
+        assert!(daddrs.iter().all(|daddr| *daddr > 0), "{:?}", daddrs);
+        let runs = 1 + daddrs
+            .windows(2)
+            .filter(|w| w[1] != w[0] + iosize / 512)
+            .count();
+        assert_eq!(runs, 4, "{:?}", daddrs);
+    }
+
+    /// Holes map to 0
+    #[named]
+    #[rstest]
+    fn sparse(harness4k: Harness) {
+        require_fusefs!();
+
+        let path = harness4k.d.path().join("files/sparse.extents.txt");
+        let (daddrs, iosize) = daddrs(&path);
+        assert_eq!(iosize, 4096);
+        assert_eq!(daddrs.len(), 4);
+        assert_eq!(daddrs[0], 0, "{:?}", daddrs);
+        assert!(daddrs[1] > 0, "{:?}", daddrs);
+        assert_eq!(daddrs[2], 0, "{:?}", daddrs);
+        assert!(daddrs[3] > 0, "{:?}", daddrs);
+    }
+}
+
+/// Print one file or attribute without mounting
+mod cat {
+    use super::*;
+
+    fn cat(img: &Path, path: &str, xattr: Option<&str>) -> Output {
+        let mut cmd = Command::cargo_bin("xfs-fuse").unwrap();
+        cmd.arg("--cat").arg(path);
+        if let Some(name) = xattr {
+            cmd.arg("--xattr").arg(name);
+        }
+        cmd.arg(img).output().unwrap()
+    }
+
+    /// Every 16 bytes of the golden files' data are their own offset, in hex
+    #[rstest]
+    #[case::extents(GOLDEN4K.as_path(), "four_extents.txt", 16384)]
+    #[case::btree(GOLDEN4K.as_path(), "btree3.txt", 16777216)]
+    #[case::btree_1k(GOLDEN1K.as_path(), "btree3.3.txt", 8388608)]
+    #[case::partial(GOLDEN4K.as_path(), "partial_extent.txt", 8448)]
+    fn file(#[case] img: &Path, #[case] filename: &str, #[case] size: usize) {
+        let output = cat(img, &format!("/files/{}", filename), None);
+        assert!(output.status.success());
+        assert_eq!(output.stdout.len(), size);
+        for (i, chunk) in output.stdout.chunks(16).enumerate() {
+            assert_eq!(chunk, format!("{:016x}", i * 16).as_bytes());
+        }
+    }
+
+    /// Holes are printed as zeros
+    #[rstest]
+    #[case::extents("sparse.extents.txt", 16384)]
+    #[case::btree("sparse.btree.txt", 65536)]
+    fn sparse(#[case] filename: &str, #[case] size: usize) {
+        let output = cat(&GOLDEN4K, &format!("/files/{}", filename), None);
+        assert!(output.status.success());
+        assert_eq!(output.stdout.len(), size);
+        // The first block is a hole, and the second isn't
+        assert_eq!(output.stdout[..4096], [0u8; 4096]);
+        assert_eq!(&output.stdout[4096..4112], b"0000000000001000");
+        for (i, chunk) in output.stdout.chunks(16).enumerate() {
+            assert!(chunk == [0u8; 16] || chunk == format!("{:016x}", i * 16).as_bytes());
+        }
+    }
+
+    #[rstest]
+    #[case::local(GOLDEN4K.as_path(), "local", "user.attr.000003", "value.000003")]
+    #[case::remote(GOLDEN1K.as_path(), "btree3", "user.remote_attr.000511", ".000511")]
+    fn xattr(#[case] img: &Path, #[case] f: &str, #[case] name: &str, #[case] suffix: &str) {
+        let output = cat(img, &format!("/xattrs/{}", f), Some(name));
+        assert!(output.status.success());
+        let value = String::from_utf8(output.stdout).unwrap();
+        assert!(value.ends_with(suffix), "{}", value);
+    }
+
+    #[rstest]
+    #[case::enoent("/files/nonexistent", None, "No such file or directory")]
+    #[case::enotdir("/files/hello.txt/x", None, "Not a directory")]
+    #[case::eisdir("/files", None, "Is a directory")]
+    #[case::fifo("/files/fifo", None, "not a regular file")]
+    #[case::enoattr("/files/hello.txt", Some("user.nonexistent"), "")]
+    fn error(#[case] path: &str, #[case] xattr: Option<&str>, #[case] msg: &str) {
+        let output = cat(&GOLDEN4K, path, xattr);
+        assert_eq!(output.status.code(), Some(1));
+        assert!(output.stdout.is_empty());
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(
+            stderr.starts_with(&format!("xfs-fuse: {}: {}", path, msg)),
+            "{}",
+            stderr
+        );
+    }
+}
+
+mod check {
+    use super::*;
+
+    /// Copy the 4k golden image and overwrite the first byte of `agno`'s AGF
+    fn corrupt_agf(d: &TempDir, agno: u64) -> PathBuf {
+        let scratch = d.path().join("scratch.img");
+        fs::copy(GOLDEN4K.as_path(), &scratch).unwrap();
+        let f = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&scratch)
+            .unwrap();
+        let mut sb = [0u8; 512];
+        f.read_exact_at(&mut sb, 0).unwrap();
+        let blocksize = u32::from_be_bytes(sb[4..8].try_into().unwrap());
+        let agblocks = u32::from_be_bytes(sb[84..88].try_into().unwrap());
+        let sectsize = u16::from_be_bytes(sb[102..104].try_into().unwrap());
+        let agf = agno * u64::from(agblocks) * u64::from(blocksize) + u64::from(sectsize);
+        f.write_all_at(&[0xff], agf).unwrap();
+        scratch
+    }
+
+    #[test]
+    fn healthy() {
+        let output = Command::cargo_bin("xfs-fuse")
+            .unwrap()
+            .arg("--check")
+            .arg(GOLDEN4K.as_path())
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            concat!(
+                "{\"agcount\":4,\"unit\":0,\"width\":0,",
+                "\"ro_compat\":[\"finobt\",\"reflink\",\"inobtcount\"],\"ags\":[",
+                "{\"agno\":0,\"ok\":true,\"problems\":[]},",
+                "{\"agno\":1,\"ok\":true,\"problems\":[]},",
+                "{\"agno\":2,\"ok\":true,\"problems\":[]},",
+                "{\"agno\":3,\"ok\":true,\"problems\":[]}]}\n"
+            )
+        );
+    }
+
+    /// --check should flag exactly the damaged AG, and files in other AGs should still be
+    /// readable.
+    #[named]
+    #[test]
+    fn damaged_agf() {
+        require_fusefs!();
+
+        let d = tempdir().unwrap();
+        let scratch = corrupt_agf(&d, 1);
+        let output = Command::cargo_bin("xfs-fuse")
+            .unwrap()
+            .arg("--check")
+            .arg(&scratch)
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(1));
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert!(stdout.contains("{\"agno\":0,\"ok\":true,"), "{}", stdout);
+        assert!(
+            stdout.contains("{\"agno\":1,\"ok\":false,\"problems\":[\"AGF: bad magic"),
+            "{}",
+            stdout
+        );
+        assert!(stdout.contains("{\"agno\":2,\"ok\":true,"), "{}", stdout);
+        assert!(stdout.contains("{\"agno\":3,\"ok\":true,"), "{}", stdout);
+
+        // /files is in AG 2
+        let h = harness(&scratch);
+        let data = fs::read(h.d.path().join("files").join("single_extent.txt")).unwrap();
+        assert_eq!(data.len(), 4096);
+    }
+
+    /// -o check_ags should refuse to mount if the root directory's AG is damaged
+    #[test]
+    fn check_ags_root() {
+        let d = tempdir().unwrap();
+        let scratch = corrupt_agf(&d, 0);
+        let mnt = tempdir().unwrap();
+        let output = Command::cargo_bin("xfs-fuse")
+            .unwrap()
+            .arg("-o")
+            .arg("check_ags")
+            .arg(&scratch)
+            .arg(mnt.path())
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(1));
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.contains("AG 0 is damaged"), "{}", stderr);
+    }
+
+    /// A superblock whose checksum doesn't match should be reported, not panic
+    #[test]
+    fn damaged_sb() {
+        let d = tempdir().unwrap();
+        let scratch = d.path().join("scratch.img");
+        fs::copy(GOLDEN4K.as_path(), &scratch).unwrap();
+        let f = fs::OpenOptions::new().write(true).open(&scratch).unwrap();
+        // sb_dblocks
+        f.write_all_at(&[0xff], 8).unwrap();
+        let mnt = tempdir().unwrap();
+        let output = Command::cargo_bin("xfs-fuse")
+            .unwrap()
+            .arg(&scratch)
+            .arg(mnt.path())
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(1));
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(
+            stderr.starts_with(&format!(
+                "xfs-fuse: {}: superblock checksum is invalid: stored 0x",
+                scratch.display()
+            )),
+            "{}",
+            stderr
+        );
+    }
+}
+
 mod close {
     use super::*;
 
@@ -346,12 +957,12 @@ mod dev {
         }
     }
 
-    /// Read all metadata from the file system to verify read alignment.
+    /// Read all metadata and data from the file system to verify read alignment.
     // Regression test for https://github.com/KhaledEmaraDev/xfuse/issues/15
-    // TODO: Read all data as well as metadata
     #[named]
     #[rstest]
     #[case::fourk(GOLDEN4K.as_path(), 512)]
+    #[case::fourk_4k_sectors(GOLDEN4K.as_path(), 4096)]
     #[case::fourkn(GOLDEN4KN.as_path(), 4096)]
     #[case::onek(GOLDEN1K.as_path(), 512)]
     #[case::v4(GOLDENV4.as_path(), 512)]
@@ -365,7 +976,10 @@ mod dev {
         let walker = walkdir::WalkDir::new(h.d.path()).into_iter();
         for entry in walker {
             let entry = entry.unwrap();
-            let _ = entry.metadata().unwrap();
+            let md = entry.metadata().unwrap();
+            if md.is_file() {
+                fs::read(entry.path()).unwrap();
+            }
             // xattrs are not supported on fifo devices.  This limitation it
             // outside of fusefs.
             if entry.path().file_name() != Some(OsStr::new("fifo")) {
@@ -376,6 +990,32 @@ mod dev {
         }
     }
 
+    /// A device whose sectors are larger than the file system's blocks can't be mounted
+    #[named]
+    #[test]
+    fn sector_larger_than_block() {
+        require_root!();
+
+        let md = mdconfig::Builder::vnode(GOLDEN1K.as_path())
+            .sectorsize(4096)
+            .create()
+            .unwrap();
+        let d = tempdir().unwrap();
+        let output = Command::cargo_bin("xfs-fuse")
+            .unwrap()
+            .arg(md.path())
+            .arg(d.path())
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(1));
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(
+            stderr.contains("sectors are larger than the file system's 1024-byte blocks"),
+            "{}",
+            stderr
+        );
+    }
+
     #[named]
     #[rstest]
     #[case::large_extent(GOLDEN4K.as_path(), 512, "large_extent.txt", 8448)]
@@ -443,6 +1083,242 @@ mod dev {
 }
 
 // TODO: xattr test on V4 file system
+mod dump {
+    use super::*;
+
+    fn dump(inode: Option<u64>) -> Output {
+        let mut cmd = Command::cargo_bin("xfs-fuse").unwrap();
+        cmd.arg("--dump");
+        if let Some(ino) = inode {
+            cmd.arg("--inode").arg(ino.to_string());
+        }
+        cmd.arg(GOLDEN4K.as_path()).output().unwrap()
+    }
+
+    #[test]
+    fn superblock() {
+        let output = dump(None);
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert!(
+            stdout
+                .starts_with("{\"superblock\":{\"uuid\":\"73315898-4fd6-4811-8821-741ec5375348\""),
+            "{}",
+            stdout
+        );
+        assert!(stdout.contains(",\"blocksize\":4096,"), "{}", stdout);
+        assert!(stdout.contains(",\"agcount\":4,"), "{}", stdout);
+        assert!(!stdout.contains("\"inode\""), "{}", stdout);
+    }
+
+    /// /files/four_extents.txt
+    #[test]
+    fn extents() {
+        let output = dump(Some(142540));
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert!(
+            stdout.contains(concat!(
+                ",\"inode\":{\"ino\":142540,\"mode\":33188,\"version\":3,",
+                "\"uid\":0,\"gid\":0,\"nlink\":1,\"size\":16384,\"nblocks\":4,",
+                "\"nextents\":4,"
+            )),
+            "{}",
+            stdout
+        );
+        assert!(
+            stdout.contains(concat!(
+                "\"data\":{\"format\":\"extents\",\"extents\":[",
+                "{\"offset\":0,\"block\":17826,\"count\":1,\"unwritten\":false},"
+            )),
+            "{}",
+            stdout
+        );
+        assert!(stdout.ends_with("}}\n"), "{}", stdout);
+    }
+
+    /// /files/btree3.txt has a two-level btree, with one record in its root
+    #[test]
+    fn btree() {
+        let output = dump(Some(142543));
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert!(
+            stdout.contains(concat!(
+                "\"data\":{\"format\":\"btree\",\"level\":2,\"numrecs\":1,",
+                "\"keys\":[0],\"ptrs\":[21865]}"
+            )),
+            "{}",
+            stdout
+        );
+    }
+
+    /// A free inode has nothing to print
+    #[test]
+    fn free() {
+        let output = dump(Some(142552));
+        assert_eq!(output.status.code(), Some(1));
+        assert!(output.stdout.is_empty());
+        assert_eq!(
+            String::from_utf8(output.stderr).unwrap(),
+            "xfs-fuse: inode 142552 is free\n"
+        );
+    }
+}
+
+mod extract {
+    use super::*;
+
+    fn extract(src: &str, dest: &Path) -> Output {
+        Command::cargo_bin("xfs-fuse")
+            .unwrap()
+            .arg("--extract")
+            .arg(src)
+            .arg("--dest")
+            .arg(dest)
+            .arg(GOLDEN4K.as_path())
+            .output()
+            .unwrap()
+    }
+
+    /// The modification time that --list reports for this path in the golden image
+    fn listed_mtime(path: &str) -> i64 {
+        let mut listing = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        listing.push("resources");
+        listing.push("xfs4096.list.json");
+        let prefix = format!("{{\"path\":\"{}\",", path);
+        let line = fs::read_to_string(listing)
+            .unwrap()
+            .lines()
+            .find(|l| l.starts_with(&prefix))
+            .unwrap()
+            .to_owned();
+        let mtime = &line[line.find("\"mtime\":").unwrap() + 8..];
+        mtime[..mtime.find(',').unwrap()].parse().unwrap()
+    }
+
+    /// Every 16 bytes of the golden files' data are their own offset, in hex, except in holes.
+    /// Holes must stay holes.
+    #[rstest]
+    #[case::extents("four_extents.txt", 16384, false)]
+    #[case::btree("btree3.txt", 16777216, false)]
+    #[case::partial("partial_extent.txt", 8448, false)]
+    #[case::sparse_extents("sparse.extents.txt", 16384, true)]
+    #[case::sparse_btree("sparse.btree.txt", 65536, true)]
+    #[case::hole_at_end("hole_at_end.extents.txt", 20480, true)]
+    fn files(#[case] filename: &str, #[case] size: usize, #[case] sparse: bool) {
+        let d = tempdir().unwrap();
+        let output = extract("/files", d.path());
+        assert!(output.status.success());
+
+        let path = d.path().join("files").join(filename);
+        let data = fs::read(&path).unwrap();
+        assert_eq!(data.len(), size);
+        for (i, chunk) in data.chunks(16).enumerate() {
+            assert!(
+                (sparse && chunk == [0u8; 16]) || chunk == format!("{:016x}", i * 16).as_bytes(),
+                "{}: offset {}",
+                filename,
+                i * 16
+            );
+        }
+        let md = fs::metadata(&path).unwrap();
+        assert_eq!(
+            md.blocks() * 512 < md.size(),
+            sparse,
+            "{} blocks",
+            md.blocks()
+        );
+        assert_eq!(md.mtime(), listed_mtime(&format!("/files/{}", filename)));
+    }
+
+    /// Permissions, timestamps, and hard links are preserved
+    #[test]
+    fn metadata() {
+        let d = tempdir().unwrap();
+        let output = extract("/files", d.path());
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert!(stdout.starts_with("{\"directories\":1,"), "{}", stdout);
+        assert!(stdout.ends_with(",\"errors\":0}\n"), "{}", stdout);
+
+        let files = d.path().join("files");
+        let hello = fs::metadata(files.join("hello.txt")).unwrap();
+        assert_eq!(hello.mode() & 0o7777, 0o1234);
+        assert_eq!(hello.mtime(), listed_mtime("/files/hello.txt"));
+        assert_eq!(hello.nlink(), 2);
+        assert_eq!(
+            fs::metadata(files.join("hello2.txt")).unwrap().ino(),
+            hello.ino()
+        );
+        assert_eq!(
+            fs::metadata(files.join("executable")).unwrap().mode() & 0o111,
+            0o111
+        );
+        // A directory's timestamps are set after its entries are created
+        assert_eq!(
+            fs::metadata(&files).unwrap().mtime(),
+            listed_mtime("/files")
+        );
+        // Devices are only created with --devices
+        assert!(!files.join("blockdev").exists());
+        assert!(fs::symlink_metadata(files.join("fifo"))
+            .unwrap()
+            .file_type()
+            .is_fifo());
+    }
+
+    #[rstest]
+    #[case::local("local")]
+    #[case::extents("extents")]
+    fn xattrs(#[case] filename: &str) {
+        let d = tempdir().unwrap();
+        let output = extract("/xattrs", d.path());
+        assert!(output.status.success());
+
+        let path = d.path().join("xattrs").join(filename);
+        let mut names = xattr::list(&path).unwrap().collect::<Vec<_>>();
+        names.sort_unstable();
+        let expected =
+            expected_xattrs_per_file(&format!("xattrs/{}", filename)).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            expected.iter().map(|a| a.name.clone()).collect::<Vec<_>>()
+        );
+        for attr in expected {
+            let value = xattr::get(&path, &attr.name).unwrap().unwrap();
+            assert_eq!(OsStr::from_bytes(&value), attr.value);
+        }
+    }
+
+    /// Nothing that already exists is replaced
+    #[test]
+    fn collision() {
+        let d = tempdir().unwrap();
+        fs::create_dir(d.path().join("links")).unwrap();
+        let output = extract("/links", d.path());
+        assert_eq!(output.status.code(), Some(65));
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.contains("/links: File exists"), "{}", stderr);
+        assert_eq!(fs::read_dir(d.path().join("links")).unwrap().count(), 0);
+    }
+
+    #[rstest]
+    #[case::enoent("/files/nonexistent", "No such file or directory")]
+    #[case::enotdir("/files/hello.txt/x", "Not a directory")]
+    fn error(#[case] path: &str, #[case] msg: &str) {
+        let d = tempdir().unwrap();
+        let output = extract(path, d.path());
+        assert_eq!(output.status.code(), Some(1));
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(
+            stderr.starts_with(&format!("xfs-fuse: {}: {}", path, msg)),
+            "{}",
+            stderr
+        );
+    }
+}
+
 mod getextattr {
     use super::*;
 
@@ -461,7 +1337,39 @@ mod getextattr {
         }
     }
 
-    /// Try to get the value of an extended attribute that doesn't exist.
+    /// The layout attribute marks the extents, or parts of them, that a file shares with its
+    /// reflinked copies.  reflink_partial.txt's first, third, and fourth blocks were
+    /// overwritten, so only its second still is.
+    #[named]
+    #[rstest]
+    #[case::reflinked("reflink_a.txt", &[true])]
+    #[case::partial("reflink_partial.txt", &[false, true, false])]
+    #[case::unshared("single_extent.txt", &[false])]
+    fn layout(harness4k: Harness, #[case] name: &str, #[case] shared: &[bool]) {
+        require_fusefs!();
+
+        let p = harness4k.d.path().join("files").join(name);
+        let v = xattr::get(p, "user.xfuse.layout").unwrap().unwrap();
+        let layout = String::from_utf8(v).unwrap();
+        assert!(layout.starts_with("{\"blocksize\":4096,"), "{}", layout);
+        let flags = layout
+            .match_indices("\"shared\":")
+            .map(|(i, _)| layout[i + 9..].starts_with("true"))
+            .collect::<Vec<_>>();
+        assert_eq!(flags, shared, "{}", layout);
+    }
+
+    /// Only regular files have a layout attribute
+    #[named]
+    #[rstest]
+    fn layout_dir(harness4k: Harness) {
+        require_fusefs!();
+
+        let p = harness4k.d.path().join("files");
+        assert_eq!(xattr::get(p, "user.xfuse.layout").unwrap(), None);
+    }
+
+    /// Try to get the value of an extended attribute that doesn't exist.
     // This test is freebsd-specific because the relevant syscall is.  It could
     // be implemented for Linux too, but I haven't done so.
     #[cfg(target_os = "freebsd")]
@@ -577,6 +1485,7 @@ fn hardlink(harness4k: Harness) {
 #[case::onek(harness1k)]
 #[case::v4(harnessv4)]
 #[case::fourkn(harness4kn)]
+#[case::sixtyfourk(harness64k)]
 fn mount(#[case] h: fn() -> Harness) {
     require_fusefs!();
 
@@ -584,9 +1493,490 @@ fn mount(#[case] h: fn() -> Harness) {
     drop(harness);
 }
 
+/// An image whose root inode isn't a directory must fail to mount, rather than mounting and then
+/// failing the first readdir.
+#[named]
+#[test]
+fn root_not_dir() {
+    require_fusefs!();
+
+    // The root's inode number, and the offset of di_mode within the inode
+    const ROOT_INO: u64 = 128;
+    const DI_MODE: usize = 2;
+
+    let d = tempdir().unwrap();
+    let img = d.path().join("root_not_dir.img");
+    fs::copy(GOLDEN4K.as_path(), &img).unwrap();
+    patch_inode(&img, ROOT_INO, |inode| {
+        inode[DI_MODE..][..2].copy_from_slice(&0o100644u16.to_be_bytes());
+    });
+
+    let mnt = d.path().join("mnt");
+    fs::create_dir(&mnt).unwrap();
+    let output = Command::cargo_bin("xfs-fuse")
+        .unwrap()
+        .arg(&img)
+        .arg(&mnt)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("the root inode is not a directory (mode 0o100644)"),
+        "{}",
+        stderr
+    );
+    assert_eq!(
+        fs::metadata(&mnt).unwrap().dev(),
+        fs::metadata(d.path()).unwrap().dev(),
+        "Mountpoint was left mounted"
+    );
+}
+
+/// The negotiated FUSE capabilities should be logged exactly once, before any other operation
+#[named]
+#[test]
+fn init_capabilities() {
+    require_fusefs!();
+
+    let d = tempdir().unwrap();
+    let child = Command::cargo_bin("xfs-fuse")
+        .unwrap()
+        .env("RUST_LOG", "fuser=debug,xfs_fuse=info")
+        .arg(GOLDEN4K.as_path())
+        .arg(d.path())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    waitfor(Duration::from_secs(5), || {
+        let s = nix::sys::statfs::statfs(d.path()).unwrap();
+        s.filesystem_type_name() == "fusefs.xfs"
+    })
+    .unwrap();
+    fs::metadata(d.path().join("sf")).unwrap();
+    let status = Command::new("umount").arg(d.path()).status().unwrap();
+    assert!(status.success());
+    let output = child.wait_with_output().unwrap();
+    let log = String::from_utf8_lossy(&output.stderr);
+
+    let negotiated = log
+        .lines()
+        .enumerate()
+        .filter(|(_, l)| l.contains("Negotiated FUSE capabilities"))
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+    assert_eq!(negotiated.len(), 1, "{}", log);
+    let first_op = log
+        .lines()
+        .position(|l| l.contains("FUSE(") && !l.contains(" INIT "))
+        .unwrap();
+    assert!(negotiated[0] < first_op, "{}", log);
+    assert!(!log.contains("before init"), "{}", log);
+}
+
+/// After unmounting a file system whose entire tree was cached, the daemon should tear down its
+/// caches and exit promptly, logging each stage.
+#[named]
+#[test]
+fn teardown() {
+    require_fusefs!();
+
+    let d = tempdir().unwrap();
+    let mut child = Command::cargo_bin("xfs-fuse")
+        .unwrap()
+        .env("RUST_LOG", "xfs_fuse=info,xfs_fuse::libxfuse::volume=debug")
+        .arg(GOLDEN1K.as_path())
+        .arg(d.path())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    waitfor(Duration::from_secs(5), || {
+        let s = nix::sys::statfs::statfs(d.path()).unwrap();
+        s.filesystem_type_name() == "fusefs.xfs"
+    })
+    .unwrap();
+    for entry in walkdir::WalkDir::new(d.path()) {
+        entry.unwrap().metadata().unwrap();
+    }
+    let status = Command::new("umount").arg(d.path()).status().unwrap();
+    assert!(status.success());
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while child.try_wait().unwrap().is_none() {
+        assert!(
+            Instant::now() < deadline,
+            "Daemon did not exit after unmount"
+        );
+        sleep(Duration::from_millis(50));
+    }
+    let output = child.wait_with_output().unwrap();
+    let log = String::from_utf8_lossy(&output.stderr);
+
+    let stages = [
+        "Tearing down",
+        "Teardown: dropped",
+        "attribute forks",
+        "open files",
+        "Teardown complete",
+    ]
+    .map(|stage| {
+        log.find(stage)
+            .unwrap_or_else(|| panic!("{:?} not logged:\n{}", stage, log))
+    });
+    assert!(stages.windows(2).all(|w| w[0] < w[1]), "{}", log);
+}
+
+/// Operations on traced inodes should be logged with the device offsets that they read, and
+/// operations on other inodes should not.
+#[named]
+#[test]
+fn trace_inos() {
+    require_fusefs!();
+
+    // Inode numbers of /files/single_extent.txt and /files/four_extents.txt
+    const TRACED: u64 = 142539;
+    const UNTRACED: u64 = 142540;
+    // Location of single_extent.txt's data on disk
+    const TRACED_OFFSET: u64 = 56360960;
+
+    let d = tempdir().unwrap();
+    let child = Command::cargo_bin("xfs-fuse")
+        .unwrap()
+        .env("RUST_LOG", "xfs_fuse=info")
+        .arg("-o")
+        .arg(format!("trace_inos={}", TRACED))
+        .arg(GOLDEN4K.as_path())
+        .arg(d.path())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    waitfor(Duration::from_secs(5), || {
+        let s = nix::sys::statfs::statfs(d.path()).unwrap();
+        s.filesystem_type_name() == "fusefs.xfs"
+    })
+    .unwrap();
+    let traced = d.path().join("files").join("single_extent.txt");
+    let untraced = d.path().join("files").join("four_extents.txt");
+    assert_eq!(fs::metadata(&traced).unwrap().ino(), TRACED);
+    assert_eq!(fs::metadata(&untraced).unwrap().ino(), UNTRACED);
+    fs::read(&traced).unwrap();
+    fs::read(&untraced).unwrap();
+    assert_eq!(
+        xattr::get(&traced, "user.xfuse.trace").unwrap(),
+        Some(b"1".to_vec())
+    );
+    assert_eq!(
+        xattr::get(&untraced, "user.xfuse.trace").unwrap(),
+        Some(b"0".to_vec())
+    );
+    let status = Command::new("umount").arg(d.path()).status().unwrap();
+    assert!(status.success());
+    let output = child.wait_with_output().unwrap();
+    let log = String::from_utf8_lossy(&output.stderr);
+
+    assert!(log.contains("LOOKUP"), "{}", log);
+    let reads = log
+        .lines()
+        .find(|l| l.contains(&format!("[{}]: READ ", TRACED)))
+        .unwrap_or_else(|| panic!("{}", log));
+    assert!(reads.contains(&format!("({}, ", TRACED_OFFSET)), "{}", log);
+    assert!(!log.contains(&UNTRACED.to_string()), "{}", log);
+}
+
+/// With -o hot_inodes, only that many inodes stay cached, but the rest remain usable
+#[named]
+#[test]
+fn hot_inodes() {
+    require_fusefs!();
+
+    let h = harness_opts(GOLDEN4K.as_path(), &["hot_inodes=2"]);
+    let files = h.d.path().join("files");
+    let mut n = 0;
+    for entry in fs::read_dir(&files).unwrap() {
+        entry.unwrap().metadata().unwrap();
+        n += 1;
+    }
+    // hello.txt was looked up long ago, and is now cold
+    assert_eq!(
+        fs::read(files.join("hello.txt")).unwrap(),
+        b"Hello, World!\n"
+    );
+
+    let v = xattr::get(h.d.path(), "user.xfuse.stats").unwrap().unwrap();
+    let stats = String::from_utf8(v).unwrap();
+    let field = |name: &str| -> usize {
+        let start = stats.find(&format!("\"{}\":", name)).unwrap() + name.len() + 3;
+        let end = start + stats[start..].find([',', '}']).unwrap();
+        stats[start..end].parse().unwrap()
+    };
+    assert!(field("hot_inodes") <= 2, "{}", stats);
+    // The kernel may already have forgotten some, but not all
+    let tracked = field("tracked_inodes");
+    assert!(tracked > 2 && tracked <= n + 2, "{}", stats);
+}
+
+/// With -o inodes_dir, /.inodes holds every allocated inode, named by number
+#[named]
+#[test]
+fn inodes_dir() {
+    require_fusefs!();
+
+    // single_extent.txt's inode number
+    const INO: u64 = 142539;
+
+    let h = harness_opts(GOLDEN4K.as_path(), &["inodes_dir"]);
+    let inodes = h.d.path().join(".inodes");
+    assert!(fs::read_dir(h.d.path())
+        .unwrap()
+        .any(|e| e.unwrap().file_name() == ".inodes"));
+    let md = fs::metadata(&inodes).unwrap();
+    assert!(md.is_dir());
+    assert_eq!(md.permissions().mode() & 0o7777, 0o555);
+
+    // read_dir omits "." and ".."
+    let n = fs::read_dir(&inodes).unwrap().count() as u64;
+    let svfs = nix::sys::statvfs::statvfs(h.d.path()).unwrap();
+    assert_eq!(n, svfs.files() - svfs.files_free());
+
+    let path = inodes.join(INO.to_string());
+    assert_eq!(fs::metadata(&path).unwrap().ino(), INO);
+    assert_eq!(
+        fs::read(&path).unwrap(),
+        fs::read(h.d.path().join("files/single_extent.txt")).unwrap()
+    );
+    // Free inodes, and names that aren't canonical inode numbers, don't exist
+    for name in ["137", "0142539", "+142539", "foo"] {
+        let e = fs::metadata(inodes.join(name)).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ENOENT), "{}", name);
+    }
+}
+
+/// With -o metadir, /.xfuse holds JSON files describing the file system and the mount
+#[named]
+#[test]
+fn metadir() {
+    require_fusefs!();
+
+    let h = harness_opts(GOLDEN4K.as_path(), &["metadir"]);
+    let metadir = h.d.path().join(".xfuse");
+    assert!(fs::read_dir(h.d.path())
+        .unwrap()
+        .any(|e| e.unwrap().file_name() == ".xfuse"));
+    let mut names = fs::read_dir(&metadir)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(names, ["mount.json", "stats.json", "superblock.json"]);
+
+    let sb = fs::read_to_string(metadir.join("superblock.json")).unwrap();
+    for field in [
+        "\"version\":5",
+        "\"blocksize\":4096",
+        "\"sectsize\":512",
+        "\"inodesize\":512",
+        "\"dblocks\":24576",
+        "\"rblocks\":0",
+        "\"agcount\":4",
+        "\"agblocks\":6144",
+        "\"rootino\":128",
+        "\"logstart\":16390",
+        "\"logblocks\":1368",
+        "\"unit\":0",
+        "\"width\":0",
+        "\"bigtime\"",
+        "\"ftype\"",
+        "\"reflink\"",
+    ] {
+        assert!(sb.contains(field), "{} lacks {}", sb, field);
+    }
+    let stats = fs::read_to_string(metadir.join("stats.json")).unwrap();
+    assert!(
+        stats.starts_with("{\"validation_failures\":0,"),
+        "{}",
+        stats
+    );
+    let mount = fs::read_to_string(metadir.join("mount.json")).unwrap();
+    assert!(mount.contains("\"device\":"), "{}", mount);
+
+    // Without the option, there's no such directory
+    let h = harness(GOLDEN4K.as_path());
+    let e = fs::metadata(h.d.path().join(".xfuse")).unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(libc::ENOENT));
+}
+
+/// List an entire image without mounting it
+mod list {
+    use super::*;
+
+    #[test]
+    fn golden4k() {
+        let mut expected = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        expected.push("resources");
+        expected.push("xfs4096.list.json");
+
+        let output = Command::cargo_bin("xfs-fuse")
+            .unwrap()
+            .arg("--list")
+            .arg("--format=json")
+            .arg(GOLDEN4K.as_path())
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            fs::read_to_string(expected).unwrap()
+        );
+    }
+
+    /// --path restricts the listing to a subtree
+    #[test]
+    fn path() {
+        let output = Command::cargo_bin("xfs-fuse")
+            .unwrap()
+            .arg("--list")
+            .arg("--path=/links")
+            .arg(GOLDEN4K.as_path())
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let paths = stdout
+            .lines()
+            .map(|l| l.rsplit(' ').next().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, ["/links", "/links/max", "/links/sf"]);
+    }
+
+    /// A directory entry that points at an ancestor must not make the listing loop forever
+    #[test]
+    fn cycle() {
+        // The root's inode number, and the offset within it of the inode number of "files" in
+        // its shortform directory
+        const ROOT_INO: u64 = 128;
+        const FILES_INO: usize = 287;
+
+        let d = tempdir().unwrap();
+        let img = d.path().join("cycle.img");
+        fs::copy(GOLDEN4K.as_path(), &img).unwrap();
+        patch_inode(&img, ROOT_INO, |inode| {
+            inode[FILES_INO..][..4].copy_from_slice(&(ROOT_INO as u32).to_be_bytes());
+        });
+
+        let output = Command::cargo_bin("xfs-fuse")
+            .unwrap()
+            .arg("--list")
+            .arg(&img)
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(65));
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(
+            stderr.contains("cycle detected: /files is the same directory as /"),
+            "{}",
+            stderr
+        );
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert!(stdout.lines().any(|l| l.ends_with(" /files")));
+        assert!(!stdout.contains("/files/"));
+    }
+
+    /// --max-depth stops the listing from descending too far
+    #[test]
+    fn max_depth() {
+        let output = Command::cargo_bin("xfs-fuse")
+            .unwrap()
+            .arg("--list")
+            .arg("--path=/links")
+            .arg("--max-depth=0")
+            .arg(GOLDEN4K.as_path())
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(65));
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let paths = stdout
+            .lines()
+            .map(|l| l.rsplit(' ').next().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, ["/links"]);
+    }
+}
+
 mod lookup {
     use super::*;
 
+    /// The same image may be mounted at two places at once
+    #[named]
+    #[test]
+    fn mounted_twice() {
+        require_fusefs!();
+
+        let h0 = harness4k();
+        let h1 = harness4k();
+        for h in [&h0, &h1] {
+            let path = h.d.path().join("files").join("hello.txt");
+            access(&path, AccessFlags::F_OK).unwrap();
+        }
+        drop(h0);
+        let path = h1.d.path().join("links").join("sf");
+        access(&path, AccessFlags::F_OK).unwrap();
+    }
+
+    /// Two images with the same UUID, like a clone that has since diverged, may be mounted at
+    /// once, and each must serve its own contents.
+    #[named]
+    #[test]
+    fn same_uuid() {
+        require_fusefs!();
+
+        // Location of /files/single_extent.txt's data within the golden image
+        const DATA_OFFSET: u64 = 56360960;
+
+        let d = tempdir().unwrap();
+        let clone = d.path().join("clone.img");
+        fs::copy(GOLDEN4K.as_path(), &clone).unwrap();
+        let f = fs::OpenOptions::new().write(true).open(&clone).unwrap();
+        f.write_all_at(b"cloned", DATA_OFFSET).unwrap();
+        drop(f);
+
+        let h0 = harness4k();
+        let h1 = harness(&clone);
+        let original = fs::read(h0.d.path().join("files").join("single_extent.txt")).unwrap();
+        let cloned = fs::read(h1.d.path().join("files").join("single_extent.txt")).unwrap();
+        assert_eq!(original.len(), cloned.len());
+        assert!(!original.starts_with(b"cloned"));
+        assert!(cloned.starts_with(b"cloned"));
+        assert_eq!(original[6..], cloned[6..]);
+    }
+
+    /// Looking up the root directory as "." or ".." must return the same inode as the mountpoint,
+    /// not the root's on-disk inode number.
+    #[named]
+    #[rstest]
+    #[case::fourk(harness4k)]
+    #[case::v4(harnessv4)]
+    fn dotdot_root(#[case] h: fn() -> Harness) {
+        require_fusefs!();
+
+        let harness = h();
+        let flags = nix::fcntl::AtFlags::AT_SYMLINK_NOFOLLOW;
+        let root_ino = nix::sys::stat::stat(harness.d.path()).unwrap().st_ino;
+
+        let root = fs::File::open(harness.d.path()).unwrap();
+        let stat = nix::sys::stat::fstatat(root.as_raw_fd(), ".", flags).unwrap();
+        assert_eq!(stat.st_ino, root_ino);
+
+        let sf = fs::File::open(harness.d.path().join("sf")).unwrap();
+        let stat = nix::sys::stat::fstatat(sf.as_raw_fd(), "..", flags).unwrap();
+        assert_eq!(stat.st_ino, root_ino);
+
+        let oflag = nix::fcntl::OFlag::O_RDONLY | nix::fcntl::OFlag::O_DIRECTORY;
+        let fd =
+            nix::fcntl::openat(sf.as_raw_fd(), "..", oflag, nix::sys::stat::Mode::empty()).unwrap();
+        let stat = nix::sys::stat::fstat(fd).unwrap();
+        nix::unistd::close(fd).unwrap();
+        assert_eq!(stat.st_ino, root_ino);
+    }
+
     #[named]
     #[rstest]
     fn all_name_lengths(harness4k: Harness) {
@@ -678,6 +2068,7 @@ mod lookup {
     #[case::sf(harness4k, "sf")]
     #[case::block(harness4k, "block")]
     #[case::leaf(harness4k, "leaf")]
+    #[case::offset_leaf(harness4k_offset_leaf, "leaf")]
     #[case::v4_sf(harnessv4, "sf")]
     #[case::v4_leaf(harnessv4, "leaf")]
     #[case::v4_node(harnessv4, "node")]
@@ -687,6 +2078,10 @@ mod lookup {
     #[case::fourkn_block(harness4kn, "block")]
     #[case::fourkn_leaf(harness4kn, "leaf")]
     #[case::fourkn_node(harness4kn, "node")]
+    #[case::sixtyfourk_sf(harness64k, "sf")]
+    #[case::sixtyfourk_block(harness64k, "block")]
+    #[case::sixtyfourk_leaf(harness64k, "leaf")]
+    #[case::sixtyfourk_node(harness64k, "node")]
     fn dots(#[case] h: fn() -> Harness, #[case] d: &str) {
         require_fusefs!();
 
@@ -720,6 +2115,22 @@ mod lookup {
         let e = access(p.as_path(), AccessFlags::F_OK).unwrap_err();
         assert_eq!(e, nix::Error::ENOENT);
     }
+
+    /// No entry can have a name longer than 255 bytes
+    #[named]
+    #[rstest]
+    #[case::sf(harness4k, "sf")]
+    #[case::block(harness4k, "block")]
+    #[case::leaf(harness4k, "leaf")]
+    #[case::btree(harness4k, "btree")]
+    fn enametoolong(#[case] h: fn() -> Harness, #[case] d: &str) {
+        require_fusefs!();
+
+        let harness = h();
+        let p = harness.d.path().join(d).join("x".repeat(300));
+        let e = access(p.as_path(), AccessFlags::F_OK).unwrap_err();
+        assert_eq!(e, nix::Error::ENAMETOOLONG);
+    }
 }
 
 mod lseek {
@@ -728,16 +2139,21 @@ mod lseek {
     #[derive(Clone, Copy, Debug, Eq, PartialEq)]
     enum Offset {
         FromStart(libc::off_t),
+        /// The last byte of the file
+        BeforeEof,
         Eof,
         BeyondEof,
     }
 
     #[template]
     #[rstest]
-    /// Already at EOF
+    /// Already at EOF.  Native file systems on every platform that we support (XFS on Linux,
+    /// UFS and ZFS on FreeBSD) return ENXIO for any offset at or beyond EOF, for either whence,
+    /// and so does xfs-fuse.  Neither kernel second-guesses the server's answer.
     #[case::eof_data(Whence::SeekData, Offset::Eof, Err(Errno::ENXIO))]
-    /// The virtual hole at EOF
-    #[case::eof_hole(Whence::SeekHole, Offset::Eof, Ok(Offset::Eof))]
+    #[case::eof_hole(Whence::SeekHole, Offset::Eof, Err(Errno::ENXIO))]
+    /// The virtual hole at EOF, found from within the file
+    #[case::before_eof_hole(Whence::SeekHole, Offset::BeforeEof, Ok(Offset::Eof))]
     /// Seeking for hole beyond EOF
     #[case::beyond_eof_hole(Whence::SeekHole, Offset::BeyondEof, Err(Errno::ENXIO))]
     /// Seek for data
@@ -794,17 +2210,56 @@ mod lseek {
         let expected = match expected {
             Ok(Offset::FromStart(ofs)) => Ok(ofs),
             Ok(Offset::Eof) => Ok(f.metadata().unwrap().size() as libc::off_t),
-            Ok(Offset::BeyondEof) => unreachable!(),
+            Ok(Offset::BeforeEof | Offset::BeyondEof) => unreachable!(),
             Err(e) => Err(e),
         };
         let ofs = match ofs {
             Offset::FromStart(ofs) => ofs,
+            Offset::BeforeEof => f.metadata().unwrap().size() as libc::off_t - 1,
             Offset::Eof => f.metadata().unwrap().size() as libc::off_t,
             Offset::BeyondEof => f.metadata().unwrap().size() as libc::off_t + 1,
         };
         assert_eq!(expected, nix::unistd::lseek(f.as_raw_fd(), ofs, whence));
     }
 
+    /// Offsets beyond 4 GiB must not be truncated
+    #[named]
+    #[test]
+    fn above_4gib() {
+        require_fusefs!();
+
+        const GIB: i64 = 1 << 30;
+        let d = tempdir().unwrap();
+        let h = harness(&sparse_5g(&d));
+        let f = fs::File::open(h.d.path().join("files/single_extent.txt")).unwrap();
+        let fd = f.as_raw_fd();
+        assert_eq!(
+            nix::unistd::lseek(fd, 0, Whence::SeekData),
+            Ok(4 * GIB + 4096)
+        );
+        assert_eq!(nix::unistd::lseek(fd, 0, Whence::SeekHole), Ok(0));
+        assert_eq!(
+            nix::unistd::lseek(fd, 4 * GIB + 4100, Whence::SeekData),
+            Ok(4 * GIB + 4100)
+        );
+        assert_eq!(
+            nix::unistd::lseek(fd, 4 * GIB + 4096, Whence::SeekHole),
+            Ok(4 * GIB + 8192)
+        );
+        assert_eq!(
+            nix::unistd::lseek(fd, 4 * GIB + 8192, Whence::SeekData),
+            Err(Errno::ENXIO)
+        );
+        assert_eq!(
+            nix::unistd::lseek(fd, 5 * GIB - 1, Whence::SeekHole),
+            Ok(5 * GIB - 1)
+        );
+        assert_eq!(
+            nix::unistd::lseek(fd, 5 * GIB, Whence::SeekHole),
+            Err(Errno::ENXIO)
+        );
+    }
+
     /// A completely sparse file has no data regions
     #[named]
     #[rstest]
@@ -817,6 +2272,10 @@ mod lseek {
             Err(Errno::ENXIO),
             nix::unistd::lseek(f.as_raw_fd(), 0, Whence::SeekData)
         );
+        assert_eq!(
+            Ok(1 << 20),
+            nix::unistd::lseek(f.as_raw_fd(), 1 << 20, Whence::SeekHole)
+        );
     }
 
     /// Try to seek to a data region, but it's only hole untiL EOF
@@ -886,6 +2345,33 @@ mod lseek {
             nix::unistd::lseek(f.as_raw_fd(), 0, Whence::SeekHole)
         );
     }
+
+    /// Like preallocated, but with the unwritten extents in a btree
+    #[named]
+    #[test]
+    fn preallocated_btree() {
+        require_fusefs!();
+
+        let d = tempdir().unwrap();
+        let h = harness(&super::preallocated_btree(&d));
+        let f = fs::File::open(h.d.path().join("files/preallocated")).unwrap();
+        let fd = f.as_raw_fd();
+
+        assert_eq!(
+            Err(Errno::ENXIO),
+            nix::unistd::lseek(fd, 0, Whence::SeekData)
+        );
+        assert_eq!(Ok(0), nix::unistd::lseek(fd, 0, Whence::SeekHole));
+        // Including from within a later extent
+        assert_eq!(
+            Err(Errno::ENXIO),
+            nix::unistd::lseek(fd, 5 << 20, Whence::SeekData)
+        );
+        assert_eq!(
+            Ok(5 << 20),
+            nix::unistd::lseek(fd, 5 << 20, Whence::SeekHole)
+        );
+    }
 }
 
 mod lsextattr {
@@ -946,6 +2432,107 @@ mod lsextattr {
         }
     }
 
+    /// Files without extended attributes must report an empty list, whether the caller asks for
+    /// the list's size or its contents.  ls(1) uses both to decide whether to mark a file as
+    /// having extended attributes.
+    #[named]
+    #[rstest]
+    #[case::sf_dir(harness4k, "sf")]
+    #[case::block_dir(harness4k, "block")]
+    #[case::file(harness4k, "files/hello.txt")]
+    #[case::btree_file(harness4k, "files/btree2.txt")]
+    #[case::v4_dir(harnessv4, "leaf")]
+    #[case::v4_file(harnessv4, "files/hello.txt")]
+    #[case::onek_file(harness1k, "files/large_extent.txt")]
+    fn none(#[case] h: fn() -> Harness, #[case] d: &str) {
+        use std::{ffi::CString, ptr};
+        require_fusefs!();
+
+        let harness = h();
+        let ns = libc::EXTATTR_NAMESPACE_USER;
+        let p = harness.d.path().join(d);
+        let cpath = CString::new(p.as_os_str().as_bytes()).unwrap();
+
+        let r = unsafe { libc::extattr_list_file(cpath.as_ptr(), ns, ptr::null_mut(), 0) };
+        assert_eq!(0, r, "{} reported a nonempty list size", d);
+
+        let mut v = Vec::<u8>::with_capacity(1024);
+        let r = unsafe {
+            libc::extattr_list_file(cpath.as_ptr(), ns, v.as_mut_ptr().cast(), v.capacity())
+        };
+        assert_eq!(0, r, "{} reported a nonempty list", d);
+    }
+
+    /// Copy the 4k golden image, and give its root directory a shortform attribute fork holding
+    /// these user attributes, after its shortform data fork.
+    fn root_xattrs(d: &TempDir, xattrs: &[(&str, &str)]) -> PathBuf {
+        // The root's inode number
+        const ROOT_INO: u64 = 128;
+        // Offsets of di_forkoff and di_aformat within the inode, and the attribute fork's offset
+        // in 8-byte units.  The root's data fork is 139 bytes long.
+        const DI_FORKOFF: usize = 82;
+        const FORKOFF: u8 = 18;
+        const XFS_DINODE_FMT_LOCAL: u8 = 1;
+
+        let mut sf = vec![0, 0, xattrs.len() as u8, 0];
+        for (name, value) in xattrs {
+            sf.extend_from_slice(&[name.len() as u8, value.len() as u8, 0]);
+            sf.extend_from_slice(name.as_bytes());
+            sf.extend_from_slice(value.as_bytes());
+        }
+        let totsize = sf.len() as u16;
+        sf[0..2].copy_from_slice(&totsize.to_be_bytes());
+
+        let scratch = d.path().join("scratch.img");
+        fs::copy(GOLDEN4K.as_path(), &scratch).unwrap();
+        patch_inode(&scratch, ROOT_INO, |inode| {
+            inode[DI_FORKOFF..][..2].copy_from_slice(&[FORKOFF, XFS_DINODE_FMT_LOCAL]);
+            let afork = 176 + usize::from(FORKOFF) * 8;
+            inode[afork..][..sf.len()].copy_from_slice(&sf);
+        });
+        scratch
+    }
+
+    /// The root directory lists its real extended attributes and its virtual ones, each exactly
+    /// once, and a real one hides a virtual one of the same name.
+    #[named]
+    #[rstest]
+    #[case::virtual_only(&[], &["user.xfuse.stats"], None)]
+    #[case::merged(&[("attr", "value")], &["user.attr", "user.xfuse.stats"], None)]
+    #[case::shadowed(
+        &[("xfuse.stats", "real"), ("attr", "value")],
+        &["user.attr", "user.xfuse.stats"],
+        Some("real")
+    )]
+    fn root(
+        #[case] xattrs: &[(&str, &str)],
+        #[case] expected: &[&str],
+        #[case] stats: Option<&str>,
+    ) {
+        require_fusefs!();
+
+        let d = tempdir().unwrap();
+        let scratch = root_xattrs(&d, xattrs);
+        let h = harness(&scratch);
+        let root = h.d.path();
+
+        let mut names = xattr::list(root).unwrap().collect::<Vec<_>>();
+        names.sort_unstable();
+        assert_eq!(names, expected);
+        for (name, value) in xattrs {
+            assert_eq!(
+                xattr::get(root, format!("user.{}", name)).unwrap(),
+                Some(value.as_bytes().to_vec())
+            );
+        }
+        let value = xattr::get(root, "user.xfuse.stats").unwrap().unwrap();
+        let value = String::from_utf8(value).unwrap();
+        match stats {
+            Some(real) => assert_eq!(value, real),
+            None => assert!(value.starts_with("{\"validation_failures\":"), "{}", value),
+        }
+    }
+
     /// Lookup the size of the extended attribute list of a file, without
     /// fetching it.
     // This test is freebsd-specific because the relevant syscall is.  It could
@@ -976,18 +2563,145 @@ mod lsextattr {
     }
 }
 
-mod open {
+mod norestrict {
+    use std::os::unix::process::CommandExt;
+
     use super::*;
 
-    /// Files can be opened multiple times
+    /// Make the "files" directory accessible only to root
+    fn private_dir(d: &TempDir) -> PathBuf {
+        // The "files" directory's inode number
+        const INO: u64 = 142529;
+        // Offset of di_mode within the inode
+        const DI_MODE: usize = 2;
+
+        let scratch = d.path().join("scratch.img");
+        fs::copy(GOLDEN4K.as_path(), &scratch).unwrap();
+        patch_inode(&scratch, INO, |inode| {
+            inode[DI_MODE..][..2].copy_from_slice(&0o40700u16.to_be_bytes());
+        });
+        scratch
+    }
+
+    /// Can an unprivileged user list this directory?
+    fn nobody_can_list(path: &Path) -> bool {
+        Command::new("ls")
+            .arg(path)
+            .uid(65534)
+            .gid(65534)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .unwrap()
+            .success()
+    }
+
+    /// By default, the kernel enforces an inode's own permissions.  With -o norestrict,
+    /// everything is readable by everyone, but ownership is unchanged.
     #[named]
     #[rstest]
-    fn multiple(harness4k: Harness) {
+    #[case::default(&[], 0o700, false)]
+    #[case::norestrict(&["norestrict"], 0o755, true)]
+    fn private(#[case] opts: &[&str], #[case] mode: u32, #[case] accessible: bool) {
         require_fusefs!();
+        require_root!();
 
-        let path = harness4k.d.path().join("files").join("executable");
+        let d = tempdir().unwrap();
+        let img = private_dir(&d);
+        let h = harness_opts(&img, opts);
+        let path = h.d.path().join("files");
+
+        let md = fs::metadata(&path).unwrap();
+        assert_eq!(md.permissions().mode() & 0o7777, mode);
+        assert_eq!(md.uid(), 0);
+        assert_eq!(nobody_can_list(&path), accessible);
+    }
+}
 
-        let _f1 = fs::File::open(&path).unwrap();
+mod nrext64 {
+    use super::*;
+
+    /// Copy the 4k golden image, and convert /files and /files/single_extent.txt to the large
+    /// extent counters format that `mkfs.xfs -i nrext64=1` would use.  Only the inodes that use
+    /// it differ on disk, so there's no need for a separate golden image.
+    fn nrext64(d: &TempDir) -> PathBuf {
+        const SB_FEATURES_INCOMPAT: usize = 216;
+        const SB_CRC: usize = 224;
+        const XFS_SB_FEAT_INCOMPAT_NREXT64: u32 = 1 << 5;
+        // Inode numbers of /files and /files/single_extent.txt
+        const INOS: [u64; 2] = [142529, 142539];
+        const DI_BIG_NEXTENTS: usize = 24;
+        const DI_NEXTENTS: usize = 76;
+        const DI_ANEXTENTS: usize = 80;
+        const DI_FLAGS2: usize = 120;
+        const XFS_DIFLAG2_NREXT64: u64 = 1 << 4;
+
+        let scratch = d.path().join("nrext64.img");
+        fs::copy(GOLDEN4K.as_path(), &scratch).unwrap();
+        let f = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&scratch)
+            .unwrap();
+        let mut sb = [0u8; 512];
+        f.read_exact_at(&mut sb, 0).unwrap();
+        let incompat = u32::from_be_bytes(sb[SB_FEATURES_INCOMPAT..][..4].try_into().unwrap());
+        let incompat = incompat | XFS_SB_FEAT_INCOMPAT_NREXT64;
+        sb[SB_FEATURES_INCOMPAT..][..4].copy_from_slice(&incompat.to_be_bytes());
+        set_crc(&mut sb, SB_CRC);
+        f.write_all_at(&sb, 0).unwrap();
+        drop(f);
+
+        for ino in INOS {
+            patch_inode(&scratch, ino, |inode| {
+                let nextents = u32::from_be_bytes(inode[DI_NEXTENTS..][..4].try_into().unwrap());
+                let anextents = u16::from_be_bytes(inode[DI_ANEXTENTS..][..2].try_into().unwrap());
+                let flags2 = u64::from_be_bytes(inode[DI_FLAGS2..][..8].try_into().unwrap());
+                inode[DI_BIG_NEXTENTS..][..8].copy_from_slice(&u64::from(nextents).to_be_bytes());
+                inode[DI_NEXTENTS..][..4].copy_from_slice(&u32::from(anextents).to_be_bytes());
+                inode[DI_ANEXTENTS..][..2].fill(0);
+                inode[DI_FLAGS2..][..8]
+                    .copy_from_slice(&(flags2 | XFS_DIFLAG2_NREXT64).to_be_bytes());
+            });
+        }
+        scratch
+    }
+
+    /// Lookup, readdir, and read should all work with large extent counters
+    #[named]
+    #[test]
+    fn read() {
+        require_fusefs!();
+
+        let d = tempdir().unwrap();
+        let h = harness(&nrext64(&d));
+        let files = h.d.path().join("files");
+        let names = fs::read_dir(&files)
+            .unwrap()
+            .map(|ent| ent.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert!(names.contains(&OsString::from("single_extent.txt")));
+        assert!(names.contains(&OsString::from("four_extents.txt")));
+        let data = fs::read(files.join("single_extent.txt")).unwrap();
+        assert_eq!(data.len(), 4096);
+        let golden = harness4k();
+        let expected = fs::read(golden.d.path().join("files").join("single_extent.txt")).unwrap();
+        assert_eq!(data, expected);
+    }
+}
+
+mod open {
+    use super::*;
+
+    /// Files can be opened multiple times
+    #[named]
+    #[rstest]
+    fn multiple(harness4k: Harness) {
+        require_fusefs!();
+
+        let path = harness4k.d.path().join("files").join("executable");
+
+        let _f1 = fs::File::open(&path).unwrap();
         // Open it again with a different mode.  This forces fusefs(4) to send a
         // separate FUSE_OPEN request.
         let _f2 = fs::OpenOptions::new()
@@ -997,7 +2711,7 @@ mod open {
             .unwrap();
     }
 
-    /// Try to open two reflinked files at the same time
+    /// Open two reflinked files at the same time, and read them in turns
     #[named]
     #[rstest]
     fn reflinks(harness4k: Harness) {
@@ -1008,6 +2722,13 @@ mod open {
 
         let fa = fs::File::open(&path_a).unwrap();
         let fb = fs::File::open(path_b).unwrap();
+        let mut buf = [0u8; 16];
+        for ofs in (0..16384).step_by(4096) {
+            for f in [&fa, &fb] {
+                f.read_exact_at(&mut buf, ofs).unwrap();
+                assert_eq!(&buf, format!("{:016x}", ofs).as_bytes());
+            }
+        }
 
         drop(fa);
         drop(fb);
@@ -1017,6 +2738,123 @@ mod open {
     }
 }
 
+mod orphans {
+    use super::*;
+
+    // /files/single_extent.txt's inode number, and the location of its data on disk
+    const INO: u64 = 142539;
+    const DATA: u64 = 56360960;
+
+    /// Copy the 4k golden image, and leave /files/single_extent.txt the way that a crash leaves a
+    /// file that was unlinked while open: with no links, on its AG's unlinked list.  Its
+    /// directory entry remains, but --orphans doesn't look at directories.
+    fn orphan_4k(d: &TempDir) -> PathBuf {
+        // Offset of di_nlink within the inode
+        const DI_NLINK: usize = 16;
+        // Offsets of the unlinked list heads and the CRC within the AGI
+        const AGI_UNLINKED: usize = 40;
+        const AGI_CRC: usize = 312;
+
+        let scratch = d.path().join("scratch.img");
+        fs::copy(GOLDEN4K.as_path(), &scratch).unwrap();
+        let f = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&scratch)
+            .unwrap();
+        let mut sb = [0u8; 512];
+        f.read_exact_at(&mut sb, 0).unwrap();
+        let blocksize = u32::from_be_bytes(sb[4..8].try_into().unwrap());
+        let agblocks = u32::from_be_bytes(sb[84..88].try_into().unwrap());
+        let sectsize = u16::from_be_bytes(sb[102..104].try_into().unwrap());
+        let inopblog = sb[123];
+        let agblklog = sb[124];
+        let agno = INO >> (agblklog + inopblog);
+        let agino = (INO & ((1 << (agblklog + inopblog)) - 1)) as u32;
+
+        patch_inode(&scratch, INO, |inode| {
+            inode[DI_NLINK..][..4].copy_from_slice(&0u32.to_be_bytes());
+        });
+        let agi_ofs = agno * u64::from(agblocks) * u64::from(blocksize) + 2 * u64::from(sectsize);
+        let mut agi = vec![0u8; usize::from(sectsize)];
+        f.read_exact_at(&mut agi, agi_ofs).unwrap();
+        let bucket = AGI_UNLINKED + 4 * (agino as usize % 64);
+        agi[bucket..bucket + 4].copy_from_slice(&agino.to_be_bytes());
+        set_crc(&mut agi, AGI_CRC);
+        f.write_all_at(&agi, agi_ofs).unwrap();
+        scratch
+    }
+
+    /// A cleanly unmounted file system has no orphans
+    #[test]
+    fn none() {
+        let output = Command::cargo_bin("xfs-fuse")
+            .unwrap()
+            .arg("--orphans")
+            .arg(GOLDEN4K.as_path())
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "{\"orphans\":[]}\n"
+        );
+    }
+
+    #[test]
+    fn scan() {
+        let d = tempdir().unwrap();
+        let scratch = orphan_4k(&d);
+        let output = Command::cargo_bin("xfs-fuse")
+            .unwrap()
+            .arg("--orphans")
+            .arg(&scratch)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            concat!(
+                "{\"orphans\":[{\"agno\":2,\"ino\":142539,\"mode\":33188,\"nlink\":0,",
+                "\"size\":4096,\"mtime\":1719334986,\"nextents\":1,\"nblocks\":1,",
+                "\"recovered\":null}]}\n"
+            )
+        );
+    }
+
+    /// --recover-orphans copies each orphan's contents
+    #[test]
+    fn recover() {
+        let d = tempdir().unwrap();
+        let scratch = orphan_4k(&d);
+        let out = tempdir().unwrap();
+        let output = Command::cargo_bin("xfs-fuse")
+            .unwrap()
+            .arg("--orphans")
+            .arg("--recover-orphans")
+            .arg(out.path())
+            .arg(&scratch)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let recovered = out.path().join(INO.to_string());
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert!(
+            stdout.contains(&format!("\"recovered\":\"{}\"", recovered.display())),
+            "{}",
+            stdout
+        );
+
+        let mut expected = vec![0u8; 4096];
+        fs::File::open(GOLDEN4K.as_path())
+            .unwrap()
+            .read_exact_at(&mut expected, DATA)
+            .unwrap();
+        assert!(expected.starts_with(b"0000000000000000"));
+        assert_eq!(fs::read(recovered).unwrap(), expected);
+    }
+}
+
 mod pathconf {
     use super::*;
 
@@ -1055,8 +2893,38 @@ mod read {
     #[case::reflink_a(harness4k, "reflink_a.txt", 16384)]
     #[case::reflink_b(harness4k, "reflink_b.txt", 16384)]
     #[case::reflink_partial(harness4k, "reflink_partial.txt", 16384)]
+    #[case::large_extent_64k(harness64k, "large_extent.txt", 1048576)]
+    #[case::four_extents_64k(harness64k, "four_extents.txt", 262144)]
+    #[case::btree_64k(harness64k, "btree.txt", 4194304)]
     fn all_files(h: fn() -> Harness, d: &str) {}
 
+    /// Reads at offsets beyond 4 GiB must not be truncated, and neither must the size
+    #[named]
+    #[test]
+    fn above_4gib() {
+        require_fusefs!();
+
+        const GIB: u64 = 1 << 30;
+        let d = tempdir().unwrap();
+        let h = harness(&sparse_5g(&d));
+        let f = fs::File::open(h.d.path().join("files/single_extent.txt")).unwrap();
+        let md = f.metadata().unwrap();
+        assert_eq!(md.size(), 5 * GIB);
+        // Only one block is allocated
+        assert!(md.blocks() <= 8, "{}", md.blocks());
+
+        let mut buf = [0u8; 16];
+        f.read_exact_at(&mut buf, 4 * GIB + 4096).unwrap();
+        assert_eq!(&buf, b"0000000000000000");
+        f.read_exact_at(&mut buf, 4 * GIB + 4096 + 16).unwrap();
+        assert_eq!(&buf, b"0000000000000010");
+        // The holes on either side
+        f.read_exact_at(&mut buf, 4 * GIB).unwrap();
+        assert_eq!(buf, [0u8; 16]);
+        f.read_exact_at(&mut buf, 5 * GIB - 16).unwrap();
+        assert_eq!(buf, [0u8; 16]);
+    }
+
     /// Attempting to read across eof should return the correct amount of data
     #[named]
     #[apply(all_files)]
@@ -1225,6 +3093,29 @@ mod read {
             assert_eq!(zbuf, buf, "Read garbage where there should've been zeros");
         }
     }
+
+    /// Like unwritten, but with the unwritten extents in a btree.  Reads that span several
+    /// extents must still return all zeros.
+    #[named]
+    #[test]
+    fn unwritten_btree() {
+        require_fusefs!();
+
+        const FLEN: u64 = 8388608;
+        const BUFLEN: usize = 1 << 20;
+
+        let d = tempdir().unwrap();
+        let h = harness(&preallocated_btree(&d));
+        let mut f = fs::File::open(h.d.path().join("files/preallocated")).unwrap();
+        assert_eq!(f.metadata().unwrap().size(), FLEN);
+
+        let zbuf = vec![0; BUFLEN];
+        let mut buf = vec![0; BUFLEN];
+        for _ in 0..(FLEN / BUFLEN as u64) {
+            f.read_exact(&mut buf[..]).unwrap();
+            assert_eq!(zbuf, buf, "Read garbage where there should've been zeros");
+        }
+    }
 }
 
 mod readdir {
@@ -1259,6 +3150,49 @@ mod readdir {
         assert_eq!(count, ents_per_dir_shortnames(harness4k.path.as_path(), d));
     }
 
+    /// A corrupt directory entry should make readdir fail with EIO, rather than end the listing
+    /// early as if the directory held nothing more.
+    #[named]
+    #[test]
+    fn corrupt_entry() {
+        require_fusefs!();
+
+        let d = tempdir().unwrap();
+        let scratch = d.path().join("scratch.img");
+        fs::copy(GOLDEN4K.as_path(), &scratch).unwrap();
+        // Find /block's data, and give frame000010 an invalid file type
+        let mut img = fs::read(&scratch).unwrap();
+        let needle = b"\x0bframe000010";
+        let name = img
+            .chunks(4096)
+            .enumerate()
+            .filter(|(_, blk)| blk.starts_with(b"XDB3"))
+            .find_map(|(i, blk)| {
+                blk.windows(needle.len())
+                    .position(|w| w == needle)
+                    .map(|p| i * 4096 + p + 1)
+            })
+            .unwrap();
+        img[name + 11] = 0x2a;
+        fs::write(&scratch, img).unwrap();
+
+        let h = harness(&scratch);
+        let mut names = Vec::new();
+        let mut error = None;
+        for rent in fs::read_dir(h.d.path().join("block")).unwrap() {
+            match rent {
+                Ok(ent) => names.push(ent.file_name()),
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
+            }
+        }
+        assert_eq!(error.unwrap().raw_os_error(), Some(libc::EIO));
+        assert!(names.contains(&OsString::from("frame000009")));
+        assert!(!names.contains(&OsString::from("frame000010")));
+    }
+
     /// A block directory with hash collisions
     #[rstest]
     #[named]
@@ -1317,6 +3251,69 @@ mod readdir {
         assert_eq!(count, ents_per_dir_longnames(harness.path.as_path(), d));
     }
 
+    extern "C" {
+        // The libc crate doesn't declare getdirentries.  Its struct dirent has the FreeBSD 11
+        // layout, so use the version of getdirentries that fills that in.
+        #[link_name = "getdirentries@FBSD_1.0"]
+        fn getdirentries(
+            fd: libc::c_int,
+            buf: *mut libc::c_char,
+            nbytes: libc::c_int,
+            basep: *mut libc::c_long,
+        ) -> libc::c_int;
+    }
+
+    /// Read a large directory with a buffer that holds only a few entries, so each getdirentries
+    /// call resumes from the offset of the last entry that the previous one returned.  Every entry
+    /// must be listed exactly once.
+    #[named]
+    #[rstest]
+    #[case::btree_3(harness1k, "btree3")]
+    #[case::v4_btree_3(harnessv4, "btree3")]
+    fn small_buffer(#[case] h: fn() -> Harness, #[case] d: &str) {
+        use nix::{fcntl::OFlag, sys::stat::Mode};
+        require_fusefs!();
+
+        let harness = h();
+        let dpath = harness.d.path().join(d);
+        let expected = fs::read_dir(&dpath)
+            .unwrap()
+            .map(|rent| rent.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            expected.len(),
+            ents_per_dir_longnames(harness.path.as_path(), d)
+        );
+
+        let fd =
+            nix::fcntl::open(&dpath, OFlag::O_RDONLY | OFlag::O_DIRECTORY, Mode::empty()).unwrap();
+        // Room for three entries with 255 byte names.  The rest keeps any struct dirent in bounds.
+        let mut buf = [0u64; 128 + std::mem::size_of::<libc::dirent>() / 8];
+        let mut basep: libc::c_long = 0;
+        let mut names = Vec::new();
+        loop {
+            let r = unsafe { getdirentries(fd, buf.as_mut_ptr().cast(), 1024, &mut basep) };
+            assert!(r >= 0, "{}", io::Error::last_os_error());
+            if r == 0 {
+                break;
+            }
+            let mut ofs = 0;
+            while ofs < r as usize {
+                let dirent = unsafe { &*buf.as_ptr().cast::<u8>().add(ofs).cast::<libc::dirent>() };
+                let namlen = usize::from(dirent.d_namlen);
+                let name = unsafe {
+                    std::slice::from_raw_parts(dirent.d_name.as_ptr().cast::<u8>(), namlen)
+                };
+                if name != b"." && name != b".." {
+                    names.push(OsStr::from_bytes(name).to_owned());
+                }
+                ofs += usize::from(dirent.d_reclen);
+            }
+        }
+        nix::unistd::close(fd).unwrap();
+        assert_eq!(names, expected);
+    }
+
     /// List a directory's contents with readdir
     #[named]
     #[apply(all_dir_types_shortnames)]
@@ -1371,6 +3368,10 @@ mod readdir {
     #[case::fourkn_block(harness4kn, "block")]
     #[case::fourkn_leaf(harness4kn, "leaf")]
     #[case::fourkn_node(harness4kn, "node")]
+    #[case::sixtyfourk_sf(harness64k, "sf")]
+    #[case::sixtyfourk_block(harness64k, "block")]
+    #[case::sixtyfourk_leaf(harness64k, "leaf")]
+    #[case::sixtyfourk_node(harness64k, "node")]
     fn dots(#[case] h: fn() -> Harness, #[case] d: &str) {
         use nix::{dir::Dir, fcntl::OFlag, sys::stat::Mode};
         require_fusefs!();
@@ -1393,6 +3394,30 @@ mod readdir {
         assert_eq!("..", dotdot.file_name().to_str().unwrap());
         assert_eq!(root_md.ino(), dotdot.ino());
     }
+
+    /// `ls -l` stats every entry that it lists.  Where the kernel supports readdirplus, those
+    /// attributes come with the listing; either way, they must be right.
+    #[named]
+    #[rstest]
+    fn ls_long(harness4k: Harness) {
+        require_fusefs!();
+
+        let output = Command::new("ls")
+            .arg("-ln")
+            .arg(harness4k.d.path().join("leaf"))
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        // Skip the "total" line
+        let lines = stdout.lines().skip(1).collect::<Vec<_>>();
+        assert_eq!(lines.len(), 384);
+        for line in lines {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            assert_eq!(fields[0], "-rw-r--r--", "{}", line);
+            assert_eq!(fields[4], "0", "{}", line);
+        }
+    }
 }
 
 #[named]
@@ -1451,6 +3476,25 @@ mod stat {
         assert_eq!(stat.st_nlink, 2);
     }
 
+    /// Birth time should reach userland.  v4 file systems don't store it, so it should match
+    /// ctime.
+    // This may need to be updated whenever the golden images get rebuilt.
+    #[named]
+    #[rstest]
+    #[case::v4(harnessv4, None)]
+    #[case::v5(harness4k, Some((1719334986, 7989770)))]
+    fn birthtime(#[case] h: fn() -> Harness, #[case] expected: Option<(i64, i64)>) {
+        require_fusefs!();
+
+        let harness = h();
+        let path = harness.d.path().join("files").join("hello.txt");
+
+        let stat = nix::sys::stat::stat(&path).unwrap();
+        let (sec, nsec) = expected.unwrap_or((stat.st_ctime, stat.st_ctime_nsec));
+        assert_eq!(stat.st_birthtime, sec);
+        assert_eq!(stat.st_birthtime_nsec, nsec);
+    }
+
     /// Timestamps from before the Epoch should work
     #[named]
     #[rstest]
@@ -1464,19 +3508,115 @@ mod stat {
         assert_eq!(stat.st_atime, -1613800129);
     }
 
+    /// The extremes of bigtime, which mkimg.sh can't produce, should all be reported exactly.
+    #[named]
+    #[test]
+    fn bigtime_bounds() {
+        require_fusefs!();
+
+        // single_extent.txt's inode number
+        const INO: u64 = 142539;
+        // Offsets of di_atime, di_mtime, and di_ctime within the inode
+        const DI_ATIME: usize = 32;
+        const DI_MTIME: usize = 40;
+        const DI_CTIME: usize = 48;
+
+        let d = tempdir().unwrap();
+        let scratch = d.path().join("scratch.img");
+        fs::copy(GOLDEN4K.as_path(), &scratch).unwrap();
+        patch_inode(&scratch, INO, |inode| {
+            // The earliest time, in December 1901
+            inode[DI_ATIME..][..8].copy_from_slice(&0u64.to_be_bytes());
+            // The latest time, in 2486
+            inode[DI_MTIME..][..8].copy_from_slice(&u64::MAX.to_be_bytes());
+            // The first second after a classic timestamp would've overflowed, in 2038
+            inode[DI_CTIME..][..8].copy_from_slice(&((1u64 << 32) * 1_000_000_000).to_be_bytes());
+        });
+
+        let h = harness(&scratch);
+        let path = h.d.path().join("files").join("single_extent.txt");
+        let stat = nix::sys::stat::stat(&path).unwrap();
+        assert_eq!(stat.st_atime, -(1 << 31));
+        assert_eq!(stat.st_atime_nsec, 0);
+        assert_eq!(stat.st_mtime, 16_299_260_425);
+        assert_eq!(stat.st_mtime_nsec, 709_551_615);
+        assert_eq!(stat.st_ctime, 1 << 31);
+        assert_eq!(stat.st_ctime_nsec, 0);
+    }
+
+    /// The setuid, setgid, and sticky bits must be reported exactly, so that copies made with
+    /// cp -p keep them.  Setting them in mkimg.sh would mean rebuilding the golden images, which
+    /// changes the timestamps and locations that other tests hard-code, so edit the modes instead.
+    #[named]
+    #[test]
+    fn special_bits() {
+        require_fusefs!();
+
+        // Inode numbers of /files/executable, /xattrs, and /links
+        const EXECUTABLE: u64 = 142531;
+        const XATTRS: u64 = 134;
+        const LINKS: u64 = 65697;
+        // Offset of di_mode within the inode
+        const DI_MODE: usize = 2;
+
+        let d = tempdir().unwrap();
+        let scratch = d.path().join("scratch.img");
+        fs::copy(GOLDEN4K.as_path(), &scratch).unwrap();
+        let modes = [
+            ("files/executable", EXECUTABLE, libc::S_IFREG | 0o4755),
+            ("xattrs", XATTRS, libc::S_IFDIR | 0o2775),
+            ("links", LINKS, libc::S_IFDIR | 0o1777),
+        ];
+        for (_, ino, mode) in modes {
+            #[allow(clippy::unnecessary_cast)] // It isn't unnecessary on all platforms.
+            let di_mode = mode as u16;
+            patch_inode(&scratch, ino, |inode| {
+                inode[DI_MODE..][..2].copy_from_slice(&di_mode.to_be_bytes());
+            });
+        }
+
+        let h = harness(&scratch);
+        for (path, _, mode) in modes {
+            let stat = nix::sys::stat::stat(&h.d.path().join(path)).unwrap();
+            assert_eq!(stat.st_mode, mode, "{}: {:o}", path, stat.st_mode);
+        }
+    }
+
+    /// Device files report the device numbers that mkimg.sh gave them
     #[named]
     #[rstest]
-    #[case::blockdev("blockdev", libc::S_IFBLK)]
-    #[case::chardev("chardev", libc::S_IFCHR)]
-    #[case::fifo("fifo", libc::S_IFIFO)]
-    #[case::socket("sock", libc::S_IFSOCK)]
-    fn devs(harness4k: Harness, #[case] filename: &str, #[case] devtype: u16) {
+    #[case::blockdev("blockdev", libc::S_IFBLK, libc::makedev(1, 2))]
+    #[case::chardev("chardev", libc::S_IFCHR, libc::makedev(1, 2))]
+    #[case::fifo("fifo", libc::S_IFIFO, 0)]
+    #[case::socket("sock", libc::S_IFSOCK, 0)]
+    fn devs(
+        harness4k: Harness,
+        #[case] filename: &str,
+        #[case] devtype: u16,
+        #[case] rdev: libc::dev_t,
+    ) {
         require_fusefs!();
 
         let path = harness4k.d.path().join("files").join(filename);
 
         let stat = nix::sys::stat::stat(&path).unwrap();
         assert_eq!(stat.st_mode & libc::S_IFMT, devtype);
+        assert_eq!(stat.st_rdev, rdev);
+    }
+
+    /// The preferred I/O size is the file system's block size
+    #[named]
+    #[rstest]
+    #[case::b512(harnessv4, 512)]
+    #[case::b4k(harness4k, 4096)]
+    fn blksize(#[case] h: fn() -> Harness, #[case] expected: libc::blksize_t) {
+        require_fusefs!();
+
+        let harness = h();
+        let path = harness.d.path().join("files").join("hello.txt");
+
+        let stat = nix::sys::stat::stat(&path).unwrap();
+        assert_eq!(stat.st_blksize, expected);
     }
 
     /// stat should work on symlinks
@@ -1494,29 +3634,312 @@ mod stat {
         assert_eq!(1, stat.st_nlink, "AT_SYMLINK_NOFOLLOW was ignored");
         assert_eq!(ino, stat.st_ino);
     }
+
+    /// Every file's attributes should be identical no matter how the inode was reached, or file
+    /// integrity monitors will think that it changed.  A mount with only one hot inode must reload
+    /// nearly every inode from disk, after having first reached it through readdir.
+    #[named]
+    #[rstest]
+    #[case::fourk(GOLDEN4K.as_path())]
+    #[case::v4(GOLDENV4.as_path())]
+    #[case::no_ftype(GOLDEN_NOFTYPE.as_path())]
+    fn invariance(#[case] image: &Path) {
+        require_fusefs!();
+
+        let fields = |path: &Path| {
+            let flags = nix::fcntl::AtFlags::AT_SYMLINK_NOFOLLOW;
+            let s = nix::sys::stat::fstatat(libc::AT_FDCWD, path, flags).unwrap();
+            (
+                (
+                    s.st_ino, s.st_mode, s.st_nlink, s.st_uid, s.st_gid, s.st_rdev,
+                ),
+                (s.st_size, s.st_blocks, s.st_blksize, s.st_flags),
+                (s.st_atime, s.st_atime_nsec, s.st_mtime, s.st_mtime_nsec),
+                (
+                    s.st_ctime,
+                    s.st_ctime_nsec,
+                    s.st_birthtime,
+                    s.st_birthtime_nsec,
+                ),
+            )
+        };
+
+        let warm = harness(image);
+        let cold = harness_opts(image, &["hot_inodes=1"]);
+        for entry in walkdir::WalkDir::new(cold.d.path()) {
+            let entry = entry.unwrap();
+            let rel = entry.path().strip_prefix(cold.d.path()).unwrap();
+            let expected = fields(&warm.d.path().join(rel));
+            assert_eq!(fields(entry.path()), expected, "{}", rel.display());
+            assert_eq!(
+                fields(&warm.d.path().join(rel)),
+                expected,
+                "{}",
+                rel.display()
+            );
+        }
+    }
+}
+
+/// Runtime statistics, for performance debugging
+mod stats {
+    use nix::{
+        sys::signal::{kill, Signal},
+        unistd::Pid,
+    };
+
+    use super::*;
+
+    /// The value of a numeric field in the stats JSON
+    fn field(json: &str, name: &str) -> u64 {
+        let key = format!("\"{}\":", name);
+        let start = json.find(&key).unwrap_or_else(|| panic!("{}", json)) + key.len();
+        json[start..]
+            .split(|c: char| !c.is_ascii_digit())
+            .next()
+            .unwrap()
+            .parse()
+            .unwrap()
+    }
+
+    /// After a fixed workload, the root's stats attribute must count the operations and the bytes
+    /// that it read
+    #[named]
+    #[test]
+    fn xattr() {
+        require_fusefs!();
+
+        const SIZE: u64 = 1 << 20;
+
+        let h = harness4k();
+        let data = fs::read(h.d.path().join("files").join("large_extent.txt")).unwrap();
+        assert_eq!(data.len() as u64, SIZE);
+        fs::read_dir(h.d.path().join("leaf")).unwrap().count();
+
+        let v = xattr::get(h.d.path(), "user.xfuse.stats").unwrap().unwrap();
+        let stats = String::from_utf8(v).unwrap();
+        // All of the file's data, plus a little metadata
+        let bytes = field(&stats, "device_bytes");
+        assert!((SIZE..SIZE + (1 << 18)).contains(&bytes), "{}", stats);
+        assert!(field(&stats, "read") > 0, "{}", stats);
+        assert!(field(&stats, "lookup") >= 3, "{}", stats);
+        assert_eq!(field(&stats, "decode_errors"), 0, "{}", stats);
+    }
+
+    /// Reading a file sequentially with O_DIRECT, 16 bytes at a time, reads its blocks from the
+    /// device only once, unless readahead is disabled.
+    #[named]
+    #[rstest]
+    #[case::default(&[], false)]
+    #[case::disabled(&["readahead=0"], true)]
+    fn readahead(#[case] opts: &[&str], #[case] amplified: bool) {
+        require_fusefs!();
+
+        const SIZE: usize = 16384;
+
+        let h = harness_opts(GOLDEN4K.as_path(), opts);
+        let path = h.d.path().join("files").join("four_extents.txt");
+        let mut f = fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(path)
+            .unwrap();
+        let mut buf = [0; 16];
+        for ofs in (0..SIZE).step_by(buf.len()) {
+            f.read_exact(&mut buf[..]).unwrap();
+            assert_eq!(&buf[..], format!("{:016x}", ofs).as_bytes());
+        }
+
+        let v = xattr::get(h.d.path(), "user.xfuse.stats").unwrap().unwrap();
+        let stats = String::from_utf8(v).unwrap();
+        // Without readahead, each read copies at least a whole block
+        let bytes = field(&stats, "device_bytes");
+        assert_eq!(
+            bytes >= (SIZE / buf.len() * 4096) as u64,
+            amplified,
+            "{}",
+            stats
+        );
+    }
+
+    /// SIGUSR1 logs the statistics
+    #[named]
+    #[test]
+    fn sigusr1() {
+        require_fusefs!();
+
+        let d = tempdir().unwrap();
+        let child = Command::cargo_bin("xfs-fuse")
+            .unwrap()
+            .env("RUST_LOG", "xfs_fuse=info")
+            .arg(GOLDEN4K.as_path())
+            .arg(d.path())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        waitfor(Duration::from_secs(5), || {
+            let s = nix::sys::statfs::statfs(d.path()).unwrap();
+            s.filesystem_type_name() == "fusefs.xfs"
+        })
+        .unwrap();
+        fs::read(d.path().join("files").join("hello.txt")).unwrap();
+        kill(Pid::from_raw(child.id() as i32), Signal::SIGUSR1).unwrap();
+        sleep(Duration::from_millis(500));
+        let status = Command::new("umount").arg(d.path()).status().unwrap();
+        assert!(status.success());
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success());
+        let log = String::from_utf8_lossy(&output.stderr);
+        assert!(log.contains("Statistics: {\"ops\":{"), "{}", log);
+    }
+}
+
+mod strict {
+    use super::*;
+
+    /// Copy the 4k golden image and damage /block's best free table, which nothing reads, so
+    /// only its CRC reveals the damage.
+    fn corrupt_block_dir(d: &TempDir) -> PathBuf {
+        let scratch = d.path().join("scratch.img");
+        fs::copy(GOLDEN4K.as_path(), &scratch).unwrap();
+        let mut img = fs::read(&scratch).unwrap();
+        let needle = b"\x0bframe000010";
+        let blk = img
+            .chunks(4096)
+            .position(|blk| {
+                blk.starts_with(b"XDB3") && blk.windows(needle.len()).any(|w| w == needle)
+            })
+            .unwrap();
+        img[blk * 4096 + 48] ^= 0x10;
+        fs::write(&scratch, img).unwrap();
+        scratch
+    }
+
+    fn stats(h: &Harness) -> String {
+        let v = xattr::get(h.d.path(), "user.xfuse.stats").unwrap().unwrap();
+        String::from_utf8(v).unwrap()
+    }
+
+    /// By default, a directory block with a bad CRC can still be listed
+    #[named]
+    #[test]
+    fn lenient() {
+        require_fusefs!();
+
+        let d = tempdir().unwrap();
+        let scratch = corrupt_block_dir(&d);
+        let h = harness(&scratch);
+        let n = fs::read_dir(h.d.path().join("block"))
+            .unwrap()
+            .map(Result::unwrap)
+            .count();
+        assert_eq!(n, ents_per_dir_shortnames(GOLDEN4K.as_path(), "block"));
+        assert!(stats(&h).starts_with(r#"{"validation_failures":0,"#));
+    }
+
+    /// With -o strict, the bad CRC is EIO, and is counted
+    #[named]
+    #[test]
+    fn strict() {
+        require_fusefs!();
+
+        let d = tempdir().unwrap();
+        let scratch = corrupt_block_dir(&d);
+        let h = harness_opts(&scratch, &["strict"]);
+        let e = fs::read_dir(h.d.path().join("block"))
+            .unwrap()
+            .find_map(Result::err)
+            .unwrap();
+        assert_eq!(e.raw_os_error(), Some(libc::EIO));
+        assert!(!stats(&h).starts_with(r#"{"validation_failures":0,"#));
+        // Undamaged directories are still fine
+        fs::read_dir(h.d.path().join("leaf"))
+            .unwrap()
+            .map(Result::unwrap)
+            .count();
+    }
+
+    /// Copy the 4k golden image and change the file type of the root directory's "files" entry
+    /// from directory to regular file.
+    fn wrong_ftype(d: &TempDir) -> PathBuf {
+        // The root's inode number, and the offset within it of the ftype byte of "files" in its
+        // shortform directory
+        const ROOT_INO: u64 = 128;
+        const FTYPE: usize = 286;
+        const XFS_DIR3_FT_REG_FILE: u8 = 1;
+
+        let scratch = d.path().join("scratch.img");
+        fs::copy(GOLDEN4K.as_path(), &scratch).unwrap();
+        patch_inode(&scratch, ROOT_INO, |inode| {
+            inode[FTYPE] = XFS_DIR3_FT_REG_FILE
+        });
+        scratch
+    }
+
+    /// By default, a directory entry whose file type disagrees with its inode is reported with
+    /// the inode's type, and counted
+    #[named]
+    #[test]
+    fn ftype_mismatch_lenient() {
+        require_fusefs!();
+
+        let d = tempdir().unwrap();
+        let scratch = wrong_ftype(&d);
+        let h = harness(&scratch);
+        let files = h.d.path().join("files");
+        assert!(fs::metadata(&files).unwrap().is_dir());
+        assert_eq!(
+            fs::read(files.join("hello.txt")).unwrap(),
+            b"Hello, World!\n"
+        );
+        let stats = stats(&h);
+        assert!(stats.contains(r#""ftype_mismatches":1"#), "{}", stats);
+    }
+
+    /// With -o strict, looking up such an entry is EIO
+    #[named]
+    #[test]
+    fn ftype_mismatch_strict() {
+        require_fusefs!();
+
+        let d = tempdir().unwrap();
+        let scratch = wrong_ftype(&d);
+        let h = harness_opts(&scratch, &["strict"]);
+        let e = fs::metadata(h.d.path().join("files")).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EIO));
+        assert!(fs::metadata(h.d.path().join("block")).unwrap().is_dir());
+    }
 }
 
+/// Block and inode counts should match what Linux reports: total == used + free.
 #[named]
 #[rstest]
-fn statfs(harness4k: Harness) {
+#[case::fourk(harness4k, 4096, 23208, 16513, 750)]
+#[case::v4(harnessv4, 512, 126266, 90592, 19320)]
+fn statfs(
+    #[case] h: fn() -> Harness,
+    #[case] bsize: u64,
+    #[case] blocks: u64,
+    #[case] bfree: u64,
+    #[case] inodes_used: u64,
+) {
     require_fusefs!();
 
-    let sfs = nix::sys::statfs::statfs(harness4k.d.path()).unwrap();
-
-    assert_eq!(sfs.blocks(), 23208);
-    assert_eq!(sfs.block_size(), 4096);
+    let harness = h();
+    let sfs = nix::sys::statfs::statfs(harness.d.path()).unwrap();
 
-    // Linux's calculation for blocks available and free is complicated and the
-    // docs indicate that it's approximate.  So don't assert on the exact value.
+    assert_eq!(sfs.blocks(), blocks);
+    assert_eq!(sfs.block_size(), bsize);
+    assert_eq!(sfs.blocks_free(), bfree);
     assert_eq!(
         sfs.blocks_available(),
         i64::try_from(sfs.blocks_free()).unwrap()
     );
 
-    // Linux's calculation for f_files is very confusing and not supported by
-    // the XFS documentation.  I think it may be wrong.  So don't assert on it
-    // here.
-    assert_eq!(i64::try_from(sfs.files()).unwrap() - sfs.files_free(), 750);
+    assert_eq!(
+        i64::try_from(sfs.files()).unwrap() - sfs.files_free(),
+        i64::try_from(inodes_used).unwrap()
+    );
 
     // There are legitimate questions about what the correct value for
     // optimal_transfer_size
@@ -1527,27 +3950,86 @@ fn statfs(harness4k: Harness) {
     // So ignore it.
 }
 
+/// Copy the 4k golden image, and give it the stripe geometry that `mkfs.xfs -d su=64k,sw=4` would:
+/// a 16 block stripe unit and a 64 block stripe width.
+fn striped(d: &TempDir) -> PathBuf {
+    const SB_UNIT: usize = 184;
+    const SB_WIDTH: usize = 188;
+    const SB_CRC: usize = 224;
+    const CASTAGNOLI: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISCSI);
+
+    let scratch = d.path().join("striped.img");
+    fs::copy(GOLDEN4K.as_path(), &scratch).unwrap();
+    let f = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&scratch)
+        .unwrap();
+    let mut sb = [0u8; 512];
+    f.read_exact_at(&mut sb, 0).unwrap();
+    sb[SB_UNIT..SB_UNIT + 4].copy_from_slice(&16u32.to_be_bytes());
+    sb[SB_WIDTH..SB_WIDTH + 4].copy_from_slice(&64u32.to_be_bytes());
+    sb[SB_CRC..SB_CRC + 4].fill(0);
+    let crc = CASTAGNOLI.checksum(&sb);
+    sb[SB_CRC..SB_CRC + 4].copy_from_slice(&crc.to_le_bytes());
+    f.write_all_at(&sb, 0).unwrap();
+    scratch
+}
+
+/// On a striped file system, the optimal transfer size is the stripe width, but the block size
+/// and counts are the same as without striping.
+#[named]
+#[test]
+fn statfs_striped() {
+    require_fusefs!();
+
+    let d = tempdir().unwrap();
+    let scratch = striped(&d);
+    let harness = harness(&scratch);
+    let sfs = nix::sys::statfs::statfs(harness.d.path()).unwrap();
+
+    assert_eq!(sfs.block_size(), 4096);
+    assert_eq!(sfs.optimal_transfer_size(), 262144);
+    assert_eq!(sfs.blocks(), 23208);
+    assert_eq!(sfs.blocks_free(), 16513);
+
+    let output = Command::cargo_bin("xfs-fuse")
+        .unwrap()
+        .arg("--check")
+        .arg(&scratch)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .starts_with("{\"agcount\":4,\"unit\":16,\"width\":64,"));
+}
+
 #[named]
 #[rstest]
-fn statvfs(harness4k: Harness) {
+#[case::fourk(harness4k, 4096, 23208, 16513, 750)]
+#[case::v4(harnessv4, 512, 126266, 90592, 19320)]
+fn statvfs(
+    #[case] h: fn() -> Harness,
+    #[case] bsize: u64,
+    #[case] blocks: u64,
+    #[case] bfree: u64,
+    #[case] inodes_used: u64,
+) {
     require_fusefs!();
 
-    let svfs = nix::sys::statvfs::statvfs(harness4k.d.path()).unwrap();
+    let harness = h();
+    let svfs = nix::sys::statvfs::statvfs(harness.d.path()).unwrap();
     // xfuse is always read-only.
     assert!(svfs.flags().contains(nix::sys::statvfs::FsFlags::ST_RDONLY));
-    assert_eq!(svfs.fragment_size(), 4096);
-    assert_eq!(svfs.blocks(), 23208);
+    assert_eq!(svfs.fragment_size(), bsize);
+    assert_eq!(svfs.blocks(), blocks);
+    assert_eq!(svfs.blocks_free(), bfree);
+    assert_eq!(svfs.blocks_available(), svfs.blocks_free());
 
-    // Linux's calculation for f_files is very confusing and not supported by
-    // the XFS documentation.  I think it may be wrong.  So don't assert on it
-    // here.
-    assert_eq!(svfs.files() - svfs.files_free(), 750);
+    assert_eq!(svfs.files() - svfs.files_free(), inodes_used);
     assert_eq!(svfs.files_free(), svfs.files_available());
 
-    // Linux's calculation for blocks available and free is complicated and the
-    // docs indicate that it's approximate.  So don't assert on the exact value.
-    assert_eq!(svfs.blocks_available(), svfs.blocks_free());
-
     // There are legitimate questions about what the correct value for f_bsize
     // really is.  Until that's decided, don't assert on it.
     // https://bugs.freebsd.org/bugzilla/show_bug.cgi?id=253424
@@ -1557,3 +4039,242 @@ fn statvfs(harness4k: Harness) {
     // svfs.f_namemax is DONTCARE.  This information should be retrieved via
     // pathconf instead.
 }
+
+/// With -o threads, reads of different files proceed at once, alongside directory listings
+mod threads {
+    use std::{sync::mpsc, thread};
+
+    use super::*;
+
+    const THREADS: usize = 8;
+
+    /// The sorted names in a directory
+    fn names(dir: &Path) -> Vec<OsString> {
+        let mut names = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[named]
+    #[test]
+    fn stress() {
+        require_fusefs!();
+
+        let h = harness_opts(GOLDEN4K.as_path(), &[&format!("threads={}", THREADS)]);
+        let root = h.d.path().to_owned();
+        // The largest regular files, short of the terabyte sparse one, and what --cat says that
+        // they contain
+        let mut files = fs::read_dir(root.join("files"))
+            .unwrap()
+            .map(|e| e.unwrap())
+            .filter(|e| e.file_type().unwrap().is_file())
+            .map(|e| (e.metadata().unwrap().len(), e.file_name()))
+            .filter(|(len, _)| *len <= 1 << 24)
+            .collect::<Vec<_>>();
+        files.sort();
+        let files = files
+            .iter()
+            .rev()
+            .take(THREADS)
+            .map(|(_, name)| {
+                let output = Command::cargo_bin("xfs-fuse")
+                    .unwrap()
+                    .arg("--cat")
+                    .arg(Path::new("/files").join(name))
+                    .arg(GOLDEN4K.as_path())
+                    .output()
+                    .unwrap();
+                assert!(output.status.success());
+                (name.clone(), output.stdout)
+            })
+            .collect::<Vec<_>>();
+        let dirs = ["files", "block", "leaf", "all_name_lengths"]
+            .iter()
+            .map(|d| (root.join(d), names(&root.join(d))))
+            .collect::<Vec<_>>();
+
+        let (tx, rx) = mpsc::channel();
+        for (i, (name, expected)) in files.into_iter().enumerate() {
+            let tx = tx.clone();
+            let path = root.join("files").join(&name);
+            let (dir, entries) = dirs[i % dirs.len()].clone();
+            thread::spawn(move || {
+                for _ in 0..4 {
+                    let data = fs::read(&path).unwrap();
+                    assert!(data == expected, "{:?} read wrong data", name);
+                    assert_eq!(names(&dir), entries, "{}", dir.display());
+                }
+                tx.send(()).unwrap();
+            });
+        }
+        drop(tx);
+        for _ in 0..THREADS {
+            // A thread that panicked drops its sender without sending
+            rx.recv_timeout(Duration::from_secs(120))
+                .expect("a reader failed, or deadlocked");
+        }
+    }
+}
+
+/// Images truncated by a copy error
+mod truncated {
+    use super::*;
+
+    /// Cut off the last 584 KiB of the 4k image.  That's the tail of the last AG, which holds the
+    /// inodes of the longest-named files in /all_name_lengths, and that directory's last data
+    /// block.
+    fn truncated_image(d: &TempDir) -> PathBuf {
+        const LEN: u64 = 100065280;
+
+        let scratch = d.path().join("scratch.img");
+        fs::copy(GOLDEN4K.as_path(), &scratch).unwrap();
+        let f = fs::OpenOptions::new().write(true).open(&scratch).unwrap();
+        f.set_len(LEN).unwrap();
+        scratch
+    }
+
+    /// Directories in earlier AGs are unaffected
+    #[named]
+    #[test]
+    fn earlier_ags() {
+        require_fusefs!();
+
+        let d = tempdir().unwrap();
+        let h = harness(&truncated_image(&d));
+        let mut count = 0;
+        for rent in fs::read_dir(h.d.path().join("block")).unwrap() {
+            rent.unwrap().metadata().unwrap();
+            count += 1;
+        }
+        assert_eq!(count, ents_per_dir_shortnames(GOLDEN4K.as_path(), "block"));
+    }
+
+    /// Entries whose inodes are missing should fail with EIO, as should the listing once it
+    /// reaches the missing directory block.  Neither may crash the daemon.
+    #[named]
+    #[test]
+    fn missing_inodes() {
+        require_fusefs!();
+
+        let d = tempdir().unwrap();
+        let h = harness(&truncated_image(&d));
+        let mut readable = 0;
+        let mut unreadable = 0;
+        let mut error = None;
+        for rent in fs::read_dir(h.d.path().join("all_name_lengths")).unwrap() {
+            match rent {
+                Ok(ent) => match ent.metadata() {
+                    Ok(_) => readable += 1,
+                    Err(e) => {
+                        assert_eq!(e.raw_os_error(), Some(libc::EIO));
+                        unreadable += 1;
+                    }
+                },
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
+            }
+        }
+        assert!(readable > 0);
+        assert!(unreadable > 0);
+        assert_eq!(error.unwrap().raw_os_error(), Some(libc::EIO));
+        fs::metadata(h.d.path().join("sf")).unwrap();
+    }
+}
+
+mod write {
+    use super::*;
+
+    /// With -o rw, overwriting an existing block should survive a remount
+    #[named]
+    #[test]
+    fn overwrite() {
+        require_fusefs!();
+
+        let d = tempdir().unwrap();
+        let scratch = d.path().join("scratch.img");
+        fs::copy(GOLDEN4K.as_path(), &scratch).unwrap();
+        let data = vec![0x5au8; 4096];
+        {
+            let h = harness_opts(&scratch, &["rw"]);
+            let path = h.d.path().join("files").join("single_extent.txt");
+            let f = fs::OpenOptions::new().write(true).open(&path).unwrap();
+            f.write_all_at(&data, 0).unwrap();
+            f.sync_all().unwrap();
+        }
+
+        let h = harness(&scratch);
+        let path = h.d.path().join("files").join("single_extent.txt");
+        assert_eq!(fs::read(path).unwrap(), data);
+    }
+
+    /// Writing into a hole would need allocation, so it must fail without changing anything
+    #[named]
+    #[test]
+    fn hole() {
+        require_fusefs!();
+
+        let d = tempdir().unwrap();
+        let scratch = d.path().join("scratch.img");
+        fs::copy(GOLDEN4K.as_path(), &scratch).unwrap();
+        let h = harness_opts(&scratch, &["rw"]);
+        let path = h.d.path().join("files").join("single_extent.txt");
+        let f = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        let e = f.write_all_at(b"x", 1 << 20).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ENOSPC));
+        assert_eq!(fs::metadata(&path).unwrap().len(), 4096);
+    }
+
+    /// A file system whose log needs recovery must not be mounted read-write, since replaying
+    /// the log later could undo the writes.  Read-only, it's fine.
+    #[named]
+    #[test]
+    fn dirty_log() {
+        require_fusefs!();
+
+        // The golden image's log begins at this byte offset.  Its last record is an unmount
+        // record at basic block 1296, whose operation's flags are at OH_FLAGS in the next one.
+        const LOG: u64 = 50356224;
+        const OH_FLAGS: u64 = 9;
+
+        let d = tempdir().unwrap();
+        let scratch = d.path().join("scratch.img");
+        fs::copy(GOLDEN4K.as_path(), &scratch).unwrap();
+        let f = fs::OpenOptions::new().write(true).open(&scratch).unwrap();
+        f.write_all_at(&[0], LOG + 1297 * 512 + OH_FLAGS).unwrap();
+        drop(f);
+
+        let mnt = d.path().join("mnt");
+        fs::create_dir(&mnt).unwrap();
+        let output = Command::cargo_bin("xfs-fuse")
+            .unwrap()
+            .arg("-o")
+            .arg("rw")
+            .arg(&scratch)
+            .arg(&mnt)
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(1));
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.contains("the log is dirty"), "{}", stderr);
+
+        let h = harness(&scratch);
+        fs::metadata(h.d.path().join("files")).unwrap();
+    }
+
+    /// Without -o rw, the mount is read-only
+    #[named]
+    #[test]
+    fn ro() {
+        require_fusefs!();
+
+        let h = harness4k();
+        let path = h.d.path().join("files").join("single_extent.txt");
+        let e = fs::OpenOptions::new().write(true).open(path).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EROFS));
+    }
+}
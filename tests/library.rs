@@ -0,0 +1,259 @@
+//! Walk a golden image using nothing but the library's public API, as another program would.
+//! It needs neither FUSE nor root.
+use std::{
+    ffi::OsStr,
+    fs,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use xfuse::{FileType, Options, Volume, ROOT_INO};
+
+#[allow(dead_code)]
+mod util;
+
+use util::{GOLDEN4K, GOLDEN4K_OFFSET_LEAF, GOLDEN64K};
+
+/// How much of a file to read at a time
+const CHUNK: u32 = 1 << 17;
+
+/// Files larger than this are only sampled at their start and end
+const SAMPLE_OVER: u64 = 1 << 26;
+
+/// Check a file's data, which the golden image fills with each 16 bytes' own offset in hex.
+/// Holes read as zeros.
+fn check_data(path: &Path, offset: u64, data: &[u8]) {
+    if path.file_name() == Some(OsStr::new("hello.txt"))
+        || path.file_name() == Some(OsStr::new("hello2.txt"))
+    {
+        assert_eq!(data, b"Hello, World!\n");
+        return;
+    }
+    for (i, chunk) in data.chunks(16).enumerate() {
+        let expected = format!("{:016x}", offset as usize + i * 16);
+        assert!(
+            chunk == &expected.as_bytes()[..chunk.len()] || chunk.iter().all(|b| *b == 0),
+            "{} at {}: {:?}",
+            path.display(),
+            offset as usize + i * 16,
+            chunk
+        );
+    }
+}
+
+/// Whole seconds since the Epoch, rounded down like stat(2) does
+fn unix_secs(t: SystemTime) -> i64 {
+    match t.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64) - i64::from(e.duration().subsec_nanos() > 0),
+    }
+}
+
+/// Read a whole regular file, or sample it if it's large, checking its contents.
+fn read_file(vol: &mut Volume, path: &Path, ino: u64, size: u64) {
+    let mut ranges = vec![(0, size.min(SAMPLE_OVER))];
+    if size > SAMPLE_OVER {
+        ranges.push((size - u64::from(CHUNK), size));
+    }
+    for (start, end) in ranges {
+        let mut offset = start;
+        while offset < end {
+            let data = vol.read(ino, offset, CHUNK).unwrap();
+            assert!(
+                !data.is_empty(),
+                "{}: short read at {}",
+                path.display(),
+                offset
+            );
+            check_data(path, offset, &data);
+            offset += data.len() as u64;
+        }
+        assert_eq!(offset, end, "{}", path.display());
+    }
+    assert!(vol.read(ino, size, CHUNK).unwrap().is_empty());
+}
+
+/// List a file, and if it's a directory, its descendants in depth-first order with each
+/// directory's entries sorted by name, in the same JSON format as `--list`.
+fn walk(vol: &mut Volume, path: PathBuf, ino: u64, lines: &mut Vec<String>) {
+    let attr = vol.getattr(ino).unwrap();
+    assert_eq!(attr.ino, ino);
+    let kind = match attr.kind {
+        FileType::NamedPipe => 'p',
+        FileType::CharDevice => 'c',
+        FileType::BlockDevice => 'b',
+        FileType::Directory => 'd',
+        FileType::RegularFile => 'f',
+        FileType::Symlink => 'l',
+        FileType::Socket => 's',
+    };
+    let mut xattrs = vol
+        .list_xattrs(ino)
+        .unwrap()
+        .into_iter()
+        // The root's virtual attributes aren't stored on disk, so --list doesn't show them
+        .filter(|name| !name.as_bytes().starts_with(b"user.xfuse."))
+        .collect::<Vec<_>>();
+    xattrs.sort_unstable();
+    for name in xattrs.iter() {
+        vol.get_xattr(ino, name).unwrap();
+    }
+    // Every name in the golden image is printable ASCII, which Debug quotes just as JSON does
+    let xattrs = xattrs
+        .iter()
+        .map(|name| format!("{:?}", name.to_str().unwrap()))
+        .collect::<Vec<_>>();
+    lines.push(format!(
+        "{{\"path\":{:?},\"type\":\"{}\",\"size\":{},\"mtime\":{},\"uid\":{},\"gid\":{},\"nlink\":\
+         {},\"xattrs\":[{}]}}",
+        path.to_str().unwrap(),
+        kind,
+        attr.size,
+        unix_secs(attr.mtime),
+        attr.uid,
+        attr.gid,
+        attr.nlink,
+        xattrs.join(",")
+    ));
+    match attr.kind {
+        FileType::Directory => {
+            let mut entries = vol.read_dir(ino).unwrap();
+            entries.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+            for entry in entries {
+                let child = path.join(&entry.name);
+                assert_eq!(vol.resolve(&child).unwrap(), entry.ino);
+                walk(vol, child, entry.ino, lines);
+            }
+        }
+        FileType::RegularFile => read_file(vol, &path, ino, attr.size),
+        FileType::Symlink => assert!(!vol.readlink(ino).unwrap().is_empty()),
+        _ => (),
+    }
+}
+
+/// The walk must find exactly what `--list` does
+#[test]
+fn walk_golden4k() {
+    let mut vol = Volume::open(&GOLDEN4K, Options::default()).unwrap();
+    let mut lines = Vec::new();
+    walk(&mut vol, PathBuf::from("/"), ROOT_INO, &mut lines);
+
+    let mut listing = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    listing.push("resources/xfs4096.list.json");
+    let expected = fs::read_to_string(listing).unwrap();
+    assert_eq!(lines.len(), expected.lines().count());
+    for (line, expected) in lines.iter().zip(expected.lines()) {
+        assert_eq!(line, expected);
+    }
+}
+
+/// Walk the 64k image, whose block size is the largest XFS allows.  It has no listing, but its
+/// directories hold a known number of files, and its files the same data as the 4k image's.
+#[test]
+fn walk_golden64k() {
+    let mut vol = Volume::open(&GOLDEN64K, Options::default()).unwrap();
+    let mut lines = Vec::new();
+    walk(&mut vol, PathBuf::from("/"), ROOT_INO, &mut lines);
+    let count = |dir: &str| {
+        lines
+            .iter()
+            .filter(|line| line.starts_with(&format!("{{\"path\":\"/{dir}/")))
+            .count()
+    };
+    assert_eq!(count("sf"), 2);
+    assert_eq!(count("block"), 1024);
+    assert_eq!(count("leaf"), 4096);
+    assert_eq!(count("node"), 16384);
+    assert_eq!(count("xattrs"), 2);
+    assert_eq!(count("files"), 3);
+    let xattrs = vol.resolve("/xattrs/extents1024").unwrap();
+    assert_eq!(vol.list_xattrs(xattrs).unwrap().len(), 1024);
+    assert!(vol.stats().to_json().contains("\"decode_errors\":0}"));
+}
+
+#[test]
+fn resolve_errors() {
+    let mut vol = Volume::open(&GOLDEN4K, Options::default()).unwrap();
+    let hello = vol.resolve("/files/hello.txt").unwrap();
+    assert_eq!(vol.resolve("files/../files/./hello.txt").unwrap(), hello);
+    let enoent = vol.resolve("/files/nonexistent").unwrap_err();
+    assert_eq!(enoent.raw_os_error(), Some(libc::ENOENT));
+    let enotdir = vol.resolve("/files/hello.txt/foo").unwrap_err();
+    assert_eq!(enotdir.raw_os_error(), Some(libc::ENOTDIR));
+    let eisdir = vol.read(ROOT_INO, 0, CHUNK).unwrap_err();
+    assert_eq!(eisdir.raw_os_error(), Some(libc::EISDIR));
+    let enotdir = vol.read_dir(hello).unwrap_err();
+    assert_eq!(enotdir.raw_os_error(), Some(libc::ENOTDIR));
+}
+
+/// A Leaf directory whose data blocks start past its first directory block holds the same entries
+/// as the one it was derived from
+#[test]
+fn offset_leaf_dir() {
+    let entries = |img: &Path| {
+        let mut vol = Volume::open(img, Options::default()).unwrap();
+        let dir = vol.resolve("/leaf").unwrap();
+        let mut entries = vol
+            .read_dir(dir)
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.name, entry.ino))
+            .collect::<Vec<_>>();
+        entries.sort_unstable();
+        for (name, ino) in entries.iter() {
+            assert_eq!(vol.resolve(Path::new("/leaf").join(name)).unwrap(), *ino);
+        }
+        assert!(vol.stats().to_json().contains("\"decode_errors\":0}"));
+        entries
+    };
+    let expected = entries(&GOLDEN4K);
+    assert_eq!(expected.len(), 384);
+    assert_eq!(entries(&GOLDEN4K_OFFSET_LEAF), expected);
+}
+
+/// Each Volume counts its own work, even when another was opened on the same thread since
+#[test]
+fn stats_per_volume() {
+    const IDLE: &str = "\"inode_cache\":{\"hits\":0,\"misses\":0}";
+
+    let mut a = Volume::open(&GOLDEN4K, Options::default()).unwrap();
+    let b = Volume::open(&GOLDEN4K, Options::default()).unwrap();
+    let (a_stats, b_stats) = (a.stats(), b.stats());
+    let before = b_stats.device_bytes();
+    let hello = a.resolve("/files/hello.txt").unwrap();
+    a.read(hello, 0, CHUNK).unwrap();
+    assert!(!a_stats.to_json().contains(IDLE), "{}", a_stats.to_json());
+    assert!(b_stats.to_json().contains(IDLE), "{}", b_stats.to_json());
+    assert_eq!(b_stats.device_bytes(), before);
+}
+
+/// The golden image has no realtime section, so it has no use for a realtime device
+#[test]
+fn rtdev_without_realtime_section() {
+    let opts = Options {
+        rtdev: Some(GOLDEN4K.to_path_buf()),
+        ..Default::default()
+    };
+    let e = Volume::open(&GOLDEN4K, opts).unwrap_err();
+    assert!(e.to_string().contains("no realtime section"), "{}", e);
+}
+
+/// Reading a file counts all of its bytes, and little else, as read from the device
+#[test]
+fn stats() {
+    const SIZE: u64 = 1 << 20;
+
+    let mut vol = Volume::open(&GOLDEN4K, Options::default()).unwrap();
+    let stats = vol.stats();
+    let file = vol.resolve("/files/large_extent.txt").unwrap();
+    let before = stats.device_bytes();
+    let mut offset = 0;
+    while offset < SIZE {
+        offset += vol.read(file, offset, CHUNK).unwrap().len() as u64;
+    }
+    let bytes = stats.device_bytes() - before;
+    assert!((SIZE..SIZE + (1 << 16)).contains(&bytes), "{}", bytes);
+    let json = stats.to_json();
+    assert!(json.contains("\"decode_errors\":0}"), "{}", json);
+}
@@ -0,0 +1,131 @@
+//! Walk a whole golden image, looking up every entry, listing every file's extended attributes,
+//! and reading every regular file, with and without `-o mem_limit`.  Without it, the caches grow
+//! past the limit.  With it, they must stay within the limit after every operation, and every
+//! operation must still succeed.  Then do the same to a single directory with 131072 entries,
+//! whose blocks alone far exceed the limit.
+//!
+//! It uses the decoders directly, without FUSE, so it needs neither fusefs nor root.  It has its
+//! own main so it can share the unit tests' view of the library.
+use std::{ffi::OsString, path::Path, process};
+
+// Test targets with harness = false are built with cfg(test), so the unit tests' helpers and
+// imports go unused.
+#[allow(dead_code, deprecated, unused_imports)]
+#[path = "../src/libxfuse/mod.rs"]
+mod libxfuse;
+#[allow(dead_code)]
+mod util;
+
+use libxfuse::{
+    stat::{FileType, ROOT_INO},
+    volume::{Options, Volume},
+};
+use util::{GOLDEN1K, GOLDEN4K};
+
+/// Small enough that walking the image must evict, but large enough to hold a few directories
+const LIMIT: u64 = 64 << 10;
+
+/// Smaller than the btree3 directory's blocks, but large enough to hold many of them
+const BTREE3_LIMIT: u64 = 4 << 20;
+
+/// Settle the caches as the FUSE dispatcher does after each operation, and return their total.
+fn settle(vol: &mut Volume) -> u64 {
+    vol.settle_caches();
+    vol.cache_budget().total()
+}
+
+/// Walk the tree rooted at the top-level directory `top`, or the whole image if None, and return
+/// the most memory that the caches ever held.
+fn walk(img: &Path, top: Option<&str>, opts: Options) -> u64 {
+    let limit = opts.mem_limit;
+    let mut vol = Volume::open(img, opts).unwrap();
+    let mut peak = 0;
+    let mut check = |vol: &mut Volume, what: &dyn Fn() -> String| {
+        let total = settle(vol);
+        if let Some(limit) = limit {
+            assert!(total <= limit, "{}: {} bytes cached", what(), total);
+        }
+        peak = peak.max(total);
+    };
+    let mut dirs = vec![match top {
+        Some(top) => {
+            let (attr, _) = vol.do_lookup(ROOT_INO, top.as_ref()).unwrap();
+            (attr.ino, OsString::from(format!("/{}", top)))
+        }
+        None => (ROOT_INO, OsString::from("/")),
+    }];
+    while let Some((dir, path)) = dirs.pop() {
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        loop {
+            let count = vol
+                .do_readdir(dir, offset, |_, off, kind, name| {
+                    offset = off;
+                    if name != "." && name != ".." {
+                        entries.push((kind, name.to_owned()));
+                    }
+                    false
+                })
+                .unwrap();
+            check(&mut vol, &|| format!("readdir {:?}", path));
+            if count == 0 {
+                break;
+            }
+        }
+        for (kind, name) in entries {
+            let (attr, _) = vol.do_lookup(dir, &name).unwrap();
+            let mut child = path.clone();
+            child.push("/");
+            child.push(&name);
+            check(&mut vol, &|| format!("lookup {:?}", child));
+            vol.do_listxattr(attr.ino, 1 << 16).unwrap();
+            check(&mut vol, &|| format!("listxattr {:?}", child));
+            match kind {
+                FileType::Directory => dirs.push((attr.ino, child)),
+                FileType::RegularFile if attr.size <= 1 << 24 => {
+                    let mut offset = 0;
+                    while offset < attr.size {
+                        let (data, skip) =
+                            vol.do_read(attr.ino, 0, offset as i64, 1 << 17).unwrap();
+                        assert!(data.len() > skip, "{:?}: short read at {}", child, offset);
+                        offset += (data.len() - skip) as u64;
+                        check(&mut vol, &|| format!("read {:?} at {}", child, offset));
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+    peak
+}
+
+/// Walk with and without `limit`, and report whether only the limited walk stayed within it.
+fn check(name: &str, img: &Path, top: Option<&str>, limit: u64) -> bool {
+    let unlimited = walk(img, top, Options::default());
+    let limited = walk(
+        img,
+        top,
+        Options {
+            mem_limit: Some(limit),
+            ..Default::default()
+        },
+    );
+    let ok = unlimited > limit && limited <= limit;
+    println!(
+        "{} (peak {} bytes unlimited, {} limited to {}) ... {}",
+        name,
+        unlimited,
+        limited,
+        limit,
+        if ok { "ok" } else { "FAILED" }
+    );
+    ok
+}
+
+fn main() {
+    let ok = check("mem_limit", &GOLDEN4K, None, LIMIT);
+    let btree3_ok = check("mem_limit::btree3", &GOLDEN1K, Some("btree3"), BTREE3_LIMIT);
+    if !(ok && btree3_ok) {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,62 @@
+//! Mount two golden images with different block sizes at the same time, from this one process,
+//! and read files from both.  Each Volume must decode its own image with its own superblock.
+//!
+//! It uses the library directly rather than the xfs-fuse executable, so it has its own main.
+use std::{fs, path::Path};
+
+use function_name::named;
+use fuser::MountOption;
+use tempfile::tempdir;
+
+// Test targets with harness = false are built with cfg(test), so the unit tests' helpers and
+// imports go unused.
+#[allow(dead_code, deprecated, unused_imports)]
+#[path = "../src/libxfuse/mod.rs"]
+mod libxfuse;
+#[allow(dead_code)]
+mod util;
+
+use libxfuse::volume::{Options, Volume};
+use util::{GOLDEN1K, GOLDEN4K};
+
+/// Every 16 bytes of the golden files' data are their own offset, in hex
+fn check_file(path: &Path, size: usize) {
+    let data = fs::read(path).unwrap();
+    assert_eq!(data.len(), size, "{}", path.display());
+    for (i, chunk) in data.chunks(16).enumerate() {
+        assert_eq!(
+            chunk,
+            format!("{:016x}", i * 16).as_bytes(),
+            "{}",
+            path.display()
+        );
+    }
+}
+
+#[named]
+fn main() {
+    require_fusefs!();
+
+    let opts = [
+        MountOption::FSName("fusefs".to_string()),
+        MountOption::Subtype("xfs".to_string()),
+        MountOption::RO,
+    ];
+    let d1k = tempdir().unwrap();
+    let d4k = tempdir().unwrap();
+    let vol1k = Volume::open(&GOLDEN1K, Options::default()).unwrap();
+    let vol4k = Volume::open(&GOLDEN4K, Options::default()).unwrap();
+    let session1k = fuser::spawn_mount2(vol1k, d1k.path(), &opts).unwrap();
+    let session4k = fuser::spawn_mount2(vol4k, d4k.path(), &opts).unwrap();
+
+    // Both are btree files, so reading them exercises the block size dependent decoders.  Take
+    // turns, so each mount decodes blocks after the other has.
+    check_file(&d1k.path().join("files/btree3.3.txt"), 8388608);
+    check_file(&d4k.path().join("files/btree3.txt"), 16777216);
+    check_file(&d1k.path().join("files/btree3.txt"), 2097152);
+    check_file(&d4k.path().join("files/four_extents.txt"), 16384);
+
+    drop(session1k);
+    drop(session4k);
+    println!("multimount ... ok");
+}
@@ -0,0 +1,126 @@
+//! Read the golden image's files on a pool of threads with `-o threads`, while the dispatcher
+//! keeps looking up and listing directories, as it would for other processes.  Every read must
+//! return the same data that an inline read does, and none may be lost or hang.
+//!
+//! It uses the decoders directly, without FUSE, so it needs neither fusefs nor root.  It has its
+//! own main so it can share the unit tests' view of the library.
+use std::{ffi::OsString, process, sync::mpsc, time::Duration};
+
+// Test targets with harness = false are built with cfg(test), so the unit tests' helpers and
+// imports go unused.
+#[allow(dead_code, deprecated, unused_imports)]
+#[path = "../src/libxfuse/mod.rs"]
+mod libxfuse;
+#[allow(dead_code)]
+mod util;
+
+use libxfuse::{
+    stat::{FileType, ROOT_INO},
+    volume::{Options, Volume},
+};
+use util::GOLDEN4K;
+
+const THREADS: usize = 8;
+
+/// The size of each read, as large as fusefs ever asks for
+const CHUNK: u32 = 1 << 17;
+
+/// Directories to list between reads
+const DIRS: [&str; 4] = ["files", "block", "leaf", "all_name_lengths"];
+
+/// List a directory's entries, other than "." and "..".
+fn list(vol: &mut Volume, dir: u64) -> Vec<(FileType, OsString)> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    loop {
+        let count = vol
+            .do_readdir(dir, offset, |_, off, kind, name| {
+                offset = off;
+                if name != "." && name != ".." {
+                    entries.push((kind, name.to_owned()));
+                }
+                false
+            })
+            .unwrap();
+        if count == 0 {
+            break entries;
+        }
+    }
+}
+
+fn main() {
+    let opts = Options {
+        threads: THREADS,
+        ..Default::default()
+    };
+    let mut vol = Volume::open(&GOLDEN4K, opts).unwrap();
+    let dirs = DIRS
+        .iter()
+        .map(|name| {
+            let (attr, _) = vol.do_lookup(ROOT_INO, name.as_ref()).unwrap();
+            let entries = list(&mut vol, attr.ino);
+            (attr.ino, entries)
+        })
+        .collect::<Vec<_>>();
+
+    // Every regular file in /files, short of the terabyte sparse one, and what an inline read
+    // returns for each of its chunks
+    let (files, _) = vol.do_lookup(ROOT_INO, "files".as_ref()).unwrap();
+    let mut chunks = Vec::new();
+    for (kind, name) in list(&mut vol, files.ino) {
+        let (attr, _) = vol.do_lookup(files.ino, &name).unwrap();
+        if kind != FileType::RegularFile || attr.size > 1 << 24 {
+            continue;
+        }
+        let mut offset = 0;
+        while offset < attr.size {
+            let expected = vol.do_read(attr.ino, 0, offset as i64, CHUNK).unwrap();
+            chunks.push((name.clone(), attr.ino, offset as i64, expected));
+            offset += u64::from(CHUNK);
+        }
+    }
+
+    let (tx, rx) = mpsc::channel();
+    for (i, (_, ino, offset, _)) in chunks.iter().enumerate() {
+        let tx = tx.clone();
+        vol.do_read_pooled(*ino, 0, *offset, CHUNK, move |r| tx.send((i, r)).unwrap());
+        let (dir, entries) = &dirs[i % dirs.len()];
+        assert_eq!(&list(&mut vol, *dir), entries);
+        let (_, name) = &entries[i % entries.len()];
+        vol.do_lookup(*dir, name).unwrap();
+        vol.settle_caches();
+    }
+    drop(tx);
+
+    let mut ok = true;
+    let mut done = vec![false; chunks.len()];
+    for _ in 0..chunks.len() {
+        let (i, r) = match rx.recv_timeout(Duration::from_secs(60)) {
+            Ok(x) => x,
+            Err(e) => {
+                println!(
+                    "{} reads never finished: {}",
+                    done.iter().filter(|d| !**d).count(),
+                    e
+                );
+                ok = false;
+                break;
+            }
+        };
+        let (name, _, offset, expected) = &chunks[i];
+        if done[i] || r.as_ref() != Ok(expected) {
+            println!("{:?} at {}: wrong or repeated reply", name, offset);
+            ok = false;
+        }
+        done[i] = true;
+    }
+    println!(
+        "stress ({} reads on {} threads) ... {}",
+        chunks.len(),
+        THREADS,
+        if ok { "ok" } else { "FAILED" }
+    );
+    if !ok {
+        process::exit(1);
+    }
+}
@@ -1,7 +1,8 @@
 use std::{
     fmt,
     fs,
-    path::PathBuf,
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
     process::Command,
     thread::sleep,
     time::{Duration, Instant},
@@ -94,13 +95,212 @@ fn prepare_image(filename: &str) -> PathBuf {
     img
 }
 
+/// Derive an attr1 file system from the V4 golden image.  attr1 differs from attr2 only in where
+/// the kernel puts a new attribute fork.  di_forkoff records where it is either way, so clearing
+/// the feature bit leaves an image that the kernel reads just as it would a native attr1 one.
+fn prepare_attr1_image() -> PathBuf {
+    /// Offsets of sb_features2 and sb_bad_features2, and the ATTR2 bit in their last byte
+    const FEATURES2: [usize; 2] = [203, 207];
+    const ATTR2: u8 = 0x08;
+
+    let mut img = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    img.push("xfsv4_attr1.img");
+    fs::copy(prepare_image("xfsv4.img"), &img).unwrap();
+    let f = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&img)
+        .unwrap();
+    for ofs in FEATURES2 {
+        let mut b = [0u8];
+        f.read_exact_at(&mut b, ofs as u64).unwrap();
+        f.write_all_at(&[b[0] & !ATTR2], ofs as u64).unwrap();
+    }
+    img
+}
+
+/// The CRC that V5 file systems use to protect their metadata
+const CASTAGNOLI: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISCSI);
+
+/// Recompute the CRC of a V5 metadata object, stored little-endian at `ofs` within it.
+pub fn set_crc(buf: &mut [u8], ofs: usize) {
+    buf[ofs..ofs + 4].fill(0);
+    let crc = CASTAGNOLI.checksum(buf);
+    buf[ofs..ofs + 4].copy_from_slice(&crc.to_le_bytes());
+}
+
+/// Just enough of a golden image's geometry to find its inodes and blocks
+struct Geometry {
+    blocksize: u64,
+    agblocks:  u64,
+    inodesize: u16,
+    inopblog:  u8,
+    agblklog:  u8,
+    dirblklog: u8,
+}
+
+impl Geometry {
+    fn read(f: &fs::File) -> Self {
+        // Offsets within the superblock
+        const SB_BLOCKSIZE: usize = 4;
+        const SB_AGBLOCKS: usize = 84;
+        const SB_INODESIZE: usize = 104;
+        const SB_INOPBLOG: usize = 123;
+        const SB_AGBLKLOG: usize = 124;
+        const SB_DIRBLKLOG: usize = 192;
+
+        let mut sb = [0u8; 512];
+        f.read_exact_at(&mut sb, 0).unwrap();
+        Geometry {
+            blocksize: u64::from(u32::from_be_bytes(
+                sb[SB_BLOCKSIZE..][..4].try_into().unwrap(),
+            )),
+            agblocks:  u64::from(u32::from_be_bytes(
+                sb[SB_AGBLOCKS..][..4].try_into().unwrap(),
+            )),
+            inodesize: u16::from_be_bytes(sb[SB_INODESIZE..][..2].try_into().unwrap()),
+            inopblog:  sb[SB_INOPBLOG],
+            agblklog:  sb[SB_AGBLKLOG],
+            dirblklog: sb[SB_DIRBLKLOG],
+        }
+    }
+
+    /// Byte offset of file system block `fsb`
+    fn fsb_offset(&self, fsb: u64) -> u64 {
+        let agno = fsb >> self.agblklog;
+        let agbno = fsb & ((1 << self.agblklog) - 1);
+        (agno * self.agblocks + agbno) * self.blocksize
+    }
+
+    /// Byte offset of inode `ino`
+    fn inode_offset(&self, ino: u64) -> u64 {
+        let index = ino & ((1 << self.inopblog) - 1);
+        self.fsb_offset(ino >> self.inopblog) + index * u64::from(self.inodesize)
+    }
+}
+
+/// Edit inode `ino` of the file system image at `img`, a copy of a golden image.  `patch` gets the
+/// whole on-disk inode, whose CRC is recomputed afterwards if it's a v3 inode.
+pub fn patch_inode<F: FnOnce(&mut [u8])>(img: &Path, ino: u64, patch: F) {
+    // Offsets within the inode
+    const DI_VERSION: usize = 4;
+    const DI_CRC: usize = 100;
+
+    let f = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(img)
+        .unwrap();
+    let geom = Geometry::read(&f);
+    let ofs = geom.inode_offset(ino);
+    let mut inode = vec![0u8; usize::from(geom.inodesize)];
+    f.read_exact_at(&mut inode, ofs).unwrap();
+    patch(&mut inode);
+    if inode[DI_VERSION] >= 3 {
+        set_crc(&mut inode, DI_CRC);
+    }
+    f.write_all_at(&inode, ofs).unwrap();
+}
+
+/// Derive an image from the 4k golden image whose /leaf directory's data blocks, including the
+/// one holding "." and "..", all begin one directory block later, leaving a hole at dablk 0.
+/// Neither mkfs nor the kernel would ever create that, so the directory's data fork and leaf
+/// block are rewritten by hand.
+fn prepare_offset_leaf_image() -> PathBuf {
+    /// Offsets within the inode
+    const DI_SIZE: usize = 56;
+    const DI_NEXTENTS: usize = 76;
+    const DFORK: usize = 176;
+    /// Where a directory's leaf blocks begin, in bytes
+    const LEAF_OFFSET: u64 = 32 << 30;
+    /// Offsets within the leaf block
+    const LEAF_MAGIC: usize = 8;
+    const LEAF_CRC: usize = 12;
+    const LEAF_COUNT: usize = 56;
+    const LEAF_ENTS: usize = 64;
+    const XFS_DIR3_LEAF1_MAGIC: u16 = 0x3df1;
+    /// The best free space of a data block that doesn't exist
+    const NULLDATAOFF: u16 = 0xffff;
+
+    let mut img = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    img.push("xfs4096_offset_leaf.img");
+    fs::copy(prepare_image("xfs4096.img"), &img).unwrap();
+    let ino = xfuse::Volume::open(&img, xfuse::Options::default())
+        .unwrap()
+        .resolve("/leaf")
+        .unwrap();
+
+    let f = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&img)
+        .unwrap();
+    let geom = Geometry::read(&f);
+    let dirblkfsbs = 1u64 << geom.dirblklog;
+    let dirblksize = (geom.blocksize << geom.dirblklog) as usize;
+    let leaf_startoff = LEAF_OFFSET / geom.blocksize;
+
+    let mut leaf_fsb = None;
+    patch_inode(&img, ino, |inode| {
+        // Move every data block's extent up one directory block, and find the leaf block's
+        let nextents = u32::from_be_bytes(inode[DI_NEXTENTS..][..4].try_into().unwrap());
+        for ext in 0..nextents as usize {
+            let rec = &mut inode[DFORK + 16 * ext..][..16];
+            let raw = u128::from_be_bytes((*rec).try_into().unwrap());
+            let startoff = (raw >> 73) as u64 & ((1 << 54) - 1);
+            if startoff < leaf_startoff {
+                let raw = raw + (u128::from(dirblkfsbs) << 73);
+                rec.copy_from_slice(&raw.to_be_bytes());
+            } else if startoff == leaf_startoff {
+                let startblock = (raw >> 21) as u64 & ((1 << 52) - 1);
+                let blockcount = raw as u64 & ((1 << 21) - 1);
+                assert!(blockcount >= dirblkfsbs, "/leaf's leaf block is fragmented");
+                leaf_fsb = Some(startblock);
+            }
+        }
+        let size = &mut inode[DI_SIZE..][..8];
+        let new_size = u64::from_be_bytes((*size).try_into().unwrap()) + dirblksize as u64;
+        size.copy_from_slice(&new_size.to_be_bytes());
+    });
+    let leaf_block = geom.fsb_offset(leaf_fsb.expect("/leaf has no leaf block"));
+
+    let mut leaf = vec![0u8; dirblksize];
+    f.read_exact_at(&mut leaf, leaf_block).unwrap();
+    assert_eq!(
+        u16::from_be_bytes(leaf[LEAF_MAGIC..][..2].try_into().unwrap()),
+        XFS_DIR3_LEAF1_MAGIC
+    );
+    // Every entry's address, in units of 8 bytes, moves one directory block up
+    let count = u16::from_be_bytes(leaf[LEAF_COUNT..][..2].try_into().unwrap());
+    for i in 0..usize::from(count) {
+        let address = &mut leaf[LEAF_ENTS + 8 * i + 4..][..4];
+        let old = u32::from_be_bytes((*address).try_into().unwrap());
+        if old != 0 {
+            let new = old + (dirblksize / 8) as u32;
+            address.copy_from_slice(&new.to_be_bytes());
+        }
+    }
+    // And the table of each data block's best free space, which ends where the tail begins,
+    // gains an entry for the hole at its start
+    let tail = dirblksize - 4;
+    let bestcount = u32::from_be_bytes(leaf[tail..].try_into().unwrap()) as usize;
+    leaf[tail - 2 * bestcount - 2..][..2].copy_from_slice(&NULLDATAOFF.to_be_bytes());
+    leaf[tail..].copy_from_slice(&(bestcount as u32 + 1).to_be_bytes());
+    set_crc(&mut leaf, LEAF_CRC);
+    f.write_all_at(&leaf, leaf_block).unwrap();
+    img
+}
+
 lazy_static! {
     pub static ref GOLDEN1K: PathBuf = prepare_image("xfs1024.img");
     pub static ref GOLDEN4K: PathBuf = prepare_image("xfs4096.img");
     pub static ref GOLDEN4KN: PathBuf = prepare_image("xfs_4kn.img");
+    pub static ref GOLDEN64K: PathBuf = prepare_image("xfs65536.img");
     pub static ref GOLDENPREALLOCATED: PathBuf = prepare_image("xfs_preallocated.img");
     pub static ref GOLDENV4: PathBuf = prepare_image("xfsv4.img");
     pub static ref GOLDEN_NOFTYPE: PathBuf = prepare_image("xfs_noftype.img");
+    pub static ref GOLDENV4_ATTR1: PathBuf = prepare_attr1_image();
+    pub static ref GOLDEN4K_OFFSET_LEAF: PathBuf = prepare_offset_leaf_image();
 }
 
 #[derive(Clone, Copy, Debug)]